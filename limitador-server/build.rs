@@ -1,4 +1,6 @@
+use std::env;
 use std::error::Error;
+use std::path::PathBuf;
 use std::process::Command;
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -9,14 +11,24 @@ fn main() -> Result<(), Box<dyn Error>> {
 }
 
 fn generate_protobuf() -> Result<(), Box<dyn Error>> {
-    tonic_build::configure().build_server(true).compile(
-        &["envoy/service/ratelimit/v3/rls.proto"],
-        &[
-            "vendor/protobufs/data-plane-api",
-            "vendor/protobufs/protoc-gen-validate",
-            "vendor/protobufs/xds",
-        ],
-    )?;
+    // Served by the reflection service
+    let descriptor_set = PathBuf::from(env::var("OUT_DIR")?).join("rls_descriptor.bin");
+    tonic_build::configure()
+        .build_server(true)
+        .file_descriptor_set_path(descriptor_set)
+        .compile(
+            &[
+                "envoy/service/ratelimit/v3/rls.proto",
+                "limitador/service/counters/v1/counters.proto",
+                "limitador/service/reservations/v1/reservations.proto",
+            ],
+            &[
+                "proto",
+                "vendor/protobufs/data-plane-api",
+                "vendor/protobufs/protoc-gen-validate",
+                "vendor/protobufs/xds",
+            ],
+        )?;
     Ok(())
 }
 
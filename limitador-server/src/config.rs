@@ -1,38 +1,194 @@
-// LIMITS_FILE: Path
+// LIMITS_FILE: Path // or an http(s) URL the limits are fetched from
+//  └ LIMITS_FILE_FORMAT: enum LimitsFileFormat { Yaml, Json } // from the extension of the file when unset
+//  └ LIMITS_FILE_POLL_INTERVAL_SEC: u64 // only applies to the limits fetched from a URL
+//
+// IN_MEMORY_SNAPSHOT_PATH: Path // only applies to the in-memory storage
+//  └ IN_MEMORY_SNAPSHOT_INTERVAL_SEC: u64
+// IN_MEMORY_GOSSIP_ADDRESS: host:port // only applies to the in-memory storage, nothing is gossiped when unset
+//  └ IN_MEMORY_GOSSIP_PEERS: Vec<String> // comma separated host:port
+//  └ IN_MEMORY_GOSSIP_INTERVAL_MS: u64
+//
+// COUNTER_OVERFLOW_POLICY: enum OverflowPolicy { Saturate, Reject } // only applies to the in-memory and disk storages, not when gossiping
 //
 // LIMIT_NAME_IN_PROMETHEUS_LABELS: bool
 //
+// ZERO_HITS_ADDEND_CHECKS_ONLY: bool
+//
+// RETRY_AFTER_HEADER: bool
+//
+// MATCHED_LIMITS_HEADER: bool
+//
+// BINDING_LIMIT_NAME: enum BindingLimitName { Header, Metadata } // not told when unset
+//
+// EMPTY_DOMAIN_ANSWER: enum EmptyDomainAnswer { Unknown, Ok, Deny, Error }
+//
+// APPROXIMATE_OVER_LIMIT_CODE: enum Code { OverLimit, Unknown }
+// APPROXIMATE_HEADER: bool
+//
 // REDIS_URL: StorageType { String }
+// └ REDIS_POOL_SIZE: usize // a single multiplexed connection when unset, not with the local cache
+// └ REDIS_PIPELINE_PERIOD_US: u64 // checks aren't pipelined when unset, nor with the local cache
+//   └ REDIS_PIPELINE_MAX_SIZE: usize
 // └ REDIS_LOCAL_CACHE_ENABLED: bool
 //   └ REDIS_LOCAL_CACHE_FLUSHING_PERIOD_MS: i64 ?!
 //   └ REDIS_LOCAL_CACHE_MAX_TTL_CACHED_COUNTERS_MS: u64 -> Duration
 //   └ REDIS_LOCAL_CACHE_TTL_RATIO_CACHED_COUNTERS: u64
+// └ REDIS_FAILOVER_RETRY_INTERVAL_MS: u64 // requests fail while Redis can't be reached when unset
+// └ REDIS_READ_REPLICA_URL: String // reads go to the primary when unset, not with the local cache
+//
+// REDIS_CLUSTER_URLS: StorageType { Vec<String> } // comma separated seed nodes
+//
+// REDIS_KEY_PREFIX: String // applies to both REDIS_URL and REDIS_CLUSTER_URLS, keys aren't prefixed when unset
+//
+// REDIS_STARTUP_TIMEOUT_SEC: u64 // applies to both REDIS_URL and REDIS_CLUSTER_URLS, Redis is only tried once when unset
+//
+// REDIS_HASHED_KEYS: bool // applies to both REDIS_URL and REDIS_CLUSTER_URLS
 //
 // INFINISPAN_URL: StorageType { String }
 //  └ INFINISPAN_CACHE_NAME: String
 //  └ INFINISPAN_COUNTERS_CONSISTENCY: enum Consistency { Weak, Strong }
 //
-// ENVOY_RLS_HOST: host // just to become ENVOY_RLS_HOST:ENVOY_RLS_PORT as String
+// DYNAMODB_TABLE: StorageType { String }
+//  └ DYNAMODB_REGION: String
+//
+// POSTGRES_URL: StorageType { String }
+//  └ POSTGRES_FLUSHING_PERIOD_MS: u64 // counters are written on every request when unset
+//
+// DECISION_CACHE_TTL_MS: u64 // decisions aren't cached when unset, nor with a storage held in memory
+//  └ DECISION_CACHE_MAX_ENTRIES: u64
+//
+// ENVOY_RLS_HOST: Vec<host> // comma separated, each to become ENVOY_RLS_HOST:ENVOY_RLS_PORT as String
+//                            // or a `unix:/path` to a socket, ignoring ENVOY_RLS_PORT
 // ENVOY_RLS_PORT: port
 //
+// ENVOY_RLS_TLS_CERT: Path // the RLS server is only served over TLS when set
+//  └ ENVOY_RLS_TLS_KEY: Path
+//  └ ENVOY_RLS_TLS_CLIENT_CA: Path
+//    └ ENVOY_RLS_TLS_REQUIRE_CLIENT_CERT: bool
+//
+// GRPC_REFLECTION_SERVICE: bool
+//
+// GRPC_COUNTERS_SERVICE: bool
+//
+// GRPC_COMPRESSION: bool
+//
+// ENVOY_RLS_MAX_CONCURRENT_REQUESTS: usize // requests aren't shed when unset
+//
+// ENVOY_RLS_MAX_DECODING_MESSAGE_SIZE: usize // tonic's default when unset
+// ENVOY_RLS_MAX_ENCODING_MESSAGE_SIZE: usize // tonic's default when unset
+//
+// ENVOY_RLS_DRAIN_TIMEOUT_SEC: u64
+//
+// ENVOY_RLS_MAX_DESCRIPTORS: usize
+// ENVOY_RLS_MAX_DESCRIPTOR_ENTRIES: usize
+//
+// ENVOY_RLS_STORAGE_TIMEOUT_MS: u64 // the storage is waited for when unset
+//
+// ENVOY_RLS_NAMESPACE_ENTRY: String // the namespace is the domain alone when unset
+//
+// ENVOY_RLS_QUOTA_REQUESTS: u32 // no quotas are granted when unset
+//  └ ENVOY_RLS_QUOTA_VALIDITY_MS: u64
+//
+// ENVOY_RLS_RESERVATION_TIMEOUT_MS: u64 // requests aren't checked in two phases when unset
+//
+// MAX_VALUE_OVERRIDE_NAMESPACES: Vec<String> // comma separated
+//
+// BYPASS_ENTRIES: Vec<String> // comma separated, each as NAMESPACE:KEY=VALUE
+//
+// DESCRIPTOR_TRANSFORMS: Vec<String> // comma separated, each as KEY:TRANSFORM
+//
+// RATE_LIMIT_HEADERS_NAMESPACES: Vec<String> // comma separated, each as NAMESPACE=HEADERS
+//
+// KILL_SWITCH: enum KillSwitchMode { Allow, Deny } // no kill switch is engaged when unset
+// KILL_SWITCH_NAMESPACES: Vec<String> // comma separated, each as NAMESPACE=MODE
+//
+// DEBUG_LOG_SAMPLE_EVERY: u64 // only applies to the RLS requests logged at debug level
+//  └ DEBUG_LOG_OVER_LIMIT_ONLY: bool
+//
+// ACCESS_LOG_LEVEL: enum Level { Error, Warn, Info, Debug, Trace } // no access log when unset
+//  └ ACCESS_LOG_REDACTED_ENTRIES: Vec<String> // comma separated
+//  └ ACCESS_LOG_HASHED_ENTRIES: Vec<String> // comma separated
+//
+// STORAGE_WARM_UP_TIMEOUT_SEC: u64 // the RLS server is served without warming the storage up when unset
+//
+// SLOW_STORAGE_CALLS_THRESHOLD_MS: u64 // slow storage calls aren't logged when unset
+//  └ SLOW_STORAGE_CALLS_LOG_LEVEL: enum Level { Error, Warn, Info, Debug, Trace }
+//
+// UTILIZATION_WEBHOOK_URL: String // no events are posted when unset
+//  └ UTILIZATION_THRESHOLD_PERCENT: u8
+//
+// OVER_LIMIT_BODY: String // the requests over limit get no body when unset
+//  └ OVER_LIMIT_BODY_FORMAT: enum OverLimitBodyFormat { Json, Text }
+//
 // HTTP_API_HOST: host // just to become HTTP_API_HOST:HTTP_API_PORT as &str
 // HTTP_API_PORT: port
+//
+// ADMIN_API_HOST: host // just to become ADMIN_API_HOST:ADMIN_API_PORT as &str
+// ADMIN_API_PORT: port // the admin API is only served when set
 
-use crate::envoy_rls::server::RateLimitHeaders;
+use crate::debug_sampling::DebugLogSampling;
+use crate::envoy_rls::descriptor_transforms::DescriptorTransforms;
+use crate::envoy_rls::server::{
+    ApproximateDecisions, BindingLimitName, Bypass, EmptyDomainAnswer, FailureMode, QuotaGrants,
+    RateLimitHeaders, RequestBounds, UNIX_SOCKET_PREFIX,
+};
+use crate::kill_switch::KillSwitches;
+use crate::over_limit::OverLimitBody;
+use limitador::limit::Namespace;
 use limitador::storage;
 use log::LevelFilter;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::Path;
 
 #[derive(Debug)]
 pub struct Configuration {
     pub limits_file: String,
+    pub limits_file_format: LimitsFileFormat,
+    pub limits_poll_interval: u64,
     pub storage: StorageConfiguration,
-    rls_host: String,
+    rls_hosts: Vec<String>,
     rls_port: u16,
     http_host: String,
     http_port: u16,
     pub limit_name_in_labels: bool,
     pub log_level: Option<LevelFilter>,
     pub rate_limit_headers: RateLimitHeaders,
+    pub failure_mode: FailureMode,
+    pub in_memory_namespaces: Vec<String>,
+    pub tracing_endpoint: Option<String>,
+    pub admin_address: Option<String>,
+    pub zero_hits_addend_checks_only: bool,
+    pub retry_after_header: bool,
+    pub matched_limits_header: bool,
+    pub binding_limit_name: Option<BindingLimitName>,
+    pub empty_domain_answer: EmptyDomainAnswer,
+    pub rls_tls: Option<RlsTlsConfiguration>,
+    pub grpc_reflection_service: bool,
+    pub grpc_counters_service: bool,
+    pub grpc_compression: bool,
+    pub rls_max_concurrent_requests: Option<usize>,
+    pub rls_max_decoding_message_size: Option<usize>,
+    pub rls_max_encoding_message_size: Option<usize>,
+    pub rls_drain_timeout: u64,
+    pub rls_request_bounds: RequestBounds,
+    pub rls_storage_timeout: Option<u64>,
+    pub rls_quota_grants: Option<QuotaGrants>,
+    pub rls_reservation_timeout: Option<u64>,
+    pub max_value_override_namespaces: Vec<String>,
+    pub bypass: Bypass,
+    pub descriptor_transforms: DescriptorTransforms,
+    pub namespace_rate_limit_headers: HashMap<Namespace, RateLimitHeaders>,
+    pub namespace_entry: Option<String>,
+    pub kill_switches: KillSwitches,
+    pub debug_log_sampling: DebugLogSampling,
+    pub access_log: Option<AccessLogConfiguration>,
+    pub storage_warm_up_timeout: Option<u64>,
+    pub slow_storage_calls: Option<SlowStorageCallsConfiguration>,
+    pub utilization_webhook: Option<UtilizationWebhookConfiguration>,
+    pub over_limit_body: Option<OverLimitBody>,
+    pub decision_cache: Option<DecisionCacheConfiguration>,
+    pub approximate_decisions: ApproximateDecisions,
 }
 
 pub mod env {
@@ -40,13 +196,59 @@ pub mod env {
 
     lazy_static! {
         pub static ref LIMITS_FILE: Option<&'static str> = value_for("LIMITS_FILE");
+        pub static ref LIMITS_FILE_FORMAT: Option<&'static str> = value_for("LIMITS_FILE_FORMAT");
+        pub static ref LIMITS_FILE_POLL_INTERVAL_SEC: Option<&'static str> =
+            value_for("LIMITS_FILE_POLL_INTERVAL_SEC");
         pub static ref ENVOY_RLS_HOST: Option<&'static str> = value_for("ENVOY_RLS_HOST");
         pub static ref ENVOY_RLS_PORT: Option<&'static str> = value_for("ENVOY_RLS_PORT");
+        pub static ref ENVOY_RLS_TLS_CERT: Option<&'static str> = value_for("ENVOY_RLS_TLS_CERT");
+        pub static ref ENVOY_RLS_TLS_KEY: Option<&'static str> = value_for("ENVOY_RLS_TLS_KEY");
+        pub static ref ENVOY_RLS_TLS_CLIENT_CA: Option<&'static str> =
+            value_for("ENVOY_RLS_TLS_CLIENT_CA");
+        pub static ref ENVOY_RLS_MAX_CONCURRENT_REQUESTS: Option<&'static str> =
+            value_for("ENVOY_RLS_MAX_CONCURRENT_REQUESTS");
+        pub static ref ENVOY_RLS_MAX_DECODING_MESSAGE_SIZE: Option<&'static str> =
+            value_for("ENVOY_RLS_MAX_DECODING_MESSAGE_SIZE");
+        pub static ref ENVOY_RLS_MAX_ENCODING_MESSAGE_SIZE: Option<&'static str> =
+            value_for("ENVOY_RLS_MAX_ENCODING_MESSAGE_SIZE");
+        pub static ref ENVOY_RLS_DRAIN_TIMEOUT_SEC: Option<&'static str> =
+            value_for("ENVOY_RLS_DRAIN_TIMEOUT_SEC");
+        pub static ref ENVOY_RLS_MAX_DESCRIPTORS: Option<&'static str> =
+            value_for("ENVOY_RLS_MAX_DESCRIPTORS");
+        pub static ref ENVOY_RLS_MAX_DESCRIPTOR_ENTRIES: Option<&'static str> =
+            value_for("ENVOY_RLS_MAX_DESCRIPTOR_ENTRIES");
+        pub static ref ENVOY_RLS_STORAGE_TIMEOUT_MS: Option<&'static str> =
+            value_for("ENVOY_RLS_STORAGE_TIMEOUT_MS");
+        pub static ref ENVOY_RLS_NAMESPACE_ENTRY: Option<&'static str> =
+            value_for("ENVOY_RLS_NAMESPACE_ENTRY");
+        pub static ref ENVOY_RLS_QUOTA_REQUESTS: Option<&'static str> =
+            value_for("ENVOY_RLS_QUOTA_REQUESTS");
+        pub static ref ENVOY_RLS_QUOTA_VALIDITY_MS: Option<&'static str> =
+            value_for("ENVOY_RLS_QUOTA_VALIDITY_MS");
+        pub static ref ENVOY_RLS_RESERVATION_TIMEOUT_MS: Option<&'static str> =
+            value_for("ENVOY_RLS_RESERVATION_TIMEOUT_MS");
         pub static ref HTTP_API_HOST: Option<&'static str> = value_for("HTTP_API_HOST");
         pub static ref HTTP_API_PORT: Option<&'static str> = value_for("HTTP_API_PORT");
+        pub static ref ADMIN_API_HOST: Option<&'static str> = value_for("ADMIN_API_HOST");
+        pub static ref ADMIN_API_PORT: Option<&'static str> = value_for("ADMIN_API_PORT");
         pub static ref DISK_PATH: Option<&'static str> = value_for("DISK_PATH");
         pub static ref DISK_OPTIMIZE: Option<&'static str> = value_for("DISK_OPTIMIZE");
+        pub static ref COUNTER_OVERFLOW_POLICY: Option<&'static str> =
+            value_for("COUNTER_OVERFLOW_POLICY");
         pub static ref REDIS_URL: Option<&'static str> = value_for("REDIS_URL");
+        pub static ref REDIS_CLUSTER_URLS: Option<&'static str> = value_for("REDIS_CLUSTER_URLS");
+        pub static ref REDIS_KEY_PREFIX: Option<&'static str> = value_for("REDIS_KEY_PREFIX");
+        pub static ref REDIS_STARTUP_TIMEOUT_SEC: Option<&'static str> =
+            value_for("REDIS_STARTUP_TIMEOUT_SEC");
+        pub static ref REDIS_POOL_SIZE: Option<&'static str> = value_for("REDIS_POOL_SIZE");
+        pub static ref REDIS_FAILOVER_RETRY_INTERVAL_MS: Option<&'static str> =
+            value_for("REDIS_FAILOVER_RETRY_INTERVAL_MS");
+        pub static ref REDIS_READ_REPLICA_URL: Option<&'static str> =
+            value_for("REDIS_READ_REPLICA_URL");
+        pub static ref REDIS_PIPELINE_PERIOD_US: Option<&'static str> =
+            value_for("REDIS_PIPELINE_PERIOD_US");
+        pub static ref REDIS_PIPELINE_MAX_SIZE: Option<&'static str> =
+            value_for("REDIS_PIPELINE_MAX_SIZE");
         pub static ref REDIS_LOCAL_CACHE_MAX_TTL_CACHED_COUNTERS_MS: Option<&'static str> =
             value_for("REDIS_LOCAL_CACHE_MAX_TTL_CACHED_COUNTERS_MS");
         pub static ref REDIS_LOCAL_CACHE_FLUSHING_PERIOD_MS: Option<&'static str> =
@@ -54,10 +256,65 @@ pub mod env {
         pub static ref REDIS_LOCAL_CACHE_TTL_RATIO_CACHED_COUNTERS: Option<&'static str> =
             value_for("REDIS_LOCAL_CACHE_TTL_RATIO_CACHED_COUNTERS");
         pub static ref RATE_LIMIT_HEADERS: Option<&'static str> = value_for("RATE_LIMIT_HEADERS");
+        pub static ref FAILURE_MODE: Option<&'static str> = value_for("FAILURE_MODE");
+        pub static ref EMPTY_DOMAIN_ANSWER: Option<&'static str> = value_for("EMPTY_DOMAIN_ANSWER");
+        pub static ref IN_MEMORY_NAMESPACES: Option<&'static str> =
+            value_for("IN_MEMORY_NAMESPACES");
+        pub static ref MAX_VALUE_OVERRIDE_NAMESPACES: Option<&'static str> =
+            value_for("MAX_VALUE_OVERRIDE_NAMESPACES");
+        pub static ref BYPASS_ENTRIES: Option<&'static str> = value_for("BYPASS_ENTRIES");
+        pub static ref DESCRIPTOR_TRANSFORMS: Option<&'static str> =
+            value_for("DESCRIPTOR_TRANSFORMS");
+        pub static ref RATE_LIMIT_HEADERS_NAMESPACES: Option<&'static str> =
+            value_for("RATE_LIMIT_HEADERS_NAMESPACES");
+        pub static ref KILL_SWITCH: Option<&'static str> = value_for("KILL_SWITCH");
+        pub static ref BINDING_LIMIT_NAME: Option<&'static str> = value_for("BINDING_LIMIT_NAME");
+        pub static ref KILL_SWITCH_NAMESPACES: Option<&'static str> =
+            value_for("KILL_SWITCH_NAMESPACES");
+        pub static ref DEBUG_LOG_SAMPLE_EVERY: Option<&'static str> =
+            value_for("DEBUG_LOG_SAMPLE_EVERY");
+        pub static ref ACCESS_LOG_LEVEL: Option<&'static str> = value_for("ACCESS_LOG_LEVEL");
+        pub static ref ACCESS_LOG_REDACTED_ENTRIES: Option<&'static str> =
+            value_for("ACCESS_LOG_REDACTED_ENTRIES");
+        pub static ref ACCESS_LOG_HASHED_ENTRIES: Option<&'static str> =
+            value_for("ACCESS_LOG_HASHED_ENTRIES");
+        pub static ref STORAGE_WARM_UP_TIMEOUT_SEC: Option<&'static str> =
+            value_for("STORAGE_WARM_UP_TIMEOUT_SEC");
+        pub static ref SLOW_STORAGE_CALLS_THRESHOLD_MS: Option<&'static str> =
+            value_for("SLOW_STORAGE_CALLS_THRESHOLD_MS");
+        pub static ref SLOW_STORAGE_CALLS_LOG_LEVEL: Option<&'static str> =
+            value_for("SLOW_STORAGE_CALLS_LOG_LEVEL");
+        pub static ref UTILIZATION_WEBHOOK_URL: Option<&'static str> =
+            value_for("UTILIZATION_WEBHOOK_URL");
+        pub static ref UTILIZATION_THRESHOLD_PERCENT: Option<&'static str> =
+            value_for("UTILIZATION_THRESHOLD_PERCENT");
+        pub static ref OVER_LIMIT_BODY: Option<&'static str> = value_for("OVER_LIMIT_BODY");
+        pub static ref OVER_LIMIT_BODY_FORMAT: Option<&'static str> =
+            value_for("OVER_LIMIT_BODY_FORMAT");
+        pub static ref IN_MEMORY_SNAPSHOT_PATH: Option<&'static str> =
+            value_for("IN_MEMORY_SNAPSHOT_PATH");
+        pub static ref IN_MEMORY_SNAPSHOT_INTERVAL_SEC: Option<&'static str> =
+            value_for("IN_MEMORY_SNAPSHOT_INTERVAL_SEC");
+        pub static ref IN_MEMORY_GOSSIP_ADDRESS: Option<&'static str> =
+            value_for("IN_MEMORY_GOSSIP_ADDRESS");
+        pub static ref IN_MEMORY_GOSSIP_PEERS: Option<&'static str> =
+            value_for("IN_MEMORY_GOSSIP_PEERS");
+        pub static ref IN_MEMORY_GOSSIP_INTERVAL_MS: Option<&'static str> =
+            value_for("IN_MEMORY_GOSSIP_INTERVAL_MS");
+        pub static ref TRACING_ENDPOINT: Option<&'static str> = value_for("TRACING_ENDPOINT");
         pub static ref INFINISPAN_CACHE_NAME: Option<&'static str> =
             value_for("INFINISPAN_CACHE_NAME");
         pub static ref INFINISPAN_COUNTERS_CONSISTENCY: Option<&'static str> =
             value_for("INFINISPAN_COUNTERS_CONSISTENCY");
+        pub static ref DYNAMODB_REGION: Option<&'static str> = value_for("DYNAMODB_REGION");
+        pub static ref POSTGRES_FLUSHING_PERIOD_MS: Option<&'static str> =
+            value_for("POSTGRES_FLUSHING_PERIOD_MS");
+        pub static ref DECISION_CACHE_TTL_MS: Option<&'static str> =
+            value_for("DECISION_CACHE_TTL_MS");
+        pub static ref DECISION_CACHE_MAX_ENTRIES: Option<&'static str> =
+            value_for("DECISION_CACHE_MAX_ENTRIES");
+        pub static ref APPROXIMATE_OVER_LIMIT_CODE: Option<&'static str> =
+            value_for("APPROXIMATE_OVER_LIMIT_CODE");
     }
 
     fn value_for(env_key: &'static str) -> Option<&'static str> {
@@ -72,38 +329,174 @@ impl Configuration {
     pub const DEFAULT_RLS_PORT: &'static str = "8081";
     pub const DEFAULT_HTTP_PORT: &'static str = "8080";
     pub const DEFAULT_IP_BIND: &'static str = "0.0.0.0";
+    pub const DEFAULT_ADMIN_IP_BIND: &'static str = "127.0.0.1";
+    pub const DEFAULT_RLS_DRAIN_TIMEOUT_SEC: u64 = 30;
+    pub const DEFAULT_LIMITS_POLL_INTERVAL_SEC: u64 = 60;
 
     #[allow(clippy::too_many_arguments)]
     pub fn with(
         storage: StorageConfiguration,
         limits_file: String,
-        rls_host: String,
+        rls_hosts: Vec<String>,
         rls_port: u16,
         http_host: String,
         http_port: u16,
         limit_name_in_labels: bool,
         rate_limit_headers: RateLimitHeaders,
+        failure_mode: FailureMode,
     ) -> Self {
         Self {
+            limits_file_format: LimitsFileFormat::of(&limits_file),
             limits_file,
+            limits_poll_interval: Self::DEFAULT_LIMITS_POLL_INTERVAL_SEC,
             storage,
-            rls_host,
+            rls_hosts,
             rls_port,
             http_host,
             http_port,
             limit_name_in_labels,
             log_level: None,
             rate_limit_headers,
+            failure_mode,
+            in_memory_namespaces: Vec::new(),
+            tracing_endpoint: None,
+            admin_address: None,
+            zero_hits_addend_checks_only: false,
+            retry_after_header: false,
+            matched_limits_header: false,
+            binding_limit_name: None,
+            empty_domain_answer: EmptyDomainAnswer::default(),
+            rls_tls: None,
+            grpc_reflection_service: false,
+            grpc_counters_service: false,
+            grpc_compression: false,
+            rls_max_concurrent_requests: None,
+            rls_max_decoding_message_size: None,
+            rls_max_encoding_message_size: None,
+            rls_drain_timeout: Self::DEFAULT_RLS_DRAIN_TIMEOUT_SEC,
+            rls_request_bounds: RequestBounds::default(),
+            rls_storage_timeout: None,
+            rls_quota_grants: None,
+            rls_reservation_timeout: None,
+            max_value_override_namespaces: Vec::new(),
+            bypass: Bypass::default(),
+            descriptor_transforms: DescriptorTransforms::default(),
+            namespace_rate_limit_headers: HashMap::new(),
+            namespace_entry: None,
+            kill_switches: KillSwitches::default(),
+            debug_log_sampling: DebugLogSampling::default(),
+            access_log: None,
+            storage_warm_up_timeout: None,
+            slow_storage_calls: None,
+            utilization_webhook: None,
+            over_limit_body: None,
+            decision_cache: None,
+            approximate_decisions: ApproximateDecisions::default(),
         }
     }
 
-    pub fn rlp_address(&self) -> String {
-        format!("{}:{}", self.rls_host, self.rls_port)
+    /// The addresses the RLS server listens on, one per host, with the IPv6
+    /// ones bracketed.
+    pub fn rlp_addresses(&self) -> Vec<String> {
+        self.rls_hosts
+            .iter()
+            .map(|host| {
+                if host.starts_with(UNIX_SOCKET_PREFIX) {
+                    host.clone()
+                } else if host.parse::<Ipv6Addr>().is_ok() {
+                    format!("[{}]:{}", host, self.rls_port)
+                } else {
+                    format!("{}:{}", host, self.rls_port)
+                }
+            })
+            .collect()
     }
 
     pub fn http_address(&self) -> String {
         format!("{}:{}", self.http_host, self.http_port)
     }
+
+    /// Why the configuration can't be served, if so, telling the value at
+    /// fault, rather than failing on it once the servers or the storage start.
+    pub fn validate(&self) -> Result<(), String> {
+        for host in &self.rls_hosts {
+            match host.strip_prefix(UNIX_SOCKET_PREFIX) {
+                Some("") => {
+                    return Err(format!("invalid RLS host '{host}': expected a socket path"))
+                }
+                Some(_) => {}
+                None => validate_host("RLS host", host)?,
+            }
+        }
+        validate_host("HTTP host", &self.http_host)?;
+        if let Some(address) = &self.admin_address {
+            let host = address
+                .rsplit_once(':')
+                .map_or(address.as_str(), |(host, _)| host);
+            validate_host("admin API host", host)?;
+        }
+        if let Some(endpoint) = &self.tracing_endpoint {
+            validate_url("tracing endpoint", endpoint)?;
+        }
+        match &self.storage {
+            StorageConfiguration::InMemory(_) => {}
+            StorageConfiguration::Disk(cfg) => {
+                if cfg.path.is_empty() {
+                    return Err("invalid disk storage path: expected a path".to_string());
+                }
+            }
+            StorageConfiguration::Redis(cfg) => {
+                validate_url("Redis URL", &cfg.url)?;
+                if let Some(url) = &cfg.read_replica_url {
+                    validate_url("Redis read replica URL", url)?;
+                    if cfg.cache.is_some() {
+                        return Err(format!(
+                            "invalid Redis read replica URL '{url}': the cached Redis storage doesn't support read replicas"
+                        ));
+                    }
+                }
+            }
+            StorageConfiguration::RedisCluster(cfg) => {
+                for url in &cfg.urls {
+                    validate_url("Redis cluster URL", url)?;
+                }
+            }
+            #[cfg(feature = "infinispan")]
+            StorageConfiguration::Infinispan(cfg) => {
+                let url = validate_url("Infinispan URL", &cfg.url)?;
+                if url.host_str().is_none() || url.port().is_none() {
+                    return Err(format!(
+                        "invalid Infinispan URL '{}': expected a host and a port",
+                        cfg.url
+                    ));
+                }
+            }
+            #[cfg(feature = "dynamodb")]
+            StorageConfiguration::DynamoDb(_) => {}
+            #[cfg(feature = "postgres")]
+            StorageConfiguration::Postgres(cfg) => validate_url("Postgres URL", &cfg.url)?,
+        }
+        Ok(())
+    }
+}
+
+// An IP address, bracketed or not when v6, or a host name to resolve
+fn validate_host(what: &str, host: &str) -> Result<(), String> {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if unbracketed.parse::<IpAddr>().is_ok() || url::Host::parse(host).is_ok() {
+        Ok(())
+    } else {
+        Err(format!(
+            "invalid {what} '{host}': expected an IP address or a host name"
+        ))
+    }
+}
+
+fn validate_url(what: &str, url: &str) -> Result<url::Url, String> {
+    url::Url::parse(url).map_err(|e| format!("invalid {what} '{url}': {e}"))
 }
 
 #[cfg(test)]
@@ -111,44 +504,214 @@ impl Default for Configuration {
     fn default() -> Self {
         Configuration {
             limits_file: "".to_string(),
+            limits_file_format: LimitsFileFormat::Yaml,
+            limits_poll_interval: Self::DEFAULT_LIMITS_POLL_INTERVAL_SEC,
             storage: StorageConfiguration::InMemory(InMemoryStorageConfiguration {
                 cache_size: Some(10_000),
+                snapshot: None,
+                gossip: None,
+                overflow_policy: storage::OverflowPolicy::default(),
             }),
-            rls_host: "".to_string(),
+            rls_hosts: Vec::new(),
             rls_port: 0,
             http_host: "".to_string(),
             http_port: 0,
             limit_name_in_labels: false,
             log_level: None,
             rate_limit_headers: RateLimitHeaders::None,
+            failure_mode: FailureMode::Passthrough,
+            in_memory_namespaces: Vec::new(),
+            tracing_endpoint: None,
+            admin_address: None,
+            zero_hits_addend_checks_only: false,
+            retry_after_header: false,
+            matched_limits_header: false,
+            binding_limit_name: None,
+            empty_domain_answer: EmptyDomainAnswer::default(),
+            rls_tls: None,
+            grpc_reflection_service: false,
+            grpc_counters_service: false,
+            grpc_compression: false,
+            rls_max_concurrent_requests: None,
+            rls_max_decoding_message_size: None,
+            rls_max_encoding_message_size: None,
+            rls_drain_timeout: Self::DEFAULT_RLS_DRAIN_TIMEOUT_SEC,
+            rls_request_bounds: RequestBounds::default(),
+            rls_storage_timeout: None,
+            rls_quota_grants: None,
+            rls_reservation_timeout: None,
+            max_value_override_namespaces: Vec::new(),
+            bypass: Bypass::default(),
+            descriptor_transforms: DescriptorTransforms::default(),
+            namespace_rate_limit_headers: HashMap::new(),
+            namespace_entry: None,
+            kill_switches: KillSwitches::default(),
+            debug_log_sampling: DebugLogSampling::default(),
+            access_log: None,
+            storage_warm_up_timeout: None,
+            slow_storage_calls: None,
+            utilization_webhook: None,
+            over_limit_body: None,
+            decision_cache: None,
+            approximate_decisions: ApproximateDecisions::default(),
         }
     }
 }
 
+/// How the limits file is parsed
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum LimitsFileFormat {
+    Yaml,
+    Json,
+}
+
+impl LimitsFileFormat {
+    /// JSON for the `.json` files, YAML for all the others, the same going
+    /// for the path of a URL
+    pub fn of(path: &str) -> Self {
+        let path = match url::Url::parse(path) {
+            Ok(url) if url.has_host() => url.path().to_string(),
+            _ => path.to_string(),
+        };
+        match Path::new(&path).extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Yaml,
+        }
+    }
+}
+
+/// PEM files the RLS server is served over TLS with
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct RlsTlsConfiguration {
+    pub cert: String,
+    pub key: String,
+    /// Client certificates are verified against it when set
+    pub client_ca: Option<String>,
+    pub require_client_cert: bool,
+}
+
+/// One line is logged per RLS request, at `level`, with the values of the
+/// descriptor entries of the given keys masked or hashed
+#[derive(PartialEq, Eq, Debug)]
+pub struct AccessLogConfiguration {
+    pub level: tracing::Level,
+    pub redacted_entries: Vec<String>,
+    pub hashed_entries: Vec<String>,
+}
+
+/// The storage calls that take longer than `threshold` milliseconds are
+/// logged at `level`
+#[derive(PartialEq, Eq, Debug)]
+pub struct SlowStorageCallsConfiguration {
+    pub threshold: u64,
+    pub level: log::Level,
+}
+
+/// An event is posted to `url` when a counter gets to `threshold` percent of
+/// its limit, once per window of the counter
+#[derive(PartialEq, Eq, Debug)]
+pub struct UtilizationWebhookConfiguration {
+    pub url: String,
+    pub threshold: u8,
+}
+
+impl UtilizationWebhookConfiguration {
+    pub const DEFAULT_THRESHOLD_PERCENT: u8 = 80;
+}
+
+/// The decisions of the async storages are cached in memory, for `ttl`
+/// milliseconds at most, and for up to `max_entries` counters
+#[derive(PartialEq, Eq, Debug)]
+pub struct DecisionCacheConfiguration {
+    pub ttl: u64,
+    pub max_entries: u64,
+}
+
+impl DecisionCacheConfiguration {
+    pub const DEFAULT_MAX_ENTRIES: u64 = 10_000;
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub enum StorageConfiguration {
     InMemory(InMemoryStorageConfiguration),
     Disk(DiskStorageConfiguration),
     Redis(RedisStorageConfiguration),
+    RedisCluster(RedisClusterStorageConfiguration),
     #[cfg(feature = "infinispan")]
     Infinispan(InfinispanStorageConfiguration),
+    #[cfg(feature = "dynamodb")]
+    DynamoDb(DynamoDbStorageConfiguration),
+    #[cfg(feature = "postgres")]
+    Postgres(PostgresStorageConfiguration),
 }
 
 #[derive(PartialEq, Eq, Debug)]
 pub struct InMemoryStorageConfiguration {
     pub cache_size: Option<u64>,
+    pub snapshot: Option<InMemorySnapshotConfiguration>,
+    pub gossip: Option<InMemoryGossipConfiguration>,
+    pub overflow_policy: storage::OverflowPolicy,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct InMemorySnapshotConfiguration {
+    pub path: String,
+    pub interval: u64,
+}
+
+impl InMemorySnapshotConfiguration {
+    pub const DEFAULT_INTERVAL_SEC: u64 = 60;
+}
+
+/// The hits of the counters are gossiped about with the `peers` every
+/// `interval` milliseconds, over UDP on `address`
+#[derive(PartialEq, Eq, Debug)]
+pub struct InMemoryGossipConfiguration {
+    pub address: String,
+    pub peers: Vec<String>,
+    pub interval: u64,
+}
+
+impl InMemoryGossipConfiguration {
+    pub const DEFAULT_INTERVAL_MS: u64 = 500;
 }
 
 #[derive(PartialEq, Eq, Debug)]
 pub struct DiskStorageConfiguration {
     pub path: String,
     pub optimization: storage::disk::OptimizeFor,
+    pub overflow_policy: storage::OverflowPolicy,
 }
 
 #[derive(PartialEq, Eq, Debug)]
 pub struct RedisStorageConfiguration {
     pub url: String,
+    pub key_prefix: Option<String>,
+    pub startup_timeout: Option<u64>,
+    pub hashed_keys: bool,
+    pub pool_size: Option<usize>,
+    pub pipeline: Option<RedisPipelineConfiguration>,
     pub cache: Option<RedisStorageCacheConfiguration>,
+    pub failover_retry_interval: Option<u64>,
+    pub read_replica_url: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct RedisPipelineConfiguration {
+    pub period: u64,
+    pub max_size: usize,
+}
+
+impl RedisPipelineConfiguration {
+    pub const DEFAULT_MAX_SIZE: usize = 100;
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct RedisClusterStorageConfiguration {
+    pub urls: Vec<String>,
+    pub key_prefix: Option<String>,
+    pub startup_timeout: Option<u64>,
+    pub hashed_keys: bool,
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -166,3 +729,17 @@ pub struct InfinispanStorageConfiguration {
     pub cache: Option<String>,
     pub consistency: Option<String>,
 }
+
+#[derive(PartialEq, Eq, Debug)]
+#[cfg(feature = "dynamodb")]
+pub struct DynamoDbStorageConfiguration {
+    pub table_name: String,
+    pub region: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+#[cfg(feature = "postgres")]
+pub struct PostgresStorageConfiguration {
+    pub url: String,
+    pub flushing_period: Option<u64>,
+}
@@ -0,0 +1,110 @@
+use log::Level;
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// Which of the RLS requests are logged at debug level
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize, Apiv2Schema)]
+pub struct DebugLogSampling {
+    /// Logs 1 in `every` requests, all of them when 1
+    pub every: u64,
+    /// Only samples the requests answered as over the limit
+    #[serde(default)]
+    pub over_limit_only: bool,
+}
+
+impl Default for DebugLogSampling {
+    fn default() -> Self {
+        Self {
+            every: 1,
+            over_limit_only: false,
+        }
+    }
+}
+
+/// Samples the requests logged at debug level, not to flood the logs under
+/// load. The sampling can be changed at runtime, through the admin API.
+#[derive(Debug, Default)]
+pub struct DebugLogSampler {
+    sampling: RwLock<DebugLogSampling>,
+    // The requests that could have been logged so far
+    requests: AtomicU64,
+}
+
+impl DebugLogSampler {
+    pub fn new(sampling: DebugLogSampling) -> Self {
+        Self {
+            sampling: RwLock::new(sampling),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether any request would be logged, for them not to be kept around
+    /// otherwise.
+    pub fn is_enabled(&self) -> bool {
+        log_enabled!(Level::Debug)
+    }
+
+    pub fn sampling(&self) -> DebugLogSampling {
+        *self.sampling.read().unwrap()
+    }
+
+    pub fn set_sampling(&self, sampling: DebugLogSampling) {
+        *self.sampling.write().unwrap() = sampling;
+        info!(
+            "Debug log sampling set to 1 in {} requests{}",
+            sampling.every,
+            if sampling.over_limit_only {
+                " over the limit"
+            } else {
+                ""
+            }
+        );
+    }
+
+    /// Logs the request, and how it was answered, when sampled.
+    pub fn log(&self, request: &impl Debug, answer: &str, over_limit: bool) {
+        if self.is_sampled(over_limit) {
+            debug!("Request received: {:?}, answered {}", request, answer);
+        }
+    }
+
+    fn is_sampled(&self, over_limit: bool) -> bool {
+        let sampling = self.sampling();
+        if sampling.over_limit_only && !over_limit {
+            return false;
+        }
+        let request = self.requests.fetch_add(1, Ordering::Relaxed);
+        request % sampling.every.max(1) == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_1_in_every_requests() {
+        let sampler = DebugLogSampler::new(DebugLogSampling {
+            every: 3,
+            over_limit_only: false,
+        });
+        let sampled: Vec<bool> = (0..6).map(|_| sampler.is_sampled(false)).collect();
+        assert_eq!(sampled, vec![true, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn samples_the_requests_over_the_limit_only_when_told_to() {
+        let sampler = DebugLogSampler::default();
+        assert!(sampler.is_sampled(false));
+
+        sampler.set_sampling(DebugLogSampling {
+            every: 1,
+            over_limit_only: true,
+        });
+        assert!(!sampler.is_sampled(false));
+        assert!(sampler.is_sampled(true));
+    }
+}
@@ -0,0 +1,384 @@
+use async_trait::async_trait;
+use limitador::counter::Counter;
+use limitador::limit::{Limit, WindowType};
+use limitador::storage::{AsyncCounterStorage, Authorization, StorageErr};
+use moka::sync::Cache;
+use moka::Expiry;
+use std::collections::HashSet;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::metrics;
+
+// What the storage last said about a counter: the hits it had remaining, and
+// when its window resets. It's forgotten as soon as the counter is written to
+// other than through a check.
+#[derive(Clone)]
+struct Decision {
+    max_value: i64,
+    remaining: i64,
+    expires_at: SystemTime,
+}
+
+impl Decision {
+    fn limits(&self, counter: &Counter, delta: i64) -> bool {
+        self.remaining < counter.hits_for(delta)
+    }
+}
+
+// The hits of a fixed window only ever grow until it resets, so a counter
+// without any hits remaining stays over its limit until then. All the other
+// decisions are only trusted for the TTL configured.
+struct DecisionExpiry {
+    ttl: Duration,
+}
+
+impl DecisionExpiry {
+    fn lifetime(&self, counter: &Counter, decision: &Decision) -> Duration {
+        let until_reset = decision
+            .expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+        match counter.window_type() {
+            WindowType::Fixed if decision.remaining <= 0 => until_reset,
+            WindowType::Fixed => until_reset.min(self.ttl),
+            WindowType::Sliding
+            | WindowType::TokenBucket
+            | WindowType::LeakyBucket
+            | WindowType::Distinct => self.ttl,
+        }
+    }
+}
+
+impl Expiry<Counter, Decision> for DecisionExpiry {
+    fn expire_after_create(
+        &self,
+        counter: &Counter,
+        decision: &Decision,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(self.lifetime(counter, decision))
+    }
+
+    fn expire_after_update(
+        &self,
+        counter: &Counter,
+        decision: &Decision,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(self.lifetime(counter, decision))
+    }
+}
+
+/// Answers from memory the checks whose outcome the `storage` already told:
+/// the requests hitting counters known to be over their limit are limited
+/// without reaching it, and the counters recently read are checked against
+/// the hits they had remaining then. Up to `max_entries` counters are kept,
+/// each for `ttl` at most, unless over the limit of a fixed window, which is
+/// then kept until the window resets.
+pub struct AsyncDecisionCache {
+    storage: Box<dyn AsyncCounterStorage>,
+    decisions: Cache<Counter, Decision>,
+}
+
+impl AsyncDecisionCache {
+    pub fn new(storage: Box<dyn AsyncCounterStorage>, ttl: Duration, max_entries: u64) -> Self {
+        Self {
+            storage,
+            decisions: Cache::builder()
+                .max_capacity(max_entries)
+                .expire_after(DecisionExpiry { ttl })
+                .build(),
+        }
+    }
+
+    // A limit updated with a new max value invalidates what was known of its
+    // counters
+    fn decision_for(&self, counter: &Counter) -> Option<Decision> {
+        self.decisions
+            .get(counter)
+            .filter(|decision| decision.max_value == counter.max_value())
+    }
+
+    fn limited_from_cache(
+        &self,
+        counters: &mut [Counter],
+        delta: i64,
+        load_counters: bool,
+    ) -> Option<Authorization> {
+        let decisions: Vec<Option<Decision>> = counters
+            .iter()
+            .map(|counter| self.decision_for(counter))
+            .collect();
+        let limited = counters
+            .iter()
+            .zip(&decisions)
+            .find(|(counter, decision)| matches!(decision, Some(d) if d.limits(counter, delta)))
+            .map(|(counter, _)| {
+                Authorization::Limited(counter.limit().name().map(str::to_owned))
+            })?;
+
+        let now = SystemTime::now();
+        for (counter, decision) in counters.iter_mut().zip(decisions) {
+            if let Some(decision) = decision {
+                counter.set_approximate();
+                if load_counters {
+                    counter.set_remaining(decision.remaining - counter.hits_for(delta));
+                    counter.set_expires_in(
+                        decision
+                            .expires_at
+                            .duration_since(now)
+                            .unwrap_or(Duration::ZERO),
+                    );
+                }
+            }
+        }
+        Some(limited)
+    }
+
+    fn remember(&self, counters: &[Counter], delta: i64, authorization: &Authorization) {
+        let now = SystemTime::now();
+        for counter in counters {
+            // Whether a counter of distinct values limits a request depends
+            // on the value of the request, that the counter doesn't tell
+            if counter.window_type() == WindowType::Distinct {
+                continue;
+            }
+            if let (Some(remaining), Some(expires_in)) = (counter.remaining(), counter.expires_in())
+            {
+                // The hits of a limited request aren't added
+                let remaining = match authorization {
+                    Authorization::Ok => remaining,
+                    Authorization::Limited(_) => remaining + counter.hits_for(delta),
+                };
+                self.decisions.insert(
+                    counter.clone(),
+                    Decision {
+                        max_value: counter.max_value(),
+                        remaining,
+                        expires_at: now + expires_in,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncCounterStorage for AsyncDecisionCache {
+    async fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        if let Some(decision) = self.decision_for(counter) {
+            metrics::incr_decision_cache_hits();
+            return Ok(!decision.limits(counter, delta));
+        }
+        metrics::incr_decision_cache_misses();
+        self.storage.is_within_limits(counter, delta).await
+    }
+
+    async fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        self.decisions.invalidate(counter);
+        self.storage.update_counter(counter, delta).await
+    }
+
+    async fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        if let Some(limited) = self.limited_from_cache(counters, delta, load_counters) {
+            metrics::incr_decision_cache_hits();
+            return Ok(limited);
+        }
+        metrics::incr_decision_cache_misses();
+
+        // The counters are always loaded, as that's what gets remembered of
+        // them
+        let authorization = self.storage.check_and_update(counters, delta, true).await?;
+        self.remember(counters, delta, &authorization);
+        Ok(authorization)
+    }
+
+    // The checks answered from the cache are left out of the batch sent to
+    // the storage, whose answers are remembered as in `check_and_update`
+    async fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        let authorizations: Vec<Option<Authorization>> = batch
+            .iter_mut()
+            .map(|(counters, delta)| self.limited_from_cache(counters, *delta, load_counters))
+            .collect();
+
+        let mut not_cached: Vec<(Vec<Counter>, i64)> = batch
+            .iter_mut()
+            .zip(&authorizations)
+            .filter(|(_, authorization)| authorization.is_none())
+            .map(|((counters, delta), _)| (std::mem::take(counters), *delta))
+            .collect();
+        for authorization in &authorizations {
+            match authorization {
+                Some(_) => metrics::incr_decision_cache_hits(),
+                None => metrics::incr_decision_cache_misses(),
+            }
+        }
+        if not_cached.is_empty() {
+            return Ok(authorizations.into_iter().flatten().collect());
+        }
+
+        let checked = self
+            .storage
+            .check_and_update_batch(&mut not_cached, true)
+            .await;
+        // The counters are given back whatever the storage answered
+        let mut not_cached = not_cached.into_iter();
+        for ((counters, _), authorization) in batch.iter_mut().zip(&authorizations) {
+            if authorization.is_none() {
+                if let Some((checked_counters, _)) = not_cached.next() {
+                    *counters = checked_counters;
+                }
+            }
+        }
+
+        let mut checked = checked?.into_iter();
+        Ok(batch
+            .iter()
+            .zip(authorizations)
+            .map(|((counters, delta), authorization)| {
+                authorization.unwrap_or_else(|| {
+                    let authorization = checked.next().expect("an answer per check");
+                    self.remember(counters, *delta, &authorization);
+                    authorization
+                })
+            })
+            .collect())
+    }
+
+    async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        self.decisions.invalidate(counter);
+        self.storage
+            .set_counter(counter, remaining, expires_in)
+            .await
+    }
+
+    async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        self.decisions.invalidate(counter);
+        self.storage.delete_counter(counter).await
+    }
+
+    async fn get_counters(&self, limits: HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
+        self.storage.get_counters(limits).await
+    }
+
+    async fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+        self.decisions.invalidate_all();
+        self.storage.delete_counters(limits).await
+    }
+
+    async fn clear(&self) -> Result<(), StorageErr> {
+        self.decisions.invalidate_all();
+        self.storage.clear().await
+    }
+
+    async fn ping(&self) -> Result<(), StorageErr> {
+        self.storage.ping().await
+    }
+
+    async fn flush(&self) -> Result<(), StorageErr> {
+        self.storage.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use limitador::storage::composite::AsyncCounterStorageAdapter;
+    use limitador::storage::in_memory::InMemoryStorage;
+    use std::collections::HashMap;
+
+    fn cache() -> AsyncDecisionCache {
+        AsyncDecisionCache::new(
+            Box::new(AsyncCounterStorageAdapter::new(
+                Box::<InMemoryStorage>::default(),
+            )),
+            Duration::from_secs(60),
+            100,
+        )
+    }
+
+    fn counter(max_value: i64) -> Counter {
+        let limit = Limit::new("ns", max_value, 60, Vec::<String>::default(), vec!["id"]);
+        Counter::new(limit, HashMap::from([("id".to_string(), "1".to_string())]))
+    }
+
+    #[tokio::test]
+    async fn limits_from_memory_the_counters_known_to_be_over_their_limit() {
+        let cache = cache();
+        let counter = counter(1);
+
+        let mut counters = vec![counter.clone()];
+        let authorization = cache.check_and_update(&mut counters, 1, false).await;
+        assert!(matches!(authorization, Ok(Authorization::Ok)));
+
+        // Whatever the storage says, the window doesn't reset for a minute
+        cache
+            .storage
+            .set_counter(&counter, 1, Duration::from_secs(60))
+            .await
+            .unwrap();
+        let mut counters = vec![counter.clone()];
+        let authorization = cache.check_and_update(&mut counters, 1, true).await;
+        assert!(matches!(authorization, Ok(Authorization::Limited(_))));
+        assert_eq!(counters[0].remaining(), Some(-1));
+        assert!(counters[0].is_approximate());
+        assert!(!cache.is_within_limits(&counter, 1).await.unwrap());
+        assert!(cache.is_within_limits(&counter, 0).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn limits_from_memory_the_checks_of_a_batch_over_their_limit() {
+        let cache = cache();
+        let counter = counter(1);
+
+        let mut batch = vec![(vec![counter.clone()], 1)];
+        let authorizations = cache.check_and_update_batch(&mut batch, false).await;
+        assert!(matches!(authorizations.as_deref(), Ok([Authorization::Ok])));
+
+        cache
+            .storage
+            .set_counter(&counter, 1, Duration::from_secs(60))
+            .await
+            .unwrap();
+        let mut batch = vec![(vec![counter.clone()], 1), (vec![counter.clone()], 0)];
+        let authorizations = cache.check_and_update_batch(&mut batch, true).await;
+        assert!(matches!(
+            authorizations.as_deref(),
+            Ok([Authorization::Limited(_), Authorization::Ok])
+        ));
+        assert_eq!(batch[0].0[0].remaining(), Some(-1));
+        assert!(batch[0].0[0].is_approximate());
+        assert_eq!(batch[1].0[0].remaining(), Some(1));
+        assert!(!batch[1].0[0].is_approximate());
+    }
+
+    #[tokio::test]
+    async fn forgets_the_counters_when_their_limit_changes() {
+        let cache = cache();
+
+        let mut counters = vec![counter(1)];
+        cache
+            .check_and_update(&mut counters, 1, false)
+            .await
+            .unwrap();
+
+        let mut counters = vec![counter(2)];
+        let authorization = cache.check_and_update(&mut counters, 1, false).await;
+        assert!(matches!(authorization, Ok(Authorization::Ok)));
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use tonic::{Response, Status};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::envoy_rls::server::envoy::service::ratelimit::v3::rate_limit_response::Code;
+use crate::envoy_rls::server::envoy::service::ratelimit::v3::{
+    RateLimitRequest, RateLimitResponse,
+};
+
+// The access log is made of the events of this target, that only the layer
+// built by `layer` outputs
+const TARGET: &str = "limitador::access_log";
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Logs one JSON line per decision on an RLS request, flagging the approximate
+/// ones
+pub struct AccessLog {
+    level: Level,
+    redacted_entries: HashSet<String>,
+    hashed_entries: HashSet<String>,
+}
+
+impl AccessLog {
+    pub fn new(
+        level: Level,
+        redacted_entries: HashSet<String>,
+        hashed_entries: HashSet<String>,
+    ) -> Self {
+        Self {
+            level,
+            redacted_entries,
+            hashed_entries,
+        }
+    }
+
+    pub fn log(
+        &self,
+        request: &RateLimitRequest,
+        result: &Result<Response<RateLimitResponse>, Status>,
+        approximate: bool,
+        latency: Duration,
+    ) {
+        let (code, statuses) = match result {
+            Ok(response) => {
+                let response = response.get_ref();
+                let code = Code::try_from(response.overall_code).unwrap_or(Code::Unknown);
+                (code.as_str_name(), response.statuses.as_slice())
+            }
+            Err(status) => (error_code(status), [].as_slice()),
+        };
+
+        let descriptors: Vec<Value> = request
+            .descriptors
+            .iter()
+            .enumerate()
+            .map(|(i, descriptor)| {
+                let entries: Map<String, Value> = descriptor
+                    .entries
+                    .iter()
+                    .map(|entry| (entry.key.clone(), self.value_of(&entry.key, &entry.value)))
+                    .collect();
+                let status = statuses.get(i);
+                let code = status.map(|status| {
+                    Code::try_from(status.code)
+                        .unwrap_or(Code::Unknown)
+                        .as_str_name()
+                });
+                let limit = status
+                    .and_then(|status| status.current_limit.as_ref())
+                    .map(|limit| {
+                        json!({
+                            "name": limit.name,
+                            "requests_per_unit": limit.requests_per_unit,
+                            "unit": limit.unit().as_str_name(),
+                        })
+                    });
+                json!({ "entries": entries, "code": code, "limit": limit })
+            })
+            .collect();
+        let descriptors = Value::Array(descriptors).to_string();
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+
+        macro_rules! event {
+            ($level:ident) => {
+                tracing::$level!(
+                    target: TARGET,
+                    namespace = %request.domain,
+                    descriptors,
+                    hits_addend = request.hits_addend,
+                    code,
+                    approximate,
+                    latency_ms,
+                )
+            };
+        }
+        match self.level {
+            Level::ERROR => event!(error),
+            Level::WARN => event!(warn),
+            Level::INFO => event!(info),
+            Level::DEBUG => event!(debug),
+            Level::TRACE => event!(trace),
+        }
+    }
+
+    fn value_of(&self, key: &str, value: &str) -> Value {
+        if self.redacted_entries.contains(key) {
+            REDACTED.into()
+        } else if self.hashed_entries.contains(key) {
+            let mut digest = String::with_capacity(64);
+            for byte in Sha256::digest(value.as_bytes()) {
+                let _ = write!(digest, "{byte:02x}");
+            }
+            digest.into()
+        } else {
+            value.into()
+        }
+    }
+}
+
+/// Outputs the access log, and nothing else, as JSON lines on stdout
+pub fn layer<S>(level: Level) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(false)
+        .with_span_list(false)
+        .with_filter(Targets::new().with_target(TARGET, level))
+}
+
+fn error_code(status: &Status) -> &'static str {
+    match status.code() {
+        tonic::Code::Unavailable => "UNAVAILABLE",
+        tonic::Code::InvalidArgument => "INVALID_ARGUMENT",
+        tonic::Code::FailedPrecondition => "FAILED_PRECONDITION",
+        tonic::Code::Internal => "INTERNAL",
+        _ => "ERROR",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_or_hashes_the_values_of_the_entries_configured() {
+        let access_log = AccessLog::new(
+            Level::INFO,
+            HashSet::from(["token".to_string()]),
+            HashSet::from(["user".to_string()]),
+        );
+
+        assert_eq!(access_log.value_of("token", "secret"), json!(REDACTED));
+        assert_eq!(
+            access_log.value_of("user", "alice"),
+            json!("2bd806c97f0e00af1a1fc3328fa763a9269723c8db8fac4f93af71db186d6e90")
+        );
+        assert_eq!(access_log.value_of("path", "/a"), json!("/a"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tonic::{Request, Response, Status};
+
+use limitador::counter::Counter as LimitadorCounter;
+use limitador::limit::Namespace;
+
+use crate::envoy_rls::counters::pb::counter_service_server::CounterService;
+use crate::envoy_rls::counters::pb::{
+    Counter, DescriptorCounters, GetCountersRequest, GetCountersResponse,
+};
+use crate::envoy_rls::server::MAX_VALUE_OVERRIDE_KEY;
+use crate::Limiter;
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+pub mod pb {
+    tonic::include_proto!("limitador.service.counters.v1");
+}
+
+pub use pb::counter_service_server::CounterServiceServer;
+
+/// Answers where the counters of a descriptor stand, looking them up the way
+/// a check consuming no hits does
+pub struct Counters {
+    limiter: Arc<Limiter>,
+}
+
+impl Counters {
+    pub fn new(limiter: Arc<Limiter>) -> Self {
+        Self { limiter }
+    }
+}
+
+#[tonic::async_trait]
+impl CounterService for Counters {
+    async fn get_counters(
+        &self,
+        request: Request<GetCountersRequest>,
+    ) -> Result<Response<GetCountersResponse>, Status> {
+        let req = request.into_inner();
+        if req.domain.is_empty() {
+            return Err(Status::invalid_argument("the domain must be set"));
+        }
+        let namespace: Namespace = req.domain.into();
+
+        let descriptors_values: Vec<HashMap<String, String>> = req
+            .descriptors
+            .into_iter()
+            .map(|descriptor| {
+                descriptor
+                    .entries
+                    .into_iter()
+                    .filter(|entry| entry.key != MAX_VALUE_OVERRIDE_KEY)
+                    .map(|entry| (entry.key, entry.value))
+                    .collect()
+            })
+            .collect();
+        let batch: Vec<_> = descriptors_values
+            .iter()
+            .map(|values| (&namespace, values, 0))
+            .collect();
+
+        let results = self
+            .limiter
+            .check_rate_limited_and_update_batch(&batch, true)
+            .await
+            .map_err(|e| {
+                error!("Error: {:?}", e);
+                Status::unavailable("Service unavailable")
+            })?;
+
+        let descriptors = results
+            .into_iter()
+            .map(|result| DescriptorCounters {
+                limited: result
+                    .counters
+                    .iter()
+                    .any(|counter| remaining(counter) < counter.hits_for(1)),
+                counters: result.counters.iter().map(to_counter).collect(),
+            })
+            .collect();
+        Ok(Response::new(GetCountersResponse { descriptors }))
+    }
+}
+
+// The counters that aren't counting yet have all their hits left
+fn remaining(counter: &LimitadorCounter) -> i64 {
+    counter.remaining().unwrap_or(counter.max_value())
+}
+
+fn to_counter(counter: &LimitadorCounter) -> Counter {
+    let remaining = remaining(counter);
+    Counter {
+        limit_name: counter.limit().name().unwrap_or_default().to_string(),
+        max_value: counter.max_value(),
+        count: counter.max_value() - remaining,
+        remaining,
+        window: prost_types::Duration::try_from(counter.window()).ok(),
+        duration_until_reset: counter
+            .expires_in()
+            .and_then(|duration| prost_types::Duration::try_from(duration).ok()),
+        variables: counter.set_variables().clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_rls::counters::pb::descriptor::Entry;
+    use crate::envoy_rls::counters::pb::Descriptor;
+    use limitador::limit::Limit;
+    use limitador::RateLimiter;
+
+    fn descriptor(entries: &[(&str, &str)]) -> Descriptor {
+        Descriptor {
+            entries: entries
+                .iter()
+                .map(|(key, value)| Entry {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn tells_the_counters_of_each_descriptor_without_counting() {
+        let namespace = "test_namespace";
+        let mut limit = Limit::new(
+            namespace,
+            2,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+        limit.set_name("per-app".to_string());
+        let limiter = RateLimiter::new(100);
+        limiter.add_limit(limit);
+        let values = HashMap::from([
+            ("req.method".to_string(), "GET".to_string()),
+            ("app_id".to_string(), "1".to_string()),
+        ]);
+        limiter
+            .update_counters(&namespace.into(), &values, 2)
+            .unwrap();
+        let counters = Counters::new(Arc::new(Limiter::Blocking(limiter)));
+
+        let request = GetCountersRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![
+                descriptor(&[("req.method", "GET"), ("app_id", "1")]),
+                descriptor(&[("req.method", "GET"), ("app_id", "2")]),
+                descriptor(&[("req.method", "POST"), ("app_id", "1")]),
+            ],
+        };
+        for _ in 0..2 {
+            let response = counters
+                .get_counters(Request::new(request.clone()))
+                .await
+                .unwrap()
+                .into_inner();
+            let [over, fresh, none] = &response.descriptors[..] else {
+                panic!("one answer per descriptor, got {response:?}");
+            };
+
+            assert!(over.limited);
+            assert_eq!(over.counters.len(), 1);
+            let counter = &over.counters[0];
+            assert_eq!(counter.limit_name, "per-app");
+            assert_eq!(
+                (counter.max_value, counter.count, counter.remaining),
+                (2, 2, 0)
+            );
+            assert_eq!(counter.window.as_ref().unwrap().seconds, 60);
+            assert!(counter.duration_until_reset.as_ref().unwrap().seconds <= 60);
+            assert_eq!(counter.variables["app_id"], "1");
+
+            assert!(!fresh.limited);
+            assert_eq!(fresh.counters.len(), 1);
+            assert_eq!(fresh.counters[0].count, 0);
+            assert_eq!(fresh.counters[0].remaining, 2);
+
+            assert!(!none.limited);
+            assert!(none.counters.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_without_a_domain() {
+        let counters = Counters::new(Arc::new(Limiter::Blocking(RateLimiter::new(100))));
+        let status = counters
+            .get_counters(Request::new(GetCountersRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
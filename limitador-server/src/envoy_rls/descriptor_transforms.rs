@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use crate::envoy_rls::server::envoy::service::ratelimit::v3::RateLimitRequest;
+
+/// How the value of a descriptor entry is normalized before it's matched
+#[derive(Debug, Clone)]
+pub enum Transform {
+    Lowercase,
+    Trim,
+    /// Replaces all the matches of the pattern, the replacement possibly
+    /// referring to its capture groups, e.g. `$1`
+    Replace(Regex, String),
+}
+
+impl Transform {
+    fn apply(&self, value: &str) -> String {
+        match self {
+            Self::Lowercase => value.to_lowercase(),
+            Self::Trim => value.trim().to_string(),
+            Self::Replace(pattern, replacement) => pattern
+                .replace_all(value, replacement.as_str())
+                .into_owned(),
+        }
+    }
+}
+
+/// The transforms applied to the values of the descriptor entries of a key,
+/// before anything else reads them: the namespace entry, the bypass entries,
+/// and the values the limits are matched against and their counters qualified
+/// by. The transforms of a key apply in the order they were given in.
+#[derive(Debug, Clone, Default)]
+pub struct DescriptorTransforms {
+    transforms: HashMap<String, Vec<Transform>>,
+}
+
+impl DescriptorTransforms {
+    /// Parses the transforms, each as `KEY:lowercase`, `KEY:trim` or
+    /// `KEY:replace:PATTERN:REPLACEMENT`, the replacement being what's past
+    /// the last `:`.
+    pub fn parse<'a>(transforms: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        for transform in transforms {
+            let invalid =
+                |reason: &str| format!("invalid descriptor transform '{transform}': {reason}");
+            let Some((key, spec)) = transform.split_once(':').filter(|(key, _)| !key.is_empty())
+            else {
+                return Err(invalid("expected KEY:TRANSFORM"));
+            };
+            let transform = match spec.split_once(':') {
+                None if spec == "lowercase" => Transform::Lowercase,
+                None if spec == "trim" => Transform::Trim,
+                Some(("replace", rest)) => {
+                    let Some((pattern, replacement)) = rest.rsplit_once(':') else {
+                        return Err(invalid("expected KEY:replace:PATTERN:REPLACEMENT"));
+                    };
+                    let pattern = Regex::new(pattern).map_err(|e| invalid(&e.to_string()))?;
+                    Transform::Replace(pattern, replacement.to_string())
+                }
+                _ => return Err(invalid("expected one of lowercase, trim or replace")),
+            };
+            parsed
+                .transforms
+                .entry(key.to_string())
+                .or_default()
+                .push(transform);
+        }
+        Ok(parsed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+
+    pub fn apply(&self, req: &mut RateLimitRequest) {
+        if self.is_empty() {
+            return;
+        }
+        for entry in req
+            .descriptors
+            .iter_mut()
+            .flat_map(|descriptor| &mut descriptor.entries)
+        {
+            if let Some(transforms) = self.transforms.get(&entry.key) {
+                for transform in transforms {
+                    entry.value = transform.apply(&entry.value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::envoy_rls::server::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
+    use crate::envoy_rls::server::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+
+    use super::*;
+
+    fn transformed(transforms: &[&str], entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        let mut req = RateLimitRequest {
+            domain: "test_namespace".to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: entries
+                    .iter()
+                    .map(|(key, value)| Entry {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                    })
+                    .collect(),
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        };
+        DescriptorTransforms::parse(transforms.iter().copied())
+            .unwrap()
+            .apply(&mut req);
+        req.descriptors[0]
+            .entries
+            .iter()
+            .map(|entry| (entry.key.clone(), entry.value.clone()))
+            .collect()
+    }
+
+    fn entries(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn lowercases_the_values_of_the_key() {
+        assert_eq!(
+            transformed(
+                &["host:lowercase"],
+                &[("host", "Example.ORG"), ("user", "Bob")]
+            ),
+            entries(&[("host", "example.org"), ("user", "Bob")])
+        );
+    }
+
+    #[test]
+    fn trims_the_values_of_the_key() {
+        assert_eq!(
+            transformed(&["user:trim"], &[("user", "  bob\t")]),
+            entries(&[("user", "bob")])
+        );
+    }
+
+    #[test]
+    fn replaces_the_matches_of_the_pattern() {
+        assert_eq!(
+            transformed(&["host:replace::\\d+$:"], &[("host", "example.org:8080")]),
+            entries(&[("host", "example.org")])
+        );
+        assert_eq!(
+            transformed(
+                &["size:replace:^(\\d)\\d{3}$:${1}k"],
+                &[("size", "4096"), ("size", "512")]
+            ),
+            entries(&[("size", "4k"), ("size", "512")])
+        );
+    }
+
+    #[test]
+    fn applies_the_transforms_of_a_key_in_order() {
+        assert_eq!(
+            transformed(
+                &["host:trim", "host:replace:^www\\.:", "host:lowercase"],
+                &[("host", " www.Example.org ")]
+            ),
+            entries(&[("host", "example.org")])
+        );
+        // Not lowercased yet, the pattern doesn't match
+        assert_eq!(
+            transformed(
+                &["host:replace:^www\\.:", "host:lowercase"],
+                &[("host", "WWW.Example.org")]
+            ),
+            entries(&[("host", "www.example.org")])
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_transforms() {
+        for transform in [
+            "host",
+            ":lowercase",
+            "host:uppercase",
+            "host:replace:no_replacement",
+            "host:replace:(:x",
+        ] {
+            assert!(
+                DescriptorTransforms::parse([transform]).is_err(),
+                "{transform}"
+            );
+        }
+    }
+}
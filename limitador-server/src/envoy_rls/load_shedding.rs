@@ -0,0 +1,160 @@
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::server::NamedService;
+use tonic::Status;
+use tower::Layer;
+
+use crate::metrics;
+
+/// Answers `RESOURCE_EXHAUSTED` to the requests coming in while `max` of them
+/// are already in flight, instead of queueing them. Without a `max`, the
+/// requests in flight are only counted.
+#[derive(Clone)]
+pub struct LoadSheddingLayer {
+    permits: Option<Arc<Semaphore>>,
+}
+
+impl LoadSheddingLayer {
+    pub fn new(max: Option<usize>) -> Self {
+        if let Some(max) = max {
+            metrics::set_rls_max_concurrent_requests(max);
+        }
+        Self {
+            permits: max.map(|max| Arc::new(Semaphore::new(max))),
+        }
+    }
+}
+
+impl<S> Layer<S> for LoadSheddingLayer {
+    type Service = LoadShedding<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LoadShedding {
+            inner,
+            permits: self.permits.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LoadShedding<S> {
+    inner: S,
+    permits: Option<Arc<Semaphore>>,
+}
+
+impl<S, B> Service<http::Request<B>> for LoadShedding<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let permit = match &self.permits {
+            Some(permits) => match Arc::clone(permits).try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    metrics::incr_rls_shed_requests();
+                    let status = Status::resource_exhausted("Too many requests in flight");
+                    return Box::pin(async move { Ok(status.to_http()) });
+                }
+            },
+            None => None,
+        };
+
+        let in_flight = InFlight::new(permit);
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let response = response.await;
+            drop(in_flight);
+            response
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for LoadShedding<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+// Accounts for a request until its response is ready, or it's cancelled
+struct InFlight {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl InFlight {
+    fn new(permit: Option<OwnedSemaphorePermit>) -> Self {
+        metrics::incr_rls_requests_in_flight();
+        Self { _permit: permit }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        metrics::decr_rls_requests_in_flight();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tokio::sync::oneshot;
+
+    // Answers once told to, to keep the requests in flight meanwhile
+    #[derive(Clone)]
+    struct Held;
+
+    impl Service<http::Request<oneshot::Receiver<()>>> for Held {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http::Request<oneshot::Receiver<()>>) -> Self::Future {
+            Box::pin(async move {
+                let _ = req.into_body().await;
+                Ok(Status::ok("").to_http())
+            })
+        }
+    }
+
+    fn grpc_status(response: &http::Response<BoxBody>) -> &str {
+        response.headers()["grpc-status"].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn sheds_the_requests_over_the_max_in_flight() {
+        let mut svc = LoadSheddingLayer::new(Some(1)).layer(Held);
+
+        let (answer, held) = oneshot::channel();
+        let first = svc.call(http::Request::new(held));
+
+        let (_, shed) = oneshot::channel();
+        let shed = svc.call(http::Request::new(shed)).await.unwrap();
+        assert_eq!(
+            grpc_status(&shed),
+            (tonic::Code::ResourceExhausted as i32).to_string()
+        );
+
+        answer.send(()).unwrap();
+        assert_eq!(grpc_status(&first.await.unwrap()), "0");
+
+        let (answer, held) = oneshot::channel();
+        answer.send(()).unwrap();
+        let next = svc.call(http::Request::new(held)).await.unwrap();
+        assert_eq!(grpc_status(&next), "0");
+    }
+}
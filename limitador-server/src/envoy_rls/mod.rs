@@ -1,2 +1,8 @@
+pub mod access_log;
+pub mod counters;
+pub mod descriptor_transforms;
 mod envoy_types;
+mod load_shedding;
+pub mod reservations;
 pub mod server;
+pub mod utilization;
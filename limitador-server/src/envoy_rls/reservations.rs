@@ -0,0 +1,58 @@
+use std::sync::Arc;
+
+use tonic::{Request, Response, Status};
+
+use crate::envoy_rls::reservations::pb::reservation_service_server::ReservationService;
+use crate::envoy_rls::reservations::pb::{ReservationRequest, ReservationResponse};
+use crate::reservations::Reservations;
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+pub mod pb {
+    tonic::include_proto!("limitador.service.reservations.v1");
+}
+
+pub use pb::reservation_service_server::ReservationServiceServer;
+
+/// Confirms or releases the reservations of the requests checked in two
+/// phases, as told by the outcome of their response
+pub struct Outcomes {
+    reservations: Arc<Reservations>,
+}
+
+impl Outcomes {
+    pub fn new(reservations: Arc<Reservations>) -> Self {
+        Self { reservations }
+    }
+}
+
+#[tonic::async_trait]
+impl ReservationService for Outcomes {
+    async fn confirm(
+        &self,
+        request: Request<ReservationRequest>,
+    ) -> Result<Response<ReservationResponse>, Status> {
+        if self.reservations.confirm(&request.into_inner().id) {
+            Ok(Response::new(ReservationResponse {}))
+        } else {
+            Err(no_such_reservation())
+        }
+    }
+
+    async fn release(
+        &self,
+        request: Request<ReservationRequest>,
+    ) -> Result<Response<ReservationResponse>, Status> {
+        match self.reservations.release(&request.into_inner().id).await {
+            Ok(true) => Ok(Response::new(ReservationResponse {})),
+            Ok(false) => Err(no_such_reservation()),
+            Err(e) => {
+                error!("Error: {:?}", e);
+                Err(Status::unavailable("Service unavailable"))
+            }
+        }
+    }
+}
+
+fn no_such_reservation() -> Status {
+    Status::not_found("no such reservation pending, it may have expired")
+}
@@ -1,137 +1,1056 @@
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::future::Future;
+use std::str::FromStr;
 use std::sync::Arc;
-
-use tonic::{transport, transport::Server, Request, Response, Status};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::future::Either;
+use futures::FutureExt;
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use tonic::codec::CompressionEncoding;
+use tonic::metadata::{KeyRef, MetadataMap};
+use tonic::server::NamedService;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+use tonic_health::ServingStatus;
+use tower::Layer;
+use tracing::{field, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 use limitador::counter::Counter;
-
+use limitador::errors::LimitadorError;
+use limitador::limit::{CalendarPeriod, Namespace};
+use limitador::{CheckOutcome, CheckResult, OverridingCheck};
+
+use crate::debug_sampling::DebugLogSampler;
+use crate::envoy_rls::access_log::AccessLog;
+use crate::envoy_rls::counters::{CounterServiceServer, Counters};
+use crate::envoy_rls::descriptor_transforms::DescriptorTransforms;
+use crate::envoy_rls::load_shedding::LoadSheddingLayer;
+use crate::envoy_rls::reservations::{Outcomes, ReservationServiceServer};
 use crate::envoy_rls::server::envoy::config::core::v3::HeaderValue;
-use crate::envoy_rls::server::envoy::service::ratelimit::v3::rate_limit_response::Code;
+use crate::envoy_rls::server::envoy::service::ratelimit::v3::rate_limit_response::rate_limit::Unit;
+use crate::envoy_rls::server::envoy::service::ratelimit::v3::rate_limit_response::{
+    quota, Code, DescriptorStatus, Quota, RateLimit,
+};
 use crate::envoy_rls::server::envoy::service::ratelimit::v3::rate_limit_service_server::{
     RateLimitService, RateLimitServiceServer,
 };
 use crate::envoy_rls::server::envoy::service::ratelimit::v3::{
     RateLimitRequest, RateLimitResponse,
 };
-use crate::Limiter;
+use crate::envoy_rls::utilization::UtilizationWebhook;
+use crate::kill_switch::{KillSwitch, KillSwitchMode};
+use crate::over_limit::OverLimitBody;
+use crate::reservations::Reservations;
+use crate::{metrics, Limiter};
 
 include!("envoy_types.rs");
 
+const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("rls_descriptor");
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum RateLimitHeaders {
     None,
+    /// Draft 03 headers, using the legacy `X-RateLimit-*` names
     DraftVersion03,
+    /// Draft 03 headers, using the `RateLimit-*` names defined by the draft
+    Standard,
+}
+
+impl FromStr for RateLimitHeaders {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "NONE" => Ok(Self::None),
+            "DRAFT_VERSION_03" => Ok(Self::DraftVersion03),
+            "STANDARD" => Ok(Self::Standard),
+            _ => Err(format!(
+                "invalid rate limit headers '{s}': expected NONE, DRAFT_VERSION_03 or STANDARD"
+            )),
+        }
+    }
+}
+
+impl RateLimitHeaders {
+    /// Parses the headers of the namespaces that get other ones than all the
+    /// others, each as `NAMESPACE=HEADERS`, e.g. to only add them to the
+    /// answers of the namespaces whose clients want to know of their usage.
+    pub fn parse_by_namespace<'a>(
+        entries: impl IntoIterator<Item = &'a str>,
+    ) -> Result<HashMap<Namespace, Self>, String> {
+        let mut by_namespace = HashMap::new();
+        for entry in entries {
+            let Some((namespace, headers)) = entry
+                .split_once('=')
+                .filter(|(namespace, _)| !namespace.is_empty())
+            else {
+                return Err(format!(
+                    "invalid rate limit headers of a namespace '{entry}': expected NAMESPACE=HEADERS"
+                ));
+            };
+            by_namespace.insert(namespace.into(), headers.parse()?);
+        }
+        Ok(by_namespace)
+    }
+}
+
+/// What to answer when the limits storage can't be reached
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub enum FailureMode {
+    /// Let the request through, i.e. fail open
+    Allow,
+    /// Reject the request as being over the limit, i.e. fail closed
+    Deny,
+    /// Return an "unavailable" error, leaving the decision to Envoy's
+    /// `failure_mode_deny` setting
+    #[default]
+    Passthrough,
+}
+
+/// What to answer to the requests without a domain, nor a namespace entry,
+/// which no limits apply to
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum EmptyDomainAnswer {
+    /// `UNKNOWN`, which Envoy lets through
+    #[default]
+    Unknown,
+    /// `OK`, letting the request through
+    Ok,
+    /// `OVER_LIMIT`, rejecting the request
+    Deny,
+    /// An `INVALID_ARGUMENT` error, leaving the decision to Envoy's
+    /// `failure_mode_deny` setting
+    Error,
+}
+
+/// How the decisions made under degraded conditions are told apart from the
+/// others: the ones made on approximate counters, e.g. answered from a cache
+/// of a Redis storage, and the ones the failure mode made for storage errors
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct ApproximateDecisions {
+    /// Answers `UNKNOWN`, rather than `OVER_LIMIT`, to the requests limited on
+    /// approximate counters, which Envoy then lets through. The failure mode
+    /// still decides the code of the answers to storage errors
+    pub unknown_over_limit: bool,
+    /// Adds an `APPROXIMATE_HEADER: true` header to the approximate answers
+    pub header: bool,
+}
+
+pub const APPROXIMATE_HEADER: &str = "X-Limitador-Approximate";
+
+// Enumerates all the limits that applied to the descriptors of a request, when
+// configured to, with what remains of each. See `to_matched_limits_header`.
+pub const MATCHED_LIMITS_HEADER: &str = "X-Limitador-Matched-Limits";
+
+// Names the limit that decided the answer to a request, when configured to
+pub const LIMIT_NAME_HEADER: &str = "X-Limitador-Limit-Name";
+
+// Warns of the requests let through past the soft max value of a limit
+pub const SOFT_LIMITED_HEADER: &str = "X-Limitador-Soft-Limited";
+
+/// Where the name of the limit that decided the answer to a request goes,
+/// when that limit has one
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BindingLimitName {
+    /// A `LIMIT_NAME_HEADER` header of the response, that the client gets
+    Header,
+    /// The `limit_name` field of the dynamic metadata of the answer, that
+    /// Envoy can log but never passes on to the client
+    Metadata,
+}
+
+impl FromStr for BindingLimitName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "header" => Ok(Self::Header),
+            "metadata" => Ok(Self::Metadata),
+            _ => Err(format!(
+                "invalid binding limit name '{s}': expected header or metadata"
+            )),
+        }
+    }
+}
+
+/// Bounds the work a single request makes the storage do: the requests with
+/// more descriptors, or with a descriptor of more entries, are rejected
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct RequestBounds {
+    pub max_descriptors: usize,
+    pub max_descriptor_entries: usize,
+}
+
+impl RequestBounds {
+    pub const DEFAULT_MAX_DESCRIPTORS: usize = 64;
+    pub const DEFAULT_MAX_DESCRIPTOR_ENTRIES: usize = 64;
+
+    fn check(&self, req: &RateLimitRequest) -> Result<(), Status> {
+        if req.descriptors.len() > self.max_descriptors {
+            return Err(Status::invalid_argument(format!(
+                "a request can have up to {} descriptors, got {}",
+                self.max_descriptors,
+                req.descriptors.len()
+            )));
+        }
+        match req
+            .descriptors
+            .iter()
+            .find(|descriptor| descriptor.entries.len() > self.max_descriptor_entries)
+        {
+            Some(descriptor) => Err(Status::invalid_argument(format!(
+                "a descriptor can have up to {} entries, got {}",
+                self.max_descriptor_entries,
+                descriptor.entries.len()
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The descriptor entries whose requests skip the limits of a namespace, e.g.
+/// `caller=internal` for the internal services. A request with one of them, in
+/// any of its descriptors, is let through without its counters being checked,
+/// nor counting any hits.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Bypass {
+    entries: HashMap<Namespace, HashSet<(String, String)>>,
+}
+
+impl Bypass {
+    /// Parses the entries, each as `NAMESPACE:KEY=VALUE`.
+    pub fn parse<'a>(entries: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        let mut bypass = Self::default();
+        for entry in entries {
+            let parsed = entry
+                .split_once(':')
+                .and_then(|(namespace, entry)| Some((namespace, entry.split_once('=')?)))
+                .filter(|(namespace, (key, _))| !namespace.is_empty() && !key.is_empty());
+            let Some((namespace, (key, value))) = parsed else {
+                return Err(format!(
+                    "invalid bypass entry '{entry}': expected NAMESPACE:KEY=VALUE"
+                ));
+            };
+            bypass
+                .entries
+                .entry(namespace.into())
+                .or_default()
+                .insert((key.to_string(), value.to_string()));
+        }
+        Ok(bypass)
+    }
+
+    fn applies(&self, namespace: &Namespace, req: &RateLimitRequest) -> bool {
+        let Some(entries) = self.entries.get(namespace) else {
+            return false;
+        };
+        req.descriptors.iter().any(|descriptor| {
+            descriptor
+                .entries
+                .iter()
+                .any(|entry| entries.contains(&(entry.key.clone(), entry.value.clone())))
+        })
+    }
+}
+
+/// The quotas granted to the descriptors within their limits, which Envoy
+/// caches to let `requests` of them through without asking again, for up to
+/// `validity`. The hits of a whole quota are counted as it's granted, and
+/// those Envoy doesn't get to use before it expires aren't given back.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct QuotaGrants {
+    pub requests: u32,
+    pub validity: Duration,
+}
+
+impl QuotaGrants {
+    pub const DEFAULT_VALIDITY_MS: u64 = 1000;
+}
+
+impl Default for RequestBounds {
+    fn default() -> Self {
+        Self {
+            max_descriptors: Self::DEFAULT_MAX_DESCRIPTORS,
+            max_descriptor_entries: Self::DEFAULT_MAX_DESCRIPTOR_ENTRIES,
+        }
+    }
+}
+
+// Requests carrying this metadata, set to "true", are only checked against the
+// limits: no hits are consumed. That's useful to measure the impact of limits
+// before enforcing them.
+pub const CHECK_ONLY_METADATA_KEY: &str = "x-limitador-check-only";
+
+// Requests carrying this metadata, set to "true", are checked in two phases
+// when reservations are enabled: the hits of the ones let through are only
+// reserved, until confirmed or released by the id of the header below, which
+// is added to the request, and set in the dynamic metadata as `reservation`.
+pub const RESERVE_METADATA_KEY: &str = "x-limitador-reserve";
+pub const RESERVATION_HEADER: &str = "X-Limitador-Reservation";
+
+// The descriptor entry with this key sets the max value the hits of the
+// descriptor are checked against, instead of the one of each limit that
+// applies. It's only honored in the namespaces configured to allow it, and is
+// never taken as one of the values of the descriptor.
+pub const MAX_VALUE_OVERRIDE_KEY: &str = "limitador.max_value";
+
+// Joins the domain of a request and the value of its namespace entry, when
+// there's one, into the namespace the request is limited in
+pub const NAMESPACE_ENTRY_SEPARATOR: char = '/';
+
+/// How the RLS requests are answered, past their limits and the rate limit
+/// headers: the features left out, or to their defaults, unless set.
+#[derive(Default)]
+pub struct RlsOptions {
+    pub failure_mode: FailureMode,
+    pub zero_hits_addend_checks_only: bool,
+    pub retry_after_header: bool,
+    pub max_value_override_namespaces: HashSet<Namespace>,
+    pub access_log: Option<AccessLog>,
+    pub approximate_decisions: ApproximateDecisions,
+    pub utilization_webhook: Option<UtilizationWebhook>,
+    pub request_bounds: RequestBounds,
+    pub over_limit_body: Option<OverLimitBody>,
+    pub storage_timeout: Option<Duration>,
+    pub bypass: Bypass,
+    pub matched_limits_header: bool,
+    pub quota_grants: Option<QuotaGrants>,
+    pub namespace_rate_limit_headers: HashMap<Namespace, RateLimitHeaders>,
+    pub namespace_entry: Option<String>,
+    pub kill_switch: Arc<KillSwitch>,
+    pub reservations: Option<Arc<Reservations>>,
+    pub debug_log_sampler: Arc<DebugLogSampler>,
+    pub binding_limit_name: Option<BindingLimitName>,
+    pub empty_domain_answer: EmptyDomainAnswer,
+    pub descriptor_transforms: DescriptorTransforms,
 }
 
 pub struct MyRateLimiter {
     limiter: Arc<Limiter>,
     rate_limit_headers: RateLimitHeaders,
+    options: RlsOptions,
 }
 
 impl MyRateLimiter {
     pub fn new(limiter: Arc<Limiter>, rate_limit_headers: RateLimitHeaders) -> Self {
+        Self::with_options(limiter, rate_limit_headers, RlsOptions::default())
+    }
+
+    pub fn with_options(
+        limiter: Arc<Limiter>,
+        rate_limit_headers: RateLimitHeaders,
+        options: RlsOptions,
+    ) -> Self {
         Self {
             limiter,
             rate_limit_headers,
+            options,
+        }
+    }
+
+    // The domain of the request, suffixed with the value of the first entry of
+    // its descriptors keyed as the namespace entry, e.g. `example.org/tenant`,
+    // or that value alone when the request has no domain
+    fn namespace_of(&self, req: &RateLimitRequest) -> String {
+        let value = self.options.namespace_entry.as_ref().and_then(|key| {
+            req.descriptors
+                .iter()
+                .flat_map(|descriptor| &descriptor.entries)
+                .find(|entry| &entry.key == key && !entry.value.is_empty())
+                .map(|entry| entry.value.as_str())
+        });
+        match value {
+            None => req.domain.clone(),
+            Some(value) if req.domain.is_empty() => value.to_string(),
+            Some(value) => format!("{}{NAMESPACE_ENTRY_SEPARATOR}{value}", req.domain),
         }
     }
 }
 
+// Lets the spans of a check be part of the trace the caller propagated in the
+// gRPC metadata
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .map(|key| match key {
+                KeyRef::Ascii(key) => key.as_str(),
+                KeyRef::Binary(key) => key.as_str(),
+            })
+            .collect()
+    }
+}
+
 #[tonic::async_trait]
 impl RateLimitService for MyRateLimiter {
     async fn should_rate_limit(
         &self,
         request: Request<RateLimitRequest>,
     ) -> Result<Response<RateLimitResponse>, Status> {
-        debug!("Request received: {:?}", request);
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&MetadataExtractor(request.metadata()))
+        });
+        let span = info_span!(
+            "should_rate_limit",
+            namespace = %request.get_ref().domain,
+            descriptors = request.get_ref().descriptors.len(),
+            limits = field::Empty,
+            code = field::Empty,
+            approximate = field::Empty,
+        );
+        span.set_parent(parent);
+
+        if self.options.access_log.is_none() && !self.options.debug_log_sampler.is_enabled() {
+            return self
+                .rate_limit(request)
+                .instrument(span)
+                .await
+                .map(|(response, _)| response);
+        }
+        let logged_request = request.get_ref().clone();
+        let started = Instant::now();
+        let (result, approximate) = match self.rate_limit(request).instrument(span).await {
+            Ok((response, approximate)) => (Ok(response), approximate),
+            Err(status) => (Err(status), false),
+        };
+        if let Some(access_log) = &self.options.access_log {
+            access_log.log(&logged_request, &result, approximate, started.elapsed());
+        }
+        if self.options.debug_log_sampler.is_enabled() {
+            let (answer, over_limit) = match &result {
+                Ok(response) => {
+                    let code =
+                        Code::try_from(response.get_ref().overall_code).unwrap_or(Code::Unknown);
+                    (code.as_str_name(), code == Code::OverLimit)
+                }
+                Err(status) => (status.code().description(), false),
+            };
+            self.options
+                .debug_log_sampler
+                .log(&logged_request, answer, over_limit);
+        }
+        result
+    }
+}
 
-        let mut values: HashMap<String, String> = HashMap::new();
-        let req = request.into_inner();
-        let namespace = req.domain;
+impl MyRateLimiter {
+    // Along with whether the answer is approximate
+    async fn rate_limit(
+        &self,
+        request: Request<RateLimitRequest>,
+    ) -> Result<(Response<RateLimitResponse>, bool), Status> {
+        let check_only = request
+            .metadata()
+            .get(CHECK_ONLY_METADATA_KEY)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let reserve = self.options.reservations.is_some()
+            && request
+                .metadata()
+                .get(RESERVE_METADATA_KEY)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.eq_ignore_ascii_case("true"))
+                .unwrap_or(false);
+
+        let mut req = request.into_inner();
+        // Checked before any descriptor is, so that an oversized request
+        // doesn't get to the storage at all
+        self.options.request_bounds.check(&req)?;
+        // Before anything reads the entries, the namespace entry included
+        self.options.descriptor_transforms.apply(&mut req);
+        let namespace = self.namespace_of(&req);
 
         if namespace.is_empty() {
-            return Ok(Response::new(RateLimitResponse {
-                overall_code: Code::Unknown.into(),
+            let code = match self.options.empty_domain_answer {
+                EmptyDomainAnswer::Unknown => Code::Unknown,
+                EmptyDomainAnswer::Ok => Code::Ok,
+                EmptyDomainAnswer::Deny => Code::OverLimit,
+                EmptyDomainAnswer::Error => {
+                    return Err(Status::invalid_argument("the request has no domain"))
+                }
+            };
+            let response = RateLimitResponse {
+                overall_code: code.into(),
                 statuses: vec![],
                 request_headers_to_add: vec![],
                 response_headers_to_add: vec![],
                 raw_body: vec![],
                 dynamic_metadata: None,
                 quota: None,
-            }));
+            };
+            return Ok((Response::new(response), false));
         }
 
-        let namespace = namespace.into();
-
-        for descriptor in &req.descriptors {
-            for entry in &descriptor.entries {
-                values.insert(entry.key.clone(), entry.value.clone());
+        let namespace: Namespace = namespace.into();
+
+        // Wins over everything else, the bypass included, and never gets to
+        // the storage either
+        if let Some(mode) = self.options.kill_switch.mode_for(&namespace) {
+            let code = match mode {
+                KillSwitchMode::Allow => Code::Ok,
+                KillSwitchMode::Deny => Code::OverLimit,
+            };
+            Span::current().record("code", code.as_str_name());
+            metrics::incr_rls_requests(namespace.as_ref(), code.as_str_name());
+            let mut response_headers = vec![];
+            let mut raw_body = vec![];
+            if code == Code::OverLimit {
+                raw_body = self.over_limit_body(&namespace, None, &mut response_headers);
             }
+            let response = RateLimitResponse {
+                overall_code: code.into(),
+                statuses: req
+                    .descriptors
+                    .iter()
+                    .map(|_| DescriptorStatus {
+                        code: code.into(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                request_headers_to_add: vec![],
+                response_headers_to_add: response_headers,
+                raw_body,
+                dynamic_metadata: None,
+                quota: None,
+            };
+            return Ok((Response::new(response), false));
+        }
+
+        // Before anything gets to the storage, so that no hits are counted
+        if self.options.bypass.applies(&namespace, &req) {
+            Span::current().record("code", Code::Ok.as_str_name());
+            metrics::incr_rls_requests(namespace.as_ref(), Code::Ok.as_str_name());
+            let response = RateLimitResponse {
+                overall_code: Code::Ok.into(),
+                statuses: req
+                    .descriptors
+                    .iter()
+                    .map(|_| DescriptorStatus {
+                        code: Code::Ok.into(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                request_headers_to_add: vec![],
+                response_headers_to_add: vec![],
+                raw_body: vec![],
+                dynamic_metadata: None,
+                quota: None,
+            };
+            return Ok((Response::new(response), false));
         }
 
         // "hits_addend" is optional according to the spec, and should default
-        // to 1, However, with the autogenerated structs it defaults to 0.
-        let hits_addend = if req.hits_addend == 0 {
+        // to 1, However, with the autogenerated structs it defaults to 0. Newer
+        // Envoy versions always set it though, so when configured to, a 0 is
+        // taken for what it then means: a check that consumes no hits.
+        let hits_addend = if req.hits_addend == 0 && !self.options.zero_hits_addend_checks_only {
             1
         } else {
             req.hits_addend
         };
 
-        let rate_limited_resp = match &*self.limiter {
-            Limiter::Blocking(limiter) => limiter.check_rate_limited_and_update(
-                &namespace,
-                &values,
-                i64::from(hits_addend),
-                self.rate_limit_headers != RateLimitHeaders::None,
-            ),
-            Limiter::Async(limiter) => {
-                limiter
-                    .check_rate_limited_and_update(
-                        &namespace,
-                        &values,
-                        i64::from(hits_addend),
-                        self.rate_limit_headers != RateLimitHeaders::None,
-                    )
-                    .await
+        let overrides_allowed = self
+            .options
+            .max_value_override_namespaces
+            .contains(&namespace);
+        let mut descriptors_values: Vec<HashMap<String, String>> =
+            Vec::with_capacity(req.descriptors.len());
+        let mut max_values: Vec<Option<i64>> = Vec::with_capacity(req.descriptors.len());
+        let mut descriptors_hits: Vec<i64> = Vec::with_capacity(req.descriptors.len());
+        for descriptor in &req.descriptors {
+            // Newer Envoy versions can also set a "hits_addend" per descriptor,
+            // overriding the one of the request. Being a wrapped value, when
+            // set, even a 0 is what was meant.
+            let hits = match descriptor.hits_addend {
+                Some(hits) => i64::try_from(hits).map_err(|_| {
+                    Status::invalid_argument(format!(
+                        "the hits_addend of a descriptor must fit in an i64, got {hits}"
+                    ))
+                })?,
+                None => i64::from(hits_addend),
+            };
+            let mut values = HashMap::with_capacity(descriptor.entries.len());
+            let mut max_value = None;
+            for entry in &descriptor.entries {
+                if entry.key != MAX_VALUE_OVERRIDE_KEY {
+                    values.insert(entry.key.clone(), entry.value.clone());
+                } else if overrides_allowed {
+                    match entry.value.parse::<i64>() {
+                        Ok(value) if value >= 0 => max_value = Some(value),
+                        _ => {
+                            return Err(Status::invalid_argument(format!(
+                                "{MAX_VALUE_OVERRIDE_KEY} must be a non-negative integer, got '{}'",
+                                entry.value
+                            )))
+                        }
+                    }
+                }
+            }
+            descriptors_values.push(values);
+            max_values.push(max_value);
+            descriptors_hits.push(hits);
+        }
+        let check_only = check_only || descriptors_hits.iter().all(|hits| *hits == 0);
+
+        // Each descriptor is evaluated on its own, so that Envoy can tell which
+        // one of a batched request tripped a limit. When hits are consumed, the
+        // counters are always loaded, as they are needed to report the
+        // remaining hits in the status of the descriptor.
+        let mut quotas_granted = vec![false; descriptors_values.len()];
+        let check = async {
+            if check_only {
+                let mut resps = Vec::with_capacity(descriptors_values.len());
+                for ((values, max_value), hits) in descriptors_values
+                    .iter()
+                    .zip(&max_values)
+                    .zip(&descriptors_hits)
+                {
+                    let resp = self
+                        .limiter
+                        .is_rate_limited_overriding(&namespace, values, *hits, *max_value)
+                        .await;
+                    resps.push(resp.map(checked_only));
+                }
+                resps.into_iter().collect()
+            } else {
+                let batch: Vec<_> = descriptors_values
+                    .iter()
+                    .zip(&max_values)
+                    .zip(&descriptors_hits)
+                    .map(|((values, max_value), hits)| (&namespace, values, *hits, *max_value))
+                    .collect();
+                // The hits of a quota are the ones of requests yet to come,
+                // which no response can confirm
+                match self.options.quota_grants {
+                    Some(quotas) if !reserve => {
+                        self.grant_quotas(&batch, quotas, &mut quotas_granted).await
+                    }
+                    _ => self.check_and_update(&batch).await,
+                }
+            }
+        };
+        // Past the timeout, the storage is deemed failing, as it would be
+        // when unreachable
+        let rate_limited_resps = match self.options.storage_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, check)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(LimitadorError::StorageConnection(format!(
+                        "timed out after {}ms",
+                        timeout.as_millis()
+                    )))
+                }),
+            None => check.await,
+        };
+
+        let rate_limited_resps: Vec<CheckResult> = match rate_limited_resps {
+            Ok(resps) => resps,
+            Err(e) => {
+                // The only errors that can happen here have to do with the
+                // limits storage, mostly with connecting to it, which should
+                // be temporary.
+                error!("Error: {:?}", e);
+                let code = match self.options.failure_mode {
+                    FailureMode::Allow => Code::Ok,
+                    FailureMode::Deny => Code::OverLimit,
+                    // In this case we could return "Code::Unknown" but that's
+                    // not very helpful. When envoy receives "Unknown" it simply
+                    // lets the request pass and this cannot be configured using
+                    // the "failure_mode_deny" attribute, so it's equivalent to
+                    // returning "Code::Ok". That's why we return an
+                    // "unavailable" error here. What envoy does after receiving
+                    // that kind of error can be configured with
+                    // "failure_mode_deny".
+                    FailureMode::Passthrough => {
+                        let (status, code) = to_status(&e);
+                        Span::current().record("code", code);
+                        metrics::incr_rls_requests(namespace.as_ref(), code);
+                        return Err(status);
+                    }
+                };
+                // The failure mode decided in place of the limits, so the
+                // answer is at best approximate
+                Span::current()
+                    .record("code", code.as_str_name())
+                    .record("approximate", true);
+                metrics::incr_rls_requests(namespace.as_ref(), code.as_str_name());
+                let mut response_headers = self.approximate_headers();
+                let mut raw_body = vec![];
+                if code == Code::OverLimit {
+                    raw_body = self.over_limit_body(&namespace, None, &mut response_headers);
+                }
+                let response = RateLimitResponse {
+                    overall_code: code.into(),
+                    statuses: req
+                        .descriptors
+                        .iter()
+                        .map(|_| DescriptorStatus {
+                            code: code.into(),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    request_headers_to_add: vec![],
+                    response_headers_to_add: response_headers,
+                    raw_body,
+                    dynamic_metadata: None,
+                    quota: None,
+                };
+                return Ok((Response::new(response), true));
             }
         };
 
-        if let Err(e) = rate_limited_resp {
-            // In this case we could return "Code::Unknown" but that's not
-            // very helpful. When envoy receives "Unknown" it simply lets
-            // the request pass and this cannot be configured using the
-            // "failure_mode_deny" attribute, so it's equivalent to
-            // returning "Code::Ok". That's why we return an "unavailable"
-            // error here. What envoy does after receiving that kind of
-            // error can be configured with "failure_mode_deny". The only
-            // errors that can happen here have to do with connecting to the
-            // limits storage, which should be temporary.
-            error!("Error: {:?}", e);
-            return Err(Status::unavailable("Service unavailable"));
+        let mut statuses = Vec::with_capacity(rate_limited_resps.len());
+        let mut counters = Vec::new();
+        let mut matched_limits = Vec::new();
+        // The descriptor over the limit, or the one closest to it when let
+        // through, and the name of its most restrictive limit
+        let mut binding_limit: Option<((bool, i64), Option<String>)> = None;
+        let mut retry_after = None;
+        let mut approximate = false;
+        let mut soft_limited = false;
+
+        let now = SystemTime::now();
+        for (mut rate_limited_resp, quota_granted) in
+            rate_limited_resps.into_iter().zip(quotas_granted)
+        {
+            approximate |= rate_limited_resp.approximate;
+            soft_limited |= rate_limited_resp.outcome() == CheckOutcome::SoftLimited;
+            let code = if !rate_limited_resp.limited {
+                Code::Ok
+            } else if rate_limited_resp.approximate
+                && self.options.approximate_decisions.unknown_over_limit
+            {
+                Code::Unknown
+            } else {
+                Code::OverLimit
+            };
+
+            let descriptor_retry_after = rate_limited_resp.retry_after();
+            retry_after = retry_after.max(descriptor_retry_after);
+
+            statuses.push(DescriptorStatus {
+                code: code.into(),
+                current_limit: rate_limited_resp
+                    .most_restrictive_counter()
+                    .map(to_current_limit),
+                limit_remaining: rate_limited_resp
+                    .remaining()
+                    .map(|remaining| u32::try_from(remaining.max(0)).unwrap_or(u32::MAX))
+                    .unwrap_or_default(),
+                duration_until_reset: descriptor_retry_after
+                    .and_then(|duration| prost_types::Duration::try_from(duration).ok()),
+                quota: quota_granted
+                    .then(|| self.granted_quota(&rate_limited_resp, now))
+                    .flatten(),
+                ..Default::default()
+            });
+            if self.options.matched_limits_header {
+                let descriptor = statuses.len() - 1;
+                matched_limits.extend(
+                    rate_limited_resp
+                        .counters
+                        .iter()
+                        .map(|counter| (descriptor, counter.clone())),
+                );
+            }
+            if self.options.binding_limit_name.is_some() {
+                if let Some(counter) = rate_limited_resp.most_restrictive_counter() {
+                    let closeness = (
+                        !rate_limited_resp.limited,
+                        counter.remaining().unwrap_or(counter.max_value()),
+                    );
+                    if binding_limit
+                        .as_ref()
+                        .map_or(true, |(binding, _)| closeness < *binding)
+                    {
+                        let name = counter.limit().name().map(str::to_string);
+                        binding_limit = Some((closeness, name));
+                    }
+                }
+            }
+            counters.append(&mut rate_limited_resp.counters);
+        }
+        if let Some(webhook) = &self.options.utilization_webhook {
+            webhook.observe(&counters);
         }
 
-        let mut rate_limited_resp = rate_limited_resp.unwrap();
-        let resp_code = if rate_limited_resp.limited {
+        let has_status = |code: Code| statuses.iter().any(|status| status.code == i32::from(code));
+        let resp_code = if has_status(Code::OverLimit) {
             Code::OverLimit
+        } else if has_status(Code::Unknown) {
+            Code::Unknown
         } else {
             Code::Ok
         };
 
+        Span::current()
+            .record("limits", counters.len())
+            .record("code", resp_code.as_str_name());
+        if approximate {
+            Span::current().record("approximate", true);
+        }
+        metrics::incr_rls_requests(namespace.as_ref(), resp_code.as_str_name());
+
+        // Only the requests let through get a reservation, the ones rejected
+        // having no response to wait for
+        let mut request_headers = vec![];
+        let mut dynamic_metadata = None;
+        let reserving = reserve && !check_only && resp_code == Code::Ok;
+        if let Some(reservations) = self.options.reservations.as_ref().filter(|_| reserving) {
+            let reserved: Vec<_> = descriptors_values
+                .into_iter()
+                .zip(descriptors_hits)
+                .filter(|(_, hits)| *hits > 0)
+                .collect();
+            if !reserved.is_empty() {
+                // Not to outlive the windows the hits were counted in
+                let validity = counters
+                    .iter()
+                    .filter_map(Counter::expires_in)
+                    .fold(Duration::MAX, Duration::min);
+                let id = reservations.reserve(&namespace, reserved, validity);
+                request_headers.push(HeaderValue {
+                    key: RESERVATION_HEADER.to_string(),
+                    value: id.clone(),
+                });
+                dynamic_metadata = Some(prost_types::Struct {
+                    fields: [(
+                        "reservation".to_string(),
+                        prost_types::Value {
+                            kind: Some(prost_types::value::Kind::StringValue(id)),
+                        },
+                    )]
+                    .into(),
+                });
+            }
+        }
+
+        let rate_limit_headers = self
+            .options
+            .namespace_rate_limit_headers
+            .get(&namespace)
+            .unwrap_or(&self.rate_limit_headers);
+        let mut response_headers = to_response_header(rate_limit_headers, &mut counters);
+        if self.options.retry_after_header {
+            if let Some(retry_after) = retry_after {
+                response_headers.push(HeaderValue {
+                    key: "Retry-After".to_string(),
+                    value: format!("{}", whole_seconds(retry_after)),
+                });
+            }
+        }
+        if let Some(header) = to_matched_limits_header(matched_limits) {
+            response_headers.push(header);
+        }
+        if let Some((_, Some(name))) = binding_limit {
+            match self.options.binding_limit_name {
+                Some(BindingLimitName::Header) => response_headers.push(HeaderValue {
+                    key: LIMIT_NAME_HEADER.to_string(),
+                    value: name,
+                }),
+                Some(BindingLimitName::Metadata) => {
+                    dynamic_metadata
+                        .get_or_insert_with(Default::default)
+                        .fields
+                        .insert(
+                            "limit_name".to_string(),
+                            prost_types::Value {
+                                kind: Some(prost_types::value::Kind::StringValue(name)),
+                            },
+                        );
+                }
+                None => {}
+            }
+        }
+        if approximate {
+            response_headers.append(&mut self.approximate_headers());
+        }
+        if soft_limited && resp_code != Code::OverLimit {
+            response_headers.push(HeaderValue {
+                key: SOFT_LIMITED_HEADER.to_string(),
+                value: "true".to_string(),
+            });
+        }
+        let mut raw_body = vec![];
+        if resp_code == Code::OverLimit {
+            raw_body = self.over_limit_body(&namespace, retry_after, &mut response_headers);
+        }
+
         let reply = RateLimitResponse {
             overall_code: resp_code.into(),
-            statuses: vec![],
-            request_headers_to_add: vec![],
-            response_headers_to_add: to_response_header(
-                &self.rate_limit_headers,
-                &mut rate_limited_resp.counters,
-            ),
-            raw_body: vec![],
-            dynamic_metadata: None,
+            statuses,
+            request_headers_to_add: request_headers,
+            response_headers_to_add: response_headers,
+            raw_body,
+            dynamic_metadata,
             quota: None,
         };
 
-        Ok(Response::new(reply))
+        Ok((Response::new(reply), approximate))
+    }
+
+    async fn check_and_update(
+        &self,
+        batch: &[OverridingCheck<'_>],
+    ) -> Result<Vec<CheckResult>, LimitadorError> {
+        self.limiter
+            .check_rate_limited_and_update_batch_overriding(batch, true)
+            .await
+    }
+
+    // Counts the hits of a whole quota for each descriptor, i.e. those of as
+    // many requests as the one asking, flagging the ones it's granted to. The
+    // descriptors a quota would get over a limit are then only counted the
+    // hits of the request, as they'd be without quotas. So are the ones of no
+    // hits, which get no quota either.
+    async fn grant_quotas(
+        &self,
+        batch: &[OverridingCheck<'_>],
+        quotas: QuotaGrants,
+        granted: &mut [bool],
+    ) -> Result<Vec<CheckResult>, LimitadorError> {
+        let reservations: Vec<OverridingCheck> = batch
+            .iter()
+            .map(|(namespace, values, hits, max_value)| {
+                let hits = hits.saturating_mul(i64::from(quotas.requests));
+                (*namespace, *values, hits, *max_value)
+            })
+            .collect();
+        let mut resps = self.check_and_update(&reservations).await?;
+
+        let mut denied = Vec::new();
+        for (i, resp) in resps.iter().enumerate() {
+            granted[i] = !resp.limited && batch[i].2 > 0;
+            if resp.limited {
+                denied.push(i);
+            }
+        }
+        if !denied.is_empty() {
+            let retries: Vec<OverridingCheck> = denied.iter().map(|i| batch[*i]).collect();
+            for (i, resp) in denied
+                .into_iter()
+                .zip(self.check_and_update(&retries).await?)
+            {
+                resps[i] = resp;
+            }
+        }
+        Ok(resps)
+    }
+
+    // Expires along with the first of the counters of the descriptor, so that
+    // the quota doesn't outlive the windows its hits were counted in
+    fn granted_quota(&self, resp: &CheckResult, now: SystemTime) -> Option<Quota> {
+        let quotas = self.options.quota_grants?;
+        let validity = resp
+            .counters
+            .iter()
+            .filter_map(Counter::expires_in)
+            .fold(quotas.validity, Duration::min);
+        Some(Quota {
+            requests: quotas.requests,
+            id: String::new(),
+            expiration_specifier: Some(quota::ExpirationSpecifier::ValidUntil(
+                (now + validity).into(),
+            )),
+        })
+    }
+
+    // The body Envoy answers the requests over the limits with, adding the
+    // header of its content type along
+    fn over_limit_body(
+        &self,
+        namespace: &Namespace,
+        retry_after: Option<Duration>,
+        headers: &mut Vec<HeaderValue>,
+    ) -> Vec<u8> {
+        let Some(body) = &self.options.over_limit_body else {
+            return vec![];
+        };
+        headers.push(HeaderValue {
+            key: "Content-Type".to_string(),
+            value: body.content_type().to_string(),
+        });
+        body.render(namespace.as_ref(), retry_after).into_bytes()
+    }
+
+    fn approximate_headers(&self) -> Vec<HeaderValue> {
+        if !self.options.approximate_decisions.header {
+            return vec![];
+        }
+        vec![HeaderValue {
+            key: APPROXIMATE_HEADER.to_string(),
+            value: "true".to_string(),
+        }]
+    }
+}
+
+fn checked_only(limited: bool) -> CheckResult {
+    CheckResult {
+        limited,
+        counters: vec![],
+        approximate: false,
+        soft_limited: false,
+    }
+}
+
+// The error sent when failing in passthrough mode, along with its name. Envoy
+// applies its "failure_mode_deny" to any of them, but they tell apart the
+// errors that retrying can't fix.
+fn to_status(e: &LimitadorError) -> (Status, &'static str) {
+    match e {
+        LimitadorError::Serialization(_) => (
+            Status::internal("The limits storage holds unreadable counters"),
+            "INTERNAL",
+        ),
+        LimitadorError::Configuration(_) | LimitadorError::InvalidCounter(_) => (
+            Status::failed_precondition("The limits can't be enforced by the storage"),
+            "FAILED_PRECONDITION",
+        ),
+        LimitadorError::Storage(_) | LimitadorError::StorageConnection(_) => {
+            (Status::unavailable("Service unavailable"), "UNAVAILABLE")
+        }
+    }
+}
+
+fn to_current_limit(counter: &Counter) -> RateLimit {
+    let unit = match counter.limit().calendar_window() {
+        Some(calendar_window) if calendar_window.period() == CalendarPeriod::Daily => Unit::Day,
+        Some(_) => Unit::Unknown,
+        None if counter.limit().milliseconds().is_some() => Unit::Unknown,
+        None => match counter.seconds() {
+            1 => Unit::Second,
+            60 => Unit::Minute,
+            3600 => Unit::Hour,
+            86400 => Unit::Day,
+            _ => Unit::Unknown,
+        },
+    };
+
+    // The tier of a group is told along with it, e.g. `api/burst`
+    let name = counter.limit().name().unwrap_or_default();
+    let name = match counter.limit().group() {
+        Some(group) if name.is_empty() => group.to_string(),
+        Some(group) => format!("{group}/{name}"),
+        None => name.to_string(),
+    };
+
+    RateLimit {
+        name,
+        requests_per_unit: u32::try_from(counter.max_value().max(0)).unwrap_or(u32::MAX),
+        unit: unit.into(),
     }
 }
 
@@ -140,83 +1059,327 @@ pub fn to_response_header(
     counters: &mut Vec<Counter>,
 ) -> Vec<HeaderValue> {
     let mut headers = Vec::new();
-    match rate_limit_headers {
-        RateLimitHeaders::None => {}
-
-        // creates response headers per https://datatracker.ietf.org/doc/id/draft-polli-ratelimit-headers-03.html
-        RateLimitHeaders::DraftVersion03 => {
-            // sort by the limit remaining..
-            counters.sort_by(|a, b| {
-                let a_remaining = a.remaining().unwrap_or(a.max_value());
-                let b_remaining = b.remaining().unwrap_or(b.max_value());
-                if a_remaining - b_remaining < 0 {
-                    Ordering::Less
-                } else {
-                    Ordering::Greater
-                }
-            });
-
-            let mut all_limits_text = String::with_capacity(20 * counters.len());
-            counters.iter_mut().for_each(|counter| {
-                all_limits_text.push_str(
-                    format!(", {};w={}", counter.max_value(), counter.seconds()).as_str(),
-                );
-                if let Some(name) = counter.limit().name() {
-                    all_limits_text
-                        .push_str(format!(";name=\"{}\"", name.replace('"', "'")).as_str());
-                }
-            });
+    let prefix = match rate_limit_headers {
+        RateLimitHeaders::None => return headers,
+        RateLimitHeaders::DraftVersion03 => "X-RateLimit",
+        RateLimitHeaders::Standard => "RateLimit",
+    };
 
-            if let Some(counter) = counters.first() {
-                headers.push(HeaderValue {
-                    key: "X-RateLimit-Limit".to_string(),
-                    value: format!("{}{}", counter.max_value(), all_limits_text),
-                });
+    // creates response headers per https://datatracker.ietf.org/doc/id/draft-polli-ratelimit-headers-03.html
+    // sort the ones over their limit first, then from the highest priority to
+    // the lowest one, and by the limit remaining..
+    counters.sort_by_key(|counter| {
+        let remaining = counter.remaining().unwrap_or(counter.max_value());
+        (
+            remaining >= 0,
+            Reverse(counter.limit().priority()),
+            remaining,
+        )
+    });
+
+    let mut all_limits_text = String::with_capacity(20 * counters.len());
+    counters.iter_mut().for_each(|counter| {
+        // windows of milliseconds are told in the seconds they take part of
+        let window = (counter.window().as_millis() + 999) / 1000;
+        all_limits_text.push_str(format!(", {};w={}", counter.max_value(), window).as_str());
+        if let Some(name) = counter.limit().name() {
+            all_limits_text.push_str(format!(";name=\"{}\"", name.replace('"', "'")).as_str());
+        }
+    });
 
-                let mut remaining = counter.remaining().unwrap_or(counter.max_value());
-                if remaining < 0 {
-                    remaining = 0
-                }
-                headers.push(HeaderValue {
-                    key: "X-RateLimit-Remaining".to_string(),
-                    value: format!("{}", remaining),
-                });
+    if let Some(counter) = counters.first() {
+        headers.push(HeaderValue {
+            key: format!("{prefix}-Limit"),
+            value: format!("{}{}", counter.max_value(), all_limits_text),
+        });
 
-                if let Some(duration) = counter.expires_in() {
-                    headers.push(HeaderValue {
-                        key: "X-RateLimit-Reset".to_string(),
-                        value: format!("{}", duration.as_secs()),
-                    });
-                }
-            }
+        let mut remaining = counter.remaining().unwrap_or(counter.max_value());
+        if remaining < 0 {
+            remaining = 0
         }
-    };
+        headers.push(HeaderValue {
+            key: format!("{prefix}-Remaining"),
+            value: format!("{}", remaining),
+        });
+
+        // When the storage doesn't know about the expiry of the counter, it
+        // hasn't been hit yet in this window, so it resets after a whole one.
+        let reset = counter
+            .expires_in()
+            .unwrap_or_else(|| counter.window_at(SystemTime::now()));
+        headers.push(HeaderValue {
+            key: format!("{prefix}-Reset"),
+            value: format!("{}", whole_seconds(reset)),
+        });
+    }
     headers
 }
 
+// Lists the limits that applied to each descriptor, by the index of the
+// descriptor, e.g. `0;max=10;w=60;remaining=3;name="per-user", 1;max=100;...`.
+// The order doesn't depend on the hits, so that the limits of a request are
+// listed in the same order in every answer: by descriptor, then from the
+// highest priority to the lowest one, then by what identifies the limit.
+fn to_matched_limits_header(mut matched_limits: Vec<(usize, Counter)>) -> Option<HeaderValue> {
+    if matched_limits.is_empty() {
+        return None;
+    }
+    matched_limits.sort_by_cached_key(|(descriptor, counter)| {
+        let limit = counter.limit();
+        (
+            *descriptor,
+            Reverse(limit.priority()),
+            limit.name().map(str::to_string),
+            limit.max_value(),
+            limit.window(),
+            limit.conditions().into_iter().collect::<BTreeSet<_>>(),
+            limit.variables().into_iter().collect::<BTreeSet<_>>(),
+        )
+    });
+
+    let value = matched_limits
+        .iter()
+        .map(|(descriptor, counter)| {
+            let remaining = counter.remaining().unwrap_or(counter.max_value()).max(0);
+            let window = (counter.window().as_millis() + 999) / 1000;
+            let mut limit = format!(
+                "{descriptor};max={};w={window};remaining={remaining}",
+                counter.max_value()
+            );
+            if let Some(name) = counter.limit().name() {
+                limit.push_str(&format!(";name=\"{}\"", name.replace('"', "'")));
+            }
+            limit
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(HeaderValue {
+        key: MATCHED_LIMITS_HEADER.to_string(),
+        value,
+    })
+}
+
+// Rounded up, so that clients waiting for that long aren't early
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+// How often the storage is pinged to report the health of the service
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Prefixes the path of the UNIX socket to serve on, instead of a TCP address
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// How the RLS server is served, past how its requests are answered: the
+/// services left out, and the limits left to tonic's defaults, unless set.
+#[derive(Default)]
+pub struct RlsServerOptions {
+    pub tls: Option<ServerTlsConfig>,
+    pub grpc_reflection_service: bool,
+    pub counters_service: bool,
+    pub compression: bool,
+    pub max_concurrent_requests: Option<usize>,
+    pub max_decoding_message_size: Option<usize>,
+    pub max_encoding_message_size: Option<usize>,
+    pub drain_timeout: Duration,
+}
+
 pub async fn run_envoy_rls_server(
-    address: String,
+    addresses: Vec<String>,
     limiter: Arc<Limiter>,
     rate_limit_headers: RateLimitHeaders,
-) -> Result<(), transport::Error> {
-    let rate_limiter = MyRateLimiter::new(limiter, rate_limit_headers);
-    let svc = RateLimitServiceServer::new(rate_limiter);
+    options: RlsOptions,
+    server_options: RlsServerOptions,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let RlsServerOptions {
+        tls,
+        grpc_reflection_service,
+        counters_service,
+        compression,
+        max_concurrent_requests,
+        max_decoding_message_size,
+        max_encoding_message_size,
+        drain_timeout,
+    } = server_options;
+    let limiter_for_health = Arc::clone(&limiter);
+    let limiter_for_flush = Arc::clone(&limiter);
+    let counters_service =
+        counters_service.then(|| CounterServiceServer::new(Counters::new(Arc::clone(&limiter))));
+    let reservations_service = options
+        .reservations
+        .clone()
+        .map(|reservations| ReservationServiceServer::new(Outcomes::new(reservations)));
+    let rate_limiter = MyRateLimiter::with_options(limiter, rate_limit_headers, options);
+    let mut rls_service = RateLimitServiceServer::new(rate_limiter);
+    // Only the clients that negotiate it get their responses compressed
+    if compression {
+        rls_service = rls_service
+            .accept_compressed(CompressionEncoding::Gzip)
+            .send_compressed(CompressionEncoding::Gzip);
+    }
+    // Left to tonic's defaults unless set: 4MiB for the requests, unbounded for the answers
+    if let Some(max) = max_decoding_message_size {
+        rls_service = rls_service.max_decoding_message_size(max);
+    }
+    if let Some(max) = max_encoding_message_size {
+        rls_service = rls_service.max_encoding_message_size(max);
+    }
+    let svc = LoadSheddingLayer::new(max_concurrent_requests).layer(rls_service);
+
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(async move {
+        let mut serving = None;
+        loop {
+            let reachable = match limiter_for_health.ping().await {
+                Ok(()) => true,
+                Err(e) => {
+                    if serving != Some(false) {
+                        warn!("Storage can't be reached, not serving: {}", e);
+                    }
+                    false
+                }
+            };
+            if serving != Some(reachable) {
+                let status = if reachable {
+                    ServingStatus::Serving
+                } else {
+                    ServingStatus::NotServing
+                };
+                health_reporter.set_service_status("", status).await;
+                health_reporter
+                    .set_service_status(
+                        <RateLimitServiceServer<MyRateLimiter> as NamedService>::NAME,
+                        status,
+                    )
+                    .await;
+                serving = Some(reachable);
+            }
+            tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+        }
+    });
+
+    let reflection_service = grpc_reflection_service.then(|| {
+        tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+            .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+            .build()
+            .expect("The descriptors of the services are built along with them")
+    });
+
+    let mut server = Server::builder();
+    if let Some(tls) = tls {
+        server = server.tls_config(tls)?;
+    }
 
-    Server::builder()
-        .add_service(svc)
-        .serve(address.parse().unwrap())
-        .await
+    // Once told to shut down, the servers stop accepting requests, but let
+    // the ones in flight complete, for up to the drain timeout
+    let (draining, drain_started) = tokio::sync::oneshot::channel();
+    let signal = async move {
+        shutdown.await;
+        let _ = draining.send(());
+    }
+    .shared();
+
+    // One server per address, all of them serving the same services
+    let mut sockets = Vec::new();
+    let mut servers = Vec::with_capacity(addresses.len());
+    for address in &addresses {
+        let router = server
+            .clone()
+            .add_service(health_service.clone())
+            .add_service(svc.clone())
+            .add_optional_service(counters_service.clone())
+            .add_optional_service(reservations_service.clone())
+            .add_optional_service(reflection_service.clone());
+        let bind_failed = |e| format!("Failed binding the Envoy RLS server to {address}: {e}");
+        let serving = match address.strip_prefix(UNIX_SOCKET_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => {
+                let listener = bind_unix_socket(path).map_err(bind_failed)?;
+                sockets.push(path.to_string());
+                let accepted = futures::stream::unfold(listener, |listener| async move {
+                    let stream = listener.accept().await.map(|(stream, _)| stream);
+                    Some((stream, listener))
+                });
+                Either::Left(router.serve_with_incoming_shutdown(accepted, signal.clone()))
+            }
+            #[cfg(not(unix))]
+            Some(_) => return Err("UNIX sockets are only supported on UNIX".into()),
+            None => {
+                let listener = tokio::net::TcpListener::bind(address)
+                    .await
+                    .map_err(bind_failed)?;
+                let incoming = TcpIncoming::from_listener(listener, false, None)?;
+                Either::Right(router.serve_with_incoming_shutdown(incoming, signal.clone()))
+            }
+        };
+        servers.push(serving);
+    }
+    let serving = futures::future::try_join_all(servers);
+    tokio::pin!(serving);
+    tokio::select! {
+        res = &mut serving => {
+            res?;
+        }
+        Ok(()) = drain_started => {
+            info!("Envoy RLS server draining the requests in flight");
+            if tokio::time::timeout(drain_timeout, &mut serving).await.is_err() {
+                warn!(
+                    "Envoy RLS server closed with requests still in flight after {:?}",
+                    drain_timeout
+                );
+            }
+        }
+    }
+
+    for path in sockets {
+        let _ = std::fs::remove_file(path);
+    }
+    // The hits counted while draining can still be buffered by the storage
+    if let Err(e) = limiter_for_flush.flush().await {
+        error!("Failed flushing the counters: {}", e);
+    }
+    Ok(())
+}
+
+// Binds the socket at `path`, replacing the one a previous instance left
+// behind, unless something still listens on it
+#[cfg(unix)]
+fn bind_unix_socket(path: &str) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket()
+            && std::os::unix::net::UnixStream::connect(path).is_err()
+        {
+            std::fs::remove_file(path)?;
+        }
+    }
+    tokio::net::UnixListener::bind(path)
 }
 
 #[cfg(test)]
 mod tests {
+    use tonic::transport::{Endpoint, Uri};
     use tonic::IntoRequest;
 
-    use limitador::limit::Limit;
-    use limitador::RateLimiter;
+    use limitador::limit::{Limit, WindowType};
+    use limitador::storage::composite::AsyncCounterStorageAdapter;
+    use limitador::storage::in_memory::InMemoryStorage;
+    use limitador::storage::wasm::{Clock, WasmStorage};
+    use limitador::{AsyncRateLimiter, RateLimiter};
+    use std::time::SystemTime;
+
+    use async_trait::async_trait;
+    use limitador::storage::{AsyncCounterStorage, Authorization, StorageErr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
+    use crate::decision_cache::AsyncDecisionCache;
     use crate::envoy_rls::server::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
     use crate::envoy_rls::server::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+    use crate::envoy_rls::server::envoy::service::ratelimit::v3::rate_limit_service_client::RateLimitServiceClient;
+    use crate::over_limit::OverLimitBodyFormat;
     use crate::Configuration;
 
     use super::*;
@@ -268,6 +1431,7 @@ mod tests {
                     },
                 ],
                 limit: None,
+                hits_addend: None,
             }],
             hits_addend: 1,
         };
@@ -281,11 +1445,14 @@ mod tests {
             .unwrap()
             .into_inner();
         assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(response.statuses.len(), 1);
+        assert_eq!(response.statuses[0].code, i32::from(Code::Ok));
         assert_eq!(
             response.response_headers_to_add,
             vec![
                 header_value("X-RateLimit-Limit", "1, 1;w=60"),
                 header_value("X-RateLimit-Remaining", "0"),
+                header_value("X-RateLimit-Reset", "60"),
             ],
         );
 
@@ -295,152 +1462,353 @@ mod tests {
             .unwrap()
             .into_inner();
         assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert_eq!(response.statuses.len(), 1);
+        assert_eq!(response.statuses[0].code, i32::from(Code::OverLimit));
         assert_eq!(
             response.response_headers_to_add,
             vec![
                 header_value("X-RateLimit-Limit", "1, 1;w=60"),
                 header_value("X-RateLimit-Remaining", "0"),
+                header_value("X-RateLimit-Reset", "60"),
             ],
         );
     }
 
     #[tokio::test]
-    async fn test_returns_ok_when_no_limits_apply() {
-        // No limits saved
-        let rate_limiter = MyRateLimiter::new(
-            Arc::new(Limiter::new(Configuration::default()).await.unwrap()),
-            RateLimitHeaders::DraftVersion03,
-        );
+    async fn test_records_the_answers_by_namespace_and_code() {
+        let namespace = "test_metrics_namespace";
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(Limit::new(namespace, 1, 60, vec!["x == '1'"], vec!["z"]));
+
+        let rate_limiter =
+            MyRateLimiter::new(Arc::new(Limiter::Blocking(limiter)), RateLimitHeaders::None);
 
         let req = RateLimitRequest {
-            domain: "test_namespace".to_string(),
+            domain: namespace.to_string(),
             descriptors: vec![RateLimitDescriptor {
-                entries: vec![Entry {
-                    key: "req.method".to_string(),
-                    value: "GET".to_string(),
-                }],
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "z".to_string(),
+                        value: "1".to_string(),
+                    },
+                ],
                 limit: None,
+                hits_addend: None,
             }],
             hits_addend: 1,
+        };
+
+        for _ in 0..3 {
+            rate_limiter
+                .should_rate_limit(req.clone().into_request())
+                .await
+                .unwrap();
         }
-        .into_request();
 
-        let response = rate_limiter
-            .should_rate_limit(req)
-            .await
-            .unwrap()
-            .into_inner();
+        let metrics = metrics::gather_metrics();
+        assert!(metrics.contains(&format!(
+            "rls_requests{{code=\"OK\",limitador_namespace=\"{namespace}\"}} 1"
+        )));
+        assert!(metrics.contains(&format!(
+            "rls_requests{{code=\"OVER_LIMIT\",limitador_namespace=\"{namespace}\"}} 2"
+        )));
+    }
 
-        assert_eq!(response.overall_code, i32::from(Code::Ok));
-        assert_eq!(response.response_headers_to_add, vec![],);
+    #[test]
+    fn test_trace_context_is_read_from_the_metadata() {
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let mut metadata = MetadataMap::new();
+        metadata.insert("traceparent", traceparent.parse().unwrap());
+
+        let extractor = MetadataExtractor(&metadata);
+        assert_eq!(extractor.get("traceparent"), Some(traceparent));
+        assert_eq!(extractor.keys(), vec!["traceparent"]);
     }
 
     #[tokio::test]
-    async fn test_returns_unknown_when_domain_is_empty() {
-        let rate_limiter = MyRateLimiter::new(
-            Arc::new(Limiter::new(Configuration::default()).await.unwrap()),
-            RateLimitHeaders::DraftVersion03,
-        );
+    async fn test_returns_the_remaining_of_the_most_restrictive_limit() {
+        let namespace = "test_namespace";
+        let mut per_minute = Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["z"]);
+        per_minute.set_name("per_minute".to_string());
+        let per_hour = Limit::new(namespace, 5, 3600, vec!["x == '1'"], vec!["z"]);
+
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(per_minute);
+        limiter.add_limit(per_hour);
+
+        let rate_limiter =
+            MyRateLimiter::new(Arc::new(Limiter::Blocking(limiter)), RateLimitHeaders::None);
 
         let req = RateLimitRequest {
-            domain: "".to_string(),
+            domain: namespace.to_string(),
             descriptors: vec![RateLimitDescriptor {
-                entries: vec![Entry {
-                    key: "req.method".to_string(),
-                    value: "GET".to_string(),
-                }],
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "z".to_string(),
+                        value: "1".to_string(),
+                    },
+                ],
                 limit: None,
+                hits_addend: None,
             }],
-            hits_addend: 1,
-        }
-        .into_request();
+            hits_addend: 2,
+        };
 
         let response = rate_limiter
-            .should_rate_limit(req)
+            .should_rate_limit(req.clone().into_request())
             .await
             .unwrap()
             .into_inner();
-        assert_eq!(response.overall_code, i32::from(Code::Unknown));
-        assert_eq!(response.response_headers_to_add, vec![],);
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(response.statuses[0].limit_remaining, 3);
+        assert_eq!(
+            response.statuses[0].current_limit,
+            Some(RateLimit {
+                name: "".to_string(),
+                requests_per_unit: 5,
+                unit: Unit::Hour.into(),
+            })
+        );
+
+        rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap();
+        let response = rate_limiter
+            .should_rate_limit(req.into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert_eq!(response.statuses[0].limit_remaining, 0);
     }
 
     #[tokio::test]
-    async fn test_takes_into_account_all_the_descriptors() {
+    async fn test_enumerates_the_limits_of_each_descriptor_when_configured_to() {
+        let namespace = "test_namespace";
+        let mut per_minute = Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["z"]);
+        per_minute.set_name("per_minute".to_string());
+        let per_hour = Limit::new(namespace, 5, 3600, vec!["x == '1'"], vec!["z"]);
+        let per_second = Limit::new(namespace, 1, 1, vec!["y == '1'"], vec!["z"]);
+
         let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(per_minute);
+        limiter.add_limit(per_hour);
+        limiter.add_limit(per_second);
+        let limiter = Arc::new(Limiter::Blocking(limiter));
+
+        let rate_limiter = |matched_limits_header| {
+            MyRateLimiter::with_options(
+                Arc::clone(&limiter),
+                RateLimitHeaders::None,
+                RlsOptions {
+                    matched_limits_header,
+                    ..Default::default()
+                },
+            )
+        };
 
-        let namespace = "test_namespace";
+        let descriptor = |key: &str| RateLimitDescriptor {
+            entries: vec![
+                Entry {
+                    key: key.to_string(),
+                    value: "1".to_string(),
+                },
+                Entry {
+                    key: "z".to_string(),
+                    value: "1".to_string(),
+                },
+            ],
+            limit: None,
+            hits_addend: None,
+        };
+        let req = |keys: &[&str]| RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: keys.iter().map(|key| descriptor(key)).collect(),
+            hits_addend: 1,
+        };
 
-        vec![
-            Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["z"]),
-            Limit::new(namespace, 0, 60, vec!["x == '1'", "y == '2'"], vec!["z"]),
-        ]
-        .into_iter()
-        .for_each(|limit| {
-            limiter.add_limit(limit);
-        });
+        let response = rate_limiter(false)
+            .should_rate_limit(req(&["x"]).into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.response_headers_to_add.is_empty());
 
-        let rate_limiter = MyRateLimiter::new(
-            Arc::new(Limiter::Blocking(limiter)),
-            RateLimitHeaders::DraftVersion03,
+        // By descriptor, then the limits of the same priority by name, the
+        // ones without one first
+        let response = rate_limiter(true)
+            .should_rate_limit(req(&["y", "x"]).into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(
+            response.response_headers_to_add,
+            vec![header_value(
+                MATCHED_LIMITS_HEADER,
+                "0;max=1;w=1;remaining=0, \
+                 1;max=5;w=3600;remaining=3, \
+                 1;max=10;w=60;remaining=8;name=\"per_minute\"",
+            )]
         );
+    }
 
-        let req = RateLimitRequest {
-            domain: namespace.to_string(),
-            descriptors: vec![
-                RateLimitDescriptor {
-                    entries: vec![
-                        Entry {
-                            key: "x".to_string(),
-                            value: "1".to_string(),
-                        },
-                        Entry {
-                            key: "z".to_string(),
-                            value: "1".to_string(),
-                        },
-                    ],
-                    limit: None,
+    #[tokio::test]
+    async fn test_names_the_binding_limit_when_configured_to() {
+        let namespace = "test_namespace";
+        let mut per_user = Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["z"]);
+        per_user.set_name("per_user".to_string());
+        let mut burst = Limit::new(namespace, 1, 60, vec!["y == '1'"], vec!["z"]);
+        burst.set_name("burst".to_string());
+
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(per_user);
+        limiter.add_limit(burst);
+        let limiter = Arc::new(Limiter::Blocking(limiter));
+
+        let rate_limiter = |binding_limit_name| {
+            MyRateLimiter::with_options(
+                Arc::clone(&limiter),
+                RateLimitHeaders::None,
+                RlsOptions {
+                    binding_limit_name,
+                    ..Default::default()
                 },
-                // If this is taken into account, the result will be "overlimit"
-                // because of the second limit that has a max of 0.
-                RateLimitDescriptor {
-                    entries: vec![Entry {
-                        key: "y".to_string(),
-                        value: "2".to_string(),
-                    }],
-                    limit: None,
+            )
+        };
+
+        let descriptor = |key: &str| RateLimitDescriptor {
+            entries: vec![
+                Entry {
+                    key: key.to_string(),
+                    value: "1".to_string(),
+                },
+                Entry {
+                    key: "z".to_string(),
+                    value: "1".to_string(),
                 },
             ],
+            limit: None,
+            hits_addend: None,
+        };
+        let req = |keys: &[&str]| RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: keys.iter().map(|key| descriptor(key)).collect(),
             hits_addend: 1,
         };
 
-        let response = rate_limiter
-            .should_rate_limit(req.clone().into_request())
+        let response = rate_limiter(None)
+            .should_rate_limit(req(&["x"]).into_request())
             .await
             .unwrap()
             .into_inner();
+        assert!(response.response_headers_to_add.is_empty());
+        assert!(response.dynamic_metadata.is_none());
 
-        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        // Let through, the limit with the fewest hits remaining
+        let response = rate_limiter(Some(BindingLimitName::Header))
+            .should_rate_limit(req(&["x", "y"]).into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
         assert_eq!(
             response.response_headers_to_add,
-            vec![
-                header_value("X-RateLimit-Limit", "0, 0;w=60, 10;w=60"),
-                header_value("X-RateLimit-Remaining", "0"),
-            ],
+            vec![header_value(LIMIT_NAME_HEADER, "burst")]
+        );
+
+        // Limited, the one over the limit
+        let response = rate_limiter(Some(BindingLimitName::Metadata))
+            .should_rate_limit(req(&["x", "y"]).into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert!(response.response_headers_to_add.is_empty());
+        assert_eq!(
+            response.dynamic_metadata.unwrap().fields["limit_name"].kind,
+            Some(prost_types::value::Kind::StringValue("burst".to_string()))
         );
     }
 
     #[tokio::test]
-    async fn test_takes_into_account_the_hits_addend_param() {
+    async fn test_warns_of_the_requests_past_the_soft_max_value() {
         let namespace = "test_namespace";
-        let limit = Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["y"]);
+        let mut limit = Limit::new(namespace, 2, 60, vec!["x == '1'"], vec!["z"]);
+        limit.set_soft_max_value(Some(1));
 
         let limiter = RateLimiter::new(10_000);
         limiter.add_limit(limit);
 
-        let rate_limiter = MyRateLimiter::new(
-            Arc::new(Limiter::Blocking(limiter)),
-            RateLimitHeaders::DraftVersion03,
+        let rate_limiter =
+            MyRateLimiter::new(Arc::new(Limiter::Blocking(limiter)), RateLimitHeaders::None);
+
+        let req = || RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "z".to_string(),
+                        value: "1".to_string(),
+                    },
+                ],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        };
+
+        let mut answers = vec![];
+        for _ in 0..3 {
+            let response = rate_limiter
+                .should_rate_limit(req().into_request())
+                .await
+                .unwrap()
+                .into_inner();
+            answers.push((response.overall_code, response.response_headers_to_add));
+        }
+        assert_eq!(
+            answers,
+            vec![
+                (i32::from(Code::Ok), vec![]),
+                (
+                    i32::from(Code::Ok),
+                    vec![header_value(SOFT_LIMITED_HEADER, "true")]
+                ),
+                (i32::from(Code::OverLimit), vec![]),
+            ]
         );
+    }
+
+    #[tokio::test]
+    async fn test_reports_the_tier_of_the_group_the_request_is_over() {
+        let namespace = "test_namespace";
+        let mut burst = Limit::new(namespace, 2, 1, vec!["x == '1'"], vec!["z"]);
+        burst.set_name("burst".to_string());
+        burst.set_group(Some("api".to_string()));
+        let mut sustained = Limit::new(namespace, 3, 60, vec!["x == '1'"], vec!["z"]);
+        sustained.set_name("sustained".to_string());
+        sustained.set_group(Some("api".to_string()));
+
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(burst);
+        limiter.add_limit(sustained);
+
+        let rate_limiter =
+            MyRateLimiter::new(Arc::new(Limiter::Blocking(limiter)), RateLimitHeaders::None);
 
         let req = RateLimitRequest {
             domain: namespace.to_string(),
@@ -451,63 +1819,64 @@ mod tests {
                         value: "1".to_string(),
                     },
                     Entry {
-                        key: "y".to_string(),
+                        key: "z".to_string(),
                         value: "1".to_string(),
                     },
                 ],
                 limit: None,
+                hits_addend: None,
             }],
-            hits_addend: 6,
+            hits_addend: 1,
+        };
+        let tier_over = |response: RateLimitResponse| {
+            assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+            response.statuses[0].current_limit.clone().unwrap().name
         };
 
-        // There's a limit of 10, "hits_addend" is 6, so the first request
-        // should return "Ok" and the second "OverLimit".
-
+        for _ in 0..2 {
+            rate_limiter
+                .should_rate_limit(req.clone().into_request())
+                .await
+                .unwrap();
+        }
         let response = rate_limiter
             .should_rate_limit(req.clone().into_request())
             .await
             .unwrap()
             .into_inner();
-        assert_eq!(response.overall_code, i32::from(Code::Ok));
-        assert_eq!(
-            response.response_headers_to_add,
-            vec![
-                header_value("X-RateLimit-Limit", "10, 10;w=60"),
-                header_value("X-RateLimit-Remaining", "4"),
-            ],
-        );
+        assert_eq!(tier_over(response), "api/burst");
 
-        let response = rate_limiter
+        // Once the burst is over, the sustained tier still applies
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        rate_limiter
             .should_rate_limit(req.clone().into_request())
             .await
+            .unwrap();
+        let response = rate_limiter
+            .should_rate_limit(req.into_request())
+            .await
             .unwrap()
             .into_inner();
-        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
-        assert_eq!(
-            response.response_headers_to_add,
-            vec![
-                header_value("X-RateLimit-Limit", "10, 10;w=60"),
-                header_value("X-RateLimit-Remaining", "0"),
-            ],
-        );
+        assert_eq!(tier_over(response), "api/sustained");
     }
 
     #[tokio::test]
-    async fn test_0_hits_addend_is_converted_to_1() {
-        // "hits_addend" is optional according to the spec, and should default
-        // to 1, However, with the autogenerated structs it defaults to 0.
-        let namespace = "test_namespace";
-        let limit = Limit::new(namespace, 1, 60, vec!["x == '1'"], vec!["y"]);
-
+    async fn test_bypassed_requests_do_not_count_hits() {
         let limiter = RateLimiter::new(10_000);
-        limiter.add_limit(limit);
+        for namespace in ["test_namespace", "other_namespace"] {
+            limiter.add_limit(Limit::new(namespace, 1, 60, vec!["x == '1'"], vec!["z"]));
+        }
 
-        let rate_limiter = MyRateLimiter::new(
+        let rate_limiter = MyRateLimiter::with_options(
             Arc::new(Limiter::Blocking(limiter)),
-            RateLimitHeaders::DraftVersion03,
+            RateLimitHeaders::None,
+            RlsOptions {
+                bypass: Bypass::parse(["test_namespace:caller=internal"]).unwrap(),
+                ..Default::default()
+            },
         );
 
-        let req = RateLimitRequest {
+        let req = |namespace: &str, caller: &str| RateLimitRequest {
             domain: namespace.to_string(),
             descriptors: vec![RateLimitDescriptor {
                 entries: vec![
@@ -516,44 +1885,1962 @@ mod tests {
                         value: "1".to_string(),
                     },
                     Entry {
-                        key: "y".to_string(),
-                        value: "2".to_string(),
+                        key: "z".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "caller".to_string(),
+                        value: caller.to_string(),
                     },
                 ],
                 limit: None,
+                hits_addend: None,
             }],
-            hits_addend: 0,
+            hits_addend: 1,
+        };
+        let code = |response: Result<Response<RateLimitResponse>, Status>| {
+            response.unwrap().into_inner().overall_code
         };
 
-        // There's a limit of 1, and hits_addend is converted to 1, so the first
-        // request should return "OK" and the second "OverLimit".
-
+        for _ in 0..3 {
+            let response = rate_limiter
+                .should_rate_limit(req("test_namespace", "internal").into_request())
+                .await;
+            assert_eq!(code(response), i32::from(Code::Ok));
+        }
+        // None of the bypassed requests counted
         let response = rate_limiter
-            .should_rate_limit(req.clone().into_request())
-            .await
-            .unwrap()
-            .into_inner();
-        assert_eq!(response.overall_code, i32::from(Code::Ok));
-        assert_eq!(
-            response.response_headers_to_add,
-            vec![
-                header_value("X-RateLimit-Limit", "1, 1;w=60"),
-                header_value("X-RateLimit-Remaining", "0"),
-            ],
+            .should_rate_limit(req("test_namespace", "external").into_request())
+            .await;
+        assert_eq!(code(response), i32::from(Code::Ok));
+        let response = rate_limiter
+            .should_rate_limit(req("test_namespace", "external").into_request())
+            .await;
+        assert_eq!(code(response), i32::from(Code::OverLimit));
+
+        // The bypass of a namespace doesn't apply to the others
+        for expected in [Code::Ok, Code::OverLimit] {
+            let response = rate_limiter
+                .should_rate_limit(req("other_namespace", "internal").into_request())
+                .await;
+            assert_eq!(code(response), i32::from(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transformed_values_share_the_counters_they_normalize_to() {
+        let namespace = "test_namespace";
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(Limit::new(
+            namespace,
+            2,
+            60,
+            vec!["x == 'get'"],
+            vec!["host"],
+        ));
+
+        let rate_limiter = MyRateLimiter::with_options(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                descriptor_transforms: DescriptorTransforms::parse([
+                    "x:lowercase",
+                    "host:trim",
+                    "host:lowercase",
+                    "host:replace::\\d+$:",
+                ])
+                .unwrap(),
+                ..Default::default()
+            },
         );
 
+        let req = |x: &str, host: &str| RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: x.to_string(),
+                    },
+                    Entry {
+                        key: "host".to_string(),
+                        value: host.to_string(),
+                    },
+                ],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        };
+        let code = |response: Result<Response<RateLimitResponse>, Status>| {
+            response.unwrap().into_inner().overall_code
+        };
+
+        // Matched on the lowercased value, and counted in the same counter
+        for (x, host, expected) in [
+            ("GET", "Example.org", Code::Ok),
+            ("get", " example.org:8080 ", Code::Ok),
+            ("Get", "EXAMPLE.ORG:443", Code::OverLimit),
+        ] {
+            let response = rate_limiter
+                .should_rate_limit(req(x, host).into_request())
+                .await;
+            assert_eq!(code(response), i32::from(expected));
+        }
         let response = rate_limiter
-            .should_rate_limit(req.clone().into_request())
+            .should_rate_limit(req("GET", "other.org").into_request())
+            .await;
+        assert_eq!(code(response), i32::from(Code::Ok));
+    }
+
+    #[tokio::test]
+    async fn test_the_kill_switch_short_circuits_the_limits() {
+        let limiter = RateLimiter::new(10_000);
+        for namespace in ["test_namespace", "other_namespace"] {
+            limiter.add_limit(Limit::new(namespace, 1, 60, vec!["x == '1'"], vec!["z"]));
+        }
+        let kill_switch = Arc::new(KillSwitch::default());
+
+        let rate_limiter = MyRateLimiter::with_options(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                kill_switch: Arc::clone(&kill_switch),
+                ..Default::default()
+            },
+        );
+
+        let req = |namespace: &str| RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "z".to_string(),
+                        value: "1".to_string(),
+                    },
+                ],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        };
+        let code = |response: Result<Response<RateLimitResponse>, Status>| {
+            response.unwrap().into_inner().overall_code
+        };
+
+        // Let through, without counting any hits
+        kill_switch.set_global(Some(KillSwitchMode::Allow));
+        for _ in 0..3 {
+            let response = rate_limiter
+                .should_rate_limit(req("test_namespace").into_request())
+                .await;
+            assert_eq!(code(response), i32::from(Code::Ok));
+        }
+
+        // The switch of a namespace wins over the global one
+        kill_switch.set_for_namespace("other_namespace", Some(KillSwitchMode::Deny));
+        let response = rate_limiter
+            .should_rate_limit(req("other_namespace").into_request())
+            .await;
+        assert_eq!(code(response), i32::from(Code::OverLimit));
+
+        kill_switch.set_global(None);
+        kill_switch.set_for_namespace("other_namespace", None);
+        for expected in [Code::Ok, Code::OverLimit] {
+            let response = rate_limiter
+                .should_rate_limit(req("test_namespace").into_request())
+                .await;
+            assert_eq!(code(response), i32::from(expected));
+        }
+        let response = rate_limiter
+            .should_rate_limit(req("other_namespace").into_request())
+            .await;
+        assert_eq!(code(response), i32::from(Code::Ok));
+    }
+
+    #[test]
+    fn test_bypass_entries_are_namespaced_keys_and_values() {
+        assert!(Bypass::parse(["ns:caller=internal", "ns:path=/health="]).is_ok());
+        for invalid in [
+            "caller=internal",
+            "ns:caller",
+            ":caller=internal",
+            "ns:=internal",
+        ] {
+            assert!(Bypass::parse([invalid]).is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_returns_ok_when_no_limits_apply() {
+        // No limits saved
+        let rate_limiter = MyRateLimiter::new(
+            Arc::new(Limiter::new(Configuration::default()).await.unwrap()),
+            RateLimitHeaders::DraftVersion03,
+        );
+
+        let req = RateLimitRequest {
+            domain: "test_namespace".to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![Entry {
+                    key: "req.method".to_string(),
+                    value: "GET".to_string(),
+                }],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        }
+        .into_request();
+
+        let response = rate_limiter
+            .should_rate_limit(req)
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(response.response_headers_to_add, vec![],);
+    }
+
+    #[tokio::test]
+    async fn test_returns_unknown_when_domain_is_empty() {
+        let rate_limiter = MyRateLimiter::new(
+            Arc::new(Limiter::new(Configuration::default()).await.unwrap()),
+            RateLimitHeaders::DraftVersion03,
+        );
+
+        let req = RateLimitRequest {
+            domain: "".to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![Entry {
+                    key: "req.method".to_string(),
+                    value: "GET".to_string(),
+                }],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        }
+        .into_request();
+
+        let response = rate_limiter
+            .should_rate_limit(req)
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Unknown));
+        assert_eq!(response.response_headers_to_add, vec![],);
+    }
+
+    #[tokio::test]
+    async fn test_answers_the_requests_without_a_domain_as_configured() {
+        let limiter = Arc::new(Limiter::new(Configuration::default()).await.unwrap());
+        let rate_limiter = |empty_domain_answer| {
+            MyRateLimiter::with_options(
+                Arc::clone(&limiter),
+                RateLimitHeaders::None,
+                RlsOptions {
+                    empty_domain_answer,
+                    ..Default::default()
+                },
+            )
+        };
+        let req = || {
+            RateLimitRequest {
+                domain: "".to_string(),
+                descriptors: vec![RateLimitDescriptor {
+                    entries: vec![Entry {
+                        key: "req.method".to_string(),
+                        value: "GET".to_string(),
+                    }],
+                    limit: None,
+                    hits_addend: None,
+                }],
+                hits_addend: 1,
+            }
+            .into_request()
+        };
+
+        for (answer, code) in [
+            (EmptyDomainAnswer::Unknown, Code::Unknown),
+            (EmptyDomainAnswer::Ok, Code::Ok),
+            (EmptyDomainAnswer::Deny, Code::OverLimit),
+        ] {
+            let response = rate_limiter(answer)
+                .should_rate_limit(req())
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.overall_code, i32::from(code), "{answer:?}");
+        }
+
+        let status = rate_limiter(EmptyDomainAnswer::Error)
+            .should_rate_limit(req())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_takes_into_account_all_the_descriptors() {
+        let limiter = RateLimiter::new(10_000);
+
+        let namespace = "test_namespace";
+
+        vec![
+            Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["z"]),
+            Limit::new(namespace, 0, 60, vec!["y == '2'"], vec!["z"]),
+        ]
+        .into_iter()
+        .for_each(|limit| {
+            limiter.add_limit(limit);
+        });
+
+        let rate_limiter = MyRateLimiter::new(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::DraftVersion03,
+        );
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![
+                RateLimitDescriptor {
+                    entries: vec![
+                        Entry {
+                            key: "x".to_string(),
+                            value: "1".to_string(),
+                        },
+                        Entry {
+                            key: "z".to_string(),
+                            value: "1".to_string(),
+                        },
+                    ],
+                    limit: None,
+                    hits_addend: None,
+                },
+                // If this is taken into account, the result will be "overlimit"
+                // because of the second limit that has a max of 0.
+                RateLimitDescriptor {
+                    entries: vec![
+                        Entry {
+                            key: "y".to_string(),
+                            value: "2".to_string(),
+                        },
+                        Entry {
+                            key: "z".to_string(),
+                            value: "1".to_string(),
+                        },
+                    ],
+                    limit: None,
+                    hits_addend: None,
+                },
+            ],
+            hits_addend: 1,
+        };
+
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
             .await
             .unwrap()
             .into_inner();
+
         assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert_eq!(
+            response
+                .statuses
+                .iter()
+                .map(|status| status.code)
+                .collect::<Vec<_>>(),
+            vec![i32::from(Code::Ok), i32::from(Code::OverLimit)],
+        );
         assert_eq!(
             response.response_headers_to_add,
             vec![
-                header_value("X-RateLimit-Limit", "1, 1;w=60"),
+                header_value("X-RateLimit-Limit", "0, 0;w=60, 10;w=60"),
+                header_value("X-RateLimit-Remaining", "0"),
+                header_value("X-RateLimit-Reset", "60"),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_takes_into_account_the_hits_addend_param() {
+        let namespace = "test_namespace";
+        let limit = Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["y"]);
+
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(limit);
+
+        let rate_limiter = MyRateLimiter::new(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::DraftVersion03,
+        );
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "y".to_string(),
+                        value: "1".to_string(),
+                    },
+                ],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 6,
+        };
+
+        // There's a limit of 10, "hits_addend" is 6, so the first request
+        // should return "Ok" and the second "OverLimit".
+
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(
+            response.response_headers_to_add,
+            vec![
+                header_value("X-RateLimit-Limit", "10, 10;w=60"),
+                header_value("X-RateLimit-Remaining", "4"),
+                header_value("X-RateLimit-Reset", "60"),
+            ],
+        );
+
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert_eq!(
+            response.response_headers_to_add,
+            vec![
+                header_value("X-RateLimit-Limit", "10, 10;w=60"),
                 header_value("X-RateLimit-Remaining", "0"),
+                header_value("X-RateLimit-Reset", "60"),
             ],
         );
     }
+
+    #[tokio::test]
+    async fn test_grants_quotas_within_the_limits() {
+        let namespace = "test_namespace";
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["z"]));
+        limiter.add_limit(Limit::new(namespace, 100, 5, vec!["y == '1'"], vec!["z"]));
+
+        let rate_limiter = MyRateLimiter::with_options(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                quota_grants: Some(QuotaGrants {
+                    requests: 4,
+                    validity: Duration::from_secs(30),
+                }),
+                ..Default::default()
+            },
+        );
+
+        let descriptor = |key: &str| RateLimitDescriptor {
+            entries: vec![
+                Entry {
+                    key: key.to_string(),
+                    value: "1".to_string(),
+                },
+                Entry {
+                    key: "z".to_string(),
+                    value: "1".to_string(),
+                },
+            ],
+            limit: None,
+            hits_addend: None,
+        };
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![descriptor("x"), descriptor("y")],
+            hits_addend: 1,
+        };
+        let valid_for = |quota: &Option<Quota>| {
+            let Some(quota::ExpirationSpecifier::ValidUntil(until)) =
+                quota.as_ref().unwrap().expiration_specifier.clone()
+            else {
+                panic!("the quota doesn't expire")
+            };
+            SystemTime::try_from(until)
+                .unwrap()
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        };
+
+        // The hits of the whole quota are counted up front, and it expires
+        // no later than the counters it was counted in
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(response.statuses[0].limit_remaining, 6);
+        assert_eq!(response.statuses[0].quota.as_ref().unwrap().requests, 4);
+        assert!(valid_for(&response.statuses[0].quota) > Duration::from_secs(25));
+        assert!(valid_for(&response.statuses[0].quota) <= Duration::from_secs(30));
+        assert_eq!(response.statuses[1].limit_remaining, 96);
+        assert!(valid_for(&response.statuses[1].quota) <= Duration::from_secs(5));
+
+        rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap();
+
+        // Another quota would get over the limit, so only the hits of the
+        // request are counted, and no quota is granted
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(response.statuses[0].limit_remaining, 1);
+        assert_eq!(response.statuses[0].quota, None);
+        assert_eq!(response.statuses[1].quota.as_ref().unwrap().requests, 4);
+    }
+
+    #[tokio::test]
+    async fn test_reserves_the_hits_of_the_requests_checked_in_two_phases() {
+        let namespace = "test_namespace";
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(Limit::new(namespace, 1, 60, vec!["x == '1'"], vec!["z"]));
+        let limiter = Arc::new(Limiter::Blocking(limiter));
+        let reservations = Arc::new(Reservations::new(
+            Arc::clone(&limiter),
+            Duration::from_secs(30),
+        ));
+
+        let rate_limiter = MyRateLimiter::with_options(
+            limiter,
+            RateLimitHeaders::None,
+            RlsOptions {
+                reservations: Some(Arc::clone(&reservations)),
+                ..Default::default()
+            },
+        );
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "z".to_string(),
+                        value: "1".to_string(),
+                    },
+                ],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        };
+        let reserving_request = || {
+            let mut request = req.clone().into_request();
+            request
+                .metadata_mut()
+                .insert(RESERVE_METADATA_KEY, "true".parse().unwrap());
+            request
+        };
+
+        let response = rate_limiter
+            .should_rate_limit(reserving_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        let [HeaderValue { key, value: id }] = &response.request_headers_to_add[..] else {
+            panic!("the request isn't told its reservation")
+        };
+        assert_eq!(key, RESERVATION_HEADER);
+        let metadata = response.dynamic_metadata.unwrap();
+        assert_eq!(
+            metadata.fields["reservation"].kind,
+            Some(prost_types::value::Kind::StringValue(id.clone()))
+        );
+
+        // Until released, the hit counts, as any other
+        let response = rate_limiter
+            .should_rate_limit(reserving_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert!(response.request_headers_to_add.is_empty());
+        assert!(reservations.release(id).await.unwrap());
+
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert!(response.request_headers_to_add.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_takes_into_account_the_hits_addend_of_each_descriptor() {
+        let namespace = "test_namespace";
+        let limit = Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["y"]);
+
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(limit);
+
+        let rate_limiter =
+            MyRateLimiter::new(Arc::new(Limiter::Blocking(limiter)), RateLimitHeaders::None);
+
+        let descriptor = |y: &str, hits_addend: Option<u64>| RateLimitDescriptor {
+            entries: vec![
+                Entry {
+                    key: "x".to_string(),
+                    value: "1".to_string(),
+                },
+                Entry {
+                    key: "y".to_string(),
+                    value: y.to_string(),
+                },
+            ],
+            limit: None,
+            hits_addend,
+        };
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![descriptor("1", Some(6)), descriptor("2", None)],
+            hits_addend: 2,
+        };
+
+        // There's a limit of 10, the first descriptor adds its own 6 hits,
+        // the second one falls back to the 2 of the request. So the second
+        // request is only over the limit for the first descriptor.
+
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(response.statuses[0].limit_remaining, 4);
+        assert_eq!(response.statuses[1].limit_remaining, 8);
+
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert_eq!(
+            response
+                .statuses
+                .iter()
+                .map(|status| status.code)
+                .collect::<Vec<_>>(),
+            vec![i32::from(Code::OverLimit), i32::from(Code::Ok)],
+        );
+        assert_eq!(response.statuses[1].limit_remaining, 6);
+    }
+
+    #[tokio::test]
+    async fn test_a_descriptor_hits_addend_of_0_only_checks_its_limits() {
+        let namespace = "test_namespace";
+        let limit = Limit::new(namespace, 1, 60, vec!["x == '1'"], vec!["y"]);
+
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(limit);
+
+        let rate_limiter =
+            MyRateLimiter::new(Arc::new(Limiter::Blocking(limiter)), RateLimitHeaders::None);
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "y".to_string(),
+                        value: "1".to_string(),
+                    },
+                ],
+                limit: None,
+                hits_addend: Some(0),
+            }],
+            hits_addend: 1,
+        };
+
+        // The descriptor overrides the hits of the request with 0, so none of
+        // the requests consumes the single hit the limit allows.
+        for _ in 0..3 {
+            let response = rate_limiter
+                .should_rate_limit(req.clone().into_request())
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.overall_code, i32::from(Code::Ok));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_standard_headers_use_the_ratelimit_names() {
+        let namespace = "test_namespace";
+        let limit = Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["y"]);
+
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(limit);
+
+        let rate_limiter = MyRateLimiter::new(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::Standard,
+        );
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "y".to_string(),
+                        value: "2".to_string(),
+                    },
+                ],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        };
+
+        let response = rate_limiter
+            .should_rate_limit(req.into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(
+            response.response_headers_to_add,
+            vec![
+                header_value("RateLimit-Limit", "10, 10;w=60"),
+                header_value("RateLimit-Remaining", "9"),
+                header_value("RateLimit-Reset", "60"),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_the_rate_limit_headers_can_be_set_by_namespace() {
+        let limiter = RateLimiter::new(10_000);
+        for namespace in ["informed", "uninformed"] {
+            limiter.add_limit(Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["y"]));
+        }
+
+        let rate_limiter = MyRateLimiter::with_options(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                namespace_rate_limit_headers: RateLimitHeaders::parse_by_namespace([
+                    "informed=STANDARD",
+                ])
+                .unwrap(),
+                ..Default::default()
+            },
+        );
+
+        let req = |namespace: &str| RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "y".to_string(),
+                        value: "2".to_string(),
+                    },
+                ],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        };
+
+        let response = rate_limiter
+            .should_rate_limit(req("informed").into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(
+            response.response_headers_to_add,
+            vec![
+                header_value("RateLimit-Limit", "10, 10;w=60"),
+                header_value("RateLimit-Remaining", "9"),
+                header_value("RateLimit-Reset", "60"),
+            ],
+        );
+
+        let response = rate_limiter
+            .should_rate_limit(req("uninformed").into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert!(response.response_headers_to_add.is_empty());
+
+        assert!(RateLimitHeaders::parse_by_namespace(["informed=ALL"]).is_err());
+        assert!(RateLimitHeaders::parse_by_namespace(["STANDARD"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_the_namespace_can_be_suffixed_with_a_descriptor_entry() {
+        let limiter = RateLimiter::new(10_000);
+        for namespace in ["example.org", "example.org/tenant-a", "tenant-b"] {
+            limiter.add_limit(Limit::new(namespace, 1, 60, vec!["x == '1'"], vec!["y"]));
+        }
+
+        let rate_limiter = MyRateLimiter::with_options(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                namespace_entry: Some("tenant".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let req = |domain: &str, tenant: Option<&str>| {
+            let mut entries = vec![
+                Entry {
+                    key: "x".to_string(),
+                    value: "1".to_string(),
+                },
+                Entry {
+                    key: "y".to_string(),
+                    value: "2".to_string(),
+                },
+            ];
+            entries.extend(tenant.map(|tenant| Entry {
+                key: "tenant".to_string(),
+                value: tenant.to_string(),
+            }));
+            RateLimitRequest {
+                domain: domain.to_string(),
+                descriptors: vec![RateLimitDescriptor {
+                    entries,
+                    limit: None,
+                    hits_addend: None,
+                }],
+                hits_addend: 1,
+            }
+        };
+
+        // Each namespace counts its own hits
+        for (request, code) in [
+            (req("example.org", Some("tenant-a")), Code::Ok),
+            (req("example.org", Some("tenant-a")), Code::OverLimit),
+            (req("example.org", None), Code::Ok),
+            (req("example.org", None), Code::OverLimit),
+            (req("", Some("tenant-b")), Code::Ok),
+            (req("", Some("tenant-b")), Code::OverLimit),
+            // No limits in `example.org/tenant-b`
+            (req("example.org", Some("tenant-b")), Code::Ok),
+            (req("example.org", Some("tenant-b")), Code::Ok),
+        ] {
+            let response = rate_limiter
+                .should_rate_limit(request.into_request())
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.overall_code, i32::from(code));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_only_requests_do_not_consume_hits() {
+        let namespace = "test_namespace";
+        let limit = Limit::new(namespace, 1, 60, vec!["x == '1'"], vec!["z"]);
+
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(limit);
+
+        let rate_limiter =
+            MyRateLimiter::new(Arc::new(Limiter::Blocking(limiter)), RateLimitHeaders::None);
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "z".to_string(),
+                        value: "1".to_string(),
+                    },
+                ],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        };
+
+        let check_only_request = || {
+            let mut request = req.clone().into_request();
+            request
+                .metadata_mut()
+                .insert(CHECK_ONLY_METADATA_KEY, "true".parse().unwrap());
+            request
+        };
+
+        for _ in 0..3 {
+            let response = rate_limiter
+                .should_rate_limit(check_only_request())
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.overall_code, i32::from(Code::Ok));
+        }
+
+        // The hit is only consumed now, which uses up the limit
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+
+        let response = rate_limiter
+            .should_rate_limit(check_only_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert_eq!(response.statuses[0].code, i32::from(Code::OverLimit));
+    }
+
+    struct TestClock;
+    impl Clock for TestClock {
+        fn get_current_time(&self) -> SystemTime {
+            SystemTime::now()
+        }
+    }
+
+    // The wasm storage only supports fixed windows, so checking a sliding
+    // window against it is an easy way of getting a storage error.
+    fn failing_rate_limiter(failure_mode: FailureMode) -> MyRateLimiter {
+        let mut limit = Limit::new("test_namespace", 10, 60, vec!["x == '1'"], vec!["z"]);
+        limit.set_window_type(WindowType::Sliding);
+
+        let limiter =
+            RateLimiter::new_with_storage(Box::new(WasmStorage::new(Box::new(TestClock))));
+        limiter.add_limit(limit);
+
+        MyRateLimiter::with_options(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                failure_mode,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn request_hitting_the_failing_limit() -> Request<RateLimitRequest> {
+        RateLimitRequest {
+            domain: "test_namespace".to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "z".to_string(),
+                        value: "1".to_string(),
+                    },
+                ],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        }
+        .into_request()
+    }
+
+    #[tokio::test]
+    async fn test_failure_mode_allow_returns_ok_on_storage_errors() {
+        let response = failing_rate_limiter(FailureMode::Allow)
+            .should_rate_limit(request_hitting_the_failing_limit())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(response.statuses.len(), 1);
+        assert_eq!(response.statuses[0].code, i32::from(Code::Ok));
+    }
+
+    #[tokio::test]
+    async fn test_failure_mode_deny_returns_overlimit_on_storage_errors() {
+        let response = failing_rate_limiter(FailureMode::Deny)
+            .should_rate_limit(request_hitting_the_failing_limit())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert_eq!(response.statuses.len(), 1);
+        assert_eq!(response.statuses[0].code, i32::from(Code::OverLimit));
+    }
+
+    #[tokio::test]
+    async fn test_failure_mode_passthrough_returns_the_error_of_the_storage() {
+        // The storage can't enforce the sliding window of the limit, which no
+        // retry fixes
+        let status = failing_rate_limiter(FailureMode::Passthrough)
+            .should_rate_limit(request_hitting_the_failing_limit())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        let errors = [
+            (
+                LimitadorError::StorageConnection("connection refused".to_string()),
+                tonic::Code::Unavailable,
+            ),
+            (
+                LimitadorError::Storage("can't acquire lock".to_string()),
+                tonic::Code::Unavailable,
+            ),
+            (
+                LimitadorError::Serialization("corrupt counter".to_string()),
+                tonic::Code::Internal,
+            ),
+        ];
+        for (error, code) in errors {
+            assert_eq!(to_status(&error).0.code(), code);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_answers_to_storage_errors_are_approximate() {
+        let mut rate_limiter = failing_rate_limiter(FailureMode::Deny);
+        rate_limiter.options.approximate_decisions = ApproximateDecisions {
+            unknown_over_limit: true,
+            header: true,
+        };
+        let response = rate_limiter
+            .should_rate_limit(request_hitting_the_failing_limit())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert_eq!(
+            response.response_headers_to_add,
+            vec![header_value(APPROXIMATE_HEADER, "true")]
+            RequestBounds::default(),
+            None,
+        );
+    }
+
+    #[tokio::test]
+    async fn test_approximate_over_limit_answers_are_unknown_when_configured_to() {
+        let limit = Limit::new("test_namespace", 1, 60, vec!["x == '1'"], vec!["z"]);
+        let storage = AsyncDecisionCache::new(
+            Box::new(AsyncCounterStorageAdapter::new(
+                Box::<InMemoryStorage>::default(),
+            )),
+            Duration::from_secs(60),
+            100,
+        );
+        let limiter = AsyncRateLimiter::new_with_storage(Box::new(storage));
+        limiter.add_limit(limit);
+
+        let mut rate_limiter =
+            MyRateLimiter::new(Arc::new(Limiter::Async(limiter)), RateLimitHeaders::None);
+
+        // The first answer is read from the storage, the next ones from what
+        // the cache kept of it
+        for (approximate_decisions, code, headers) in [
+            (ApproximateDecisions::default(), Code::Ok, vec![]),
+            (ApproximateDecisions::default(), Code::OverLimit, vec![]),
+            (
+                ApproximateDecisions {
+                    unknown_over_limit: true,
+                    header: true,
+                },
+                Code::Unknown,
+                vec![header_value(APPROXIMATE_HEADER, "true")],
+            ),
+        ] {
+            rate_limiter.options.approximate_decisions = approximate_decisions;
+            let response = rate_limiter
+                .should_rate_limit(request_hitting_the_failing_limit())
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.overall_code, i32::from(code));
+            assert_eq!(response.statuses[0].code, i32::from(code));
+            assert_eq!(response.response_headers_to_add, headers);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_0_hits_addend_is_converted_to_1() {
+        // "hits_addend" is optional according to the spec, and should default
+        // to 1, However, with the autogenerated structs it defaults to 0.
+        let namespace = "test_namespace";
+        let limit = Limit::new(namespace, 1, 60, vec!["x == '1'"], vec!["y"]);
+
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(limit);
+
+        let rate_limiter = MyRateLimiter::new(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::DraftVersion03,
+        );
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![
+                    Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    },
+                    Entry {
+                        key: "y".to_string(),
+                        value: "2".to_string(),
+                    },
+                ],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 0,
+        };
+
+        // There's a limit of 1, and hits_addend is converted to 1, so the first
+        // request should return "OK" and the second "OverLimit".
+
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(
+            response.response_headers_to_add,
+            vec![
+                header_value("X-RateLimit-Limit", "1, 1;w=60"),
+                header_value("X-RateLimit-Remaining", "0"),
+                header_value("X-RateLimit-Reset", "60"),
+            ],
+        );
+
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert_eq!(
+            response.response_headers_to_add,
+            vec![
+                header_value("X-RateLimit-Limit", "1, 1;w=60"),
+                header_value("X-RateLimit-Remaining", "0"),
+                header_value("X-RateLimit-Reset", "60"),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_0_hits_addend_only_checks_when_configured_to() {
+        let namespace = "test_namespace";
+        let limit = Limit::new(namespace, 1, 60, vec!["x == '1'"], Vec::<String>::default());
+
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(limit);
+
+        let rate_limiter = MyRateLimiter::with_options(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                zero_hits_addend_checks_only: true,
+                ..Default::default()
+            },
+        );
+
+        let req = |hits_addend| {
+            RateLimitRequest {
+                domain: namespace.to_string(),
+                descriptors: vec![RateLimitDescriptor {
+                    entries: vec![Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    }],
+                    limit: None,
+                    hits_addend: None,
+                }],
+                hits_addend,
+            }
+            .into_request()
+        };
+
+        // There's a limit of 1: the checks don't consume it, the first request
+        // with hits does
+        for (hits_addend, expected_code) in [
+            (0, Code::Ok),
+            (0, Code::Ok),
+            (1, Code::Ok),
+            (1, Code::OverLimit),
+        ] {
+            let response = rate_limiter
+                .should_rate_limit(req(hits_addend))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.overall_code, i32::from(expected_code));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reports_when_to_retry_the_descriptors_over_limit() {
+        let namespace = "test_namespace";
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(Limit::new(
+            namespace,
+            1,
+            60,
+            vec!["x == '1'"],
+            Vec::<String>::default(),
+        ));
+        limiter.add_limit(Limit::new(
+            namespace,
+            10,
+            60,
+            vec!["y == '1'"],
+            Vec::<String>::default(),
+        ));
+
+        let rate_limiter = MyRateLimiter::with_options(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                retry_after_header: true,
+                ..Default::default()
+            },
+        );
+
+        let descriptor = |key: &str| RateLimitDescriptor {
+            entries: vec![Entry {
+                key: key.to_string(),
+                value: "1".to_string(),
+            }],
+            limit: None,
+            hits_addend: None,
+        };
+        let req = || {
+            RateLimitRequest {
+                domain: namespace.to_string(),
+                descriptors: vec![descriptor("x"), descriptor("y")],
+                hits_addend: 1,
+            }
+            .into_request()
+        };
+
+        let response = rate_limiter
+            .should_rate_limit(req())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert!(response
+            .statuses
+            .iter()
+            .all(|status| status.duration_until_reset.is_none()));
+        assert!(response.response_headers_to_add.is_empty());
+
+        let response = rate_limiter
+            .should_rate_limit(req())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        let duration_until_reset = response.statuses[0]
+            .duration_until_reset
+            .clone()
+            .expect("the counter's TTL");
+        assert!(duration_until_reset.seconds > 0 && duration_until_reset.seconds <= 60);
+        assert_eq!(response.statuses[1].code, i32::from(Code::Ok));
+        assert_eq!(response.statuses[1].duration_until_reset, None);
+        assert_eq!(
+            response.response_headers_to_add,
+            vec![header_value("Retry-After", "60")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_answers_the_requests_over_limit_with_the_body_configured() {
+        let namespace = "test_namespace";
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(Limit::new(
+            namespace,
+            1,
+            60,
+            vec!["x == '1'"],
+            Vec::<String>::default(),
+        ));
+
+        let rate_limiter = MyRateLimiter::with_options(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                over_limit_body: Some(
+                    OverLimitBody::new(
+                        r#"{"namespace": "{namespace}", "retry_after": {retry_after}}"#.to_string(),
+                        OverLimitBodyFormat::Json,
+                    )
+                    .unwrap(),
+                ),
+                ..Default::default()
+            },
+        );
+
+        let req = || {
+            RateLimitRequest {
+                domain: namespace.to_string(),
+                descriptors: vec![RateLimitDescriptor {
+                    entries: vec![Entry {
+                        key: "x".to_string(),
+                        value: "1".to_string(),
+                    }],
+                    limit: None,
+                    hits_addend: None,
+                }],
+                hits_addend: 1,
+            }
+            .into_request()
+        };
+
+        let response = rate_limiter
+            .should_rate_limit(req())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert!(response.raw_body.is_empty());
+        assert!(response.response_headers_to_add.is_empty());
+
+        let response = rate_limiter
+            .should_rate_limit(req())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert_eq!(
+            String::from_utf8(response.raw_body).unwrap(),
+            r#"{"namespace": "test_namespace", "retry_after": 60}"#
+        );
+        assert_eq!(
+            response.response_headers_to_add,
+            vec![header_value("Content-Type", "application/json")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_value_override_only_in_the_namespaces_allowing_it() {
+        let limiter = RateLimiter::new(10_000);
+        for namespace in ["overridable", "fixed"] {
+            limiter.add_limit(Limit::new(
+                namespace,
+                1,
+                60,
+                vec!["x == '1'"],
+                Vec::<String>::default(),
+            ));
+        }
+
+        let rate_limiter = MyRateLimiter::with_options(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                max_value_override_namespaces: HashSet::from(["overridable".into()]),
+                ..Default::default()
+            },
+        );
+
+        let req = |namespace: &str, max_value: &str| {
+            RateLimitRequest {
+                domain: namespace.to_string(),
+                descriptors: vec![RateLimitDescriptor {
+                    entries: vec![
+                        Entry {
+                            key: "x".to_string(),
+                            value: "1".to_string(),
+                        },
+                        Entry {
+                            key: MAX_VALUE_OVERRIDE_KEY.to_string(),
+                            value: max_value.to_string(),
+                        },
+                    ],
+                    limit: None,
+                    hits_addend: None,
+                }],
+                hits_addend: 1,
+            }
+            .into_request()
+        };
+
+        for (namespace, expected_codes) in [
+            ("overridable", [Code::Ok, Code::Ok, Code::OverLimit]),
+            ("fixed", [Code::Ok, Code::OverLimit, Code::OverLimit]),
+        ] {
+            for expected_code in expected_codes {
+                let response = rate_limiter
+                    .should_rate_limit(req(namespace, "2"))
+                    .await
+                    .unwrap()
+                    .into_inner();
+                assert_eq!(response.overall_code, i32::from(expected_code));
+            }
+        }
+
+        let status = rate_limiter
+            .should_rate_limit(req("overridable", "lots"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_the_requests_over_the_bounds() {
+        let namespace = "test_namespace";
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(Limit::new(namespace, 10, 60, vec!["x == '1'"], vec!["z"]));
+
+        let rate_limiter = MyRateLimiter::with_options(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                request_bounds: RequestBounds {
+                    max_descriptors: 2,
+                    max_descriptor_entries: 2,
+                },
+                ..Default::default()
+            },
+        );
+
+        let descriptor = |entries: usize| RateLimitDescriptor {
+            entries: (0..entries)
+                .map(|i| Entry {
+                    key: if i == 0 { "x" } else { "z" }.to_string(),
+                    value: "1".to_string(),
+                })
+                .collect(),
+            limit: None,
+            hits_addend: None,
+        };
+        let req = |descriptors: Vec<RateLimitDescriptor>| {
+            RateLimitRequest {
+                domain: namespace.to_string(),
+                descriptors,
+                hits_addend: 1,
+            }
+            .into_request()
+        };
+
+        let response = rate_limiter
+            .should_rate_limit(req(vec![descriptor(2), descriptor(2)]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+
+        for descriptors in [vec![descriptor(2); 3], vec![descriptor(1), descriptor(3)]] {
+            let status = rate_limiter
+                .should_rate_limit(req(descriptors))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+
+        // The rejected requests didn't count any hits
+        let response = rate_limiter
+            .should_rate_limit(req(vec![descriptor(2)]))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.statuses[0].limit_remaining, 7);
+    }
+
+    // Takes `delay` to answer the checks, telling when one comes in, and
+    // counts the times it's flushed
+    struct SlowStorage {
+        storage: AsyncCounterStorageAdapter,
+        delay: Duration,
+        checking: Arc<tokio::sync::Notify>,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl AsyncCounterStorage for SlowStorage {
+        async fn is_within_limits(
+            &self,
+            counter: &Counter,
+            delta: i64,
+        ) -> Result<bool, StorageErr> {
+            self.storage.is_within_limits(counter, delta).await
+        }
+
+        async fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+            self.storage.update_counter(counter, delta).await
+        }
+
+        async fn check_and_update(
+            &self,
+            counters: &mut Vec<Counter>,
+            delta: i64,
+            load_counters: bool,
+        ) -> Result<Authorization, StorageErr> {
+            self.checking.notify_one();
+            tokio::time::sleep(self.delay).await;
+            self.storage
+                .check_and_update(counters, delta, load_counters)
+                .await
+        }
+
+        async fn set_counter(
+            &self,
+            counter: &Counter,
+            remaining: i64,
+            expires_in: Duration,
+        ) -> Result<(), StorageErr> {
+            self.storage
+                .set_counter(counter, remaining, expires_in)
+                .await
+        }
+
+        async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+            self.storage.delete_counter(counter).await
+        }
+
+        async fn get_counters(
+            &self,
+            limits: HashSet<Limit>,
+        ) -> Result<HashSet<Counter>, StorageErr> {
+            self.storage.get_counters(limits).await
+        }
+
+        async fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+            self.storage.delete_counters(limits).await
+        }
+
+        async fn clear(&self) -> Result<(), StorageErr> {
+            self.storage.clear().await
+        }
+
+        async fn flush(&self) -> Result<(), StorageErr> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_storage_timeouts_answer_as_the_failure_mode_says() {
+        let limiter = AsyncRateLimiter::new_with_storage(Box::new(SlowStorage {
+            storage: AsyncCounterStorageAdapter::new(Box::<InMemoryStorage>::default()),
+            delay: Duration::from_secs(600),
+            checking: Arc::new(tokio::sync::Notify::new()),
+            flushes: Arc::new(AtomicUsize::new(0)),
+        }));
+        limiter.add_limit(Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["x == '1'"],
+            vec!["x"],
+        ));
+        let rate_limiter = MyRateLimiter::with_options(
+            Arc::new(Limiter::Async(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions {
+                failure_mode: FailureMode::Deny,
+                storage_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        );
+
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            rate_limiter.should_rate_limit(request_hitting_the_slow_limit().into_request()),
+        )
+        .await
+        .expect("the storage timeout should have answered")
+        .unwrap()
+        .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        assert_eq!(response.statuses[0].code, i32::from(Code::OverLimit));
+    }
+
+    struct SlowServer {
+        address: String,
+        checking: Arc<tokio::sync::Notify>,
+        flushes: Arc<AtomicUsize>,
+        shutdown: tokio::sync::oneshot::Sender<()>,
+        server: tokio::task::JoinHandle<Result<(), Box<dyn Error + Send + Sync>>>,
+    }
+
+    fn free_address() -> String {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .to_string()
+    }
+
+    fn serve_slowly(delay: Duration, drain_timeout: Duration) -> SlowServer {
+        serve_slowly_on(
+            vec![free_address()],
+            delay,
+            drain_timeout,
+            false,
+            None,
+            None,
+        )
+    }
+
+    fn serve_slowly_on(
+        addresses: Vec<String>,
+        delay: Duration,
+        drain_timeout: Duration,
+        compression: bool,
+        max_decoding_message_size: Option<usize>,
+        max_encoding_message_size: Option<usize>,
+    ) -> SlowServer {
+        let checking = Arc::new(tokio::sync::Notify::new());
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let limiter = AsyncRateLimiter::new_with_storage(Box::new(SlowStorage {
+            storage: AsyncCounterStorageAdapter::new(Box::<InMemoryStorage>::default()),
+            delay,
+            checking: Arc::clone(&checking),
+            flushes: Arc::clone(&flushes),
+        }));
+        limiter.add_limit(Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["x == '1'"],
+            vec!["x"],
+        ));
+
+        let (shutdown, shutting_down) = tokio::sync::oneshot::channel::<()>();
+        let address = addresses[0].clone();
+        let server = tokio::spawn(run_envoy_rls_server(
+            addresses,
+            Arc::new(Limiter::Async(limiter)),
+            RateLimitHeaders::None,
+            RlsOptions::default(),
+            RlsServerOptions {
+                compression,
+                max_decoding_message_size,
+                max_encoding_message_size,
+                drain_timeout,
+                ..Default::default()
+            },
+            async {
+                let _ = shutting_down.await;
+            },
+        ));
+
+        SlowServer {
+            address,
+            checking,
+            flushes,
+            shutdown,
+            server,
+        }
+    }
+
+    fn request_hitting_the_slow_limit() -> RateLimitRequest {
+        RateLimitRequest {
+            domain: "test_namespace".to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![Entry {
+                    key: "x".to_string(),
+                    value: "1".to_string(),
+                }],
+                limit: None,
+                hits_addend: None,
+            }],
+            hits_addend: 1,
+        }
+    }
+
+    // Sends a request hitting the limit, once the server is up
+    fn request_in_flight(
+        address: &str,
+    ) -> tokio::task::JoinHandle<Result<Response<RateLimitResponse>, Status>> {
+        let endpoint = format!("http://{address}");
+        tokio::spawn(async move {
+            let mut client = loop {
+                match RateLimitServiceClient::connect(endpoint.clone()).await {
+                    Ok(client) => break client,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            client
+                .should_rate_limit(request_hitting_the_slow_limit())
+                .await
+        })
+    }
+
+    #[tokio::test]
+    async fn test_requests_in_flight_complete_on_shutdown() {
+        let server = serve_slowly(Duration::from_millis(200), Duration::from_secs(10));
+        let answered = request_in_flight(&server.address);
+
+        server.checking.notified().await;
+        server.shutdown.send(()).unwrap();
+
+        let response = answered.await.unwrap().unwrap().into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        server.server.await.unwrap().unwrap();
+        // The hits counted are flushed once drained
+        assert_eq!(server.flushes.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_the_server_after_the_drain_timeout() {
+        let server = serve_slowly(Duration::from_secs(600), Duration::from_millis(100));
+        let answered = request_in_flight(&server.address);
+
+        server.checking.notified().await;
+        server.shutdown.send(()).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), server.server)
+            .await
+            .expect("the server to close after the drain timeout")
+            .unwrap()
+            .unwrap();
+        assert_eq!(server.flushes.load(Ordering::SeqCst), 1);
+        answered.abort();
+    }
+
+    #[tokio::test]
+    async fn test_serves_on_every_address() {
+        let addresses = vec![free_address(), free_address()];
+        let server = serve_slowly_on(
+            addresses.clone(),
+            Duration::ZERO,
+            Duration::from_secs(10),
+            false,
+            None,
+            None,
+        );
+
+        for address in &addresses {
+            let response = request_in_flight(address)
+                .await
+                .unwrap()
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.overall_code, i32::from(Code::Ok));
+        }
+
+        server.shutdown.send(()).unwrap();
+        server.server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fails_when_any_address_cant_be_bound() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = taken.local_addr().unwrap().to_string();
+        let server = serve_slowly_on(
+            vec![free_address(), address.clone()],
+            Duration::ZERO,
+            Duration::from_secs(10),
+            false,
+            None,
+            None,
+        );
+
+        let e = server.server.await.unwrap().unwrap_err();
+        assert!(e.to_string().contains(&address));
+    }
+
+    #[tokio::test]
+    async fn test_negotiates_gzip_compression_when_enabled() {
+        for compression in [true, false] {
+            let address = free_address();
+            let server = serve_slowly_on(
+                vec![address.clone()],
+                Duration::ZERO,
+                Duration::from_secs(10),
+                compression,
+                None,
+                None,
+            );
+            let endpoint = format!("http://{address}");
+            let client = loop {
+                match RateLimitServiceClient::connect(endpoint.clone()).await {
+                    Ok(client) => break client,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            let response = client
+                .send_compressed(CompressionEncoding::Gzip)
+                .accept_compressed(CompressionEncoding::Gzip)
+                .should_rate_limit(request_hitting_the_slow_limit())
+                .await;
+
+            if compression {
+                let response = response.unwrap();
+                assert_eq!(response.metadata().get("grpc-encoding").unwrap(), "gzip");
+                assert_eq!(response.into_inner().overall_code, i32::from(Code::Ok));
+            } else {
+                assert_eq!(response.unwrap_err().code(), tonic::Code::Unimplemented);
+            }
+
+            server.shutdown.send(()).unwrap();
+            server.server.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejects_the_requests_past_the_max_decoding_message_size() {
+        use prost::Message;
+
+        const MAX: usize = 1024;
+        let address = free_address();
+        let server = serve_slowly_on(
+            vec![address.clone()],
+            Duration::ZERO,
+            Duration::from_secs(10),
+            false,
+            Some(MAX),
+            None,
+        );
+        let endpoint = format!("http://{address}");
+        let mut client = loop {
+            match RateLimitServiceClient::connect(endpoint.clone()).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        // Padded to exactly MAX bytes once encoded
+        let mut request = request_hitting_the_slow_limit();
+        request.descriptors[0].entries.push(Entry {
+            key: "padding".to_string(),
+            value: "a".repeat(MAX),
+        });
+        while request.encoded_len() > MAX {
+            request.descriptors[0].entries[1].value.pop();
+        }
+        assert_eq!(request.encoded_len(), MAX);
+
+        let response = client.should_rate_limit(request.clone()).await.unwrap();
+        assert_eq!(response.into_inner().overall_code, i32::from(Code::Ok));
+
+        request.descriptors[0].entries[1].value.push('a');
+        let status = client.should_rate_limit(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::OutOfRange);
+
+        server.shutdown.send(()).unwrap();
+        server.server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fails_the_answers_past_the_max_encoding_message_size() {
+        let address = free_address();
+        let server = serve_slowly_on(
+            vec![address.clone()],
+            Duration::ZERO,
+            Duration::from_secs(10),
+            false,
+            None,
+            Some(1),
+        );
+        let status = request_in_flight(&address).await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::OutOfRange);
+
+        server.shutdown.send(()).unwrap();
+        server.server.await.unwrap().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serves_on_a_unix_socket_replacing_a_stale_one() {
+        let path = std::env::temp_dir().join(format!("limitador-rls-{}.sock", std::process::id()));
+        // As left behind by an instance that didn't shut down cleanly
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let server = serve_slowly_on(
+            vec![format!("{UNIX_SOCKET_PREFIX}{}", path.display())],
+            Duration::ZERO,
+            Duration::from_secs(10),
+            false,
+            None,
+            None,
+        );
+        let socket = path.clone();
+        let channel = loop {
+            let socket = socket.clone();
+            let connected = Endpoint::from_static("http://localhost")
+                .connect_with_connector(tower::service_fn(move |_: Uri| {
+                    tokio::net::UnixStream::connect(socket.clone())
+                }))
+                .await;
+            match connected {
+                Ok(channel) => break channel,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        let response = RateLimitServiceClient::new(channel)
+            .should_rate_limit(request_hitting_the_slow_limit())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+
+        server.shutdown.send(()).unwrap();
+        server.server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn the_descriptors_of_the_rate_limit_service_are_served() {
+        use prost::Message;
+
+        let set = prost_types::FileDescriptorSet::decode(FILE_DESCRIPTOR_SET).unwrap();
+        let services: Vec<_> = set
+            .file
+            .iter()
+            .filter(|file| file.package() == "envoy.service.ratelimit.v3")
+            .flat_map(|file| file.service.iter().map(|service| service.name()))
+            .collect();
+
+        assert_eq!(services, vec!["RateLimitService"]);
+    }
 }
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use limitador::counter::Counter;
+use serde_json::{json, Value};
+
+use crate::http_api::LimitVO;
+
+// A webhook too slow to answer is given up on, the event being lost
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts a JSON event to a webhook when a counter gets to `threshold` percent
+/// of its limit, only once per window of the counter
+pub struct UtilizationWebhook {
+    client: reqwest::Client,
+    url: String,
+    threshold: u8,
+    // The counters already posted about, until their window resets
+    notified: Mutex<HashMap<Counter, Instant>>,
+}
+
+impl UtilizationWebhook {
+    pub fn new(url: String, threshold: u8) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(POST_TIMEOUT)
+                .build()
+                .expect("a client without TLS settings of its own"),
+            url,
+            threshold,
+            notified: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Posts the events of the counters, as just loaded by a check, that
+    /// got to the threshold in their current window. Doesn't wait for the
+    /// webhook to answer.
+    pub fn observe(&self, counters: &[Counter]) {
+        for event in self.crossed(counters, Instant::now()) {
+            let request = self.client.post(&self.url).json(&event);
+            let url = self.url.clone();
+            tokio::spawn(async move {
+                if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+                    warn!(
+                        "Failed posting the utilization of a counter to {}: {}",
+                        url, e
+                    );
+                }
+            });
+        }
+    }
+
+    fn crossed(&self, counters: &[Counter], now: Instant) -> Vec<Value> {
+        let over: Vec<(&Counter, i64)> = counters
+            .iter()
+            .filter_map(|counter| Some((counter, hits(counter)?)))
+            .filter(|(counter, hits)| {
+                counter.max_value() > 0
+                    && hits * 100 >= i64::from(self.threshold) * counter.max_value()
+            })
+            .collect();
+        if over.is_empty() {
+            return vec![];
+        }
+
+        let mut notified = self.notified.lock().unwrap();
+        let fresh: Vec<_> = over
+            .into_iter()
+            .filter(|(counter, _)| !matches!(notified.get(*counter), Some(until) if *until > now))
+            .collect();
+        if fresh.is_empty() {
+            return vec![];
+        }
+        // Only pruned when growing, not on every check of a counter notified
+        notified.retain(|_, until| *until > now);
+        fresh
+            .into_iter()
+            .map(|(counter, hits)| {
+                let window_left = counter.expires_in().unwrap_or(counter.window());
+                notified.insert(counter.clone(), now + window_left);
+                self.event(counter, hits)
+            })
+            .collect()
+    }
+
+    fn event(&self, counter: &Counter, hits: i64) -> Value {
+        json!({
+            "namespace": counter.namespace().as_ref(),
+            "limit": LimitVO::from(counter.limit()),
+            "set_variables": counter.set_variables(),
+            "value": hits,
+            "threshold": self.threshold,
+            "timestamp": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        })
+    }
+}
+
+// The hits counted so far, unknown unless the counter was loaded
+fn hits(counter: &Counter) -> Option<i64> {
+    counter
+        .remaining()
+        .map(|remaining| counter.max_value() - remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use limitador::limit::Limit;
+
+    fn counter(app_id: &str, remaining: i64, expires_in: Duration) -> Counter {
+        let mut limit = Limit::new("test_namespace", 10, 60, Vec::<&str>::new(), vec!["app_id"]);
+        limit.set_name("per-app".to_string());
+        let mut counter = Counter::new(
+            limit,
+            HashMap::from([("app_id".to_string(), app_id.to_string())]),
+        );
+        counter.set_remaining(remaining);
+        counter.set_expires_in(expires_in);
+        counter
+    }
+
+    #[test]
+    fn tells_of_a_counter_crossing_the_threshold_once_per_window() {
+        let webhook = UtilizationWebhook::new("http://localhost".to_string(), 80);
+        let now = Instant::now();
+        let window = Duration::from_secs(30);
+
+        assert!(webhook.crossed(&[counter("1", 3, window)], now).is_empty());
+
+        let events = webhook.crossed(&[counter("1", 2, window), counter("2", 9, window)], now);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["namespace"], "test_namespace");
+        assert_eq!(events[0]["limit"]["name"], "per-app");
+        assert_eq!(events[0]["limit"]["max_value"], 10);
+        assert_eq!(events[0]["set_variables"]["app_id"], "1");
+        assert_eq!(events[0]["value"], 8);
+        assert_eq!(events[0]["threshold"], 80);
+        assert!(events[0]["timestamp"].as_u64().unwrap() > 0);
+
+        let later = now + Duration::from_secs(10);
+        assert!(webhook
+            .crossed(&[counter("1", 0, window)], later)
+            .is_empty());
+        assert_eq!(webhook.crossed(&[counter("2", 1, window)], later).len(), 1);
+
+        // Once the window of the counter reset
+        let next_window = now + window + Duration::from_secs(1);
+        assert_eq!(
+            webhook
+                .crossed(&[counter("1", 1, window)], next_window)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn ignores_the_counters_not_loaded() {
+        let webhook = UtilizationWebhook::new("http://localhost".to_string(), 1);
+        let limit = Limit::new("test_namespace", 10, 60, Vec::<&str>::new(), vec!["app_id"]);
+        let counter = Counter::new(limit, HashMap::new());
+        assert!(webhook.crossed(&[counter], Instant::now()).is_empty());
+    }
+}
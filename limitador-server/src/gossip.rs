@@ -0,0 +1,113 @@
+use limitador::storage::distributed::{DistributedInMemoryStorage, Gossip};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+// Bounds the datagrams, so that they don't get fragmented much on their way
+const MAX_DATAGRAM_SIZE: usize = 8 * 1024;
+
+/// Tells the `peers` about the hits of the counters every `interval`, and
+/// merges what they tell in turn, for as long as the storage is around. A
+/// peer is a `host:port`, all the addresses of which are gossiped to, e.g.
+/// the ones of a headless service.
+pub async fn gossip(
+    storage: Weak<DistributedInMemoryStorage>,
+    socket: UdpSocket,
+    peers: Vec<String>,
+    interval: Duration,
+) {
+    let socket = Arc::new(socket);
+    tokio::spawn(listen(storage.clone(), Arc::clone(&socket)));
+
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let Some(storage) = storage.upgrade() else {
+            break;
+        };
+        let gossip = storage.gossip();
+        if gossip.counters.is_empty() {
+            continue;
+        }
+        let datagrams = gossip.encode(MAX_DATAGRAM_SIZE);
+        for peer in &peers {
+            // Resolved every time, as peers come and go
+            let addresses = match tokio::net::lookup_host(peer).await {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    warn!("Failed resolving gossip peer {}: {}", peer, e);
+                    continue;
+                }
+            };
+            for address in addresses {
+                for datagram in &datagrams {
+                    if let Err(e) = socket.send_to(datagram, address).await {
+                        debug!("Failed gossiping to {}: {}", address, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn listen(storage: Weak<DistributedInMemoryStorage>, socket: Arc<UdpSocket>) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Failed receiving gossip: {}", e);
+                continue;
+            }
+        };
+        let Some(storage) = storage.upgrade() else {
+            break;
+        };
+        match Gossip::decode(&buf[..len]) {
+            Ok(gossip) => storage.merge(gossip),
+            Err(e) => debug!("Ignoring gossip from {}: {}", from, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use limitador::counter::Counter;
+    use limitador::limit::Limit;
+    use limitador::storage::CounterStorage;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn nodes_limit_on_the_hits_of_each_other() {
+        let limit = Limit::new("ns", 3, 60, vec!["x == '1'"], vec!["z"]);
+        let counter = Counter::new(limit, HashMap::from([("z".to_string(), "1".to_string())]));
+
+        let mut nodes = Vec::new();
+        let mut sockets = Vec::new();
+        for node in ["a", "b"] {
+            nodes.push(Arc::new(DistributedInMemoryStorage::new(node, 100)));
+            sockets.push(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        }
+        let addresses: Vec<String> = sockets
+            .iter()
+            .map(|socket| socket.local_addr().unwrap().to_string())
+            .collect();
+        for (node, socket) in nodes.iter().zip(sockets) {
+            tokio::spawn(gossip(
+                Arc::downgrade(node),
+                socket,
+                addresses.clone(),
+                Duration::from_millis(10),
+            ));
+        }
+
+        nodes[0].update_counter(&counter, 2).unwrap();
+        nodes[1].update_counter(&counter, 1).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        for node in &nodes {
+            assert!(!node.is_within_limits(&counter, 1).unwrap());
+        }
+    }
+}
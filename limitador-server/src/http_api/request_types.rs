@@ -1,8 +1,14 @@
 use limitador::counter::Counter as LimitadorCounter;
-use limitador::limit::Limit as LimitadorLimit;
+use limitador::limit::{
+    CalendarPeriod as LimitadorCalendarPeriod, CalendarWindow as LimitadorCalendarWindow,
+    Condition, Cost as LimitadorCost, HttpMethod as LimitadorHttpMethod, Limit as LimitadorLimit,
+    MaxValues as LimitadorMaxValues, Tz, WindowStart as LimitadorWindowStart,
+    WindowType as LimitadorWindowType,
+};
 use paperclip::actix::Apiv2Schema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::Duration;
 
 // We need to define the Limit and Counter types. They're basically the same as
 // defined in the lib but with some modifications to be able to derive
@@ -19,10 +25,252 @@ pub struct CheckAndReportInfo {
 pub struct Limit {
     namespace: String,
     max_value: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    soft_max_value: Option<i64>,
+    #[serde(default)]
     seconds: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    milliseconds: Option<u64>,
     name: Option<String>,
+    #[serde(default)]
+    priority: i32,
     conditions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    methods: Vec<HttpMethod>,
     variables: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    optional_variables: Vec<String>,
+    #[serde(default)]
+    window_type: WindowType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refill_rate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    leak_rate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    distinct_variable: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sliding_buckets: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shards: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    calendar_window: Option<CalendarWindow>,
+    #[serde(default, skip_serializing_if = "WindowStart::is_first_hit")]
+    window_start: WindowStart,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jitter: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cost: Option<Cost>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_values: Option<MaxValues>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shared_counter: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    fallback: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(default = "enabled", skip_serializing_if = "is_enabled")]
+    enabled: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    shadow: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn is_enabled(enabled: &bool) -> bool {
+    *enabled
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub enum WindowType {
+    #[default]
+    Fixed,
+    Sliding,
+    TokenBucket,
+    LeakyBucket,
+    Distinct,
+}
+
+impl From<LimitadorWindowType> for WindowType {
+    fn from(window_type: LimitadorWindowType) -> Self {
+        match window_type {
+            LimitadorWindowType::Fixed => Self::Fixed,
+            LimitadorWindowType::Sliding => Self::Sliding,
+            LimitadorWindowType::TokenBucket => Self::TokenBucket,
+            LimitadorWindowType::LeakyBucket => Self::LeakyBucket,
+            LimitadorWindowType::Distinct => Self::Distinct,
+        }
+    }
+}
+
+impl From<WindowType> for LimitadorWindowType {
+    fn from(window_type: WindowType) -> Self {
+        match window_type {
+            WindowType::Fixed => Self::Fixed,
+            WindowType::Sliding => Self::Sliding,
+            WindowType::TokenBucket => Self::TokenBucket,
+            WindowType::LeakyBucket => Self::LeakyBucket,
+            WindowType::Distinct => Self::Distinct,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HttpMethod {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl From<LimitadorHttpMethod> for HttpMethod {
+    fn from(method: LimitadorHttpMethod) -> Self {
+        match method {
+            LimitadorHttpMethod::Get => Self::Get,
+            LimitadorHttpMethod::Head => Self::Head,
+            LimitadorHttpMethod::Post => Self::Post,
+            LimitadorHttpMethod::Put => Self::Put,
+            LimitadorHttpMethod::Delete => Self::Delete,
+            LimitadorHttpMethod::Connect => Self::Connect,
+            LimitadorHttpMethod::Options => Self::Options,
+            LimitadorHttpMethod::Trace => Self::Trace,
+            LimitadorHttpMethod::Patch => Self::Patch,
+        }
+    }
+}
+
+impl From<HttpMethod> for LimitadorHttpMethod {
+    fn from(method: HttpMethod) -> Self {
+        match method {
+            HttpMethod::Get => Self::Get,
+            HttpMethod::Head => Self::Head,
+            HttpMethod::Post => Self::Post,
+            HttpMethod::Put => Self::Put,
+            HttpMethod::Delete => Self::Delete,
+            HttpMethod::Connect => Self::Connect,
+            HttpMethod::Options => Self::Options,
+            HttpMethod::Trace => Self::Trace,
+            HttpMethod::Patch => Self::Patch,
+        }
+    }
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub enum WindowStart {
+    #[default]
+    FirstHit,
+    Clock,
+}
+
+impl WindowStart {
+    fn is_first_hit(&self) -> bool {
+        *self == WindowStart::FirstHit
+    }
+}
+
+impl From<LimitadorWindowStart> for WindowStart {
+    fn from(window_start: LimitadorWindowStart) -> Self {
+        match window_start {
+            LimitadorWindowStart::FirstHit => Self::FirstHit,
+            LimitadorWindowStart::Clock => Self::Clock,
+        }
+    }
+}
+
+impl From<WindowStart> for LimitadorWindowStart {
+    fn from(window_start: WindowStart) -> Self {
+        match window_start {
+            WindowStart::FirstHit => Self::FirstHit,
+            WindowStart::Clock => Self::Clock,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct CalendarWindow {
+    period: CalendarPeriod,
+    // An IANA timezone, e.g. "Europe/Madrid"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timezone: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub enum CalendarPeriod {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl From<LimitadorCalendarWindow> for CalendarWindow {
+    fn from(calendar_window: LimitadorCalendarWindow) -> Self {
+        Self {
+            period: match calendar_window.period() {
+                LimitadorCalendarPeriod::Daily => CalendarPeriod::Daily,
+                LimitadorCalendarPeriod::Weekly => CalendarPeriod::Weekly,
+                LimitadorCalendarPeriod::Monthly => CalendarPeriod::Monthly,
+            },
+            timezone: Some(calendar_window.timezone().name().to_string()),
+        }
+    }
+}
+
+impl CalendarWindow {
+    fn timezone(&self) -> Result<Tz, String> {
+        match &self.timezone {
+            Some(timezone) => timezone
+                .parse()
+                .map_err(|_| format!("invalid value for `timezone`: unknown timezone {timezone}")),
+            None => Ok(Tz::UTC),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct Cost {
+    // The descriptor entry whose value sets the cost
+    entry: String,
+    #[serde(default)]
+    values: HashMap<String, i64>,
+    #[serde(default = "unit_cost")]
+    default: i64,
+}
+
+fn unit_cost() -> i64 {
+    1
+}
+
+impl From<&LimitadorCost> for Cost {
+    fn from(cost: &LimitadorCost) -> Self {
+        Self {
+            entry: cost.entry().to_string(),
+            values: cost.values().clone(),
+            default: cost.default(),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct MaxValues {
+    // The descriptor entry whose value sets the max value
+    entry: String,
+    #[serde(default)]
+    values: HashMap<String, i64>,
+}
+
+impl From<&LimitadorMaxValues> for MaxValues {
+    fn from(max_values: &LimitadorMaxValues) -> Self {
+        Self {
+            entry: max_values.entry().to_string(),
+            values: max_values.values().clone(),
+        }
+    }
 }
 
 impl From<&LimitadorLimit> for Limit {
@@ -30,11 +278,74 @@ impl From<&LimitadorLimit> for Limit {
         Self {
             namespace: ll.namespace().as_ref().to_string(),
             max_value: ll.max_value(),
+            soft_max_value: ll.soft_max_value(),
             seconds: ll.seconds(),
+            milliseconds: ll.milliseconds(),
             name: ll.name().map(|name| name.to_string()),
+            priority: ll.priority(),
             conditions: ll.conditions().into_iter().collect(),
+            methods: ll.methods().into_iter().map(HttpMethod::from).collect(),
             variables: ll.variables().into_iter().collect(),
+            optional_variables: ll.optional_variables().into_iter().collect(),
+            window_type: ll.window_type().into(),
+            refill_rate: ll.refill_rate(),
+            leak_rate: ll.leak_rate(),
+            distinct_variable: ll.distinct_variable().map(str::to_string),
+            sliding_buckets: ll.sliding_buckets(),
+            shards: ll.shards(),
+            calendar_window: ll.calendar_window().map(CalendarWindow::from),
+            window_start: ll.window_start().into(),
+            jitter: ll.jitter(),
+            cost: ll.cost().map(Cost::from),
+            max_values: ll.max_values().map(MaxValues::from),
+            shared_counter: ll.shared_counter().map(str::to_string),
+            fallback: ll.is_fallback(),
+            group: ll.group().map(str::to_string),
+            enabled: ll.is_enabled(),
+            shadow: ll.is_shadow(),
+        }
+    }
+}
+
+impl Limit {
+    /// Why the limit couldn't be turned into a [`LimitadorLimit`], if so.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_value < 0 {
+            return Err("invalid value for `max_value`: positive integer expected".to_string());
+        }
+        if self
+            .soft_max_value
+            .is_some_and(|soft_max_value| !(0..=self.max_value).contains(&soft_max_value))
+        {
+            return Err(
+                "invalid value for `soft_max_value`: positive integer up to `max_value` expected"
+                    .to_string(),
+            );
+        }
+        for condition in &self.conditions {
+            Condition::try_from(condition.as_str()).map_err(|e| e.to_string())?;
         }
+        if let Some(calendar_window) = &self.calendar_window {
+            calendar_window.timezone()?;
+        }
+        if self.window_type == WindowType::Distinct && self.distinct_variable.is_none() {
+            return Err(
+                "invalid value for `distinct_variable`: required by `Distinct` windows".to_string(),
+            );
+        }
+        if let Some(cost) = &self.cost {
+            if cost.default < 0 || cost.values.values().any(|value| *value < 0) {
+                return Err("invalid value for `cost`: positive integers expected".to_string());
+            }
+        }
+        if let Some(max_values) = &self.max_values {
+            if max_values.values.values().any(|value| *value < 0) {
+                return Err(
+                    "invalid value for `max_values`: positive integers expected".to_string()
+                );
+            }
+        }
+        Ok(())
     }
 }
 
@@ -51,12 +362,50 @@ impl From<Limit> for LimitadorLimit {
         if let Some(name) = limit.name {
             limitador_limit.set_name(name)
         }
+        limitador_limit.set_milliseconds(limit.milliseconds);
+        limitador_limit.set_soft_max_value(limit.soft_max_value);
+        limitador_limit.set_optional_variables(limit.optional_variables);
+        limitador_limit.set_methods(limit.methods.into_iter().map(LimitadorHttpMethod::from));
+        limitador_limit.set_priority(limit.priority);
+        limitador_limit.set_window_type(limit.window_type.into());
+        limitador_limit.set_refill_rate(limit.refill_rate);
+        limitador_limit.set_leak_rate(limit.leak_rate);
+        limitador_limit.set_distinct_variable(limit.distinct_variable);
+        limitador_limit.set_sliding_buckets(limit.sliding_buckets);
+        limitador_limit.set_shards(limit.shards);
+        limitador_limit.set_calendar_window(limit.calendar_window.map(|calendar_window| {
+            LimitadorCalendarWindow::new(
+                match calendar_window.period {
+                    CalendarPeriod::Daily => LimitadorCalendarPeriod::Daily,
+                    CalendarPeriod::Weekly => LimitadorCalendarPeriod::Weekly,
+                    CalendarPeriod::Monthly => LimitadorCalendarPeriod::Monthly,
+                },
+                calendar_window.timezone().unwrap_or(Tz::UTC),
+            )
+        }));
+        limitador_limit.set_window_start(limit.window_start.into());
+        limitador_limit.set_jitter(limit.jitter);
+        limitador_limit.set_cost(
+            limit
+                .cost
+                .map(|cost| LimitadorCost::new(cost.entry, cost.values, cost.default)),
+        );
+        limitador_limit.set_max_values(
+            limit
+                .max_values
+                .map(|max_values| LimitadorMaxValues::new(max_values.entry, max_values.values)),
+        );
+        limitador_limit.set_shared_counter(limit.shared_counter);
+        limitador_limit.set_fallback(limit.fallback);
+        limitador_limit.set_group(limit.group);
+        limitador_limit.set_enabled(limit.enabled);
+        limitador_limit.set_shadow(limit.shadow);
 
         limitador_limit
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize, Apiv2Schema)]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
 pub struct Counter {
     limit: Limit,
     set_variables: HashMap<String, String>,
@@ -74,3 +423,27 @@ impl From<&LimitadorCounter> for Counter {
         }
     }
 }
+
+impl Counter {
+    /// The counter to set, with the hits it has remaining and when it expires,
+    /// or why it can't be set.
+    pub fn into_counter_to_set(self) -> Result<(LimitadorCounter, i64, Duration), String> {
+        self.limit.validate()?;
+        if let Some(variable) = self
+            .limit
+            .variables
+            .iter()
+            .find(|variable| !self.set_variables.contains_key(*variable))
+        {
+            return Err(format!("missing value for variable `{variable}`"));
+        }
+        let remaining = self
+            .remaining
+            .ok_or("missing value for `remaining`".to_string())?;
+        let expires_in = self
+            .expires_in_seconds
+            .ok_or("missing value for `expires_in_seconds`".to_string())?;
+        let counter = LimitadorCounter::new(self.limit.into(), self.set_variables);
+        Ok((counter, remaining, Duration::from_secs(expires_in)))
+    }
+}
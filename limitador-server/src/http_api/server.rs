@@ -1,7 +1,15 @@
+use crate::debug_sampling::{DebugLogSampler, DebugLogSampling};
 use crate::http_api::request_types::{CheckAndReportInfo, Counter, Limit};
+use crate::kill_switch::{KillSwitch, KillSwitchMode, KillSwitches};
+use crate::over_limit::OverLimitBody;
+use crate::reservations::Reservations;
 use crate::Limiter;
+use actix_web::web::Bytes;
 use actix_web::{http::StatusCode, ResponseError};
-use actix_web::{App, HttpServer};
+use actix_web::{App, HttpResponse, HttpServer};
+use futures::{stream, StreamExt};
+use limitador::errors::LimitadorError;
+use limitador::limit::{Limit as LimitadorLimit, Namespace};
 use paperclip::actix::{
     api_v2_errors,
     api_v2_operation,
@@ -10,20 +18,29 @@ use paperclip::actix::{
     // extension trait for actix_web::App and proc-macro attributes
     OpenApiExt,
 };
+use std::collections::HashMap;
 use std::fmt;
 use std::sync::Arc;
+use std::time::Duration;
 
-#[api_v2_errors(429, 500)]
+#[api_v2_errors(400, 404, 409, 429, 500)]
 #[derive(Debug)]
 enum ErrorResponse {
-    TooManyRequests,
+    BadRequest(String),
+    NotFound,
+    Conflict,
+    // Along with the content type and the body configured, if any
+    TooManyRequests(Option<(&'static str, String)>),
     InternalServerError,
 }
 
 impl fmt::Display for ErrorResponse {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::TooManyRequests => write!(f, "Too many requests"),
+            Self::BadRequest(reason) => write!(f, "Bad request: {reason}"),
+            Self::NotFound => write!(f, "Not found"),
+            Self::Conflict => write!(f, "Conflict"),
+            Self::TooManyRequests(_) => write!(f, "Too many requests"),
             Self::InternalServerError => write!(f, "Internal server error"),
         }
     }
@@ -32,10 +49,36 @@ impl fmt::Display for ErrorResponse {
 impl ResponseError for ErrorResponse {
     fn status_code(&self) -> StatusCode {
         match self {
-            Self::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Conflict => StatusCode::CONFLICT,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
             Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        let (content_type, body) = match self {
+            Self::TooManyRequests(Some((content_type, body))) => (*content_type, body.clone()),
+            _ => ("text/plain; charset=utf-8", self.to_string()),
+        };
+        HttpResponse::build(self.status_code())
+            .content_type(content_type)
+            .body(body)
+    }
+}
+
+fn too_many_requests(
+    over_limit_body: &Option<OverLimitBody>,
+    namespace: &Namespace,
+    retry_after: Option<Duration>,
+) -> ErrorResponse {
+    ErrorResponse::TooManyRequests(over_limit_body.as_ref().map(|body| {
+        (
+            body.content_type(),
+            body.render(namespace.as_ref(), retry_after),
+        )
+    }))
 }
 
 // Used for health checks
@@ -46,10 +89,7 @@ async fn status() -> web::Json<()> {
 
 #[api_v2_operation]
 async fn metrics(data: web::Data<Arc<Limiter>>) -> String {
-    match data.get_ref().as_ref() {
-        Limiter::Blocking(limiter) => limiter.gather_prometheus_metrics(),
-        Limiter::Async(limiter) => limiter.gather_prometheus_metrics(),
-    }
+    data.gather_prometheus_metrics() + &crate::metrics::gather_metrics()
 }
 
 #[api_v2_operation]
@@ -58,26 +98,137 @@ async fn get_limits(
     namespace: web::Path<String>,
 ) -> Result<web::Json<Vec<Limit>>, ErrorResponse> {
     let namespace = &namespace.into_inner().into();
-    let limits = match data.get_ref().as_ref() {
-        Limiter::Blocking(limiter) => limiter.get_limits(namespace),
-        Limiter::Async(limiter) => limiter.get_limits(namespace),
-    };
+    let limits = data.get_limits(namespace);
     let resp_limits: Vec<Limit> = limits.iter().map(|l| l.into()).collect();
     Ok(Json(resp_limits))
 }
 
+#[api_v2_operation]
+async fn add_limit(
+    data: web::Data<Arc<Limiter>>,
+    request: web::Json<Limit>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let limit = request.into_inner();
+    limit.validate().map_err(ErrorResponse::BadRequest)?;
+    let limit: LimitadorLimit = limit.into();
+
+    if data.add_limit(limit) {
+        Ok(Json(()))
+    } else {
+        Err(ErrorResponse::Conflict)
+    }
+}
+
+#[api_v2_operation]
+async fn delete_limit(
+    data: web::Data<Arc<Limiter>>,
+    request: web::Json<Limit>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let limit = request.into_inner();
+    limit.validate().map_err(ErrorResponse::BadRequest)?;
+    let limit: LimitadorLimit = limit.into();
+
+    if !data.get_limits(limit.namespace()).contains(&limit) {
+        return Err(ErrorResponse::NotFound);
+    }
+
+    match data.delete_limit(&limit).await {
+        Ok(_) => Ok(Json(())),
+        Err(_) => Err(ErrorResponse::InternalServerError),
+    }
+}
+
+#[api_v2_operation]
+async fn delete_limit_by_name(
+    data: web::Data<Arc<Limiter>>,
+    path: web::Path<(String, String)>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let (namespace, name) = path.into_inner();
+    let namespace = &namespace.into();
+
+    match data.delete_limit_by_name(namespace, &name).await {
+        Ok(true) => Ok(Json(())),
+        Ok(false) => Err(ErrorResponse::NotFound),
+        Err(_) => Err(ErrorResponse::InternalServerError),
+    }
+}
+
+// Enables or disables the limit, e.g. during an incident, keeping its counters
+// for when it's enabled again. Until the limits are reloaded, which sets it as
+// they say.
+#[api_v2_operation]
+async fn set_limit_enabled(
+    data: web::Data<Arc<Limiter>>,
+    path: web::Path<(String, String)>,
+    request: web::Json<bool>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let (namespace, name) = path.into_inner();
+    let namespace = &namespace.into();
+    let enabled = request.into_inner();
+
+    if data.set_limit_enabled_by_name(namespace, &name, enabled) {
+        Ok(Json(()))
+    } else {
+        Err(ErrorResponse::NotFound)
+    }
+}
+
+#[api_v2_operation]
+async fn get_kill_switches(data: web::Data<Arc<KillSwitch>>) -> web::Json<KillSwitches> {
+    Json(data.switches())
+}
+
+// Engages the kill switch of all the namespaces, or releases it on null. The
+// RLS then answers right away, without reaching the storage.
+#[api_v2_operation]
+async fn set_kill_switch(
+    data: web::Data<Arc<KillSwitch>>,
+    request: web::Json<Option<KillSwitchMode>>,
+) -> web::Json<()> {
+    data.set_global(request.into_inner());
+    Json(())
+}
+
+#[api_v2_operation]
+async fn set_namespace_kill_switch(
+    data: web::Data<Arc<KillSwitch>>,
+    namespace: web::Path<String>,
+    request: web::Json<Option<KillSwitchMode>>,
+) -> web::Json<()> {
+    data.set_for_namespace(&namespace.into_inner(), request.into_inner());
+    Json(())
+}
+
+#[api_v2_operation]
+async fn get_debug_log_sampling(
+    data: web::Data<Arc<DebugLogSampler>>,
+) -> web::Json<DebugLogSampling> {
+    Json(data.sampling())
+}
+
+// Takes effect on the next RLS request logged at debug level
+#[api_v2_operation]
+async fn set_debug_log_sampling(
+    data: web::Data<Arc<DebugLogSampler>>,
+    request: web::Json<DebugLogSampling>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let sampling = request.into_inner();
+    if sampling.every == 0 {
+        return Err(ErrorResponse::BadRequest(
+            "every must be 1 or more".to_string(),
+        ));
+    }
+    data.set_sampling(sampling);
+    Ok(Json(()))
+}
+
 #[api_v2_operation]
 async fn get_counters(
     data: web::Data<Arc<Limiter>>,
     namespace: web::Path<String>,
 ) -> Result<web::Json<Vec<Counter>>, ErrorResponse> {
     let namespace = namespace.into_inner().into();
-    let get_counters_result = match data.get_ref().as_ref() {
-        Limiter::Blocking(limiter) => limiter.get_counters(&namespace),
-        Limiter::Async(limiter) => limiter.get_counters(&namespace).await,
-    };
-
-    match get_counters_result {
+    match data.get_counters(&namespace).await {
         Ok(counters) => {
             let mut resp_counters: Vec<Counter> = vec![];
             for c in &counters {
@@ -89,9 +240,106 @@ async fn get_counters(
     }
 }
 
+// Streams all the counters, as a JSON array that import_counters takes. Only
+// the counters of one limit are held in memory at a time. Should a storage
+// fail meanwhile, the response is cut short.
+#[api_v2_operation]
+async fn export_counters(data: web::Data<Arc<Limiter>>) -> HttpResponse {
+    let limiter = data.get_ref().clone();
+    let limits: Vec<LimitadorLimit> = limiter
+        .get_namespaces()
+        .iter()
+        .flat_map(|namespace| limiter.get_limits(namespace))
+        .collect();
+
+    let counters = stream::iter(limits).then(move |limit| {
+        let limiter = limiter.clone();
+        async move { limiter.get_counters_of_limit(&limit).await }
+    });
+    let mut first = true;
+    let chunks = counters.map(move |counters| {
+        let mut chunk = Vec::new();
+        for counter in &counters? {
+            if !std::mem::take(&mut first) {
+                chunk.push(b',');
+            }
+            serde_json::to_writer(&mut chunk, &Counter::from(counter))?;
+        }
+        Ok::<_, Box<dyn std::error::Error>>(Bytes::from(chunk))
+    });
+
+    let array = stream::once(async { Ok(Bytes::from_static(b"[")) })
+        .chain(chunks)
+        .chain(stream::once(async { Ok(Bytes::from_static(b"]")) }));
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .streaming(array)
+}
+
+// Sets the counters given, e.g. as exported from another instance, one by one.
+// When one can't be, the ones before it are still set.
+#[api_v2_operation]
+async fn import_counters(
+    data: web::Data<Arc<Limiter>>,
+    request: web::Json<Vec<Counter>>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let counters = request
+        .into_inner()
+        .into_iter()
+        .enumerate()
+        .map(|(index, counter)| {
+            counter
+                .into_counter_to_set()
+                .map_err(|reason| ErrorResponse::BadRequest(format!(".[{index}]: {reason}")))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (index, (counter, remaining, expires_in)) in counters.iter().enumerate() {
+        match data.set_counter(counter, *remaining, *expires_in).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(ErrorResponse::BadRequest(format!(
+                    ".[{index}]: no such limit in namespace `{}`",
+                    counter.namespace().as_ref()
+                )))
+            }
+            Err(LimitadorError::InvalidCounter(reason)) => {
+                return Err(ErrorResponse::BadRequest(format!(".[{index}]: {reason}")))
+            }
+            Err(_) => return Err(ErrorResponse::InternalServerError),
+        }
+    }
+    Ok(Json(()))
+}
+
+// Resets the counter of the named limit of the namespace, for the values of its
+// variables given, e.g. those of a customer after a billing correction, and
+// answers whether it existed. Only the limits of the namespace of the path are
+// looked up, so that the counters of the others can't be reset by mistake.
+#[api_v2_operation]
+async fn reset_counter(
+    data: web::Data<Arc<Limiter>>,
+    path: web::Path<(String, String)>,
+    request: web::Json<HashMap<String, String>>,
+) -> Result<web::Json<bool>, ErrorResponse> {
+    let (namespace, name) = path.into_inner();
+    let namespace = &namespace.into();
+
+    match data
+        .reset_counter(namespace, &name, &request.into_inner())
+        .await
+    {
+        Ok(Some(existed)) => Ok(Json(existed)),
+        Ok(None) => Err(ErrorResponse::NotFound),
+        Err(LimitadorError::InvalidCounter(reason)) => Err(ErrorResponse::BadRequest(reason)),
+        Err(_) => Err(ErrorResponse::InternalServerError),
+    }
+}
+
 #[api_v2_operation]
 async fn check(
     state: web::Data<Arc<Limiter>>,
+    over_limit_body: web::Data<Option<OverLimitBody>>,
     request: web::Json<CheckAndReportInfo>,
 ) -> Result<web::Json<()>, ErrorResponse> {
     let CheckAndReportInfo {
@@ -100,15 +348,10 @@ async fn check(
         delta,
     } = request.into_inner();
     let namespace = namespace.into();
-    let is_rate_limited_result = match state.get_ref().as_ref() {
-        Limiter::Blocking(limiter) => limiter.is_rate_limited(&namespace, &values, delta),
-        Limiter::Async(limiter) => limiter.is_rate_limited(&namespace, &values, delta).await,
-    };
-
-    match is_rate_limited_result {
+    match state.is_rate_limited(&namespace, &values, delta).await {
         Ok(rate_limited) => {
             if rate_limited {
-                Err(ErrorResponse::TooManyRequests)
+                Err(too_many_requests(&over_limit_body, &namespace, None))
             } else {
                 Ok(Json(()))
             }
@@ -128,12 +371,7 @@ async fn report(
         delta,
     } = request.into_inner();
     let namespace = namespace.into();
-    let update_counters_result = match data.get_ref().as_ref() {
-        Limiter::Blocking(limiter) => limiter.update_counters(&namespace, &values, delta),
-        Limiter::Async(limiter) => limiter.update_counters(&namespace, &values, delta).await,
-    };
-
-    match update_counters_result {
+    match data.update_counters(&namespace, &values, delta).await {
         Ok(_) => Ok(Json(())),
         Err(_) => Err(ErrorResponse::InternalServerError),
     }
@@ -142,6 +380,7 @@ async fn report(
 #[api_v2_operation]
 async fn check_and_report(
     data: web::Data<Arc<Limiter>>,
+    over_limit_body: web::Data<Option<OverLimitBody>>,
     request: web::Json<CheckAndReportInfo>,
 ) -> Result<web::Json<()>, ErrorResponse> {
     let CheckAndReportInfo {
@@ -150,21 +389,19 @@ async fn check_and_report(
         delta,
     } = request.into_inner();
     let namespace = namespace.into();
-    let rate_limited_and_update_result = match data.get_ref().as_ref() {
-        Limiter::Blocking(limiter) => {
-            limiter.check_rate_limited_and_update(&namespace, &values, delta, false)
-        }
-        Limiter::Async(limiter) => {
-            limiter
-                .check_rate_limited_and_update(&namespace, &values, delta, false)
-                .await
-        }
-    };
-
-    match rate_limited_and_update_result {
+    // The counters tell when to retry, should the body tell it
+    let load_counters = over_limit_body.is_some();
+    match data
+        .check_rate_limited_and_update(&namespace, &values, delta, load_counters)
+        .await
+    {
         Ok(is_rate_limited) => {
             if is_rate_limited.limited {
-                Err(ErrorResponse::TooManyRequests)
+                Err(too_many_requests(
+                    &over_limit_body,
+                    &namespace,
+                    is_rate_limited.retry_after(),
+                ))
             } else {
                 Ok(Json(()))
             }
@@ -173,8 +410,44 @@ async fn check_and_report(
     }
 }
 
-pub async fn run_http_server(address: &str, rate_limiter: Arc<Limiter>) -> std::io::Result<()> {
+// Keeps the hits of a reservation of the RLS counted, once its response
+// succeeded. Not found when reservations aren't enabled.
+#[api_v2_operation]
+async fn confirm_reservation(
+    data: web::Data<Option<Arc<Reservations>>>,
+    id: web::Path<String>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    match data.as_ref() {
+        Some(reservations) if reservations.confirm(&id) => Ok(Json(())),
+        _ => Err(ErrorResponse::NotFound),
+    }
+}
+
+// Gives the hits of a reservation of the RLS back, once its response failed
+#[api_v2_operation]
+async fn release_reservation(
+    data: web::Data<Option<Arc<Reservations>>>,
+    id: web::Path<String>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let Some(reservations) = data.as_ref() else {
+        return Err(ErrorResponse::NotFound);
+    };
+    match reservations.release(&id).await {
+        Ok(true) => Ok(Json(())),
+        Ok(false) => Err(ErrorResponse::NotFound),
+        Err(_) => Err(ErrorResponse::InternalServerError),
+    }
+}
+
+pub async fn run_http_server(
+    address: &str,
+    rate_limiter: Arc<Limiter>,
+    over_limit_body: Option<OverLimitBody>,
+    reservations: Option<Arc<Reservations>>,
+) -> std::io::Result<()> {
     let data = web::Data::new(rate_limiter);
+    let over_limit_body = web::Data::new(over_limit_body);
+    let reservations = web::Data::new(reservations);
 
     // This uses the paperclip crate to generate an OpenAPI spec.
     // Ref: https://paperclip.waffles.space/actix-plugin.html
@@ -184,6 +457,8 @@ pub async fn run_http_server(address: &str, rate_limiter: Arc<Limiter>) -> std::
             .wrap_api()
             .with_json_spec_at("/api/spec")
             .app_data(data.clone())
+            .app_data(over_limit_body.clone())
+            .app_data(reservations.clone())
             .route("/status", web::get().to(status))
             .route("/metrics", web::get().to(metrics))
             .route("/limits/{namespace}", web::get().to(get_limits))
@@ -191,19 +466,88 @@ pub async fn run_http_server(address: &str, rate_limiter: Arc<Limiter>) -> std::
             .route("/check_and_report", web::post().to(check_and_report))
             .route("/check", web::post().to(check))
             .route("/report", web::post().to(report))
+            .route(
+                "/reservations/{id}/confirm",
+                web::post().to(confirm_reservation),
+            )
+            .route(
+                "/reservations/{id}/release",
+                web::post().to(release_reservation),
+            )
             .build()
     })
-    .bind(address)?
+    .bind(address)
+    .map_err(|e| bind_failed("HTTP server", address, e))?
     .run()
     .await
 }
 
+// Lets the limits be managed at runtime. Meant to be bound to a private
+// address, as anyone able to reach it can change the limits enforced.
+pub async fn run_admin_server(
+    address: &str,
+    rate_limiter: Arc<Limiter>,
+    kill_switch: Arc<KillSwitch>,
+    debug_log_sampler: Arc<DebugLogSampler>,
+) -> std::io::Result<()> {
+    let data = web::Data::new(rate_limiter);
+    let kill_switch = web::Data::new(kill_switch);
+    let debug_log_sampler = web::Data::new(debug_log_sampler);
+
+    HttpServer::new(move || {
+        App::new()
+            .wrap_api()
+            .with_json_spec_at("/api/spec")
+            .app_data(data.clone())
+            .app_data(kill_switch.clone())
+            .app_data(debug_log_sampler.clone())
+            .route("/limits/{namespace}", web::get().to(get_limits))
+            .route("/limits", web::post().to(add_limit))
+            .route("/limits", web::delete().to(delete_limit))
+            .route(
+                "/limits/{namespace}/{name}",
+                web::delete().to(delete_limit_by_name),
+            )
+            .route(
+                "/limits/{namespace}/{name}/enabled",
+                web::put().to(set_limit_enabled),
+            )
+            .route("/counters", web::get().to(export_counters))
+            .route("/counters", web::post().to(import_counters))
+            .route(
+                "/counters/{namespace}/{name}",
+                web::delete().to(reset_counter),
+            )
+            .route("/kill_switch", web::get().to(get_kill_switches))
+            .route("/kill_switch", web::put().to(set_kill_switch))
+            .route(
+                "/kill_switch/{namespace}",
+                web::put().to(set_namespace_kill_switch),
+            )
+            .route("/debug_log_sampling", web::get().to(get_debug_log_sampling))
+            .route("/debug_log_sampling", web::put().to(set_debug_log_sampling))
+            .build()
+    })
+    .bind(address)
+    .map_err(|e| bind_failed("admin API", address, e))?
+    .run()
+    .await
+}
+
+// Tells the address that couldn't be bound, as the error alone doesn't
+fn bind_failed(server: &str, address: &str, e: std::io::Error) -> std::io::Error {
+    std::io::Error::new(
+        e.kind(),
+        format!("Failed binding the {server} to {address}: {e}"),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::over_limit::OverLimitBodyFormat;
     use crate::Configuration;
     use actix_web::{test, web};
-    use limitador::limit::Limit as LimitadorLimit;
     use std::collections::HashMap;
 
     // All these tests use the in-memory storage implementation to simplify. We
@@ -281,6 +625,7 @@ mod tests {
         let app = test::init_service(
             App::new()
                 .app_data(data.clone())
+                .app_data(web::Data::new(None::<OverLimitBody>))
                 .route("/check_and_report", web::post().to(check_and_report)),
         )
         .await;
@@ -314,6 +659,57 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
     }
 
+    #[actix_rt::test]
+    async fn test_check_and_report_answers_with_the_over_limit_body() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+
+        let namespace = "test_namespace";
+        let _limit = create_test_limit(&limiter, namespace, 1).await;
+        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let data = web::Data::new(rate_limiter);
+        let over_limit_body = OverLimitBody::new(
+            "{namespace} is over its limit, retry in {retry_after}s".to_string(),
+            OverLimitBodyFormat::Text,
+        )
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .app_data(web::Data::new(Some(over_limit_body)))
+                .route("/check_and_report", web::post().to(check_and_report)),
+        )
+        .await;
+
+        let mut values = HashMap::new();
+        values.insert("req.method".into(), "GET".into());
+        values.insert("app_id".into(), "1".into());
+        let info = CheckAndReportInfo {
+            namespace: namespace.into(),
+            values,
+            delta: 1,
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/check_and_report")
+            .set_json(&info)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/check_and_report")
+            .set_json(&info)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        let body = test::read_body(resp).await;
+        assert_eq!(body, "test_namespace is over its limit, retry in 60s");
+    }
+
     #[actix_rt::test]
     async fn test_check_and_report_endpoints_separately() {
         let namespace = "test_namespace";
@@ -325,6 +721,7 @@ mod tests {
         let app = test::init_service(
             App::new()
                 .app_data(data.clone())
+                .app_data(web::Data::new(None::<OverLimitBody>))
                 .route("/check", web::post().to(check))
                 .route("/report", web::post().to(report)),
         )
@@ -368,6 +765,571 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
     }
 
+    #[actix_rt::test]
+    async fn test_reservations_confirmed_and_released() {
+        let namespace = "test_namespace";
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let _limit = create_test_limit(&limiter, namespace, 1).await;
+        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let reservations = Arc::new(Reservations::new(
+            Arc::clone(&rate_limiter),
+            Duration::from_secs(60),
+        ));
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Arc::clone(&rate_limiter)))
+                .app_data(web::Data::new(Some(Arc::clone(&reservations))))
+                .route(
+                    "/reservations/{id}/confirm",
+                    web::post().to(confirm_reservation),
+                )
+                .route(
+                    "/reservations/{id}/release",
+                    web::post().to(release_reservation),
+                ),
+        )
+        .await;
+
+        let namespace = namespace.into();
+        let values = HashMap::from([
+            ("req.method".to_string(), "GET".to_string()),
+            ("app_id".to_string(), "1".to_string()),
+        ]);
+        let post = |uri: String| test::TestRequest::post().uri(&uri).to_request();
+
+        // Released, the hits are given back, so the next one gets through
+        let id = reserve(&rate_limiter, &reservations, &namespace, &values).await;
+        let resp = test::call_service(&app, post(format!("/reservations/{id}/release"))).await;
+        assert!(resp.status().is_success());
+        let resp = test::call_service(&app, post(format!("/reservations/{id}/release"))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let id = reserve(&rate_limiter, &reservations, &namespace, &values).await;
+        let resp = test::call_service(&app, post(format!("/reservations/{id}/confirm"))).await;
+        assert!(resp.status().is_success());
+        assert!(rate_limiter
+            .is_rate_limited(&namespace, &values, 1)
+            .await
+            .unwrap());
+        let resp = test::call_service(&app, post("/reservations/unknown/confirm".into())).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_limits_added_and_deleted() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let data = web::Data::new(Arc::new(limiter));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/limits/{namespace}", web::get().to(get_limits))
+                .route("/limits", web::post().to(add_limit))
+                .route("/limits", web::delete().to(delete_limit)),
+        )
+        .await;
+
+        let limitador_limit = LimitadorLimit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+        let limit = Limit::from(&limitador_limit);
+        let add = || {
+            test::TestRequest::post()
+                .uri("/limits")
+                .set_json(&limit)
+                .to_request()
+        };
+        let delete = || {
+            test::TestRequest::delete()
+                .uri("/limits")
+                .set_json(&limit)
+                .to_request()
+        };
+
+        assert!(test::call_service(&app, add()).await.status().is_success());
+        assert_eq!(
+            test::call_service(&app, add()).await.status(),
+            StatusCode::CONFLICT
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/limits/test_namespace")
+            .to_request();
+        let resp_limits: Vec<Limit> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp_limits, vec![Limit::from(&limitador_limit)]);
+
+        assert!(test::call_service(&app, delete())
+            .await
+            .status()
+            .is_success());
+        assert_eq!(
+            test::call_service(&app, delete()).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_limits_deleted_by_name() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let data = web::Data::new(Arc::new(limiter));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/limits/{namespace}", web::get().to(get_limits))
+                .route("/limits", web::post().to(add_limit))
+                .route(
+                    "/limits/{namespace}/{name}",
+                    web::delete().to(delete_limit_by_name),
+                ),
+        )
+        .await;
+
+        let add = |seconds| {
+            let mut limit = LimitadorLimit::new(
+                "test_namespace",
+                10,
+                seconds,
+                Vec::<String>::default(),
+                vec!["app_id"],
+            );
+            limit.set_name("per_app".to_string());
+            test::TestRequest::post()
+                .uri("/limits")
+                .set_json(Limit::from(&limit))
+                .to_request()
+        };
+        assert!(test::call_service(&app, add(60))
+            .await
+            .status()
+            .is_success());
+        // Another limit can't take the same name
+        assert_eq!(
+            test::call_service(&app, add(1)).await.status(),
+            StatusCode::CONFLICT
+        );
+
+        let delete = || {
+            test::TestRequest::delete()
+                .uri("/limits/test_namespace/per_app")
+                .to_request()
+        };
+        assert!(test::call_service(&app, delete())
+            .await
+            .status()
+            .is_success());
+        assert_eq!(
+            test::call_service(&app, delete()).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/limits/test_namespace")
+            .to_request();
+        let resp_limits: Vec<Limit> = test::call_and_read_body_json(&app, req).await;
+        assert!(resp_limits.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_limits_disabled_stop_enforcing_at_once() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let namespace = "test_namespace";
+        let mut limit = LimitadorLimit::new(
+            namespace,
+            1,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+        limit.set_name("per_app".to_string());
+        limiter.add_limit(limit);
+        let data = web::Data::new(Arc::new(limiter));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .app_data(web::Data::new(None::<OverLimitBody>))
+                .route("/limits/{namespace}", web::get().to(get_limits))
+                .route(
+                    "/limits/{namespace}/{name}/enabled",
+                    web::put().to(set_limit_enabled),
+                )
+                .route("/check_and_report", web::post().to(check_and_report)),
+        )
+        .await;
+
+        let check_and_report = || {
+            let mut values = HashMap::new();
+            values.insert("req.method".into(), "GET".into());
+            values.insert("app_id".into(), "1".into());
+            test::TestRequest::post()
+                .uri("/check_and_report")
+                .set_json(CheckAndReportInfo {
+                    namespace: namespace.into(),
+                    values,
+                    delta: 1,
+                })
+                .to_request()
+        };
+        let set_enabled = |name: &str, enabled: bool| {
+            test::TestRequest::put()
+                .uri(&format!("/limits/{namespace}/{name}/enabled"))
+                .set_json(enabled)
+                .to_request()
+        };
+
+        assert!(test::call_service(&app, check_and_report())
+            .await
+            .status()
+            .is_success());
+        assert_eq!(
+            test::call_service(&app, check_and_report()).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        assert!(test::call_service(&app, set_enabled("per_app", false))
+            .await
+            .status()
+            .is_success());
+        assert!(test::call_service(&app, check_and_report())
+            .await
+            .status()
+            .is_success());
+        let req = test::TestRequest::get()
+            .uri("/limits/test_namespace")
+            .to_request();
+        let resp_limits: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp_limits[0]["enabled"], false);
+
+        // The counter was kept along
+        assert!(test::call_service(&app, set_enabled("per_app", true))
+            .await
+            .status()
+            .is_success());
+        assert_eq!(
+            test::call_service(&app, check_and_report()).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        assert_eq!(
+            test::call_service(&app, set_enabled("per_user", false))
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_kill_switches_flipped() {
+        let kill_switch = Arc::new(KillSwitch::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(kill_switch.clone()))
+                .route("/kill_switch", web::get().to(get_kill_switches))
+                .route("/kill_switch", web::put().to(set_kill_switch))
+                .route(
+                    "/kill_switch/{namespace}",
+                    web::put().to(set_namespace_kill_switch),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/kill_switch")
+            .set_json("Allow")
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::put()
+            .uri("/kill_switch/test_namespace")
+            .set_json("Deny")
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(
+            kill_switch.mode_for(&"test_namespace".into()),
+            Some(KillSwitchMode::Deny)
+        );
+
+        let req = test::TestRequest::get().uri("/kill_switch").to_request();
+        let switches: KillSwitches = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(switches.global, Some(KillSwitchMode::Allow));
+        assert_eq!(
+            switches.namespaces,
+            HashMap::from([("test_namespace".to_string(), KillSwitchMode::Deny)])
+        );
+
+        let req = test::TestRequest::put()
+            .uri("/kill_switch")
+            .set_json(None::<KillSwitchMode>)
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(kill_switch.mode_for(&"other_namespace".into()), None);
+
+        let req = test::TestRequest::put()
+            .uri("/kill_switch/test_namespace")
+            .set_json("Bogus")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_debug_log_sampling_changed() {
+        let sampler = Arc::new(DebugLogSampler::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(sampler.clone()))
+                .route("/debug_log_sampling", web::get().to(get_debug_log_sampling))
+                .route("/debug_log_sampling", web::put().to(set_debug_log_sampling)),
+        )
+        .await;
+
+        let sampling = DebugLogSampling {
+            every: 100,
+            over_limit_only: true,
+        };
+        let req = test::TestRequest::put()
+            .uri("/debug_log_sampling")
+            .set_json(sampling)
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        assert_eq!(sampler.sampling(), sampling);
+
+        let req = test::TestRequest::get()
+            .uri("/debug_log_sampling")
+            .to_request();
+        let read: DebugLogSampling = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(read, sampling);
+
+        let req = test::TestRequest::put()
+            .uri("/debug_log_sampling")
+            .set_json(DebugLogSampling {
+                every: 0,
+                over_limit_only: false,
+            })
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(sampler.sampling(), sampling);
+    }
+
+    #[actix_rt::test]
+    async fn test_counters_imported() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let namespace = "test_namespace";
+        let limit = create_test_limit(&limiter, namespace, 10).await;
+        let data = web::Data::new(Arc::new(limiter));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/counters/{namespace}", web::get().to(get_counters))
+                .route("/counters", web::post().to(import_counters)),
+        )
+        .await;
+
+        let import = |expires_in_seconds| {
+            let counter = serde_json::json!({
+                "limit": Limit::from(&limit),
+                "set_variables": {"app_id": "test_app_id"},
+                "remaining": 3,
+                "expires_in_seconds": expires_in_seconds,
+            });
+            test::TestRequest::post()
+                .uri("/counters")
+                .set_json([counter])
+                .to_request()
+        };
+        // Over the window of the limit
+        assert_eq!(
+            test::call_service(&app, import(61)).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert!(test::call_service(&app, import(30))
+            .await
+            .status()
+            .is_success());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/counters/{namespace}"))
+            .to_request();
+        let counters: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0]["remaining"], 3);
+        assert_eq!(counters[0]["set_variables"]["app_id"], "test_app_id");
+        assert!(counters[0]["expires_in_seconds"].as_u64().unwrap() <= 30);
+    }
+
+    #[actix_rt::test]
+    async fn test_counters_exported_and_imported_into_another_instance() {
+        let namespace = "test_namespace";
+        let blue = Limiter::new(Configuration::default()).await.unwrap();
+        create_test_limit(&blue, namespace, 10).await;
+        let green = Limiter::new(Configuration::default()).await.unwrap();
+        create_test_limit(&green, namespace, 10).await;
+
+        let mut values = HashMap::new();
+        values.insert("req.method".to_string(), "GET".to_string());
+        for (app_id, hits) in [("1", 2), ("2", 5)] {
+            values.insert("app_id".to_string(), app_id.to_string());
+            blue.update_counters(&namespace.into(), &values, hits)
+                .await
+                .unwrap();
+        }
+
+        let app = |limiter| {
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(Arc::new(limiter)))
+                    .route("/counters/{namespace}", web::get().to(get_counters))
+                    .route("/counters", web::get().to(export_counters))
+                    .route("/counters", web::post().to(import_counters)),
+            )
+        };
+        let blue = app(blue).await;
+        let green = app(green).await;
+
+        let req = test::TestRequest::get().uri("/counters").to_request();
+        let exported: Vec<serde_json::Value> = test::call_and_read_body_json(&blue, req).await;
+        assert_eq!(exported.len(), 2);
+
+        let req = test::TestRequest::post()
+            .uri("/counters")
+            .set_json(&exported)
+            .to_request();
+        assert!(test::call_service(&green, req).await.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/counters/{namespace}"))
+            .to_request();
+        let imported: Vec<serde_json::Value> = test::call_and_read_body_json(&green, req).await;
+        let remaining = |counters: &[serde_json::Value], app_id| {
+            counters
+                .iter()
+                .find(|counter| counter["set_variables"]["app_id"] == app_id)
+                .map(|counter| counter["remaining"].clone())
+        };
+        assert_eq!(remaining(&imported, "1"), Some(8.into()));
+        assert_eq!(remaining(&imported, "2"), Some(5.into()));
+    }
+
+    #[actix_rt::test]
+    async fn test_counter_reset_in_its_namespace_only() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let mut values = HashMap::new();
+        values.insert("req.method".to_string(), "GET".to_string());
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+        for namespace in ["test_namespace", "other_namespace"] {
+            let mut limit = LimitadorLimit::new(
+                namespace,
+                1,
+                60,
+                vec!["req.method == 'GET'"],
+                vec!["app_id"],
+            );
+            limit.set_name("per_app".to_string());
+            limiter.add_limit(limit);
+            limiter
+                .update_counters(&namespace.into(), &values, 1)
+                .await
+                .unwrap();
+        }
+        let data = web::Data::new(Arc::new(limiter));
+        let app = test::init_service(App::new().app_data(data.clone()).route(
+            "/counters/{namespace}/{name}",
+            web::delete().to(reset_counter),
+        ))
+        .await;
+
+        let reset = |uri: &str, values: &HashMap<String, String>| {
+            test::TestRequest::delete()
+                .uri(uri)
+                .set_json(values)
+                .to_request()
+        };
+        let existed: bool =
+            test::call_and_read_body_json(&app, reset("/counters/test_namespace/per_app", &values))
+                .await;
+        assert!(existed);
+        let existed: bool =
+            test::call_and_read_body_json(&app, reset("/counters/test_namespace/per_app", &values))
+                .await;
+        assert!(!existed);
+        assert_eq!(
+            test::call_service(&app, reset("/counters/test_namespace/per_user", &values))
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            test::call_service(
+                &app,
+                reset("/counters/test_namespace/per_app", &HashMap::new())
+            )
+            .await
+            .status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        assert!(!data
+            .is_rate_limited(&"test_namespace".into(), &values, 1)
+            .await
+            .unwrap());
+        assert!(data
+            .is_rate_limited(&"other_namespace".into(), &values, 1)
+            .await
+            .unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn test_limits_with_invalid_conditions_are_rejected() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let data = web::Data::new(Arc::new(limiter));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/limits", web::post().to(add_limit)),
+        )
+        .await;
+
+        let mut limit = serde_json::to_value(Limit::from(&LimitadorLimit::new(
+            "test_namespace",
+            10,
+            60,
+            Vec::<String>::default(),
+            vec!["app_id"],
+        )))
+        .unwrap();
+        limit["conditions"] = serde_json::json!(["req.method = GET"]);
+
+        let req = test::TestRequest::post()
+            .uri("/limits")
+            .set_json(&limit)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    async fn reserve(
+        limiter: &Limiter,
+        reservations: &Reservations,
+        namespace: &Namespace,
+        values: &HashMap<String, String>,
+    ) -> String {
+        let checked = limiter
+            .check_rate_limited_and_update(namespace, values, 1, true)
+            .await
+            .unwrap();
+        assert!(!checked.limited);
+        reservations.reserve(namespace, vec![(values.clone(), 1)], Duration::MAX)
+    }
+
     async fn create_test_limit(limiter: &Limiter, namespace: &str, max: i64) -> LimitadorLimit {
         // Create a limit
         let limit = LimitadorLimit::new(
@@ -378,10 +1340,7 @@ mod tests {
             vec!["app_id"],
         );
 
-        match &limiter {
-            Limiter::Blocking(limiter) => limiter.add_limit(limit.clone()),
-            Limiter::Async(limiter) => limiter.add_limit(limit.clone()),
-        };
+        limiter.add_limit(limit.clone());
         limit
     }
 }
@@ -0,0 +1,174 @@
+use limitador::limit::Namespace;
+use paperclip::actix::Apiv2Schema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/// What the RLS answers the requests a kill switch is engaged for, whatever
+/// their limits
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize, Apiv2Schema)]
+pub enum KillSwitchMode {
+    /// Let all the requests through, as if there were no limits
+    Allow,
+    /// Reject all the requests, as if they were all over the limit
+    Deny,
+}
+
+impl FromStr for KillSwitchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Self::Allow),
+            "deny" => Ok(Self::Deny),
+            _ => Err(format!(
+                "invalid kill switch mode '{s}': expected allow or deny"
+            )),
+        }
+    }
+}
+
+/// The kill switches engaged: the one of all the namespaces, and the ones of
+/// some of them, that win over the former.
+#[derive(PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize, Apiv2Schema)]
+pub struct KillSwitches {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub global: Option<KillSwitchMode>,
+    #[serde(default)]
+    pub namespaces: HashMap<String, KillSwitchMode>,
+}
+
+impl KillSwitches {
+    /// Parses the switches of the namespaces, each as `NAMESPACE=MODE`.
+    pub fn parse_by_namespace<'a>(
+        entries: impl IntoIterator<Item = &'a str>,
+    ) -> Result<HashMap<String, KillSwitchMode>, String> {
+        let mut by_namespace = HashMap::new();
+        for entry in entries {
+            let Some((namespace, mode)) = entry
+                .split_once('=')
+                .filter(|(namespace, _)| !namespace.is_empty())
+            else {
+                return Err(format!(
+                    "invalid kill switch of a namespace '{entry}': expected NAMESPACE=MODE"
+                ));
+            };
+            by_namespace.insert(namespace.to_string(), mode.parse()?);
+        }
+        Ok(by_namespace)
+    }
+
+    fn is_engaged(&self) -> bool {
+        self.global.is_some() || !self.namespaces.is_empty()
+    }
+}
+
+/// The emergency lever of the RLS: while engaged for a namespace, its requests
+/// are all let through, or all rejected, before any of their limits is looked
+/// at, and without the storage being reached. The switches can be flipped at
+/// runtime, through the admin API, each flip being logged.
+#[derive(Debug, Default)]
+pub struct KillSwitch {
+    // Whether any switch is engaged, for the requests not to lock otherwise
+    engaged: AtomicBool,
+    switches: RwLock<KillSwitches>,
+}
+
+impl KillSwitch {
+    pub fn new(switches: KillSwitches) -> Self {
+        Self {
+            engaged: AtomicBool::new(switches.is_engaged()),
+            switches: RwLock::new(switches),
+        }
+    }
+
+    /// The mode of the switch engaged for `namespace`, if any.
+    pub fn mode_for(&self, namespace: &Namespace) -> Option<KillSwitchMode> {
+        if !self.engaged.load(Ordering::Acquire) {
+            return None;
+        }
+        let switches = self.switches.read().unwrap();
+        switches
+            .namespaces
+            .get(namespace.as_ref())
+            .or(switches.global.as_ref())
+            .copied()
+    }
+
+    pub fn switches(&self) -> KillSwitches {
+        self.switches.read().unwrap().clone()
+    }
+
+    /// Engages the switch of all the namespaces, or releases it when `mode`
+    /// is `None`.
+    pub fn set_global(&self, mode: Option<KillSwitchMode>) {
+        let mut switches = self.switches.write().unwrap();
+        switches.global = mode;
+        self.engaged.store(switches.is_engaged(), Ordering::Release);
+        log_flip("all the namespaces", mode);
+    }
+
+    /// Engages the switch of `namespace`, or releases it when `mode` is
+    /// `None`, the global one then applying to it again.
+    pub fn set_for_namespace(&self, namespace: &str, mode: Option<KillSwitchMode>) {
+        let mut switches = self.switches.write().unwrap();
+        match mode {
+            Some(mode) => switches.namespaces.insert(namespace.to_string(), mode),
+            None => switches.namespaces.remove(namespace),
+        };
+        self.engaged.store(switches.is_engaged(), Ordering::Release);
+        log_flip(&format!("namespace {namespace}"), mode);
+    }
+}
+
+fn log_flip(scope: &str, mode: Option<KillSwitchMode>) {
+    match mode {
+        Some(KillSwitchMode::Allow) => {
+            warn!("Kill switch engaged for {scope}: letting all the requests through")
+        }
+        Some(KillSwitchMode::Deny) => {
+            warn!("Kill switch engaged for {scope}: rejecting all the requests")
+        }
+        None => warn!("Kill switch released for {scope}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespace_switches_win_over_the_global_one() {
+        let kill_switch = KillSwitch::default();
+        let namespace: Namespace = "example.org".into();
+        let other: Namespace = "other.org".into();
+        assert_eq!(kill_switch.mode_for(&namespace), None);
+
+        kill_switch.set_global(Some(KillSwitchMode::Allow));
+        kill_switch.set_for_namespace("example.org", Some(KillSwitchMode::Deny));
+        assert_eq!(kill_switch.mode_for(&namespace), Some(KillSwitchMode::Deny));
+        assert_eq!(kill_switch.mode_for(&other), Some(KillSwitchMode::Allow));
+
+        kill_switch.set_global(None);
+        assert_eq!(kill_switch.mode_for(&other), None);
+        kill_switch.set_for_namespace("example.org", None);
+        assert_eq!(kill_switch.mode_for(&namespace), None);
+        assert_eq!(kill_switch.switches(), KillSwitches::default());
+    }
+
+    #[test]
+    fn parses_the_switches_of_the_namespaces() {
+        assert_eq!(
+            KillSwitches::parse_by_namespace(["a.org=deny", "b.org=allow"]),
+            Ok(HashMap::from([
+                ("a.org".to_string(), KillSwitchMode::Deny),
+                ("b.org".to_string(), KillSwitchMode::Allow),
+            ]))
+        );
+        assert!(KillSwitches::parse_by_namespace(["a.org=off"]).is_err());
+        assert!(KillSwitches::parse_by_namespace(["=deny"]).is_err());
+        assert!(KillSwitches::parse_by_namespace(["a.org"]).is_err());
+    }
+}
@@ -0,0 +1,285 @@
+use crate::config::LimitsFileFormat;
+use crate::http_api::LimitVO;
+use crate::{parse_limits, LimitadorServerError};
+use limitador::limit::Limit;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How a set of limits differs from the one active: the limits it adds, the
+/// ones it removes, and the ones it modifies, along with what changes in them.
+#[derive(Debug, Default, Serialize)]
+pub struct LimitsDiff {
+    added: Vec<LimitVO>,
+    removed: Vec<LimitVO>,
+    modified: Vec<ModifiedLimit>,
+    unchanged: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct ModifiedLimit {
+    namespace: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    changes: BTreeMap<String, Change>,
+}
+
+// The values of a field of a limit, null when not set
+#[derive(Debug, PartialEq, Serialize)]
+struct Change {
+    from: Value,
+    to: Value,
+}
+
+impl LimitsDiff {
+    /// Reads the limits of both files, the active one in `active_format`, the
+    /// other in the format told by its extension, whatever the active one's.
+    pub fn between_files(
+        active: &str,
+        active_format: LimitsFileFormat,
+        file: &str,
+    ) -> Result<Self, LimitadorServerError> {
+        let read = |path: &str, format| {
+            std::fs::File::open(path)
+                .map_err(|e| {
+                    LimitadorServerError::ConfigFile(format!("Couldn't read file '{path}': {e}"))
+                })
+                .and_then(|f| parse_limits(f, format))
+        };
+        let active = read(active, active_format)?;
+        let limits = read(file, LimitsFileFormat::of(file))?;
+        Ok(Self::between(&active, &limits))
+    }
+
+    /// Named limits are told apart by their namespace and name, whatever else
+    /// changes in them. The others, and the ones renamed, by what identifies
+    /// their counters, i.e. their window, conditions and variables.
+    pub fn between(active: &[Limit], limits: &[Limit]) -> Self {
+        let mut unmatched: Vec<&Limit> = active.iter().collect();
+        let mut take = |found: &dyn Fn(&Limit) -> bool| {
+            let position = unmatched.iter().position(|limit| found(limit))?;
+            Some(unmatched.swap_remove(position))
+        };
+
+        let mut pairs = Vec::new();
+        let mut unnamed = Vec::new();
+        for limit in limits {
+            let by_name = limit.name().and_then(|name| {
+                take(&|active| {
+                    active.namespace() == limit.namespace() && active.name() == Some(name)
+                })
+            });
+            match by_name {
+                Some(active) => pairs.push((active, limit)),
+                None => unnamed.push(limit),
+            }
+        }
+
+        let mut diff = Self::default();
+        for limit in unnamed {
+            match take(&|active| active == limit) {
+                Some(active) => pairs.push((active, limit)),
+                None => diff.added.push(limit.into()),
+            }
+        }
+        diff.removed = unmatched.into_iter().map(LimitVO::from).collect();
+
+        for (active, limit) in pairs {
+            let changes = changes(active, limit);
+            if changes.is_empty() {
+                diff.unchanged += 1;
+            } else {
+                diff.modified.push(ModifiedLimit {
+                    namespace: limit.namespace().as_ref().to_string(),
+                    name: limit.name().or(active.name()).map(str::to_string),
+                    changes,
+                });
+            }
+        }
+        diff
+    }
+}
+
+fn changes(active: &Limit, limit: &Limit) -> BTreeMap<String, Change> {
+    let (from, to) = (fields_of(active), fields_of(limit));
+    let names: BTreeSet<&String> = from.keys().chain(to.keys()).collect();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let change = Change {
+                from: from.get(name).cloned().unwrap_or(Value::Null),
+                to: to.get(name).cloned().unwrap_or(Value::Null),
+            };
+            (change.from != change.to).then(|| (name.clone(), change))
+        })
+        .collect()
+}
+
+// The fields of the limit as they read in a limits file, the sets of
+// conditions and variables sorted, for their order not to tell them apart
+fn fields_of(limit: &Limit) -> Map<String, Value> {
+    let Ok(Value::Object(mut fields)) = serde_json::to_value(LimitVO::from(limit)) else {
+        unreachable!("Limits serialize to JSON objects")
+    };
+    for value in fields.values_mut() {
+        if let Value::Array(items) = value {
+            items.sort_by_key(Value::to_string);
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(yaml: &str) -> Vec<Limit> {
+        parse_limits(yaml.as_bytes(), LimitsFileFormat::Yaml).unwrap()
+    }
+
+    #[test]
+    fn tells_the_limits_added_removed_and_modified() {
+        let active = limits(
+            "
+- namespace: test_namespace
+  name: per_app
+  max_value: 10
+  seconds: 60
+  conditions: [\"req.method == 'GET'\", \"req.path == '/'\"]
+  variables: [app_id]
+- namespace: test_namespace
+  max_value: 5
+  seconds: 1
+  conditions: []
+  variables: [user_id]
+- namespace: test_namespace
+  max_value: 100
+  seconds: 3600
+  conditions: []
+  variables: []
+",
+        );
+        let new = limits(
+            "
+- namespace: test_namespace
+  name: per_app
+  max_value: 20
+  seconds: 60
+  conditions: [\"req.path == '/'\", \"req.method == 'GET'\"]
+  variables: [app_id]
+- namespace: test_namespace
+  max_value: 5
+  seconds: 1
+  conditions: []
+  variables: [user_id]
+- namespace: other_namespace
+  max_value: 1
+  seconds: 1
+  conditions: []
+  variables: []
+",
+        );
+
+        let diff = LimitsDiff::between(&active, &new);
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.added, vec![LimitVO::from(&new[2])]);
+        assert_eq!(diff.removed, vec![LimitVO::from(&active[2])]);
+        assert_eq!(diff.modified.len(), 1);
+        assert_eq!(diff.modified[0].name.as_deref(), Some("per_app"));
+        assert_eq!(
+            diff.modified[0].changes,
+            BTreeMap::from([(
+                "max_value".to_string(),
+                Change {
+                    from: 10.into(),
+                    to: 20.into(),
+                }
+            )])
+        );
+    }
+
+    #[test]
+    fn limits_renamed_are_modified_rather_than_replaced() {
+        let active = limits(
+            "
+- namespace: test_namespace
+  max_value: 10
+  seconds: 60
+  conditions: []
+  variables: [app_id]
+",
+        );
+        let new = limits(
+            "
+- namespace: test_namespace
+  name: per_app
+  max_value: 10
+  seconds: 60
+  conditions: []
+  variables: [app_id]
+",
+        );
+
+        let diff = LimitsDiff::between(&active, &new);
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert_eq!(
+            diff.modified[0].changes,
+            BTreeMap::from([(
+                "name".to_string(),
+                Change {
+                    from: Value::Null,
+                    to: "per_app".into(),
+                }
+            )])
+        );
+    }
+
+    #[test]
+    fn reads_each_file_in_its_own_format() {
+        let dir = std::env::temp_dir();
+        let yaml = dir.join(format!("limitador-diff-{}.yaml", std::process::id()));
+        let json = dir.join(format!("limitador-diff-{}.json", std::process::id()));
+        std::fs::write(
+            &yaml,
+            "
+- namespace: test_namespace
+  name: per_app
+  max_value: 10
+  seconds: 60
+  conditions: []
+  variables: [app_id]
+",
+        )
+        .unwrap();
+        std::fs::write(
+            &json,
+            r#"[{"namespace": "test_namespace", "name": "per_app", "max_value": 20,
+                "seconds": 60, "conditions": [], "variables": ["app_id"]}]"#,
+        )
+        .unwrap();
+        let (yaml, json) = (yaml.to_str().unwrap(), json.to_str().unwrap());
+
+        let diff = LimitsDiff::between_files(yaml, LimitsFileFormat::Yaml, json).unwrap();
+        assert_eq!(diff.modified.len(), 1);
+        assert_eq!(
+            diff.modified[0].changes["max_value"],
+            Change {
+                from: 10.into(),
+                to: 20.into(),
+            }
+        );
+
+        let diff = LimitsDiff::between_files(json, LimitsFileFormat::Json, yaml).unwrap();
+        assert_eq!(
+            diff.modified[0].changes["max_value"],
+            Change {
+                from: 20.into(),
+                to: 10.into(),
+            }
+        );
+
+        std::fs::remove_file(yaml).unwrap();
+        std::fs::remove_file(json).unwrap();
+    }
+}
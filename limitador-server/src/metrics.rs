@@ -0,0 +1,467 @@
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use limitador::counter::Counter;
+use limitador::limit::{Limit, Namespace};
+use limitador::storage::{AsyncCounterStorage, Authorization, CounterStorage, StorageErr};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::{BTreeSet, HashSet};
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::{info_span, Instrument};
+
+// These complement the metrics of the limiter itself, which only knows about
+// the outcome of each check, with what the server sees: the answers sent to
+// Envoy, and how the storages behind the limiter are doing.
+
+const NAMESPACE_LABEL: &str = "limitador_namespace";
+const CODE_LABEL: &str = "code";
+const STORAGE_LABEL: &str = "storage";
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    static ref RLS_REQUESTS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "rls_requests",
+                "Envoy RLS requests answered, by response code"
+            ),
+            &[NAMESPACE_LABEL, CODE_LABEL],
+        )
+        .unwrap()
+    );
+    static ref RLS_REQUESTS_IN_FLIGHT: IntGauge = register(
+        IntGauge::new(
+            "rls_requests_in_flight",
+            "Envoy RLS requests being answered"
+        )
+        .unwrap()
+    );
+    static ref RLS_MAX_CONCURRENT_REQUESTS: IntGauge = register(
+        IntGauge::new(
+            "rls_max_concurrent_requests",
+            "Envoy RLS requests that can be in flight before new ones are shed, 0 when unlimited"
+        )
+        .unwrap()
+    );
+    static ref RLS_SHED_REQUESTS: IntCounter = register(
+        IntCounter::new(
+            "rls_shed_requests",
+            "Envoy RLS requests answered RESOURCE_EXHAUSTED, for too many being in flight"
+        )
+        .unwrap()
+    );
+    static ref DECISION_CACHE_HITS: IntCounter = register(
+        IntCounter::new(
+            "decision_cache_hits",
+            "Checks answered from the decision cache, without reaching the storage"
+        )
+        .unwrap()
+    );
+    static ref DECISION_CACHE_MISSES: IntCounter = register(
+        IntCounter::new(
+            "decision_cache_misses",
+            "Checks the decision cache had no answer for, passed on to the storage"
+        )
+        .unwrap()
+    );
+    static ref DATASTORE_LATENCY: HistogramVec = register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "datastore_latency",
+                "Latency of the calls to the counters storage, in seconds",
+            ),
+            &[STORAGE_LABEL],
+        )
+        .unwrap()
+    );
+    static ref DATASTORE_ERRORS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "datastore_errors",
+                "Errors returned by the counters storage"
+            ),
+            &[STORAGE_LABEL],
+        )
+        .unwrap()
+    );
+}
+
+// Set at startup, when the storage calls slower than the threshold are to be
+// logged
+static SLOW_STORAGE_CALLS: OnceLock<(Duration, log::Level)> = OnceLock::new();
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+pub fn incr_rls_requests(namespace: &str, code: &str) {
+    RLS_REQUESTS.with_label_values(&[namespace, code]).inc();
+}
+
+pub fn incr_rls_requests_in_flight() {
+    RLS_REQUESTS_IN_FLIGHT.inc();
+}
+
+pub fn decr_rls_requests_in_flight() {
+    RLS_REQUESTS_IN_FLIGHT.dec();
+}
+
+pub fn set_rls_max_concurrent_requests(max: usize) {
+    RLS_MAX_CONCURRENT_REQUESTS.set(max as i64);
+}
+
+pub fn incr_rls_shed_requests() {
+    RLS_SHED_REQUESTS.inc();
+}
+
+pub fn incr_decision_cache_hits() {
+    DECISION_CACHE_HITS.inc();
+}
+
+pub fn incr_decision_cache_misses() {
+    DECISION_CACHE_MISSES.inc();
+}
+
+/// Logs, at `level`, the storage calls that take longer than `threshold`,
+/// along with the namespaces of the counters they were about.
+pub fn log_slow_storage_calls(threshold: Duration, level: log::Level) {
+    let _ = SLOW_STORAGE_CALLS.set((threshold, level));
+}
+
+pub fn gather_metrics() -> String {
+    let mut buffer = Vec::new();
+
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .unwrap();
+
+    String::from_utf8(buffer).unwrap()
+}
+
+fn observe<T>(
+    storage: &str,
+    call: &str,
+    namespaces: Option<String>,
+    started: Instant,
+    res: &Result<T, StorageErr>,
+) {
+    let elapsed = started.elapsed();
+    DATASTORE_LATENCY
+        .with_label_values(&[storage])
+        .observe(elapsed.as_secs_f64());
+    if res.is_err() {
+        DATASTORE_ERRORS.with_label_values(&[storage]).inc();
+    }
+    if let (Some((threshold, level)), Some(namespaces)) = (SLOW_STORAGE_CALLS.get(), namespaces) {
+        if elapsed > *threshold {
+            log::log!(
+                *level,
+                "Slow {} call to the {} storage, for namespaces {:?}: took {}ms",
+                call,
+                storage,
+                namespaces,
+                elapsed.as_millis()
+            );
+        }
+    }
+}
+
+// The namespaces a call is about, as logged should it be slow, only when the
+// slow calls are logged at all. Taken before the call, as it might borrow the
+// counters mutably.
+fn namespaces_of<'a>(namespaces: impl IntoIterator<Item = &'a Namespace>) -> Option<String> {
+    SLOW_STORAGE_CALLS.get()?;
+    let namespaces: BTreeSet<&str> = namespaces.into_iter().map(AsRef::as_ref).collect();
+    Some(namespaces.into_iter().collect::<Vec<_>>().join(","))
+}
+
+/// Records the latency and errors of the calls made to `storage`, labeled as
+/// `name`, and traces them as `datastore` spans.
+pub struct MeteredStorage {
+    name: &'static str,
+    storage: Box<dyn CounterStorage>,
+}
+
+impl MeteredStorage {
+    pub fn new(name: &'static str, storage: Box<dyn CounterStorage>) -> Self {
+        Self { name, storage }
+    }
+
+    fn metered<T>(
+        &self,
+        call: &str,
+        namespaces: Option<String>,
+        f: impl FnOnce() -> Result<T, StorageErr>,
+    ) -> Result<T, StorageErr> {
+        let _span = info_span!("datastore", storage = self.name).entered();
+        let started = Instant::now();
+        let res = f();
+        observe(self.name, call, namespaces, started, &res);
+        res
+    }
+}
+
+impl CounterStorage for MeteredStorage {
+    fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        self.metered(
+            "is_within_limits",
+            namespaces_of([counter.namespace()]),
+            || self.storage.is_within_limits(counter, delta),
+        )
+    }
+
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.metered("add_counter", namespaces_of([limit.namespace()]), || {
+            self.storage.add_counter(limit)
+        })
+    }
+
+    fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        self.metered(
+            "update_counter",
+            namespaces_of([counter.namespace()]),
+            || self.storage.update_counter(counter, delta),
+        )
+    }
+
+    fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        let namespaces = namespaces_of(counters.iter().map(Counter::namespace));
+        self.metered("check_and_update", namespaces, || {
+            self.storage
+                .check_and_update(counters, delta, load_counters)
+        })
+    }
+
+    fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        let namespaces = namespaces_of(
+            batch
+                .iter()
+                .flat_map(|(counters, _)| counters.iter().map(Counter::namespace)),
+        );
+        self.metered("check_and_update_batch", namespaces, || {
+            self.storage.check_and_update_batch(batch, load_counters)
+        })
+    }
+
+    fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        self.metered("set_counter", namespaces_of([counter.namespace()]), || {
+            self.storage.set_counter(counter, remaining, expires_in)
+        })
+    }
+
+    fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        self.metered(
+            "delete_counter",
+            namespaces_of([counter.namespace()]),
+            || self.storage.delete_counter(counter),
+        )
+    }
+
+    fn get_counters(&self, limits: &HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
+        self.metered(
+            "get_counters",
+            namespaces_of(limits.iter().map(Limit::namespace)),
+            || self.storage.get_counters(limits),
+        )
+    }
+
+    fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+        let namespaces = namespaces_of(limits.iter().map(Limit::namespace));
+        self.metered("delete_counters", namespaces, || {
+            self.storage.delete_counters(limits)
+        })
+    }
+
+    fn clear(&self) -> Result<(), StorageErr> {
+        self.metered("clear", namespaces_of([]), || self.storage.clear())
+    }
+
+    fn ping(&self) -> Result<(), StorageErr> {
+        self.storage.ping()
+    }
+}
+
+/// Same as [`MeteredStorage`], for async storages.
+pub struct AsyncMeteredStorage {
+    name: &'static str,
+    storage: Box<dyn AsyncCounterStorage>,
+}
+
+impl AsyncMeteredStorage {
+    pub fn new(name: &'static str, storage: Box<dyn AsyncCounterStorage>) -> Self {
+        Self { name, storage }
+    }
+
+    async fn metered<T>(
+        &self,
+        call: &str,
+        namespaces: Option<String>,
+        f: impl Future<Output = Result<T, StorageErr>>,
+    ) -> Result<T, StorageErr> {
+        let started = Instant::now();
+        let res = f
+            .instrument(info_span!("datastore", storage = self.name))
+            .await;
+        observe(self.name, call, namespaces, started, &res);
+        res
+    }
+}
+
+#[async_trait]
+impl AsyncCounterStorage for AsyncMeteredStorage {
+    async fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        self.metered(
+            "is_within_limits",
+            namespaces_of([counter.namespace()]),
+            self.storage.is_within_limits(counter, delta),
+        )
+        .await
+    }
+
+    async fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        self.metered(
+            "update_counter",
+            namespaces_of([counter.namespace()]),
+            self.storage.update_counter(counter, delta),
+        )
+        .await
+    }
+
+    async fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        let namespaces = namespaces_of(counters.iter().map(Counter::namespace));
+        self.metered(
+            "check_and_update",
+            namespaces,
+            self.storage
+                .check_and_update(counters, delta, load_counters),
+        )
+        .await
+    }
+
+    async fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        let namespaces = namespaces_of(
+            batch
+                .iter()
+                .flat_map(|(counters, _)| counters.iter().map(Counter::namespace)),
+        );
+        self.metered(
+            "check_and_update_batch",
+            namespaces,
+            self.storage.check_and_update_batch(batch, load_counters),
+        )
+        .await
+    }
+
+    async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        self.metered(
+            "set_counter",
+            namespaces_of([counter.namespace()]),
+            self.storage.set_counter(counter, remaining, expires_in),
+        )
+        .await
+    }
+
+    async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        self.metered(
+            "delete_counter",
+            namespaces_of([counter.namespace()]),
+            self.storage.delete_counter(counter),
+        )
+        .await
+    }
+
+    async fn get_counters(&self, limits: HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
+        let namespaces = namespaces_of(limits.iter().map(Limit::namespace));
+        self.metered(
+            "get_counters",
+            namespaces,
+            self.storage.get_counters(limits),
+        )
+        .await
+    }
+
+    async fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+        let namespaces = namespaces_of(limits.iter().map(Limit::namespace));
+        self.metered(
+            "delete_counters",
+            namespaces,
+            self.storage.delete_counters(limits),
+        )
+        .await
+    }
+
+    async fn clear(&self) -> Result<(), StorageErr> {
+        self.metered("clear", namespaces_of([]), self.storage.clear())
+            .await
+    }
+
+    async fn ping(&self) -> Result<(), StorageErr> {
+        self.storage.ping().await
+    }
+
+    async fn flush(&self) -> Result<(), StorageErr> {
+        self.storage.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use limitador::storage::in_memory::InMemoryStorage;
+    use std::collections::HashMap;
+
+    #[test]
+    fn records_the_latency_of_the_storage_calls() {
+        let storage =
+            MeteredStorage::new("metered_test_storage", Box::<InMemoryStorage>::default());
+        let limit = Limit::new("ns", 10, 60, Vec::<String>::default(), vec!["id"]);
+        let counter = Counter::new(limit, HashMap::from([("id".to_string(), "1".to_string())]));
+
+        storage.update_counter(&counter, 1).unwrap();
+        storage.is_within_limits(&counter, 1).unwrap();
+
+        assert!(gather_metrics()
+            .contains("datastore_latency_count{storage=\"metered_test_storage\"} 2"));
+    }
+
+    #[test]
+    fn slow_calls_are_logged_with_their_namespaces() {
+        log_slow_storage_calls(Duration::ZERO, log::Level::Debug);
+        let (a, b): (Namespace, Namespace) = ("a".into(), "b".into());
+
+        assert_eq!(namespaces_of([&b, &a, &b]), Some("a,b".to_string()));
+    }
+}
@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+pub const NAMESPACE_PLACEHOLDER: &str = "{namespace}";
+pub const RETRY_AFTER_PLACEHOLDER: &str = "{retry_after}";
+
+/// How the template of the body is to be read, and the body sent
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum OverLimitBodyFormat {
+    Json,
+    Text,
+}
+
+/// The body the requests over the limits are answered with, e.g. in the
+/// `429` responses Envoy sends, from a template whose `{namespace}` and
+/// `{retry_after}` placeholders are replaced with the namespace of the
+/// request, and the seconds to wait before retrying it, `0` when unknown.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct OverLimitBody {
+    template: String,
+    format: OverLimitBodyFormat,
+}
+
+impl OverLimitBody {
+    /// Errs when a JSON template doesn't render to valid JSON, e.g. because a
+    /// `{namespace}` placeholder isn't quoted.
+    pub fn new(template: String, format: OverLimitBodyFormat) -> Result<Self, String> {
+        let body = Self { template, format };
+        if format == OverLimitBodyFormat::Json {
+            serde_json::from_str::<serde_json::Value>(&body.render("namespace", None))
+                .map_err(|e| format!("the over limit body isn't a JSON template: {e}"))?;
+        }
+        Ok(body)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            OverLimitBodyFormat::Json => "application/json",
+            OverLimitBodyFormat::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn render(&self, namespace: &str, retry_after: Option<Duration>) -> String {
+        let namespace = match self.format {
+            // Escaped as within a JSON string, the template quoting it
+            OverLimitBodyFormat::Json => {
+                let quoted = serde_json::to_string(namespace).unwrap();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            OverLimitBodyFormat::Text => namespace.to_string(),
+        };
+        // Rounded up, so that clients waiting for that long aren't early
+        let retry_after = retry_after.map_or(0, |duration| {
+            duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+        });
+        self.template
+            .replace(NAMESPACE_PLACEHOLDER, &namespace)
+            .replace(RETRY_AFTER_PLACEHOLDER, &retry_after.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_placeholders() {
+        let body = OverLimitBody::new(
+            "Too many requests to {namespace}, retry in {retry_after}s".to_string(),
+            OverLimitBodyFormat::Text,
+        )
+        .unwrap();
+        assert_eq!(
+            body.render("example.org", Some(Duration::from_millis(1_500))),
+            "Too many requests to example.org, retry in 2s"
+        );
+        assert_eq!(
+            body.render("example.org", None),
+            "Too many requests to example.org, retry in 0s"
+        );
+        assert_eq!(body.content_type(), "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn json_templates_render_to_json() {
+        let body = OverLimitBody::new(
+            r#"{"error": "over limit", "namespace": "{namespace}", "retry_after": {retry_after}}"#
+                .to_string(),
+            OverLimitBodyFormat::Json,
+        )
+        .unwrap();
+        let rendered: serde_json::Value =
+            serde_json::from_str(&body.render(r#"a "quoted" ns"#, Some(Duration::from_secs(3))))
+                .unwrap();
+        assert_eq!(
+            rendered,
+            serde_json::json!({
+                "error": "over limit",
+                "namespace": "a \"quoted\" ns",
+                "retry_after": 3,
+            })
+        );
+        assert_eq!(body.content_type(), "application/json");
+
+        assert!(OverLimitBody::new(
+            r#"{"namespace": {namespace}}"#.to_string(),
+            OverLimitBodyFormat::Json
+        )
+        .is_err());
+    }
+}
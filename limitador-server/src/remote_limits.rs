@@ -0,0 +1,215 @@
+use crate::config::LimitsFileFormat;
+use crate::{parse_limits, LimitadorServerError, Limiter};
+use reqwest::header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use std::sync::Weak;
+use std::time::Duration;
+
+// A config service too slow to answer is given up on until the next poll
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether the limits are served over HTTP, rather than read from a file
+pub fn is_remote(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Limits served over HTTP, that are only parsed and loaded again when they
+/// changed since they last were, as told by their `ETag` or `Last-Modified`.
+pub struct RemoteLimits {
+    client: reqwest::Client,
+    url: String,
+    format: LimitsFileFormat,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+impl RemoteLimits {
+    pub fn new(url: String, format: LimitsFileFormat) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(FETCH_TIMEOUT)
+                .build()
+                .expect("a client without TLS settings of its own"),
+            url,
+            format,
+            etag: None,
+            last_modified: None,
+        }
+    }
+
+    /// Loads the limits into the limiter, unless they didn't change. Returns
+    /// whether they were loaded: on failure, the limiter keeps its limits.
+    pub async fn load_into(&mut self, limiter: &Limiter) -> Result<bool, LimitadorServerError> {
+        let mut request = self.client.get(&self.url);
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+
+        let response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| self.fetch_error(e))?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(false);
+        }
+        let etag = response.headers().get(ETAG).cloned();
+        let last_modified = response.headers().get(LAST_MODIFIED).cloned();
+        let body = response.bytes().await.map_err(|e| self.fetch_error(e))?;
+
+        limiter
+            .configure_with(parse_limits(body.as_ref(), self.format)?)
+            .await?;
+        // Only once loaded, so that the limits failing to be are tried again
+        self.etag = etag;
+        self.last_modified = last_modified;
+        Ok(true)
+    }
+
+    /// Loads the limits every `interval` they changed, for as long as the
+    /// limiter is around.
+    pub async fn poll(mut self, limiter: Weak<Limiter>, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let Some(limiter) = limiter.upgrade() else {
+                break;
+            };
+            match self.load_into(&limiter).await {
+                Ok(true) => info!("limits changed; reloaded them from {}", self.url),
+                Ok(false) => {}
+                Err(e) => error!("Failed reloading the limits: {}", e),
+            }
+        }
+    }
+
+    fn fetch_error(&self, e: reqwest::Error) -> LimitadorServerError {
+        LimitadorServerError::ConfigFile(format!("Couldn't fetch '{}': {}", self.url, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Configuration;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const LIMITS_V1: &str = "
+- namespace: test_namespace
+  max_value: 10
+  seconds: 60
+  conditions: []
+  variables: [app_id]
+";
+
+    const LIMITS_V2: &str = "
+- namespace: test_namespace
+  max_value: 20
+  seconds: 60
+  conditions: []
+  variables: [app_id]
+";
+
+    // What the config service serves: the ETag of the limits, and the limits
+    struct Served {
+        limits: Mutex<(&'static str, &'static str)>,
+        failing: AtomicBool,
+        requests: AtomicUsize,
+        not_modified: AtomicUsize,
+    }
+
+    // A config service answering each request on a connection of its own
+    async fn serve(limits: (&'static str, &'static str)) -> (String, Arc<Served>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/limits.yaml", listener.local_addr().unwrap());
+        let served = Arc::new(Served {
+            limits: Mutex::new(limits),
+            failing: AtomicBool::new(false),
+            requests: AtomicUsize::new(0),
+            not_modified: AtomicUsize::new(0),
+        });
+
+        let state = Arc::clone(&served);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+                let request = String::from_utf8(request).unwrap().to_lowercase();
+                state.requests.fetch_add(1, Ordering::SeqCst);
+
+                let (etag, limits) = *state.limits.lock().unwrap();
+                let response = if state.failing.load(Ordering::SeqCst) {
+                    "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+                } else if request.contains(&format!("if-none-match: {etag}\r\n")) {
+                    state.not_modified.fetch_add(1, Ordering::SeqCst);
+                    format!(
+                        "HTTP/1.1 304 Not Modified\r\nETag: {etag}\r\nConnection: close\r\n\r\n"
+                    )
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nETag: {etag}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{limits}",
+                        limits.len()
+                    )
+                };
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, served)
+    }
+
+    fn max_values(limiter: &Limiter) -> Vec<i64> {
+        let limits = limiter.get_limits(&"test_namespace".into());
+        limits.iter().map(|limit| limit.max_value()).collect()
+    }
+
+    #[tokio::test]
+    async fn loads_the_limits_again_only_when_they_changed() {
+        let (url, served) = serve(("\"v1\"", LIMITS_V1)).await;
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let mut remote = RemoteLimits::new(url, LimitsFileFormat::Yaml);
+
+        assert!(remote.load_into(&limiter).await.unwrap());
+        assert_eq!(max_values(&limiter), vec![10]);
+
+        assert!(!remote.load_into(&limiter).await.unwrap());
+        assert_eq!(served.not_modified.load(Ordering::SeqCst), 1);
+
+        *served.limits.lock().unwrap() = ("\"v2\"", LIMITS_V2);
+        assert!(remote.load_into(&limiter).await.unwrap());
+        assert_eq!(max_values(&limiter), vec![20]);
+        assert_eq!(served.requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_last_limits_loaded_when_failing() {
+        let (url, served) = serve(("\"v1\"", LIMITS_V1)).await;
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let mut remote = RemoteLimits::new(url, LimitsFileFormat::Yaml);
+        remote.load_into(&limiter).await.unwrap();
+
+        served.failing.store(true, Ordering::SeqCst);
+        assert!(remote.load_into(&limiter).await.is_err());
+        assert_eq!(max_values(&limiter), vec![10]);
+
+        served.failing.store(false, Ordering::SeqCst);
+        *served.limits.lock().unwrap() = ("\"v2\"", "not: [limits");
+        assert!(remote.load_into(&limiter).await.is_err());
+        assert_eq!(max_values(&limiter), vec![10]);
+
+        // The limits that failed to load are fetched whole again
+        assert!(remote.load_into(&limiter).await.is_err());
+        assert_eq!(served.not_modified.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use limitador::errors::LimitadorError;
+use limitador::limit::Namespace;
+
+use crate::Limiter;
+
+// How late the reservations expired are released at most
+const MAX_EXPIRY_PERIOD: Duration = Duration::from_secs(1);
+
+// The hits a request was counted, pending the outcome of its response
+struct Reservation {
+    namespace: Namespace,
+    descriptors: Vec<(HashMap<String, String>, i64)>,
+    expires_at: Instant,
+}
+
+/// The hits of the requests checked in two phases: counted as they're let
+/// through, as any others, but only kept for good once the outcome of their
+/// response confirms them. Released, they're given back to their counters, and
+/// so are the ones of the reservations neither confirmed nor released in time.
+pub struct Reservations {
+    limiter: Arc<Limiter>,
+    timeout: Duration,
+    pending: Mutex<HashMap<String, Reservation>>,
+    // The ids are the sequence hashed with a random key, not to be guessed
+    ids: RandomState,
+    sequence: AtomicU64,
+}
+
+impl Reservations {
+    pub fn new(limiter: Arc<Limiter>, timeout: Duration) -> Self {
+        Self {
+            limiter,
+            timeout,
+            pending: Mutex::new(HashMap::new()),
+            ids: RandomState::new(),
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    /// Holds the hits counted to the descriptors, for up to the timeout, or
+    /// `validity` when shorter, e.g. not to outlive the windows they were
+    /// counted in. Returns the id to confirm or release them with.
+    pub fn reserve(
+        &self,
+        namespace: &Namespace,
+        descriptors: Vec<(HashMap<String, String>, i64)>,
+        validity: Duration,
+    ) -> String {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let id = format!("{:016x}{sequence:x}", self.ids.hash_one(sequence));
+        let reservation = Reservation {
+            namespace: namespace.clone(),
+            descriptors,
+            expires_at: Instant::now() + validity.min(self.timeout),
+        };
+        self.pending.lock().unwrap().insert(id.clone(), reservation);
+        id
+    }
+
+    /// Keeps the hits of the reservation counted. False when there's no such
+    /// reservation pending, e.g. it already expired.
+    pub fn confirm(&self, id: &str) -> bool {
+        self.take(id).is_some()
+    }
+
+    /// Gives the hits of the reservation back to its counters. False when
+    /// there's no such reservation pending, e.g. it already expired. Should
+    /// the storage fail meanwhile, the hits stay counted.
+    pub async fn release(&self, id: &str) -> Result<bool, LimitadorError> {
+        match self.take(id) {
+            Some(reservation) => self.give_back(reservation).await.map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Releases the reservations as they expire, for as long as they are
+    /// around.
+    pub async fn expire(reservations: Weak<Self>) {
+        loop {
+            let Some(reservations) = reservations.upgrade() else {
+                return;
+            };
+            reservations.release_expired().await;
+            let period = reservations.timeout.min(MAX_EXPIRY_PERIOD);
+            drop(reservations);
+            tokio::time::sleep(period).await;
+        }
+    }
+
+    async fn release_expired(&self) {
+        let now = Instant::now();
+        let expired: Vec<Reservation> = {
+            let mut pending = self.pending.lock().unwrap();
+            let ids: Vec<String> = pending
+                .iter()
+                .filter(|(_, reservation)| reservation.expires_at <= now)
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.iter().filter_map(|id| pending.remove(id)).collect()
+        };
+        if !expired.is_empty() {
+            debug!("Releasing {} expired reservation(s)", expired.len());
+        }
+        for reservation in expired {
+            if let Err(e) = self.give_back(reservation).await {
+                error!("Failed to release an expired reservation: {:?}", e);
+            }
+        }
+    }
+
+    fn take(&self, id: &str) -> Option<Reservation> {
+        let mut pending = self.pending.lock().unwrap();
+        // Expired, it's as good as released, whether the sweep got to it yet
+        // or not
+        if pending.get(id)?.expires_at <= Instant::now() {
+            return None;
+        }
+        pending.remove(id)
+    }
+
+    async fn give_back(&self, reservation: Reservation) -> Result<(), LimitadorError> {
+        for (values, hits) in &reservation.descriptors {
+            self.limiter
+                .update_counters(&reservation.namespace, values, -hits)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use limitador::limit::Limit;
+    use limitador::RateLimiter;
+
+    fn reservations(timeout: Duration) -> (Arc<Limiter>, Reservations) {
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(Limit::new("ns", 2, 60, vec!["x == '1'"], vec!["z"]));
+        let limiter = Arc::new(Limiter::Blocking(limiter));
+        let reservations = Reservations::new(Arc::clone(&limiter), timeout);
+        (limiter, reservations)
+    }
+
+    async fn reserve_one(limiter: &Limiter, reservations: &Reservations) -> String {
+        let namespace: Namespace = "ns".into();
+        let values = HashMap::from([
+            ("x".to_string(), "1".to_string()),
+            ("z".to_string(), "1".to_string()),
+        ]);
+        let checked = limiter
+            .check_rate_limited_and_update(&namespace, &values, 1, false)
+            .await
+            .unwrap();
+        assert!(!checked.limited);
+        reservations.reserve(&namespace, vec![(values, 1)], Duration::from_secs(60))
+    }
+
+    async fn is_limited(limiter: &Limiter) -> bool {
+        let values = HashMap::from([
+            ("x".to_string(), "1".to_string()),
+            ("z".to_string(), "1".to_string()),
+        ]);
+        limiter
+            .is_rate_limited(&"ns".into(), &values, 1)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn released_hits_are_given_back() {
+        let (limiter, reservations) = reservations(Duration::from_secs(60));
+        let confirmed = reserve_one(&limiter, &reservations).await;
+        let released = reserve_one(&limiter, &reservations).await;
+        assert_ne!(confirmed, released);
+        assert!(is_limited(&limiter).await);
+
+        assert!(reservations.confirm(&confirmed));
+        assert!(!reservations.confirm(&confirmed));
+        assert!(reservations.release(&released).await.unwrap());
+        assert!(!reservations.release(&released).await.unwrap());
+        assert!(!is_limited(&limiter).await);
+    }
+
+    #[tokio::test]
+    async fn expired_reservations_are_released() {
+        let (limiter, reservations) = reservations(Duration::ZERO);
+        let id = reserve_one(&limiter, &reservations).await;
+        reserve_one(&limiter, &reservations).await;
+        assert!(is_limited(&limiter).await);
+
+        assert!(!reservations.confirm(&id));
+        reservations.release_expired().await;
+        assert!(!is_limited(&limiter).await);
+        assert!(reservations.pending.lock().unwrap().is_empty());
+    }
+}
@@ -1,26 +1,64 @@
-use criterion::{black_box, criterion_group, criterion_main, Bencher, BenchmarkId, Criterion};
+use criterion::{
+    black_box, criterion_group, criterion_main, Bencher, BenchmarkId, Criterion, Throughput,
+};
 use rand::seq::SliceRandom;
 
+use limitador::counter::Counter;
 use limitador::limit::Limit;
 #[cfg(feature = "disk_storage")]
 use limitador::storage::disk::{DiskStorage, OptimizeFor};
 use limitador::storage::in_memory::InMemoryStorage;
+#[cfg(feature = "redis_storage")]
+use limitador::storage::AsyncCounterStorage;
 use limitador::storage::CounterStorage;
+#[cfg(feature = "redis_storage")]
+use limitador::AsyncRateLimiter;
 use limitador::RateLimiter;
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::hash::BuildHasher;
 
 const SEED: u64 = 42;
 
 #[cfg(all(not(feature = "disk_storage"), not(feature = "redis_storage")))]
-criterion_group!(benches, bench_in_mem);
+criterion_group!(
+    benches,
+    bench_conditions,
+    bench_counter_keys,
+    bench_in_mem,
+    bench_in_mem_mixed
+);
 #[cfg(all(feature = "disk_storage", not(feature = "redis_storage")))]
-criterion_group!(benches, bench_in_mem, bench_disk);
+criterion_group!(
+    benches,
+    bench_conditions,
+    bench_counter_keys,
+    bench_in_mem,
+    bench_in_mem_mixed,
+    bench_disk
+);
 #[cfg(all(not(feature = "disk_storage"), feature = "redis_storage"))]
-criterion_group!(benches, bench_in_mem, bench_redis);
+criterion_group!(
+    benches,
+    bench_conditions,
+    bench_counter_keys,
+    bench_in_mem,
+    bench_in_mem_mixed,
+    bench_redis,
+    bench_async_redis_throughput
+);
 #[cfg(all(feature = "disk_storage", feature = "redis_storage"))]
-criterion_group!(benches, bench_in_mem, bench_disk, bench_redis);
+criterion_group!(
+    benches,
+    bench_conditions,
+    bench_counter_keys,
+    bench_in_mem,
+    bench_in_mem_mixed,
+    bench_disk,
+    bench_redis,
+    bench_async_redis_throughput
+);
 
 criterion_main!(benches);
 
@@ -53,6 +91,39 @@ const TEST_SCENARIOS: &[&TestScenario] = &[
     },
 ];
 
+// Most limits of a namespace don't apply to any one request, as they're for
+// other routes, methods, etc. and the variables of the ones that do take many
+// values, e.g. one per user, so that the requests go to as many counters
+#[derive(Debug, Clone)]
+struct MixedScenario {
+    n_limits: u32,
+    n_matching_limits: u32,
+    n_distinct_values: u32,
+}
+
+const MIXED_SCENARIOS: &[&MixedScenario] = &[
+    &MixedScenario {
+        n_limits: 10,
+        n_matching_limits: 2,
+        n_distinct_values: 1,
+    },
+    &MixedScenario {
+        n_limits: 10,
+        n_matching_limits: 2,
+        n_distinct_values: 10_000,
+    },
+    &MixedScenario {
+        n_limits: 100,
+        n_matching_limits: 5,
+        n_distinct_values: 10_000,
+    },
+    &MixedScenario {
+        n_limits: 100,
+        n_matching_limits: 5,
+        n_distinct_values: 1_000_000,
+    },
+];
+
 struct TestCallParams {
     namespace: String,
     values: HashMap<String, String>,
@@ -69,6 +140,66 @@ impl Display for TestScenario {
     }
 }
 
+impl Display for MixedScenario {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} limits of which {} match, over {} distinct values",
+            self.n_limits, self.n_matching_limits, self.n_distinct_values
+        )
+    }
+}
+
+// Conditions are evaluated for every limit of the namespace on every request
+fn bench_conditions(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Conditions");
+    let path = "/api/v1/users/42/orders";
+    for (name, condition) in [
+        ("equality", "req.path == '/api/v1/users/42/orders'"),
+        ("glob_prefix", "req.path =~ '/api/v1/*'"),
+        ("glob_suffix", "req.path =~ '*/orders'"),
+        ("glob_backtracking", "req.path =~ '/*/*s/*/o*s'"),
+        ("glob_mismatch", "req.path =~ '/api/v2/*'"),
+        ("regex_prefix", "req.path ~= '^/api/v[0-9]+/'"),
+        (
+            "regex_full",
+            r"req.path ~= '^/api/v\d+/users/(\d+)/orders$'",
+        ),
+        ("regex_mismatch", "req.path ~= '^/api/v[2-9]/'"),
+    ] {
+        let limit = Limit::new("ns", 10, 60, vec![condition], Vec::<String>::default());
+        let values = HashMap::from([("req.path".to_string(), path.to_string())]);
+        group.bench_function(BenchmarkId::new("applies", name), |b: &mut Bencher| {
+            b.iter(|| black_box(limit.applies(black_box(&values))))
+        });
+    }
+    group.finish();
+}
+
+// The counters of the limits that apply are qualified by the values of their
+// variables on every request, and looked up by their hash in memory
+fn bench_counter_keys(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Counter keys");
+    let hasher = std::collections::hash_map::RandomState::new();
+    for n_vars in [1, 10] {
+        let variables: Vec<String> = (0..n_vars).map(|idx| format!("var_{idx}")).collect();
+        let limit = Limit::new("ns", 10, 60, Vec::<String>::default(), variables.clone());
+        let mut values: HashMap<String, String> = variables
+            .into_iter()
+            .map(|var| (var, "some_value".to_string()))
+            .collect();
+        values.insert("unrelated".to_string(), "other_value".to_string());
+        group.bench_function(BenchmarkId::new("new", n_vars), |b: &mut Bencher| {
+            b.iter(|| black_box(Counter::new(limit.clone(), values.clone())))
+        });
+        let counter = Counter::new(limit.clone(), values.clone());
+        group.bench_function(BenchmarkId::new("hash", n_vars), |b: &mut Bencher| {
+            b.iter(|| black_box(hasher.hash_one(black_box(&counter))))
+        });
+    }
+    group.finish();
+}
+
 fn bench_in_mem(c: &mut Criterion) {
     let mut group = c.benchmark_group("In memory");
     for scenario in TEST_SCENARIOS {
@@ -100,6 +231,39 @@ fn bench_in_mem(c: &mut Criterion) {
     group.finish();
 }
 
+fn bench_in_mem_mixed(c: &mut Criterion) {
+    let mut group = c.benchmark_group("In memory, mixed limits");
+    for scenario in MIXED_SCENARIOS {
+        group.bench_with_input(
+            BenchmarkId::new("check_rate_limited_and_update", scenario),
+            scenario,
+            |b: &mut Bencher, scenario: &&MixedScenario| {
+                let rate_limiter = RateLimiter::new_with_storage(Box::<InMemoryStorage>::default());
+                for limit in generate_mixed_limits(scenario) {
+                    rate_limiter.add_limit(limit);
+                }
+                let namespace = "ns".into();
+                let rng = &mut rand::rngs::StdRng::seed_from_u64(SEED);
+                b.iter(|| {
+                    let values = HashMap::from([
+                        ("req.method".to_string(), "GET".to_string()),
+                        (
+                            "user_id".to_string(),
+                            rng.gen_range(0..scenario.n_distinct_values).to_string(),
+                        ),
+                    ]);
+                    black_box(
+                        rate_limiter
+                            .check_rate_limited_and_update(&namespace, &values, 1, false)
+                            .unwrap(),
+                    )
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
 #[cfg(feature = "disk_storage")]
 fn bench_disk(c: &mut Criterion) {
     let mut group = c.benchmark_group("Disk");
@@ -170,6 +334,60 @@ fn bench_redis(c: &mut Criterion) {
     group.finish();
 }
 
+// How many checks are made concurrently on each iteration of the throughput
+// benchmarks
+#[cfg(feature = "redis_storage")]
+const CONCURRENT_CHECKS: u64 = 100;
+
+// Concurrent checks are what the pool of connections and the pipelining of the
+// checks are meant for, so they are compared to the single connection under
+// those
+#[cfg(feature = "redis_storage")]
+fn bench_async_redis_throughput(c: &mut Criterion) {
+    use limitador::storage::redis::AsyncRedisStorageBuilder;
+    use std::time::Duration;
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("Async Redis throughput");
+    group.throughput(Throughput::Elements(CONCURRENT_CHECKS));
+    type Builder = fn() -> AsyncRedisStorageBuilder;
+    let builders: [(&str, Builder); 4] = [
+        ("single_connection", || {
+            AsyncRedisStorageBuilder::new("redis://127.0.0.1:6379")
+        }),
+        ("pooled", || {
+            AsyncRedisStorageBuilder::new("redis://127.0.0.1:6379").pool_size(10)
+        }),
+        ("pipelined", || {
+            AsyncRedisStorageBuilder::new("redis://127.0.0.1:6379")
+                .pipelining(100, Duration::from_micros(200))
+        }),
+        ("pooled_and_pipelined", || {
+            AsyncRedisStorageBuilder::new("redis://127.0.0.1:6379")
+                .pool_size(10)
+                .pipelining(100, Duration::from_micros(200))
+        }),
+    ];
+    for scenario in TEST_SCENARIOS {
+        for (name, builder) in builders {
+            group.bench_with_input(
+                BenchmarkId::new(name, scenario),
+                scenario,
+                |b: &mut Bencher, test_scenario: &&TestScenario| {
+                    let storage = runtime.block_on(builder().build()).unwrap();
+                    bench_concurrent_check_rate_limited_and_update(
+                        b,
+                        &runtime,
+                        test_scenario,
+                        Box::new(storage),
+                    );
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
 fn bench_is_rate_limited(
     b: &mut Bencher,
     test_scenario: &TestScenario,
@@ -243,6 +461,54 @@ fn bench_check_rate_limited_and_update(
     })
 }
 
+#[cfg(feature = "redis_storage")]
+fn bench_concurrent_check_rate_limited_and_update(
+    b: &mut Bencher,
+    runtime: &tokio::runtime::Runtime,
+    test_scenario: &TestScenario,
+    storage: Box<dyn AsyncCounterStorage>,
+) {
+    use std::sync::Arc;
+
+    runtime.block_on(storage.clear()).unwrap();
+    let (test_limits, call_params) = generate_test_limits(test_scenario);
+    let rate_limiter = AsyncRateLimiter::new_with_storage(storage);
+    for limit in test_limits {
+        rate_limiter.add_limit(limit);
+    }
+    let rate_limiter = Arc::new(rate_limiter);
+    let call_params = Arc::new(call_params);
+
+    let rng = &mut rand::rngs::StdRng::seed_from_u64(SEED);
+    b.iter(|| {
+        let mut checks = tokio::task::JoinSet::new();
+        for _ in 0..CONCURRENT_CHECKS {
+            let rate_limiter = rate_limiter.clone();
+            let call_params = call_params.clone();
+            let params = call_params.choose(rng).unwrap();
+            let (namespace, values, delta) = (
+                params.namespace.to_owned().into(),
+                params.values.clone(),
+                params.delta,
+            );
+            checks.spawn_on(
+                async move {
+                    rate_limiter
+                        .check_rate_limited_and_update(&namespace, &values, delta, false)
+                        .await
+                        .unwrap()
+                },
+                runtime.handle(),
+            );
+        }
+        runtime.block_on(async {
+            while let Some(result) = checks.join_next().await {
+                black_box(result.unwrap());
+            }
+        })
+    })
+}
+
 // Notice that this function creates all the limits with the same conditions and
 // variables. Also, all the conditions have the same format: "cond_x == 1".
 // That's to simplify things, those are not the aspects that should have the
@@ -255,6 +521,40 @@ fn generate_test_data(
     scenario: &TestScenario,
     storage: Box<dyn CounterStorage>,
 ) -> (RateLimiter, Vec<TestCallParams>) {
+    let (test_limits, call_params) = generate_test_limits(scenario);
+
+    let rate_limiter = RateLimiter::new_with_storage(storage);
+
+    for limit in test_limits {
+        rate_limiter.add_limit(limit);
+    }
+
+    (rate_limiter, call_params)
+}
+
+// The limits that don't match have a condition on another method, and all of
+// them are by user, for as many counters as there are users for each limit that
+// matches
+fn generate_mixed_limits(scenario: &MixedScenario) -> Vec<Limit> {
+    (0..scenario.n_limits)
+        .map(|idx| {
+            let method = if idx < scenario.n_matching_limits {
+                "GET"
+            } else {
+                "POST"
+            };
+            Limit::new(
+                "ns",
+                i64::MAX,
+                ((idx * 60) + 10) as u64,
+                vec![format!("req.method == '{method}'")],
+                vec!["user_id"],
+            )
+        })
+        .collect()
+}
+
+fn generate_test_limits(scenario: &TestScenario) -> (Vec<Limit>, Vec<TestCallParams>) {
     let mut test_values: HashMap<String, String> = HashMap::new();
 
     let mut conditions = vec![];
@@ -294,11 +594,5 @@ fn generate_test_data(
         });
     }
 
-    let rate_limiter = RateLimiter::new_with_storage(storage);
-
-    for limit in test_limits {
-        rate_limiter.add_limit(limit);
-    }
-
-    (rate_limiter, call_params)
+    (test_limits, call_params)
 }
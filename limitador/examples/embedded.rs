@@ -0,0 +1,99 @@
+//! A service that rate-limits the requests it handles itself, embedding
+//! Limitador rather than calling its server.
+//!
+//! Run it with `cargo run --example embedded`, to keep the counters in memory,
+//! or with `cargo run --example embedded -- <dir>` to keep them on disk, in
+//! that directory, so that they survive restarts.
+
+use limitador::limit::{Limit, Namespace};
+use limitador::{CheckResult, RateLimiter};
+use std::collections::HashMap;
+
+const NAMESPACE: &str = "shop";
+
+struct Service {
+    limiter: RateLimiter,
+    namespace: Namespace,
+}
+
+impl Service {
+    fn new(limiter: RateLimiter) -> Self {
+        // Every user gets 3 orders a minute, and 5 requests of any kind
+        let mut per_user = Limit::new(NAMESPACE, 5, 60, Vec::<&str>::new(), vec!["user"]);
+        per_user.set_name("per-user".to_string());
+        let mut orders = Limit::new(NAMESPACE, 3, 60, vec!["method == 'POST'"], vec!["user"]);
+        orders.set_name("orders".to_string());
+
+        limiter
+            .configure_with([per_user, orders])
+            .expect("the limits to be stored");
+
+        Self {
+            limiter,
+            namespace: NAMESPACE.into(),
+        }
+    }
+
+    fn handle(&self, user: &str, method: &str) -> String {
+        let values = HashMap::from([
+            ("user".to_string(), user.to_string()),
+            ("method".to_string(), method.to_string()),
+        ]);
+
+        match self
+            .limiter
+            .check_rate_limited_and_update(&self.namespace, &values, 1, true)
+        {
+            Ok(result) if result.limited => too_many_requests(&result),
+            Ok(result) => format!("200 OK ({} left)", result.remaining().unwrap_or_default()),
+            // The counters can't be reached, let the request through
+            Err(err) => format!("200 OK (not limited: {err})"),
+        }
+    }
+}
+
+fn too_many_requests(result: &CheckResult) -> String {
+    let limit = result
+        .most_restrictive_counter()
+        .and_then(|counter| counter.limit().name())
+        .unwrap_or("unnamed");
+    match result.retry_after() {
+        Some(retry_after) => format!(
+            "429 Too Many Requests ({limit}, retry after {}s)",
+            retry_after.as_secs()
+        ),
+        None => format!("429 Too Many Requests ({limit})"),
+    }
+}
+
+fn limiter() -> RateLimiter {
+    match std::env::args().nth(1) {
+        #[cfg(feature = "disk_storage")]
+        Some(dir) => {
+            use limitador::storage::disk::{DiskStorage, OptimizeFor};
+            let storage = DiskStorage::open(dir, OptimizeFor::Throughput).expect("a disk storage");
+            RateLimiter::new_with_storage(Box::new(storage))
+        }
+        #[cfg(not(feature = "disk_storage"))]
+        Some(_) => panic!("storing the counters on disk needs the disk_storage feature"),
+        None => RateLimiter::new(10_000),
+    }
+}
+
+fn main() {
+    let service = Service::new(limiter());
+
+    let requests = [
+        ("alice", "POST"),
+        ("alice", "POST"),
+        ("alice", "POST"),
+        ("alice", "POST"),
+        ("alice", "GET"),
+        ("bob", "POST"),
+        ("alice", "GET"),
+        ("alice", "GET"),
+    ];
+    for (user, method) in requests {
+        println!("{user} {method}: {}", service.handle(user, method));
+    }
+}
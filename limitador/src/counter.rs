@@ -1,8 +1,8 @@
-use crate::limit::{Limit, Namespace};
+use crate::limit::{Limit, Namespace, WindowType};
 use serde::{Deserialize, Serialize, Serializer};
 use std::collections::{BTreeMap, HashMap};
 use std::hash::{Hash, Hasher};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 #[derive(Eq, Clone, Debug, Serialize, Deserialize)]
 pub struct Counter {
@@ -15,6 +15,46 @@ pub struct Counter {
 
     remaining: Option<i64>,
     expires_in: Option<Duration>,
+
+    // What each hit of the request counts for, as the limit's cost of it
+    #[serde(skip, default = "unit_cost")]
+    cost: i64,
+
+    // The value of the request for the limit's distinct variable, that the
+    // counters of Distinct limits count the distinct values of
+    #[serde(skip)]
+    distinct_value: Option<String>,
+
+    #[serde(skip)]
+    approximate: bool,
+}
+
+fn unit_cost() -> i64 {
+    1
+}
+
+// Unlike the hashers of std, FNV-1a is guaranteed to stay the same across
+// releases, and so across the instances of Limitador sharing a storage
+pub(crate) struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    // Each part is terminated, so that moving bytes between parts changes the hash
+    pub(crate) fn write(&mut self, part: &str) {
+        for byte in part.bytes().chain([0xff]) {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
 }
 
 fn ordered_map<S>(value: &HashMap<String, String>, serializer: S) -> Result<S::Ok, S::Error>
@@ -29,17 +69,38 @@ impl Counter {
     pub fn new(limit: Limit, set_variables: HashMap<String, String>) -> Self {
         // TODO: check that all the variables defined in the limit are set.
 
+        let cost = limit.cost().map_or(1, |cost| cost.of(&set_variables));
+        let distinct_value = limit
+            .distinct_variable()
+            .and_then(|var| set_variables.get(var).cloned());
         let mut vars = set_variables;
         vars.retain(|var, _| limit.has_variable(var));
 
         Self {
-            limit,
+            limit: limit.into_counter_limit(),
             set_variables: vars,
             remaining: None,
             expires_in: None,
+            cost,
+            distinct_value,
+            approximate: false,
         }
     }
 
+    /// The hits counted in this counter for a request of `delta` hits, i.e.
+    /// `delta` times the cost of the request for the limit. Storages count
+    /// these, rather than `delta`.
+    pub fn hits_for(&self, delta: i64) -> i64 {
+        delta.saturating_mul(self.cost)
+    }
+
+    /// The same counter, for requests that cost a single hit, e.g. to add
+    /// hits already weighed by their cost.
+    pub(crate) fn with_unit_cost(mut self) -> Self {
+        self.cost = 1;
+        self
+    }
+
     pub fn limit(&self) -> &Limit {
         &self.limit
     }
@@ -48,12 +109,35 @@ impl Counter {
         self.limit.max_value()
     }
 
+    /// Whether the hits of the counter, as loaded, are past the soft max
+    /// value of its limit, when it has one.
+    pub fn is_past_soft_max_value(&self) -> bool {
+        match (self.limit.soft_max_value(), self.remaining) {
+            (Some(soft_max_value), Some(remaining)) => {
+                self.max_value() - remaining > soft_max_value
+            }
+            _ => false,
+        }
+    }
+
     pub fn update_to_limit(&mut self, limit: &Limit) -> bool {
-        if limit == &self.limit {
-            self.limit.set_max_value(limit.max_value());
+        let same_counters = match limit.shared_counter() {
+            Some(_) => limit.counter_limit() == self.limit,
+            None => limit == &self.limit,
+        };
+        if same_counters {
+            // Looked up again, should the entry be one of the variables
+            self.limit
+                .set_max_value(limit.max_value_for(&self.set_variables));
             if let Some(name) = limit.name() {
                 self.limit.set_name(name.to_string());
             }
+            self.limit.set_soft_max_value(limit.soft_max_value());
+            self.limit.set_priority(limit.priority());
+            self.limit.set_cost(limit.cost().cloned());
+            self.limit.set_max_values(limit.max_values().cloned());
+            self.limit.set_jitter(limit.jitter());
+            self.limit.set_group(limit.group().map(str::to_string));
             return true;
         }
         false
@@ -63,6 +147,47 @@ impl Counter {
         self.limit.seconds()
     }
 
+    /// See [`Limit::window`]
+    pub fn window(&self) -> Duration {
+        self.limit.window()
+    }
+
+    pub fn window_type(&self) -> WindowType {
+        self.limit.window_type()
+    }
+
+    /// How long the fixed window of this counter lasts when it starts at
+    /// `now`: the one of its limit, pushed back by the counter's share of the
+    /// limit's jitter, if any.
+    pub fn window_at(&self, now: SystemTime) -> Duration {
+        self.limit.window_at(now).saturating_add(self.jitter())
+    }
+
+    // Picked by a hash of what identifies the counter, rather than randomly,
+    // so that it's the same wherever and whenever it's computed
+    fn jitter(&self) -> Duration {
+        let max_millis = match self.limit.jitter() {
+            Some(jitter) if jitter > 0 => jitter.saturating_mul(1000),
+            _ => return Duration::ZERO,
+        };
+        let mut conditions: Vec<String> = self.limit.conditions().into_iter().collect();
+        conditions.sort();
+        let mut variables: Vec<(&String, &String)> = self.set_variables.iter().collect();
+        variables.sort();
+
+        let mut hash = Fnv1a::default();
+        hash.write(self.namespace().as_ref());
+        hash.write(&self.seconds().to_string());
+        for condition in &conditions {
+            hash.write(condition);
+        }
+        for (var, value) in variables {
+            hash.write(var);
+            hash.write(value);
+        }
+        Duration::from_millis(hash.finish() % max_millis.saturating_add(1))
+    }
+
     pub fn namespace(&self) -> &Namespace {
         self.limit.namespace()
     }
@@ -71,6 +196,12 @@ impl Counter {
         &self.set_variables
     }
 
+    /// The value counted by this counter of a [`WindowType::Distinct`] limit,
+    /// if the request had one. It doesn't identify the counter.
+    pub fn distinct_value(&self) -> Option<&str> {
+        self.distinct_value.as_deref()
+    }
+
     pub fn remaining(&self) -> Option<i64> {
         self.remaining
     }
@@ -87,6 +218,17 @@ impl Counter {
         self.expires_in = Some(duration)
     }
 
+    /// Whether the storage checked this counter against a copy of its hits
+    /// that might be behind, e.g. cached, rather than against the hits it
+    /// keeps.
+    pub fn is_approximate(&self) -> bool {
+        self.approximate
+    }
+
+    pub fn set_approximate(&mut self) {
+        self.approximate = true
+    }
+
     pub fn is_qualified(&self) -> bool {
         !self.set_variables.is_empty()
     }
@@ -118,3 +260,33 @@ impl PartialEq for Counter {
         self.limit == other.limit && self.set_variables == other.set_variables
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Counter;
+    use crate::limit::Limit;
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn jitters_the_window_within_the_jitter_of_its_limit() {
+        let mut limit = Limit::new("ns", 10, 60, vec!["x == '1'"], vec!["y"]);
+        limit.set_jitter(Some(5));
+        let counter = Counter::new(limit, HashMap::from([("y".to_string(), "1".to_string())]));
+
+        let window = counter.window_at(SystemTime::now());
+        assert!(window >= Duration::from_secs(60));
+        assert!(window <= Duration::from_secs(65));
+    }
+
+    #[test]
+    fn jitters_the_window_by_a_huge_jitter_without_overflowing() {
+        let mut limit = Limit::new("ns", 10, 60, vec!["x == '1'"], vec!["y"]);
+        limit.set_jitter(Some(u64::MAX));
+        let counter = Counter::new(limit, HashMap::from([("y".to_string(), "1".to_string())]));
+
+        let window = counter.window_at(SystemTime::now());
+        assert!(window >= Duration::from_secs(60));
+        assert!(window <= Duration::from_secs(60) + Duration::from_millis(u64::MAX));
+    }
+}
@@ -1,14 +1,41 @@
-use crate::storage::StorageErr;
+use crate::storage::{StorageErr, StorageErrKind};
 use thiserror::Error;
 
 #[derive(Error, Debug, Eq, PartialEq)]
 pub enum LimitadorError {
+    /// Any error of the storage that isn't of the kinds below.
     #[error("error while accessing the limits storage: {0:?}")]
     Storage(String),
+    /// The storage couldn't be reached, or didn't answer in time. Likely to be
+    /// temporary, so worth retrying.
+    #[error("error while accessing the limits storage: {0:?}")]
+    StorageConnection(String),
+    /// What the storage holds, or was sent, couldn't be read back.
+    #[error("error while accessing the limits storage: {0:?}")]
+    Serialization(String),
+    /// The storage can't enforce the limits as configured, e.g. a window it
+    /// doesn't support. Retrying won't help.
+    #[error("error while accessing the limits storage: {0:?}")]
+    Configuration(String),
+    #[error("invalid counter: {0}")]
+    InvalidCounter(String),
+}
+
+impl LimitadorError {
+    /// Whether the error comes from the storage, whatever its kind.
+    pub fn is_storage(&self) -> bool {
+        !matches!(self, Self::InvalidCounter(_))
+    }
 }
 
 impl From<StorageErr> for LimitadorError {
     fn from(e: StorageErr) -> Self {
-        Self::Storage(e.msg().to_owned())
+        let msg = e.msg().to_owned();
+        match e.kind() {
+            StorageErrKind::Connection => Self::StorageConnection(msg),
+            StorageErrKind::Serialization => Self::Serialization(msg),
+            StorageErrKind::Configuration => Self::Configuration(msg),
+            StorageErrKind::Other => Self::Storage(msg),
+        }
     }
 }
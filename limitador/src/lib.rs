@@ -180,6 +180,54 @@
 //! # }
 //! ```
 //!
+//! # Embedding
+//!
+//! Limitador doesn't need its server: a service can keep a limiter of its own
+//! and check every request it handles against it. The storage of the counters
+//! is chosen when constructing the limiter:
+//! - [`RateLimiter::new`] keeps them in memory, for a single instance.
+//! - `storage::disk::DiskStorage`, with the `disk_storage` feature, keeps them
+//!   on disk, so that they survive restarts.
+//! - `storage::redis::RedisStorage`, or `storage::redis::AsyncRedisStorage`
+//!   for an [`AsyncRateLimiter`], with the `redis_storage` feature, keeps them
+//!   in Redis, so that several instances share them.
+//!
+//! From there, [`RateLimiter::check_rate_limited_and_update`] is all a request
+//! needs. The [`CheckResult`] tells whether it's limited and, when its counters
+//! are loaded, how many hits remain and how long until it could be let through.
+//!
+//! ```
+//! use limitador::RateLimiter;
+//! use limitador::limit::Limit;
+//! use std::collections::HashMap;
+//!
+//! let rate_limiter = RateLimiter::new(1000);
+//! rate_limiter.add_limit(Limit::new(
+//!     "my_namespace",
+//!     1,
+//!     60,
+//!     vec!["req.method == 'GET'"],
+//!     vec!["user_id"],
+//! ));
+//!
+//! let namespace = "my_namespace".into();
+//! let values = HashMap::from([
+//!     ("req.method".to_string(), "GET".to_string()),
+//!     ("user_id".to_string(), "1".to_string()),
+//! ]);
+//!
+//! let result = rate_limiter.check_rate_limited_and_update(&namespace, &values, 1, true).unwrap();
+//! assert!(!result.limited);
+//! assert_eq!(result.remaining(), Some(0));
+//!
+//! let result = rate_limiter.check_rate_limited_and_update(&namespace, &values, 1, true).unwrap();
+//! assert!(result.limited);
+//! assert!(result.retry_after().is_some());
+//! ```
+//!
+//! The `embedded` example is such a service, run it with
+//! `cargo run --example embedded`.
+//!
 //! # Limits accuracy
 //!
 //! When storing the counters in memory, Limitador guarantees that we'll never go
@@ -192,11 +240,13 @@
 // TODO this needs review to reduce the bloat pulled in by dependencies
 #![allow(clippy::multiple_crate_versions)]
 
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
 
 use crate::counter::Counter;
 use crate::errors::LimitadorError;
-use crate::limit::{Limit, Namespace};
+use crate::limit::{Limit, Namespace, WindowType};
 use crate::prometheus_metrics::PrometheusMetrics;
 use crate::storage::in_memory::InMemoryStorage;
 use crate::storage::{AsyncCounterStorage, AsyncStorage, Authorization, CounterStorage, Storage};
@@ -211,24 +261,110 @@ pub mod limit;
 mod prometheus_metrics;
 pub mod storage;
 
+/// A rate limiter that keeps its limits in memory, and their counters in a
+/// blocking storage, [`InMemoryStorage`] by default. It can be shared
+/// between threads, all of its functions taking `&self`.
 pub struct RateLimiter {
     storage: Storage,
     prometheus_metrics: PrometheusMetrics,
 }
 
+/// Same as [`RateLimiter`], but with the counters kept in an async storage,
+/// e.g. [`storage::redis::AsyncRedisStorage`], hence the async functions.
 pub struct AsyncRateLimiter {
     storage: AsyncStorage,
     prometheus_metrics: PrometheusMetrics,
 }
 
+/// Builds a [`RateLimiter`] with the options that
+/// [`RateLimiter::new`] and [`RateLimiter::new_with_storage`] don't take.
 pub struct RateLimiterBuilder {
     storage: Storage,
     prometheus_limit_name_labels_enabled: bool,
 }
 
+/// A request of a batch, along with the max value its counters are checked
+/// against instead of the ones of their limits, if any.
+pub type OverridingCheck<'a> = (&'a Namespace, &'a HashMap<String, String>, i64, Option<i64>);
+
+/// The outcome of checking a request against the limits that apply to it.
 pub struct CheckResult {
+    /// Whether the request is over one of the limits, in which case none of
+    /// its hits were counted.
     pub limited: bool,
+    /// The counters of the limits that apply, with their hits as of the
+    /// check, when loaded with `load_counters`. Empty otherwise.
     pub counters: Vec<Counter>,
+    /// Whether the decision was made on approximate counters, i.e. ones the
+    /// storage checked against a copy of their hits that might be behind,
+    /// rather than against the hits it keeps. See
+    /// [`Counter::is_approximate`].
+    pub approximate: bool,
+    /// Whether the request, let through, took one of the counters past the
+    /// soft max value of its limit. See [`Limit::soft_max_value`].
+    pub soft_limited: bool,
+}
+
+/// Where a request stands against the limits that apply to it.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CheckOutcome {
+    /// Within all of them.
+    Ok,
+    /// Let through, but past the soft max value of one of them.
+    SoftLimited,
+    /// Over one of them, so not let through.
+    Limited,
+}
+
+impl CheckResult {
+    /// Tells whether the request is within its limits, let through past the
+    /// soft max value of one of them, or limited.
+    pub fn outcome(&self) -> CheckOutcome {
+        if self.limited {
+            CheckOutcome::Limited
+        } else if self.soft_limited {
+            CheckOutcome::SoftLimited
+        } else {
+            CheckOutcome::Ok
+        }
+    }
+
+    /// Returns the counter that is the closest to being limited, i.e. the one
+    /// with the least hits remaining among those of the limits of the highest
+    /// priority. When limited, only the counters over their limit are
+    /// considered. The counters are only loaded when requested with
+    /// `load_counters`, otherwise this returns `None`.
+    pub fn most_restrictive_counter(&self) -> Option<&Counter> {
+        self.counters.iter().min_by_key(|counter| {
+            let remaining = counter.remaining().unwrap_or(counter.max_value());
+            (
+                self.limited && remaining >= 0,
+                Reverse(counter.limit().priority()),
+                remaining,
+            )
+        })
+    }
+
+    /// The hits remaining of the most restrictive counter, `None` if no
+    /// counters were loaded.
+    pub fn remaining(&self) -> Option<i64> {
+        self.most_restrictive_counter()
+            .map(|counter| counter.remaining().unwrap_or(counter.max_value()))
+    }
+
+    /// How long until all the counters that are over their limit reset, i.e.
+    /// when the request could be let through again. `None` unless limited and
+    /// the counters were loaded.
+    pub fn retry_after(&self) -> Option<Duration> {
+        if !self.limited {
+            return None;
+        }
+        self.counters
+            .iter()
+            .filter(|counter| matches!(counter.remaining(), Some(remaining) if remaining < 0))
+            .filter_map(|counter| counter.expires_in())
+            .max()
+    }
 }
 
 impl From<CheckResult> for bool {
@@ -238,6 +374,8 @@ impl From<CheckResult> for bool {
 }
 
 impl RateLimiterBuilder {
+    /// Starts from the storage given, e.g. one of
+    /// [`Storage::with_counter_storage`].
     pub fn with_storage(storage: Storage) -> Self {
         Self {
             storage,
@@ -245,6 +383,7 @@ impl RateLimiterBuilder {
         }
     }
 
+    /// Starts from an in-memory storage of at most `cache_size` counters.
     pub fn new(cache_size: u64) -> Self {
         Self {
             storage: Storage::new(cache_size),
@@ -257,6 +396,7 @@ impl RateLimiterBuilder {
         self
     }
 
+    /// Labels the metrics of the limited calls with the name of the limit.
     pub fn with_prometheus_limit_name_labels(mut self) -> Self {
         self.prometheus_limit_name_labels_enabled = true;
         self
@@ -276,6 +416,7 @@ impl RateLimiterBuilder {
     }
 }
 
+/// Builds an [`AsyncRateLimiter`], see [`RateLimiterBuilder`].
 pub struct AsyncRateLimiterBuilder {
     storage: AsyncStorage,
     prometheus_limit_name_labels_enabled: bool,
@@ -289,6 +430,7 @@ impl AsyncRateLimiterBuilder {
         }
     }
 
+    /// Labels the metrics of the limited calls with the name of the limit.
     pub fn with_prometheus_limit_name_labels(mut self) -> Self {
         self.prometheus_limit_name_labels_enabled = true;
         self
@@ -309,6 +451,8 @@ impl AsyncRateLimiterBuilder {
 }
 
 impl RateLimiter {
+    /// A rate limiter that keeps at most `cache_size` counters in memory,
+    /// evicting the least used ones when full.
     pub fn new(cache_size: u64) -> Self {
         Self {
             storage: Storage::new(cache_size),
@@ -316,6 +460,8 @@ impl RateLimiter {
         }
     }
 
+    /// A rate limiter that keeps its counters in the storage given, e.g.
+    /// [`storage::disk::DiskStorage`] or [`storage::redis::RedisStorage`].
     pub fn new_with_storage(counters: Box<dyn CounterStorage>) -> Self {
         Self {
             storage: Storage::with_counter_storage(counters),
@@ -327,6 +473,8 @@ impl RateLimiter {
         self.storage.get_namespaces()
     }
 
+    /// Adds the limit, unless it exists already or another limit of its
+    /// namespace has the same name. Returns whether it was added.
     pub fn add_limit(&self, limit: Limit) -> bool {
         self.storage.add_limit(limit)
     }
@@ -336,27 +484,100 @@ impl RateLimiter {
         Ok(())
     }
 
+    /// Deletes the limit of the namespace that has the name given, along with
+    /// its counters. Returns whether there was such a limit.
+    pub fn delete_limit_by_name(
+        &self,
+        namespace: &Namespace,
+        name: &str,
+    ) -> Result<bool, LimitadorError> {
+        match self.get_limit_by_name(namespace, name) {
+            Some(limit) => {
+                self.delete_limit(&limit)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
     pub fn get_limits(&self, namespace: &Namespace) -> HashSet<Limit> {
         self.storage.get_limits(namespace)
     }
 
+    pub fn get_limit_by_name(&self, namespace: &Namespace, name: &str) -> Option<Limit> {
+        self.get_limits(namespace)
+            .into_iter()
+            .find(|limit| limit.name() == Some(name))
+    }
+
+    /// Enables or disables the limit of the namespace that has the name
+    /// given, keeping its counters either way, see [`Limit::is_enabled`].
+    /// Returns whether there was such a limit.
+    pub fn set_limit_enabled_by_name(
+        &self,
+        namespace: &Namespace,
+        name: &str,
+        enabled: bool,
+    ) -> bool {
+        match self.get_limit_by_name(namespace, name) {
+            Some(mut limit) => {
+                limit.set_enabled(enabled);
+                self.storage.update_limit(&limit);
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn delete_limits(&self, namespace: &Namespace) -> Result<(), LimitadorError> {
         self.storage.delete_limits(namespace)?;
         Ok(())
     }
 
+    /// Errs when the storage of the counters can't be reached.
+    pub fn ping(&self) -> Result<(), LimitadorError> {
+        self.storage.ping()?;
+        Ok(())
+    }
+
+    /// Checks and updates a counter of its own, as a request would, so as to
+    /// err unless the storage of the counters can actually be used, not only
+    /// reached. The counter is of a namespace of its own, `limitador.warm_up`.
+    pub fn warm_up(&self) -> Result<(), LimitadorError> {
+        self.storage.warm_up()?;
+        Ok(())
+    }
+
+    /// Whether a request of `delta` hits, described by the `values` its
+    /// limits are evaluated against, would be over one of the limits of the
+    /// namespace. Counts nothing, see [`RateLimiter::update_counters`].
     pub fn is_rate_limited(
         &self,
         namespace: &Namespace,
         values: &HashMap<String, String>,
         delta: i64,
     ) -> Result<bool, LimitadorError> {
-        let counters = self.counters_that_apply(namespace, values)?;
+        self.is_rate_limited_overriding(namespace, values, delta, None)
+    }
+
+    /// Same as [`RateLimiter::is_rate_limited`], but checks the counters of the
+    /// limits that apply against `max_value`, when given, instead of their own.
+    pub fn is_rate_limited_overriding(
+        &self,
+        namespace: &Namespace,
+        values: &HashMap<String, String>,
+        delta: i64,
+        max_value: Option<i64>,
+    ) -> Result<bool, LimitadorError> {
+        let counters = self.counters_that_apply(namespace, values, max_value)?;
 
         for counter in counters {
             match self.storage.is_within_limits(&counter, delta) {
                 Ok(within_limits) => {
-                    if !within_limits {
+                    if !within_limits && counter.limit().is_shadow() {
+                        self.prometheus_metrics
+                            .incr_shadow_limited_calls(namespace, counter.limit().name());
+                    } else if !within_limits {
                         self.prometheus_metrics
                             .incr_limited_calls(namespace, counter.limit().name());
                         return Ok(true);
@@ -370,13 +591,15 @@ impl RateLimiter {
         Ok(false)
     }
 
+    /// Counts the `delta` hits of a request in the counters of the limits
+    /// that apply to it, whether that puts them over their limit or not.
     pub fn update_counters(
         &self,
         namespace: &Namespace,
         values: &HashMap<String, String>,
         delta: i64,
     ) -> Result<(), LimitadorError> {
-        let counters = self.counters_that_apply(namespace, values)?;
+        let counters = self.counters_that_apply(namespace, values, None)?;
 
         counters
             .iter()
@@ -384,6 +607,10 @@ impl RateLimiter {
             .map_err(|err| err.into())
     }
 
+    /// Checks a request against the limits of the namespace and, unless it's
+    /// limited, counts its `delta` hits, all at once. This is what a service
+    /// calls for each request it handles. The counters are only loaded in the
+    /// result when `load_counters`, e.g. to tell how many hits remain.
     pub fn check_rate_limited_and_update(
         &self,
         namespace: &Namespace,
@@ -391,43 +618,96 @@ impl RateLimiter {
         delta: i64,
         load_counters: bool,
     ) -> Result<CheckResult, LimitadorError> {
-        let mut counters = self.counters_that_apply(namespace, values)?;
+        let (mut counters, shadow_counters) =
+            split_shadow_counters(self.counters_that_apply(namespace, values, None)?);
 
-        if counters.is_empty() {
-            self.prometheus_metrics.incr_authorized_calls(namespace);
-            return Ok(CheckResult {
-                limited: false,
-                counters,
-            });
+        let check_result = if counters.is_empty() {
+            Authorization::Ok
+        } else {
+            let loaded = loads_counters(&counters, load_counters);
+            self.storage
+                .check_and_update(&mut counters, delta, loaded)?
+        };
+        if let Authorization::Ok = check_result {
+            self.count_shadow_hits(namespace, &shadow_counters, delta)?;
         }
 
-        let check_result = self
-            .storage
-            .check_and_update(&mut counters, delta, load_counters)?;
+        Ok(to_check_result(
+            &self.prometheus_metrics,
+            namespace,
+            counters,
+            check_result,
+            load_counters,
+        ))
+    }
 
-        let counters = if load_counters {
-            counters
-        } else {
-            Vec::default()
-        };
+    /// Checks and updates the counters of several requests at once, possibly
+    /// across namespaces, so that the storage can do so in fewer round trips.
+    /// The results are returned in the same order as the requests.
+    ///
+    /// Each request is authorized or limited on its own: the hits of a request
+    /// that is limited are not counted, while the ones of the others are. When
+    /// the storage fails, an error is returned for the whole batch, even though
+    /// some of the requests might have been counted already. Also, depending on
+    /// the storage, requests that share counters might all be checked against
+    /// their values from before the batch.
+    pub fn check_rate_limited_and_update_batch(
+        &self,
+        batch: &[(&Namespace, &HashMap<String, String>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<CheckResult>, LimitadorError> {
+        let batch: Vec<_> = batch
+            .iter()
+            .map(|(namespace, values, delta)| (*namespace, *values, *delta, None))
+            .collect();
+        self.check_rate_limited_and_update_batch_overriding(&batch, load_counters)
+    }
 
-        match check_result {
-            Authorization::Ok => {
-                self.prometheus_metrics.incr_authorized_calls(namespace);
-                Ok(CheckResult {
-                    limited: false,
-                    counters,
-                })
-            }
-            Authorization::Limited(name) => {
-                self.prometheus_metrics
-                    .incr_limited_calls(namespace, name.as_deref());
-                Ok(CheckResult {
-                    limited: true,
-                    counters,
-                })
+    /// Same as [`RateLimiter::check_rate_limited_and_update_batch`], but the
+    /// counters of each request are checked against its max value, when given,
+    /// instead of the ones of their limits.
+    pub fn check_rate_limited_and_update_batch_overriding(
+        &self,
+        batch: &[OverridingCheck<'_>],
+        load_counters: bool,
+    ) -> Result<Vec<CheckResult>, LimitadorError> {
+        let mut counters = Vec::with_capacity(batch.len());
+        let mut shadow_counters = Vec::with_capacity(batch.len());
+        for (namespace, values, delta, max_value) in batch {
+            let (enforced, shadow) =
+                split_shadow_counters(self.counters_that_apply(namespace, values, *max_value)?);
+            counters.push((enforced, *delta));
+            shadow_counters.push(shadow);
+        }
+
+        let loaded = loads_counters(
+            counters.iter().flat_map(|(counters, _)| counters),
+            load_counters,
+        );
+        let check_results = self.storage.check_and_update_batch(&mut counters, loaded)?;
+
+        for (((namespace, _, delta, _), shadow_counters), check_result) in
+            batch.iter().zip(&shadow_counters).zip(&check_results)
+        {
+            if let Authorization::Ok = check_result {
+                self.count_shadow_hits(namespace, shadow_counters, *delta)?;
             }
         }
+
+        Ok(batch
+            .iter()
+            .zip(counters)
+            .zip(check_results)
+            .map(|(((namespace, _, _, _), (counters, _)), check_result)| {
+                to_check_result(
+                    &self.prometheus_metrics,
+                    namespace,
+                    counters,
+                    check_result,
+                    load_counters,
+                )
+            })
+            .collect())
     }
 
     pub fn get_counters(&self, namespace: &Namespace) -> Result<HashSet<Counter>, LimitadorError> {
@@ -436,41 +716,64 @@ impl RateLimiter {
             .map_err(|err| err.into())
     }
 
-    // Deletes all the limits stored except the ones received in the params. For
-    // every limit received, if it does not exist, it is created. If it already
-    // exists, its associated counters are not reset.
+    /// The counters of one of the limits, e.g. to go through the ones of a
+    /// namespace a limit at a time, rather than all at once.
+    pub fn get_counters_of_limit(&self, limit: &Limit) -> Result<HashSet<Counter>, LimitadorError> {
+        Ok(self.storage.get_counters_of_limit(limit)?)
+    }
+
+    /// Sets the counter, of one of the limits of its namespace, to have
+    /// `remaining` hits left for the next `expires_in`, whatever its hits
+    /// were, e.g. to carry over the counters of another instance. Only the
+    /// counters of fixed windows can be set, to expire before their window
+    /// would, and with no more hits remaining than the max value of their
+    /// limit. Returns whether the limit of the counter was found.
+    pub fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<bool, LimitadorError> {
+        let limits = self.storage.get_limits_applying_to(counter.namespace());
+        let Some(counter) = counter_to_set(&limits, counter, remaining, expires_in)? else {
+            return Ok(false);
+        };
+        // Already expired, so there's nothing to carry over
+        if !expires_in.is_zero() {
+            self.storage.set_counter(&counter, remaining, expires_in)?;
+        }
+        Ok(true)
+    }
+
+    /// Resets the counter of the limit of the namespace that has the name
+    /// given, for the values of the variables of that limit given, e.g. those
+    /// of a customer, so that it starts anew. Only the limits of `namespace`
+    /// are looked up, so that none of another namespace can be reset. Returns
+    /// `None` when there's no such limit, or else whether the counter existed.
+    pub fn reset_counter(
+        &self,
+        namespace: &Namespace,
+        limit_name: &str,
+        values: &HashMap<String, String>,
+    ) -> Result<Option<bool>, LimitadorError> {
+        let Some(limit) = self.get_limit_by_name(namespace, limit_name) else {
+            return Ok(None);
+        };
+        let counter = counter_to_reset(limit, values)?;
+        Ok(Some(self.storage.delete_counter(&counter)?))
+    }
+
+    /// Deletes all the limits stored except the ones received in the params.
+    /// For every limit received, if it does not exist, it is created. If it
+    /// already exists, its associated counters are not reset.
     pub fn configure_with(
         &self,
         limits: impl IntoIterator<Item = Limit>,
     ) -> Result<(), LimitadorError> {
-        let limits_to_keep_or_create = classify_limits_by_namespace(limits);
-
-        let namespaces_limits_to_keep_or_create: HashSet<Namespace> =
-            limits_to_keep_or_create.keys().cloned().collect();
-
-        for namespace in self
-            .get_namespaces()
-            .union(&namespaces_limits_to_keep_or_create)
-        {
-            let limits_in_namespace = self.get_limits(namespace);
-            let limits_to_keep_in_ns: HashSet<Limit> = limits_to_keep_or_create
-                .get(namespace)
-                .cloned()
-                .unwrap_or_default();
-
-            for limit in limits_in_namespace.difference(&limits_to_keep_in_ns) {
-                self.delete_limit(limit)?;
-            }
-
-            for limit in limits_to_keep_in_ns.difference(&limits_in_namespace) {
-                self.add_limit(limit.clone());
-            }
-
-            for limit in limits_to_keep_in_ns.union(&limits_in_namespace) {
-                self.storage.update_limit(limit);
-            }
-        }
-
+        // All the limits are swapped at once, so that concurrent checks see
+        // either the previous limits or the new ones, never a mix of both
+        self.storage
+            .replace_limits(classify_limits_by_namespace(limits))?;
         Ok(())
     }
 
@@ -478,18 +781,43 @@ impl RateLimiter {
         self.prometheus_metrics.gather_metrics()
     }
 
+    // Counts the hits of a request let through in the counters of the shadow
+    // limits that apply to it, whether that puts them over their limit or not,
+    // only recording the ones it does in the metrics
+    fn count_shadow_hits(
+        &self,
+        namespace: &Namespace,
+        counters: &[Counter],
+        delta: i64,
+    ) -> Result<(), LimitadorError> {
+        for counter in counters {
+            if !self.storage.is_within_limits(counter, delta)? {
+                self.prometheus_metrics
+                    .incr_shadow_limited_calls(namespace, counter.limit().name());
+            }
+            self.storage.update_counter(counter, delta)?;
+        }
+        Ok(())
+    }
+
     fn counters_that_apply(
         &self,
         namespace: &Namespace,
         values: &HashMap<String, String>,
+        max_value: Option<i64>,
     ) -> Result<Vec<Counter>, LimitadorError> {
-        let limits = self.get_limits(namespace);
-
-        let counters = limits
-            .iter()
-            .filter(|lim| lim.applies(values))
-            .map(|lim| Counter::new(lim.clone(), values.clone()))
-            .collect();
+        let limits = self.storage.get_limits_applying_to(namespace);
+
+        let mut counters = merge_shared_counters(
+            limits_that_apply(&limits, values)
+                .map(|lim| {
+                    let mut lim = lim.clone();
+                    lim.set_max_value(max_value.unwrap_or_else(|| lim.max_value_for(values)));
+                    Counter::new(lim, values.clone())
+                })
+                .collect(),
+        );
+        sort_by_priority(&mut counters);
 
         Ok(counters)
     }
@@ -501,6 +829,7 @@ impl RateLimiter {
 // to remove this duplication.
 
 impl AsyncRateLimiter {
+    /// A rate limiter that keeps its counters in the async storage given.
     pub fn new_with_storage(storage: Box<dyn AsyncCounterStorage>) -> Self {
         Self {
             storage: AsyncStorage::with_counter_storage(storage),
@@ -512,6 +841,8 @@ impl AsyncRateLimiter {
         self.storage.get_namespaces()
     }
 
+    /// Adds the limit, unless it exists already or another limit of its
+    /// namespace has the same name. Returns whether it was added.
     pub fn add_limit(&self, limit: Limit) -> bool {
         self.storage.add_limit(limit)
     }
@@ -521,27 +852,107 @@ impl AsyncRateLimiter {
         Ok(())
     }
 
+    /// Deletes the limit of the namespace that has the name given, along with
+    /// its counters. Returns whether there was such a limit.
+    pub async fn delete_limit_by_name(
+        &self,
+        namespace: &Namespace,
+        name: &str,
+    ) -> Result<bool, LimitadorError> {
+        match self.get_limit_by_name(namespace, name) {
+            Some(limit) => {
+                self.delete_limit(&limit).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
     pub fn get_limits(&self, namespace: &Namespace) -> HashSet<Limit> {
         self.storage.get_limits(namespace)
     }
 
+    pub fn get_limit_by_name(&self, namespace: &Namespace, name: &str) -> Option<Limit> {
+        self.get_limits(namespace)
+            .into_iter()
+            .find(|limit| limit.name() == Some(name))
+    }
+
+    /// Enables or disables the limit of the namespace that has the name
+    /// given, keeping its counters either way, see [`Limit::is_enabled`].
+    /// Returns whether there was such a limit.
+    pub fn set_limit_enabled_by_name(
+        &self,
+        namespace: &Namespace,
+        name: &str,
+        enabled: bool,
+    ) -> bool {
+        match self.get_limit_by_name(namespace, name) {
+            Some(mut limit) => {
+                limit.set_enabled(enabled);
+                self.storage.update_limit(&limit);
+                true
+            }
+            None => false,
+        }
+    }
+
     pub async fn delete_limits(&self, namespace: &Namespace) -> Result<(), LimitadorError> {
         self.storage.delete_limits(namespace).await?;
         Ok(())
     }
 
+    /// Errs when the storage of the counters can't be reached.
+    pub async fn ping(&self) -> Result<(), LimitadorError> {
+        self.storage.ping().await?;
+        Ok(())
+    }
+
+    /// Checks and updates a counter of its own, as a request would, so as to
+    /// err unless the storage of the counters can actually be used, not only
+    /// reached. The counter is of a namespace of its own, `limitador.warm_up`.
+    pub async fn warm_up(&self) -> Result<(), LimitadorError> {
+        self.storage.warm_up().await?;
+        Ok(())
+    }
+
+    /// Writes the hits the storage of the counters has buffered, e.g. the
+    /// ones of the Redis local cache, so that none is lost on shutdown.
+    pub async fn flush(&self) -> Result<(), LimitadorError> {
+        self.storage.flush().await?;
+        Ok(())
+    }
+
+    /// Same as [`RateLimiter::is_rate_limited`].
     pub async fn is_rate_limited(
         &self,
         namespace: &Namespace,
         values: &HashMap<String, String>,
         delta: i64,
     ) -> Result<bool, LimitadorError> {
-        let counters = self.counters_that_apply(namespace, values).await?;
+        self.is_rate_limited_overriding(namespace, values, delta, None)
+            .await
+    }
+
+    /// Same as [`RateLimiter::is_rate_limited_overriding`].
+    pub async fn is_rate_limited_overriding(
+        &self,
+        namespace: &Namespace,
+        values: &HashMap<String, String>,
+        delta: i64,
+        max_value: Option<i64>,
+    ) -> Result<bool, LimitadorError> {
+        let counters = self
+            .counters_that_apply(namespace, values, max_value)
+            .await?;
 
         for counter in counters {
             match self.storage.is_within_limits(&counter, delta).await {
                 Ok(within_limits) => {
-                    if !within_limits {
+                    if !within_limits && counter.limit().is_shadow() {
+                        self.prometheus_metrics
+                            .incr_shadow_limited_calls(namespace, counter.limit().name());
+                    } else if !within_limits {
                         self.prometheus_metrics
                             .incr_limited_calls(namespace, counter.limit().name());
                         return Ok(true);
@@ -555,13 +966,14 @@ impl AsyncRateLimiter {
         Ok(false)
     }
 
+    /// Same as [`RateLimiter::update_counters`].
     pub async fn update_counters(
         &self,
         namespace: &Namespace,
         values: &HashMap<String, String>,
         delta: i64,
     ) -> Result<(), LimitadorError> {
-        let counters = self.counters_that_apply(namespace, values).await?;
+        let counters = self.counters_that_apply(namespace, values, None).await?;
 
         for counter in counters {
             self.storage.update_counter(&counter, delta).await?
@@ -570,6 +982,7 @@ impl AsyncRateLimiter {
         Ok(())
     }
 
+    /// Same as [`RateLimiter::check_rate_limited_and_update`].
     pub async fn check_rate_limited_and_update(
         &self,
         namespace: &Namespace,
@@ -577,46 +990,95 @@ impl AsyncRateLimiter {
         delta: i64,
         load_counters: bool,
     ) -> Result<CheckResult, LimitadorError> {
-        // the above where-clause is needed in order to call unwrap().
-        let mut counters = self.counters_that_apply(namespace, values).await?;
+        let (mut counters, shadow_counters) =
+            split_shadow_counters(self.counters_that_apply(namespace, values, None).await?);
 
-        if counters.is_empty() {
-            self.prometheus_metrics.incr_authorized_calls(namespace);
-            return Ok(CheckResult {
-                limited: false,
-                counters,
-            });
+        let check_result = if counters.is_empty() {
+            Authorization::Ok
+        } else {
+            let loaded = loads_counters(&counters, load_counters);
+            self.storage
+                .check_and_update(&mut counters, delta, loaded)
+                .await?
+        };
+        if let Authorization::Ok = check_result {
+            self.count_shadow_hits(namespace, &shadow_counters, delta)
+                .await?;
         }
 
-        let check_result = self
-            .storage
-            .check_and_update(&mut counters, delta, load_counters)
-            .await?;
+        Ok(to_check_result(
+            &self.prometheus_metrics,
+            namespace,
+            counters,
+            check_result,
+            load_counters,
+        ))
+    }
 
-        let counters = if load_counters {
-            counters
-        } else {
-            Vec::default()
-        };
+    /// Checks and updates the counters of several requests at once, see
+    /// [`RateLimiter::check_rate_limited_and_update_batch`].
+    pub async fn check_rate_limited_and_update_batch(
+        &self,
+        batch: &[(&Namespace, &HashMap<String, String>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<CheckResult>, LimitadorError> {
+        let batch: Vec<_> = batch
+            .iter()
+            .map(|(namespace, values, delta)| (*namespace, *values, *delta, None))
+            .collect();
+        self.check_rate_limited_and_update_batch_overriding(&batch, load_counters)
+            .await
+    }
 
-        match check_result {
-            Authorization::Ok => {
-                self.prometheus_metrics.incr_authorized_calls(namespace);
+    /// Same as [`RateLimiter::check_rate_limited_and_update_batch_overriding`].
+    pub async fn check_rate_limited_and_update_batch_overriding(
+        &self,
+        batch: &[OverridingCheck<'_>],
+        load_counters: bool,
+    ) -> Result<Vec<CheckResult>, LimitadorError> {
+        let mut counters = Vec::with_capacity(batch.len());
+        let mut shadow_counters = Vec::with_capacity(batch.len());
+        for (namespace, values, delta, max_value) in batch {
+            let (enforced, shadow) = split_shadow_counters(
+                self.counters_that_apply(namespace, values, *max_value)
+                    .await?,
+            );
+            counters.push((enforced, *delta));
+            shadow_counters.push(shadow);
+        }
 
-                Ok(CheckResult {
-                    limited: false,
-                    counters,
-                })
-            }
-            Authorization::Limited(name) => {
-                self.prometheus_metrics
-                    .incr_limited_calls(namespace, name.as_deref());
-                Ok(CheckResult {
-                    limited: true,
-                    counters,
-                })
+        let loaded = loads_counters(
+            counters.iter().flat_map(|(counters, _)| counters),
+            load_counters,
+        );
+        let check_results = self
+            .storage
+            .check_and_update_batch(&mut counters, loaded)
+            .await?;
+
+        for (((namespace, _, delta, _), shadow_counters), check_result) in
+            batch.iter().zip(&shadow_counters).zip(&check_results)
+        {
+            if let Authorization::Ok = check_result {
+                self.count_shadow_hits(namespace, shadow_counters, *delta)
+                    .await?;
             }
         }
+
+        Ok(batch
+            .iter()
+            .zip(counters)
+            .zip(check_results)
+            .map(|(((namespace, _, _, _), (counters, _)), check_result)| {
+                to_check_result(
+                    &self.prometheus_metrics,
+                    namespace,
+                    counters,
+                    check_result,
+                    load_counters,
+                )
+            })
+            .collect())
     }
 
     pub async fn get_counters(
@@ -629,41 +1091,64 @@ impl AsyncRateLimiter {
             .map_err(|err| err.into())
     }
 
-    // Deletes all the limits stored except the ones received in the params. For
-    // every limit received, if it does not exist, it is created. If it already
-    // exists, its associated counters are not reset.
-    pub async fn configure_with(
+    /// The counters of one of the limits, e.g. to go through the ones of a
+    /// namespace a limit at a time, rather than all at once.
+    pub async fn get_counters_of_limit(
         &self,
-        limits: impl IntoIterator<Item = Limit>,
-    ) -> Result<(), LimitadorError> {
-        let limits_to_keep_or_create = classify_limits_by_namespace(limits);
-
-        let namespaces_limits_to_keep_or_create: HashSet<Namespace> =
-            limits_to_keep_or_create.keys().cloned().collect();
-
-        for namespace in self
-            .get_namespaces()
-            .union(&namespaces_limits_to_keep_or_create)
-        {
-            let limits_in_namespace = self.get_limits(namespace);
-            let limits_to_keep_in_ns: HashSet<Limit> = limits_to_keep_or_create
-                .get(namespace)
-                .cloned()
-                .unwrap_or_default();
-
-            for limit in limits_in_namespace.difference(&limits_to_keep_in_ns) {
-                self.delete_limit(limit).await?;
-            }
-
-            for limit in limits_to_keep_in_ns.difference(&limits_in_namespace) {
-                self.add_limit(limit.clone());
-            }
+        limit: &Limit,
+    ) -> Result<HashSet<Counter>, LimitadorError> {
+        Ok(self.storage.get_counters_of_limit(limit).await?)
+    }
 
-            for limit in limits_to_keep_in_ns.union(&limits_in_namespace) {
-                self.storage.update_limit(limit);
-            }
+    /// Sets the counter, of one of the limits of its namespace, to have
+    /// `remaining` hits left for the next `expires_in`, whatever its hits
+    /// were, e.g. to carry over the counters of another instance. Only the
+    /// counters of fixed windows can be set, to expire before their window
+    /// would, and with no more hits remaining than the max value of their
+    /// limit. Returns whether the limit of the counter was found.
+    pub async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<bool, LimitadorError> {
+        let limits = self.storage.get_limits_applying_to(counter.namespace());
+        let Some(counter) = counter_to_set(&limits, counter, remaining, expires_in)? else {
+            return Ok(false);
+        };
+        // Already expired, so there's nothing to carry over
+        if !expires_in.is_zero() {
+            self.storage
+                .set_counter(&counter, remaining, expires_in)
+                .await?;
         }
+        Ok(true)
+    }
 
+    /// Same as [`RateLimiter::reset_counter`].
+    pub async fn reset_counter(
+        &self,
+        namespace: &Namespace,
+        limit_name: &str,
+        values: &HashMap<String, String>,
+    ) -> Result<Option<bool>, LimitadorError> {
+        let Some(limit) = self.get_limit_by_name(namespace, limit_name) else {
+            return Ok(None);
+        };
+        let counter = counter_to_reset(limit, values)?;
+        Ok(Some(self.storage.delete_counter(&counter).await?))
+    }
+
+    /// Same as [`RateLimiter::configure_with`].
+    pub async fn configure_with(
+        &self,
+        limits: impl IntoIterator<Item = Limit>,
+    ) -> Result<(), LimitadorError> {
+        // All the limits are swapped at once, so that concurrent checks see
+        // either the previous limits or the new ones, never a mix of both
+        self.storage
+            .replace_limits(classify_limits_by_namespace(limits))
+            .await?;
         Ok(())
     }
 
@@ -671,20 +1156,201 @@ impl AsyncRateLimiter {
         self.prometheus_metrics.gather_metrics()
     }
 
+    async fn count_shadow_hits(
+        &self,
+        namespace: &Namespace,
+        counters: &[Counter],
+        delta: i64,
+    ) -> Result<(), LimitadorError> {
+        for counter in counters {
+            if !self.storage.is_within_limits(counter, delta).await? {
+                self.prometheus_metrics
+                    .incr_shadow_limited_calls(namespace, counter.limit().name());
+            }
+            self.storage.update_counter(counter, delta).await?;
+        }
+        Ok(())
+    }
+
     async fn counters_that_apply(
         &self,
         namespace: &Namespace,
         values: &HashMap<String, String>,
+        max_value: Option<i64>,
     ) -> Result<Vec<Counter>, LimitadorError> {
-        let limits = self.get_limits(namespace);
+        let limits = self.storage.get_limits_applying_to(namespace);
+
+        let mut counters = merge_shared_counters(
+            limits_that_apply(&limits, values)
+                .map(|lim| {
+                    let mut lim = lim.clone();
+                    lim.set_max_value(max_value.unwrap_or_else(|| lim.max_value_for(values)));
+                    Counter::new(lim, values.clone())
+                })
+                .collect(),
+        );
+        sort_by_priority(&mut counters);
 
-        let counters = limits
+        Ok(counters)
+    }
+}
+
+// The fallback limits only apply when none of the others do
+fn limits_that_apply<'a>(
+    limits: &'a HashSet<Limit>,
+    values: &'a HashMap<String, String>,
+) -> impl Iterator<Item = &'a Limit> {
+    // The limits disabled don't apply, not even to tell whether the fallback
+    // ones do
+    let enabled = move || limits.iter().filter(|limit| limit.is_enabled());
+    let fallback = !enabled().any(|limit| !limit.is_fallback() && limit.applies(values));
+    enabled().filter(move |limit| limit.is_fallback() == fallback && limit.applies(values))
+}
+
+// The counter of the limit qualified by the values given, which have to set all
+// of its variables but the optional ones
+fn counter_to_reset(
+    limit: Limit,
+    values: &HashMap<String, String>,
+) -> Result<Counter, LimitadorError> {
+    if let Some(variable) = limit
+        .variables()
+        .into_iter()
+        .filter(|variable| !values.contains_key(variable))
+        .min()
+    {
+        return Err(LimitadorError::InvalidCounter(format!(
+            "missing value for variable `{variable}`"
+        )));
+    }
+    Ok(Counter::new(limit, values.clone()))
+}
+
+// The counter to set, as of the limit stored, unless it isn't one of the limits
+fn counter_to_set(
+    limits: &HashSet<Limit>,
+    counter: &Counter,
+    remaining: i64,
+    expires_in: Duration,
+) -> Result<Option<Counter>, LimitadorError> {
+    // Of the limits sharing the counter, the one of the lowest max value
+    let limit = match counter.limit().shared_counter() {
+        Some(_) => limits
             .iter()
-            .filter(|lim| lim.applies(values))
-            .map(|lim| Counter::new(lim.clone(), values.clone()))
-            .collect();
+            .filter(|limit| limit.counter_limit() == *counter.limit())
+            .min_by_key(|limit| limit.max_value()),
+        None => limits.get(counter.limit()),
+    };
+    let Some(limit) = limit else {
+        return Ok(None);
+    };
+    let mut counter = counter.clone();
+    counter.update_to_limit(limit);
+
+    if counter.window_type() != WindowType::Fixed {
+        return Err(LimitadorError::InvalidCounter(format!(
+            "the counters of {:?} windows can't be set",
+            counter.window_type()
+        )));
+    }
+    if remaining > counter.max_value() {
+        return Err(LimitadorError::InvalidCounter(format!(
+            "{remaining} hits remaining, over the max value of {}",
+            counter.max_value()
+        )));
+    }
+    if expires_in > counter.window_at(SystemTime::now()) {
+        return Err(LimitadorError::InvalidCounter(format!(
+            "expires in {}s, after its window would",
+            expires_in.as_secs()
+        )));
+    }
+    Ok(Some(counter))
+}
 
-        Ok(counters)
+// The limits sharing a counter count the hits of a request in it once, against
+// the lowest of their max values
+fn merge_shared_counters(counters: Vec<Counter>) -> Vec<Counter> {
+    let mut merged: Vec<Counter> = Vec::with_capacity(counters.len());
+    for counter in counters {
+        match merged.iter_mut().find(|other| **other == counter) {
+            Some(other) if other.max_value() <= counter.max_value() => {}
+            Some(other) => *other = counter,
+            None => merged.push(counter),
+        }
+    }
+    merged
+}
+
+// The counters of the limits with a soft max value are always loaded, as
+// that's how the requests past it are told
+fn loads_counters<'a>(
+    counters: impl IntoIterator<Item = &'a Counter>,
+    load_counters: bool,
+) -> bool {
+    load_counters
+        || counters
+            .into_iter()
+            .any(|counter| counter.limit().soft_max_value().is_some())
+}
+
+// The counters of the shadow limits are left out of the ones checked, so that
+// they never limit the requests
+fn split_shadow_counters(counters: Vec<Counter>) -> (Vec<Counter>, Vec<Counter>) {
+    counters
+        .into_iter()
+        .partition(|counter| !counter.limit().is_shadow())
+}
+
+// The counters of the limits of the highest priority are evaluated first, so
+// that the storages stop at the first of them that is over their limit
+fn sort_by_priority(counters: &mut [Counter]) {
+    counters.sort_by_key(|counter| Reverse(counter.limit().priority()));
+}
+
+fn to_check_result(
+    prometheus_metrics: &PrometheusMetrics,
+    namespace: &Namespace,
+    counters: Vec<Counter>,
+    authorization: Authorization,
+    load_counters: bool,
+) -> CheckResult {
+    let approximate = counters.iter().any(Counter::is_approximate);
+    let soft_limited_by = match authorization {
+        Authorization::Ok => counters
+            .iter()
+            .find(|counter| counter.is_past_soft_max_value()),
+        Authorization::Limited(_) => None,
+    };
+    if let Some(counter) = soft_limited_by {
+        prometheus_metrics.incr_soft_limited_calls(namespace, counter.limit().name());
+    }
+    let soft_limited = soft_limited_by.is_some();
+    let counters = if load_counters {
+        counters
+    } else {
+        Vec::default()
+    };
+
+    match authorization {
+        Authorization::Ok => {
+            prometheus_metrics.incr_authorized_calls(namespace);
+            CheckResult {
+                limited: false,
+                counters,
+                approximate,
+                soft_limited,
+            }
+        }
+        Authorization::Limited(name) => {
+            prometheus_metrics.incr_limited_calls(namespace, name.as_deref());
+            CheckResult {
+                limited: true,
+                counters,
+                approximate,
+                soft_limited,
+            }
+        }
     }
 }
 
@@ -1,10 +1,118 @@
 use crate::limit::conditions::{ErrorType, Literal, SyntaxError, Token, TokenType};
+use chrono::{DateTime, Datelike, Days, LocalResult, Months, NaiveDate, Offset, TimeZone, Utc};
+pub use chrono_tz::Tz;
 use serde::{Deserialize, Serialize, Serializer};
 use std::cmp::Ordering;
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The patterns of the `=~` operator: a `*` matches any sequence of characters,
+// including none, while `\*` and `\\` match a literal `*` and `\`. No other
+// character is special, nor can be escaped.
+mod glob {
+    /// Returns the offset of the first invalid escape sequence of `pattern`, if any
+    pub fn validate(pattern: &str) -> Result<(), usize> {
+        let mut chars = pattern.chars().enumerate();
+        while let Some((offset, c)) = chars.next() {
+            if c == '\\' && !matches!(chars.next(), Some((_, '*' | '\\'))) {
+                return Err(offset);
+            }
+        }
+        Ok(())
+    }
+
+    // Matches byte per byte, backtracking to the last `*` seen on mismatches,
+    // which keeps it linear for the usual prefix and suffix patterns and never
+    // allocates
+    pub fn matches(pattern: &str, value: &str) -> bool {
+        let (pattern, value) = (pattern.as_bytes(), value.as_bytes());
+        let (mut p, mut v) = (0, 0);
+        let mut last_star: Option<(usize, usize)> = None;
+
+        while v < value.len() {
+            match pattern.get(p) {
+                Some(b'*') => {
+                    p += 1;
+                    last_star = Some((p, v));
+                    continue;
+                }
+                Some(b'\\') if pattern.get(p + 1) == Some(&value[v]) => {
+                    p += 2;
+                    v += 1;
+                    continue;
+                }
+                Some(&c) if c != b'\\' && c == value[v] => {
+                    p += 1;
+                    v += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match last_star {
+                Some((star_p, star_v)) => {
+                    p = star_p;
+                    v = star_v + 1;
+                    last_star = Some((star_p, v));
+                }
+                None => return false,
+            }
+        }
+
+        pattern[p..].iter().all(|&c| c == b'*')
+    }
+}
+
+// The patterns of the `~=` operator, compiled once when the condition is
+// parsed, rather than on every request they're matched on
+mod regex_pattern {
+    use regex::{Regex, RegexBuilder};
+    use std::hash::{Hash, Hasher};
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone)]
+    pub struct Pattern(Arc<Regex>);
+
+    impl Pattern {
+        /// Errs with the reason the pattern isn't a valid regular expression
+        pub fn new(pattern: &str, ignore_case: bool) -> Result<Self, String> {
+            RegexBuilder::new(pattern)
+                .case_insensitive(ignore_case)
+                .build()
+                .map(|regex| Self(Arc::new(regex)))
+                .map_err(|err| match err {
+                    // Only the last line, not the pattern pointed at
+                    regex::Error::Syntax(msg) => msg
+                        .lines()
+                        .last()
+                        .unwrap_or_default()
+                        .trim_start_matches("error: ")
+                        .to_string(),
+                    err => err.to_string(),
+                })
+        }
+
+        pub fn is_match(&self, value: &str) -> bool {
+            self.0.is_match(value)
+        }
+    }
+
+    impl PartialEq for Pattern {
+        fn eq(&self, other: &Self) -> bool {
+            self.0.as_str() == other.0.as_str()
+        }
+    }
+
+    impl Eq for Pattern {}
+
+    impl Hash for Pattern {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.0.as_str().hash(state);
+        }
+    }
+}
 
 #[cfg(feature = "lenient_conditions")]
 mod deprecated {
@@ -31,6 +139,16 @@ pub use deprecated::check_deprecated_syntax_usages_and_reset;
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Namespace(String);
 
+/// The namespace of the limits that apply to the requests of all the
+/// namespaces, along with the limits of their own.
+pub const WILDCARD_NAMESPACE: &str = "*";
+
+impl Namespace {
+    pub fn is_wildcard(&self) -> bool {
+        self.0 == WILDCARD_NAMESPACE
+    }
+}
+
 impl From<&str> for Namespace {
     fn from(s: &str) -> Namespace {
         Self(s.into())
@@ -54,16 +172,436 @@ pub struct Limit {
     namespace: Namespace,
     #[serde(skip_serializing, default)]
     max_value: i64,
+    // Nor the hits past which the requests let through are flagged
+    #[serde(skip_serializing, default)]
+    soft_max_value: Option<i64>,
+    #[serde(default)]
     seconds: u64,
+    // Sets the length of the windows instead of `seconds`, when sub-second
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    milliseconds: Option<u64>,
     #[serde(skip_serializing, default)]
     name: Option<String>,
+    // Doesn't identify the limit either, it only decides the order in which
+    // the limits that apply to a request are evaluated
+    #[serde(skip_serializing, default)]
+    priority: i32,
+    // Nor does how many hits the requests count for
+    #[serde(skip_serializing, default)]
+    cost: Option<Cost>,
+    // Nor the max values looked up for the requests
+    #[serde(skip_serializing, default)]
+    max_values: Option<MaxValues>,
+    // Nor how much later than the end of their windows its counters expire
+    #[serde(skip_serializing, default)]
+    jitter: Option<u64>,
+    // Nor the group of limits it is a tier of
+    #[serde(skip_serializing, default)]
+    group: Option<String>,
+    // Nor the nested windows it's split into, see `Limit::into_levels`
+    #[serde(skip_serializing, default)]
+    levels: Vec<WindowLevel>,
+    // Nor whether it's enforced, so that disabling it keeps its counters
+    #[serde(skip_serializing, default = "enabled")]
+    enabled: bool,
+    // Nor whether it limits the requests over it, rather than only counting
+    // their hits, so that it keeps its counters once it does
+    #[serde(skip_serializing, default)]
+    shadow: bool,
 
     // Need to sort to generate the same object when using the JSON as a key or
     // value in Redis.
     #[serde(serialize_with = "ordered_condition_set")]
     conditions: HashSet<Condition>,
+    // The `req.method in "..."` condition the methods desugar to, that reads
+    // and writes as the list of the methods
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "http_methods"
+    )]
+    methods: Option<Condition>,
     #[serde(serialize_with = "ordered_set")]
     variables: HashSet<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "HashSet::is_empty",
+        serialize_with = "ordered_set"
+    )]
+    optional_variables: HashSet<String>,
+
+    #[serde(default, skip_serializing_if = "WindowType::is_fixed")]
+    window_type: WindowType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refill_rate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    leak_rate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    distinct_variable: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sliding_buckets: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shards: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    calendar_window: Option<CalendarWindow>,
+    #[serde(default, skip_serializing_if = "WindowStart::is_first_hit")]
+    window_start: WindowStart,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shared_counter: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    fallback: bool,
+}
+
+/// How the hits are accounted for over the `seconds` of a limit.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Default, Serialize, Deserialize)]
+pub enum WindowType {
+    /// The counter is reset once the window, that starts with its first hit,
+    /// is over.
+    #[default]
+    Fixed,
+    /// The window slides over time: the hits of the previous window still
+    /// count, proportionally to how much the window overlaps with it, which
+    /// avoids bursts at the boundaries of fixed windows. Split into the
+    /// limit's `sliding_buckets`, the window slides a bucket at a time, only
+    /// the hits of the oldest bucket being weighted, for a closer estimate.
+    Sliding,
+    /// A bucket of `max_value` tokens, refilled at the limit's `refill_rate`
+    /// tokens per second, or completely over `seconds` if not set. Each hit
+    /// consumes tokens from the bucket.
+    TokenBucket,
+    /// A bucket holding up to `max_value` hits, that leak out of it at the
+    /// limit's `leak_rate` hits per second, or completely over `seconds` if
+    /// not set. Hits are only let in while there's room for them in the
+    /// bucket, so that past a burst they get through at the leak rate.
+    LeakyBucket,
+    /// Counts the distinct values of the limit's `distinct_variable` seen
+    /// over fixed windows, rather than the hits: once `max_value` of them
+    /// are, only the requests with one of those values are let through.
+    /// The values are counted approximately, with a HyperLogLog, for the
+    /// counters to take the same memory however many values they see.
+    Distinct,
+}
+
+impl WindowType {
+    fn is_fixed(&self) -> bool {
+        *self == WindowType::Fixed
+    }
+}
+
+/// When the fixed windows of the counters of a limit start.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Default, Serialize, Deserialize)]
+pub enum WindowStart {
+    /// With the first hit of each counter, so that the windows of the counters
+    /// first hit at different times end at different times.
+    #[default]
+    FirstHit,
+    /// On the boundaries of the clock, i.e. every multiple of the window since
+    /// the epoch, e.g. every minute on the minute: all the counters, and all
+    /// the instances of Limitador, agree on when the windows end.
+    Clock,
+}
+
+impl WindowStart {
+    fn is_first_hit(&self) -> bool {
+        *self == WindowStart::FirstHit
+    }
+}
+
+/// The descriptor entry holding the method of the request, that the methods
+/// of a limit are matched against.
+pub const METHOD_VARIABLE: &str = "req.method";
+
+/// An HTTP method a limit can be restricted to.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HttpMethod {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl HttpMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Head => "HEAD",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Delete => "DELETE",
+            HttpMethod::Connect => "CONNECT",
+            HttpMethod::Options => "OPTIONS",
+            HttpMethod::Trace => "TRACE",
+            HttpMethod::Patch => "PATCH",
+        }
+    }
+}
+
+impl TryFrom<&str> for HttpMethod {
+    type Error = String;
+
+    fn try_from(method: &str) -> Result<Self, Self::Error> {
+        match method {
+            "GET" => Ok(HttpMethod::Get),
+            "HEAD" => Ok(HttpMethod::Head),
+            "POST" => Ok(HttpMethod::Post),
+            "PUT" => Ok(HttpMethod::Put),
+            "DELETE" => Ok(HttpMethod::Delete),
+            "CONNECT" => Ok(HttpMethod::Connect),
+            "OPTIONS" => Ok(HttpMethod::Options),
+            "TRACE" => Ok(HttpMethod::Trace),
+            "PATCH" => Ok(HttpMethod::Patch),
+            _ => Err(format!("unknown HTTP method `{method}`")),
+        }
+    }
+}
+
+impl Display for HttpMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// The methods of a limit desugar to a `req.method in "GET,POST"` condition
+mod http_methods {
+    use super::{Condition, HttpMethod, Predicate, METHOD_VARIABLE};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeSet;
+
+    pub fn condition(methods: impl IntoIterator<Item = HttpMethod>) -> Option<Condition> {
+        let methods: BTreeSet<HttpMethod> = methods.into_iter().collect();
+        if methods.is_empty() {
+            return None;
+        }
+        Some(Condition {
+            var_name: METHOD_VARIABLE.to_string(),
+            predicate: Predicate::In,
+            operand: methods
+                .iter()
+                .map(HttpMethod::as_str)
+                .collect::<Vec<_>>()
+                .join(","),
+            case_insensitive: false,
+        })
+    }
+
+    pub fn of(condition: &Condition) -> Vec<HttpMethod> {
+        condition
+            .operand
+            .split(',')
+            .filter_map(|method| HttpMethod::try_from(method).ok())
+            .collect()
+    }
+
+    pub fn serialize<S>(condition: &Option<Condition>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        condition.as_ref().map(of).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Condition>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let methods: Vec<HttpMethod> = Deserialize::deserialize(deserializer)?;
+        Ok(condition(methods))
+    }
+}
+
+/// A fixed window that is aligned to the calendar of a timezone, rather than
+/// starting with the first hit of the counter: it's over at the start of the
+/// next day, week or month there, whatever the `seconds` of the limit.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Serialize, Deserialize)]
+pub struct CalendarWindow {
+    period: CalendarPeriod,
+    #[serde(default = "utc")]
+    timezone: Tz,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Serialize, Deserialize)]
+pub enum CalendarPeriod {
+    Daily,
+    /// Weeks start on Mondays.
+    Weekly,
+    Monthly,
+}
+
+fn utc() -> Tz {
+    Tz::UTC
+}
+
+impl CalendarWindow {
+    pub fn new(period: CalendarPeriod, timezone: Tz) -> Self {
+        Self { period, timezone }
+    }
+
+    pub fn period(&self) -> CalendarPeriod {
+        self.period
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    /// When the window that `when` falls in is over.
+    pub fn ends_at(&self, when: SystemTime) -> SystemTime {
+        let today = DateTime::<Utc>::from(when)
+            .with_timezone(&self.timezone)
+            .date_naive();
+        let next = match self.period {
+            CalendarPeriod::Daily => today + Days::new(1),
+            CalendarPeriod::Weekly => {
+                today + Days::new(7 - u64::from(today.weekday().num_days_from_monday()))
+            }
+            CalendarPeriod::Monthly => {
+                today.with_day(1).expect("every month has a 1st") + Months::new(1)
+            }
+        };
+        self.start_of(next).into()
+    }
+
+    // The first instant of the day, which is midnight but for the days the
+    // clocks are set forward at midnight: they start when they were.
+    fn start_of(&self, day: NaiveDate) -> DateTime<Tz> {
+        let midnight = day.and_hms_opt(0, 0, 0).expect("midnight is a valid time");
+        match self.timezone.from_local_datetime(&midnight) {
+            LocalResult::Single(start) | LocalResult::Ambiguous(start, _) => start,
+            LocalResult::None => {
+                let offset_before = self
+                    .timezone
+                    .offset_from_utc_datetime(&(midnight - Days::new(1)))
+                    .fix();
+                self.timezone.from_utc_datetime(&(midnight - offset_before))
+            }
+        }
+    }
+}
+
+/// How many hits a request counts for in the counters of a limit, given the
+/// value of one of its descriptor entries: the hits of the request are
+/// multiplied by the cost of that value.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Cost {
+    entry: String,
+    #[serde(default)]
+    values: HashMap<String, i64>,
+    #[serde(default = "unit_cost")]
+    default: i64,
+}
+
+fn unit_cost() -> i64 {
+    1
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Cost {
+    /// The `default` cost applies to the requests without the `entry`, or with
+    /// a value not in `values`.
+    pub fn new(entry: impl Into<String>, values: HashMap<String, i64>, default: i64) -> Self {
+        Self {
+            entry: entry.into(),
+            values,
+            default,
+        }
+    }
+
+    pub fn entry(&self) -> &str {
+        &self.entry
+    }
+
+    pub fn values(&self) -> &HashMap<String, i64> {
+        &self.values
+    }
+
+    pub fn default(&self) -> i64 {
+        self.default
+    }
+
+    /// The cost of a request with these descriptor entries.
+    pub fn of(&self, values: &HashMap<String, String>) -> i64 {
+        values
+            .get(&self.entry)
+            .and_then(|value| self.values.get(value))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// A level of a limit of nested windows, e.g. the hourly quota of a limit of
+/// per-minute, hourly and daily ones, that all count the same hits. See
+/// [`Limit::into_levels`].
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct WindowLevel {
+    name: String,
+    seconds: u64,
+    max_value: i64,
+}
+
+impl WindowLevel {
+    pub fn new(name: impl Into<String>, seconds: u64, max_value: i64) -> Self {
+        Self {
+            name: name.into(),
+            seconds,
+            max_value,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn seconds(&self) -> u64 {
+        self.seconds
+    }
+
+    pub fn max_value(&self) -> i64 {
+        self.max_value
+    }
+}
+
+/// The max value a limit checks the hits of a request against, given the value
+/// of one of its descriptor entries, e.g. the plan of the user. The requests
+/// without the `entry`, or with a value not in `values`, are checked against
+/// the `max_value` of the limit.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct MaxValues {
+    entry: String,
+    #[serde(default)]
+    values: HashMap<String, i64>,
+}
+
+impl MaxValues {
+    pub fn new(entry: impl Into<String>, values: HashMap<String, i64>) -> Self {
+        Self {
+            entry: entry.into(),
+            values,
+        }
+    }
+
+    pub fn entry(&self) -> &str {
+        &self.entry
+    }
+
+    pub fn values(&self) -> &HashMap<String, i64> {
+        &self.values
+    }
+
+    /// The max value of a request with these descriptor entries, if looked up.
+    pub fn of(&self, values: &HashMap<String, String>) -> Option<i64> {
+        values
+            .get(&self.entry)
+            .and_then(|value| self.values.get(value))
+            .copied()
+    }
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Hash)]
@@ -72,6 +610,8 @@ pub struct Condition {
     var_name: String,
     predicate: Predicate,
     operand: String,
+    // Set by an `i` right after the string operand, e.g. `method == 'get'i`
+    case_insensitive: bool,
 }
 
 #[derive(Debug)]
@@ -106,140 +646,275 @@ impl TryFrom<String> for Condition {
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         match conditions::Scanner::scan(value.clone()) {
-            Ok(tokens) => match tokens.len().cmp(&(3_usize)) {
-                Ordering::Equal => {
-                    match (
-                        &tokens[0].token_type,
-                        &tokens[1].token_type,
-                        &tokens[2].token_type,
-                    ) {
-                        (
-                            TokenType::Identifier,
-                            TokenType::EqualEqual | TokenType::NotEqual,
-                            TokenType::String,
-                        ) => {
-                            if let (
-                                Some(Literal::Identifier(var_name)),
-                                Some(Literal::String(operand)),
-                            ) = (&tokens[0].literal, &tokens[2].literal)
-                            {
-                                let predicate = match &tokens[1].token_type {
-                                    TokenType::EqualEqual => Predicate::Equal,
-                                    TokenType::NotEqual => Predicate::NotEqual,
-                                    _ => unreachable!(),
-                                };
-                                Ok(Condition {
-                                    var_name: var_name.clone(),
-                                    predicate,
-                                    operand: operand.clone(),
-                                })
-                            } else {
-                                panic!(
+            Ok(mut tokens) => {
+                // The flag can only follow the operand of the condition
+                let ignore_case = match tokens
+                    .iter()
+                    .position(|token| token.token_type == TokenType::IgnoreCase)
+                {
+                    Some(3) if tokens.len() == 4 => tokens.pop(),
+                    Some(at) => {
+                        return Err(ConditionParsingError {
+                            error: SyntaxError {
+                                pos: tokens[at].pos,
+                                error: ErrorType::UnexpectedToken(tokens[at].clone()),
+                            },
+                            tokens,
+                            condition: value,
+                        })
+                    }
+                    None => None,
+                };
+                match tokens.len().cmp(&(3_usize)) {
+                    Ordering::Equal => {
+                        match (
+                            &tokens[0].token_type,
+                            &tokens[1].token_type,
+                            &tokens[2].token_type,
+                        ) {
+                            (
+                                TokenType::Identifier,
+                                TokenType::EqualEqual
+                                | TokenType::NotEqual
+                                | TokenType::In
+                                | TokenType::NotIn
+                                | TokenType::Matches
+                                | TokenType::MatchesRegex,
+                                TokenType::String,
+                            ) => {
+                                if let (
+                                    Some(Literal::Identifier(var_name)),
+                                    Some(Literal::String(operand)),
+                                ) = (&tokens[0].literal, &tokens[2].literal)
+                                {
+                                    let predicate = match &tokens[1].token_type {
+                                        TokenType::EqualEqual => Predicate::Equal,
+                                        TokenType::NotEqual => Predicate::NotEqual,
+                                        TokenType::In => Predicate::In,
+                                        TokenType::NotIn => Predicate::NotIn,
+                                        TokenType::Matches => Predicate::Matches,
+                                        TokenType::MatchesRegex => {
+                                            match regex_pattern::Pattern::new(
+                                                operand,
+                                                ignore_case.is_some(),
+                                            ) {
+                                                Ok(pattern) => Predicate::MatchesRegex(pattern),
+                                                Err(reason) => {
+                                                    return Err(ConditionParsingError {
+                                                        error: SyntaxError {
+                                                            pos: tokens[2].pos,
+                                                            error: ErrorType::InvalidRegex(reason),
+                                                        },
+                                                        tokens,
+                                                        condition: value,
+                                                    })
+                                                }
+                                            }
+                                        }
+                                        _ => unreachable!(),
+                                    };
+                                    if predicate == Predicate::Matches {
+                                        if let Err(offset) = glob::validate(operand) {
+                                            return Err(ConditionParsingError {
+                                                error: SyntaxError {
+                                                    pos: tokens[2].pos + offset,
+                                                    error: ErrorType::InvalidEscapeSequence,
+                                                },
+                                                tokens,
+                                                condition: value,
+                                            });
+                                        }
+                                    }
+                                    Ok(Condition {
+                                        var_name: var_name.clone(),
+                                        predicate,
+                                        operand: operand.clone(),
+                                        case_insensitive: ignore_case.is_some(),
+                                    })
+                                } else {
+                                    panic!(
                                     "Unexpected state {tokens:?} returned from Scanner for: `{value}`"
                                 )
+                                }
                             }
-                        }
-                        (
-                            TokenType::String,
-                            TokenType::EqualEqual | TokenType::NotEqual,
-                            TokenType::Identifier,
-                        ) => {
-                            if let (
-                                Some(Literal::String(operand)),
-                                Some(Literal::Identifier(var_name)),
-                            ) = (&tokens[0].literal, &tokens[2].literal)
-                            {
+                            (
+                                TokenType::String,
+                                TokenType::EqualEqual | TokenType::NotEqual,
+                                TokenType::Identifier,
+                            ) => {
+                                if let (
+                                    Some(Literal::String(operand)),
+                                    Some(Literal::Identifier(var_name)),
+                                ) = (&tokens[0].literal, &tokens[2].literal)
+                                {
+                                    let predicate = match &tokens[1].token_type {
+                                        TokenType::EqualEqual => Predicate::Equal,
+                                        TokenType::NotEqual => Predicate::NotEqual,
+                                        _ => unreachable!(),
+                                    };
+                                    Ok(Condition {
+                                        var_name: var_name.clone(),
+                                        predicate,
+                                        operand: operand.clone(),
+                                        case_insensitive: false,
+                                    })
+                                } else {
+                                    panic!(
+                                    "Unexpected state {tokens:?} returned from Scanner for: `{value}`"
+                                )
+                                }
+                            }
+                            (
+                                TokenType::Identifier,
+                                TokenType::Less
+                                | TokenType::LessEqual
+                                | TokenType::Greater
+                                | TokenType::GreaterEqual,
+                                TokenType::String | TokenType::Number,
+                            ) => {
+                                let var_name = match &tokens[0].literal {
+                                Some(Literal::Identifier(var_name)) => var_name.clone(),
+                                _ => panic!(
+                                    "Unexpected state {tokens:?} returned from Scanner for: `{value}`"
+                                ),
+                            };
+                                // Numbers have no case
+                                if let Some(flag) = ignore_case {
+                                    return Err(ConditionParsingError {
+                                        error: SyntaxError {
+                                            pos: flag.pos,
+                                            error: ErrorType::UnexpectedToken(flag),
+                                        },
+                                        tokens,
+                                        condition: value,
+                                    });
+                                }
+                                // Comparisons only make sense against numbers
+                                let operand = match &tokens[2].literal {
+                                    Some(Literal::Number(operand)) => operand.to_string(),
+                                    Some(Literal::String(operand))
+                                        if parse_number(operand).is_some() =>
+                                    {
+                                        operand.clone()
+                                    }
+                                    _ => {
+                                        return Err(ConditionParsingError {
+                                            error: SyntaxError {
+                                                pos: tokens[2].pos,
+                                                error: ErrorType::InvalidNumber,
+                                            },
+                                            tokens,
+                                            condition: value,
+                                        })
+                                    }
+                                };
                                 let predicate = match &tokens[1].token_type {
-                                    TokenType::EqualEqual => Predicate::Equal,
-                                    TokenType::NotEqual => Predicate::NotEqual,
+                                    TokenType::Less => Predicate::Less,
+                                    TokenType::LessEqual => Predicate::LessEqual,
+                                    TokenType::Greater => Predicate::Greater,
+                                    TokenType::GreaterEqual => Predicate::GreaterEqual,
                                     _ => unreachable!(),
                                 };
                                 Ok(Condition {
-                                    var_name: var_name.clone(),
+                                    var_name,
                                     predicate,
-                                    operand: operand.clone(),
+                                    operand,
+                                    case_insensitive: false,
                                 })
-                            } else {
-                                panic!(
+                            }
+                            #[cfg(feature = "lenient_conditions")]
+                            (
+                                TokenType::Identifier,
+                                TokenType::EqualEqual,
+                                TokenType::Identifier,
+                            ) => {
+                                if let (
+                                    Some(Literal::Identifier(var_name)),
+                                    Some(Literal::Identifier(operand)),
+                                ) = (&tokens[0].literal, &tokens[2].literal)
+                                {
+                                    deprecated::deprecated_syntax_used();
+                                    Ok(Condition {
+                                        var_name: var_name.clone(),
+                                        predicate: Predicate::Equal,
+                                        operand: operand.clone(),
+                                        case_insensitive: false,
+                                    })
+                                } else {
+                                    panic!(
                                     "Unexpected state {tokens:?} returned from Scanner for: `{value}`"
                                 )
+                                }
                             }
-                        }
-                        #[cfg(feature = "lenient_conditions")]
-                        (TokenType::Identifier, TokenType::EqualEqual, TokenType::Identifier) => {
-                            if let (
-                                Some(Literal::Identifier(var_name)),
-                                Some(Literal::Identifier(operand)),
-                            ) = (&tokens[0].literal, &tokens[2].literal)
-                            {
-                                deprecated::deprecated_syntax_used();
-                                Ok(Condition {
-                                    var_name: var_name.clone(),
-                                    predicate: Predicate::Equal,
-                                    operand: operand.clone(),
-                                })
-                            } else {
-                                panic!(
+                            #[cfg(feature = "lenient_conditions")]
+                            (TokenType::Identifier, TokenType::EqualEqual, TokenType::Number) => {
+                                if let (
+                                    Some(Literal::Identifier(var_name)),
+                                    Some(Literal::Number(operand)),
+                                ) = (&tokens[0].literal, &tokens[2].literal)
+                                {
+                                    deprecated::deprecated_syntax_used();
+                                    Ok(Condition {
+                                        var_name: var_name.clone(),
+                                        predicate: Predicate::Equal,
+                                        operand: operand.to_string(),
+                                        case_insensitive: false,
+                                    })
+                                } else {
+                                    panic!(
                                     "Unexpected state {tokens:?} returned from Scanner for: `{value}`"
                                 )
+                                }
                             }
-                        }
-                        #[cfg(feature = "lenient_conditions")]
-                        (TokenType::Identifier, TokenType::EqualEqual, TokenType::Number) => {
-                            if let (
-                                Some(Literal::Identifier(var_name)),
-                                Some(Literal::Number(operand)),
-                            ) = (&tokens[0].literal, &tokens[2].literal)
-                            {
-                                deprecated::deprecated_syntax_used();
-                                Ok(Condition {
-                                    var_name: var_name.clone(),
-                                    predicate: Predicate::Equal,
-                                    operand: operand.to_string(),
+                            (t1, t2, _) => {
+                                let faulty = match (t1, t2) {
+                                    (
+                                        TokenType::Identifier | TokenType::String,
+                                        TokenType::EqualEqual | TokenType::NotEqual,
+                                    )
+                                    | (
+                                        TokenType::Identifier,
+                                        TokenType::In
+                                        | TokenType::NotIn
+                                        | TokenType::Matches
+                                        | TokenType::MatchesRegex
+                                        | TokenType::Less
+                                        | TokenType::LessEqual
+                                        | TokenType::Greater
+                                        | TokenType::GreaterEqual,
+                                    ) => 2,
+                                    (TokenType::Identifier | TokenType::String, _) => 1,
+                                    (_, _) => 0,
+                                };
+                                Err(ConditionParsingError {
+                                    error: SyntaxError {
+                                        pos: tokens[faulty].pos,
+                                        error: ErrorType::UnexpectedToken(tokens[faulty].clone()),
+                                    },
+                                    tokens,
+                                    condition: value,
                                 })
-                            } else {
-                                panic!(
-                                    "Unexpected state {tokens:?} returned from Scanner for: `{value}`"
-                                )
                             }
                         }
-                        (t1, t2, _) => {
-                            let faulty = match (t1, t2) {
-                                (
-                                    TokenType::Identifier | TokenType::String,
-                                    TokenType::EqualEqual | TokenType::NotEqual,
-                                ) => 2,
-                                (TokenType::Identifier | TokenType::String, _) => 1,
-                                (_, _) => 0,
-                            };
-                            Err(ConditionParsingError {
-                                error: SyntaxError {
-                                    pos: tokens[faulty].pos,
-                                    error: ErrorType::UnexpectedToken(tokens[faulty].clone()),
-                                },
-                                tokens,
-                                condition: value,
-                            })
-                        }
                     }
+                    Ordering::Less => Err(ConditionParsingError {
+                        error: SyntaxError {
+                            pos: value.len(),
+                            error: ErrorType::MissingToken,
+                        },
+                        tokens,
+                        condition: value,
+                    }),
+                    Ordering::Greater => Err(ConditionParsingError {
+                        error: SyntaxError {
+                            pos: tokens[3].pos,
+                            error: ErrorType::UnexpectedToken(tokens[3].clone()),
+                        },
+                        tokens,
+                        condition: value,
+                    }),
                 }
-                Ordering::Less => Err(ConditionParsingError {
-                    error: SyntaxError {
-                        pos: value.len(),
-                        error: ErrorType::MissingToken,
-                    },
-                    tokens,
-                    condition: value,
-                }),
-                Ordering::Greater => Err(ConditionParsingError {
-                    error: SyntaxError {
-                        pos: tokens[3].pos,
-                        error: ErrorType::UnexpectedToken(tokens[3].clone()),
-                    },
-                    tokens,
-                    condition: value,
-                }),
-            },
+            }
             Err(err) => Err(ConditionParsingError {
                 error: err,
                 tokens: Vec::new(),
@@ -258,9 +933,10 @@ impl From<Condition> for String {
         } else {
             '"'
         };
+        let flag = if condition.case_insensitive { "i" } else { "" };
         format!(
-            "{} {} {}{}{}",
-            condition.var_name, predicate, quotes, condition.operand, quotes
+            "{} {} {}{}{}{}",
+            condition.var_name, predicate, quotes, condition.operand, quotes, flag
         )
     }
 }
@@ -269,13 +945,65 @@ impl From<Condition> for String {
 pub enum Predicate {
     Equal,
     NotEqual,
+    In,
+    NotIn,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Matches,
+    MatchesRegex(regex_pattern::Pattern),
+}
+
+// Values that aren't numbers never compare, so that the limits comparing them
+// simply don't apply
+fn parse_number(value: &str) -> Option<f64> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|number| number.is_finite())
+}
+
+// Compares the lowercase of each character, not to allocate for every value
+fn eq_ignoring_case(lhs: &str, rhs: &str) -> bool {
+    lhs.chars()
+        .flat_map(char::to_lowercase)
+        .eq(rhs.chars().flat_map(char::to_lowercase))
 }
 
 impl Predicate {
-    fn test(&self, lhs: &str, rhs: &str) -> bool {
+    fn test(&self, lhs: &str, rhs: &str, ignore_case: bool) -> bool {
+        let eq = |lhs: &str, rhs: &str| {
+            if ignore_case {
+                eq_ignoring_case(lhs, rhs)
+            } else {
+                lhs == rhs
+            }
+        };
         match self {
-            Predicate::Equal => lhs == rhs,
-            Predicate::NotEqual => lhs != rhs,
+            Predicate::Equal => eq(lhs, rhs),
+            Predicate::NotEqual => !eq(lhs, rhs),
+            // The operand of `in` and `not in` is a comma separated list of values
+            Predicate::In => rhs.split(',').any(|value| eq(value.trim(), lhs)),
+            Predicate::NotIn => !rhs.split(',').any(|value| eq(value.trim(), lhs)),
+            Predicate::Less => Self::compare(lhs, rhs, |o| o.is_lt()),
+            Predicate::LessEqual => Self::compare(lhs, rhs, |o| o.is_le()),
+            Predicate::Greater => Self::compare(lhs, rhs, |o| o.is_gt()),
+            Predicate::GreaterEqual => Self::compare(lhs, rhs, |o| o.is_ge()),
+            Predicate::Matches if ignore_case => {
+                glob::matches(&rhs.to_lowercase(), &lhs.to_lowercase())
+            }
+            Predicate::Matches => glob::matches(rhs, lhs),
+            // Compiled case insensitive already, when flagged so
+            Predicate::MatchesRegex(pattern) => pattern.is_match(lhs),
+        }
+    }
+
+    fn compare(lhs: &str, rhs: &str, expected: impl Fn(Ordering) -> bool) -> bool {
+        match (parse_number(lhs), parse_number(rhs)) {
+            (Some(lhs), Some(rhs)) => lhs.partial_cmp(&rhs).is_some_and(expected),
+            _ => false,
         }
     }
 }
@@ -285,6 +1013,14 @@ impl From<Predicate> for String {
         match op {
             Predicate::Equal => "==".to_string(),
             Predicate::NotEqual => "!=".to_string(),
+            Predicate::In => "in".to_string(),
+            Predicate::NotIn => "not in".to_string(),
+            Predicate::Less => "<".to_string(),
+            Predicate::LessEqual => "<=".to_string(),
+            Predicate::Greater => ">".to_string(),
+            Predicate::GreaterEqual => ">=".to_string(),
+            Predicate::Matches => "=~".to_string(),
+            Predicate::MatchesRegex(_) => "~=".to_string(),
         }
     }
 }
@@ -321,13 +1057,35 @@ impl Limit {
         Self {
             namespace: namespace.into(),
             max_value,
+            soft_max_value: None,
             seconds,
+            milliseconds: None,
             name: None,
+            priority: 0,
+            cost: None,
+            max_values: None,
+            jitter: None,
+            group: None,
+            levels: Vec::new(),
+            enabled: true,
+            shadow: false,
             conditions: conditions
                 .into_iter()
                 .map(|cond| cond.try_into().expect("Invalid condition"))
                 .collect(),
+            methods: None,
             variables: variables.into_iter().map(|var| var.into()).collect(),
+            optional_variables: HashSet::new(),
+            window_type: WindowType::Fixed,
+            refill_rate: None,
+            leak_rate: None,
+            distinct_variable: None,
+            sliding_buckets: None,
+            shards: None,
+            calendar_window: None,
+            window_start: WindowStart::FirstHit,
+            shared_counter: None,
+            fallback: false,
         }
     }
 
@@ -335,6 +1093,15 @@ impl Limit {
         &self.namespace
     }
 
+    /// The limit as it applies to the requests of `namespace`, e.g. one of
+    /// the [`WILDCARD_NAMESPACE`], so that its counters are kept apart for
+    /// each namespace.
+    pub fn in_namespace(&self, namespace: &Namespace) -> Limit {
+        let mut limit = self.clone();
+        limit.namespace = namespace.clone();
+        limit
+    }
+
     pub fn max_value(&self) -> i64 {
         self.max_value
     }
@@ -343,6 +1110,27 @@ impl Limit {
         self.seconds
     }
 
+    /// The length of the windows in milliseconds, taking precedence over
+    /// `seconds` when set, for the windows that last less than a second, or
+    /// a fraction of seconds. `seconds` is then only kept for compatibility.
+    pub fn milliseconds(&self) -> Option<u64> {
+        self.milliseconds
+    }
+
+    pub fn set_milliseconds(&mut self, milliseconds: Option<u64>) {
+        self.milliseconds = milliseconds;
+    }
+
+    /// The length of the windows of the limit, as set by `milliseconds` or
+    /// else `seconds`. Calendar windows have lengths of their own, see
+    /// [`Limit::window_at`].
+    pub fn window(&self) -> Duration {
+        match self.milliseconds {
+            Some(milliseconds) => Duration::from_millis(milliseconds),
+            None => Duration::from_secs(self.seconds),
+        }
+    }
+
     pub fn name(&self) -> Option<&str> {
         self.name.as_deref()
     }
@@ -355,75 +1143,435 @@ impl Limit {
         self.max_value = value;
     }
 
-    pub fn conditions(&self) -> HashSet<String> {
-        self.conditions
-            .iter()
-            .map(|cond| cond.clone().into())
-            .collect()
+    /// The limits that apply to a request are evaluated from the highest
+    /// priority to the lowest one, and the most restrictive one reported is
+    /// picked among those of the highest priority. Defaults to `0`.
+    pub fn priority(&self) -> i32 {
+        self.priority
     }
 
-    pub fn variables(&self) -> HashSet<String> {
-        self.variables.iter().map(|var| var.into()).collect()
+    pub fn set_priority(&mut self, priority: i32) {
+        self.priority = priority;
     }
 
-    #[cfg(feature = "disk_storage")]
-    pub(crate) fn variables_for_key(&self) -> Vec<&str> {
-        let mut variables = Vec::with_capacity(self.variables.len());
-        for var in &self.variables {
-            variables.push(var.as_str());
-        }
-        variables.sort();
-        variables
+    pub fn cost(&self) -> Option<&Cost> {
+        self.cost.as_ref()
     }
 
-    pub fn has_variable(&self, var: &str) -> bool {
-        self.variables.contains(var)
+    pub fn set_cost(&mut self, cost: Option<Cost>) {
+        self.cost = cost;
     }
 
-    pub fn applies(&self, values: &HashMap<String, String>) -> bool {
-        let all_conditions_apply = self
-            .conditions
-            .iter()
-            .all(|cond| Self::condition_applies(cond, values));
+    /// The max values looked up by the value of a descriptor entry, in place
+    /// of `max_value`, if any.
+    pub fn max_values(&self) -> Option<&MaxValues> {
+        self.max_values.as_ref()
+    }
 
-        let all_vars_are_set = self.variables.iter().all(|var| values.contains_key(var));
+    pub fn set_max_values(&mut self, max_values: Option<MaxValues>) {
+        self.max_values = max_values;
+    }
 
-        all_conditions_apply && all_vars_are_set
+    /// The max value the hits of a request with these descriptor entries are
+    /// checked against.
+    pub fn max_value_for(&self, values: &HashMap<String, String>) -> i64 {
+        self.max_values
+            .as_ref()
+            .and_then(|max_values| max_values.of(values))
+            .unwrap_or(self.max_value)
     }
 
-    fn condition_applies(condition: &Condition, values: &HashMap<String, String>) -> bool {
-        let left_operand = condition.var_name.as_str();
-        let right_operand = condition.operand.as_str();
+    /// The hits past which the requests are still let through, up to the max
+    /// value, but flagged as soft limited, e.g. to warn the clients of a limit
+    /// before enforcing it. See [`CheckResult::outcome`].
+    ///
+    /// [`CheckResult::outcome`]: crate::CheckResult::outcome
+    pub fn soft_max_value(&self) -> Option<i64> {
+        self.soft_max_value
+    }
 
-        match values.get(left_operand) {
-            Some(val) => condition.predicate.test(val, right_operand),
-            None => false,
-        }
+    pub fn set_soft_max_value(&mut self, soft_max_value: Option<i64>) {
+        self.soft_max_value = soft_max_value;
     }
-}
 
-impl Hash for Limit {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.namespace.hash(state);
-        self.seconds.hash(state);
-        self.conditions.iter().for_each(|e| e.hash(state));
-        self.variables.iter().for_each(|e| e.hash(state));
+    /// Up to how many seconds past the end of their fixed windows the counters
+    /// of the limit expire, each of them by an offset of its own, so that the
+    /// counters whose windows end together don't all reset at once. The offset
+    /// only depends on the counter, so all the instances of Limitador agree on
+    /// it. Only applies to [`WindowType::Fixed`] limits.
+    pub fn jitter(&self) -> Option<u64> {
+        self.jitter
     }
-}
 
-impl PartialEq for Limit {
-    fn eq(&self, other: &Self) -> bool {
-        self.namespace == other.namespace
-            && self.seconds == other.seconds
-            && self.conditions == other.conditions
-            && self.variables == other.variables
+    pub fn set_jitter(&mut self, jitter: Option<u64>) {
+        self.jitter = jitter;
     }
-}
 
-mod conditions {
-    use std::error::Error;
-    use std::fmt::{Debug, Display, Formatter};
-    use std::num::IntErrorKind;
+    /// The group of limits this one is a tier of, e.g. a burst limit along
+    /// with a sustained one. The limits of a group apply to the same requests,
+    /// all of them being checked at once, and the tier a request is over is
+    /// reported along with the group.
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    pub fn set_group(&mut self, group: Option<String>) {
+        self.group = group;
+    }
+
+    /// The nested windows of the limit, e.g. per minute, hour and day, that
+    /// [`Limit::into_levels`] splits it into. A limit isn't split on its own:
+    /// until it is, its levels are ignored.
+    pub fn levels(&self) -> &[WindowLevel] {
+        &self.levels
+    }
+
+    pub fn set_levels(&mut self, levels: Vec<WindowLevel>) {
+        self.levels = levels;
+    }
+
+    /// Splits a limit of nested windows into a group of limits, one for each
+    /// of its levels, with the `seconds` and `max_value` of the level, and
+    /// everything else of the limit. They count the same hits over windows of
+    /// their own, and are all checked at once, so that a request over any of
+    /// them is limited, the level it's over being reported as the tier of the
+    /// group. The group is the one of the limit, or else its name, and each
+    /// limit is named after its level. A limit without levels is left as is.
+    pub fn into_levels(mut self) -> Vec<Limit> {
+        if self.levels.is_empty() {
+            return vec![self];
+        }
+        let levels = std::mem::take(&mut self.levels);
+        let name = self.name.take();
+        if self.group.is_none() {
+            self.group = name;
+        }
+        levels
+            .into_iter()
+            .map(|level| {
+                let mut limit = self.clone();
+                limit.name = Some(level.name);
+                limit.seconds = level.seconds;
+                limit.milliseconds = None;
+                limit.max_value = level.max_value;
+                limit
+            })
+            .collect()
+    }
+
+    /// Whether the limit is enforced. A disabled limit applies to no request,
+    /// so it neither counts hits nor limits, yet its counters are kept for
+    /// when it's enabled again.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// A shadow limit counts the hits of the requests it applies to, but never
+    /// limits them, only recording the ones that would have been in the
+    /// `shadow_limited_calls` metric, e.g. to tune its max value against the
+    /// actual traffic before enforcing it. Its hits are only counted for the
+    /// requests the other limits let through.
+    pub fn is_shadow(&self) -> bool {
+        self.shadow
+    }
+
+    pub fn set_shadow(&mut self, shadow: bool) {
+        self.shadow = shadow;
+    }
+
+    pub fn window_type(&self) -> WindowType {
+        self.window_type
+    }
+
+    pub fn set_window_type(&mut self, window_type: WindowType) {
+        self.window_type = window_type;
+    }
+
+    pub fn refill_rate(&self) -> Option<u64> {
+        self.refill_rate
+    }
+
+    pub fn set_refill_rate(&mut self, refill_rate: Option<u64>) {
+        self.refill_rate = refill_rate;
+    }
+
+    /// Only applies to [`WindowType::LeakyBucket`] limits.
+    pub fn leak_rate(&self) -> Option<u64> {
+        self.leak_rate
+    }
+
+    pub fn set_leak_rate(&mut self, leak_rate: Option<u64>) {
+        self.leak_rate = leak_rate;
+    }
+
+    /// The variable whose distinct values the counters of a
+    /// [`WindowType::Distinct`] limit count, e.g. `source.address` for the
+    /// unique clients of each tenant, if `tenant` is a variable of the
+    /// limit. The limit only applies to the requests that have a value for
+    /// it, and only applies to `Distinct` limits.
+    pub fn distinct_variable(&self) -> Option<&str> {
+        self.distinct_variable.as_deref()
+    }
+
+    pub fn set_distinct_variable(&mut self, distinct_variable: Option<String>) {
+        self.distinct_variable = distinct_variable;
+    }
+
+    /// Only applies to [`WindowType::Sliding`] limits, whose windows are a
+    /// single bucket when not set.
+    pub fn sliding_buckets(&self) -> Option<u64> {
+        self.sliding_buckets
+    }
+
+    pub fn set_sliding_buckets(&mut self, sliding_buckets: Option<u64>) {
+        self.sliding_buckets = sliding_buckets;
+    }
+
+    /// Only applies to [`WindowType::Fixed`] limits, and only to the storages
+    /// that shard their counters, i.e. the Redis ones: the hits of each
+    /// counter are then spread across that many keys, that are summed for
+    /// the decision. Not sharded when not set.
+    pub fn shards(&self) -> Option<u64> {
+        self.shards
+    }
+
+    pub fn set_shards(&mut self, shards: Option<u64>) {
+        self.shards = shards;
+    }
+
+    /// Only applies to [`WindowType::Fixed`] limits.
+    pub fn calendar_window(&self) -> Option<CalendarWindow> {
+        self.calendar_window
+    }
+
+    pub fn set_calendar_window(&mut self, calendar_window: Option<CalendarWindow>) {
+        self.calendar_window = calendar_window;
+    }
+
+    /// Only applies to [`WindowType::Fixed`] limits, the calendar windows
+    /// being aligned to the clock already.
+    pub fn window_start(&self) -> WindowStart {
+        self.window_start
+    }
+
+    pub fn set_window_start(&mut self, window_start: WindowStart) {
+        self.window_start = window_start;
+    }
+
+    /// The name of the counter this limit shares with the other limits of the
+    /// namespace that go by it, and have the same window and variables. The
+    /// hits of the requests any of them applies to count in the same counters,
+    /// one per value of the variables, that each limit checks against its own
+    /// max value.
+    pub fn shared_counter(&self) -> Option<&str> {
+        self.shared_counter.as_deref()
+    }
+
+    pub fn set_shared_counter(&mut self, shared_counter: Option<String>) {
+        self.shared_counter = shared_counter;
+    }
+
+    /// A fallback limit only applies to the requests that none of the other
+    /// limits of its namespace apply to, whatever their priorities, e.g. to
+    /// bound the traffic that no specific limit was defined for.
+    pub fn is_fallback(&self) -> bool {
+        self.fallback
+    }
+
+    pub fn set_fallback(&mut self, fallback: bool) {
+        self.fallback = fallback;
+    }
+
+    /// The limit the counters of this one are kept under: itself, unless it
+    /// shares them, in which case its conditions are left out, so that it's
+    /// the same for all the limits sharing them.
+    pub fn counter_limit(&self) -> Limit {
+        self.clone().into_counter_limit()
+    }
+
+    pub(crate) fn into_counter_limit(mut self) -> Limit {
+        if self.shared_counter.is_some() {
+            self.conditions.clear();
+            self.methods = None;
+        }
+        self
+    }
+
+    /// How long the fixed window of a counter that starts at `now` lasts:
+    /// the limit's [`Limit::window`], or up to the end of its calendar window,
+    /// or of its window aligned to the clock.
+    pub fn window_at(&self, now: SystemTime) -> Duration {
+        match self.calendar_window {
+            Some(calendar_window) => calendar_window
+                .ends_at(now)
+                .duration_since(now)
+                .unwrap_or(Duration::ZERO),
+            None if self.window_start == WindowStart::Clock && !self.window().is_zero() => {
+                let window = self.window().as_millis();
+                let since_epoch = now
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                Duration::from_millis((window - since_epoch % window) as u64)
+            }
+            None => self.window(),
+        }
+    }
+
+    pub fn conditions(&self) -> HashSet<String> {
+        self.conditions
+            .iter()
+            .map(|cond| cond.clone().into())
+            .collect()
+    }
+
+    /// The HTTP methods of the requests the limit applies to, on top of its
+    /// conditions, all of them when none are set. They are matched against
+    /// the `req.method` entry, as a `req.method in "..."` condition would.
+    pub fn methods(&self) -> Vec<HttpMethod> {
+        self.methods
+            .as_ref()
+            .map(http_methods::of)
+            .unwrap_or_default()
+    }
+
+    pub fn set_methods(&mut self, methods: impl IntoIterator<Item = HttpMethod>) {
+        self.methods = http_methods::condition(methods);
+    }
+
+    pub fn variables(&self) -> HashSet<String> {
+        self.variables.iter().map(|var| var.into()).collect()
+    }
+
+    /// The variables that, unlike the others, don't need to be set for the
+    /// limit to apply. They qualify its counters only when they are: the
+    /// requests without a value for one of them count in the same counter,
+    /// apart from the counters of those that have one.
+    pub fn optional_variables(&self) -> HashSet<String> {
+        self.optional_variables.clone()
+    }
+
+    /// The `optional_variables` that are already among the variables of the
+    /// limit are left out, as those are required.
+    pub fn set_optional_variables(
+        &mut self,
+        optional_variables: impl IntoIterator<Item = impl Into<String>>,
+    ) {
+        self.optional_variables = optional_variables
+            .into_iter()
+            .map(|var| var.into())
+            .filter(|var| !self.variables.contains(var))
+            .collect();
+    }
+
+    #[cfg(feature = "disk_storage")]
+    pub(crate) fn variables_for_key(&self) -> Vec<&str> {
+        let mut variables = Vec::with_capacity(self.variables.len());
+        for var in &self.variables {
+            variables.push(var.as_str());
+        }
+        variables.sort();
+        variables
+    }
+
+    #[cfg(feature = "disk_storage")]
+    pub(crate) fn optional_variables_for_key(&self) -> Vec<&str> {
+        let mut variables: Vec<&str> = self.optional_variables.iter().map(String::as_str).collect();
+        variables.sort();
+        variables
+    }
+
+    pub fn has_variable(&self, var: &str) -> bool {
+        self.variables.contains(var) || self.optional_variables.contains(var)
+    }
+
+    pub fn applies(&self, values: &HashMap<String, String>) -> bool {
+        // The methods rule out requests the cheapest, so go first
+        if let Some(methods) = &self.methods {
+            if !Self::condition_applies(methods, values) {
+                return false;
+            }
+        }
+
+        let all_conditions_apply = self
+            .conditions
+            .iter()
+            .all(|cond| Self::condition_applies(cond, values));
+
+        let all_vars_are_set = self
+            .variables
+            .iter()
+            .chain(self.distinct_variable.iter())
+            .all(|var| values.contains_key(var));
+
+        all_conditions_apply && all_vars_are_set
+    }
+
+    fn condition_applies(condition: &Condition, values: &HashMap<String, String>) -> bool {
+        let left_operand = condition.var_name.as_str();
+        let right_operand = condition.operand.as_str();
+
+        match values.get(left_operand) {
+            Some(val) => condition
+                .predicate
+                .test(val, right_operand, condition.case_insensitive),
+            None => false,
+        }
+    }
+}
+
+impl Hash for Limit {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.namespace.hash(state);
+        self.seconds.hash(state);
+        self.milliseconds.hash(state);
+        self.conditions.iter().for_each(|e| e.hash(state));
+        self.methods.hash(state);
+        self.variables.iter().for_each(|e| e.hash(state));
+        self.optional_variables.iter().for_each(|e| e.hash(state));
+        self.window_type.hash(state);
+        self.refill_rate.hash(state);
+        self.leak_rate.hash(state);
+        self.distinct_variable.hash(state);
+        self.sliding_buckets.hash(state);
+        self.shards.hash(state);
+        self.calendar_window.hash(state);
+        self.window_start.hash(state);
+        self.shared_counter.hash(state);
+        self.fallback.hash(state);
+    }
+}
+
+impl PartialEq for Limit {
+    fn eq(&self, other: &Self) -> bool {
+        self.namespace == other.namespace
+            && self.seconds == other.seconds
+            && self.milliseconds == other.milliseconds
+            && self.conditions == other.conditions
+            && self.methods == other.methods
+            && self.variables == other.variables
+            && self.optional_variables == other.optional_variables
+            && self.window_type == other.window_type
+            && self.refill_rate == other.refill_rate
+            && self.leak_rate == other.leak_rate
+            && self.distinct_variable == other.distinct_variable
+            && self.sliding_buckets == other.sliding_buckets
+            && self.shards == other.shards
+            && self.calendar_window == other.calendar_window
+            && self.window_start == other.window_start
+            && self.shared_counter == other.shared_counter
+            && self.fallback == other.fallback
+    }
+}
+
+mod conditions {
+    use std::error::Error;
+    use std::fmt::{Debug, Display, Formatter};
+    use std::num::IntErrorKind;
 
     #[derive(Debug)]
     pub struct SyntaxError {
@@ -438,6 +1586,8 @@ mod conditions {
         InvalidCharacter(char),
         InvalidNumber,
         UnclosedStringLiteral(char),
+        InvalidEscapeSequence,
+        InvalidRegex(String),
     }
 
     impl Display for SyntaxError {
@@ -459,6 +1609,20 @@ mod conditions {
                 ErrorType::MissingToken => {
                     write!(f, "SyntaxError: Expected token at offset {}", self.pos)
                 }
+                ErrorType::InvalidEscapeSequence => {
+                    write!(
+                        f,
+                        "SyntaxError: Invalid escape sequence at offset {}",
+                        self.pos
+                    )
+                }
+                ErrorType::InvalidRegex(reason) => {
+                    write!(
+                        f,
+                        "SyntaxError: Invalid regular expression at offset {}: {}",
+                        self.pos, reason
+                    )
+                }
                 ErrorType::UnclosedStringLiteral(char) => {
                     write!(f, "SyntaxError: Missing closing `{}` for string literal starting at offset {}", char, self.pos)
                 }
@@ -473,6 +1637,17 @@ mod conditions {
         // Predicates
         EqualEqual,
         NotEqual,
+        In,
+        NotIn,
+        Less,
+        LessEqual,
+        Greater,
+        GreaterEqual,
+        Matches,
+        MatchesRegex,
+
+        // The `i` flag of a string literal, right after it
+        IgnoreCase,
 
         //Literals
         Identifier,
@@ -509,6 +1684,15 @@ mod conditions {
             match self.token_type {
                 TokenType::EqualEqual => write!(f, "Equality (==)"),
                 TokenType::NotEqual => write!(f, "Unequal (!=)"),
+                TokenType::In => write!(f, "Membership (in)"),
+                TokenType::NotIn => write!(f, "Non-membership (not in)"),
+                TokenType::Less => write!(f, "Less than (<)"),
+                TokenType::LessEqual => write!(f, "Less than or equal (<=)"),
+                TokenType::Greater => write!(f, "Greater than (>)"),
+                TokenType::GreaterEqual => write!(f, "Greater than or equal (>=)"),
+                TokenType::Matches => write!(f, "Glob match (=~)"),
+                TokenType::MatchesRegex => write!(f, "Regex match (~=)"),
+                TokenType::IgnoreCase => write!(f, "Case insensitivity flag (i)"),
                 TokenType::Identifier => {
                     write!(f, "Identifier: {}", self.literal.as_ref().unwrap())
                 }
@@ -522,6 +1706,10 @@ mod conditions {
         }
     }
 
+    fn is_id_char(char: char) -> bool {
+        char.is_alphanumeric() || char == '.' || char == '_'
+    }
+
     pub struct Scanner {
         input: Vec<char>,
         pos: usize,
@@ -538,7 +1726,15 @@ mod conditions {
                 match scanner.next_token() {
                     Ok(token) => {
                         if let Some(token) = token {
-                            tokens.push(token)
+                            let string = token.token_type == TokenType::String;
+                            tokens.push(token);
+                            if string && scanner.next_flag_matches('i') {
+                                tokens.push(Token {
+                                    token_type: TokenType::IgnoreCase,
+                                    literal: None,
+                                    pos: scanner.pos,
+                                });
+                            }
                         }
                     }
                     Err(err) => {
@@ -559,6 +1755,12 @@ mod conditions {
                             literal: None,
                             pos: self.pos - 1,
                         }))
+                    } else if self.next_matches('~') {
+                        Ok(Some(Token {
+                            token_type: TokenType::Matches,
+                            literal: None,
+                            pos: self.pos - 1,
+                        }))
                     } else {
                         Err(SyntaxError {
                             pos: self.pos,
@@ -566,6 +1768,46 @@ mod conditions {
                         })
                     }
                 }
+                '~' => {
+                    if self.next_matches('=') {
+                        Ok(Some(Token {
+                            token_type: TokenType::MatchesRegex,
+                            literal: None,
+                            pos: self.pos - 1,
+                        }))
+                    } else {
+                        Err(SyntaxError {
+                            pos: self.pos,
+                            error: ErrorType::InvalidCharacter(self.input[self.pos - 1]),
+                        })
+                    }
+                }
+                '<' => {
+                    let pos = self.pos;
+                    let token_type = if self.next_matches('=') {
+                        TokenType::LessEqual
+                    } else {
+                        TokenType::Less
+                    };
+                    Ok(Some(Token {
+                        token_type,
+                        literal: None,
+                        pos,
+                    }))
+                }
+                '>' => {
+                    let pos = self.pos;
+                    let token_type = if self.next_matches('=') {
+                        TokenType::GreaterEqual
+                    } else {
+                        TokenType::Greater
+                    };
+                    Ok(Some(Token {
+                        token_type,
+                        literal: None,
+                        pos,
+                    }))
+                }
                 '!' => {
                     if self.next_matches('=') {
                         Ok(Some(Token {
@@ -602,18 +1844,59 @@ mod conditions {
             while !self.done() && self.valid_id_char() {
                 self.advance();
             }
+            let identifier: String = self.input[start - 1..self.pos].iter().collect();
+            let token_type = match identifier.as_str() {
+                "in" => TokenType::In,
+                "not" if self.next_word_matches("in") => TokenType::NotIn,
+                _ => TokenType::Identifier,
+            };
             Ok(Token {
-                token_type: TokenType::Identifier,
-                literal: Some(Literal::Identifier(
-                    self.input[start - 1..self.pos].iter().collect(),
-                )),
+                literal: match token_type {
+                    TokenType::Identifier => Some(Literal::Identifier(identifier)),
+                    _ => None,
+                },
+                token_type,
                 pos: start,
             })
         }
 
+        // Consumes the next word, past any whitespaces, only if it is `word`
+        fn next_word_matches(&mut self, word: &str) -> bool {
+            let mut end = self.pos;
+            while end < self.input.len() && self.input[end].is_whitespace() {
+                end += 1;
+            }
+            if end == self.pos {
+                return false;
+            }
+            for c in word.chars() {
+                if end >= self.input.len() || self.input[end] != c {
+                    return false;
+                }
+                end += 1;
+            }
+            if end < self.input.len() && is_id_char(self.input[end]) {
+                return false;
+            }
+            self.pos = end;
+            true
+        }
+
+        // Consumes the flag, only if it's right there and not the start of a word
+        fn next_flag_matches(&mut self, flag: char) -> bool {
+            let end = self.pos + 1;
+            if self.done()
+                || self.input[self.pos] != flag
+                || (end < self.input.len() && is_id_char(self.input[end]))
+            {
+                return false;
+            }
+            self.pos = end;
+            true
+        }
+
         fn valid_id_char(&mut self) -> bool {
-            let char = self.input[self.pos];
-            char.is_alphanumeric() || char == '.' || char == '_'
+            is_id_char(self.input[self.pos])
         }
 
         fn scan_string(&mut self, until: char) -> Result<Token, SyntaxError> {
@@ -898,6 +2181,20 @@ mod tests {
         assert!(!limit.applies(&values))
     }
 
+    #[test]
+    fn limit_applies_when_optional_var_not_set() {
+        let mut limit = Limit::new("test_namespace", 10, 60, vec!["x == \"5\""], vec!["y"]);
+        limit.set_optional_variables(["y", "z"]);
+        assert_eq!(limit.optional_variables(), HashSet::from(["z".to_string()]));
+
+        // Notice that "z" is not set
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("x".into(), "5".into());
+        values.insert("y".into(), "1".into());
+
+        assert!(limit.applies(&values))
+    }
+
     #[test]
     fn limit_applies_when_all_its_conditions_apply() {
         let limit = Limit::new(
@@ -934,6 +2231,366 @@ mod tests {
         assert!(!limit.applies(&values))
     }
 
+    #[test]
+    fn limit_applies_when_value_is_in_list() {
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![
+                "req.path in \"/a, /b,/c\"",
+                "req.method not in \"PUT,DELETE\"",
+            ],
+            Vec::<String>::default(),
+        );
+
+        let values = |path: &str, method: &str| {
+            HashMap::from([
+                ("req.path".to_string(), path.to_string()),
+                ("req.method".to_string(), method.to_string()),
+            ])
+        };
+
+        assert!(limit.applies(&values("/a", "GET")));
+        assert!(limit.applies(&values("/b", "POST")));
+        assert!(!limit.applies(&values("/d", "GET")));
+        assert!(!limit.applies(&values("/c", "DELETE")));
+    }
+
+    #[test]
+    fn case_insensitive_condition_parsing() {
+        let result: Condition = "method == 'get'i".try_into().expect("Should parse");
+        assert!(result.case_insensitive);
+        assert_eq!(String::from(result), r#"method == "get"i"#);
+
+        let result: Condition = "x =~ \"/API/*\"i".try_into().expect("Should parse");
+        assert_eq!(result.predicate, Predicate::Matches);
+        assert!(result.case_insensitive);
+
+        // Not the flag, but the start of a word
+        let result = Condition::try_from("x == 'a'in").expect_err("should fail parsing");
+        assert_eq!(
+            result.to_string(),
+            "SyntaxError: Unexpected token `Membership (in)` at offset 9 of condition \"x == 'a'in\""
+        );
+
+        let result = Condition::try_from("'get'i == method").expect_err("should fail parsing");
+        assert_eq!(
+            result.to_string(),
+            "SyntaxError: Unexpected token `Case insensitivity flag (i)` at offset 6 of condition \"'get'i == method\""
+        );
+
+        let result = Condition::try_from("x < '5'i").expect_err("should fail parsing");
+        assert_eq!(
+            result.to_string(),
+            "SyntaxError: Unexpected token `Case insensitivity flag (i)` at offset 8 of condition \"x < '5'i\""
+        );
+    }
+
+    #[test]
+    fn limit_with_case_insensitive_conditions_applies_whatever_the_case() {
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![
+                "req.method in 'get,head'i",
+                "req.path =~ '/API/*'i",
+                "req.host != 'internal'i",
+            ],
+            Vec::<String>::new(),
+        );
+        let values = |method: &str, path: &str, host: &str| {
+            HashMap::from([
+                ("req.method".to_string(), method.to_string()),
+                ("req.path".to_string(), path.to_string()),
+                ("req.host".to_string(), host.to_string()),
+            ])
+        };
+
+        assert!(limit.applies(&values("GET", "/api/v1", "example.com")));
+        assert!(limit.applies(&values("get", "/Api/v1", "example.com")));
+        assert!(limit.applies(&values("Head", "/API/", "example.com")));
+        assert!(!limit.applies(&values("POST", "/api/v1", "example.com")));
+        assert!(!limit.applies(&values("GET", "/v1/api", "example.com")));
+        assert!(!limit.applies(&values("GET", "/api/v1", "Internal")));
+
+        let case_sensitive = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["req.method == 'GET'"],
+            Vec::<String>::new(),
+        );
+        assert!(!case_sensitive.applies(&values("get", "/api/v1", "example.com")));
+        assert_ne!(
+            case_sensitive,
+            Limit::new(
+                "test_namespace",
+                10,
+                60,
+                vec!["req.method == 'GET'i"],
+                Vec::<String>::new(),
+            )
+        );
+    }
+
+    #[test]
+    fn membership_condition_parsing() {
+        let result: Condition =
+            serde_json::from_str(r#""x not  in '1,2'""#).expect("Should deserialize");
+        assert_eq!(
+            result,
+            Condition {
+                var_name: "x".to_string(),
+                predicate: Predicate::NotIn,
+                operand: "1,2".to_string(),
+                case_insensitive: false,
+            }
+        );
+        assert_eq!(String::from(result), r#"x not in "1,2""#);
+
+        let result: Condition = "not == 'in'".try_into().expect("Should parse");
+        assert_eq!(result.var_name, "not");
+
+        let result = Condition::try_from("'1,2' in x").expect_err("should fail parsing");
+        assert_eq!(
+            result.to_string(),
+            "SyntaxError: Unexpected token `Membership (in)` at offset 7 of condition \"'1,2' in x\""
+        );
+    }
+
+    #[test]
+    fn limit_applies_when_numeric_comparison_holds() {
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["request.size > 1024", "request.size <= '4096.5'"],
+            Vec::<String>::default(),
+        );
+
+        let values = |size: &str| HashMap::from([("request.size".to_string(), size.to_string())]);
+
+        assert!(limit.applies(&values("1025")));
+        assert!(limit.applies(&values(" 4096.5 ")));
+        assert!(!limit.applies(&values("1024")));
+        assert!(!limit.applies(&values("5000")));
+        assert!(!limit.applies(&values("big")));
+        assert!(!limit.applies(&values("2000kb")));
+        assert!(!limit.applies(&values("")));
+        assert!(!limit.applies(&values("NaN")));
+    }
+
+    #[test]
+    fn comparison_condition_parsing() {
+        let result: Condition = "x >= '-1.5'".try_into().expect("Should parse");
+        assert_eq!(result.predicate, Predicate::GreaterEqual);
+        assert_eq!(String::from(result), r#"x >= "-1.5""#);
+
+        let result: Condition = "x<3".try_into().expect("Should parse");
+        assert_eq!(result.predicate, Predicate::Less);
+        assert_eq!(result.operand, "3");
+
+        let result = Condition::try_from("x > 'abc'").expect_err("should fail parsing");
+        assert_eq!(
+            result.to_string(),
+            "SyntaxError: Invalid number at offset 5 of condition \"x > 'abc'\""
+        );
+
+        let result = Condition::try_from("'3' < x").expect_err("should fail parsing");
+        assert_eq!(
+            result.to_string(),
+            "SyntaxError: Unexpected token `Less than (<)` at offset 5 of condition \"'3' < x\""
+        );
+    }
+
+    #[test]
+    fn limit_applies_when_value_matches_glob() {
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["req.path =~ '/api/v1/*'"],
+            Vec::<String>::default(),
+        );
+
+        let values = |path: &str| HashMap::from([("req.path".to_string(), path.to_string())]);
+
+        assert!(limit.applies(&values("/api/v1/")));
+        assert!(limit.applies(&values("/api/v1/users/42")));
+        assert!(!limit.applies(&values("/api/v2/users")));
+        assert!(!limit.applies(&values("/api/v1")));
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob::matches("*", ""));
+        assert!(glob::matches("a*b*c", "aXbYbZc"));
+        assert!(glob::matches("*.example.org", "api.example.org"));
+        assert!(!glob::matches("*.example.org", "example.org"));
+        assert!(glob::matches("a*a", "aaa"));
+        assert!(!glob::matches("abc", "abcd"));
+        assert!(glob::matches("ü*ü", "üxü"));
+        assert!(glob::matches(r"a\*", "a*"));
+        assert!(!glob::matches(r"a\*", "ab"));
+        assert!(glob::matches(r"a\\*", r"a\bc"));
+        assert!(!glob::matches(r"a\\", "a"));
+    }
+
+    #[test]
+    fn regex_condition_parsing() {
+        let result: Condition = r"req.path ~= '^/api/v[0-9]+/'"
+            .try_into()
+            .expect("Should parse");
+        assert!(matches!(result.predicate, Predicate::MatchesRegex(_)));
+        assert_eq!(String::from(result), r#"req.path ~= "^/api/v[0-9]+/""#);
+
+        let result = Condition::try_from("x ~= '^/api/(v1'").expect_err("should fail parsing");
+        assert_eq!(
+            result.to_string(),
+            r#"SyntaxError: Invalid regular expression at offset 6: unclosed group of condition "x ~= '^/api/(v1'""#
+        );
+
+        let result = Condition::try_from("x ~ '.*'").expect_err("should fail parsing");
+        assert_eq!(
+            result.to_string(),
+            r#"SyntaxError: Invalid character `~` at offset 3 of condition "x ~ '.*'""#
+        );
+    }
+
+    #[test]
+    fn regex_condition_applies() {
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![
+                r"req.path ~= '^/api/v[0-9]+/'",
+                "req.method ~= '^(get|head)$'i",
+            ],
+            Vec::<String>::new(),
+        );
+        let values = |path: &str, method: &str| {
+            HashMap::from([
+                ("req.path".to_string(), path.to_string()),
+                ("req.method".to_string(), method.to_string()),
+            ])
+        };
+
+        assert!(limit.applies(&values("/api/v1/users", "GET")));
+        assert!(limit.applies(&values("/api/v42/", "head")));
+        assert!(!limit.applies(&values("/api/vX/users", "GET")));
+        assert!(!limit.applies(&values("/v1/api/v1/", "GET")));
+        assert!(!limit.applies(&values("/api/v1/users", "POST")));
+    }
+
+    #[test]
+    fn limit_of_nested_windows_is_split_into_a_group_of_its_levels() {
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","name":"api","conditions":[],"variables":["user_id"],"levels":[{"name":"minute","seconds":60,"max_value":10},{"name":"hour","seconds":3600,"max_value":100}]}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(
+            limit.levels(),
+            [
+                WindowLevel::new("minute", 60, 10),
+                WindowLevel::new("hour", 3600, 100)
+            ]
+        );
+
+        let levels = limit.into_levels();
+        assert_eq!(
+            levels
+                .iter()
+                .map(|limit| (limit.name(), limit.seconds(), limit.max_value()))
+                .collect::<Vec<_>>(),
+            vec![(Some("minute"), 60, 10), (Some("hour"), 3600, 100)]
+        );
+        for level in &levels {
+            assert_eq!(level.group(), Some("api"));
+            assert_eq!(level.variables(), HashSet::from(["user_id".to_string()]));
+            assert!(level.levels().is_empty());
+        }
+
+        let limit = Limit::new("ns", 10, 60, Vec::<String>::new(), vec!["user_id"]);
+        assert_eq!(limit.clone().into_levels(), vec![limit]);
+    }
+
+    #[test]
+    fn limit_can_be_restricted_to_methods() {
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","max_value":10,"seconds":60,"conditions":["req.path == '/api'"],"variables":[],"methods":["POST","GET","POST"]}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(limit.methods(), vec![HttpMethod::Get, HttpMethod::Post]);
+        assert_eq!(
+            limit.conditions(),
+            HashSet::from(["req.path == \"/api\"".to_string()])
+        );
+
+        let values = |path: &str, method: &str| {
+            HashMap::from([
+                ("req.path".to_string(), path.to_string()),
+                ("req.method".to_string(), method.to_string()),
+            ])
+        };
+        assert!(limit.applies(&values("/api", "GET")));
+        assert!(limit.applies(&values("/api", "POST")));
+        assert!(!limit.applies(&values("/api", "DELETE")));
+        assert!(!limit.applies(&values("/other", "GET")));
+        assert!(!limit.applies(&HashMap::from([(
+            "req.path".to_string(),
+            "/api".to_string()
+        )])));
+
+        // The same as the condition it desugars to
+        let desugared = Limit::new(
+            "ns",
+            10,
+            60,
+            vec!["req.path == '/api'", "req.method in 'GET,POST'"],
+            Vec::<String>::new(),
+        );
+        for method in ["GET", "POST", "DELETE", "get"] {
+            assert_eq!(
+                limit.applies(&values("/api", method)),
+                desugared.applies(&values("/api", method))
+            );
+        }
+
+        let serialized = serde_json::to_string(&limit).unwrap();
+        assert!(serialized.contains(r#""methods":["GET","POST"]"#));
+        assert_eq!(serde_json::from_str::<Limit>(&serialized).unwrap(), limit);
+
+        let mut other = limit.clone();
+        other.set_methods(vec![HttpMethod::Get]);
+        assert_ne!(limit, other);
+        other.set_methods(vec![]);
+        assert!(other.methods().is_empty());
+        assert!(other.applies(&values("/api", "DELETE")));
+
+        assert!(serde_json::from_str::<Limit>(
+            r#"{"namespace":"ns","max_value":10,"seconds":60,"conditions":[],"variables":[],"methods":["FETCH"]}"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn glob_condition_parsing() {
+        let result: Condition = r"x =~ '*\*'".try_into().expect("Should parse");
+        assert_eq!(result.predicate, Predicate::Matches);
+        assert_eq!(String::from(result), r#"x =~ "*\*""#);
+
+        let result = Condition::try_from(r"x =~ 'a\b'").expect_err("should fail parsing");
+        assert_eq!(
+            result.to_string(),
+            r#"SyntaxError: Invalid escape sequence at offset 7 of condition "x =~ 'a\b'""#
+        );
+
+        Condition::try_from(r"x =~ 'a\'").expect_err("trailing escape should fail parsing");
+    }
+
     #[test]
     fn valid_condition_literal_parsing() {
         let result: Condition = serde_json::from_str(r#""x == '5'""#).expect("Should deserialize");
@@ -943,6 +2600,7 @@ mod tests {
                 var_name: "x".to_string(),
                 predicate: Predicate::Equal,
                 operand: "5".to_string(),
+                case_insensitive: false,
             }
         );
 
@@ -954,6 +2612,7 @@ mod tests {
                 var_name: "foobar".to_string(),
                 predicate: Predicate::Equal,
                 operand: "ok".to_string(),
+                case_insensitive: false,
             }
         );
 
@@ -965,6 +2624,7 @@ mod tests {
                 var_name: "foobar".to_string(),
                 predicate: Predicate::Equal,
                 operand: "ok".to_string(),
+                case_insensitive: false,
             }
         );
     }
@@ -977,6 +2637,21 @@ mod tests {
             .expect("Should fail!");
     }
 
+    #[test]
+    fn limits_are_enabled_unless_deserialized_as_not() {
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace": "ns", "max_value": 10, "seconds": 60, "conditions": [], "variables": []}"#,
+        )
+        .expect("Should deserialize");
+        assert!(limit.is_enabled());
+
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace": "ns", "max_value": 10, "seconds": 60, "conditions": [], "variables": [], "enabled": false}"#,
+        )
+        .expect("Should deserialize");
+        assert!(!limit.is_enabled());
+    }
+
     #[test]
     fn invalid_condition_parsing() {
         let result = serde_json::from_str::<Condition>(r#""x != 5 && x > 12""#)
@@ -994,8 +2669,257 @@ mod tests {
             var_name: "foobar".to_string(),
             predicate: Predicate::Equal,
             operand: "ok".to_string(),
+            case_insensitive: false,
         };
         let result = serde_json::to_string(&condition).expect("Should serialize");
         assert_eq!(result, r#""foobar == \"ok\"""#.to_string());
     }
+
+    #[test]
+    fn limit_window_type_defaults_to_fixed() {
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","max_value":10,"seconds":60,"conditions":[],"variables":[]}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(limit.window_type(), WindowType::Fixed);
+        // Fixed windows don't show up, so that the counter keys stay the same
+        assert!(!serde_json::to_string(&limit)
+            .unwrap()
+            .contains("window_type"));
+
+        let sliding: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","max_value":10,"seconds":60,"conditions":[],"variables":[],"window_type":"Sliding"}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(sliding.window_type(), WindowType::Sliding);
+        assert_ne!(limit, sliding);
+    }
+
+    #[test]
+    fn limit_can_be_a_token_bucket() {
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","max_value":10,"seconds":60,"conditions":[],"variables":[],"window_type":"TokenBucket","refill_rate":2}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(limit.window_type(), WindowType::TokenBucket);
+        assert_eq!(limit.refill_rate(), Some(2));
+
+        let mut other = limit.clone();
+        other.set_refill_rate(Some(5));
+        assert_ne!(limit, other);
+    }
+
+    #[test]
+    fn limit_can_be_a_leaky_bucket() {
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","max_value":10,"seconds":60,"conditions":[],"variables":[],"window_type":"LeakyBucket","leak_rate":2}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(limit.window_type(), WindowType::LeakyBucket);
+        assert_eq!(limit.leak_rate(), Some(2));
+
+        let mut other = limit.clone();
+        other.set_leak_rate(Some(5));
+        assert_ne!(limit, other);
+    }
+
+    #[test]
+    fn distinct_limit_only_applies_with_a_value_for_its_distinct_variable() {
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","max_value":10,"seconds":60,"conditions":[],"variables":["tenant"],"window_type":"Distinct","distinct_variable":"source.address"}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(limit.window_type(), WindowType::Distinct);
+        assert_eq!(limit.distinct_variable(), Some("source.address"));
+
+        let mut values = HashMap::from([("tenant".to_string(), "a".to_string())]);
+        assert!(!limit.applies(&values));
+        values.insert("source.address".to_string(), "10.0.0.1".to_string());
+        assert!(limit.applies(&values));
+
+        let mut other = limit.clone();
+        other.set_distinct_variable(Some("user".to_string()));
+        assert_ne!(limit, other);
+    }
+
+    #[test]
+    fn limit_can_be_a_sliding_window_of_buckets() {
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","max_value":10,"seconds":60,"conditions":[],"variables":[],"window_type":"Sliding","sliding_buckets":6}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(limit.window_type(), WindowType::Sliding);
+        assert_eq!(limit.sliding_buckets(), Some(6));
+
+        let mut other = limit.clone();
+        other.set_sliding_buckets(None);
+        assert_ne!(limit, other);
+    }
+
+    #[test]
+    fn limit_can_be_sharded() {
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","max_value":1000000,"seconds":1,"conditions":[],"variables":[],"shards":8}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(limit.window_type(), WindowType::Fixed);
+        assert_eq!(limit.shards(), Some(8));
+
+        let mut other = limit.clone();
+        other.set_shards(None);
+        assert_ne!(limit, other);
+        assert!(!serde_json::to_string(&other).unwrap().contains("shards"));
+    }
+
+    #[test]
+    fn limit_can_have_a_window_of_milliseconds() {
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","max_value":10,"milliseconds":250,"conditions":[],"variables":[]}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(limit.seconds(), 0);
+        assert_eq!(limit.window(), Duration::from_millis(250));
+        assert_eq!(
+            limit.window_at(SystemTime::now()),
+            Duration::from_millis(250)
+        );
+
+        let seconds = Limit::new("ns", 10, 60, Vec::<&str>::new(), Vec::<&str>::new());
+        assert_eq!(seconds.window(), Duration::from_secs(60));
+        let mut other = seconds.clone();
+        other.set_milliseconds(Some(60_000));
+        assert_eq!(other.window(), seconds.window());
+        assert_ne!(other, seconds);
+    }
+
+    fn at(rfc3339: &str) -> SystemTime {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().into()
+    }
+
+    #[test]
+    fn limit_can_be_aligned_to_the_calendar() {
+        let limit: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","max_value":10,"seconds":60,"conditions":[],"variables":[],"calendar_window":{"period":"Monthly","timezone":"Europe/Madrid"}}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(
+            limit.calendar_window(),
+            Some(CalendarWindow::new(
+                CalendarPeriod::Monthly,
+                Tz::Europe__Madrid
+            ))
+        );
+
+        let utc: Limit = serde_json::from_str(
+            r#"{"namespace":"ns","max_value":10,"seconds":60,"conditions":[],"variables":[],"calendar_window":{"period":"Monthly"}}"#,
+        )
+        .expect("Should deserialize");
+        assert_eq!(utc.calendar_window().unwrap().timezone(), Tz::UTC);
+        assert_ne!(limit, utc);
+
+        assert!(serde_json::from_str::<Limit>(
+            r#"{"namespace":"ns","max_value":10,"seconds":60,"conditions":[],"variables":[],"calendar_window":{"period":"Monthly","timezone":"Mars/Olympus_Mons"}}"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn windows_aligned_to_the_clock_end_on_its_boundaries() {
+        let mut limit = Limit::new("ns", 10, 60, Vec::<String>::new(), Vec::<String>::new());
+        assert_eq!(
+            limit.window_at(at("2024-05-15T12:00:30Z")),
+            Duration::from_secs(60)
+        );
+
+        limit.set_window_start(WindowStart::Clock);
+        // Whenever the counters start, their windows end on the minute
+        for now in [
+            "2024-05-15T12:00:00Z",
+            "2024-05-15T12:00:30Z",
+            "2024-05-15T12:00:59.999Z",
+        ] {
+            assert_eq!(
+                at(now) + limit.window_at(at(now)),
+                at("2024-05-15T12:01:00Z")
+            );
+        }
+
+        limit.set_milliseconds(Some(250));
+        assert_eq!(
+            limit.window_at(at("2024-05-15T12:00:00.100Z")),
+            Duration::from_millis(150)
+        );
+    }
+
+    #[test]
+    fn calendar_windows_end_at_the_next_boundary() {
+        let monthly = CalendarWindow::new(CalendarPeriod::Monthly, Tz::UTC);
+        assert_eq!(
+            monthly.ends_at(at("2024-01-31T23:59:59.999Z")),
+            at("2024-02-01T00:00:00Z")
+        );
+        // The boundary itself starts the next window
+        assert_eq!(
+            monthly.ends_at(at("2024-02-01T00:00:00Z")),
+            at("2024-03-01T00:00:00Z")
+        );
+        assert_eq!(
+            monthly.ends_at(at("2024-12-15T08:00:00Z")),
+            at("2025-01-01T00:00:00Z")
+        );
+
+        let weekly = CalendarWindow::new(CalendarPeriod::Weekly, Tz::UTC);
+        assert_eq!(
+            weekly.ends_at(at("2024-05-15T12:00:00Z")),
+            at("2024-05-20T00:00:00Z")
+        );
+        assert_eq!(
+            weekly.ends_at(at("2024-05-20T00:00:00Z")),
+            at("2024-05-27T00:00:00Z")
+        );
+
+        let daily = CalendarWindow::new(CalendarPeriod::Daily, Tz::UTC);
+        assert_eq!(
+            daily.ends_at(at("2024-02-28T10:00:00Z")),
+            at("2024-02-29T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn calendar_windows_follow_the_clocks_of_their_timezone() {
+        let monthly = CalendarWindow::new(CalendarPeriod::Monthly, Tz::Europe__Madrid);
+        // April starts at 22:00 UTC, as Madrid is on summer time by then...
+        assert_eq!(
+            monthly.ends_at(at("2024-03-31T21:59:59Z")),
+            at("2024-03-31T22:00:00Z")
+        );
+        // ... and it's already April there, while still March in UTC
+        assert_eq!(
+            monthly.ends_at(at("2024-03-31T22:30:00Z")),
+            at("2024-04-30T22:00:00Z")
+        );
+        // Back on winter time
+        assert_eq!(
+            monthly.ends_at(at("2024-11-15T00:00:00Z")),
+            at("2024-11-30T23:00:00Z")
+        );
+
+        let mut limit = Limit::new("ns", 10, 86400, Vec::<String>::new(), Vec::<String>::new());
+        limit.set_calendar_window(Some(CalendarWindow::new(
+            CalendarPeriod::Daily,
+            Tz::Europe__Madrid,
+        )));
+        // The day the clocks are set forward only lasts 23 hours
+        assert_eq!(
+            limit.window_at(at("2024-03-30T23:00:00Z")),
+            Duration::from_secs(23 * 3600)
+        );
+
+        // Chile sets its clocks forward at midnight: that day starts at 1am
+        let daily = CalendarWindow::new(CalendarPeriod::Daily, Tz::America__Santiago);
+        assert_eq!(
+            daily.ends_at(at("2024-09-07T12:00:00Z")),
+            at("2024-09-08T04:00:00Z")
+        );
+    }
 }
@@ -18,6 +18,14 @@ lazy_static! {
         name: "limited_calls".into(),
         description: "Limited calls".into(),
     };
+    static ref SOFT_LIMITED_CALLS: Metric = Metric {
+        name: "soft_limited_calls".into(),
+        description: "Calls let through past the soft max value of a limit".into(),
+    };
+    static ref SHADOW_LIMITED_CALLS: Metric = Metric {
+        name: "shadow_limited_calls".into(),
+        description: "Calls that shadow limits would have limited".into(),
+    };
     static ref LIMITADOR_UP: Metric = Metric { // Can be used as a simple health check
         name: "limitador_up".into(),
         description: "Limitador is running".into(),
@@ -28,6 +36,8 @@ pub struct PrometheusMetrics {
     registry: Registry,
     authorized_calls: IntCounterVec,
     limited_calls: IntCounterVec,
+    soft_limited_calls: IntCounterVec,
+    shadow_limited_calls: IntCounterVec,
     use_limit_name_label: bool,
 }
 
@@ -65,6 +75,30 @@ impl PrometheusMetrics {
         self.limited_calls.with_label_values(&labels).inc();
     }
 
+    pub fn incr_soft_limited_calls<'a, LN>(&self, namespace: &Namespace, limit_name: LN)
+    where
+        LN: Into<Option<&'a str>>,
+    {
+        let mut labels = vec![namespace.as_ref()];
+
+        if self.use_limit_name_label {
+            labels.push(limit_name.into().unwrap_or(""));
+        }
+
+        self.soft_limited_calls.with_label_values(&labels).inc();
+    }
+
+    // Always labeled with the name of the limit, as there are few shadow limits
+    // at a time, and telling them apart is what they're for
+    pub fn incr_shadow_limited_calls<'a, LN>(&self, namespace: &Namespace, limit_name: LN)
+    where
+        LN: Into<Option<&'a str>>,
+    {
+        self.shadow_limited_calls
+            .with_label_values(&[namespace.as_ref(), limit_name.into().unwrap_or("")])
+            .inc();
+    }
+
     pub fn gather_metrics(&self) -> String {
         let mut buffer = Vec::new();
 
@@ -78,6 +112,8 @@ impl PrometheusMetrics {
     fn new_with_options(use_limit_name_label: bool) -> Self {
         let authorized_calls_counter = Self::authorized_calls_counter();
         let limited_calls_counter = Self::limited_calls_counter(use_limit_name_label);
+        let soft_limited_calls_counter = Self::soft_limited_calls_counter(use_limit_name_label);
+        let shadow_limited_calls_counter = Self::shadow_limited_calls_counter();
         let limitador_up_gauge = Self::limitador_up_gauge();
 
         let registry = Registry::new();
@@ -90,6 +126,14 @@ impl PrometheusMetrics {
             .register(Box::new(limited_calls_counter.clone()))
             .unwrap();
 
+        registry
+            .register(Box::new(soft_limited_calls_counter.clone()))
+            .unwrap();
+
+        registry
+            .register(Box::new(shadow_limited_calls_counter.clone()))
+            .unwrap();
+
         registry
             .register(Box::new(limitador_up_gauge.clone()))
             .unwrap();
@@ -100,6 +144,8 @@ impl PrometheusMetrics {
             registry,
             authorized_calls: authorized_calls_counter,
             limited_calls: limited_calls_counter,
+            soft_limited_calls: soft_limited_calls_counter,
+            shadow_limited_calls: shadow_limited_calls_counter,
             use_limit_name_label,
         }
     }
@@ -126,6 +172,31 @@ impl PrometheusMetrics {
         .unwrap()
     }
 
+    fn soft_limited_calls_counter(use_limit_name_label: bool) -> IntCounterVec {
+        let mut labels = vec![NAMESPACE_LABEL];
+
+        if use_limit_name_label {
+            labels.push(LIMIT_NAME_LABEL);
+        }
+
+        IntCounterVec::new(
+            Opts::new(&SOFT_LIMITED_CALLS.name, &SOFT_LIMITED_CALLS.description),
+            &labels,
+        )
+        .unwrap()
+    }
+
+    fn shadow_limited_calls_counter() -> IntCounterVec {
+        IntCounterVec::new(
+            Opts::new(
+                &SHADOW_LIMITED_CALLS.name,
+                &SHADOW_LIMITED_CALLS.description,
+            ),
+            &[NAMESPACE_LABEL, LIMIT_NAME_LABEL],
+        )
+        .unwrap()
+    }
+
     fn limitador_up_gauge() -> IntGauge {
         IntGauge::new(&LIMITADOR_UP.name, &LIMITADOR_UP.description).unwrap()
     }
@@ -246,6 +317,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn can_show_soft_limited_calls_by_limit_name() {
+        let prometheus_metrics = PrometheusMetrics::new_with_counters_by_limit_name();
+        let namespace = "some_namespace".into();
+        prometheus_metrics.incr_soft_limited_calls(&namespace, "Some limit");
+        prometheus_metrics.incr_soft_limited_calls(&namespace, "Some limit");
+
+        let metrics_output = prometheus_metrics.gather_metrics();
+
+        assert!(
+            metrics_output.contains(&formatted_counter_with_namespace_and_limit(
+                &SOFT_LIMITED_CALLS.name,
+                2,
+                &namespace,
+                "Some limit",
+            ))
+        );
+    }
+
+    #[test]
+    fn shows_shadow_limited_calls_by_limit_name() {
+        let prometheus_metrics = PrometheusMetrics::new();
+        let namespace = "some_namespace".into();
+        prometheus_metrics.incr_shadow_limited_calls(&namespace, "Some limit");
+        prometheus_metrics.incr_shadow_limited_calls(&namespace, "Some limit");
+
+        let metrics_output = prometheus_metrics.gather_metrics();
+
+        assert!(
+            metrics_output.contains(&formatted_counter_with_namespace_and_limit(
+                &SHADOW_LIMITED_CALLS.name,
+                2,
+                &namespace,
+                "Some limit",
+            ))
+        );
+    }
+
     #[test]
     fn shows_limitador_up_set_to_1() {
         let metrics_output = PrometheusMetrics::new().gather_metrics();
@@ -26,12 +26,8 @@ impl AtomicExpiringValue {
         self.value.load(Ordering::SeqCst)
     }
 
-    pub fn value(&self) -> i64 {
-        self.value_at(SystemTime::now())
-    }
-
-    pub fn update(&self, delta: i64, ttl: u64, when: SystemTime) -> i64 {
-        let ttl_micros = ttl * 1_000_000;
+    pub fn update(&self, delta: i64, ttl: Duration, when: SystemTime) -> i64 {
+        let ttl_micros = ttl.as_micros() as u64;
         let when_micros = Self::get_duration_micros(when);
 
         let expiry = self.expiry.load(Ordering::SeqCst);
@@ -46,14 +42,23 @@ impl AtomicExpiringValue {
             }
             return delta;
         }
-        self.value.fetch_add(delta, Ordering::SeqCst) + delta
+        // Saturates, rather than wrapping around, past the bounds of an i64
+        let previous = self
+            .value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                Some(value.saturating_add(delta))
+            })
+            .unwrap();
+        previous.saturating_add(delta)
+    }
+
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.expiry.load(Ordering::SeqCst))
     }
 
-    pub fn ttl(&self) -> Duration {
-        let expiry =
-            SystemTime::UNIX_EPOCH + Duration::from_micros(self.expiry.load(Ordering::SeqCst));
-        expiry
-            .duration_since(SystemTime::now())
+    pub fn ttl_at(&self, when: SystemTime) -> Duration {
+        self.expires_at()
+            .duration_since(when)
             .unwrap_or(Duration::ZERO)
     }
 
@@ -88,6 +93,14 @@ mod tests {
     use std::thread;
     use std::time::{Duration, SystemTime};
 
+    #[test]
+    fn updates_saturate_instead_of_overflowing() {
+        let now = SystemTime::now();
+        let val = AtomicExpiringValue::new(i64::MAX - 1, now + Duration::from_secs(1));
+        assert_eq!(val.update(3, Duration::from_secs(10), now), i64::MAX);
+        assert_eq!(val.value_at(now), i64::MAX);
+    }
+
     #[test]
     fn returns_value_when_valid() {
         let now = SystemTime::now();
@@ -113,7 +126,7 @@ mod tests {
     fn updates_when_valid() {
         let now = SystemTime::now();
         let val = AtomicExpiringValue::new(42, now + Duration::from_secs(1));
-        val.update(3, 10, now);
+        val.update(3, Duration::from_secs(10), now);
         assert_eq!(val.value_at(now - Duration::from_secs(1)), 45);
     }
 
@@ -121,8 +134,8 @@ mod tests {
     fn updates_when_expired() {
         let now = SystemTime::now();
         let val = AtomicExpiringValue::new(42, now);
-        assert_eq!(val.ttl(), Duration::ZERO);
-        val.update(3, 10, now);
+        assert_eq!(val.ttl_at(now), Duration::ZERO);
+        val.update(3, Duration::from_secs(10), now);
         assert_eq!(val.value_at(now - Duration::from_secs(1)), 3);
     }
 
@@ -133,10 +146,14 @@ mod tests {
 
         thread::scope(|s| {
             s.spawn(|| {
-                atomic_expiring_value.update(1, 1, now);
+                atomic_expiring_value.update(1, Duration::from_secs(1), now);
             });
             s.spawn(|| {
-                atomic_expiring_value.update(2, 1, now + Duration::from_secs(11));
+                atomic_expiring_value.update(
+                    2,
+                    Duration::from_secs(1),
+                    now + Duration::from_secs(11),
+                );
             });
         });
         assert!([2i64, 3i64].contains(&atomic_expiring_value.value.load(Ordering::SeqCst)));
@@ -0,0 +1,56 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+// The storages that take a clock tell the time with it, rather than with
+// SystemTime::now(), so that their windows can be driven by tests, or by the
+// host when there's no system clock, e.g. in WASM.
+
+pub trait Clock: Sync + Send {
+    fn get_current_time(&self) -> SystemTime;
+}
+
+/// The clock of the system, the one storages use unless given another.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn get_current_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, e.g. for tests to get windows over
+/// without waiting for them. Clones share the same time, so that a test can
+/// keep one to move that of the storage it gave another to.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<RwLock<SystemTime>>,
+}
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Arc::new(RwLock::new(now)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.write().unwrap() += by;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.write().unwrap() = now;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(SystemTime::now())
+    }
+}
+
+impl Clock for ManualClock {
+    fn get_current_time(&self) -> SystemTime {
+        *self.now.read().unwrap()
+    }
+}
@@ -0,0 +1,382 @@
+use crate::counter::Counter;
+use crate::limit::{Limit, Namespace};
+use crate::storage::{AsyncCounterStorage, Authorization, CounterStorage, StorageErr};
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::time::Duration;
+
+// Routes the counters of each namespace to a storage of their own. Namespaces
+// are matched either exactly, or by prefix when the pattern ends with a "*".
+// The first route that matches wins, the namespaces not matched by any of them
+// end up in the default storage.
+
+struct Routes(Vec<Vec<String>>);
+
+impl Routes {
+    // 0 is the default storage, the routes come after it
+    fn index_for(&self, namespace: &Namespace) -> usize {
+        let namespace = namespace.as_ref();
+        self.0
+            .iter()
+            .position(|patterns| {
+                patterns
+                    .iter()
+                    .any(|pattern| match pattern.strip_suffix('*') {
+                        Some(prefix) => namespace.starts_with(prefix),
+                        None => namespace == pattern,
+                    })
+            })
+            .map(|index| index + 1)
+            .unwrap_or(0)
+    }
+
+    fn group_limits(&self, limits: HashSet<Limit>) -> Vec<HashSet<Limit>> {
+        let mut groups = vec![HashSet::new(); self.0.len() + 1];
+        for limit in limits {
+            groups[self.index_for(limit.namespace())].insert(limit);
+        }
+        groups
+    }
+}
+
+fn add_patterns<I, S>(routes: &mut Routes, namespaces: I)
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    routes
+        .0
+        .push(namespaces.into_iter().map(|ns| ns.into()).collect());
+}
+
+pub struct CompositeStorage {
+    routes: Routes,
+    storages: Vec<Box<dyn CounterStorage>>,
+}
+
+impl CompositeStorage {
+    pub fn new(default: Box<dyn CounterStorage>) -> Self {
+        Self {
+            routes: Routes(vec![]),
+            storages: vec![default],
+        }
+    }
+
+    /// Keeps the counters of the given namespaces in `storage`.
+    pub fn route<I, S>(mut self, namespaces: I, storage: Box<dyn CounterStorage>) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        add_patterns(&mut self.routes, namespaces);
+        self.storages.push(storage);
+        self
+    }
+
+    fn storage_for(&self, namespace: &Namespace) -> &dyn CounterStorage {
+        self.storages[self.routes.index_for(namespace)].as_ref()
+    }
+}
+
+impl CounterStorage for CompositeStorage {
+    fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        self.storage_for(counter.namespace())
+            .is_within_limits(counter, delta)
+    }
+
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.storage_for(limit.namespace()).add_counter(limit)
+    }
+
+    fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        self.storage_for(counter.namespace())
+            .update_counter(counter, delta)
+    }
+
+    fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        // All the counters of a check belong to the same namespace
+        match counters.first() {
+            Some(counter) => self.storage_for(counter.namespace()).check_and_update(
+                counters,
+                delta,
+                load_counters,
+            ),
+            None => Ok(Authorization::Ok),
+        }
+    }
+
+    fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        self.storage_for(counter.namespace())
+            .set_counter(counter, remaining, expires_in)
+    }
+
+    fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        self.storage_for(counter.namespace())
+            .delete_counter(counter)
+    }
+
+    fn get_counters(&self, limits: &HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
+        let mut res = HashSet::new();
+        for (storage, limits) in self
+            .storages
+            .iter()
+            .zip(self.routes.group_limits(limits.clone()))
+        {
+            if !limits.is_empty() {
+                res.extend(storage.get_counters(&limits)?);
+            }
+        }
+        Ok(res)
+    }
+
+    fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+        for (storage, limits) in self.storages.iter().zip(self.routes.group_limits(limits)) {
+            if !limits.is_empty() {
+                storage.delete_counters(limits)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), StorageErr> {
+        self.storages.iter().try_for_each(|storage| storage.clear())
+    }
+
+    fn ping(&self) -> Result<(), StorageErr> {
+        self.storages.iter().try_for_each(|storage| storage.ping())
+    }
+}
+
+pub struct AsyncCompositeStorage {
+    routes: Routes,
+    storages: Vec<Box<dyn AsyncCounterStorage>>,
+}
+
+impl AsyncCompositeStorage {
+    pub fn new(default: Box<dyn AsyncCounterStorage>) -> Self {
+        Self {
+            routes: Routes(vec![]),
+            storages: vec![default],
+        }
+    }
+
+    /// Keeps the counters of the given namespaces in `storage`.
+    pub fn route<I, S>(mut self, namespaces: I, storage: Box<dyn AsyncCounterStorage>) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        add_patterns(&mut self.routes, namespaces);
+        self.storages.push(storage);
+        self
+    }
+
+    fn storage_for(&self, namespace: &Namespace) -> &dyn AsyncCounterStorage {
+        self.storages[self.routes.index_for(namespace)].as_ref()
+    }
+}
+
+#[async_trait]
+impl AsyncCounterStorage for AsyncCompositeStorage {
+    async fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        self.storage_for(counter.namespace())
+            .is_within_limits(counter, delta)
+            .await
+    }
+
+    async fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        self.storage_for(counter.namespace())
+            .update_counter(counter, delta)
+            .await
+    }
+
+    async fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        // All the counters of a check belong to the same namespace
+        let storage = match counters.first() {
+            Some(counter) => self.storage_for(counter.namespace()),
+            None => return Ok(Authorization::Ok),
+        };
+        storage
+            .check_and_update(counters, delta, load_counters)
+            .await
+    }
+
+    async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        self.storage_for(counter.namespace())
+            .set_counter(counter, remaining, expires_in)
+            .await
+    }
+
+    async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        self.storage_for(counter.namespace())
+            .delete_counter(counter)
+            .await
+    }
+
+    async fn get_counters(&self, limits: HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
+        let mut res = HashSet::new();
+        for (storage, limits) in self.storages.iter().zip(self.routes.group_limits(limits)) {
+            if !limits.is_empty() {
+                res.extend(storage.get_counters(limits).await?);
+            }
+        }
+        Ok(res)
+    }
+
+    async fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+        for (storage, limits) in self.storages.iter().zip(self.routes.group_limits(limits)) {
+            if !limits.is_empty() {
+                storage.delete_counters(limits).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<(), StorageErr> {
+        for storage in &self.storages {
+            storage.clear().await?;
+        }
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), StorageErr> {
+        for storage in &self.storages {
+            storage.ping().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), StorageErr> {
+        for storage in &self.storages {
+            storage.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Lets a blocking storage be routed to by an [`AsyncCompositeStorage`]. Only
+/// meant for storages that don't actually block, like the in-memory one.
+pub struct AsyncCounterStorageAdapter {
+    storage: Box<dyn CounterStorage>,
+}
+
+impl AsyncCounterStorageAdapter {
+    pub fn new(storage: Box<dyn CounterStorage>) -> Self {
+        Self { storage }
+    }
+}
+
+#[async_trait]
+impl AsyncCounterStorage for AsyncCounterStorageAdapter {
+    async fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        self.storage.is_within_limits(counter, delta)
+    }
+
+    async fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        self.storage.update_counter(counter, delta)
+    }
+
+    async fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        self.storage
+            .check_and_update(counters, delta, load_counters)
+    }
+
+    async fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        self.storage.check_and_update_batch(batch, load_counters)
+    }
+
+    async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        self.storage.set_counter(counter, remaining, expires_in)
+    }
+
+    async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        self.storage.delete_counter(counter)
+    }
+
+    async fn get_counters(&self, limits: HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
+        self.storage.get_counters(&limits)
+    }
+
+    async fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+        self.storage.delete_counters(limits)
+    }
+
+    async fn clear(&self) -> Result<(), StorageErr> {
+        self.storage.clear()
+    }
+
+    async fn ping(&self) -> Result<(), StorageErr> {
+        self.storage.ping()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::in_memory::InMemoryStorage;
+    use std::collections::HashMap;
+
+    fn counter(namespace: &str) -> Counter {
+        let limit = Limit::new(namespace, 1, 60, Vec::<String>::default(), vec!["id"]);
+        Counter::new(limit, HashMap::from([("id".to_string(), "1".to_string())]))
+    }
+
+    #[test]
+    fn routes_by_exact_namespace_or_prefix() {
+        let routed = InMemoryStorage::default();
+        let storage = CompositeStorage::new(Box::<InMemoryStorage>::default())
+            .route(["exact", "prefix_*"], Box::new(routed));
+
+        for namespace in ["exact", "prefix_a", "other", "exact_not"] {
+            storage.update_counter(&counter(namespace), 1).unwrap();
+        }
+
+        let routed_to = |namespace| storage.routes.index_for(&Namespace::from(namespace));
+        assert_eq!(routed_to("exact"), 1);
+        assert_eq!(routed_to("prefix_a"), 1);
+        assert_eq!(routed_to("other"), 0);
+        assert_eq!(routed_to("exact_not"), 0);
+
+        let limits: HashSet<Limit> = ["exact", "prefix_a", "other", "exact_not"]
+            .into_iter()
+            .map(|namespace| counter(namespace).limit().clone())
+            .collect();
+        assert_eq!(storage.storages[1].get_counters(&limits).unwrap().len(), 2);
+        assert_eq!(storage.storages[0].get_counters(&limits).unwrap().len(), 2);
+        assert_eq!(storage.get_counters(&limits).unwrap().len(), 4);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::storage::StorageErr;
+use crate::storage::{StorageErr, StorageErrKind};
 use std::array::TryFromSliceError;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
@@ -25,14 +25,14 @@ impl ExpiringValue {
     }
 
     #[must_use]
-    pub fn update(self, delta: i64, ttl: u64, now: SystemTime) -> Self {
+    pub fn update(self, delta: i64, ttl: Duration, now: SystemTime) -> Self {
         let expiry = if self.expiry <= now {
-            now + Duration::from_secs(ttl)
+            now + ttl
         } else {
             self.expiry
         };
 
-        let value = self.value_at(now) + delta;
+        let value = self.value_at(now).saturating_add(delta);
         Self { value, expiry }
     }
 
@@ -40,7 +40,7 @@ impl ExpiringValue {
     pub fn merge(self, other: ExpiringValue, now: SystemTime) -> Self {
         if self.expiry > now {
             ExpiringValue {
-                value: self.value + other.value,
+                value: self.value.saturating_add(other.value),
                 expiry: self.expiry,
             }
         } else {
@@ -70,13 +70,19 @@ impl TryFrom<&[u8]> for ExpiringValue {
     fn try_from(raw: &[u8]) -> Result<Self, Self::Error> {
         let raw_val: [u8; 8] = raw[0..8].try_into()?;
         let raw_exp: [u8; 8] = raw[8..16].try_into()?;
+        // the values written before the expiry had sub-second precision lack it
+        let raw_nanos: [u8; 4] = match raw.get(16..20) {
+            Some(raw_nanos) => raw_nanos.try_into()?,
+            None => [0; 4],
+        };
 
         let val = i64::from_be_bytes(raw_val);
         let exp = u64::from_be_bytes(raw_exp);
+        let nanos = u32::from_be_bytes(raw_nanos);
 
         Ok(Self {
             value: val,
-            expiry: UNIX_EPOCH + Duration::from_secs(exp),
+            expiry: UNIX_EPOCH + Duration::new(exp, nanos),
         })
     }
 }
@@ -84,13 +90,13 @@ impl TryFrom<&[u8]> for ExpiringValue {
 impl From<ExpiringValue> for Vec<u8> {
     fn from(value: ExpiringValue) -> Self {
         let val: [u8; 8] = value.value.to_be_bytes();
-        let exp: [u8; 8] = value
+        let expiry = value
             .expiry
             .duration_since(UNIX_EPOCH)
-            .expect("Can't expire before Epoch")
-            .as_secs()
-            .to_be_bytes();
-        [val, exp].concat()
+            .expect("Can't expire before Epoch");
+        let exp: [u8; 8] = expiry.as_secs().to_be_bytes();
+        let nanos: [u8; 4] = expiry.subsec_nanos().to_be_bytes();
+        [&val[..], &exp[..], &nanos[..]].concat()
     }
 }
 
@@ -98,6 +104,7 @@ impl From<TryFromSliceError> for StorageErr {
     fn from(_: TryFromSliceError) -> Self {
         Self {
             msg: "Corrupted byte sequence while reading 8 bytes for 64-bit integer".to_owned(),
+            kind: StorageErrKind::Serialization,
         }
     }
 }
@@ -131,7 +138,11 @@ mod tests {
     #[test]
     fn updates_when_valid() {
         let now = SystemTime::now();
-        let val = ExpiringValue::new(42, now + Duration::from_secs(1)).update(3, 10, now);
+        let val = ExpiringValue::new(42, now + Duration::from_secs(1)).update(
+            3,
+            Duration::from_secs(10),
+            now,
+        );
         assert_eq!(val.value_at(now - Duration::from_secs(1)), 45);
     }
 
@@ -140,7 +151,7 @@ mod tests {
         let now = SystemTime::now();
         let val = ExpiringValue::new(42, now);
         assert_eq!(val.ttl(), Duration::ZERO);
-        let val = val.update(3, 10, now);
+        let val = val.update(3, Duration::from_secs(10), now);
         assert_eq!(val.value_at(now - Duration::from_secs(1)), 3);
     }
 
@@ -156,5 +167,16 @@ mod tests {
             back.expiry.duration_since(UNIX_EPOCH).unwrap().as_secs(),
             now.duration_since(UNIX_EPOCH).unwrap().as_secs()
         );
+        assert_eq!(back.expiry, now);
+    }
+
+    #[test]
+    fn reads_values_without_sub_second_expiry() {
+        let mut raw = 42i64.to_be_bytes().to_vec();
+        raw.extend(1_000u64.to_be_bytes());
+        let back: ExpiringValue = raw.as_slice().try_into().unwrap();
+
+        assert_eq!(back.value, 42);
+        assert_eq!(back.expiry, UNIX_EPOCH + Duration::from_secs(1_000));
     }
 }
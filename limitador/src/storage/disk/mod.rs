@@ -1,4 +1,4 @@
-use crate::storage::StorageErr;
+use crate::storage::{StorageErr, StorageErrKind};
 
 mod expiring_value;
 mod rocksdb_storage;
@@ -9,6 +9,7 @@ impl From<rocksdb::Error> for StorageErr {
     fn from(error: rocksdb::Error) -> Self {
         Self {
             msg: format!("Underlying storage error: {error}"),
+            kind: StorageErrKind::Other,
         }
     }
 }
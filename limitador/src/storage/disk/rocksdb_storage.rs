@@ -1,11 +1,13 @@
 use crate::counter::Counter;
-use crate::limit::Limit;
+use crate::limit::{Limit, WindowStart};
 use crate::storage::disk::expiring_value::ExpiringValue;
 use crate::storage::disk::OptimizeFor;
 use crate::storage::keys::bin::{
     key_for_counter, partial_counter_from_counter_key, prefix_for_namespace,
 };
-use crate::storage::{Authorization, CounterStorage, StorageErr};
+use crate::storage::{
+    only_fixed_windows, Authorization, CounterStorage, OverflowPolicy, StorageErr, StorageErrKind,
+};
 use rocksdb::{
     CompactionDecision, DBCompressionType, DBWithThreadMode, IteratorMode, MultiThreaded, Options,
     DB,
@@ -15,13 +17,54 @@ use std::time::{Duration, SystemTime};
 
 pub struct RocksDbStorage {
     db: DBWithThreadMode<MultiThreaded>,
+    overflow_policy: OverflowPolicy,
+}
+
+// The keys of the counters only hold the seconds and the conditions of their
+// limit, so a limit aligned to the calendar, or to the clock, or restricted to
+// some methods, would share its counters with the one that isn't.
+fn only_windows_of_seconds(counters: &[Counter]) -> Result<(), StorageErr> {
+    only_fixed_windows(counters)?;
+    if let Some(calendar_window) = counters
+        .iter()
+        .find_map(|counter| counter.limit().calendar_window())
+    {
+        return Err(StorageErr {
+            msg: format!(
+                "{:?} calendar windows are not supported by this storage",
+                calendar_window.period()
+            ),
+            kind: StorageErrKind::Configuration,
+        });
+    }
+    if counters
+        .iter()
+        .any(|counter| counter.limit().window_start() == WindowStart::Clock)
+    {
+        return Err(StorageErr {
+            msg: "windows aligned to the clock are not supported by this storage".to_string(),
+            kind: StorageErrKind::Configuration,
+        });
+    }
+    if counters
+        .iter()
+        .any(|counter| !counter.limit().methods().is_empty())
+    {
+        return Err(StorageErr {
+            msg: "limits restricted to some methods are not supported by this storage".to_string(),
+            kind: StorageErrKind::Configuration,
+        });
+    }
+    Ok(())
 }
 
 impl CounterStorage for RocksDbStorage {
     fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        only_windows_of_seconds(std::slice::from_ref(counter))?;
         let key = key_for_counter(counter);
         let value = self.insert_or_update(&key, counter, 0)?;
-        Ok(counter.max_value() >= value.value() + delta)
+        self.overflow_policy
+            .is_within_limit(counter, value.value(), counter.hits_for(delta))
     }
 
     fn add_counter(&self, _limit: &Limit) -> Result<(), StorageErr> {
@@ -29,6 +72,7 @@ impl CounterStorage for RocksDbStorage {
     }
 
     fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        only_windows_of_seconds(std::slice::from_ref(counter))?;
         let key = key_for_counter(counter);
         self.insert_or_update(&key, counter, delta)?;
         Ok(())
@@ -40,13 +84,15 @@ impl CounterStorage for RocksDbStorage {
         delta: i64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
+        only_windows_of_seconds(counters)?;
         let mut keys: Vec<Vec<u8>> = Vec::with_capacity(counters.len());
 
         for counter in &mut *counters {
+            let delta = counter.hits_for(delta);
             let key = key_for_counter(counter);
             let slice: &[u8] = key.as_ref();
             let (val, ttl) = match self.db.get(slice)? {
-                None => (0, Duration::from_secs(counter.limit().seconds())),
+                None => (0, counter.window_at(SystemTime::now())),
                 Some(raw) => {
                     let slice: &[u8] = raw.as_ref();
                     let value: ExpiringValue = slice.try_into()?;
@@ -56,10 +102,14 @@ impl CounterStorage for RocksDbStorage {
 
             if load_counters {
                 counter.set_expires_in(ttl);
-                counter.set_remaining(counter.max_value() - val - delta);
+                counter.set_remaining(
+                    counter
+                        .max_value()
+                        .saturating_sub(val.saturating_add(delta)),
+                );
             }
 
-            if counter.max_value() < val + delta {
+            if !self.overflow_policy.is_within_limit(counter, val, delta)? {
                 return Ok(Authorization::Limited(
                     counter.limit().name().map(|n| n.to_string()),
                 ));
@@ -75,6 +125,35 @@ impl CounterStorage for RocksDbStorage {
         Ok(Authorization::Ok)
     }
 
+    fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        only_windows_of_seconds(std::slice::from_ref(counter))?;
+        let value = ExpiringValue::new(
+            counter.max_value() - remaining,
+            SystemTime::now() + expires_in,
+        );
+        self.db.put(
+            key_for_counter(counter),
+            <ExpiringValue as Into<Vec<u8>>>::into(value),
+        )?;
+        Ok(())
+    }
+
+    fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        only_windows_of_seconds(std::slice::from_ref(counter))?;
+        let key = key_for_counter(counter);
+        let Some(value) = self.db.get(&key)? else {
+            return Ok(false);
+        };
+        let value: ExpiringValue = value.as_ref().try_into()?;
+        self.db.delete(key)?;
+        Ok(value.ttl() > Duration::ZERO)
+    }
+
     fn get_counters(&self, limits: &HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
         let mut counters = HashSet::default();
         let namepaces: BTreeSet<&str> = limits.iter().map(|l| l.namespace().as_ref()).collect();
@@ -85,16 +164,14 @@ impl CounterStorage for RocksDbStorage {
                 if counter.namespace().as_ref() != ns {
                     break;
                 }
+                // The counters of the other limits of the namespace
+                let Some(limit) = limits.iter().find(|limit| *limit == counter.limit()) else {
+                    continue;
+                };
                 let value: ExpiringValue = value.as_ref().try_into()?;
-                for limit in limits {
-                    if limit == counter.limit() {
-                        counter.update_to_limit(limit);
-                        let ttl = value.ttl();
-                        counter.set_expires_in(ttl);
-                        counter.set_remaining(limit.max_value() - value.value());
-                        break;
-                    }
-                }
+                counter.update_to_limit(limit);
+                counter.set_expires_in(value.ttl());
+                counter.set_remaining(limit.max_value() - value.value());
                 if counter.expires_in().expect("Duration needs to be set") > Duration::ZERO {
                     counters.insert(counter);
                 }
@@ -153,7 +230,17 @@ impl RocksDbStorage {
         });
         opts.create_if_missing(true);
         let db = DB::open(&opts, path).unwrap();
-        Ok(Self { db })
+        Ok(Self {
+            db,
+            overflow_policy: OverflowPolicy::default(),
+        })
+    }
+
+    /// What to do with the hits that would overflow the counters, saturating
+    /// them by default.
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
     }
 
     fn insert_or_update(
@@ -162,6 +249,7 @@ impl RocksDbStorage {
         counter: &Counter,
         delta: i64,
     ) -> Result<ExpiringValue, StorageErr> {
+        let delta = counter.hits_for(delta);
         let now = SystemTime::now();
         let value = match self.db.get(key)? {
             None => ExpiringValue::default(),
@@ -170,12 +258,14 @@ impl RocksDbStorage {
                 slice.try_into()?
             }
         };
-        if value.value_at(now) + delta <= counter.max_value() {
-            let expiring_value =
-                ExpiringValue::new(delta, now + Duration::from_secs(counter.limit().seconds()));
+        if self
+            .overflow_policy
+            .is_within_limit(counter, value.value_at(now), delta)?
+        {
+            let expiring_value = ExpiringValue::new(delta, now + counter.window_at(now));
             self.db
                 .merge(key, <ExpiringValue as Into<Vec<u8>>>::into(expiring_value))?;
-            return Ok(value.update(delta, counter.seconds(), now));
+            return Ok(value.update(delta, counter.window_at(now), now));
         }
         Ok(value)
     }
@@ -187,7 +277,7 @@ mod tests {
     use crate::counter::Counter;
     use crate::limit::Limit;
     use crate::storage::disk::OptimizeFor;
-    use crate::storage::CounterStorage;
+    use crate::storage::{CounterStorage, OverflowPolicy};
     use std::collections::HashMap;
     use std::fs;
     use std::time::Duration;
@@ -234,4 +324,28 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn rejects_the_hits_overflowing_a_counter_when_told_to() {
+        let limit = Limit::new(
+            "test_namespace",
+            i64::MAX,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+        let counter = Counter::new(limit, HashMap::default());
+
+        let tmp = TempDir::new().expect("We should have a dir!");
+        let storage = RocksDbStorage::open(tmp.path(), OptimizeFor::Space)
+            .expect("We should have a storage")
+            .with_overflow_policy(OverflowPolicy::Reject);
+
+        storage.update_counter(&counter, i64::MAX - 1).unwrap();
+        assert!(storage.is_within_limits(&counter, 1).unwrap());
+        assert!(storage.is_within_limits(&counter, 2).is_err());
+        assert!(storage
+            .check_and_update(&mut vec![counter.clone()], 2, false)
+            .is_err());
+    }
 }
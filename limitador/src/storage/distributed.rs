@@ -0,0 +1,471 @@
+//! Counters held in memory by each of several nodes, that gossip about the
+//! hits they counted, so that each of them limits on an approximation of the
+//! hits of all of them.
+//!
+//! Each node only ever counts its own hits, and tells the others where they
+//! stand, as of when it last counted them. A node keeps what the others told
+//! it as long as it's more recent than what it knew, so that gossip can be
+//! lost, duplicated or reordered, and the nodes still end up agreeing. Only
+//! the counters a node hit since it last gossiped are told about.
+
+use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::in_memory::IdleCounterExpiry;
+use crate::storage::{
+    only_fixed_windows, Authorization, CounterStorage, StorageErr, StorageErrKind,
+};
+use moka::sync::Cache;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Only supports the limits of fixed windows.
+pub struct DistributedInMemoryStorage {
+    node: String,
+    counters: Cache<Counter, Arc<CrCounterValue>>,
+    // The counters hit since the node last gossiped
+    hit: Mutex<HashSet<Counter>>,
+}
+
+impl CounterStorage for DistributedInMemoryStorage {
+    fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        only_fixed_windows([counter])?;
+        let value = self
+            .counters
+            .get(counter)
+            .map_or(0, |value| value.value_at(SystemTime::now()));
+        Ok(counter.max_value() >= value + counter.hits_for(delta))
+    }
+
+    fn add_counter(&self, _limit: &Limit) -> Result<(), StorageErr> {
+        Ok(())
+    }
+
+    fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
+        let now = SystemTime::now();
+        self.value_of(counter)
+            .update(counter.hits_for(delta), counter.window_at(now), now);
+        self.hit.lock().unwrap().insert(counter.clone());
+        Ok(())
+    }
+
+    fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        only_fixed_windows(counters.iter())?;
+        let now = SystemTime::now();
+        let mut first_limited = None;
+        let mut values_to_update = Vec::with_capacity(counters.len());
+
+        for counter in counters.iter_mut() {
+            let delta = counter.hits_for(delta);
+            let value = self.value_of(counter);
+            let hits = value.value_at(now);
+            let remaining = counter.max_value() - (hits + delta);
+            if load_counters {
+                counter.set_remaining(remaining);
+                // An expired counter starts a new window with this very hit
+                counter.set_expires_in(match value.ttl_at(now) {
+                    ttl if ttl.is_zero() => counter.window_at(now),
+                    ttl => ttl,
+                });
+            }
+            if remaining < 0 {
+                let limited = Authorization::Limited(counter.limit().name().map(str::to_owned));
+                if !load_counters {
+                    return Ok(limited);
+                }
+                first_limited.get_or_insert(limited);
+            }
+            values_to_update.push((value, counter.window_at(now), delta));
+        }
+
+        if let Some(limited) = first_limited {
+            return Ok(limited);
+        }
+        let mut hit = self.hit.lock().unwrap();
+        for ((value, window, delta), counter) in values_to_update.into_iter().zip(counters.iter()) {
+            value.update(delta, window, now);
+            hit.insert(counter.clone());
+        }
+        Ok(Authorization::Ok)
+    }
+
+    fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
+        self.value_of(counter).set(
+            counter.max_value() - remaining,
+            SystemTime::now() + expires_in,
+            SystemTime::now(),
+        );
+        self.hit.lock().unwrap().insert(counter.clone());
+        Ok(())
+    }
+
+    // Only the hits this node knows of are deleted, the other nodes still
+    // count theirs, and gossip about them again on their next hits
+    fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        only_fixed_windows([counter])?;
+        Ok(self
+            .counters
+            .remove(counter)
+            .is_some_and(|value| !value.ttl_at(SystemTime::now()).is_zero()))
+    }
+
+    fn get_counters(&self, limits: &HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
+        let now = SystemTime::now();
+        let mut res = HashSet::new();
+        for (counter, value) in self.counters.iter() {
+            // The counters only gossiped about don't know of the max value
+            let Some(limit) = limits.get(counter.limit()) else {
+                continue;
+            };
+            let ttl = value.ttl_at(now);
+            if ttl.is_zero() {
+                continue;
+            }
+            let mut counter_with_val = counter.deref().clone();
+            counter_with_val.update_to_limit(limit);
+            counter_with_val.set_remaining(limit.max_value() - value.value_at(now));
+            counter_with_val.set_expires_in(ttl);
+            res.insert(counter_with_val);
+        }
+        Ok(res)
+    }
+
+    fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+        for (counter, _) in self.counters.iter() {
+            if limits.contains(counter.limit()) {
+                self.counters.invalidate(counter.deref());
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), StorageErr> {
+        self.counters.invalidate_all();
+        Ok(())
+    }
+}
+
+impl DistributedInMemoryStorage {
+    /// Keeps up to `cache_size` counters in memory, counting the hits of this
+    /// `node`, which has to be told apart from all the others gossiping.
+    pub fn new(node: impl Into<String>, cache_size: u64) -> Self {
+        Self {
+            node: node.into(),
+            counters: Cache::builder()
+                .max_capacity(cache_size)
+                .expire_after(IdleCounterExpiry)
+                .build(),
+            hit: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Where the hits of this node stand, for the counters it hit since it
+    /// last gossiped.
+    pub fn gossip(&self) -> Gossip {
+        let hit = std::mem::take(&mut *self.hit.lock().unwrap());
+        let counters = hit
+            .into_iter()
+            .filter_map(|counter| {
+                let hits = self.counters.get(&counter)?.ours();
+                Some(GossipedCounter { counter, hits })
+            })
+            .collect();
+        Gossip {
+            node: self.node.clone(),
+            counters,
+        }
+    }
+
+    /// Takes in what another node gossiped, keeping what's more recent than
+    /// what this one knew of its hits. The gossip of this very node is left
+    /// out.
+    pub fn merge(&self, gossip: Gossip) {
+        if gossip.node == self.node {
+            return;
+        }
+        for GossipedCounter { counter, hits } in gossip.counters {
+            self.value_of(&counter).merge(&gossip.node, hits);
+        }
+    }
+
+    fn value_of(&self, counter: &Counter) -> Arc<CrCounterValue> {
+        self.counters.get_with_by_ref(counter, Default::default)
+    }
+}
+
+/// What a node tells the others of its hits.
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+pub struct Gossip {
+    pub node: String,
+    pub counters: Vec<GossipedCounter>,
+}
+
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+pub struct GossipedCounter {
+    counter: Counter,
+    hits: NodeHits,
+}
+
+impl Gossip {
+    /// Encodes the gossip in as few payloads of up to `max_size` bytes as it
+    /// fits in, each of which can be decoded on its own. The counters that
+    /// don't fit in a payload by themselves are left out.
+    pub fn encode(self, max_size: usize) -> Vec<Vec<u8>> {
+        // What a payload weighs without any counter, and each counter in it
+        let empty = Self {
+            node: self.node.clone(),
+            counters: Vec::new(),
+        };
+        let overhead = serde_json::to_vec(&empty).unwrap().len();
+
+        let mut payloads = Vec::new();
+        let mut chunk = empty;
+        let mut size = overhead;
+        for counter in self.counters {
+            let counter_size = serde_json::to_vec(&counter).unwrap().len() + 1;
+            if overhead + counter_size > max_size {
+                continue;
+            }
+            if size + counter_size > max_size {
+                payloads.push(serde_json::to_vec(&chunk).unwrap());
+                chunk.counters.clear();
+                size = overhead;
+            }
+            chunk.counters.push(counter);
+            size += counter_size;
+        }
+        if !chunk.counters.is_empty() {
+            payloads.push(serde_json::to_vec(&chunk).unwrap());
+        }
+        payloads
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, StorageErr> {
+        serde_json::from_slice(payload).map_err(|e| StorageErr {
+            msg: format!("invalid gossip: {e}"),
+            kind: StorageErrKind::Serialization,
+        })
+    }
+}
+
+// The hits of a node in its window, which it only ever counted at `version`,
+// a timestamp in microseconds that grows with each of its updates
+#[derive(Clone, Copy, Default, PartialEq, Debug, Serialize, Deserialize)]
+struct NodeHits {
+    hits: i64,
+    expires_at: u64,
+    version: u64,
+}
+
+impl NodeHits {
+    fn hits_at(&self, when: u64) -> i64 {
+        if self.expires_at <= when {
+            return 0;
+        }
+        self.hits
+    }
+}
+
+// The hits of a counter, as counted by each of the nodes
+#[derive(Default)]
+struct CrCounterValue {
+    state: Mutex<CrCounterState>,
+}
+
+#[derive(Default)]
+struct CrCounterState {
+    ours: NodeHits,
+    others: HashMap<String, NodeHits>,
+}
+
+impl CrCounterValue {
+    fn value_at(&self, when: SystemTime) -> i64 {
+        let when = micros(when);
+        let state = self.state.lock().unwrap();
+        state.ours.hits_at(when)
+            + state
+                .others
+                .values()
+                .map(|hits| hits.hits_at(when))
+                .sum::<i64>()
+    }
+
+    // Only the window of this node tells when the counter expires
+    fn ttl_at(&self, when: SystemTime) -> Duration {
+        let expires_at = self.state.lock().unwrap().ours.expires_at;
+        Duration::from_micros(expires_at.saturating_sub(micros(when)))
+    }
+
+    fn update(&self, delta: i64, window: Duration, when: SystemTime) {
+        let mut state = self.state.lock().unwrap();
+        let now = micros(when);
+        let ours = &mut state.ours;
+        if ours.expires_at <= now {
+            ours.hits = 0;
+            ours.expires_at = now + window.as_micros() as u64;
+        }
+        ours.hits += delta;
+        ours.version = now.max(ours.version + 1);
+    }
+
+    fn set(&self, hits: i64, expires_at: SystemTime, when: SystemTime) {
+        let mut state = self.state.lock().unwrap();
+        let now = micros(when);
+        // The hits the others counted still count, so only ours are set
+        let others: i64 = state.others.values().map(|hits| hits.hits_at(now)).sum();
+        let ours = &mut state.ours;
+        ours.hits = hits - others;
+        ours.expires_at = micros(expires_at);
+        ours.version = now.max(ours.version + 1);
+    }
+
+    fn ours(&self) -> NodeHits {
+        self.state.lock().unwrap().ours
+    }
+
+    fn merge(&self, node: &str, hits: NodeHits) {
+        let mut state = self.state.lock().unwrap();
+        match state.others.get_mut(node) {
+            Some(known) if known.version >= hits.version => {}
+            Some(known) => *known = hits,
+            None => {
+                state.others.insert(node.to_string(), hits);
+            }
+        }
+    }
+}
+
+fn micros(when: SystemTime) -> u64 {
+    when.duration_since(UNIX_EPOCH)
+        .expect("SystemTime before UNIX EPOCH!")
+        .as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter(max_value: i64) -> Counter {
+        let limit = Limit::new("ns", max_value, 60, vec!["x == '1'"], vec!["z"]);
+        Counter::new(limit, HashMap::from([("z".to_string(), "1".to_string())]))
+    }
+
+    fn gossip_between(a: &DistributedInMemoryStorage, b: &DistributedInMemoryStorage) {
+        for payload in a.gossip().encode(usize::MAX) {
+            b.merge(Gossip::decode(&payload).unwrap());
+        }
+        for payload in b.gossip().encode(usize::MAX) {
+            a.merge(Gossip::decode(&payload).unwrap());
+        }
+    }
+
+    #[test]
+    fn limits_on_the_hits_of_all_the_nodes() {
+        let a = DistributedInMemoryStorage::new("a", 100);
+        let b = DistributedInMemoryStorage::new("b", 100);
+        let counter = counter(4);
+
+        for node in [&a, &a, &b] {
+            let result = node.check_and_update(&mut vec![counter.clone()], 1, false);
+            assert!(matches!(result, Ok(Authorization::Ok)));
+        }
+        gossip_between(&a, &b);
+
+        assert!(b.is_within_limits(&counter, 1).unwrap());
+        assert!(!b.is_within_limits(&counter, 2).unwrap());
+        assert!(matches!(
+            b.check_and_update(&mut vec![counter.clone()], 1, false),
+            Ok(Authorization::Ok)
+        ));
+        gossip_between(&a, &b);
+        assert!(matches!(
+            a.check_and_update(&mut vec![counter.clone()], 1, false),
+            Ok(Authorization::Limited(_))
+        ));
+
+        let limits = HashSet::from([counter.limit().clone()]);
+        for node in [&a, &b] {
+            let counters = node.get_counters(&limits).unwrap();
+            assert_eq!(counters.len(), 1);
+            assert_eq!(counters.iter().next().unwrap().remaining(), Some(0));
+        }
+    }
+
+    #[test]
+    fn merging_is_idempotent_and_ignores_stale_gossip() {
+        let a = DistributedInMemoryStorage::new("a", 100);
+        let b = DistributedInMemoryStorage::new("b", 100);
+        let counter = counter(10);
+
+        a.update_counter(&counter, 2).unwrap();
+        let stale = a.gossip().encode(usize::MAX);
+        a.update_counter(&counter, 3).unwrap();
+        let fresh = a.gossip().encode(usize::MAX);
+
+        for payload in fresh.iter().chain(&fresh).chain(&stale) {
+            b.merge(Gossip::decode(payload).unwrap());
+        }
+        assert!(b.is_within_limits(&counter, 5).unwrap());
+        assert!(!b.is_within_limits(&counter, 6).unwrap());
+
+        // Nothing was hit since, so there is nothing to tell
+        assert!(a.gossip().counters.is_empty());
+        // Nor is a node told about its own hits
+        a.merge(Gossip::decode(&fresh[0]).unwrap());
+        assert!(a.is_within_limits(&counter, 5).unwrap());
+    }
+
+    #[test]
+    fn setting_a_counter_leaves_the_hits_of_the_others() {
+        let a = DistributedInMemoryStorage::new("a", 100);
+        let b = DistributedInMemoryStorage::new("b", 100);
+        let counter = counter(10);
+
+        b.update_counter(&counter, 4).unwrap();
+        gossip_between(&a, &b);
+        a.set_counter(&counter, 7, Duration::from_secs(60)).unwrap();
+        assert!(a.is_within_limits(&counter, 7).unwrap());
+        assert!(!a.is_within_limits(&counter, 8).unwrap());
+
+        gossip_between(&a, &b);
+        assert!(b.is_within_limits(&counter, 7).unwrap());
+        assert!(!b.is_within_limits(&counter, 8).unwrap());
+    }
+
+    #[test]
+    fn gossip_is_split_in_payloads_of_bounded_size() {
+        let a = DistributedInMemoryStorage::new("a", 100);
+        let b = DistributedInMemoryStorage::new("b", 100);
+        let limit = Limit::new("ns", 10, 60, Vec::<String>::new(), vec!["z"]);
+        for z in 0..20 {
+            let counter = Counter::new(limit.clone(), HashMap::from([("z".into(), z.to_string())]));
+            a.update_counter(&counter, 1).unwrap();
+        }
+
+        let payloads = a.gossip().encode(1_000);
+        assert!(payloads.len() > 1);
+        for payload in payloads {
+            assert!(payload.len() <= 1_000);
+            b.merge(Gossip::decode(&payload).unwrap());
+        }
+        assert_eq!(b.counters.iter().count(), 20);
+    }
+}
@@ -0,0 +1,455 @@
+use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::keys::*;
+use crate::storage::{
+    only_fixed_windows, AsyncCounterStorage, Authorization, StorageErr, StorageErrKind,
+};
+use async_trait::async_trait;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_dynamodb::error::{BuildError, DisplayErrorContext, SdkError};
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue, TransactWriteItem, Update};
+use aws_sdk_dynamodb::Client;
+use futures::future::try_join_all;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Each counter is an item of the table, keyed by `key`. It holds the hits
+// consumed in its current window, as `hits`, when that window ends, in
+// milliseconds since the epoch, as `expires_at`, and the key of its limit, as
+// `limit`, so that the counters of a limit can be found. `ttl` lets DynamoDB
+// delete the counters of past windows, but as it only does so eventually,
+// `expires_at` is what tells whether a window is over.
+//
+// Unlike Redis, where a Lua script checks and updates all the counters of a
+// request at once, DynamoDB runs no logic of its own. The counters are read,
+// and then updated in a single transaction, where each update is conditioned
+// on its counter still being in the window read and, when checking the
+// limits, on it having room for the hits. Either all the counters of a
+// request are updated, or none is. When a condition fails, because a
+// concurrent request consumed the last hits or started a new window, the
+// whole check is retried from the read, at most MAX_ATTEMPTS times. That
+// makes the checks as accurate as with Redis, at the cost of at least two
+// round trips per request, and of transactional writes.
+
+const KEY: &str = "key";
+const HITS: &str = "hits";
+const EXPIRES_AT: &str = "expires_at";
+const TTL: &str = "ttl";
+const LIMIT: &str = "limit";
+
+const MAX_ATTEMPTS: usize = 5;
+
+pub struct DynamoDbStorage {
+    client: Client,
+    table_name: String,
+}
+
+// A counter, as read from the table
+struct Window {
+    hits: i64,
+    expires_at: u64,
+}
+
+#[async_trait]
+impl AsyncCounterStorage for DynamoDbStorage {
+    async fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        only_fixed_windows([counter])?;
+        let hits = self
+            .read_window(&key_for_counter(counter), now_millis())
+            .await?
+            .map_or(0, |window| window.hits);
+        Ok(counter.max_value() - hits - counter.hits_for(delta) >= 0)
+    }
+
+    async fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
+        let mut counters = [counter.clone()];
+        self.update(&mut counters, delta, false, false).await?;
+        Ok(())
+    }
+
+    async fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        only_fixed_windows(counters.iter())?;
+        self.update(counters, delta, true, load_counters).await
+    }
+
+    async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
+        let expires_at = now_millis() + expires_in.as_millis() as u64;
+        self.client
+            .put_item()
+            .table_name(&self.table_name)
+            .item(KEY, AttributeValue::S(key_for_counter(counter)))
+            .item(HITS, number(counter.max_value() - remaining))
+            .item(EXPIRES_AT, number(expires_at))
+            .item(TTL, number(expires_at / 1000 + 1))
+            .item(
+                LIMIT,
+                AttributeValue::S(key_for_counters_of_limit(counter.limit())),
+            )
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        only_fixed_windows([counter])?;
+        let deleted = self
+            .client
+            .delete_item()
+            .table_name(&self.table_name)
+            .key(KEY, AttributeValue::S(key_for_counter(counter)))
+            .return_values(ReturnValue::AllOld)
+            .send()
+            .await?;
+        // The counters of past windows may not have been deleted yet
+        Ok(deleted
+            .attributes()
+            .and_then(|item| window_of(item, now_millis()))
+            .is_some())
+    }
+
+    async fn get_counters(&self, limits: HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
+        let now = now_millis();
+        let mut res = HashSet::new();
+
+        for limit in limits {
+            for item in self.items_of_limit(&limit).await? {
+                let (Some(key), Some(window)) = (string_attr(&item, KEY), window_of(&item, now))
+                else {
+                    continue;
+                };
+                let mut counter = counter_from_counter_key(key, &limit);
+                counter.set_remaining(counter.max_value() - window.hits);
+                counter.set_expires_in(Duration::from_millis(window.expires_at - now));
+                res.insert(counter);
+            }
+        }
+
+        Ok(res)
+    }
+
+    async fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+        for limit in limits {
+            for item in self.items_of_limit(&limit).await? {
+                if let Some(key) = string_attr(&item, KEY) {
+                    self.delete(key).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<(), StorageErr> {
+        let items: Vec<_> = self
+            .client
+            .scan()
+            .table_name(&self.table_name)
+            .projection_expression("#key")
+            .expression_attribute_names("#key", KEY)
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<_, _>>()
+            .await?;
+
+        for item in items {
+            if let Some(key) = string_attr(&item, KEY) {
+                self.delete(key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), StorageErr> {
+        self.client
+            .describe_table()
+            .table_name(&self.table_name)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+impl DynamoDbStorage {
+    /// Keeps the counters in `table_name`, whose partition key must be a
+    /// string attribute named `key`. The credentials, and the region when none
+    /// is given, are looked up as with any other AWS SDK, e.g. from the
+    /// environment.
+    pub async fn new(table_name: &str, region: Option<&str>) -> Self {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = region {
+            loader = loader.region(Region::new(region.to_owned()));
+        }
+        Self::with_client(Client::new(&loader.load().await), table_name)
+    }
+
+    pub fn with_client(client: Client, table_name: impl Into<String>) -> Self {
+        Self {
+            client,
+            table_name: table_name.into(),
+        }
+    }
+
+    async fn update(
+        &self,
+        counters: &mut [Counter],
+        delta: i64,
+        check_limits: bool,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        let keys: Vec<String> = counters.iter().map(key_for_counter).collect();
+
+        for _ in 0..MAX_ATTEMPTS {
+            let now = now_millis();
+            let windows = try_join_all(keys.iter().map(|key| self.read_window(key, now))).await?;
+
+            if check_limits {
+                let mut first_limited = None;
+                for (counter, window) in counters.iter_mut().zip(&windows) {
+                    let hits = window.as_ref().map_or(0, |window| window.hits);
+                    let remaining = counter.max_value() - hits - counter.hits_for(delta);
+                    if load_counters {
+                        counter.set_remaining(remaining);
+                        counter.set_expires_in(match window {
+                            Some(window) => Duration::from_millis(window.expires_at - now),
+                            None => counter.window_at(UNIX_EPOCH + Duration::from_millis(now)),
+                        });
+                    }
+                    if first_limited.is_none() && remaining < 0 {
+                        first_limited = Some(Authorization::Limited(
+                            counter.limit().name().map(|n| n.to_owned()),
+                        ));
+                        if !load_counters {
+                            break;
+                        }
+                    }
+                }
+                if let Some(limited) = first_limited {
+                    return Ok(limited);
+                }
+            }
+
+            let updates = counters
+                .iter()
+                .zip(&keys)
+                .zip(&windows)
+                .map(|((counter, key), window)| {
+                    self.update_for(counter, key, window.as_ref(), delta, check_limits, now)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            match self
+                .client
+                .transact_write_items()
+                .set_transact_items(Some(updates))
+                .send()
+                .await
+            {
+                Ok(_) => return Ok(Authorization::Ok),
+                Err(err) if transaction_canceled(&err) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Err(StorageErr {
+            msg: format!(
+                "counters kept changing while being updated, gave up after {MAX_ATTEMPTS} attempts"
+            ),
+            kind: StorageErrKind::Other,
+        })
+    }
+
+    fn update_for(
+        &self,
+        counter: &Counter,
+        key: &str,
+        window: Option<&Window>,
+        delta: i64,
+        check_limits: bool,
+        now: u64,
+    ) -> Result<TransactWriteItem, BuildError> {
+        let delta = counter.hits_for(delta);
+        let update = Update::builder()
+            .table_name(&self.table_name)
+            .key(KEY, AttributeValue::S(key.to_owned()))
+            .expression_attribute_names("#hits", HITS)
+            .expression_attribute_names("#expires_at", EXPIRES_AT)
+            .expression_attribute_values(":delta", number(delta));
+
+        let update = match window {
+            // Starts a new window, unless a concurrent request already did
+            None => {
+                let window = counter.window_at(UNIX_EPOCH + Duration::from_millis(now));
+                let expires_at = now + window.as_millis() as u64;
+                update
+                    .update_expression(
+                        "SET #hits = :delta, #expires_at = :expires_at, #ttl = :ttl, #limit = :limit",
+                    )
+                    .condition_expression("attribute_not_exists(#hits) OR #expires_at <= :now")
+                    .expression_attribute_names("#ttl", TTL)
+                    .expression_attribute_names("#limit", LIMIT)
+                    .expression_attribute_values(":expires_at", number(expires_at))
+                    .expression_attribute_values(":ttl", number(expires_at / 1000 + 1))
+                    .expression_attribute_values(
+                        ":limit",
+                        AttributeValue::S(key_for_counters_of_limit(counter.limit())),
+                    )
+                    .expression_attribute_values(":now", number(now))
+            }
+            // Adds to the window read, as long as it's still the current one
+            Some(window) => {
+                let update = update
+                    .update_expression("ADD #hits :delta")
+                    .expression_attribute_values(":expires_at", number(window.expires_at));
+                if check_limits {
+                    update
+                        .condition_expression("#expires_at = :expires_at AND #hits <= :max_hits")
+                        .expression_attribute_values(
+                            ":max_hits",
+                            number(counter.max_value() - delta),
+                        )
+                } else {
+                    update.condition_expression("#expires_at = :expires_at")
+                }
+            }
+        };
+
+        Ok(TransactWriteItem::builder().update(update.build()?).build())
+    }
+
+    async fn read_window(&self, key: &str, now: u64) -> Result<Option<Window>, StorageErr> {
+        let res = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key(KEY, AttributeValue::S(key.to_owned()))
+            .consistent_read(true)
+            .send()
+            .await?;
+
+        Ok(res.item().and_then(|item| window_of(item, now)))
+    }
+
+    async fn items_of_limit(
+        &self,
+        limit: &Limit,
+    ) -> Result<Vec<HashMap<String, AttributeValue>>, StorageErr> {
+        Ok(self
+            .client
+            .scan()
+            .table_name(&self.table_name)
+            .filter_expression("#limit = :limit")
+            .expression_attribute_names("#limit", LIMIT)
+            .expression_attribute_values(
+                ":limit",
+                AttributeValue::S(key_for_counters_of_limit(limit)),
+            )
+            .consistent_read(true)
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<_, _>>()
+            .await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageErr> {
+        self.client
+            .delete_item()
+            .table_name(&self.table_name)
+            .key(KEY, AttributeValue::S(key.to_owned()))
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+// The window of the counter held by `item`, unless it's over
+fn window_of(item: &HashMap<String, AttributeValue>, now: u64) -> Option<Window> {
+    let hits = item.get(HITS)?.as_n().ok()?.parse().ok()?;
+    let expires_at = item.get(EXPIRES_AT)?.as_n().ok()?.parse().ok()?;
+    (expires_at > now).then_some(Window { hits, expires_at })
+}
+
+fn string_attr<'a>(item: &'a HashMap<String, AttributeValue>, name: &str) -> Option<&'a str> {
+    item.get(name)?.as_s().ok().map(String::as_str)
+}
+
+fn number(n: impl ToString) -> AttributeValue {
+    AttributeValue::N(n.to_string())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn transaction_canceled<R>(err: &SdkError<TransactWriteItemsError, R>) -> bool {
+    matches!(
+        err.as_service_error(),
+        Some(TransactWriteItemsError::TransactionCanceledException(_))
+    )
+}
+
+impl<E: std::error::Error + 'static, R: std::fmt::Debug> From<SdkError<E, R>> for StorageErr {
+    fn from(e: SdkError<E, R>) -> Self {
+        let kind = match e {
+            SdkError::DispatchFailure(_) | SdkError::TimeoutError(_) => StorageErrKind::Connection,
+            SdkError::ResponseError(_) => StorageErrKind::Serialization,
+            SdkError::ConstructionFailure(_) => StorageErrKind::Configuration,
+            _ => StorageErrKind::Other,
+        };
+        Self {
+            msg: DisplayErrorContext(e).to_string(),
+            kind,
+        }
+    }
+}
+
+impl From<BuildError> for StorageErr {
+    fn from(e: BuildError) -> Self {
+        Self {
+            msg: e.to_string(),
+            kind: StorageErrKind::Configuration,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(hits: &str, expires_at: &str) -> HashMap<String, AttributeValue> {
+        HashMap::from([
+            (HITS.to_string(), AttributeValue::N(hits.to_string())),
+            (
+                EXPIRES_AT.to_string(),
+                AttributeValue::N(expires_at.to_string()),
+            ),
+        ])
+    }
+
+    #[test]
+    fn windows_that_are_over_are_ignored() {
+        let window = window_of(&item("3", "2000"), 1000).expect("window should be current");
+        assert_eq!(window.hits, 3);
+        assert_eq!(window.expires_at, 2000);
+
+        assert!(window_of(&item("3", "2000"), 2000).is_none());
+        assert!(window_of(&item("3", "nope"), 1000).is_none());
+        assert!(window_of(&HashMap::new(), 1000).is_none());
+    }
+}
@@ -0,0 +1,456 @@
+use crate::counter::Counter;
+use crate::limit::{Limit, WindowType};
+use crate::storage::in_memory::InMemoryStorage;
+use crate::storage::{
+    AsyncCounterStorage, Authorization, CounterStorage, StorageErr, StorageErrKind,
+};
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Counts the hits in memory while the storage can't be reached, rather than
+// failing the requests. Each instance then limits the requests it gets on its
+// own, so that the limits are only enforced globally again once the storage
+// is back: until then, all the instances together let up to as many times the
+// limits through as there are instances.
+//
+// The storage is tried again, with a ping, once per retry interval. Once it
+// answers, the checks go to it again, and the hits counted in memory in the
+// fixed windows still running are then added to its counters. These start a
+// window of their own when the storage didn't have one running, which then
+// lasts longer than the one counted in memory. The hits of any other kind of
+// window are dropped.
+
+pub struct AsyncFailoverStorage {
+    storage: Box<dyn AsyncCounterStorage>,
+    fallback: InMemoryStorage,
+    retry_interval: Duration,
+    // When the storage was last tried, while it can't be reached
+    failed_at: Mutex<Option<Instant>>,
+    // The limits of the counters in memory, to add their hits to the storage
+    limits: Mutex<HashSet<Limit>>,
+}
+
+impl AsyncFailoverStorage {
+    /// Counts the hits in `fallback` while `storage` can't be reached, trying
+    /// it again every `retry_interval`.
+    pub fn new(
+        storage: Box<dyn AsyncCounterStorage>,
+        fallback: InMemoryStorage,
+        retry_interval: Duration,
+    ) -> Self {
+        Self {
+            storage,
+            fallback,
+            retry_interval,
+            failed_at: Mutex::new(None),
+            limits: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Whether the hits are being counted in memory.
+    pub fn is_failed_over(&self) -> bool {
+        self.failed_at.lock().unwrap().is_some()
+    }
+
+    fn fail_over(&self) {
+        *self.failed_at.lock().unwrap() = Some(Instant::now());
+    }
+
+    // Whether to go to the storage: it's not known to be unreachable, or it's
+    // time to try it again and it answers, in which case the hits counted in
+    // memory meanwhile are added to it
+    async fn tries_storage(&self) -> bool {
+        {
+            let mut failed_at = self.failed_at.lock().unwrap();
+            match *failed_at {
+                None => return true,
+                Some(at) if at.elapsed() < self.retry_interval => return false,
+                // Only one of the requests tries it
+                Some(_) => *failed_at = Some(Instant::now()),
+            }
+        }
+        if self.storage.ping().await.is_err() {
+            return false;
+        }
+
+        // The requests go to the storage again before the hits in memory are
+        // added to it, so that none are counted in memory past this point
+        *self.failed_at.lock().unwrap() = None;
+        if let Err(err) = self.add_hits_in_memory().await {
+            if err.kind() == StorageErrKind::Connection {
+                self.fail_over();
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn add_hits_in_memory(&self) -> Result<(), StorageErr> {
+        let limits = std::mem::take(&mut *self.limits.lock().unwrap());
+        let counters = self.fallback.get_counters(&limits)?;
+        for counter in counters {
+            if counter.window_type() != WindowType::Fixed {
+                continue;
+            }
+            let (Some(remaining), Some(expires_in)) = (counter.remaining(), counter.expires_in())
+            else {
+                continue;
+            };
+            // The hits in memory are already weighed by their cost
+            let hits = counter.max_value() - remaining;
+            if hits > 0 {
+                let added = self
+                    .storage
+                    .update_counter(&counter.clone().with_unit_cost(), hits)
+                    .await;
+                if let Err(err) = added {
+                    self.limits.lock().unwrap().extend(limits);
+                    return Err(err);
+                }
+                // So that they aren't added again if the others can't be
+                self.fallback
+                    .set_counter(&counter, counter.max_value(), expires_in)?;
+            }
+        }
+        self.fallback.delete_counters(limits)
+    }
+
+    fn remember<'a>(&self, counters: impl IntoIterator<Item = &'a Counter>) {
+        let mut limits = self.limits.lock().unwrap();
+        for counter in counters {
+            if !limits.contains(counter.limit()) {
+                limits.insert(counter.limit().clone());
+            }
+        }
+    }
+}
+
+// Whether the storage answered, rather than couldn't be reached, in which case
+// the hits are to be counted in memory
+fn answered<T>(result: &Result<T, StorageErr>) -> bool {
+    !matches!(result, Err(err) if err.kind() == StorageErrKind::Connection)
+}
+
+#[async_trait]
+impl AsyncCounterStorage for AsyncFailoverStorage {
+    async fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        if self.tries_storage().await {
+            let result = self.storage.is_within_limits(counter, delta).await;
+            if answered(&result) {
+                return result;
+            }
+            self.fail_over();
+        }
+        self.fallback.is_within_limits(counter, delta)
+    }
+
+    async fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        if self.tries_storage().await {
+            let result = self.storage.update_counter(counter, delta).await;
+            if answered(&result) {
+                return result;
+            }
+            self.fail_over();
+        }
+        self.remember([counter]);
+        self.fallback.update_counter(counter, delta)
+    }
+
+    async fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        if self.tries_storage().await {
+            let result = self
+                .storage
+                .check_and_update(counters, delta, load_counters)
+                .await;
+            if answered(&result) {
+                return result;
+            }
+            self.fail_over();
+        }
+        self.remember(counters.iter());
+        self.fallback
+            .check_and_update(counters, delta, load_counters)
+    }
+
+    async fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        if self.tries_storage().await {
+            let result = self
+                .storage
+                .check_and_update_batch(batch, load_counters)
+                .await;
+            if answered(&result) {
+                return result;
+            }
+            self.fail_over();
+        }
+        self.remember(batch.iter().flat_map(|(counters, _)| counters));
+        self.fallback.check_and_update_batch(batch, load_counters)
+    }
+
+    async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        if self.tries_storage().await {
+            let result = self
+                .storage
+                .set_counter(counter, remaining, expires_in)
+                .await;
+            if answered(&result) {
+                return result;
+            }
+            self.fail_over();
+        }
+        self.remember([counter]);
+        self.fallback.set_counter(counter, remaining, expires_in)
+    }
+
+    async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        let in_memory = self.fallback.delete_counter(counter)?;
+        if self.tries_storage().await {
+            let result = self.storage.delete_counter(counter).await;
+            if answered(&result) {
+                return result.map(|existed| existed || in_memory);
+            }
+            self.fail_over();
+        }
+        Ok(in_memory)
+    }
+
+    async fn get_counters(&self, limits: HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
+        if self.tries_storage().await {
+            let result = self.storage.get_counters(limits.clone()).await;
+            if answered(&result) {
+                return result;
+            }
+            self.fail_over();
+        }
+        self.fallback.get_counters(&limits)
+    }
+
+    async fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+        self.fallback.delete_counters(limits.clone())?;
+        if self.tries_storage().await {
+            let result = self.storage.delete_counters(limits).await;
+            if answered(&result) {
+                return result;
+            }
+            self.fail_over();
+        }
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<(), StorageErr> {
+        self.limits.lock().unwrap().clear();
+        self.fallback.clear()?;
+        if self.tries_storage().await {
+            let result = self.storage.clear().await;
+            if answered(&result) {
+                return result;
+            }
+            self.fail_over();
+        }
+        Ok(())
+    }
+
+    // The requests are answered, whether the storage can be reached or not
+    async fn ping(&self) -> Result<(), StorageErr> {
+        if self.tries_storage().await {
+            let result = self.storage.ping().await;
+            if answered(&result) {
+                return result;
+            }
+            self.fail_over();
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), StorageErr> {
+        self.storage.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::composite::AsyncCounterStorageAdapter;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    // A storage in memory that can be told to be unreachable
+    struct Unreachable {
+        storage: AsyncCounterStorageAdapter,
+        unreachable: Arc<AtomicBool>,
+    }
+
+    impl Unreachable {
+        fn reached(&self) -> Result<(), StorageErr> {
+            match self.unreachable.load(Ordering::SeqCst) {
+                true => Err(StorageErr {
+                    msg: "unreachable".to_string(),
+                    kind: StorageErrKind::Connection,
+                }),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl AsyncCounterStorage for Unreachable {
+        async fn is_within_limits(
+            &self,
+            counter: &Counter,
+            delta: i64,
+        ) -> Result<bool, StorageErr> {
+            self.reached()?;
+            self.storage.is_within_limits(counter, delta).await
+        }
+
+        async fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+            self.reached()?;
+            self.storage.update_counter(counter, delta).await
+        }
+
+        async fn check_and_update(
+            &self,
+            counters: &mut Vec<Counter>,
+            delta: i64,
+            load_counters: bool,
+        ) -> Result<Authorization, StorageErr> {
+            self.reached()?;
+            self.storage
+                .check_and_update(counters, delta, load_counters)
+                .await
+        }
+
+        async fn set_counter(
+            &self,
+            counter: &Counter,
+            remaining: i64,
+            expires_in: Duration,
+        ) -> Result<(), StorageErr> {
+            self.reached()?;
+            self.storage
+                .set_counter(counter, remaining, expires_in)
+                .await
+        }
+
+        async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+            self.reached()?;
+            self.storage.delete_counter(counter).await
+        }
+
+        async fn get_counters(
+            &self,
+            limits: HashSet<Limit>,
+        ) -> Result<HashSet<Counter>, StorageErr> {
+            self.reached()?;
+            self.storage.get_counters(limits).await
+        }
+
+        async fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+            self.reached()?;
+            self.storage.delete_counters(limits).await
+        }
+
+        async fn clear(&self) -> Result<(), StorageErr> {
+            self.reached()?;
+            self.storage.clear().await
+        }
+
+        async fn ping(&self) -> Result<(), StorageErr> {
+            self.reached()
+        }
+    }
+
+    fn failover(retry_interval: Duration) -> (AsyncFailoverStorage, Arc<AtomicBool>) {
+        let unreachable = Arc::new(AtomicBool::new(false));
+        let storage = Unreachable {
+            storage: AsyncCounterStorageAdapter::new(Box::<InMemoryStorage>::default()),
+            unreachable: unreachable.clone(),
+        };
+        (
+            AsyncFailoverStorage::new(
+                Box::new(storage),
+                InMemoryStorage::default(),
+                retry_interval,
+            ),
+            unreachable,
+        )
+    }
+
+    fn counter(max_value: i64) -> Counter {
+        let limit = Limit::new("ns", max_value, 60, Vec::<String>::default(), vec!["id"]);
+        Counter::new(limit, HashMap::from([("id".to_string(), "1".to_string())]))
+    }
+
+    async fn check(storage: &AsyncFailoverStorage, counter: &Counter) -> Authorization {
+        storage
+            .check_and_update(&mut vec![counter.clone()], 1, false)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn limits_in_memory_while_the_storage_cant_be_reached() {
+        let (storage, unreachable) = failover(Duration::from_secs(60));
+        let counter = counter(2);
+
+        assert!(matches!(check(&storage, &counter).await, Authorization::Ok));
+        unreachable.store(true, Ordering::SeqCst);
+        assert!(matches!(check(&storage, &counter).await, Authorization::Ok));
+        assert!(storage.is_failed_over());
+        assert!(matches!(check(&storage, &counter).await, Authorization::Ok));
+        assert!(matches!(
+            check(&storage, &counter).await,
+            Authorization::Limited(_)
+        ));
+        assert!(storage.ping().await.is_ok());
+
+        // Not tried again before the retry interval
+        unreachable.store(false, Ordering::SeqCst);
+        assert!(matches!(
+            check(&storage, &counter).await,
+            Authorization::Limited(_)
+        ));
+        assert!(storage.is_failed_over());
+    }
+
+    #[tokio::test]
+    async fn adds_the_hits_counted_in_memory_once_the_storage_is_back() {
+        let (storage, unreachable) = failover(Duration::ZERO);
+        let counter = counter(3);
+
+        assert!(matches!(check(&storage, &counter).await, Authorization::Ok));
+        unreachable.store(true, Ordering::SeqCst);
+        assert!(matches!(check(&storage, &counter).await, Authorization::Ok));
+        assert!(storage.is_failed_over());
+
+        unreachable.store(false, Ordering::SeqCst);
+        assert!(matches!(check(&storage, &counter).await, Authorization::Ok));
+        assert!(!storage.is_failed_over());
+        assert!(matches!(
+            check(&storage, &counter).await,
+            Authorization::Limited(_)
+        ));
+
+        let limits = HashSet::from([counter.limit().clone()]);
+        let counters = storage.storage.get_counters(limits.clone()).await.unwrap();
+        assert_eq!(counters.iter().next().unwrap().remaining(), Some(0));
+        assert!(storage.fallback.get_counters(&limits).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::counter::Fnv1a;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The counters of Distinct limits estimate how many distinct values they saw
+// with a HyperLogLog, that takes the same memory whatever that number: 2^12
+// registers of a byte, i.e. 4KiB per counter, for a standard error of
+// 1.04 / sqrt(2^12), about 1.6%. Up to a few thousand values, the estimate is
+// corrected by linear counting, that is close to exact. A value is told apart
+// from the ones seen already by whether it raises its register, so a value
+// never seen can pass for one that was, about as often as the estimate is off.
+
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    /// Restores the registers of a HyperLogLog, unless they aren't as many
+    /// as this one's.
+    pub fn from_registers(registers: Vec<u8>) -> Option<Self> {
+        (registers.len() == REGISTERS).then_some(Self { registers })
+    }
+
+    pub fn registers(&self) -> &[u8] {
+        &self.registers
+    }
+
+    /// Adds the value, returning whether it was new to the ones added so far.
+    pub fn add(&mut self, value: &str) -> bool {
+        let (index, rank) = Self::register_of(value);
+        if self.registers[index] < rank {
+            self.registers[index] = rank;
+            return true;
+        }
+        false
+    }
+
+    /// Whether adding the value would count it as a new one.
+    pub fn is_new(&self, value: &str) -> bool {
+        let (index, rank) = Self::register_of(value);
+        self.registers[index] < rank
+    }
+
+    /// The estimated number of distinct values added.
+    pub fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let (sum, zeros) = self
+            .registers
+            .iter()
+            .fold((0.0, 0_u32), |(sum, zeros), &rank| {
+                (
+                    sum + 2_f64.powi(-i32::from(rank)),
+                    zeros + u32::from(rank == 0),
+                )
+            });
+        let raw = alpha * m * m / sum;
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            m * (m / f64::from(zeros)).ln()
+        } else {
+            raw
+        };
+        estimate.round() as u64
+    }
+
+    // The register the value goes to, picked by the first bits of its hash,
+    // and its rank there: the position of the first bit set in the others.
+    fn register_of(value: &str) -> (usize, u8) {
+        let mut hash = Fnv1a::default();
+        hash.write(value);
+        let hash = fmix64(hash.finish());
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // The bit set past the ones left caps the rank at 64 - PRECISION + 1
+        let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+        (index, rest.leading_zeros() as u8 + 1)
+    }
+}
+
+// The finalizer of MurmurHash3, so that all the bits of the hash depend on
+// all the bytes of the value, which FNV-1a alone doesn't quite get to
+fn fmix64(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// The distinct values seen by a counter over its current fixed window.
+#[derive(Debug)]
+pub(crate) struct DistinctValues {
+    // The values of the window, and when it ends
+    state: Mutex<(HyperLogLog, SystemTime)>,
+}
+
+impl Default for DistinctValues {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new((HyperLogLog::default(), UNIX_EPOCH)),
+        }
+    }
+}
+
+impl DistinctValues {
+    /// Restores the values of a window ending at `expires_at`.
+    pub fn new(values: HyperLogLog, expires_at: SystemTime) -> Self {
+        Self {
+            state: Mutex::new((values, expires_at)),
+        }
+    }
+
+    /// The estimated number of distinct values seen in the window, none
+    /// once it's over.
+    pub fn count_at(&self, when: SystemTime) -> u64 {
+        let state = self.state.lock().unwrap();
+        if state.1 <= when {
+            return 0;
+        }
+        state.0.estimate()
+    }
+
+    /// Whether the value wasn't seen yet in the window.
+    pub fn is_new_at(&self, value: &str, when: SystemTime) -> bool {
+        let state = self.state.lock().unwrap();
+        state.1 <= when || state.0.is_new(value)
+    }
+
+    /// Adds the value to the window, starting a new one of `window` if the
+    /// last one is over.
+    pub fn add(&self, value: &str, window: Duration, when: SystemTime) {
+        let mut state = self.state.lock().unwrap();
+        if state.1 <= when {
+            *state = (HyperLogLog::default(), when + window);
+        }
+        state.0.add(value);
+    }
+
+    pub fn expires_at(&self) -> SystemTime {
+        self.state.lock().unwrap().1
+    }
+
+    pub fn ttl_at(&self, when: SystemTime) -> Duration {
+        self.expires_at()
+            .duration_since(when)
+            .unwrap_or(Duration::ZERO)
+    }
+
+    /// The values of the window, and when it ends.
+    pub fn state(&self) -> (HyperLogLog, SystemTime) {
+        self.state.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_only_new_once() {
+        let mut values = HyperLogLog::default();
+        assert!(values.is_new("10.0.0.1"));
+        assert!(values.add("10.0.0.1"));
+        assert!(!values.is_new("10.0.0.1"));
+        assert!(!values.add("10.0.0.1"));
+        assert_eq!(values.estimate(), 1);
+    }
+
+    #[test]
+    fn estimates_are_within_a_few_standard_errors() {
+        let mut values = HyperLogLog::default();
+        let mut added = 0;
+        for expected in [100, 1_000, 100_000] {
+            for value in added..expected {
+                values.add(&format!("value-{value}"));
+            }
+            added = expected;
+            let error = (values.estimate() as f64 - expected as f64).abs() / expected as f64;
+            assert!(error < 0.05, "{} for {expected}", values.estimate());
+        }
+    }
+
+    #[test]
+    fn windows_start_afresh_once_over() {
+        let values = DistinctValues::default();
+        let now = SystemTime::now();
+        values.add("a", Duration::from_secs(60), now);
+        values.add("b", Duration::from_secs(60), now);
+        assert_eq!(values.count_at(now), 2);
+        assert!(!values.is_new_at("a", now));
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(values.count_at(later), 0);
+        assert!(values.is_new_at("a", later));
+        values.add("a", Duration::from_secs(60), later);
+        assert_eq!(values.count_at(later), 1);
+        assert_eq!(values.ttl_at(later), Duration::from_secs(60));
+    }
+}
@@ -1,42 +1,82 @@
 use crate::counter::Counter;
-use crate::limit::{Limit, Namespace};
+use crate::limit::{Limit, Namespace, WindowType};
 use crate::storage::atomic_expiring_value::AtomicExpiringValue;
-use crate::storage::{Authorization, CounterStorage, StorageErr};
-use moka::sync::Cache;
+use crate::storage::clock::{Clock, SystemClock};
+use crate::storage::hyperloglog::{DistinctValues, HyperLogLog};
+use crate::storage::leaky_bucket::{LeakyBucket, LeakyBucketValue};
+use crate::storage::sliding_window::{SlidingWindow, SlidingWindowState, SlidingWindowValue};
+use crate::storage::token_bucket::{TokenBucket, TokenBucketValue};
+use crate::storage::{
+    only_fixed_windows, Authorization, CounterStorage, OverflowPolicy, StorageErr, StorageErrKind,
+};
+use moka::sync::{Cache, ConcurrentCacheExt};
+use moka::Expiry;
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind};
 use std::ops::Deref;
+use std::path::Path;
 use std::sync::{Arc, RwLock};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 type NamespacedLimitCounters<T> = HashMap<Namespace, HashMap<Limit, T>>;
 
 pub struct InMemoryStorage {
     limits_for_namespace: RwLock<NamespacedLimitCounters<AtomicExpiringValue>>,
     qualified_counters: Cache<Counter, Arc<AtomicExpiringValue>>,
+    // Counters of sliding windows, qualified or not, as they need to keep track
+    // of the previous window too.
+    sliding_windows: Cache<Counter, Arc<SlidingWindowValue>>,
+    token_buckets: Cache<Counter, Arc<TokenBucketValue>>,
+    leaky_buckets: Cache<Counter, Arc<LeakyBucketValue>>,
+    distinct_values: Cache<Counter, Arc<DistinctValues>>,
+    overflow_policy: OverflowPolicy,
+    clock: Box<dyn Clock>,
 }
 
 impl CounterStorage for InMemoryStorage {
     fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
         let limits_by_namespace = self.limits_for_namespace.read().unwrap();
+        let now = self.clock.get_current_time();
+        let delta = counter.hits_for(delta);
+
+        if counter.window_type() == WindowType::Distinct {
+            let values = self.distinct_values.get(counter).unwrap_or_default();
+            let (value, delta) = Self::distinct_value_hits(counter, &values, delta, now);
+            return self.overflow_policy.is_within_limit(counter, value, delta);
+        }
 
         let mut value = 0;
 
-        if counter.is_qualified() {
+        if counter.window_type() == WindowType::Sliding {
+            if let Some(window) = self.sliding_windows.get(counter) {
+                value = window.value_at(&SlidingWindow::of(counter, now));
+            }
+        } else if counter.window_type() == WindowType::TokenBucket {
+            if let Some(bucket) = self.token_buckets.get(counter) {
+                value = Self::token_bucket_hits(counter, &bucket, now);
+            }
+        } else if counter.window_type() == WindowType::LeakyBucket {
+            if let Some(bucket) = self.leaky_buckets.get(counter) {
+                value = Self::leaky_bucket_hits(counter, &bucket, now);
+            }
+        } else if counter.is_qualified() {
             if let Some(counter) = self.qualified_counters.get(counter) {
-                value = counter.value();
+                value = counter.value_at(now);
             }
         } else if let Some(limits) = limits_by_namespace.get(counter.limit().namespace()) {
             if let Some(counter) = limits.get(counter.limit()) {
-                value = counter.value();
+                value = counter.value_at(now);
             }
         }
 
-        Ok(counter.max_value() >= value + delta)
+        self.overflow_policy.is_within_limit(counter, value, delta)
     }
 
     fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
-        if limit.variables().is_empty() {
+        if limit.variables().is_empty() && limit.window_type() == WindowType::Fixed {
             let mut limits_by_namespace = self.limits_for_namespace.write().unwrap();
             limits_by_namespace
                 .entry(limit.namespace().clone())
@@ -49,28 +89,39 @@ impl CounterStorage for InMemoryStorage {
 
     fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
         let mut limits_by_namespace = self.limits_for_namespace.write().unwrap();
-        let now = SystemTime::now();
-        if counter.is_qualified() {
+        let now = self.clock.get_current_time();
+        let delta = counter.hits_for(delta);
+        if counter.window_type() == WindowType::Sliding {
+            self.sliding_window_of(counter)
+                .update(delta, &SlidingWindow::of(counter, now));
+        } else if counter.window_type() == WindowType::TokenBucket {
+            self.token_bucket_of(counter)
+                .consume(&TokenBucket::of(counter), delta, now);
+        } else if counter.window_type() == WindowType::LeakyBucket {
+            self.leaky_bucket_of(counter)
+                .fill(&LeakyBucket::of(counter), delta, now);
+        } else if counter.window_type() == WindowType::Distinct {
+            if let Some(value) = counter.distinct_value().filter(|_| delta > 0) {
+                self.distinct_values_of(counter)
+                    .add(value, counter.window_at(now), now);
+            }
+        } else if counter.is_qualified() {
             let value = match self.qualified_counters.get(counter) {
                 None => self.qualified_counters.get_with(counter.clone(), || {
-                    Arc::new(AtomicExpiringValue::new(
-                        0,
-                        now + Duration::from_secs(counter.seconds()),
-                    ))
+                    Arc::new(AtomicExpiringValue::new(0, now + counter.window_at(now)))
                 }),
                 Some(counter) => counter,
             };
-            value.update(delta, counter.seconds(), now);
+            self.overflow_policy
+                .add(counter, value.value_at(now), delta)?;
+            value.update(delta, counter.window_at(now), now);
         } else {
             match limits_by_namespace.entry(counter.limit().namespace().clone()) {
                 Entry::Vacant(v) => {
                     let mut limits = HashMap::new();
                     limits.insert(
                         counter.limit().clone(),
-                        AtomicExpiringValue::new(
-                            delta,
-                            now + Duration::from_secs(counter.seconds()),
-                        ),
+                        AtomicExpiringValue::new(delta, now + counter.window_at(now)),
                     );
                     v.insert(limits);
                 }
@@ -78,11 +129,13 @@ impl CounterStorage for InMemoryStorage {
                     Entry::Vacant(v) => {
                         v.insert(AtomicExpiringValue::new(
                             delta,
-                            now + Duration::from_secs(counter.seconds()),
+                            now + counter.window_at(now),
                         ));
                     }
                     Entry::Occupied(o) => {
-                        o.get().update(delta, counter.seconds(), now);
+                        self.overflow_policy
+                            .add(counter, o.get().value_at(now), delta)?;
+                        o.get().update(delta, counter.window_at(now), now);
                     }
                 },
             }
@@ -96,87 +149,89 @@ impl CounterStorage for InMemoryStorage {
         delta: i64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
-        let limits_by_namespace = self.limits_for_namespace.write().unwrap();
-        let mut first_limited = None;
-        let mut counter_values_to_update: Vec<(&AtomicExpiringValue, u64)> = Vec::new();
-        let mut qualified_counter_values_to_updated: Vec<(Arc<AtomicExpiringValue>, u64)> =
-            Vec::new();
-        let now = SystemTime::now();
-
-        let mut process_counter =
-            |counter: &mut Counter, value: i64, delta: i64| -> Option<Authorization> {
-                if load_counters {
-                    let remaining = counter.max_value() - (value + delta);
-                    counter.set_remaining(remaining);
-                    if first_limited.is_none() && remaining < 0 {
-                        first_limited = Some(Authorization::Limited(
-                            counter.limit().name().map(|n| n.to_owned()),
-                        ));
-                    }
-                }
-                if !Self::counter_is_within_limits(counter, Some(&value), delta) {
-                    return Some(Authorization::Limited(
-                        counter.limit().name().map(|n| n.to_owned()),
-                    ));
-                }
-                None
-            };
-
-        // Process simple counters
-        for counter in counters.iter_mut().filter(|c| !c.is_qualified()) {
-            let atomic_expiring_value: &AtomicExpiringValue = limits_by_namespace
-                .get(counter.limit().namespace())
-                .and_then(|limits| limits.get(counter.limit()))
-                .unwrap();
-
-            if let Some(limited) = process_counter(counter, atomic_expiring_value.value(), delta) {
-                if !load_counters {
-                    return Ok(limited);
-                }
-            }
-            counter_values_to_update.push((atomic_expiring_value, counter.seconds()));
-        }
-
-        // Process qualified counters
-        for counter in counters.iter_mut().filter(|c| c.is_qualified()) {
-            let value = match self.qualified_counters.get(counter) {
-                None => self.qualified_counters.get_with(counter.clone(), || {
-                    Arc::new(AtomicExpiringValue::new(
-                        0,
-                        now + Duration::from_secs(counter.seconds()),
-                    ))
-                }),
-                Some(counter) => counter,
-            };
-
-            if let Some(limited) = process_counter(counter, value.value(), delta) {
-                if !load_counters {
-                    return Ok(limited);
-                }
-            }
+        let mut limits_by_namespace = self.limits_for_namespace.write().unwrap();
+        self.check_and_update_with(&mut limits_by_namespace, counters, delta, load_counters)
+    }
 
-            qualified_counter_values_to_updated.push((value, counter.seconds()));
-        }
+    // Same as check_and_update, but takes the lock only once for the whole batch
+    fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        let mut limits_by_namespace = self.limits_for_namespace.write().unwrap();
+        batch
+            .iter_mut()
+            .map(|(counters, delta)| {
+                self.check_and_update_with(
+                    &mut limits_by_namespace,
+                    counters,
+                    *delta,
+                    load_counters,
+                )
+            })
+            .collect()
+    }
 
-        if let Some(limited) = first_limited {
-            return Ok(limited);
+    fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
+        let mut limits_by_namespace = self.limits_for_namespace.write().unwrap();
+        let value = AtomicExpiringValue::new(
+            counter.max_value() - remaining,
+            self.clock.get_current_time() + expires_in,
+        );
+        if counter.is_qualified() {
+            self.qualified_counters
+                .insert(counter.clone(), Arc::new(value));
+        } else {
+            limits_by_namespace
+                .entry(counter.limit().namespace().clone())
+                .or_default()
+                .insert(counter.limit().clone(), value);
         }
+        Ok(())
+    }
 
-        // Update counters
-        counter_values_to_update.iter().for_each(|(v, ttl)| {
-            v.update(delta, *ttl, now);
-        });
-        qualified_counter_values_to_updated
-            .iter()
-            .for_each(|(v, ttl)| {
-                v.update(delta, *ttl, now);
-            });
-
-        Ok(Authorization::Ok)
+    fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        let mut limits_by_namespace = self.limits_for_namespace.write().unwrap();
+        let now = self.clock.get_current_time();
+        let existed = match counter.window_type() {
+            WindowType::Sliding => self
+                .sliding_windows
+                .remove(counter)
+                .is_some_and(|value| value.is_live_at(&SlidingWindow::of(counter, now))),
+            WindowType::TokenBucket => self
+                .token_buckets
+                .remove(counter)
+                .is_some_and(|value| value.is_live_at(&TokenBucket::of(counter), now)),
+            WindowType::LeakyBucket => self
+                .leaky_buckets
+                .remove(counter)
+                .is_some_and(|value| value.is_live_at(&LeakyBucket::of(counter), now)),
+            WindowType::Distinct => self
+                .distinct_values
+                .remove(counter)
+                .is_some_and(|value| value.expires_at() > now),
+            WindowType::Fixed if counter.is_qualified() => self
+                .qualified_counters
+                .remove(counter)
+                .is_some_and(|value| value.ttl_at(now) > Duration::ZERO),
+            WindowType::Fixed => limits_by_namespace
+                .get_mut(counter.namespace())
+                .and_then(|limits| limits.remove(counter.limit()))
+                .is_some_and(|value| value.ttl_at(now) > Duration::ZERO),
+        };
+        Ok(existed)
     }
 
     fn get_counters(&self, limits: &HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
         let mut res = HashSet::new();
+        let now = self.clock.get_current_time();
 
         let namespaces: HashSet<&Namespace> = limits.iter().map(Limit::namespace).collect();
         let limits_by_namespace = self.limits_for_namespace.read().unwrap();
@@ -188,9 +243,9 @@ impl CounterStorage for InMemoryStorage {
                         for (counter, expiring_value) in self.counters_in_namespace(namespace) {
                             let mut counter_with_val = counter.clone();
                             counter_with_val.set_remaining(
-                                counter_with_val.max_value() - expiring_value.value(),
+                                counter_with_val.max_value() - expiring_value.value_at(now),
                             );
-                            counter_with_val.set_expires_in(expiring_value.ttl());
+                            counter_with_val.set_expires_in(expiring_value.ttl_at(now));
                             if counter_with_val.expires_in().unwrap() > Duration::ZERO {
                                 res.insert(counter_with_val);
                             }
@@ -204,14 +259,57 @@ impl CounterStorage for InMemoryStorage {
             if limits.contains(counter.limit()) {
                 let mut counter_with_val = counter.deref().clone();
                 counter_with_val
-                    .set_remaining(counter_with_val.max_value() - expiring_value.value());
-                counter_with_val.set_expires_in(expiring_value.ttl());
+                    .set_remaining(counter_with_val.max_value() - expiring_value.value_at(now));
+                counter_with_val.set_expires_in(expiring_value.ttl_at(now));
                 if counter_with_val.expires_in().unwrap() > Duration::ZERO {
                     res.insert(counter_with_val);
                 }
             }
         }
 
+        for (counter, value) in self.sliding_windows.iter() {
+            let window = SlidingWindow::of(&counter, now);
+            if limits.contains(counter.limit()) && value.is_live_at(&window) {
+                let mut counter_with_val = counter.deref().clone();
+                counter_with_val
+                    .set_remaining(counter_with_val.max_value() - value.value_at(&window));
+                counter_with_val.set_expires_in(window.expires_in());
+                res.insert(counter_with_val);
+            }
+        }
+
+        for (counter, value) in self.token_buckets.iter() {
+            let bucket = TokenBucket::of(&counter);
+            if limits.contains(counter.limit()) && value.is_live_at(&bucket, now) {
+                let mut counter_with_val = counter.deref().clone();
+                let tokens = value.tokens_at(&bucket, now);
+                counter_with_val.set_remaining(tokens.floor() as i64);
+                counter_with_val.set_expires_in(bucket.full_in(tokens));
+                res.insert(counter_with_val);
+            }
+        }
+
+        for (counter, value) in self.leaky_buckets.iter() {
+            let bucket = LeakyBucket::of(&counter);
+            if limits.contains(counter.limit()) && value.is_live_at(&bucket, now) {
+                let mut counter_with_val = counter.deref().clone();
+                let level = value.level_at(&bucket, now);
+                counter_with_val.set_remaining(counter_with_val.max_value() - bucket.hits(level));
+                counter_with_val.set_expires_in(bucket.empty_in(level));
+                res.insert(counter_with_val);
+            }
+        }
+
+        for (counter, value) in self.distinct_values.iter() {
+            if limits.contains(counter.limit()) && value.expires_at() > now {
+                let mut counter_with_val = counter.deref().clone();
+                counter_with_val
+                    .set_remaining(counter_with_val.max_value() - value.count_at(now) as i64);
+                counter_with_val.set_expires_in(value.ttl_at(now));
+                res.insert(counter_with_val);
+            }
+        }
+
         Ok(res)
     }
 
@@ -224,18 +322,592 @@ impl CounterStorage for InMemoryStorage {
 
     fn clear(&self) -> Result<(), StorageErr> {
         self.limits_for_namespace.write().unwrap().clear();
+        self.sliding_windows.invalidate_all();
+        self.token_buckets.invalidate_all();
+        self.leaky_buckets.invalidate_all();
+        self.distinct_values.invalidate_all();
         Ok(())
     }
 }
 
 impl InMemoryStorage {
+    /// Keeps up to `cache_size` counters per window type in memory, evicting
+    /// the least recently used ones past that. Counters that haven't been hit
+    /// for long enough for their window to be over get dropped too.
     pub fn new(cache_size: u64) -> Self {
         Self {
             limits_for_namespace: RwLock::new(HashMap::new()),
-            qualified_counters: Cache::new(cache_size),
+            qualified_counters: Self::cache(cache_size),
+            sliding_windows: Self::cache(cache_size),
+            token_buckets: Self::cache(cache_size),
+            leaky_buckets: Self::cache(cache_size),
+            distinct_values: Self::cache(cache_size),
+            overflow_policy: OverflowPolicy::default(),
+            clock: Box::new(SystemClock),
         }
     }
 
+    /// What to do with the hits that would overflow the counters of fixed
+    /// windows, saturating them by default.
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Tells the time with `clock` rather than the system's, e.g. for tests
+    /// to move it rather than wait for windows to be over. The counters held
+    /// in memory are still dropped once idle for as long as their window,
+    /// as per the system clock.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// The number of counters currently held, expired ones excluded.
+    pub fn counter_count(&self) -> u64 {
+        self.qualified_counters.sync();
+        self.sliding_windows.sync();
+        self.token_buckets.sync();
+        self.leaky_buckets.sync();
+        self.distinct_values.sync();
+
+        let now = self.clock.get_current_time();
+        let unqualified = self
+            .limits_for_namespace
+            .read()
+            .unwrap()
+            .values()
+            .flat_map(HashMap::values)
+            .filter(|value| value.ttl_at(now) > Duration::ZERO)
+            .count() as u64;
+
+        unqualified
+            + self.qualified_counters.entry_count()
+            + self.sliding_windows.entry_count()
+            + self.token_buckets.entry_count()
+            + self.leaky_buckets.entry_count()
+            + self.distinct_values.entry_count()
+    }
+
+    /// Saves the counters whose window isn't over yet to `path`, so that they
+    /// can be restored with [`InMemoryStorage::load_snapshot`], e.g. after a
+    /// restart. The snapshot is written aside first, so that a previous one at
+    /// `path` is only ever replaced by a complete one.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<(), StorageErr> {
+        let path = path.as_ref();
+        let snapshot = self.snapshot();
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let write = || -> std::io::Result<()> {
+            let mut file = BufWriter::new(File::create(&tmp)?);
+            serde_json::to_writer(&mut file, &snapshot)?;
+            file.into_inner()?.sync_all()?;
+            std::fs::rename(&tmp, path)
+        };
+
+        write().map_err(|e| StorageErr {
+            msg: format!("failed to save snapshot to {}: {e}", path.display()),
+            kind: StorageErrKind::Other,
+        })
+    }
+
+    /// Restores the counters of the snapshot at `path`, leaving out the ones
+    /// whose window is over by now, and returns how many were restored. There
+    /// being no snapshot at `path` isn't an error, but it being corrupt is.
+    pub fn load_snapshot(&self, path: impl AsRef<Path>) -> Result<usize, StorageErr> {
+        let path = path.as_ref();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => {
+                return Err(StorageErr {
+                    msg: format!("failed to read snapshot {}: {e}", path.display()),
+                    kind: StorageErrKind::Other,
+                })
+            }
+        };
+        let snapshot: Snapshot =
+            serde_json::from_reader(BufReader::new(file)).map_err(|e| StorageErr {
+                msg: format!("corrupt snapshot {}: {e}", path.display()),
+                kind: StorageErrKind::Serialization,
+            })?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(StorageErr {
+                msg: format!(
+                    "snapshot {} is of unsupported version {}",
+                    path.display(),
+                    snapshot.version
+                ),
+                kind: StorageErrKind::Serialization,
+            });
+        }
+
+        let mut limits_by_namespace = self.limits_for_namespace.write().unwrap();
+        let now = self.clock.get_current_time();
+        let mut restored = 0;
+
+        for entry in snapshot.counters {
+            let mut counter = entry.counter;
+            let mut limit = counter.limit().clone();
+            limit.set_max_value(entry.max_value);
+            if let Some(name) = entry.name {
+                limit.set_name(name);
+            }
+            counter.update_to_limit(&limit);
+
+            match (counter.window_type(), entry.value) {
+                (WindowType::Fixed, SnapshotValue::Fixed { value, expires_at }) => {
+                    let expires_at = UNIX_EPOCH + Duration::from_micros(expires_at);
+                    if expires_at <= now {
+                        continue;
+                    }
+                    let value = AtomicExpiringValue::new(value, expires_at);
+                    if counter.is_qualified() {
+                        self.qualified_counters.insert(counter, Arc::new(value));
+                    } else {
+                        limits_by_namespace
+                            .entry(limit.namespace().clone())
+                            .or_default()
+                            .insert(limit, value);
+                    }
+                }
+                (WindowType::Sliding, SnapshotValue::Sliding(state)) => {
+                    let window = SlidingWindowValue::from(state);
+                    if !window.is_live_at(&SlidingWindow::of(&counter, now)) {
+                        continue;
+                    }
+                    self.sliding_windows.insert(counter, Arc::new(window));
+                }
+                (WindowType::TokenBucket, SnapshotValue::TokenBucket { tokens, counted_at }) => {
+                    let value = TokenBucketValue::from((tokens, counted_at));
+                    if !value.is_live_at(&TokenBucket::of(&counter), now) {
+                        continue;
+                    }
+                    self.token_buckets.insert(counter, Arc::new(value));
+                }
+                (WindowType::LeakyBucket, SnapshotValue::LeakyBucket { level, leaked_at }) => {
+                    let value = LeakyBucketValue::from((level, leaked_at));
+                    if !value.is_live_at(&LeakyBucket::of(&counter), now) {
+                        continue;
+                    }
+                    self.leaky_buckets.insert(counter, Arc::new(value));
+                }
+                (
+                    WindowType::Distinct,
+                    SnapshotValue::Distinct {
+                        registers,
+                        expires_at,
+                    },
+                ) => {
+                    let expires_at = UNIX_EPOCH + Duration::from_micros(expires_at);
+                    let Some(values) = HyperLogLog::from_registers(registers) else {
+                        continue;
+                    };
+                    if expires_at <= now {
+                        continue;
+                    }
+                    self.distinct_values
+                        .insert(counter, Arc::new(DistinctValues::new(values, expires_at)));
+                }
+                // Doesn't match the counter's window type, so not ours to restore
+                _ => continue,
+            }
+            restored += 1;
+        }
+
+        Ok(restored)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        // Counters are only updated with the write lock held
+        let limits_by_namespace = self.limits_for_namespace.read().unwrap();
+        let now = self.clock.get_current_time();
+        let mut counters = Vec::new();
+
+        let fixed = |value: &AtomicExpiringValue| SnapshotValue::Fixed {
+            value: value.value_at(now),
+            expires_at: value
+                .expires_at()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+        };
+
+        for (limit, value) in limits_by_namespace.values().flat_map(HashMap::iter) {
+            if value.expires_at() > now {
+                let counter = Counter::new(limit.clone(), HashMap::default());
+                counters.push(SnapshotEntry::of(&counter, fixed(value)));
+            }
+        }
+
+        for (counter, value) in self.qualified_counters.iter() {
+            if value.expires_at() > now {
+                counters.push(SnapshotEntry::of(&counter, fixed(&value)));
+            }
+        }
+
+        for (counter, window) in self.sliding_windows.iter() {
+            if window.is_live_at(&SlidingWindow::of(&counter, now)) {
+                counters.push(SnapshotEntry::of(
+                    &counter,
+                    SnapshotValue::Sliding(window.state()),
+                ));
+            }
+        }
+
+        for (counter, value) in self.token_buckets.iter() {
+            if let Some((tokens, counted_at)) = value.state() {
+                if value.is_live_at(&TokenBucket::of(&counter), now) {
+                    counters.push(SnapshotEntry::of(
+                        &counter,
+                        SnapshotValue::TokenBucket { tokens, counted_at },
+                    ));
+                }
+            }
+        }
+
+        for (counter, value) in self.leaky_buckets.iter() {
+            if let Some((level, leaked_at)) = value.state() {
+                if value.is_live_at(&LeakyBucket::of(&counter), now) {
+                    counters.push(SnapshotEntry::of(
+                        &counter,
+                        SnapshotValue::LeakyBucket { level, leaked_at },
+                    ));
+                }
+            }
+        }
+
+        for (counter, value) in self.distinct_values.iter() {
+            let (values, expires_at) = value.state();
+            if expires_at > now {
+                counters.push(SnapshotEntry::of(
+                    &counter,
+                    SnapshotValue::Distinct {
+                        registers: values.registers().to_vec(),
+                        expires_at: expires_at
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_micros() as u64,
+                    },
+                ));
+            }
+        }
+
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            counters,
+        }
+    }
+
+    fn cache<V: Send + Sync + 'static>(cache_size: u64) -> Cache<Counter, Arc<V>> {
+        Cache::builder()
+            .max_capacity(cache_size)
+            .expire_after(IdleCounterExpiry)
+            .build()
+    }
+
+    fn check_and_update_with(
+        &self,
+        limits_by_namespace: &mut NamespacedLimitCounters<AtomicExpiringValue>,
+        counters: &mut [Counter],
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        // The storage is told about the limits as added, not about the
+        // wildcard ones as they apply to each namespace, which start here
+        for counter in counters
+            .iter()
+            .filter(|c| !c.is_qualified() && c.window_type() == WindowType::Fixed)
+        {
+            let limit = counter.limit();
+            let known = limits_by_namespace
+                .get(limit.namespace())
+                .is_some_and(|limits| limits.contains_key(limit));
+            if !known {
+                limits_by_namespace
+                    .entry(limit.namespace().clone())
+                    .or_default()
+                    .insert(limit.clone(), AtomicExpiringValue::default());
+            }
+        }
+        let limits_by_namespace = &*limits_by_namespace;
+
+        let mut first_limited = None;
+        // Along with the hits to count in each of them
+        let mut counter_values_to_update: Vec<(&AtomicExpiringValue, Duration, i64)> = Vec::new();
+        let mut qualified_counter_values_to_updated: Vec<(
+            Arc<AtomicExpiringValue>,
+            Duration,
+            i64,
+        )> = Vec::new();
+        let mut sliding_window_values_to_update: Vec<(
+            Arc<SlidingWindowValue>,
+            SlidingWindow,
+            i64,
+        )> = Vec::new();
+        let mut token_bucket_values_to_update: Vec<(Arc<TokenBucketValue>, TokenBucket, i64)> =
+            Vec::new();
+        let mut leaky_bucket_values_to_update: Vec<(Arc<LeakyBucketValue>, LeakyBucket, i64)> =
+            Vec::new();
+        // Along with the value to add to each of them, when new to it
+        let mut distinct_values_to_update: Vec<(Arc<DistinctValues>, Duration, String)> =
+            Vec::new();
+        let now = self.clock.get_current_time();
+
+        let overflow_policy = self.overflow_policy;
+        let mut process_counter = |counter: &mut Counter,
+                                   value: i64,
+                                   ttl: Duration,
+                                   delta: i64|
+         -> Result<Option<Authorization>, StorageErr> {
+            if load_counters {
+                let remaining = counter
+                    .max_value()
+                    .saturating_sub(value.saturating_add(delta));
+                counter.set_remaining(remaining);
+                // An expired counter starts a new window with this very hit
+                if ttl.is_zero() {
+                    counter.set_expires_in(counter.window_at(now));
+                } else {
+                    counter.set_expires_in(ttl);
+                }
+                if first_limited.is_none() && remaining < 0 {
+                    first_limited = Some(Authorization::Limited(
+                        counter.limit().name().map(|n| n.to_owned()),
+                    ));
+                }
+            }
+            if !overflow_policy.is_within_limit(counter, value, delta)? {
+                return Ok(Some(Authorization::Limited(
+                    counter.limit().name().map(|n| n.to_owned()),
+                )));
+            }
+            Ok(None)
+        };
+
+        // Process simple counters
+        for counter in counters
+            .iter_mut()
+            .filter(|c| !c.is_qualified() && c.window_type() == WindowType::Fixed)
+        {
+            let delta = counter.hits_for(delta);
+            let atomic_expiring_value: &AtomicExpiringValue = limits_by_namespace
+                .get(counter.limit().namespace())
+                .and_then(|limits| limits.get(counter.limit()))
+                .unwrap();
+
+            if let Some(limited) = process_counter(
+                counter,
+                atomic_expiring_value.value_at(now),
+                atomic_expiring_value.ttl_at(now),
+                delta,
+            )? {
+                if !load_counters {
+                    return Ok(limited);
+                }
+            }
+            counter_values_to_update.push((atomic_expiring_value, counter.window_at(now), delta));
+        }
+
+        // Process qualified counters
+        for counter in counters
+            .iter_mut()
+            .filter(|c| c.is_qualified() && c.window_type() == WindowType::Fixed)
+        {
+            let delta = counter.hits_for(delta);
+            let value = match self.qualified_counters.get(counter) {
+                None => self.qualified_counters.get_with(counter.clone(), || {
+                    Arc::new(AtomicExpiringValue::new(0, now + counter.window_at(now)))
+                }),
+                Some(counter) => counter,
+            };
+
+            if let Some(limited) =
+                process_counter(counter, value.value_at(now), value.ttl_at(now), delta)?
+            {
+                if !load_counters {
+                    return Ok(limited);
+                }
+            }
+
+            qualified_counter_values_to_updated.push((value, counter.window_at(now), delta));
+        }
+
+        // Process sliding windows
+        for counter in counters
+            .iter_mut()
+            .filter(|c| c.window_type() == WindowType::Sliding)
+        {
+            let delta = counter.hits_for(delta);
+            let value = self.sliding_window_of(counter);
+            let window = SlidingWindow::of(counter, now);
+
+            if let Some(limited) =
+                process_counter(counter, value.value_at(&window), window.expires_in(), delta)?
+            {
+                if !load_counters {
+                    return Ok(limited);
+                }
+            }
+
+            sliding_window_values_to_update.push((value, window, delta));
+        }
+
+        // Process token buckets
+        for counter in counters
+            .iter_mut()
+            .filter(|c| c.window_type() == WindowType::TokenBucket)
+        {
+            let delta = counter.hits_for(delta);
+            let value = self.token_bucket_of(counter);
+            let bucket = TokenBucket::of(counter);
+            let hits = Self::token_bucket_hits(counter, &value, now);
+
+            if let Some(limited) = process_counter(
+                counter,
+                hits,
+                bucket.full_in(value.tokens_at(&bucket, now) - delta as f64),
+                delta,
+            )? {
+                if !load_counters {
+                    return Ok(limited);
+                }
+            }
+
+            token_bucket_values_to_update.push((value, bucket, delta));
+        }
+
+        // Process leaky buckets
+        for counter in counters
+            .iter_mut()
+            .filter(|c| c.window_type() == WindowType::LeakyBucket)
+        {
+            let delta = counter.hits_for(delta);
+            let value = self.leaky_bucket_of(counter);
+            let bucket = LeakyBucket::of(counter);
+            let hits = Self::leaky_bucket_hits(counter, &value, now);
+
+            if let Some(limited) = process_counter(
+                counter,
+                hits,
+                bucket.empty_in(value.level_at(&bucket, now) + delta as f64),
+                delta,
+            )? {
+                if !load_counters {
+                    return Ok(limited);
+                }
+            }
+
+            leaky_bucket_values_to_update.push((value, bucket, delta));
+        }
+
+        // Process distinct values
+        for counter in counters
+            .iter_mut()
+            .filter(|c| c.window_type() == WindowType::Distinct)
+        {
+            let values = self.distinct_values_of(counter);
+            let (value, delta) =
+                Self::distinct_value_hits(counter, &values, counter.hits_for(delta), now);
+
+            if let Some(limited) = process_counter(counter, value, values.ttl_at(now), delta)? {
+                if !load_counters {
+                    return Ok(limited);
+                }
+            }
+
+            if let Some(value) = counter.distinct_value().filter(|_| delta > 0) {
+                distinct_values_to_update.push((values, counter.window_at(now), value.to_owned()));
+            }
+        }
+
+        if let Some(limited) = first_limited {
+            return Ok(limited);
+        }
+
+        // Update counters
+        counter_values_to_update.iter().for_each(|(v, ttl, delta)| {
+            v.update(*delta, *ttl, now);
+        });
+        qualified_counter_values_to_updated
+            .iter()
+            .for_each(|(v, ttl, delta)| {
+                v.update(*delta, *ttl, now);
+            });
+        sliding_window_values_to_update
+            .iter()
+            .for_each(|(v, window, delta)| {
+                v.update(*delta, window);
+            });
+        token_bucket_values_to_update
+            .iter()
+            .for_each(|(v, bucket, delta)| {
+                v.consume(bucket, *delta, now);
+            });
+        leaky_bucket_values_to_update
+            .iter()
+            .for_each(|(v, bucket, delta)| {
+                v.fill(bucket, *delta, now);
+            });
+        distinct_values_to_update
+            .iter()
+            .for_each(|(v, window, value)| {
+                v.add(value, *window, now);
+            });
+
+        Ok(Authorization::Ok)
+    }
+
+    fn sliding_window_of(&self, counter: &Counter) -> Arc<SlidingWindowValue> {
+        self.sliding_windows
+            .get_with(counter.clone(), || Arc::new(SlidingWindowValue::default()))
+    }
+
+    fn token_bucket_of(&self, counter: &Counter) -> Arc<TokenBucketValue> {
+        self.token_buckets
+            .get_with(counter.clone(), || Arc::new(TokenBucketValue::default()))
+    }
+
+    // The tokens missing from the bucket, so that they can be accounted for as
+    // hits of any other counter.
+    fn token_bucket_hits(counter: &Counter, value: &TokenBucketValue, when: SystemTime) -> i64 {
+        let tokens = value.tokens_at(&TokenBucket::of(counter), when);
+        counter.max_value() - tokens.floor() as i64
+    }
+
+    fn leaky_bucket_of(&self, counter: &Counter) -> Arc<LeakyBucketValue> {
+        self.leaky_buckets
+            .get_with(counter.clone(), || Arc::new(LeakyBucketValue::default()))
+    }
+
+    fn leaky_bucket_hits(counter: &Counter, value: &LeakyBucketValue, when: SystemTime) -> i64 {
+        let bucket = LeakyBucket::of(counter);
+        bucket.hits(value.level_at(&bucket, when))
+    }
+
+    fn distinct_values_of(&self, counter: &Counter) -> Arc<DistinctValues> {
+        self.distinct_values
+            .get_with(counter.clone(), || Arc::new(DistinctValues::default()))
+    }
+
+    // The distinct values counted so far, capped at the max value so that the
+    // ones already seen still get through should the estimate overshoot it,
+    // along with the one the request counts for, unless its value was seen.
+    fn distinct_value_hits(
+        counter: &Counter,
+        values: &DistinctValues,
+        delta: i64,
+        when: SystemTime,
+    ) -> (i64, i64) {
+        let count = (values.count_at(when) as i64).min(counter.max_value());
+        let new = delta > 0
+            && counter
+                .distinct_value()
+                .is_some_and(|value| values.is_new_at(value, when));
+        (count, i64::from(new))
+    }
+
     fn counters_in_namespace(
         &self,
         namespace: &Namespace,
@@ -269,16 +941,149 @@ impl InMemoryStorage {
         {
             counters_by_limit.remove(limit);
         }
+
+        match limit.window_type() {
+            WindowType::Fixed => {
+                for (counter, _) in self.qualified_counters.iter() {
+                    if counter.limit() == limit {
+                        self.qualified_counters.invalidate(counter.deref());
+                    }
+                }
+            }
+            WindowType::Sliding => {
+                for (counter, _) in self.sliding_windows.iter() {
+                    if counter.limit() == limit {
+                        self.sliding_windows.invalidate(counter.deref());
+                    }
+                }
+            }
+            WindowType::TokenBucket => {
+                for (counter, _) in self.token_buckets.iter() {
+                    if counter.limit() == limit {
+                        self.token_buckets.invalidate(counter.deref());
+                    }
+                }
+            }
+            WindowType::LeakyBucket => {
+                for (counter, _) in self.leaky_buckets.iter() {
+                    if counter.limit() == limit {
+                        self.leaky_buckets.invalidate(counter.deref());
+                    }
+                }
+            }
+            WindowType::Distinct => {
+                for (counter, _) in self.distinct_values.iter() {
+                    if counter.limit() == limit {
+                        self.distinct_values.invalidate(counter.deref());
+                    }
+                }
+            }
+        }
     }
+}
 
-    fn counter_is_within_limits(counter: &Counter, current_val: Option<&i64>, delta: i64) -> bool {
-        match current_val {
-            Some(current_val) => current_val + delta <= counter.max_value(),
-            None => counter.max_value() >= delta,
+// Snapshots are JSON documents listing the counters, each along with the max
+// value and name of its limit, as neither is part of the serialized form of a
+// counter, and its value, with the times as microseconds since the epoch for
+// fixed windows and distinct values, and milliseconds for token and leaky
+// buckets, as they are stored. Distinct values are the registers of their
+// HyperLogLog.
+const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    counters: Vec<SnapshotEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    counter: Counter,
+    max_value: i64,
+    name: Option<String>,
+    value: SnapshotValue,
+}
+
+impl SnapshotEntry {
+    fn of(counter: &Counter, value: SnapshotValue) -> Self {
+        Self {
+            counter: counter.clone(),
+            max_value: counter.max_value(),
+            name: counter.limit().name().map(str::to_owned),
+            value,
         }
     }
 }
 
+#[derive(Serialize, Deserialize)]
+enum SnapshotValue {
+    Fixed { value: i64, expires_at: u64 },
+    Sliding(SlidingWindowState),
+    TokenBucket { tokens: f64, counted_at: u64 },
+    LeakyBucket { level: f64, leaked_at: u64 },
+    Distinct { registers: Vec<u8>, expires_at: u64 },
+}
+
+// Values are updated in place, so the cache only sees them being read. A
+// counter that hasn't been read for as long as its window lasts is over, and
+// would start afresh anyways, so there is no point in keeping it around.
+pub(crate) struct IdleCounterExpiry;
+
+impl IdleCounterExpiry {
+    fn idle_timeout(counter: &Counter) -> Duration {
+        match counter.window_type() {
+            WindowType::Fixed | WindowType::Distinct => counter.window_at(SystemTime::now()),
+            // the hits of the previous window still weigh on the current one
+            WindowType::Sliding => match counter.window() {
+                window if window.is_zero() => Duration::from_secs(2),
+                window => window * 2,
+            },
+            // leaves room for buckets overdrawn by as much as their capacity
+            WindowType::TokenBucket => {
+                let bucket = TokenBucket::of(counter);
+                bucket.full_in(-bucket.capacity())
+            }
+            // leaves room for buckets overfilled by as much as their size
+            WindowType::LeakyBucket => {
+                let bucket = LeakyBucket::of(counter);
+                bucket.empty_in(2.0 * bucket.size())
+            }
+        }
+    }
+}
+
+impl<V> Expiry<Counter, Arc<V>> for IdleCounterExpiry {
+    fn expire_after_create(
+        &self,
+        counter: &Counter,
+        _value: &Arc<V>,
+        _current_time: Instant,
+    ) -> Option<Duration> {
+        Some(Self::idle_timeout(counter))
+    }
+
+    fn expire_after_read(
+        &self,
+        counter: &Counter,
+        _value: &Arc<V>,
+        _current_time: Instant,
+        _current_duration: Option<Duration>,
+        _last_modified_at: Instant,
+    ) -> Option<Duration> {
+        Some(Self::idle_timeout(counter))
+    }
+
+    fn expire_after_update(
+        &self,
+        counter: &Counter,
+        _value: &Arc<V>,
+        _current_time: Instant,
+        _current_duration: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(Self::idle_timeout(counter))
+    }
+}
+
 impl Default for InMemoryStorage {
     fn default() -> Self {
         Self::new(10_000)
@@ -288,6 +1093,8 @@ impl Default for InMemoryStorage {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::limit::WindowStart;
+    use crate::storage::clock::ManualClock;
 
     #[test]
     fn counters_for_multiple_limit_per_ns() {
@@ -311,4 +1118,238 @@ mod tests {
             2
         );
     }
+
+    #[test]
+    fn hits_overflowing_a_counter_saturate_it_or_are_rejected() {
+        let limit = Limit::new("ns", i64::MAX, 60, Vec::<String>::default(), vec!["id"]);
+        let counter = Counter::new(
+            limit.clone(),
+            HashMap::from([("id".to_string(), "1".to_string())]),
+        );
+        let limits = HashSet::from([limit]);
+
+        let storage = InMemoryStorage::default();
+        storage.update_counter(&counter, i64::MAX - 1).unwrap();
+        assert!(!storage.is_within_limits(&counter, 2).unwrap());
+        assert!(matches!(
+            storage
+                .check_and_update(&mut vec![counter.clone()], 2, false)
+                .unwrap(),
+            Authorization::Limited(_)
+        ));
+        storage.update_counter(&counter, 2).unwrap();
+        let counters = storage.get_counters(&limits).unwrap();
+        assert_eq!(counters.iter().next().unwrap().remaining(), Some(0));
+
+        let storage = InMemoryStorage::default().with_overflow_policy(OverflowPolicy::Reject);
+        storage.update_counter(&counter, i64::MAX - 1).unwrap();
+        assert!(storage.is_within_limits(&counter, 2).is_err());
+        assert!(storage
+            .check_and_update(&mut vec![counter.clone()], 2, false)
+            .is_err());
+        assert!(storage.update_counter(&counter, 2).is_err());
+        let counters = storage.get_counters(&limits).unwrap();
+        assert_eq!(counters.iter().next().unwrap().remaining(), Some(1));
+    }
+
+    #[test]
+    fn counters_whose_window_is_over_are_dropped() {
+        let storage = InMemoryStorage::default();
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            1,
+            Vec::<String>::default(),
+            vec!["id"],
+        );
+        for id in 0..100 {
+            let counter = Counter::new(
+                limit.clone(),
+                HashMap::from([("id".to_string(), id.to_string())]),
+            );
+            storage.update_counter(&counter, 1).unwrap();
+        }
+        assert_eq!(storage.counter_count(), 100);
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(storage.counter_count(), 0);
+    }
+
+    #[test]
+    fn windows_are_over_once_the_clock_says_so() {
+        // At the start of a sliding window, as they're aligned to the epoch
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_040));
+        let storage = InMemoryStorage::default().with_clock(clock.clone());
+        let values = HashMap::from([("id".to_string(), "1".to_string())]);
+        let fixed = Limit::new("ns", 2, 60, Vec::<String>::default(), vec!["id"]);
+        let mut sliding = fixed.clone();
+        sliding.set_window_type(WindowType::Sliding);
+
+        for limit in [fixed, sliding] {
+            let counter = Counter::new(limit.clone(), values.clone());
+            storage.update_counter(&counter, 2).unwrap();
+            assert!(!storage.is_within_limits(&counter, 1).unwrap());
+
+            clock.advance(Duration::from_secs(59));
+            assert!(!storage.is_within_limits(&counter, 1).unwrap());
+
+            // The hits of the previous window of a sliding one weigh on the
+            // current one, for as long as it lasts
+            clock.advance(Duration::from_secs(1));
+            let within = storage.is_within_limits(&counter, 1).unwrap();
+            assert_eq!(within, limit.window_type() == WindowType::Fixed);
+
+            clock.advance(Duration::from_secs(60));
+            assert!(storage.is_within_limits(&counter, 1).unwrap());
+            let counters = storage.get_counters(&HashSet::from([limit])).unwrap();
+            assert!(counters.is_empty());
+        }
+    }
+
+    #[test]
+    fn nodes_agree_on_the_windows_aligned_to_the_clock() {
+        let mut limit = Limit::new("ns", 10, 1, Vec::<String>::default(), vec!["id"]);
+        limit.set_window_start(WindowStart::Clock);
+        let counter = Counter::new(
+            limit.clone(),
+            HashMap::from([("id".to_string(), "1".to_string())]),
+        );
+        let limits = HashSet::from([limit]);
+
+        // Right after the start of a window, so that both hits land in it
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_millis(1_700_000_000_050));
+        let nodes = [
+            InMemoryStorage::default().with_clock(clock.clone()),
+            InMemoryStorage::default().with_clock(clock.clone()),
+        ];
+        let mut ends = Vec::new();
+        for node in &nodes {
+            node.update_counter(&counter, 1).unwrap();
+            let counters = node.get_counters(&limits).unwrap();
+            let expires_in = counters.iter().next().unwrap().expires_in().unwrap();
+            ends.push(clock.get_current_time() + expires_in);
+            clock.advance(Duration::from_millis(300));
+        }
+
+        assert_eq!(ends[0], ends[1]);
+        assert_eq!(ends[0], UNIX_EPOCH + Duration::from_secs(1_700_000_001));
+    }
+
+    #[test]
+    fn distinct_limits_count_the_values_rather_than_the_hits() {
+        let mut limit = Limit::new("ns", 2, 60, Vec::<String>::default(), vec!["tenant"]);
+        limit.set_window_type(WindowType::Distinct);
+        limit.set_distinct_variable(Some("source.address".to_string()));
+        let counter_for = |address: &str| {
+            Counter::new(
+                limit.clone(),
+                HashMap::from([
+                    ("tenant".to_string(), "a".to_string()),
+                    ("source.address".to_string(), address.to_string()),
+                ]),
+            )
+        };
+        let check = |storage: &InMemoryStorage, address: &str| {
+            storage
+                .check_and_update(&mut vec![counter_for(address)], 1, false)
+                .unwrap()
+        };
+
+        let storage = InMemoryStorage::default();
+        for address in ["10.0.0.1", "10.0.0.1", "10.0.0.2", "10.0.0.1"] {
+            assert!(matches!(check(&storage, address), Authorization::Ok));
+        }
+        assert!(matches!(
+            check(&storage, "10.0.0.3"),
+            Authorization::Limited(_)
+        ));
+        assert!(storage
+            .is_within_limits(&counter_for("10.0.0.2"), 1)
+            .unwrap());
+        assert!(!storage
+            .is_within_limits(&counter_for("10.0.0.3"), 1)
+            .unwrap());
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("snapshot.json");
+        storage.save_snapshot(&path).unwrap();
+        let restored = InMemoryStorage::default();
+        assert_eq!(restored.load_snapshot(&path).unwrap(), 1);
+        let counters = restored
+            .get_counters(&HashSet::from([limit.clone()]))
+            .unwrap();
+        assert_eq!(counters.iter().next().unwrap().remaining(), Some(0));
+        assert!(matches!(check(&restored, "10.0.0.2"), Authorization::Ok));
+    }
+
+    #[test]
+    fn snapshots_restore_the_counters_whose_window_isnt_over() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("snapshot.json");
+        let values = HashMap::from([("id".to_string(), "1".to_string())]);
+
+        let mut unqualified = Limit::new(
+            "ns",
+            10,
+            60,
+            Vec::<String>::default(),
+            Vec::<String>::default(),
+        );
+        unqualified.set_name("unqualified".to_string());
+        let qualified = Limit::new("ns", 10, 60, Vec::<String>::default(), vec!["id"]);
+        let mut sliding = Limit::new("ns", 10, 60, Vec::<String>::default(), vec!["id"]);
+        sliding.set_window_type(WindowType::Sliding);
+        let mut bucket = Limit::new("ns", 10, 60, Vec::<String>::default(), vec!["id"]);
+        bucket.set_window_type(WindowType::TokenBucket);
+        let mut leaky = Limit::new("ns", 10, 60, Vec::<String>::default(), vec!["id"]);
+        leaky.set_window_type(WindowType::LeakyBucket);
+        let short = Limit::new("ns", 10, 1, Vec::<String>::default(), vec!["id"]);
+
+        let counters: Vec<Counter> = [unqualified, qualified, sliding, bucket, leaky, short]
+            .into_iter()
+            .map(|limit| Counter::new(limit, values.clone()))
+            .collect();
+        let clock = ManualClock::default();
+        let storage = InMemoryStorage::default().with_clock(clock.clone());
+        for counter in &counters {
+            storage.add_counter(counter.limit()).unwrap();
+            storage.update_counter(counter, 3).unwrap();
+        }
+        storage.save_snapshot(&path).unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        let restored = InMemoryStorage::default().with_clock(clock);
+        assert_eq!(restored.load_snapshot(&path).unwrap(), 5);
+
+        let limits = counters.iter().map(|c| c.limit().clone()).collect();
+        let restored_counters = restored.get_counters(&limits).unwrap();
+        assert_eq!(restored_counters.len(), 5);
+        for counter in &restored_counters {
+            assert_eq!(counter.max_value(), 10);
+            assert_eq!(counter.remaining(), Some(7));
+        }
+        assert!(restored_counters
+            .iter()
+            .any(|c| c.limit().name() == Some("unqualified")));
+        assert!(!restored.is_within_limits(&counters[1], 8).unwrap());
+        assert!(restored.is_within_limits(&counters[5], 10).unwrap());
+    }
+
+    #[test]
+    fn missing_snapshots_restore_nothing_and_corrupt_ones_err() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("snapshot.json");
+        let storage = InMemoryStorage::default();
+
+        assert_eq!(storage.load_snapshot(&path).unwrap(), 0);
+
+        std::fs::write(&path, "{\"version\": 1, \"counters\": [").unwrap();
+        let err = storage.load_snapshot(&path).unwrap_err();
+        assert!(err.msg().starts_with("corrupt snapshot"));
+        assert_eq!(err.kind(), StorageErrKind::Serialization);
+
+        std::fs::write(&path, "{\"version\": 42, \"counters\": []}").unwrap();
+        let err = storage.load_snapshot(&path).unwrap_err();
+        assert!(err.msg().contains("unsupported version 42"));
+    }
 }
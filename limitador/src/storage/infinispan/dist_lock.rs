@@ -13,7 +13,7 @@
 // the lock critical section is racing against the TTL, and we cannot guarantee
 // that the section will be finished within the limit of the TTL.
 
-use crate::storage::StorageErr;
+use crate::storage::{StorageErr, StorageErrKind};
 use infinispan::errors::InfinispanError;
 use infinispan::request;
 use infinispan::Infinispan;
@@ -49,6 +49,7 @@ pub async fn lock(
             if retries >= RETRIES {
                 return Err(StorageErr {
                     msg: "can't acquire lock".into(),
+                    kind: StorageErrKind::Other,
                 });
             }
 
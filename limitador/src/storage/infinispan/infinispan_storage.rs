@@ -6,13 +6,13 @@ use crate::storage::infinispan::{
     counters, sets, DEFAULT_INFINISPAN_CONSISTENCY, DEFAULT_INFINISPAN_LIMITS_CACHE_NAME,
 };
 use crate::storage::keys::*;
-use crate::storage::{AsyncCounterStorage, Authorization, StorageErr};
+use crate::storage::{only_fixed_windows, AsyncCounterStorage, Authorization, StorageErr};
 use async_trait::async_trait;
 use infinispan::errors::InfinispanError;
 use infinispan::request;
 use infinispan::Infinispan;
 use std::collections::HashSet;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 pub struct InfinispanStorage {
     infinispan: Infinispan,
@@ -31,10 +31,12 @@ pub struct InfinispanStorageBuilder {
 #[async_trait]
 impl AsyncCounterStorage for InfinispanStorage {
     async fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        only_fixed_windows([counter])?;
         let counter_key = key_for_counter(counter);
         let counter_val =
             counters::get_value(&self.infinispan, &self.cache_name, &counter_key).await?;
 
+        let delta = counter.hits_for(delta);
         match counter_val {
             Some(val) => Ok(val - delta >= 0),
             None => Ok(counter.max_value() - delta >= 0),
@@ -42,16 +44,17 @@ impl AsyncCounterStorage for InfinispanStorage {
     }
 
     async fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
         let counter_key = key_for_counter(counter);
 
         let counter_created = counters::decrement_by(
             &self.infinispan,
             &self.cache_name,
             &counter_key,
-            delta,
+            counter.hits_for(delta),
             &CounterOpts::new(
                 counter.max_value(),
-                Duration::from_secs(counter.seconds()),
+                counter.window_at(SystemTime::now()),
                 self.counters_consistency,
             ),
         )
@@ -71,6 +74,7 @@ impl AsyncCounterStorage for InfinispanStorage {
         delta: i64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
+        only_fixed_windows(counters.iter())?;
         let mut counter_keys = Vec::with_capacity(counters.len());
 
         if load_counters {
@@ -82,7 +86,8 @@ impl AsyncCounterStorage for InfinispanStorage {
                     counters::get_value(&self.infinispan, &self.cache_name, &counter_keys[idx])
                         .await?;
 
-                let remaining = counter_val.unwrap_or(counter.max_value()) - delta;
+                let remaining =
+                    counter_val.unwrap_or(counter.max_value()) - counter.hits_for(delta);
                 counter.set_remaining(remaining);
 
                 if first_limited.is_none() && remaining < 0 {
@@ -112,10 +117,10 @@ impl AsyncCounterStorage for InfinispanStorage {
                 &self.infinispan,
                 &self.cache_name,
                 &counter_key,
-                delta,
+                counter.hits_for(delta),
                 &CounterOpts::new(
                     counter.max_value(),
-                    Duration::from_secs(counter.seconds()),
+                    counter.window_at(SystemTime::now()),
                     self.counters_consistency,
                 ),
             )
@@ -130,6 +135,42 @@ impl AsyncCounterStorage for InfinispanStorage {
         Ok(Authorization::Ok)
     }
 
+    async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
+        let counter_key = key_for_counter(counter);
+
+        // Created again, to start with the value given
+        counters::delete(&self.infinispan, &self.cache_name, &counter_key).await?;
+        counters::decrement_by(
+            &self.infinispan,
+            &self.cache_name,
+            &counter_key,
+            counter.max_value() - remaining,
+            &CounterOpts::new(counter.max_value(), expires_in, self.counters_consistency),
+        )
+        .await?;
+
+        self.add_to_set(key_for_counters_of_limit(counter.limit()), counter_key)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        only_fixed_windows([counter])?;
+        let counter_key = key_for_counter(counter);
+        // The counters that expired are gone already
+        let existed = counters::get_value(&self.infinispan, &self.cache_name, &counter_key)
+            .await?
+            .is_some();
+        counters::delete(&self.infinispan, &self.cache_name, &counter_key).await?;
+        Ok(existed)
+    }
+
     async fn get_counters(&self, limits: HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
         let mut res = HashSet::new();
 
@@ -175,6 +216,14 @@ impl AsyncCounterStorage for InfinispanStorage {
 
         Ok(())
     }
+
+    async fn ping(&self) -> Result<(), StorageErr> {
+        let _ = self
+            .infinispan
+            .run(&request::caches::exists(&self.cache_name))
+            .await?;
+        Ok(())
+    }
 }
 
 impl InfinispanStorage {
@@ -4,7 +4,7 @@ mod infinispan_storage;
 mod response;
 mod sets;
 
-use crate::storage::StorageErr;
+use crate::storage::{StorageErr, StorageErrKind};
 pub use counters::Consistency;
 use infinispan::errors::InfinispanError;
 pub use infinispan_storage::InfinispanStorage;
@@ -12,13 +12,26 @@ pub use infinispan_storage::InfinispanStorageBuilder;
 
 impl From<reqwest::Error> for StorageErr {
     fn from(e: reqwest::Error) -> Self {
-        Self { msg: e.to_string() }
+        let kind = if e.is_connect() || e.is_timeout() {
+            StorageErrKind::Connection
+        } else if e.is_decode() {
+            StorageErrKind::Serialization
+        } else {
+            StorageErrKind::Other
+        };
+        Self {
+            msg: e.to_string(),
+            kind,
+        }
     }
 }
 
 impl From<InfinispanError> for StorageErr {
     fn from(e: InfinispanError) -> Self {
-        Self { msg: e.to_string() }
+        Self {
+            msg: e.to_string(),
+            kind: StorageErrKind::Other,
+        }
     }
 }
 
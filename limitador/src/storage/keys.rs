@@ -153,9 +153,45 @@ pub mod bin {
         }
     }
 
+    // The name of a shared counter, the optional variables, and whether the
+    // limit is a fallback one follow the rest of the key, so that the keys of
+    // the other counters stay as they were. So do the milliseconds of the
+    // window, after the extension, when the limit has any.
+    #[derive(PartialEq, Debug, Serialize, Deserialize)]
+    struct CounterKeyExtension<'a> {
+        shared_counter: Option<&'a str>,
+        optional_variables: Vec<&'a str>,
+        fallback: bool,
+    }
+
+    impl<'a> CounterKeyExtension<'a> {
+        fn of(limit: &'a Limit) -> Option<Self> {
+            let optional_variables = limit.optional_variables_for_key();
+            if limit.shared_counter().is_none()
+                && optional_variables.is_empty()
+                && !limit.is_fallback()
+                && limit.milliseconds().is_none()
+            {
+                return None;
+            }
+            Some(CounterKeyExtension {
+                shared_counter: limit.shared_counter(),
+                optional_variables,
+                fallback: limit.is_fallback(),
+            })
+        }
+    }
+
     pub fn key_for_counter(counter: &Counter) -> Vec<u8> {
         let key: CounterKey = counter.into();
-        postcard::to_stdvec(&key).unwrap()
+        let mut key = postcard::to_stdvec(&key).unwrap();
+        if let Some(extension) = CounterKeyExtension::of(counter.limit()) {
+            key.extend(postcard::to_stdvec(&extension).unwrap());
+        }
+        if let Some(milliseconds) = counter.limit().milliseconds() {
+            key.extend(postcard::to_stdvec(&milliseconds).unwrap());
+        }
+        key
     }
 
     pub fn prefix_for_namespace(namespace: &str) -> Vec<u8> {
@@ -163,19 +199,47 @@ pub mod bin {
     }
 
     pub fn partial_counter_from_counter_key(key: &[u8]) -> Counter {
-        let key: CounterKey = postcard::from_bytes(key).unwrap();
+        let (key, extension): (CounterKey, &[u8]) = postcard::take_from_bytes(key).unwrap();
         let CounterKey {
             ns,
             seconds,
             conditions,
             variables,
         } = key;
+        let (extension, milliseconds): (Option<CounterKeyExtension>, Option<u64>) =
+            if extension.is_empty() {
+                (None, None)
+            } else {
+                let (extension, milliseconds): (CounterKeyExtension, &[u8]) =
+                    postcard::take_from_bytes(extension).unwrap();
+                (
+                    Some(extension),
+                    (!milliseconds.is_empty()).then(|| postcard::from_bytes(milliseconds).unwrap()),
+                )
+            };
+        let optional_variables = extension
+            .as_ref()
+            .map(|extension| extension.optional_variables.as_slice())
+            .unwrap_or_default();
 
         let map: HashMap<String, String> = variables
             .into_iter()
             .map(|(var, value)| (var.to_string(), value.to_string()))
             .collect();
-        let limit = Limit::new(ns, i64::default(), seconds, conditions, map.keys());
+        let mut limit = Limit::new(
+            ns,
+            i64::default(),
+            seconds,
+            conditions,
+            map.keys()
+                .filter(|var| !optional_variables.contains(&var.as_str())),
+        );
+        if let Some(extension) = &extension {
+            limit.set_shared_counter(extension.shared_counter.map(str::to_string));
+            limit.set_optional_variables(extension.optional_variables.iter().copied());
+            limit.set_fallback(extension.fallback);
+        }
+        limit.set_milliseconds(milliseconds);
         Counter::new(limit, map)
     }
 
@@ -222,6 +286,58 @@ pub mod bin {
             assert_eq!(counter, partial_counter_from_counter_key(&raw));
         }
 
+        #[test]
+        fn shared_counter_key_and_counter_are_symmetric() {
+            let namespace = "ns_counter:";
+            let mut limit =
+                Limit::new(namespace, 1, 1, vec!["req.method == 'GET'"], vec!["app_id"]);
+            limit.set_shared_counter(Some("budget".to_string()));
+            let variables = HashMap::from([("app_id".to_string(), "123".to_string())]);
+            let counter = Counter::new(limit.clone(), variables);
+            let raw = key_for_counter(&counter);
+            assert_eq!(counter, partial_counter_from_counter_key(&raw));
+        }
+
+        #[test]
+        fn optional_variables_counter_key_and_counter_are_symmetric() {
+            let namespace = "ns_counter:";
+            let mut limit =
+                Limit::new(namespace, 1, 1, vec!["req.method == 'GET'"], vec!["app_id"]);
+            limit.set_optional_variables(["region", "zone"]);
+            let variables = HashMap::from([
+                ("app_id".to_string(), "123".to_string()),
+                ("zone".to_string(), "a".to_string()),
+            ]);
+            let counter = Counter::new(limit.clone(), variables);
+            let raw = key_for_counter(&counter);
+            assert_eq!(counter, partial_counter_from_counter_key(&raw));
+        }
+
+        #[test]
+        fn fallback_counter_key_and_counter_are_symmetric() {
+            let namespace = "ns_counter:";
+            let mut limit =
+                Limit::new(namespace, 1, 1, vec!["req.method == 'GET'"], vec!["app_id"]);
+            limit.set_fallback(true);
+            let variables = HashMap::from([("app_id".to_string(), "123".to_string())]);
+            let counter = Counter::new(limit.clone(), variables);
+            let raw = key_for_counter(&counter);
+            assert_eq!(counter, partial_counter_from_counter_key(&raw));
+        }
+
+        #[test]
+        fn milliseconds_counter_key_and_counter_are_symmetric() {
+            let namespace = "ns_counter:";
+            let limit = Limit::new(namespace, 1, 0, vec!["req.method == 'GET'"], vec!["app_id"]);
+            let variables = HashMap::from([("app_id".to_string(), "123".to_string())]);
+            let mut sub_second = limit.clone();
+            sub_second.set_milliseconds(Some(250));
+            let counter = Counter::new(sub_second, variables.clone());
+            let raw = key_for_counter(&counter);
+            assert_eq!(counter, partial_counter_from_counter_key(&raw));
+            assert_ne!(raw, key_for_counter(&Counter::new(limit, variables)));
+        }
+
         #[test]
         fn counter_key_starts_with_namespace_prefix() {
             let namespace = "ns_counter:";
@@ -0,0 +1,161 @@
+use crate::counter::Counter;
+use crate::storage::token_bucket::millis_since_epoch;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+// Leaky buckets hold up to `max_value` hits, that leak out of them continuously
+// at the limit's leak rate. A hit is only let in while there's room for it in
+// the bucket, so that once a burst has filled it, hits are admitted at the
+// leak rate. A bucket that has leaked empty is the same as one that was never
+// hit, so there's no need to keep it around.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct LeakyBucket {
+    size: f64,
+    leak_per_ms: f64,
+}
+
+impl LeakyBucket {
+    pub fn of(counter: &Counter) -> Self {
+        let size = counter.max_value() as f64;
+        // Unless told otherwise, leak the whole bucket over its window
+        let leak_per_sec = match counter.limit().leak_rate() {
+            Some(rate) if rate > 0 => rate as f64,
+            _ => match counter.window().as_secs_f64() {
+                window if window > 0.0 => size / window,
+                _ => size,
+            },
+        };
+        Self {
+            size,
+            leak_per_ms: leak_per_sec / 1000.0,
+        }
+    }
+
+    pub fn size(&self) -> f64 {
+        self.size
+    }
+
+    pub fn leak_per_ms(&self) -> f64 {
+        self.leak_per_ms
+    }
+
+    /// Level of the bucket at `now_ms`, given the one stored at `last_ms`.
+    pub fn level_at(&self, stored: Option<(f64, u64)>, now_ms: u64) -> f64 {
+        match stored {
+            None => 0.0,
+            Some((level, last_ms)) => {
+                let leaked = now_ms.saturating_sub(last_ms) as f64 * self.leak_per_ms;
+                (level - leaked).max(0.0)
+            }
+        }
+    }
+
+    /// The hits a bucket filled up to `level` holds, as counted against its
+    /// size, the ones partially leaked included.
+    pub fn hits(&self, level: f64) -> i64 {
+        level.ceil() as i64
+    }
+
+    /// Time it takes for a bucket filled up to `level` to be empty again.
+    pub fn empty_in(&self, level: f64) -> Duration {
+        if level <= 0.0 || self.leak_per_ms <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_millis((level / self.leak_per_ms).ceil() as u64)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct LeakyBucketValue {
+    // level of the bucket, and when it last leaked
+    state: Mutex<Option<(f64, u64)>>,
+}
+
+impl LeakyBucketValue {
+    pub fn level_at(&self, bucket: &LeakyBucket, when: SystemTime) -> f64 {
+        bucket.level_at(*self.state.lock().unwrap(), millis_since_epoch(when))
+    }
+
+    pub fn fill(&self, bucket: &LeakyBucket, delta: i64, when: SystemTime) -> f64 {
+        let now_ms = millis_since_epoch(when);
+        let mut state = self.state.lock().unwrap();
+        let level = bucket.level_at(*state, now_ms) + delta as f64;
+        *state = Some((level, now_ms));
+        level
+    }
+
+    /// The level of the bucket, and when it last leaked, unless never hit.
+    pub fn state(&self) -> Option<(f64, u64)> {
+        *self.state.lock().unwrap()
+    }
+
+    /// Whether the bucket still holds some hits at `when`.
+    pub fn is_live_at(&self, bucket: &LeakyBucket, when: SystemTime) -> bool {
+        self.level_at(bucket, when) > 0.0
+    }
+}
+
+impl From<(f64, u64)> for LeakyBucketValue {
+    fn from(state: (f64, u64)) -> Self {
+        Self {
+            state: Mutex::new(Some(state)),
+        }
+    }
+}
+
+impl Clone for LeakyBucketValue {
+    fn clone(&self) -> Self {
+        Self {
+            state: Mutex::new(*self.state.lock().unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limit::{Limit, WindowType};
+    use std::collections::HashMap;
+    use std::time::UNIX_EPOCH;
+
+    fn bucket(max_value: i64, seconds: u64, leak_rate: Option<u64>) -> LeakyBucket {
+        let mut limit = Limit::new(
+            "ns",
+            max_value,
+            seconds,
+            Vec::<String>::default(),
+            vec!["id"],
+        );
+        limit.set_window_type(WindowType::LeakyBucket);
+        limit.set_leak_rate(leak_rate);
+        let values = HashMap::from([("id".to_string(), "1".to_string())]);
+        LeakyBucket::of(&Counter::new(limit, values))
+    }
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn leaks_the_whole_bucket_over_the_limit_seconds_by_default() {
+        let bucket = bucket(10, 10, None);
+        assert_eq!(bucket.leak_per_ms(), 0.001);
+        assert_eq!(bucket.level_at(None, 1_000), 0.0);
+        assert_eq!(bucket.empty_in(5.0), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn hits_leak_out_at_the_leak_rate() {
+        let bucket = bucket(10, 60, Some(2));
+        let value = LeakyBucketValue::default();
+        assert_eq!(value.fill(&bucket, 10, at(1_000)), 10.0);
+        assert!(value.is_live_at(&bucket, at(1_000)));
+        assert_eq!(value.level_at(&bucket, at(2_500)), 7.0);
+        assert_eq!(bucket.hits(value.level_at(&bucket, at(2_750))), 7);
+        assert_eq!(value.fill(&bucket, 3, at(2_500)), 10.0);
+        // never goes below empty
+        assert_eq!(value.level_at(&bucket, at(60_000)), 0.0);
+        assert!(!value.is_live_at(&bucket, at(60_000)));
+    }
+}
@@ -1,13 +1,19 @@
 use crate::counter::Counter;
-use crate::limit::{Limit, Namespace};
+use crate::limit::{Limit, Namespace, WindowType, WILDCARD_NAMESPACE};
 use crate::InMemoryStorage;
 use async_trait::async_trait;
 use std::collections::{HashMap, HashSet};
-use std::sync::RwLock;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use thiserror::Error;
 
+pub mod clock;
+pub mod composite;
 #[cfg(feature = "disk_storage")]
 pub mod disk;
+pub mod distributed;
+pub mod failover;
 pub mod in_memory;
 pub mod wasm;
 
@@ -17,13 +23,25 @@ pub mod redis;
 #[cfg(feature = "infinispan_storage")]
 pub mod infinispan;
 
+#[cfg(feature = "dynamodb_storage")]
+pub mod dynamodb;
+
+#[cfg(feature = "postgres_storage")]
+pub mod postgres;
+
 mod atomic_expiring_value;
+mod hyperloglog;
 #[cfg(any(
     feature = "disk_storage",
+    feature = "dynamodb_storage",
     feature = "infinispan_storage",
+    feature = "postgres_storage",
     feature = "redis_storage"
 ))]
 mod keys;
+mod leaky_bucket;
+mod sliding_window;
+mod token_bucket;
 
 pub enum Authorization {
     Ok,
@@ -62,8 +80,12 @@ impl Storage {
     pub fn add_limit(&self, limit: Limit) -> bool {
         let namespace = limit.namespace().clone();
         let mut limits = self.limits.write().unwrap();
-        self.counters.add_counter(&limit).unwrap();
-        limits.entry(namespace).or_default().insert(limit)
+        let limits = limits.entry(namespace).or_default();
+        if name_taken(limits, &limit) {
+            return false;
+        }
+        self.counters.add_counter(&limit.counter_limit()).unwrap();
+        limits.insert(limit)
     }
 
     pub fn update_limit(&self, update: &Limit) -> bool {
@@ -71,7 +93,14 @@ impl Storage {
         let limits = namespaces.get_mut(update.namespace());
         if let Some(limits) = limits {
             let req_update = if let Some(limit) = limits.get(update) {
-                limit.max_value() != update.max_value() || limit.name() != update.name()
+                limit.max_value() != update.max_value()
+                    || limit.name() != update.name()
+                    || limit.priority() != update.priority()
+                    || limit.cost() != update.cost()
+                    || limit.max_values() != update.max_values()
+                    || limit.jitter() != update.jitter()
+                    || limit.group() != update.group()
+                    || limit.is_enabled() != update.is_enabled()
             } else {
                 false
             };
@@ -91,10 +120,15 @@ impl Storage {
         }
     }
 
+    /// The limits that apply to the requests of the namespace: its own, and
+    /// the wildcard ones, see [`Limit::in_namespace`].
+    pub fn get_limits_applying_to(&self, namespace: &Namespace) -> HashSet<Limit> {
+        limits_applying_to(&self.limits.read().unwrap(), namespace)
+    }
+
     pub fn delete_limit(&self, limit: &Limit) -> Result<(), StorageErr> {
-        let mut limits = HashSet::new();
-        limits.insert(limit.clone());
-        self.counters.delete_counters(limits)?;
+        let counters = counters_of_deleted_limit(&self.limits.read().unwrap(), limit);
+        self.counters.delete_counters(counters)?;
 
         let mut limits = self.limits.write().unwrap();
 
@@ -110,7 +144,28 @@ impl Storage {
 
     pub fn delete_limits(&self, namespace: &Namespace) -> Result<(), StorageErr> {
         if let Some(data) = self.limits.write().unwrap().remove(namespace) {
-            self.counters.delete_counters(data)?;
+            self.counters.delete_counters(counter_limits(&data))?;
+        }
+        Ok(())
+    }
+
+    /// Replaces all the limits with the ones given at once. The counters of the
+    /// limits kept are preserved, the ones of the limits that are gone get
+    /// deleted.
+    pub fn replace_limits(
+        &self,
+        limits: HashMap<Namespace, HashSet<Limit>>,
+    ) -> Result<(), StorageErr> {
+        let removed = {
+            let mut current = self.limits.write().unwrap();
+            for limit in limits.values().flatten() {
+                self.counters.add_counter(&limit.counter_limit())?;
+            }
+            let previous = std::mem::replace(&mut *current, limits);
+            removed_limits(previous, &current)
+        };
+        if !removed.is_empty() {
+            self.counters.delete_counters(removed)?;
         }
         Ok(())
     }
@@ -133,9 +188,40 @@ impl Storage {
             .check_and_update(counters, delta, load_counters)
     }
 
+    pub fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        self.counters.check_and_update_batch(batch, load_counters)
+    }
+
+    pub fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        self.counters.set_counter(counter, remaining, expires_in)
+    }
+
+    pub fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        self.counters.delete_counter(counter)
+    }
+
     pub fn get_counters(&self, namespace: &Namespace) -> Result<HashSet<Counter>, StorageErr> {
-        match self.limits.read().unwrap().get(namespace) {
-            Some(limits) => self.counters.get_counters(limits),
+        let limits = self.get_limits_applying_to(namespace);
+        if limits.is_empty() {
+            return Ok(HashSet::new());
+        }
+        self.counters.get_counters(&counter_limits(&limits))
+    }
+
+    pub fn get_counters_of_limit(&self, limit: &Limit) -> Result<HashSet<Counter>, StorageErr> {
+        match stored_limit(&self.limits.read().unwrap(), limit) {
+            Some(limit) => self
+                .counters
+                .get_counters(&HashSet::from([limit.into_counter_limit()])),
             None => Ok(HashSet::new()),
         }
     }
@@ -144,6 +230,36 @@ impl Storage {
         self.limits.write().unwrap().clear();
         self.counters.clear()
     }
+
+    pub fn ping(&self) -> Result<(), StorageErr> {
+        self.counters.ping()
+    }
+
+    pub fn warm_up(&self) -> Result<(), StorageErr> {
+        self.counters
+            .check_and_update(&mut vec![warm_up_counter()], 1, true)
+            .map(|_| ())
+    }
+}
+
+// The namespace of the counter the storages are warmed up with, out of the
+// ones of the limits, so that it's never told about
+const WARM_UP_NAMESPACE: &str = "limitador.warm_up";
+
+// Over a window short enough for the storages that persist it not to keep it
+// around for long. Qualified, as the storages only keep the other counters for
+// the limits they were told about.
+fn warm_up_counter() -> Counter {
+    Counter::new(
+        Limit::new(
+            WARM_UP_NAMESPACE,
+            1,
+            1,
+            Vec::<String>::default(),
+            vec!["id"],
+        ),
+        HashMap::from([("id".to_string(), "warm_up".to_string())]),
+    )
 }
 
 impl AsyncStorage {
@@ -164,7 +280,7 @@ impl AsyncStorage {
         let mut limits_for_namespace = self.limits.write().unwrap();
 
         match limits_for_namespace.get_mut(&namespace) {
-            Some(limits) => limits.insert(limit),
+            Some(limits) => !name_taken(limits, &limit) && limits.insert(limit),
             None => {
                 let mut limits = HashSet::new();
                 limits.insert(limit);
@@ -179,7 +295,14 @@ impl AsyncStorage {
         let limits = namespaces.get_mut(update.namespace());
         if let Some(limits) = limits {
             let req_update = if let Some(limit) = limits.get(update) {
-                limit.max_value() != update.max_value() || limit.name() != update.name()
+                limit.max_value() != update.max_value()
+                    || limit.name() != update.name()
+                    || limit.priority() != update.priority()
+                    || limit.cost() != update.cost()
+                    || limit.max_values() != update.max_values()
+                    || limit.jitter() != update.jitter()
+                    || limit.group() != update.group()
+                    || limit.is_enabled() != update.is_enabled()
             } else {
                 false
             };
@@ -199,10 +322,14 @@ impl AsyncStorage {
         }
     }
 
+    /// See [`Storage::get_limits_applying_to`].
+    pub fn get_limits_applying_to(&self, namespace: &Namespace) -> HashSet<Limit> {
+        limits_applying_to(&self.limits.read().unwrap(), namespace)
+    }
+
     pub async fn delete_limit(&self, limit: &Limit) -> Result<(), StorageErr> {
-        let mut limits = HashSet::new();
-        limits.insert(limit.clone());
-        self.counters.delete_counters(limits).await?;
+        let counters = counters_of_deleted_limit(&self.limits.read().unwrap(), limit);
+        self.counters.delete_counters(counters).await?;
 
         let mut limits_for_namespace = self.limits.write().unwrap();
 
@@ -219,8 +346,25 @@ impl AsyncStorage {
     pub async fn delete_limits(&self, namespace: &Namespace) -> Result<(), StorageErr> {
         let option = { self.limits.write().unwrap().remove(namespace) };
         if let Some(data) = option {
-            let limits = data.iter().cloned().collect();
-            self.counters.delete_counters(limits).await?;
+            self.counters.delete_counters(counter_limits(&data)).await?;
+        }
+        Ok(())
+    }
+
+    /// Replaces all the limits with the ones given at once. The counters of the
+    /// limits kept are preserved, the ones of the limits that are gone get
+    /// deleted.
+    pub async fn replace_limits(
+        &self,
+        limits: HashMap<Namespace, HashSet<Limit>>,
+    ) -> Result<(), StorageErr> {
+        let removed = {
+            let mut current = self.limits.write().unwrap();
+            let previous = std::mem::replace(&mut *current, limits);
+            removed_limits(previous, &current)
+        };
+        if !removed.is_empty() {
+            self.counters.delete_counters(removed).await?;
         }
         Ok(())
     }
@@ -248,18 +392,160 @@ impl AsyncStorage {
             .await
     }
 
+    pub async fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        self.counters
+            .check_and_update_batch(batch, load_counters)
+            .await
+    }
+
+    pub async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        self.counters
+            .set_counter(counter, remaining, expires_in)
+            .await
+    }
+
+    pub async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        self.counters.delete_counter(counter).await
+    }
+
     pub async fn get_counters(
         &self,
         namespace: &Namespace,
     ) -> Result<HashSet<Counter>, StorageErr> {
-        let limits = self.get_limits(namespace);
+        let limits = counter_limits(&self.get_limits_applying_to(namespace));
         self.counters.get_counters(limits).await
     }
 
+    pub async fn get_counters_of_limit(
+        &self,
+        limit: &Limit,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        let limit = stored_limit(&self.limits.read().unwrap(), limit);
+        match limit {
+            Some(limit) => {
+                self.counters
+                    .get_counters(HashSet::from([limit.into_counter_limit()]))
+                    .await
+            }
+            None => Ok(HashSet::new()),
+        }
+    }
+
     pub async fn clear(&self) -> Result<(), StorageErr> {
         self.limits.write().unwrap().clear();
         self.counters.clear().await
     }
+
+    pub async fn ping(&self) -> Result<(), StorageErr> {
+        self.counters.ping().await
+    }
+
+    pub async fn warm_up(&self) -> Result<(), StorageErr> {
+        self.counters
+            .check_and_update(&mut vec![warm_up_counter()], 1, true)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn flush(&self) -> Result<(), StorageErr> {
+        self.counters.flush().await
+    }
+}
+
+// The limit as stored, with its own max value and name, if it is
+// The limits of the namespace, along with the wildcard ones as if they were
+// its own, so that the counters of these are kept under the namespace too
+fn limits_applying_to(
+    limits: &HashMap<Namespace, HashSet<Limit>>,
+    namespace: &Namespace,
+) -> HashSet<Limit> {
+    let mut applying = limits.get(namespace).cloned().unwrap_or_default();
+    if !namespace.is_wildcard() {
+        if let Some(wildcard) = limits.get(&WILDCARD_NAMESPACE.into()) {
+            applying.extend(wildcard.iter().map(|limit| limit.in_namespace(namespace)));
+        }
+    }
+    applying
+}
+
+fn stored_limit(limits: &HashMap<Namespace, HashSet<Limit>>, limit: &Limit) -> Option<Limit> {
+    limits
+        .get(limit.namespace())
+        .and_then(|limits| limits.get(limit))
+        .cloned()
+}
+
+// Whether one of the limits already goes by the name of the one given. An equal
+// limit counts too, even though it would keep its own name.
+fn name_taken(limits: &HashSet<Limit>, limit: &Limit) -> bool {
+    limit
+        .name()
+        .is_some_and(|name| limits.iter().any(|other| other.name() == Some(name)))
+}
+
+// The limits the counters of the ones given are kept under, see
+// `Limit::counter_limit`. Of the limits sharing their counters, the one of the
+// lowest max value is kept.
+fn counter_limits<'a>(limits: impl IntoIterator<Item = &'a Limit>) -> HashSet<Limit> {
+    let mut counter_limits: HashSet<Limit> = HashSet::new();
+    for limit in limits {
+        let limit = limit.counter_limit();
+        match counter_limits.get(&limit) {
+            Some(kept) if kept.max_value() <= limit.max_value() => {}
+            _ => {
+                counter_limits.replace(limit);
+            }
+        }
+    }
+    counter_limits
+}
+
+// The counters of a limit about to be deleted, unless other limits share them
+fn counters_of_deleted_limit(
+    limits: &HashMap<Namespace, HashSet<Limit>>,
+    limit: &Limit,
+) -> HashSet<Limit> {
+    let counter_limit = limit.counter_limit();
+    let shared = limit.shared_counter().is_some()
+        && limits.get(limit.namespace()).is_some_and(|limits| {
+            limits
+                .iter()
+                .any(|other| other != limit && other.counter_limit() == counter_limit)
+        });
+    if shared {
+        HashSet::new()
+    } else {
+        HashSet::from([counter_limit])
+    }
+}
+
+// The counters of the limits gone, but for the ones the limits left still share
+fn removed_limits(
+    previous: HashMap<Namespace, HashSet<Limit>>,
+    current: &HashMap<Namespace, HashSet<Limit>>,
+) -> HashSet<Limit> {
+    let removed: Vec<Limit> = previous
+        .into_values()
+        .flatten()
+        .filter(|limit| {
+            !current
+                .get(limit.namespace())
+                .is_some_and(|limits| limits.contains(limit))
+        })
+        .collect();
+    let left = counter_limits(current.values().flatten());
+    let mut removed = counter_limits(&removed);
+    removed.retain(|limit| !left.contains(limit));
+    removed
 }
 
 pub trait CounterStorage: Sync + Send {
@@ -272,9 +558,101 @@ pub trait CounterStorage: Sync + Send {
         delta: i64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr>;
+    /// Checks and updates each set of counters, with its own delta, as
+    /// `check_and_update` would. Storages can override it to do so in fewer
+    /// round trips.
+    fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        batch
+            .iter_mut()
+            .map(|(counters, delta)| self.check_and_update(counters, *delta, load_counters))
+            .collect()
+    }
+    /// Leaves the counter with `remaining` hits, until it expires in
+    /// `expires_in`, whatever its hits were. Only counters of fixed windows
+    /// can be set.
+    fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr>;
+    /// Deletes the counter, so that it starts anew on its next hit. Returns
+    /// whether it existed, i.e. whether it had any hits still counted.
+    fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr>;
     fn get_counters(&self, limits: &HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr>;
     fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr>;
     fn clear(&self) -> Result<(), StorageErr>;
+    /// Errs when the storage can't be reached. Storages that live in the
+    /// process always can.
+    fn ping(&self) -> Result<(), StorageErr> {
+        Ok(())
+    }
+}
+
+// Lets a storage keep being used once handed over to a limiter, e.g. to
+// snapshot an `InMemoryStorage` in the background.
+impl<S: CounterStorage + ?Sized> CounterStorage for Arc<S> {
+    fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        (**self).is_within_limits(counter, delta)
+    }
+
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        (**self).add_counter(limit)
+    }
+
+    fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        (**self).update_counter(counter, delta)
+    }
+
+    fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        (**self).check_and_update(counters, delta, load_counters)
+    }
+
+    fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        (**self).check_and_update_batch(batch, load_counters)
+    }
+
+    fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        (**self).set_counter(counter, remaining, expires_in)
+    }
+
+    fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        (**self).delete_counter(counter)
+    }
+
+    fn get_counters(&self, limits: &HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
+        (**self).get_counters(limits)
+    }
+
+    fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr> {
+        (**self).delete_counters(limits)
+    }
+
+    fn clear(&self) -> Result<(), StorageErr> {
+        (**self).clear()
+    }
+
+    fn ping(&self) -> Result<(), StorageErr> {
+        (**self).ping()
+    }
 }
 
 #[async_trait]
@@ -287,19 +665,166 @@ pub trait AsyncCounterStorage: Sync + Send {
         delta: i64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr>;
+    /// Checks and updates each set of counters, with its own delta, as
+    /// `check_and_update` would. Storages can override it to do so in fewer
+    /// round trips.
+    async fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        let mut res = Vec::with_capacity(batch.len());
+        for (counters, delta) in batch.iter_mut() {
+            res.push(
+                self.check_and_update(counters, *delta, load_counters)
+                    .await?,
+            );
+        }
+        Ok(res)
+    }
+    /// Leaves the counter with `remaining` hits, until it expires in
+    /// `expires_in`, whatever its hits were. Only counters of fixed windows
+    /// can be set.
+    async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr>;
+    /// Deletes the counter, so that it starts anew on its next hit. Returns
+    /// whether it existed, i.e. whether it had any hits still counted.
+    async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr>;
     async fn get_counters(&self, limits: HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr>;
     async fn delete_counters(&self, limits: HashSet<Limit>) -> Result<(), StorageErr>;
     async fn clear(&self) -> Result<(), StorageErr>;
+    /// Errs when the storage can't be reached.
+    async fn ping(&self) -> Result<(), StorageErr> {
+        Ok(())
+    }
+    /// Writes the hits the storage buffers before writing them, if any, e.g.
+    /// before shutting down.
+    async fn flush(&self) -> Result<(), StorageErr> {
+        Ok(())
+    }
 }
 
 #[derive(Error, Debug)]
 #[error("error while accessing the limits storage: {msg}")]
 pub struct StorageErr {
     msg: String,
+    kind: StorageErrKind,
+}
+
+/// What went wrong accessing a storage, so that callers can react to each
+/// kind of error differently.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum StorageErrKind {
+    /// The storage couldn't be reached, or didn't answer in time. Likely to
+    /// be temporary.
+    Connection,
+    /// What the storage holds, or was sent, couldn't be read back.
+    Serialization,
+    /// The storage can't do what it was asked as configured, e.g. enforce a
+    /// window it doesn't support.
+    Configuration,
+    /// Any other error of the storage.
+    Other,
 }
 
 impl StorageErr {
     pub fn msg(&self) -> &str {
         &self.msg
     }
+
+    pub fn kind(&self) -> StorageErrKind {
+        self.kind
+    }
+}
+
+/// What the storages that count the hits in `i64`s do with the ones that would
+/// take a counter past what it can hold, e.g. with a pathological
+/// `hits_addend`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum OverflowPolicy {
+    /// The counter is clamped at `i64::MAX`, and the hits are over its limit,
+    /// whatever its max value.
+    #[default]
+    Saturate,
+    /// The hits are rejected with an error, the counter left as it was.
+    Reject,
+}
+
+impl OverflowPolicy {
+    /// Whether `delta` hits fit within the limit of a counter at `value`.
+    pub(crate) fn is_within_limit(
+        self,
+        counter: &Counter,
+        value: i64,
+        delta: i64,
+    ) -> Result<bool, StorageErr> {
+        match value.checked_add(delta) {
+            Some(hits) => Ok(hits <= counter.max_value()),
+            // Way below any limit
+            None if delta < 0 => Ok(true),
+            None => self.overflowed(counter).map(|()| false),
+        }
+    }
+
+    /// The value of a counter at `value` once `delta` hits are counted in it.
+    pub(crate) fn add(self, counter: &Counter, value: i64, delta: i64) -> Result<i64, StorageErr> {
+        match value.checked_add(delta) {
+            Some(hits) => Ok(hits),
+            None => self
+                .overflowed(counter)
+                .map(|()| value.saturating_add(delta)),
+        }
+    }
+
+    fn overflowed(self, counter: &Counter) -> Result<(), StorageErr> {
+        match self {
+            OverflowPolicy::Saturate => Ok(()),
+            OverflowPolicy::Reject => Err(StorageErr {
+                msg: format!(
+                    "the hits would overflow the counter of the limit {:?} of namespace {}",
+                    counter.limit().name().unwrap_or_default(),
+                    counter.namespace().as_ref()
+                ),
+                kind: StorageErrKind::Other,
+            }),
+        }
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "saturate" => Ok(OverflowPolicy::Saturate),
+            "reject" => Ok(OverflowPolicy::Reject),
+            _ => Err(format!(
+                "unknown overflow policy `{policy}`: expected saturate or reject"
+            )),
+        }
+    }
+}
+
+// Used by the storages that only implement fixed windows, so that a limit they
+// can't enforce isn't mistaken for one they can.
+pub(crate) fn only_fixed_windows<'a>(
+    counters: impl IntoIterator<Item = &'a Counter>,
+) -> Result<(), StorageErr> {
+    match counters
+        .into_iter()
+        .find(|counter| counter.window_type() != WindowType::Fixed)
+    {
+        Some(counter) => Err(StorageErr {
+            msg: format!(
+                "{:?} windows are not supported by this storage",
+                counter.window_type()
+            ),
+            kind: StorageErrKind::Configuration,
+        }),
+        None => Ok(()),
+    }
 }
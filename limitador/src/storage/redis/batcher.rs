@@ -1,6 +1,6 @@
 use crate::counter::Counter;
 use crate::storage::redis::AsyncRedisStorage;
-use crate::storage::AsyncCounterStorage;
+use crate::storage::{AsyncCounterStorage, StorageErr};
 use std::collections::HashMap;
 use tokio::sync::Mutex;
 
@@ -30,15 +30,29 @@ impl Batcher {
         }
     }
 
-    pub async fn flush(&self) {
+    // Drops the hits of the counter not sent yet, returning whether there
+    // were any
+    pub async fn discard(&self, counter: &Counter) -> bool {
+        self.accumulated_counter_updates
+            .lock()
+            .await
+            .remove(counter)
+            .is_some()
+    }
+
+    // The updates that fail are kept, along with the ones not sent yet, for
+    // the next flush
+    pub async fn flush(&self) -> Result<(), StorageErr> {
         let mut accumulated_counter_updates = self.accumulated_counter_updates.lock().await;
 
-        for (counter, delta) in accumulated_counter_updates.iter() {
-            self.redis_storage
-                .update_counter(counter, *delta)
-                .await
-                .unwrap();
+        let updates: Vec<_> = accumulated_counter_updates
+            .iter()
+            .map(|(counter, delta)| (counter.clone(), *delta))
+            .collect();
+        for (counter, delta) in updates {
+            self.redis_storage.update_counter(&counter, delta).await?;
+            accumulated_counter_updates.remove(&counter);
         }
-        accumulated_counter_updates.clear();
+        Ok(())
     }
 }
@@ -3,7 +3,7 @@ use crate::storage::redis::{
     DEFAULT_MAX_CACHED_COUNTERS, DEFAULT_MAX_TTL_CACHED_COUNTERS_SEC,
     DEFAULT_TTL_RATIO_CACHED_COUNTERS,
 };
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use ttl_cache::TtlCache;
 
 pub struct CountersCache {
@@ -64,7 +64,11 @@ impl CountersCache {
         ttl_margin: Duration,
     ) {
         let counter_val = Self::value_from_redis_val(redis_val, counter.max_value());
-        let counter_ttl = self.ttl_from_redis_ttl(redis_ttl_ms, counter.seconds(), counter_val);
+        let counter_ttl = self.ttl_from_redis_ttl(
+            redis_ttl_ms,
+            counter.window_at(SystemTime::now()),
+            counter_val,
+        );
         if let Some(ttl) = counter_ttl.checked_sub(ttl_margin) {
             if ttl > Duration::from_secs(0) {
                 self.cache.insert(counter, counter_val, ttl);
@@ -78,6 +82,10 @@ impl CountersCache {
         };
     }
 
+    pub fn remove(&mut self, counter: &Counter) {
+        self.cache.remove(counter);
+    }
+
     fn value_from_redis_val(redis_val: Option<i64>, counter_max: i64) -> i64 {
         match redis_val {
             Some(val) => val,
@@ -88,18 +96,18 @@ impl CountersCache {
     fn ttl_from_redis_ttl(
         &self,
         redis_ttl_ms: i64,
-        counter_seconds: u64,
+        counter_window: Duration,
         counter_val: i64,
     ) -> Duration {
         // Redis returns -2 when the key does not exist. Ref:
         // https://redis.io/commands/ttl
-        // This function returns a ttl of the given counter seconds in this
+        // This function returns a ttl of the given counter window in this
         // case.
 
         let counter_ttl = if redis_ttl_ms >= 0 {
             Duration::from_millis(redis_ttl_ms as u64)
         } else {
-            Duration::from_secs(counter_seconds)
+            counter_window
         };
 
         // If a counter is already at 0, we can cache it for as long as its TTL
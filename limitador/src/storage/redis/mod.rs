@@ -1,8 +1,10 @@
-use ::redis::RedisError;
-use std::time::Duration;
+use ::redis::{ErrorKind, RedisError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
 
 mod batcher;
 mod counters_cache;
+mod pipeliner;
 mod redis_async;
 mod redis_cached;
 mod redis_sync;
@@ -14,22 +16,532 @@ pub const DEFAULT_MAX_TTL_CACHED_COUNTERS_SEC: u64 = 5;
 pub const DEFAULT_TTL_RATIO_CACHED_COUNTERS: u64 = 10;
 
 use crate::counter::Counter;
-use crate::storage::{Authorization, StorageErr};
+use crate::limit::{Limit, WindowType};
+use crate::storage::keys::{
+    counter_from_counter_key, key_for_counter, key_for_counters_of_limit, prefix_for_namespace,
+};
+use crate::storage::leaky_bucket::LeakyBucket;
+use crate::storage::redis::scripts::SCRIPT_CHECK_AND_UPDATE;
+use crate::storage::sliding_window::SlidingWindow;
+use crate::storage::token_bucket::{millis_since_epoch, TokenBucket};
+use crate::storage::{Authorization, StorageErr, StorageErrKind};
 pub use redis_async::AsyncRedisStorage;
+pub use redis_async::AsyncRedisStorageBuilder;
 pub use redis_cached::CachedRedisStorage;
 pub use redis_cached::CachedRedisStorageBuilder;
 pub use redis_sync::RedisStorage;
+use sha2::{Digest, Sha256};
 
 impl From<RedisError> for StorageErr {
     fn from(e: RedisError) -> Self {
-        Self { msg: e.to_string() }
+        let kind = if e.is_io_error()
+            || e.is_connection_refusal()
+            || e.is_connection_dropped()
+            || e.is_timeout()
+        {
+            StorageErrKind::Connection
+        } else if e.kind() == ErrorKind::TypeError {
+            StorageErrKind::Serialization
+        } else {
+            StorageErrKind::Other
+        };
+        Self {
+            msg: e.to_string(),
+            kind,
+        }
     }
 }
 
+// The pool only fails to hand out connections it can't open
 impl From<::r2d2::Error> for StorageErr {
     fn from(e: ::r2d2::Error) -> Self {
-        Self { msg: e.to_string() }
+        Self {
+            msg: e.to_string(),
+            kind: StorageErrKind::Connection,
+        }
+    }
+}
+
+// The keys of the counters, and of the sets of counters of each limit, start
+// with the prefix configured for the storage, so that Limitadors using
+// different prefixes can share the same Redis. None by default, as it used to
+// be. The prefix comes before the hash tag of the namespace, so the keys of a
+// namespace still land on the same node of a cluster.
+//
+// The key of a counter spells out its limit and the values of its variables,
+// which can make for long keys. When hashed, all of that past the namespace is
+// replaced by the first 128 bits of its SHA-256, so that the keys have a
+// bounded length, and are the same across restarts and nodes. Colliding would
+// take in the order of 2^64 counters in the same namespace. The counters being
+// rebuilt from the members of the sets of counters of their limits, those are
+// still the keys as they'd be unhashed.
+#[derive(Clone, Debug, Default)]
+struct RedisKeys {
+    prefix: String,
+    hashed: bool,
+}
+
+impl RedisKeys {
+    fn new(prefix: &str, hashed: bool) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            hashed,
+        }
+    }
+
+    fn counter(&self, counter: &Counter) -> String {
+        if !self.hashed {
+            return self.member(counter);
+        }
+        let namespace = prefix_for_namespace(counter.namespace().as_ref());
+        let digest = Sha256::digest(&key_for_counter(counter).as_bytes()[namespace.len()..]);
+        let mut key = format!("{}{}counter#", self.prefix, namespace);
+        for byte in &digest[..16] {
+            key.push_str(&format!("{byte:02x}"));
+        }
+        key
+    }
+
+    // What the set of counters of its limit has for the counter
+    fn member(&self, counter: &Counter) -> String {
+        format!("{}{}", self.prefix, key_for_counter(counter))
+    }
+
+    // The key of a shard of the counter, see Limit::shards. Its hash tag is
+    // the namespace suffixed with the shard, so that the shards of a counter
+    // are spread across the nodes of a cluster, rather than all landing on
+    // the one of the namespace.
+    fn shard(&self, counter: &Counter, shard: u64) -> String {
+        let namespace = counter.namespace().as_ref();
+        let key = self.counter(counter);
+        let unsharded = self.prefix.len() + prefix_for_namespace(namespace).len();
+        format!(
+            "{}{}{}",
+            self.prefix,
+            prefix_for_namespace(&format!("{namespace}#shard:{shard}")),
+            &key[unsharded..]
+        )
+    }
+
+    // Where the value of a request is tried on a copy of a counter of
+    // distinct values, see SCRIPT_CHECK_DISTINCT_VALUE. In the same slot as
+    // the counter.
+    fn distinct_scratch(&self, counter: &Counter) -> String {
+        format!("{}#scratch", self.counter(counter))
+    }
+
+    fn counters_of_limit(&self, limit: &Limit) -> String {
+        format!("{}{}", self.prefix, key_for_counters_of_limit(limit))
     }
+
+    fn counter_from_member(&self, member: &str, limit: &Limit) -> Counter {
+        counter_from_counter_key(&member[self.prefix.len()..], limit)
+    }
+
+    fn is_prefixed(&self) -> bool {
+        !self.prefix.is_empty()
+    }
+
+    // The pattern of KEYS matching all the keys under the prefix
+    fn pattern(&self) -> String {
+        let mut pattern = String::with_capacity(self.prefix.len() + 1);
+        for c in self.prefix.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push('*');
+        pattern
+    }
+}
+
+// The fields of the hash holding the state of a sliding window, see
+// SCRIPT_UPDATE_SLIDING_WINDOW
+fn sliding_window_fields(window: &SlidingWindow) -> Vec<String> {
+    let mut fields = vec![
+        "window".to_string(),
+        "current".to_string(),
+        "previous".to_string(),
+    ];
+    fields.extend((2..=window.buckets()).map(|bucket| format!("previous_{bucket}")));
+    fields
+}
+
+fn sliding_window_state(state: &[Option<i64>]) -> (Option<u64>, Vec<i64>) {
+    let index = state.first().copied().flatten().map(|index| index as u64);
+    let values = state
+        .iter()
+        .skip(1)
+        .map(|value| value.unwrap_or(0))
+        .collect();
+    (index, values)
+}
+
+fn sliding_window_hits(window: &SlidingWindow, state: &[Option<i64>]) -> i64 {
+    let (index, values) = sliding_window_state(state);
+    window.hits(index, &values)
+}
+
+fn sliding_window_is_live(window: &SlidingWindow, state: &[Option<i64>]) -> bool {
+    let (index, values) = sliding_window_state(state);
+    window.is_live(index, &values)
+}
+
+// Checks the sliding windows, given their states as returned by HMGET of the
+// sliding_window_fields, in the same order as the counters.
+fn sliding_windows_limited(
+    counters: &mut [Counter],
+    states: &[Vec<Option<i64>>],
+    delta: i64,
+    load_counters: bool,
+    now: SystemTime,
+) -> Option<Authorization> {
+    let mut first_limited = None;
+    for (counter, state) in counters.iter_mut().zip(states) {
+        let delta = counter.hits_for(delta);
+        let window = SlidingWindow::of(counter, now);
+        let remaining = counter.max_value() - (sliding_window_hits(&window, state) + delta);
+        if load_counters {
+            counter.set_remaining(remaining);
+            counter.set_expires_in(window.expires_in());
+        }
+        if first_limited.is_none() && remaining < 0 {
+            first_limited = Some(Authorization::Limited(
+                counter.limit().name().map(|n| n.to_owned()),
+            ));
+            if !load_counters {
+                break;
+            }
+        }
+    }
+    first_limited
+}
+
+// How many shards the hits of a counter are spread across, see Limit::shards.
+// Only fixed windows get sharded.
+fn shards_of(counter: &Counter) -> u64 {
+    match counter.limit().shards() {
+        Some(shards) if counter.window_type() == WindowType::Fixed => shards.max(1),
+        _ => 1,
+    }
+}
+
+fn is_sharded(counter: &Counter) -> bool {
+    shards_of(counter) > 1
+}
+
+// The part of `total` that goes to the shard, all the parts adding up to it
+fn share_of_shard(total: i64, shards: u64, shard: u64) -> i64 {
+    let shards = shards as i64;
+    total.div_euclid(shards) + i64::from((shard as i64) < total.rem_euclid(shards))
+}
+
+// The hits are counted in the shards in turn, for them to be spread evenly
+static NEXT_SHARD: AtomicU64 = AtomicU64::new(0);
+
+fn next_shard(counter: &Counter) -> u64 {
+    NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % shards_of(counter)
+}
+
+// The hits remaining in a sharded counter, summed across its shards, given
+// their values and TTLs (in ms) as GET and PTTL return them. Along with when
+// the shards expire, unless none of them is live.
+fn sharded_counter_state(
+    counter: &Counter,
+    state: &[(Option<i64>, i64)],
+) -> (i64, Option<Duration>) {
+    let shards = shards_of(counter);
+    let remaining = state
+        .iter()
+        .zip(0..)
+        .map(|((value, _), shard)| {
+            value.unwrap_or_else(|| share_of_shard(counter.max_value(), shards, shard))
+        })
+        .sum();
+    let expires_in = state
+        .iter()
+        .filter(|(value, ttl)| value.is_some() && *ttl >= 0)
+        .map(|(_, ttl)| Duration::from_millis(*ttl as u64))
+        .max();
+    (remaining, expires_in)
+}
+
+// The TTL (in ms) of a shard created at `now`, for it to expire along with
+// the other shards of its counter already live, if any
+fn shard_ttl(counter: &Counter, state: &[(Option<i64>, i64)], now: SystemTime) -> u64 {
+    let (_, expires_in) = sharded_counter_state(counter, state);
+    expires_in
+        .unwrap_or_else(|| counter.window_at(now))
+        .as_millis() as u64
+}
+
+// Checks the sharded counters, given the states of their shards, in the same
+// order as the counters. The shards being read before one of them is updated,
+// concurrent requests can take the counters a bit over their limits.
+fn sharded_counters_limited(
+    counters: &mut [Counter],
+    states: &[Vec<(Option<i64>, i64)>],
+    delta: i64,
+    load_counters: bool,
+    now: SystemTime,
+) -> Option<Authorization> {
+    let mut first_limited = None;
+    for (counter, state) in counters.iter_mut().zip(states) {
+        let (remaining, expires_in) = sharded_counter_state(counter, state);
+        let remaining = remaining - counter.hits_for(delta);
+        if load_counters {
+            counter.set_remaining(remaining);
+            counter.set_expires_in(expires_in.unwrap_or_else(|| counter.window_at(now)));
+        }
+        if first_limited.is_none() && remaining < 0 {
+            first_limited = Some(Authorization::Limited(
+                counter.limit().name().map(|n| n.to_owned()),
+            ));
+            if !load_counters {
+                break;
+            }
+        }
+    }
+    first_limited
+}
+
+// The fields of the hash holding the state of a token bucket, see
+// SCRIPT_UPDATE_TOKEN_BUCKET
+const TOKEN_BUCKET_FIELDS: [&str; 2] = ["tokens", "last"];
+
+fn token_bucket_tokens(bucket: &TokenBucket, state: &[Option<f64>], now: SystemTime) -> f64 {
+    let stored = match (
+        state.first().copied().flatten(),
+        state.get(1).copied().flatten(),
+    ) {
+        (Some(tokens), Some(last)) => Some((tokens, last as u64)),
+        _ => None,
+    };
+    bucket.tokens_at(stored, millis_since_epoch(now))
+}
+
+// Checks the token buckets, given their states as returned by HMGET of the
+// TOKEN_BUCKET_FIELDS, in the same order as the counters.
+fn token_buckets_limited(
+    counters: &mut [Counter],
+    states: &[Vec<Option<f64>>],
+    delta: i64,
+    load_counters: bool,
+    now: SystemTime,
+) -> Option<Authorization> {
+    let mut first_limited = None;
+    for (counter, state) in counters.iter_mut().zip(states) {
+        let delta = counter.hits_for(delta);
+        let bucket = TokenBucket::of(counter);
+        let tokens = token_bucket_tokens(&bucket, state, now);
+        let remaining = tokens.floor() as i64 - delta;
+        if load_counters {
+            counter.set_remaining(remaining);
+            counter.set_expires_in(bucket.full_in(tokens - delta as f64));
+        }
+        if first_limited.is_none() && remaining < 0 {
+            first_limited = Some(Authorization::Limited(
+                counter.limit().name().map(|n| n.to_owned()),
+            ));
+            if !load_counters {
+                break;
+            }
+        }
+    }
+    first_limited
+}
+
+// The fields of the hash holding the state of a leaky bucket, see
+// SCRIPT_UPDATE_LEAKY_BUCKET
+const LEAKY_BUCKET_FIELDS: [&str; 2] = ["level", "last"];
+
+fn leaky_bucket_level(bucket: &LeakyBucket, state: &[Option<f64>], now: SystemTime) -> f64 {
+    let stored = match (
+        state.first().copied().flatten(),
+        state.get(1).copied().flatten(),
+    ) {
+        (Some(level), Some(last)) => Some((level, last as u64)),
+        _ => None,
+    };
+    bucket.level_at(stored, millis_since_epoch(now))
+}
+
+// Checks the leaky buckets, given their states as returned by HMGET of the
+// LEAKY_BUCKET_FIELDS, in the same order as the counters.
+fn leaky_buckets_limited(
+    counters: &mut [Counter],
+    states: &[Vec<Option<f64>>],
+    delta: i64,
+    load_counters: bool,
+    now: SystemTime,
+) -> Option<Authorization> {
+    let mut first_limited = None;
+    for (counter, state) in counters.iter_mut().zip(states) {
+        let delta = counter.hits_for(delta);
+        let bucket = LeakyBucket::of(counter);
+        let level = leaky_bucket_level(&bucket, state, now);
+        let remaining = counter.max_value() - (bucket.hits(level) + delta);
+        if load_counters {
+            counter.set_remaining(remaining);
+            counter.set_expires_in(bucket.empty_in(level + delta as f64));
+        }
+        if first_limited.is_none() && remaining < 0 {
+            first_limited = Some(Authorization::Limited(
+                counter.limit().name().map(|n| n.to_owned()),
+            ));
+            if !load_counters {
+                break;
+            }
+        }
+    }
+    first_limited
+}
+
+// The value a request of `hits` counts for in a counter of distinct values,
+// unless it counts for none
+fn counted_distinct_value(counter: &Counter, hits: i64) -> Option<&str> {
+    counter.distinct_value().filter(|_| hits > 0)
+}
+
+// The distinct values a counter can still see, given its state as returned by
+// SCRIPT_CHECK_DISTINCT_VALUE. The ones counted are capped at the max value,
+// so that the values already seen still get through should the estimate
+// overshoot it.
+fn distinct_values_remaining(counter: &Counter, state: &(i64, i64, i64)) -> i64 {
+    let (count, new, _) = *state;
+    counter.max_value() - (count.min(counter.max_value()) + new)
+}
+
+// Checks the counters of distinct values, given their states as returned by
+// SCRIPT_CHECK_DISTINCT_VALUE, in the same order as the counters.
+fn distinct_values_limited(
+    counters: &mut [Counter],
+    states: &[(i64, i64, i64)],
+    load_counters: bool,
+    now: SystemTime,
+) -> Option<Authorization> {
+    let mut first_limited = None;
+    for (counter, state) in counters.iter_mut().zip(states) {
+        let remaining = distinct_values_remaining(counter, state);
+        if load_counters {
+            counter.set_remaining(remaining);
+            let (_, _, ttl) = *state;
+            counter.set_expires_in(if ttl > 0 {
+                Duration::from_millis(ttl as u64)
+            } else {
+                counter.window_at(now)
+            });
+        }
+        if first_limited.is_none() && remaining < 0 {
+            first_limited = Some(Authorization::Limited(
+                counter.limit().name().map(|n| n.to_owned()),
+            ));
+            if !load_counters {
+                break;
+            }
+        }
+    }
+    first_limited
+}
+
+// Sorts the counters by window type, returning how many are using fixed
+// windows, followed by how many are using sliding ones, how many are token
+// buckets, how many are leaky buckets, and how many count distinct values.
+// The sharded counters come last.
+fn partition_by_window_type(counters: &mut [Counter]) -> (usize, usize, usize, usize, usize) {
+    counters.sort_by_key(|counter| match counter.window_type() {
+        _ if is_sharded(counter) => 5,
+        WindowType::Fixed => 0,
+        WindowType::Sliding => 1,
+        WindowType::TokenBucket => 2,
+        WindowType::LeakyBucket => 3,
+        WindowType::Distinct => 4,
+    });
+    let count = |window_type| {
+        counters
+            .iter()
+            .filter(|counter| counter.window_type() == window_type && !is_sharded(counter))
+            .count()
+    };
+    (
+        count(WindowType::Fixed),
+        count(WindowType::Sliding),
+        count(WindowType::TokenBucket),
+        count(WindowType::LeakyBucket),
+        count(WindowType::Distinct),
+    )
+}
+
+// The EVAL of SCRIPT_CHECK_AND_UPDATE, that only counts the hits in these
+// counters of fixed windows when none of them is limited.
+fn check_and_update_cmd(
+    keys: &RedisKeys,
+    counters: &[Counter],
+    delta: i64,
+    now: SystemTime,
+) -> ::redis::Cmd {
+    let mut cmd = ::redis::cmd("EVAL");
+    cmd.arg(SCRIPT_CHECK_AND_UPDATE).arg(counters.len() * 2);
+    for counter in counters {
+        cmd.arg(keys.counter(counter))
+            .arg(keys.counters_of_limit(counter.limit()));
+    }
+    for counter in counters {
+        cmd.arg(counter.max_value())
+            .arg(counter.window_at(now).as_millis() as u64)
+            .arg(counter.hits_for(delta))
+            .arg(keys.member(counter));
+    }
+    cmd
+}
+
+// Checks the counters against the values and TTLs they had before the
+// SCRIPT_CHECK_AND_UPDATE answering them, which is the same decision the
+// script made. Loads them into the counters, if asked to.
+fn fixed_windows_limited(
+    counters: &mut [Counter],
+    delta: i64,
+    script_res: Vec<Option<i64>>,
+    load_counters: bool,
+) -> Option<Authorization> {
+    if load_counters {
+        return is_limited(counters, delta, script_res);
+    }
+    counters
+        .iter()
+        .zip(script_res.chunks(2))
+        .find(|(counter, val_ttl_pair)| {
+            val_ttl_pair[0].unwrap_or(counter.max_value()) - counter.hits_for(delta) < 0
+        })
+        .map(|(counter, _)| Authorization::Limited(counter.limit().name().map(|n| n.to_owned())))
+}
+
+// Pipelines the checks of all the entries of a batch, each of them counting
+// its hits only when none of its counters is limited. The batch only has
+// counters of fixed windows.
+fn batch_check_and_update(keys: &RedisKeys, batch: &[(Vec<Counter>, i64)]) -> ::redis::Pipeline {
+    let now = SystemTime::now();
+    let mut pipeline = ::redis::pipe();
+    for (counters, delta) in batch {
+        pipeline.add_command(check_and_update_cmd(keys, counters, *delta, now));
+    }
+    pipeline
+}
+
+fn batch_limited(
+    batch: &mut [(Vec<Counter>, i64)],
+    script_res: Vec<Vec<Option<i64>>>,
+    load_counters: bool,
+) -> Vec<Authorization> {
+    batch
+        .iter_mut()
+        .zip(script_res)
+        .map(|((counters, delta), entry_res)| {
+            fixed_windows_limited(counters, *delta, entry_res, load_counters)
+                .unwrap_or(Authorization::Ok)
+        })
+        .collect()
 }
 
 pub fn is_limited(
@@ -47,17 +559,12 @@ pub fn is_limited(
 
     let mut first_limited = None;
     for (i, counter) in counters.iter_mut().enumerate() {
-        let remaining = counter_vals[i].unwrap_or(counter.max_value()) - delta;
+        let remaining = counter_vals[i].unwrap_or(counter.max_value()) - counter.hits_for(delta);
         counter.set_remaining(remaining);
-        let expires_in = counter_ttls_msecs[i]
-            .map(|x| {
-                if x >= 0 {
-                    Duration::from_millis(x as u64)
-                } else {
-                    Duration::from_secs(counter.seconds())
-                }
-            })
-            .unwrap_or(Duration::from_secs(counter.seconds()));
+        let expires_in = match counter_ttls_msecs[i] {
+            Some(x) if x >= 0 => Duration::from_millis(x as u64),
+            _ => counter.window_at(SystemTime::now()),
+        };
 
         counter.set_expires_in(expires_in);
         if first_limited.is_none() && remaining < 0 {
@@ -68,3 +575,94 @@ pub fn is_limited(
     }
     first_limited
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{share_of_shard, RedisKeys};
+    use crate::counter::Counter;
+    use crate::limit::Limit;
+    use std::collections::HashMap;
+
+    #[test]
+    fn prefixed_counter_key_and_counter_are_symmetric() {
+        let keys = RedisKeys::new("staging:", false);
+        let limit = Limit::new("ns", 10, 60, vec!["req.method == 'GET'"], vec!["app_id"]);
+        let counter = Counter::new(
+            limit.clone(),
+            HashMap::from([("app_id".to_string(), "foo".to_string())]),
+        );
+
+        let key = keys.counter(&counter);
+        assert!(key.starts_with("staging:namespace:{ns},"));
+        assert!(keys.counters_of_limit(&limit).starts_with("staging:"));
+        assert_eq!(key, keys.member(&counter));
+        assert_eq!(keys.counter_from_member(&key, &limit), counter);
+    }
+
+    #[test]
+    fn hashed_counter_keys_are_bounded_and_keep_the_namespace() {
+        let keys = RedisKeys::new("staging:", true);
+        let limit = Limit::new("ns", 10, 60, vec!["req.method == 'GET'"], vec!["app_id"]);
+        let counter = |app_id: &str| {
+            Counter::new(
+                limit.clone(),
+                HashMap::from([("app_id".to_string(), app_id.to_string())]),
+            )
+        };
+
+        let key = keys.counter(&counter("foo"));
+        assert!(key.starts_with("staging:namespace:{ns},counter#"));
+        assert_eq!(key.len(), "staging:namespace:{ns},counter#".len() + 32);
+        assert_eq!(
+            RedisKeys::new("staging:", true).counter(&counter("foo")),
+            key
+        );
+        assert_eq!(keys.counter(&counter(&"foo".repeat(100))).len(), key.len());
+        assert_ne!(keys.counter(&counter("bar")), key);
+        assert_eq!(
+            keys.counter_from_member(&keys.member(&counter("foo")), &limit),
+            counter("foo")
+        );
+    }
+
+    #[test]
+    fn shards_of_a_counter_are_keyed_apart_from_its_namespace() {
+        let keys = RedisKeys::new("staging:", true);
+        let mut limit = Limit::new("ns", 10, 60, Vec::<String>::default(), vec!["app_id"]);
+        limit.set_shards(Some(3));
+        let counter = Counter::new(
+            limit,
+            HashMap::from([("app_id".to_string(), "foo".to_string())]),
+        );
+
+        let key = keys.counter(&counter);
+        let shard = keys.shard(&counter, 2);
+        assert!(shard.starts_with("staging:namespace:{ns#shard:2},counter#"));
+        assert!(shard.ends_with(&key["staging:namespace:{ns},".len()..]));
+        assert_ne!(keys.shard(&counter, 1), shard);
+    }
+
+    #[test]
+    fn shares_of_the_shards_add_up() {
+        assert_eq!(
+            (0..3)
+                .map(|shard| share_of_shard(10, 3, shard))
+                .collect::<Vec<_>>(),
+            vec![4, 3, 3]
+        );
+        assert_eq!(
+            (0..4)
+                .map(|shard| share_of_shard(-5, 4, shard))
+                .sum::<i64>(),
+            -5
+        );
+    }
+
+    #[test]
+    fn pattern_of_the_prefix_matches_it_literally() {
+        assert_eq!(
+            RedisKeys::new("env[1]*:", false).pattern(),
+            "env\\[1\\]\\*:*"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use crate::counter::Counter;
+use crate::storage::redis::AsyncRedisStorage;
+use crate::storage::{AsyncCounterStorage, Authorization, StorageErr, StorageErrKind};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+type Answer = (Vec<Counter>, Result<Authorization, StorageErr>);
+
+struct Check {
+    counters: Vec<Counter>,
+    delta: i64,
+    load_counters: bool,
+    answer: oneshot::Sender<Answer>,
+}
+
+// Sends the checks arriving close together to Redis as a single batch, see
+// `check_and_update_batch`, trading a bit of latency for throughput. A batch
+// is sent as soon as it has `max_size` checks, or `period` after its first
+// one arrived, without waiting for the previous batches to be answered.
+//
+// Each check of a batch is made against the values the counters have after
+// the ones before it in the batch, as when they're sent one by one.
+#[derive(Clone)]
+pub struct Pipeliner {
+    checks: mpsc::UnboundedSender<Check>,
+}
+
+impl Pipeliner {
+    pub fn new(storage: AsyncRedisStorage, max_size: usize, period: Duration) -> Self {
+        let (checks, mut pending) = mpsc::unbounded_channel::<Check>();
+        tokio::spawn(async move {
+            while let Some(first) = pending.recv().await {
+                let mut batch = vec![first];
+                let deadline = tokio::time::sleep(period);
+                tokio::pin!(deadline);
+                while batch.len() < max_size {
+                    tokio::select! {
+                        _ = &mut deadline => break,
+                        check = pending.recv() => match check {
+                            Some(check) => batch.push(check),
+                            None => break,
+                        },
+                    }
+                }
+                tokio::spawn(Self::flush(storage.clone(), batch));
+            }
+        });
+        Self { checks }
+    }
+
+    pub async fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: i64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        let (answer, answered) = oneshot::channel();
+        let check = Check {
+            counters: std::mem::take(counters),
+            delta,
+            load_counters,
+            answer,
+        };
+        if let Err(mpsc::error::SendError(check)) = self.checks.send(check) {
+            *counters = check.counters;
+            return Err(StorageErr {
+                msg: "the pipeline to Redis is closed".to_string(),
+                kind: StorageErrKind::Connection,
+            });
+        }
+
+        let (checked, authorization) = answered.await.map_err(|_| StorageErr {
+            msg: "the pipeline to Redis dropped the check".to_string(),
+            kind: StorageErrKind::Connection,
+        })?;
+        *counters = checked;
+        authorization
+    }
+
+    async fn flush(storage: AsyncRedisStorage, checks: Vec<Check>) {
+        let load_counters = checks.iter().any(|check| check.load_counters);
+        let (mut batch, answers): (Vec<(Vec<Counter>, i64)>, Vec<_>) = checks
+            .into_iter()
+            .map(|check| ((check.counters, check.delta), check.answer))
+            .unzip();
+
+        match storage
+            .check_and_update_batch(&mut batch, load_counters)
+            .await
+        {
+            Ok(authorizations) => {
+                for (((counters, _), answer), authorization) in
+                    batch.into_iter().zip(answers).zip(authorizations)
+                {
+                    let _ = answer.send((counters, Ok(authorization)));
+                }
+            }
+            Err(err) => {
+                for ((counters, _), answer) in batch.into_iter().zip(answers) {
+                    let err = StorageErr {
+                        msg: err.msg().to_string(),
+                        kind: err.kind(),
+                    };
+                    let _ = answer.send((counters, Err(err)));
+                }
+            }
+        }
+    }
+}
@@ -1,22 +1,43 @@
 extern crate redis;
 
-use self::redis::aio::ConnectionManager;
-use self::redis::ConnectionInfo;
+use self::redis::aio::{ConnectionLike, ConnectionManager};
+use self::redis::cluster::ClusterClient;
+use self::redis::cluster_async::ClusterConnection;
+use self::redis::{Cmd, ConnectionInfo, ErrorKind, Pipeline, RedisFuture, Value};
 use crate::counter::Counter;
-use crate::limit::Limit;
-use crate::storage::keys::*;
-use crate::storage::redis::is_limited;
-use crate::storage::redis::scripts::{SCRIPT_UPDATE_COUNTER, VALUES_AND_TTLS};
-use crate::storage::{AsyncCounterStorage, Authorization, StorageErr};
+use crate::limit::{Limit, WindowType};
+use crate::storage::leaky_bucket::LeakyBucket;
+use crate::storage::redis::pipeliner::Pipeliner;
+use crate::storage::redis::scripts::{
+    SCRIPT_ADD_DISTINCT_VALUE, SCRIPT_CHECK_DISTINCT_VALUE, SCRIPT_SET_COUNTER,
+    SCRIPT_UPDATE_COUNTER, SCRIPT_UPDATE_LEAKY_BUCKET, SCRIPT_UPDATE_SHARD,
+    SCRIPT_UPDATE_SLIDING_WINDOW, SCRIPT_UPDATE_TOKEN_BUCKET,
+};
+use crate::storage::redis::{
+    batch_check_and_update, batch_limited, check_and_update_cmd, counted_distinct_value,
+    distinct_values_limited, distinct_values_remaining, fixed_windows_limited, is_sharded,
+    leaky_bucket_level, leaky_buckets_limited, next_shard, partition_by_window_type, shard_ttl,
+    sharded_counter_state, sharded_counters_limited, shards_of, share_of_shard,
+    sliding_window_fields, sliding_window_hits, sliding_window_is_live, sliding_windows_limited,
+    token_bucket_tokens, token_buckets_limited, RedisKeys, LEAKY_BUCKET_FIELDS,
+    TOKEN_BUCKET_FIELDS,
+};
+use crate::storage::sliding_window::SlidingWindow;
+use crate::storage::token_bucket::{millis_since_epoch, TokenBucket};
+use crate::storage::{only_fixed_windows, AsyncCounterStorage, Authorization, StorageErr};
 use async_trait::async_trait;
+use deadpool::managed::{self, Pool, PoolError};
 use redis::{AsyncCommands, RedisError};
 use std::collections::HashSet;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-// Note: this implementation does not guarantee exact limits. Ensuring that we
-// never go over the limits would hurt performance. This implementation
-// sacrifices a bit of accuracy to be more performant.
+// Note: this implementation only guarantees exact limits for fixed windows,
+// that are checked and updated at once in Redis. Sliding windows, token or
+// leaky buckets, distinct values and sharded counters are checked before being
+// updated, so
+// concurrent requests can take them a bit over their limits, sacrificing a bit
+// of accuracy for performance.
 
 // TODO: the code of this implementation is almost identical to the blocking
 // one. The only exception is that the functions defined are "async" and all the
@@ -25,16 +46,146 @@ use std::time::Duration;
 
 #[derive(Clone)]
 pub struct AsyncRedisStorage {
-    conn_manager: ConnectionManager,
+    conn: RedisConnection,
+    // The connection to a replica the pure reads are sent over, when set, see
+    // `with_read_replica`
+    read_conn: Option<RedisConnection>,
+    keys: RedisKeys,
+    pipeliner: Option<Pipeliner>,
+}
+
+// Keys are sharded by namespace (see `keys.rs`), so all the keys involved in
+// checking a single request land on the same slot, and work the same against a
+// cluster as they do against a single node. The shards of sharded counters are
+// the exception, and are only ever read and updated one at a time.
+#[derive(Clone)]
+enum RedisConnection {
+    Single(ConnectionManager),
+    Pooled(Pool<AsyncRedisConnectionManager>),
+    Cluster(ClusterConnection),
+}
+
+// Each command of a pooled connection is sent over the first connection of the
+// pool not busy with another one, so that they don't all queue up behind each
+// other on a single connection.
+struct AsyncRedisConnectionManager {
+    client: redis::Client,
+}
+
+#[async_trait]
+impl managed::Manager for AsyncRedisConnectionManager {
+    type Type = redis::aio::Connection;
+    type Error = RedisError;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        self.client.get_async_connection().await
+    }
+
+    async fn recycle(
+        &self,
+        conn: &mut Self::Type,
+        _: &managed::Metrics,
+    ) -> managed::RecycleResult<Self::Error> {
+        redis::cmd("PING").query_async::<_, ()>(conn).await?;
+        Ok(())
+    }
+}
+
+fn pool_error(err: PoolError<RedisError>) -> RedisError {
+    match err {
+        PoolError::Backend(err) => err,
+        err => RedisError::from((
+            ErrorKind::IoError,
+            "Couldn't get a connection from the pool",
+            err.to_string(),
+        )),
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisConnection::Single(con) => con.req_packed_command(cmd),
+            RedisConnection::Pooled(pool) => Box::pin(async move {
+                let mut con = pool.get().await.map_err(pool_error)?;
+                con.req_packed_command(cmd).await
+            }),
+            RedisConnection::Cluster(con) => con.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisConnection::Single(con) => con.req_packed_commands(cmd, offset, count),
+            RedisConnection::Pooled(pool) => Box::pin(async move {
+                let mut con = pool.get().await.map_err(pool_error)?;
+                con.req_packed_commands(cmd, offset, count).await
+            }),
+            RedisConnection::Cluster(con) => con.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Single(con) => con.get_db(),
+            RedisConnection::Pooled(pool) => pool.manager().client.get_connection_info().redis.db,
+            RedisConnection::Cluster(con) => con.get_db(),
+        }
+    }
 }
 
 #[async_trait]
 impl AsyncCounterStorage for AsyncRedisStorage {
     async fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
-        let mut con = self.conn_manager.clone();
+        let delta = counter.hits_for(delta);
+        let mut con = self.read_conn();
+
+        if counter.window_type() == WindowType::Sliding {
+            let window = SlidingWindow::of(counter, SystemTime::now());
+            let state: Vec<Option<i64>> = con
+                .hget(self.keys.counter(counter), sliding_window_fields(&window))
+                .await?;
+            return Ok(counter.max_value() - (sliding_window_hits(&window, &state) + delta) >= 0);
+        }
+
+        if counter.window_type() == WindowType::TokenBucket {
+            let state: Vec<Option<f64>> = con
+                .hget(self.keys.counter(counter), &TOKEN_BUCKET_FIELDS)
+                .await?;
+            let tokens = token_bucket_tokens(&TokenBucket::of(counter), &state, SystemTime::now());
+            return Ok(tokens.floor() as i64 - delta >= 0);
+        }
+
+        if counter.window_type() == WindowType::LeakyBucket {
+            let state: Vec<Option<f64>> = con
+                .hget(self.keys.counter(counter), &LEAKY_BUCKET_FIELDS)
+                .await?;
+            let bucket = LeakyBucket::of(counter);
+            let level = leaky_bucket_level(&bucket, &state, SystemTime::now());
+            return Ok(counter.max_value() - (bucket.hits(level) + delta) >= 0);
+        }
+
+        if counter.window_type() == WindowType::Distinct {
+            // Checking the value of the request writes a scratch key, that a
+            // replica wouldn't take
+            let mut con = self.conn.clone();
+            let state = self.distinct_values_state(&mut con, counter, delta).await?;
+            return Ok(distinct_values_remaining(counter, &state) >= 0);
+        }
+
+        if is_sharded(counter) {
+            let state = self.shard_states(&mut con, counter).await?;
+            let (remaining, _) = sharded_counter_state(counter, &state);
+            return Ok(remaining - delta >= 0);
+        }
 
         match con
-            .get::<String, Option<i64>>(key_for_counter(counter))
+            .get::<String, Option<i64>>(self.keys.counter(counter))
             .await?
         {
             Some(val) => Ok(val - delta >= 0),
@@ -43,14 +194,75 @@ impl AsyncCounterStorage for AsyncRedisStorage {
     }
 
     async fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
-        let mut con = self.conn_manager.clone();
+        let delta = counter.hits_for(delta);
+        let mut con = self.conn.clone();
+
+        if counter.window_type() == WindowType::Sliding {
+            let window = SlidingWindow::of(counter, SystemTime::now());
+            redis::Script::new(SCRIPT_UPDATE_SLIDING_WINDOW)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(window.index())
+                .arg(window.retention().as_millis() as u64)
+                .arg(delta)
+                .arg(self.keys.member(counter))
+                .arg(window.buckets())
+                .invoke_async::<_, _>(&mut con)
+                .await?;
+            return Ok(());
+        }
+
+        if counter.window_type() == WindowType::TokenBucket {
+            let bucket = TokenBucket::of(counter);
+            redis::Script::new(SCRIPT_UPDATE_TOKEN_BUCKET)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(millis_since_epoch(SystemTime::now()))
+                .arg(bucket.capacity())
+                .arg(bucket.refill_per_ms())
+                .arg(delta)
+                .arg(self.keys.member(counter))
+                .invoke_async::<_, _>(&mut con)
+                .await?;
+            return Ok(());
+        }
+
+        if counter.window_type() == WindowType::LeakyBucket {
+            let bucket = LeakyBucket::of(counter);
+            redis::Script::new(SCRIPT_UPDATE_LEAKY_BUCKET)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(millis_since_epoch(SystemTime::now()))
+                .arg(bucket.leak_per_ms())
+                .arg(delta)
+                .arg(self.keys.member(counter))
+                .invoke_async::<_, _>(&mut con)
+                .await?;
+            return Ok(());
+        }
+
+        if counter.window_type() == WindowType::Distinct {
+            if let Some(value) = counted_distinct_value(counter, delta) {
+                self.add_distinct_value(&mut con, counter, value, SystemTime::now())
+                    .await?;
+            }
+            return Ok(());
+        }
+
+        if is_sharded(counter) {
+            let state = self.shard_states(&mut con, counter).await?;
+            return self
+                .update_shard(&mut con, counter, &state, delta, SystemTime::now())
+                .await;
+        }
 
         redis::Script::new(SCRIPT_UPDATE_COUNTER)
-            .key(key_for_counter(counter))
-            .key(key_for_counters_of_limit(counter.limit()))
+            .key(self.keys.counter(counter))
+            .key(self.keys.counters_of_limit(counter.limit()))
             .arg(counter.max_value())
-            .arg(counter.seconds())
+            .arg(counter.window_at(SystemTime::now()).as_millis() as u64)
             .arg(delta)
+            .arg(self.keys.member(counter))
             .invoke_async::<_, _>(&mut con)
             .await?;
 
@@ -63,65 +275,318 @@ impl AsyncCounterStorage for AsyncRedisStorage {
         delta: i64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
-        let mut con = self.conn_manager.clone();
-        let counter_keys: Vec<String> = counters.iter().map(key_for_counter).collect();
+        // Only the fixed windows can be checked in the batches pipelined
+        if let Some(pipeliner) = &self.pipeliner {
+            if counters
+                .iter()
+                .all(|counter| counter.window_type() == WindowType::Fixed && !is_sharded(counter))
+            {
+                return pipeliner
+                    .check_and_update(counters, delta, load_counters)
+                    .await;
+            }
+        }
 
-        if load_counters {
-            let script = redis::Script::new(VALUES_AND_TTLS);
-            let mut script_invocation = script.prepare_invoke();
+        let mut con = self.conn.clone();
+        let now = SystemTime::now();
+        let (fixed_windows, sliding_windows, token_buckets, leaky_buckets, distinct_values) =
+            partition_by_window_type(counters);
+        let (counters, others) = counters.split_at_mut(fixed_windows);
+        let (sliding_windows, others) = others.split_at_mut(sliding_windows);
+        let (token_buckets, others) = others.split_at_mut(token_buckets);
+        let (leaky_buckets, others) = others.split_at_mut(leaky_buckets);
+        let (distinct_values, sharded) = others.split_at_mut(distinct_values);
+        let mut states = Vec::with_capacity(sliding_windows.len());
+        for counter in sliding_windows.iter() {
+            let state: Vec<Option<i64>> = con
+                .hget(
+                    self.keys.counter(counter),
+                    sliding_window_fields(&SlidingWindow::of(counter, now)),
+                )
+                .await?;
+            states.push(state);
+        }
+        if let Some(res) =
+            sliding_windows_limited(sliding_windows, &states, delta, load_counters, now)
+        {
+            return Ok(res);
+        }
 
-            for counter_key in &counter_keys {
-                script_invocation.key(counter_key);
-            }
+        let mut states = Vec::with_capacity(token_buckets.len());
+        for counter in token_buckets.iter() {
+            let state: Vec<Option<f64>> = con
+                .hget(self.keys.counter(counter), &TOKEN_BUCKET_FIELDS)
+                .await?;
+            states.push(state);
+        }
+        if let Some(res) = token_buckets_limited(token_buckets, &states, delta, load_counters, now)
+        {
+            return Ok(res);
+        }
+
+        let mut states = Vec::with_capacity(leaky_buckets.len());
+        for counter in leaky_buckets.iter() {
+            let state: Vec<Option<f64>> = con
+                .hget(self.keys.counter(counter), &LEAKY_BUCKET_FIELDS)
+                .await?;
+            states.push(state);
+        }
+        if let Some(res) = leaky_buckets_limited(leaky_buckets, &states, delta, load_counters, now)
+        {
+            return Ok(res);
+        }
+
+        let mut states = Vec::with_capacity(distinct_values.len());
+        for counter in distinct_values.iter() {
+            let hits = counter.hits_for(delta);
+            states.push(self.distinct_values_state(&mut con, counter, hits).await?);
+        }
+        if let Some(res) = distinct_values_limited(distinct_values, &states, load_counters, now) {
+            return Ok(res);
+        }
+
+        let mut shard_states = Vec::with_capacity(sharded.len());
+        for counter in sharded.iter() {
+            shard_states.push(self.shard_states(&mut con, counter).await?);
+        }
+        if let Some(res) =
+            sharded_counters_limited(sharded, &shard_states, delta, load_counters, now)
+        {
+            return Ok(res);
+        }
 
-            let script_res: Vec<Option<i64>> = script_invocation.invoke_async(&mut con).await?;
-            if let Some(res) = is_limited(counters, delta, script_res) {
+        // Checked and counted at once, so that none of their hits are counted
+        // when one of them is limited, even by the requests checked meanwhile
+        if !counters.is_empty() {
+            let script_res: Vec<Option<i64>> =
+                check_and_update_cmd(&self.keys, counters, delta, now)
+                    .query_async(&mut con)
+                    .await?;
+            if let Some(res) = fixed_windows_limited(counters, delta, script_res, load_counters) {
                 return Ok(res);
             }
-        } else {
-            let counter_vals: Vec<Option<i64>> = redis::cmd("MGET")
-                .arg(counter_keys.clone())
-                .query_async(&mut con)
+        }
+
+        for counter in sliding_windows.iter() {
+            let window = SlidingWindow::of(counter, now);
+            redis::Script::new(SCRIPT_UPDATE_SLIDING_WINDOW)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(window.index())
+                .arg(window.retention().as_millis() as u64)
+                .arg(counter.hits_for(delta))
+                .arg(self.keys.member(counter))
+                .arg(window.buckets())
+                .invoke_async::<_, _>(&mut con)
                 .await?;
+        }
 
-            for (i, counter) in counters.iter().enumerate() {
-                let remaining = counter_vals[i].unwrap_or(counter.max_value()) - delta;
-                if remaining < 0 {
-                    return Ok(Authorization::Limited(
-                        counter.limit().name().map(|n| n.to_owned()),
-                    ));
-                }
-            }
+        for counter in token_buckets.iter() {
+            let bucket = TokenBucket::of(counter);
+            redis::Script::new(SCRIPT_UPDATE_TOKEN_BUCKET)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(millis_since_epoch(now))
+                .arg(bucket.capacity())
+                .arg(bucket.refill_per_ms())
+                .arg(counter.hits_for(delta))
+                .arg(self.keys.member(counter))
+                .invoke_async::<_, _>(&mut con)
+                .await?;
         }
 
-        // TODO: this can be optimized by using pipelines with multiple updates
-        for (counter_idx, key) in counter_keys.into_iter().enumerate() {
-            let counter = &counters[counter_idx];
-            redis::Script::new(SCRIPT_UPDATE_COUNTER)
-                .key(key)
-                .key(key_for_counters_of_limit(counter.limit()))
-                .arg(counter.max_value())
-                .arg(counter.seconds())
-                .arg(delta)
+        for counter in leaky_buckets.iter() {
+            let bucket = LeakyBucket::of(counter);
+            redis::Script::new(SCRIPT_UPDATE_LEAKY_BUCKET)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(millis_since_epoch(now))
+                .arg(bucket.leak_per_ms())
+                .arg(counter.hits_for(delta))
+                .arg(self.keys.member(counter))
                 .invoke_async::<_, _>(&mut con)
                 .await?;
         }
 
+        // Only the values new to their counters need adding
+        for (counter, (_, new, _)) in distinct_values.iter().zip(&states) {
+            let hits = counter.hits_for(delta);
+            if let Some(value) = counted_distinct_value(counter, hits).filter(|_| *new > 0) {
+                self.add_distinct_value(&mut con, counter, value, now)
+                    .await?;
+            }
+        }
+
+        for (counter, state) in sharded.iter().zip(&shard_states) {
+            self.update_shard(&mut con, counter, state, counter.hits_for(delta), now)
+                .await?;
+        }
+
         Ok(Authorization::Ok)
     }
 
+    async fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        // Sliding windows, buckets and sharded counters need round trips of
+        // their own, and a cluster can't serve keys of different namespaces
+        // in one command
+        let cluster = matches!(self.conn, RedisConnection::Cluster(_));
+        if cluster
+            || batch
+                .iter()
+                .flat_map(|(counters, _)| counters.iter())
+                .any(|counter| counter.window_type() != WindowType::Fixed || is_sharded(counter))
+        {
+            let mut res = Vec::with_capacity(batch.len());
+            for (counters, delta) in batch.iter_mut() {
+                res.push(
+                    self.check_and_update(counters, *delta, load_counters)
+                        .await?,
+                );
+            }
+            return Ok(res);
+        }
+
+        let mut con = self.conn.clone();
+        let script_res = batch_check_and_update(&self.keys, batch)
+            .query_async(&mut con)
+            .await?;
+        Ok(batch_limited(batch, script_res, load_counters))
+    }
+
+    async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
+        let mut con = self.conn.clone();
+        if is_sharded(counter) {
+            let shards = shards_of(counter);
+            for shard in 0..shards {
+                con.pset_ex::<_, _, ()>(
+                    self.keys.shard(counter, shard),
+                    share_of_shard(remaining, shards, shard),
+                    expires_in.as_millis() as usize,
+                )
+                .await?;
+            }
+            con.sadd::<_, _, ()>(
+                self.keys.counters_of_limit(counter.limit()),
+                self.keys.member(counter),
+            )
+            .await?;
+            return Ok(());
+        }
+        redis::Script::new(SCRIPT_SET_COUNTER)
+            .key(self.keys.counter(counter))
+            .key(self.keys.counters_of_limit(counter.limit()))
+            .arg(remaining)
+            .arg(expires_in.as_millis() as u64)
+            .arg(self.keys.member(counter))
+            .invoke_async::<_, _>(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        let mut con = self.conn.clone();
+        let mut deleted: u64 = con.del(self.keys.counter(counter)).await?;
+        if is_sharded(counter) {
+            for shard in 0..shards_of(counter) {
+                deleted += con.del::<_, u64>(self.keys.shard(counter, shard)).await?;
+            }
+        }
+        con.srem::<_, _, ()>(
+            self.keys.counters_of_limit(counter.limit()),
+            self.keys.member(counter),
+        )
+        .await?;
+        Ok(deleted > 0)
+    }
+
     async fn get_counters(&self, limits: HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
         let mut res = HashSet::new();
 
-        let mut con = self.conn_manager.clone();
+        let mut con = self.read_conn();
 
         for limit in limits {
-            let counter_keys = con
-                .smembers::<String, HashSet<String>>(key_for_counters_of_limit(&limit))
+            let members = con
+                .smembers::<String, HashSet<String>>(self.keys.counters_of_limit(&limit))
                 .await?;
 
-            for counter_key in counter_keys {
-                let mut counter: Counter = counter_from_counter_key(&counter_key, &limit);
+            for member in members {
+                let mut counter: Counter = self.keys.counter_from_member(&member, &limit);
+                let counter_key = self.keys.counter(&counter);
+
+                if limit.window_type() == WindowType::Sliding {
+                    let window = SlidingWindow::of(&counter, SystemTime::now());
+                    let state: Vec<Option<i64>> = con
+                        .hget(&counter_key, sliding_window_fields(&window))
+                        .await?;
+                    if sliding_window_is_live(&window, &state) {
+                        counter.set_remaining(
+                            limit.max_value() - sliding_window_hits(&window, &state),
+                        );
+                        counter.set_expires_in(window.expires_in());
+                        res.insert(counter);
+                    }
+                    continue;
+                }
+
+                if limit.window_type() == WindowType::TokenBucket {
+                    let state: Vec<Option<f64>> =
+                        con.hget(&counter_key, &TOKEN_BUCKET_FIELDS).await?;
+                    let bucket = TokenBucket::of(&counter);
+                    let tokens = token_bucket_tokens(&bucket, &state, SystemTime::now());
+                    if tokens < bucket.capacity() {
+                        counter.set_remaining(tokens.floor() as i64);
+                        counter.set_expires_in(bucket.full_in(tokens));
+                        res.insert(counter);
+                    }
+                    continue;
+                }
+
+                if limit.window_type() == WindowType::LeakyBucket {
+                    let state: Vec<Option<f64>> =
+                        con.hget(&counter_key, &LEAKY_BUCKET_FIELDS).await?;
+                    let bucket = LeakyBucket::of(&counter);
+                    let level = leaky_bucket_level(&bucket, &state, SystemTime::now());
+                    if level > 0.0 {
+                        counter.set_remaining(limit.max_value() - bucket.hits(level));
+                        counter.set_expires_in(bucket.empty_in(level));
+                        res.insert(counter);
+                    }
+                    continue;
+                }
+
+                if limit.window_type() == WindowType::Distinct {
+                    let (count, ttl): (i64, i64) = redis::pipe()
+                        .pfcount(&counter_key)
+                        .pttl(&counter_key)
+                        .query_async(&mut con)
+                        .await?;
+                    if ttl > 0 {
+                        counter.set_remaining(limit.max_value() - count);
+                        counter.set_expires_in(Duration::from_millis(ttl as u64));
+                        res.insert(counter);
+                    }
+                    continue;
+                }
+
+                if is_sharded(&counter) {
+                    let state = self.shard_states(&mut con, &counter).await?;
+                    if let (remaining, Some(expires_in)) = sharded_counter_state(&counter, &state) {
+                        counter.set_remaining(remaining);
+                        counter.set_expires_in(expires_in);
+                        res.insert(counter);
+                    }
+                    continue;
+                }
 
                 // If the key does not exist, it means that the counter expired,
                 // so we don't have to return it.
@@ -132,8 +597,8 @@ impl AsyncCounterStorage for AsyncRedisStorage {
                 // unnecessarily.
                 if let Some(val) = con.get::<String, Option<i64>>(counter_key.clone()).await? {
                     counter.set_remaining(val);
-                    let ttl = con.ttl(&counter_key).await?;
-                    counter.set_expires_in(Duration::from_secs(ttl));
+                    let ttl = con.pttl(&counter_key).await?;
+                    counter.set_expires_in(Duration::from_millis(ttl));
 
                     res.insert(counter);
                 }
@@ -151,8 +616,21 @@ impl AsyncCounterStorage for AsyncRedisStorage {
     }
 
     async fn clear(&self) -> Result<(), StorageErr> {
-        let mut con = self.conn_manager.clone();
-        redis::cmd("FLUSHDB").query_async(&mut con).await?;
+        let mut con = self.conn.clone();
+        // Only the keys under the prefix, others may be using the same Redis
+        if self.keys.is_prefixed() {
+            for key in con.keys::<String, Vec<String>>(self.keys.pattern()).await? {
+                con.del(key).await?;
+            }
+        } else {
+            redis::cmd("FLUSHDB").query_async(&mut con).await?;
+        }
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), StorageErr> {
+        let mut con = self.conn.clone();
+        redis::cmd("PING").query_async::<_, ()>(&mut con).await?;
         Ok(())
     }
 }
@@ -161,37 +639,286 @@ impl AsyncRedisStorage {
     pub async fn new(redis_url: &str) -> Result<Self, RedisError> {
         let info = ConnectionInfo::from_str(redis_url)?;
         Ok(Self {
-            conn_manager: ConnectionManager::new(
-                redis::Client::open(info)
-                    .expect("This couldn't fail in the past, yet now it did somehow!"),
-            )
-            .await?,
+            conn: RedisConnection::Single(
+                ConnectionManager::new(
+                    redis::Client::open(info)
+                        .expect("This couldn't fail in the past, yet now it did somehow!"),
+                )
+                .await?,
+            ),
+            read_conn: None,
+            keys: RedisKeys::default(),
+            pipeliner: None,
+        })
+    }
+
+    /// Connects to a Redis Cluster, discovering its topology from any of the
+    /// `seed_urls` given.
+    pub async fn new_cluster<S: AsRef<str>>(seed_urls: &[S]) -> Result<Self, RedisError> {
+        let nodes = seed_urls
+            .iter()
+            .map(|url| ConnectionInfo::from_str(url.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            conn: RedisConnection::Cluster(
+                ClusterClient::new(nodes)?.get_async_connection().await?,
+            ),
+            read_conn: None,
+            keys: RedisKeys::default(),
+            pipeliner: None,
         })
     }
 
     pub fn new_with_conn_manager(conn_manager: ConnectionManager) -> Self {
-        Self { conn_manager }
+        Self {
+            conn: RedisConnection::Single(conn_manager),
+            read_conn: None,
+            keys: RedisKeys::default(),
+            pipeliner: None,
+        }
+    }
+
+    /// Prefixes all the keys of the storage with `key_prefix`, so that it can
+    /// share the same Redis with others using different prefixes. To keep the
+    /// keys of a namespace on the same node of a cluster, the prefix shouldn't
+    /// have any curly braces.
+    pub fn with_key_prefix(mut self, key_prefix: &str) -> Self {
+        self.keys = RedisKeys::new(key_prefix, self.keys.hashed);
+        self
+    }
+
+    /// Hashes what identifies each counter in its key, past its namespace, so
+    /// that the keys are of a bounded length however long the values of the
+    /// variables of the counters are. The same counters get the same keys
+    /// across restarts and nodes, but not the same keys as unhashed.
+    pub fn with_hashed_keys(mut self, hashed_keys: bool) -> Self {
+        self.keys = RedisKeys::new(&self.keys.prefix, hashed_keys);
+        self
+    }
+
+    /// Sends the pure reads, the checks that don't update the counters and
+    /// the lookups of their values, to the replica at `replica_url`, and only
+    /// the rest to the primary. As Redis replicates asynchronously, those reads
+    /// can be a bit stale, missing the latest hits of the counters. The checks
+    /// of distinct values are the exception, and are still sent to the primary.
+    pub async fn with_read_replica(mut self, replica_url: &str) -> Result<Self, RedisError> {
+        let info = ConnectionInfo::from_str(replica_url)?;
+        self.read_conn = Some(RedisConnection::Single(
+            ConnectionManager::new(redis::Client::open(info)?).await?,
+        ));
+        Ok(self)
+    }
+
+    // The connection to send the pure reads over, the replica's when there's
+    // one
+    fn read_conn(&self) -> RedisConnection {
+        self.read_conn.as_ref().unwrap_or(&self.conn).clone()
     }
 
     async fn delete_counters_associated_with_limit(&self, limit: &Limit) -> Result<(), StorageErr> {
-        let mut con = self.conn_manager.clone();
+        let mut con = self.conn.clone();
 
-        let counter_keys = con
-            .smembers::<String, HashSet<String>>(key_for_counters_of_limit(limit))
+        let members = con
+            .smembers::<String, HashSet<String>>(self.keys.counters_of_limit(limit))
             .await?;
 
-        for counter_key in counter_keys {
-            con.del(counter_key).await?;
+        for member in members {
+            let counter = self.keys.counter_from_member(&member, limit);
+            con.del(self.keys.counter(&counter)).await?;
+            if is_sharded(&counter) {
+                for shard in 0..shards_of(&counter) {
+                    con.del::<_, ()>(self.keys.shard(&counter, shard)).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // The values and TTLs (in ms) of the shards of the counter, one at a
+    // time, as they can be on different nodes of a cluster
+    async fn shard_states(
+        &self,
+        con: &mut RedisConnection,
+        counter: &Counter,
+    ) -> Result<Vec<(Option<i64>, i64)>, StorageErr> {
+        let mut states = Vec::with_capacity(shards_of(counter) as usize);
+        for shard in 0..shards_of(counter) {
+            let key = self.keys.shard(counter, shard);
+            states.push(redis::pipe().get(&key).pttl(&key).query_async(con).await?);
+        }
+        Ok(states)
+    }
+
+    // The distinct values of the counter, and whether the value of the
+    // request is new to them, see SCRIPT_CHECK_DISTINCT_VALUE
+    async fn distinct_values_state(
+        &self,
+        con: &mut RedisConnection,
+        counter: &Counter,
+        hits: i64,
+    ) -> Result<(i64, i64, i64), StorageErr> {
+        let script = redis::Script::new(SCRIPT_CHECK_DISTINCT_VALUE);
+        let mut invocation = script.key(self.keys.counter(counter));
+        invocation.key(self.keys.distinct_scratch(counter));
+        if let Some(value) = counted_distinct_value(counter, hits) {
+            invocation.arg(value);
         }
+        Ok(invocation.invoke_async(con).await?)
+    }
 
+    async fn add_distinct_value(
+        &self,
+        con: &mut RedisConnection,
+        counter: &Counter,
+        value: &str,
+        now: SystemTime,
+    ) -> Result<(), StorageErr> {
+        redis::Script::new(SCRIPT_ADD_DISTINCT_VALUE)
+            .key(self.keys.counter(counter))
+            .key(self.keys.counters_of_limit(counter.limit()))
+            .arg(value)
+            .arg(counter.window_at(now).as_millis() as u64)
+            .arg(self.keys.member(counter))
+            .invoke_async::<_, ()>(con)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_shard(
+        &self,
+        con: &mut RedisConnection,
+        counter: &Counter,
+        state: &[(Option<i64>, i64)],
+        delta: i64,
+        now: SystemTime,
+    ) -> Result<(), StorageErr> {
+        let shard = next_shard(counter);
+        let created: bool = redis::Script::new(SCRIPT_UPDATE_SHARD)
+            .key(self.keys.shard(counter, shard))
+            .arg(share_of_shard(
+                counter.max_value(),
+                shards_of(counter),
+                shard,
+            ))
+            .arg(shard_ttl(counter, state, now))
+            .arg(delta)
+            .invoke_async(con)
+            .await?;
+        if created {
+            con.sadd::<_, _, ()>(
+                self.keys.counters_of_limit(counter.limit()),
+                self.keys.member(counter),
+            )
+            .await?;
+        }
         Ok(())
     }
 }
 
+pub struct AsyncRedisStorageBuilder {
+    redis_url: String,
+    key_prefix: String,
+    hashed_keys: bool,
+    pool_size: Option<usize>,
+    pipelining: Option<(usize, Duration)>,
+    read_replica_url: Option<String>,
+}
+
+impl AsyncRedisStorageBuilder {
+    pub fn new(redis_url: &str) -> Self {
+        Self {
+            redis_url: redis_url.to_string(),
+            key_prefix: String::new(),
+            hashed_keys: false,
+            pool_size: None,
+            pipelining: None,
+            read_replica_url: None,
+        }
+    }
+
+    /// See [`AsyncRedisStorage::with_key_prefix`]
+    pub fn key_prefix(mut self, key_prefix: &str) -> Self {
+        self.key_prefix = key_prefix.to_string();
+        self
+    }
+
+    /// See [`AsyncRedisStorage::with_hashed_keys`]
+    pub fn hashed_keys(mut self, hashed_keys: bool) -> Self {
+        self.hashed_keys = hashed_keys;
+        self
+    }
+
+    /// Sends the commands over a pool of up to `pool_size` connections,
+    /// instead of a single one multiplexing them all.
+    pub fn pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = Some(pool_size);
+        self
+    }
+
+    /// Pipelines the checks arriving close together, in batches of up to
+    /// `max_size` checks or waiting for `period` at most after the first one,
+    /// for higher throughput at the cost of that latency. The checks of the
+    /// same batch are all made against the values the counters had before it.
+    /// Only the checks of fixed windows, unsharded, are pipelined.
+    pub fn pipelining(mut self, max_size: usize, period: Duration) -> Self {
+        self.pipelining = Some((max_size, period));
+        self
+    }
+
+    /// See [`AsyncRedisStorage::with_read_replica`]
+    pub fn read_replica(mut self, replica_url: &str) -> Self {
+        self.read_replica_url = Some(replica_url.to_string());
+        self
+    }
+
+    pub async fn build(self) -> Result<AsyncRedisStorage, RedisError> {
+        // Fails early on a malformed replica URL, before connecting to anything
+        if let Some(replica_url) = &self.read_replica_url {
+            ConnectionInfo::from_str(replica_url)?;
+        }
+        let mut storage = match self.pool_size {
+            None => AsyncRedisStorage::new(&self.redis_url).await?,
+            Some(pool_size) => {
+                let client = redis::Client::open(ConnectionInfo::from_str(&self.redis_url)?)?;
+                let pool = Pool::builder(AsyncRedisConnectionManager { client })
+                    .max_size(pool_size)
+                    .build()
+                    .map_err(|err| {
+                        RedisError::from((
+                            ErrorKind::InvalidClientConfig,
+                            "Couldn't build the pool of connections",
+                            err.to_string(),
+                        ))
+                    })?;
+                // Fails early when Redis can't be reached, as with a single
+                // connection
+                drop(pool.get().await.map_err(pool_error)?);
+                AsyncRedisStorage {
+                    conn: RedisConnection::Pooled(pool),
+                    read_conn: None,
+                    keys: RedisKeys::default(),
+                    pipeliner: None,
+                }
+            }
+        }
+        .with_key_prefix(&self.key_prefix)
+        .with_hashed_keys(self.hashed_keys);
+        if let Some(replica_url) = &self.read_replica_url {
+            storage = storage.with_read_replica(replica_url).await?;
+        }
+        if let Some((max_size, period)) = self.pipelining {
+            storage.pipeliner = Some(Pipeliner::new(storage.clone(), max_size, period));
+        }
+        Ok(storage)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::storage::redis::AsyncRedisStorage;
+    use crate::storage::redis::{AsyncRedisStorage, AsyncRedisStorageBuilder};
     use redis::ErrorKind;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn errs_on_bad_url() {
@@ -200,6 +927,13 @@ mod tests {
         assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidClientConfig);
     }
 
+    #[tokio::test]
+    async fn errs_on_cluster_without_seed_nodes() {
+        let result = AsyncRedisStorage::new_cluster::<&str>(&[]).await;
+        assert!(result.is_err());
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidClientConfig);
+    }
+
     #[tokio::test]
     async fn errs_on_connection_issue() {
         let result = AsyncRedisStorage::new("redis://127.0.0.1:21").await;
@@ -208,4 +942,27 @@ mod tests {
         assert_eq!(error.kind(), ErrorKind::IoError);
         assert!(error.is_connection_refusal())
     }
+
+    #[tokio::test]
+    async fn errs_on_bad_read_replica_url() {
+        let result = AsyncRedisStorageBuilder::new("redis://127.0.0.1:21")
+            .read_replica("cassandra://127.0.0.1:6380")
+            .build()
+            .await;
+        assert!(result.is_err());
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidClientConfig);
+    }
+
+    #[tokio::test]
+    async fn errs_on_connection_issue_with_a_pool() {
+        let result = AsyncRedisStorageBuilder::new("redis://127.0.0.1:21")
+            .pool_size(2)
+            .pipelining(10, Duration::from_micros(100))
+            .build()
+            .await;
+        assert!(result.is_err());
+        let error = result.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::IoError);
+        assert!(error.is_connection_refusal())
+    }
 }
@@ -1,15 +1,14 @@
 use crate::counter::Counter;
 use crate::limit::Limit;
-use crate::storage::keys::*;
 use crate::storage::redis::batcher::Batcher;
 use crate::storage::redis::counters_cache::{CountersCache, CountersCacheBuilder};
 use crate::storage::redis::redis_async::AsyncRedisStorage;
 use crate::storage::redis::scripts::VALUES_AND_TTLS;
 use crate::storage::redis::{
-    DEFAULT_FLUSHING_PERIOD_SEC, DEFAULT_MAX_CACHED_COUNTERS, DEFAULT_MAX_TTL_CACHED_COUNTERS_SEC,
-    DEFAULT_TTL_RATIO_CACHED_COUNTERS,
+    RedisKeys, DEFAULT_FLUSHING_PERIOD_SEC, DEFAULT_MAX_CACHED_COUNTERS,
+    DEFAULT_MAX_TTL_CACHED_COUNTERS_SEC, DEFAULT_TTL_RATIO_CACHED_COUNTERS,
 };
-use crate::storage::{AsyncCounterStorage, Authorization, StorageErr};
+use crate::storage::{only_fixed_windows, AsyncCounterStorage, Authorization, StorageErr};
 use async_trait::async_trait;
 use redis::aio::ConnectionManager;
 use redis::{ConnectionInfo, RedisError};
@@ -43,17 +42,20 @@ pub struct CachedRedisStorage {
     async_redis_storage: AsyncRedisStorage,
     redis_conn_manager: ConnectionManager,
     batching_is_enabled: bool,
+    keys: RedisKeys,
 }
 
 #[async_trait]
 impl AsyncCounterStorage for CachedRedisStorage {
     async fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        only_fixed_windows([counter])?;
         self.async_redis_storage
             .is_within_limits(counter, delta)
             .await
     }
 
     async fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
         self.async_redis_storage
             .update_counter(counter, delta)
             .await
@@ -69,6 +71,7 @@ impl AsyncCounterStorage for CachedRedisStorage {
         delta: i64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
+        only_fixed_windows(counters.iter())?;
         let mut con = self.redis_conn_manager.clone();
 
         let mut not_cached: Vec<&mut Counter> = vec![];
@@ -80,7 +83,10 @@ impl AsyncCounterStorage for CachedRedisStorage {
             for counter in counters.iter_mut() {
                 match cached_counters.get(counter) {
                     Some(val) => {
-                        if first_limited.is_none() && val - delta < 0 {
+                        // What's cached misses the hits of the other instances
+                        // since it was fetched
+                        counter.set_approximate();
+                        if first_limited.is_none() && val - counter.hits_for(delta) < 0 {
                             let a = Authorization::Limited(
                                 counter.limit().name().map(|n| n.to_owned()),
                             );
@@ -107,7 +113,7 @@ impl AsyncCounterStorage for CachedRedisStorage {
             let time_start_get_ttl = Instant::now();
 
             let (counter_vals, counter_ttls_msecs) =
-                Self::values_with_ttls(&not_cached, &mut con).await?;
+                Self::values_with_ttls(&self.keys, &not_cached, &mut con).await?;
 
             // Some time could have passed from the moment we got the TTL from Redis.
             // This margin is not exact, because we don't know exactly the
@@ -125,7 +131,8 @@ impl AsyncCounterStorage for CachedRedisStorage {
                         counter_ttls_msecs[i],
                         ttl_margin,
                     );
-                    let remaining = counter_vals[i].unwrap_or(counter.max_value()) - delta;
+                    let remaining =
+                        counter_vals[i].unwrap_or(counter.max_value()) - counter.hits_for(delta);
                     if first_limited.is_none() && remaining < 0 {
                         first_limited = Some(Authorization::Limited(
                             counter.limit().name().map(|n| n.to_owned()),
@@ -147,7 +154,7 @@ impl AsyncCounterStorage for CachedRedisStorage {
         {
             let mut cached_counters = self.cached_counters.lock().await;
             for counter in counters.iter() {
-                cached_counters.decrease_by(counter, delta);
+                cached_counters.decrease_by(counter, counter.hits_for(delta));
             }
         }
 
@@ -166,6 +173,33 @@ impl AsyncCounterStorage for CachedRedisStorage {
         Ok(Authorization::Ok)
     }
 
+    async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        self.async_redis_storage
+            .set_counter(counter, remaining, expires_in)
+            .await?;
+        // Or the value cached would keep being used instead
+        self.cached_counters.lock().await.remove(counter);
+        Ok(())
+    }
+
+    async fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        // Or the hits not flushed yet would count towards the counter anew,
+        // and the value cached keep being used instead
+        let pending = self
+            .batcher_counter_updates
+            .lock()
+            .await
+            .discard(counter)
+            .await;
+        self.cached_counters.lock().await.remove(counter);
+        Ok(self.async_redis_storage.delete_counter(counter).await? || pending)
+    }
+
     async fn get_counters(&self, limits: HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
         self.async_redis_storage.get_counters(limits).await
     }
@@ -177,6 +211,14 @@ impl AsyncCounterStorage for CachedRedisStorage {
     async fn clear(&self) -> Result<(), StorageErr> {
         self.async_redis_storage.clear().await
     }
+
+    async fn ping(&self) -> Result<(), StorageErr> {
+        self.async_redis_storage.ping().await
+    }
+
+    async fn flush(&self) -> Result<(), StorageErr> {
+        self.batcher_counter_updates.lock().await.flush().await
+    }
 }
 
 impl CachedRedisStorage {
@@ -187,6 +229,8 @@ impl CachedRedisStorage {
             DEFAULT_MAX_CACHED_COUNTERS,
             Duration::from_secs(DEFAULT_MAX_TTL_CACHED_COUNTERS_SEC),
             DEFAULT_TTL_RATIO_CACHED_COUNTERS,
+            "",
+            false,
         )
         .await
     }
@@ -197,6 +241,8 @@ impl CachedRedisStorage {
         max_cached_counters: usize,
         ttl_cached_counters: Duration,
         ttl_ratio_cached_counters: u64,
+        key_prefix: &str,
+        hashed_keys: bool,
     ) -> Result<Self, RedisError> {
         let info = ConnectionInfo::from_str(redis_url)?;
         let redis_conn_manager = ConnectionManager::new(
@@ -206,7 +252,9 @@ impl CachedRedisStorage {
         .await?;
 
         let async_redis_storage =
-            AsyncRedisStorage::new_with_conn_manager(redis_conn_manager.clone());
+            AsyncRedisStorage::new_with_conn_manager(redis_conn_manager.clone())
+                .with_key_prefix(key_prefix)
+                .with_hashed_keys(hashed_keys);
 
         let batcher = Arc::new(Mutex::new(Batcher::new(async_redis_storage.clone())));
         if let Some(flushing_period) = flushing_period {
@@ -214,7 +262,9 @@ impl CachedRedisStorage {
             tokio::spawn(async move {
                 loop {
                     let time_start = Instant::now();
-                    batcher_flusher.lock().await.flush().await;
+                    // The updates a failing flush couldn't send are kept for
+                    // the next one, e.g. once Redis is reconnected to
+                    let _ = batcher_flusher.lock().await.flush().await;
                     let sleep_time = flushing_period
                         .checked_sub(time_start.elapsed())
                         .unwrap_or_else(|| Duration::from_secs(0));
@@ -235,16 +285,18 @@ impl CachedRedisStorage {
             redis_conn_manager,
             async_redis_storage,
             batching_is_enabled: flushing_period.is_some(),
+            keys: RedisKeys::new(key_prefix, hashed_keys),
         })
     }
 
     async fn values_with_ttls(
+        keys: &RedisKeys,
         counters: &[&mut Counter],
         redis_con: &mut ConnectionManager,
     ) -> Result<(Vec<Option<i64>>, Vec<i64>), StorageErr> {
         let counter_keys: Vec<String> = counters
             .iter()
-            .map(|counter| key_for_counter(counter))
+            .map(|counter| keys.counter(counter))
             .collect();
 
         let script = redis::Script::new(VALUES_AND_TTLS);
@@ -276,6 +328,8 @@ pub struct CachedRedisStorageBuilder {
     max_cached_counters: usize,
     max_ttl_cached_counters: Duration,
     ttl_ratio_cached_counters: u64,
+    key_prefix: String,
+    hashed_keys: bool,
 }
 
 impl CachedRedisStorageBuilder {
@@ -286,6 +340,8 @@ impl CachedRedisStorageBuilder {
             max_cached_counters: DEFAULT_MAX_CACHED_COUNTERS,
             max_ttl_cached_counters: Duration::from_secs(DEFAULT_MAX_TTL_CACHED_COUNTERS_SEC),
             ttl_ratio_cached_counters: DEFAULT_TTL_RATIO_CACHED_COUNTERS,
+            key_prefix: String::new(),
+            hashed_keys: false,
         }
     }
 
@@ -309,6 +365,18 @@ impl CachedRedisStorageBuilder {
         self
     }
 
+    /// See [`AsyncRedisStorage::with_key_prefix`]
+    pub fn key_prefix(mut self, key_prefix: &str) -> Self {
+        self.key_prefix = key_prefix.to_string();
+        self
+    }
+
+    /// See [`AsyncRedisStorage::with_hashed_keys`]
+    pub fn hashed_keys(mut self, hashed_keys: bool) -> Self {
+        self.hashed_keys = hashed_keys;
+        self
+    }
+
     pub async fn build(self) -> Result<CachedRedisStorage, RedisError> {
         CachedRedisStorage::new_with_options(
             &self.redis_url,
@@ -316,6 +384,8 @@ impl CachedRedisStorageBuilder {
             self.max_cached_counters,
             self.max_ttl_cached_counters,
             self.ttl_ratio_cached_counters,
+            &self.key_prefix,
+            self.hashed_keys,
         )
         .await
     }
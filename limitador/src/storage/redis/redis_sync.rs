@@ -2,31 +2,83 @@ extern crate redis;
 
 use self::redis::{Commands, ConnectionInfo, ConnectionLike, IntoConnectionInfo, RedisError};
 use crate::counter::Counter;
-use crate::limit::Limit;
-use crate::storage::keys::*;
-use crate::storage::redis::is_limited;
-use crate::storage::redis::scripts::{SCRIPT_UPDATE_COUNTER, VALUES_AND_TTLS};
-use crate::storage::{Authorization, CounterStorage, StorageErr};
+use crate::limit::{Limit, WindowType};
+use crate::storage::leaky_bucket::LeakyBucket;
+use crate::storage::redis::scripts::{
+    SCRIPT_ADD_DISTINCT_VALUE, SCRIPT_CHECK_DISTINCT_VALUE, SCRIPT_SET_COUNTER,
+    SCRIPT_UPDATE_COUNTER, SCRIPT_UPDATE_LEAKY_BUCKET, SCRIPT_UPDATE_SHARD,
+    SCRIPT_UPDATE_SLIDING_WINDOW, SCRIPT_UPDATE_TOKEN_BUCKET,
+};
+use crate::storage::redis::{
+    batch_check_and_update, batch_limited, check_and_update_cmd, counted_distinct_value,
+    distinct_values_limited, distinct_values_remaining, fixed_windows_limited, is_sharded,
+    leaky_bucket_level, leaky_buckets_limited, next_shard, partition_by_window_type, shard_ttl,
+    sharded_counter_state, sharded_counters_limited, shards_of, share_of_shard,
+    sliding_window_fields, sliding_window_hits, sliding_window_is_live, sliding_windows_limited,
+    token_bucket_tokens, token_buckets_limited, RedisKeys, LEAKY_BUCKET_FIELDS,
+    TOKEN_BUCKET_FIELDS,
+};
+use crate::storage::sliding_window::SlidingWindow;
+use crate::storage::token_bucket::{millis_since_epoch, TokenBucket};
+use crate::storage::{only_fixed_windows, Authorization, CounterStorage, StorageErr};
 use r2d2::{ManageConnection, Pool};
 use std::collections::HashSet;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379";
 const MAX_REDIS_CONNS: u32 = 20; // TODO: make it configurable
 
-// Note: this implementation does no guarantee exact limits. Ensuring that we
-// never go over the limits would hurt performance. This implementation
-// sacrifices a bit of accuracy to be more performant.
+// Note: this implementation only guarantees exact limits for fixed windows,
+// that are checked and updated at once in Redis. Sliding windows, token or
+// leaky buckets, distinct values and sharded counters are checked before being
+// updated, so
+// concurrent requests can take them a bit over their limits, sacrificing a bit
+// of accuracy for performance.
 
 pub struct RedisStorage {
     conn_pool: Pool<RedisConnectionManager>,
+    keys: RedisKeys,
 }
 
 impl CounterStorage for RedisStorage {
     fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        let delta = counter.hits_for(delta);
         let mut con = self.conn_pool.get()?;
 
-        match con.get::<String, Option<i64>>(key_for_counter(counter))? {
+        if counter.window_type() == WindowType::Sliding {
+            let window = SlidingWindow::of(counter, SystemTime::now());
+            let state: Vec<Option<i64>> =
+                con.hget(self.keys.counter(counter), sliding_window_fields(&window))?;
+            return Ok(counter.max_value() - (sliding_window_hits(&window, &state) + delta) >= 0);
+        }
+
+        if counter.window_type() == WindowType::TokenBucket {
+            let state: Vec<Option<f64>> =
+                con.hget(self.keys.counter(counter), &TOKEN_BUCKET_FIELDS)?;
+            let tokens = token_bucket_tokens(&TokenBucket::of(counter), &state, SystemTime::now());
+            return Ok(tokens.floor() as i64 - delta >= 0);
+        }
+
+        if counter.window_type() == WindowType::LeakyBucket {
+            let state: Vec<Option<f64>> =
+                con.hget(self.keys.counter(counter), &LEAKY_BUCKET_FIELDS)?;
+            let bucket = LeakyBucket::of(counter);
+            let level = leaky_bucket_level(&bucket, &state, SystemTime::now());
+            return Ok(counter.max_value() - (bucket.hits(level) + delta) >= 0);
+        }
+
+        if counter.window_type() == WindowType::Distinct {
+            let state = self.distinct_values_state(&mut con, counter, delta)?;
+            return Ok(distinct_values_remaining(counter, &state) >= 0);
+        }
+
+        if is_sharded(counter) {
+            let state = self.shard_states(&mut con, counter)?;
+            let (remaining, _) = sharded_counter_state(counter, &state);
+            return Ok(remaining - delta >= 0);
+        }
+
+        match con.get::<String, Option<i64>>(self.keys.counter(counter))? {
             Some(val) => Ok(val - delta >= 0),
             None => Ok(counter.max_value() - delta >= 0),
         }
@@ -37,14 +89,69 @@ impl CounterStorage for RedisStorage {
     }
 
     fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        let delta = counter.hits_for(delta);
         let mut con = self.conn_pool.get()?;
 
+        if counter.window_type() == WindowType::Sliding {
+            let window = SlidingWindow::of(counter, SystemTime::now());
+            redis::Script::new(SCRIPT_UPDATE_SLIDING_WINDOW)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(window.index())
+                .arg(window.retention().as_millis() as u64)
+                .arg(delta)
+                .arg(self.keys.member(counter))
+                .arg(window.buckets())
+                .invoke(&mut *con)?;
+            return Ok(());
+        }
+
+        if counter.window_type() == WindowType::TokenBucket {
+            let bucket = TokenBucket::of(counter);
+            redis::Script::new(SCRIPT_UPDATE_TOKEN_BUCKET)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(millis_since_epoch(SystemTime::now()))
+                .arg(bucket.capacity())
+                .arg(bucket.refill_per_ms())
+                .arg(delta)
+                .arg(self.keys.member(counter))
+                .invoke(&mut *con)?;
+            return Ok(());
+        }
+
+        if counter.window_type() == WindowType::LeakyBucket {
+            let bucket = LeakyBucket::of(counter);
+            redis::Script::new(SCRIPT_UPDATE_LEAKY_BUCKET)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(millis_since_epoch(SystemTime::now()))
+                .arg(bucket.leak_per_ms())
+                .arg(delta)
+                .arg(self.keys.member(counter))
+                .invoke(&mut *con)?;
+            return Ok(());
+        }
+
+        if counter.window_type() == WindowType::Distinct {
+            if let Some(value) = counted_distinct_value(counter, delta) {
+                self.add_distinct_value(&mut con, counter, value, SystemTime::now())?;
+            }
+            return Ok(());
+        }
+
+        if is_sharded(counter) {
+            let state = self.shard_states(&mut con, counter)?;
+            return self.update_shard(&mut con, counter, &state, delta, SystemTime::now());
+        }
+
         redis::Script::new(SCRIPT_UPDATE_COUNTER)
-            .key(key_for_counter(counter))
-            .key(key_for_counters_of_limit(counter.limit()))
+            .key(self.keys.counter(counter))
+            .key(self.keys.counters_of_limit(counter.limit()))
             .arg(counter.max_value())
-            .arg(counter.seconds())
+            .arg(counter.window_at(SystemTime::now()).as_millis() as u64)
             .arg(delta)
+            .arg(self.keys.member(counter))
             .invoke(&mut *con)?;
 
         Ok(())
@@ -57,60 +164,276 @@ impl CounterStorage for RedisStorage {
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
         let mut con = self.conn_pool.get()?;
-        let counter_keys: Vec<String> = counters.iter().map(key_for_counter).collect();
+        let now = SystemTime::now();
+        let (fixed_windows, sliding_windows, token_buckets, leaky_buckets, distinct_values) =
+            partition_by_window_type(counters);
+        let (counters, others) = counters.split_at_mut(fixed_windows);
+        let (sliding_windows, others) = others.split_at_mut(sliding_windows);
+        let (token_buckets, others) = others.split_at_mut(token_buckets);
+        let (leaky_buckets, others) = others.split_at_mut(leaky_buckets);
+        let (distinct_values, sharded) = others.split_at_mut(distinct_values);
+        let mut states = Vec::with_capacity(sliding_windows.len());
+        for counter in sliding_windows.iter() {
+            let state: Vec<Option<i64>> = con.hget(
+                self.keys.counter(counter),
+                sliding_window_fields(&SlidingWindow::of(counter, now)),
+            )?;
+            states.push(state);
+        }
+        if let Some(res) =
+            sliding_windows_limited(sliding_windows, &states, delta, load_counters, now)
+        {
+            return Ok(res);
+        }
 
-        if load_counters {
-            let script = redis::Script::new(VALUES_AND_TTLS);
-            let mut script_invocation = script.prepare_invoke();
-            for counter_key in &counter_keys {
-                script_invocation.key(counter_key);
-            }
-            let script_res: Vec<Option<i64>> = script_invocation.invoke(&mut *con)?;
+        let mut states = Vec::with_capacity(token_buckets.len());
+        for counter in token_buckets.iter() {
+            let state: Vec<Option<f64>> =
+                con.hget(self.keys.counter(counter), &TOKEN_BUCKET_FIELDS)?;
+            states.push(state);
+        }
+        if let Some(res) = token_buckets_limited(token_buckets, &states, delta, load_counters, now)
+        {
+            return Ok(res);
+        }
+
+        let mut states = Vec::with_capacity(leaky_buckets.len());
+        for counter in leaky_buckets.iter() {
+            let state: Vec<Option<f64>> =
+                con.hget(self.keys.counter(counter), &LEAKY_BUCKET_FIELDS)?;
+            states.push(state);
+        }
+        if let Some(res) = leaky_buckets_limited(leaky_buckets, &states, delta, load_counters, now)
+        {
+            return Ok(res);
+        }
 
-            if let Some(res) = is_limited(counters, delta, script_res) {
+        let mut states = Vec::with_capacity(distinct_values.len());
+        for counter in distinct_values.iter() {
+            let hits = counter.hits_for(delta);
+            states.push(self.distinct_values_state(&mut con, counter, hits)?);
+        }
+        if let Some(res) = distinct_values_limited(distinct_values, &states, load_counters, now) {
+            return Ok(res);
+        }
+
+        let mut shard_states = Vec::with_capacity(sharded.len());
+        for counter in sharded.iter() {
+            shard_states.push(self.shard_states(&mut con, counter)?);
+        }
+        if let Some(res) =
+            sharded_counters_limited(sharded, &shard_states, delta, load_counters, now)
+        {
+            return Ok(res);
+        }
+
+        // Checked and counted at once, so that none of their hits are counted
+        // when one of them is limited, even by the requests checked meanwhile
+        if !counters.is_empty() {
+            let script_res: Vec<Option<i64>> =
+                check_and_update_cmd(&self.keys, counters, delta, now).query(&mut *con)?;
+            if let Some(res) = fixed_windows_limited(counters, delta, script_res, load_counters) {
                 return Ok(res);
             }
-        } else {
-            let counter_vals: Vec<Option<i64>> = redis::cmd("MGET")
-                .arg(counter_keys.clone())
-                .query(&mut *con)?;
-
-            for (i, counter) in counters.iter().enumerate() {
-                let remaining = counter_vals[i].unwrap_or(counter.max_value()) - delta;
-                if remaining < 0 {
-                    return Ok(Authorization::Limited(
-                        counter.limit().name().map(|n| n.to_owned()),
-                    ));
-                }
-            }
         }
 
-        // TODO: this can be optimized by using pipelines with multiple updates
-        for (counter_idx, key) in counter_keys.into_iter().enumerate() {
-            let counter = &counters[counter_idx];
-            redis::Script::new(SCRIPT_UPDATE_COUNTER)
-                .key(key)
-                .key(key_for_counters_of_limit(counter.limit()))
-                .arg(counter.max_value())
-                .arg(counter.seconds())
-                .arg(delta)
+        for counter in sliding_windows.iter() {
+            let window = SlidingWindow::of(counter, now);
+            redis::Script::new(SCRIPT_UPDATE_SLIDING_WINDOW)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(window.index())
+                .arg(window.retention().as_millis() as u64)
+                .arg(counter.hits_for(delta))
+                .arg(self.keys.member(counter))
+                .arg(window.buckets())
+                .invoke(&mut *con)?;
+        }
+
+        for counter in token_buckets.iter() {
+            let bucket = TokenBucket::of(counter);
+            redis::Script::new(SCRIPT_UPDATE_TOKEN_BUCKET)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(millis_since_epoch(now))
+                .arg(bucket.capacity())
+                .arg(bucket.refill_per_ms())
+                .arg(counter.hits_for(delta))
+                .arg(self.keys.member(counter))
                 .invoke(&mut *con)?;
         }
 
+        for counter in leaky_buckets.iter() {
+            let bucket = LeakyBucket::of(counter);
+            redis::Script::new(SCRIPT_UPDATE_LEAKY_BUCKET)
+                .key(self.keys.counter(counter))
+                .key(self.keys.counters_of_limit(counter.limit()))
+                .arg(millis_since_epoch(now))
+                .arg(bucket.leak_per_ms())
+                .arg(counter.hits_for(delta))
+                .arg(self.keys.member(counter))
+                .invoke(&mut *con)?;
+        }
+
+        // Only the values new to their counters need adding
+        for (counter, (_, new, _)) in distinct_values.iter().zip(&states) {
+            let hits = counter.hits_for(delta);
+            if let Some(value) = counted_distinct_value(counter, hits).filter(|_| *new > 0) {
+                self.add_distinct_value(&mut con, counter, value, now)?;
+            }
+        }
+
+        for (counter, state) in sharded.iter().zip(&shard_states) {
+            self.update_shard(&mut con, counter, state, counter.hits_for(delta), now)?;
+        }
+
         Ok(Authorization::Ok)
     }
 
+    fn check_and_update_batch(
+        &self,
+        batch: &mut [(Vec<Counter>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        // Sliding windows, buckets and sharded counters need round trips of
+        // their own
+        if batch
+            .iter()
+            .flat_map(|(counters, _)| counters.iter())
+            .any(|counter| counter.window_type() != WindowType::Fixed || is_sharded(counter))
+        {
+            return batch
+                .iter_mut()
+                .map(|(counters, delta)| self.check_and_update(counters, *delta, load_counters))
+                .collect();
+        }
+
+        let mut con = self.conn_pool.get()?;
+        let script_res = batch_check_and_update(&self.keys, batch).query(&mut *con)?;
+        Ok(batch_limited(batch, script_res, load_counters))
+    }
+
+    fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
+        let mut con = self.conn_pool.get()?;
+        if is_sharded(counter) {
+            let shards = shards_of(counter);
+            for shard in 0..shards {
+                con.pset_ex::<_, _, ()>(
+                    self.keys.shard(counter, shard),
+                    share_of_shard(remaining, shards, shard),
+                    expires_in.as_millis() as usize,
+                )?;
+            }
+            con.sadd::<_, _, ()>(
+                self.keys.counters_of_limit(counter.limit()),
+                self.keys.member(counter),
+            )?;
+            return Ok(());
+        }
+        redis::Script::new(SCRIPT_SET_COUNTER)
+            .key(self.keys.counter(counter))
+            .key(self.keys.counters_of_limit(counter.limit()))
+            .arg(remaining)
+            .arg(expires_in.as_millis() as u64)
+            .arg(self.keys.member(counter))
+            .invoke(&mut *con)?;
+        Ok(())
+    }
+
+    fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        let mut con = self.conn_pool.get()?;
+        let mut deleted: u64 = con.del(self.keys.counter(counter))?;
+        if is_sharded(counter) {
+            for shard in 0..shards_of(counter) {
+                deleted += con.del::<_, u64>(self.keys.shard(counter, shard))?;
+            }
+        }
+        con.srem::<_, _, ()>(
+            self.keys.counters_of_limit(counter.limit()),
+            self.keys.member(counter),
+        )?;
+        Ok(deleted > 0)
+    }
+
     fn get_counters(&self, limits: &HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
         let mut res = HashSet::new();
 
         let mut con = self.conn_pool.get()?;
 
         for limit in limits {
-            let counter_keys =
-                con.smembers::<String, HashSet<String>>(key_for_counters_of_limit(limit))?;
+            let members =
+                con.smembers::<String, HashSet<String>>(self.keys.counters_of_limit(limit))?;
+
+            for member in members {
+                let mut counter: Counter = self.keys.counter_from_member(&member, limit);
+                let counter_key = self.keys.counter(&counter);
+
+                if limit.window_type() == WindowType::Sliding {
+                    let window = SlidingWindow::of(&counter, SystemTime::now());
+                    let state: Vec<Option<i64>> =
+                        con.hget(&counter_key, sliding_window_fields(&window))?;
+                    if sliding_window_is_live(&window, &state) {
+                        counter.set_remaining(
+                            limit.max_value() - sliding_window_hits(&window, &state),
+                        );
+                        counter.set_expires_in(window.expires_in());
+                        res.insert(counter);
+                    }
+                    continue;
+                }
 
-            for counter_key in counter_keys {
-                let mut counter: Counter = counter_from_counter_key(&counter_key, limit);
+                if limit.window_type() == WindowType::TokenBucket {
+                    let state: Vec<Option<f64>> = con.hget(&counter_key, &TOKEN_BUCKET_FIELDS)?;
+                    let bucket = TokenBucket::of(&counter);
+                    let tokens = token_bucket_tokens(&bucket, &state, SystemTime::now());
+                    if tokens < bucket.capacity() {
+                        counter.set_remaining(tokens.floor() as i64);
+                        counter.set_expires_in(bucket.full_in(tokens));
+                        res.insert(counter);
+                    }
+                    continue;
+                }
+
+                if limit.window_type() == WindowType::LeakyBucket {
+                    let state: Vec<Option<f64>> = con.hget(&counter_key, &LEAKY_BUCKET_FIELDS)?;
+                    let bucket = LeakyBucket::of(&counter);
+                    let level = leaky_bucket_level(&bucket, &state, SystemTime::now());
+                    if level > 0.0 {
+                        counter.set_remaining(limit.max_value() - bucket.hits(level));
+                        counter.set_expires_in(bucket.empty_in(level));
+                        res.insert(counter);
+                    }
+                    continue;
+                }
+
+                if limit.window_type() == WindowType::Distinct {
+                    let (count, ttl): (i64, i64) = redis::pipe()
+                        .pfcount(&counter_key)
+                        .pttl(&counter_key)
+                        .query(&mut *con)?;
+                    if ttl > 0 {
+                        counter.set_remaining(limit.max_value() - count);
+                        counter.set_expires_in(Duration::from_millis(ttl as u64));
+                        res.insert(counter);
+                    }
+                    continue;
+                }
+
+                if is_sharded(&counter) {
+                    let state = self.shard_states(&mut con, &counter)?;
+                    if let (remaining, Some(expires_in)) = sharded_counter_state(&counter, &state) {
+                        counter.set_remaining(remaining);
+                        counter.set_expires_in(expires_in);
+                        res.insert(counter);
+                    }
+                    continue;
+                }
 
                 // If the key does not exist, it means that the counter expired,
                 // so we don't have to return it.
@@ -121,8 +444,8 @@ impl CounterStorage for RedisStorage {
                 // unnecessarily.
                 if let Some(val) = con.get::<String, Option<i64>>(counter_key.clone())? {
                     counter.set_remaining(val);
-                    let ttl = con.ttl(&counter_key)?;
-                    counter.set_expires_in(Duration::from_secs(ttl));
+                    let ttl = con.pttl(&counter_key)?;
+                    counter.set_expires_in(Duration::from_millis(ttl));
 
                     res.insert(counter);
                 }
@@ -136,11 +459,17 @@ impl CounterStorage for RedisStorage {
         let mut con = self.conn_pool.get()?;
 
         for limit in limits {
-            let counter_keys =
-                con.smembers::<String, HashSet<String>>(key_for_counters_of_limit(&limit))?;
-
-            for counter_key in counter_keys {
-                con.del(counter_key)?;
+            let members =
+                con.smembers::<String, HashSet<String>>(self.keys.counters_of_limit(&limit))?;
+
+            for member in members {
+                let counter = self.keys.counter_from_member(&member, &limit);
+                con.del(self.keys.counter(&counter))?;
+                if is_sharded(&counter) {
+                    for shard in 0..shards_of(&counter) {
+                        con.del::<_, ()>(self.keys.shard(&counter, shard))?;
+                    }
+                }
             }
         }
 
@@ -149,7 +478,20 @@ impl CounterStorage for RedisStorage {
 
     fn clear(&self) -> Result<(), StorageErr> {
         let mut con = self.conn_pool.get()?;
-        redis::cmd("FLUSHDB").execute(&mut *con);
+        // Only the keys under the prefix, others may be using the same Redis
+        if self.keys.is_prefixed() {
+            for key in con.keys::<String, Vec<String>>(self.keys.pattern())? {
+                con.del(key)?;
+            }
+        } else {
+            redis::cmd("FLUSHDB").execute(&mut *con);
+        }
+        Ok(())
+    }
+
+    fn ping(&self) -> Result<(), StorageErr> {
+        let mut con = self.conn_pool.get()?;
+        redis::cmd("PING").query::<()>(&mut *con)?;
         Ok(())
     }
 }
@@ -167,10 +509,108 @@ impl RedisStorage {
             .max_size(MAX_REDIS_CONNS)
             .build(conn_manager)
         {
-            Ok(conn_pool) => Ok(Self { conn_pool }),
+            Ok(conn_pool) => Ok(Self {
+                conn_pool,
+                keys: RedisKeys::default(),
+            }),
             Err(err) => Err(err.to_string()),
         }
     }
+
+    /// Prefixes all the keys of the storage with `key_prefix`, so that it can
+    /// share the same Redis with others using different prefixes. To keep the
+    /// keys of a namespace on the same node of a cluster, the prefix shouldn't
+    /// have any curly braces.
+    pub fn with_key_prefix(mut self, key_prefix: &str) -> Self {
+        self.keys = RedisKeys::new(key_prefix, self.keys.hashed);
+        self
+    }
+
+    /// Hashes what identifies each counter in its key, past its namespace, so
+    /// that the keys are of a bounded length however long the values of the
+    /// variables of the counters are. The same counters get the same keys
+    /// across restarts and nodes, but not the same keys as unhashed.
+    pub fn with_hashed_keys(mut self, hashed_keys: bool) -> Self {
+        self.keys = RedisKeys::new(&self.keys.prefix, hashed_keys);
+        self
+    }
+
+    // The values and TTLs (in ms) of the shards of the counter, one at a
+    // time, as they can be on different nodes of a cluster
+    fn shard_states(
+        &self,
+        con: &mut redis::Connection,
+        counter: &Counter,
+    ) -> Result<Vec<(Option<i64>, i64)>, StorageErr> {
+        (0..shards_of(counter))
+            .map(|shard| {
+                let key = self.keys.shard(counter, shard);
+                Ok(redis::pipe().get(&key).pttl(&key).query(&mut *con)?)
+            })
+            .collect()
+    }
+
+    // The distinct values of the counter, and whether the value of the
+    // request is new to them, see SCRIPT_CHECK_DISTINCT_VALUE
+    fn distinct_values_state(
+        &self,
+        con: &mut redis::Connection,
+        counter: &Counter,
+        hits: i64,
+    ) -> Result<(i64, i64, i64), StorageErr> {
+        let script = redis::Script::new(SCRIPT_CHECK_DISTINCT_VALUE);
+        let mut invocation = script.key(self.keys.counter(counter));
+        invocation.key(self.keys.distinct_scratch(counter));
+        if let Some(value) = counted_distinct_value(counter, hits) {
+            invocation.arg(value);
+        }
+        Ok(invocation.invoke(&mut *con)?)
+    }
+
+    fn add_distinct_value(
+        &self,
+        con: &mut redis::Connection,
+        counter: &Counter,
+        value: &str,
+        now: SystemTime,
+    ) -> Result<(), StorageErr> {
+        redis::Script::new(SCRIPT_ADD_DISTINCT_VALUE)
+            .key(self.keys.counter(counter))
+            .key(self.keys.counters_of_limit(counter.limit()))
+            .arg(value)
+            .arg(counter.window_at(now).as_millis() as u64)
+            .arg(self.keys.member(counter))
+            .invoke::<()>(&mut *con)?;
+        Ok(())
+    }
+
+    fn update_shard(
+        &self,
+        con: &mut redis::Connection,
+        counter: &Counter,
+        state: &[(Option<i64>, i64)],
+        delta: i64,
+        now: SystemTime,
+    ) -> Result<(), StorageErr> {
+        let shard = next_shard(counter);
+        let created: bool = redis::Script::new(SCRIPT_UPDATE_SHARD)
+            .key(self.keys.shard(counter, shard))
+            .arg(share_of_shard(
+                counter.max_value(),
+                shards_of(counter),
+                shard,
+            ))
+            .arg(shard_ttl(counter, state, now))
+            .arg(delta)
+            .invoke(&mut *con)?;
+        if created {
+            con.sadd::<_, _, ()>(
+                self.keys.counters_of_limit(counter.limit()),
+                self.keys.member(counter),
+            )?;
+        }
+        Ok(())
+    }
 }
 
 // The RedisConnectionManager is very similar to the one found in the r2d2_redis
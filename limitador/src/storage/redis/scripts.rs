@@ -10,13 +10,199 @@
 // KEYS[1]: counter key
 // KEYS[2]: key that contains the counters that belong to the limit
 // ARGV[1]: counter max val
-// ARGV[2]: counter TTL
+// ARGV[2]: counter TTL in ms
 // ARGV[3]: delta
+// ARGV[4]: member of the set of counters of the limit for the counter
 pub const SCRIPT_UPDATE_COUNTER: &str = "
-    local set_res = redis.call('set', KEYS[1], ARGV[1], 'EX', ARGV[2], 'NX')
+    local set_res = redis.call('set', KEYS[1], ARGV[1], 'PX', ARGV[2], 'NX')
     redis.call('incrby', KEYS[1], - ARGV[3])
     if set_res then
-        redis.call('sadd', KEYS[2], KEYS[1])
+        redis.call('sadd', KEYS[2], ARGV[4])
+    end";
+
+// KEYS[2i - 1]: key of the i-th counter
+// KEYS[2i]: key that contains the counters that belong to its limit
+// ARGV[4i - 3]: max val of the i-th counter
+// ARGV[4i - 2]: its TTL in ms
+// ARGV[4i - 1]: the hits to count in it
+// ARGV[4i]: its member of the set of counters of its limit
+// Checks all the counters before updating any, so that their hits are only
+// counted when none of them is over its limit. Returns the value and TTL (in
+// ms) each counter had before, laid out as in VALUES_AND_TTLS.
+pub const SCRIPT_CHECK_AND_UPDATE: &str = "
+    local res = {}
+    local limited = false
+    for i = 1, #KEYS / 2 do
+        local value = redis.call('get', KEYS[2 * i - 1])
+        table.insert(res, value)
+        table.insert(res, redis.call('pttl', KEYS[2 * i - 1]))
+        local remaining = tonumber(value) or tonumber(ARGV[4 * i - 3])
+        if remaining - tonumber(ARGV[4 * i - 1]) < 0 then
+            limited = true
+        end
+    end
+    if not limited then
+        for i = 1, #KEYS / 2 do
+            local set_res = redis.call('set', KEYS[2 * i - 1], ARGV[4 * i - 3], 'PX', ARGV[4 * i - 2], 'NX')
+            redis.call('incrby', KEYS[2 * i - 1], - ARGV[4 * i - 1])
+            if set_res then
+                redis.call('sadd', KEYS[2 * i], ARGV[4 * i])
+            end
+        end
+    end
+    return res";
+
+// KEYS[1]: key of a shard of a counter
+// ARGV[1]: the share of the max val of the counter the shard starts with
+// ARGV[2]: its TTL in ms
+// ARGV[3]: delta
+// The shards of a counter are spread across the slots of a cluster, so unlike
+// SCRIPT_UPDATE_COUNTER, this one can't add the counter to the set of counters
+// of its limit. Returns whether the shard was created, for that to be done
+// along.
+pub const SCRIPT_UPDATE_SHARD: &str = "
+    local set_res = redis.call('set', KEYS[1], ARGV[1], 'PX', ARGV[2], 'NX')
+    redis.call('incrby', KEYS[1], - ARGV[3])
+    if set_res then
+        return 1
+    end
+    return 0";
+
+// KEYS[1]: counter key
+// KEYS[2]: key that contains the counters that belong to the limit
+// ARGV[1]: hits remaining
+// ARGV[2]: counter TTL in ms
+// ARGV[3]: member of the set of counters of the limit for the counter
+pub const SCRIPT_SET_COUNTER: &str = "
+    redis.call('set', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    redis.call('sadd', KEYS[2], ARGV[3])";
+
+// KEYS[1]: counter key
+// KEYS[2]: key that contains the counters that belong to the limit
+// ARGV[1]: index of the current bucket
+// ARGV[2]: counter TTL in ms, i.e. until the end of the window starting with
+//          the next bucket
+// ARGV[3]: delta
+// ARGV[4]: member of the set of counters of the limit for the counter
+// ARGV[5]: number of buckets of the window
+// The counter is a hash holding the index of the last bucket hit ("window"),
+// and the hits of that bucket ("current"), of the one before ("previous"),
+// and of the ones before that ("previous_2", "previous_3"...), as many as the
+// buckets of the window.
+pub const SCRIPT_UPDATE_SLIDING_WINDOW: &str = "
+    local buckets = tonumber(ARGV[5])
+    local fields = {'current', 'previous'}
+    for i = 2, buckets do
+        fields[#fields + 1] = 'previous_' .. i
+    end
+    local state = redis.call('hmget', KEYS[1], 'window', unpack(fields))
+    local window = tonumber(ARGV[1])
+    local stored = tonumber(state[1])
+    if stored ~= window then
+        local shift = #fields
+        if stored and stored < window then
+            shift = window - stored
+        end
+        local values = {'window', window}
+        for i = 1, #fields do
+            local value = 0
+            if i > shift then
+                value = tonumber(state[i - shift + 1]) or 0
+            end
+            values[#values + 1] = fields[i]
+            values[#values + 1] = value
+        end
+        redis.call('hset', KEYS[1], unpack(values))
+        redis.call('pexpire', KEYS[1], ARGV[2])
+        redis.call('sadd', KEYS[2], ARGV[4])
+    end
+    redis.call('hincrby', KEYS[1], 'current', ARGV[3])";
+
+// KEYS[1]: counter key
+// KEYS[2]: key that contains the counters that belong to the limit
+// ARGV[1]: current time, in ms since the epoch
+// ARGV[2]: capacity of the bucket
+// ARGV[3]: tokens refilled per ms
+// ARGV[4]: delta
+// ARGV[5]: member of the set of counters of the limit for the counter
+// The counter is a hash holding the tokens left in the bucket ("tokens") and
+// when they were counted ("last"). It expires once the bucket is full again.
+pub const SCRIPT_UPDATE_TOKEN_BUCKET: &str = "
+    local state = redis.call('hmget', KEYS[1], 'tokens', 'last')
+    local now = tonumber(ARGV[1])
+    local capacity = tonumber(ARGV[2])
+    local refill = tonumber(ARGV[3])
+    local tokens = capacity
+    if state[1] then
+        local last = tonumber(state[2])
+        tokens = tonumber(state[1])
+        if now > last then
+            tokens = math.min(capacity, tokens + (now - last) * refill)
+        else
+            now = last
+        end
+    end
+    tokens = tokens - tonumber(ARGV[4])
+    redis.call('hset', KEYS[1], 'tokens', tostring(tokens), 'last', now)
+    redis.call('pexpire', KEYS[1], math.max(1, math.ceil((capacity - tokens) / refill)))
+    redis.call('sadd', KEYS[2], ARGV[5])
+    return math.floor(tokens)";
+
+// KEYS[1]: counter key
+// KEYS[2]: key that contains the counters that belong to the limit
+// ARGV[1]: current time, in ms since the epoch
+// ARGV[2]: hits leaked per ms
+// ARGV[3]: delta
+// ARGV[4]: member of the set of counters of the limit for the counter
+// The counter is a hash holding the hits in the bucket ("level") and when it
+// last leaked ("last"). It expires once the bucket has leaked empty.
+pub const SCRIPT_UPDATE_LEAKY_BUCKET: &str = "
+    local state = redis.call('hmget', KEYS[1], 'level', 'last')
+    local now = tonumber(ARGV[1])
+    local leak = tonumber(ARGV[2])
+    local level = 0
+    if state[1] then
+        local last = tonumber(state[2])
+        level = tonumber(state[1])
+        if now > last then
+            level = math.max(0, level - (now - last) * leak)
+        else
+            now = last
+        end
+    end
+    level = level + tonumber(ARGV[3])
+    redis.call('hset', KEYS[1], 'level', tostring(level), 'last', now)
+    redis.call('pexpire', KEYS[1], math.max(1, math.ceil(level / leak)))
+    redis.call('sadd', KEYS[2], ARGV[4])
+    return math.ceil(level)";
+
+// KEYS[1]: counter key
+// KEYS[2]: scratch key, in the same slot as the counter
+// ARGV[1]: the value of the request, only when it counts
+// The counter is a HyperLogLog of the distinct values seen in its window.
+// Returns how many it estimates, whether the value is new to them (1) or not
+// (0), as told by adding it to a copy of the counter, and the TTL (in ms) of
+// the counter.
+pub const SCRIPT_CHECK_DISTINCT_VALUE: &str = "
+    local res = {redis.call('pfcount', KEYS[1]), 0, redis.call('pttl', KEYS[1])}
+    if ARGV[1] then
+        redis.call('pfmerge', KEYS[2], KEYS[1])
+        res[2] = redis.call('pfadd', KEYS[2], ARGV[1])
+        redis.call('del', KEYS[2])
+    end
+    return res";
+
+// KEYS[1]: counter key
+// KEYS[2]: key that contains the counters that belong to the limit
+// ARGV[1]: the value of the request
+// ARGV[2]: counter TTL in ms
+// ARGV[3]: member of the set of counters of the limit for the counter
+pub const SCRIPT_ADD_DISTINCT_VALUE: &str = "
+    local exists = redis.call('exists', KEYS[1])
+    redis.call('pfadd', KEYS[1], ARGV[1])
+    if exists == 0 then
+        redis.call('pexpire', KEYS[1], ARGV[2])
+        redis.call('sadd', KEYS[2], ARGV[3])
     end";
 
 // KEYS: the function returns the value and TTL (in ms) for these keys
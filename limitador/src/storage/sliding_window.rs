@@ -0,0 +1,313 @@
+use crate::counter::Counter;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Sliding windows are approximated using the hits of the clock-aligned window
+// "now" falls in, plus the ones of the previous window, weighted by how much of
+// the sliding window still overlaps with it. That only requires keeping two
+// values per counter, while smoothing out the bursts at the boundaries of
+// fixed windows.
+// Windows split into buckets slide a bucket at a time instead: the hits of the
+// buckets the window spans count in full, but the ones of the oldest, that
+// are weighted by how much the window still overlaps with it. That takes one
+// value per bucket, plus one, for an estimate off by a fraction of a bucket
+// at most.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SlidingWindow {
+    // of the bucket "now" falls in, as are `elapsed` and `length`
+    index: u64,
+    elapsed: Duration,
+    length: Duration,
+    buckets: usize,
+}
+
+impl SlidingWindow {
+    pub fn of(counter: &Counter, when: SystemTime) -> Self {
+        Self::at(
+            when,
+            counter.window(),
+            counter.limit().sliding_buckets().unwrap_or(1),
+        )
+    }
+
+    pub fn at(when: SystemTime, length: Duration, buckets: u64) -> Self {
+        // windows of no length would never roll, so they last a second
+        let length = if length.is_zero() {
+            Duration::from_secs(1)
+        } else {
+            length
+        };
+        let buckets = buckets.max(1);
+        let since_epoch = when
+            .duration_since(UNIX_EPOCH)
+            .expect("SystemTime before UNIX EPOCH!");
+        let length_micros = (length.as_micros() / u128::from(buckets)).max(1);
+        let index = since_epoch.as_micros() / length_micros;
+        let elapsed = since_epoch.as_micros() % length_micros;
+        Self {
+            index: index as u64,
+            elapsed: Duration::from_micros(elapsed as u64),
+            length: Duration::from_micros(length_micros as u64),
+            buckets: buckets as usize,
+        }
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    pub fn buckets(&self) -> usize {
+        self.buckets
+    }
+
+    /// Estimated hits, given the ones stored for the bucket at `stored_index`,
+    /// followed by the ones of the buckets before it, the latest first.
+    pub fn hits(&self, stored_index: Option<u64>, stored: &[i64]) -> i64 {
+        let length = self.length.as_micros() as i128;
+        let overlap = length - self.elapsed.as_micros() as i128;
+        let oldest = self.value_of(stored_index, stored, self.buckets);
+        (0..self.buckets)
+            .map(|bucket| self.value_of(stored_index, stored, bucket))
+            .fold(0_i64, i64::saturating_add)
+            .saturating_add((i128::from(oldest) * overlap / length) as i64)
+    }
+
+    /// The values of the buckets of this window, the current one first, once
+    /// the ones stored for `stored_index` are moved forward.
+    pub fn rolled(&self, stored_index: Option<u64>, stored: &[i64]) -> Vec<i64> {
+        (0..=self.buckets)
+            .map(|bucket| self.value_of(stored_index, stored, bucket))
+            .collect()
+    }
+
+    /// Whether any of the hits stored still weigh on this window.
+    pub fn is_live(&self, stored_index: Option<u64>, stored: &[i64]) -> bool {
+        (0..=self.buckets).any(|bucket| self.value_of(stored_index, stored, bucket) != 0)
+    }
+
+    // The hits of the `bucket`th bucket before the current one, out of the
+    // ones stored for `stored_index`
+    fn value_of(&self, stored_index: Option<u64>, stored: &[i64], bucket: usize) -> i64 {
+        match stored_index {
+            Some(index) if index <= self.index => usize::try_from(self.index - index)
+                .ok()
+                .and_then(|rolled| bucket.checked_sub(rolled))
+                .and_then(|at| stored.get(at).copied())
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// Time left until the current bucket is over, and the hits of the window
+    /// start to be let go of.
+    pub fn expires_in(&self) -> Duration {
+        self.length - self.elapsed
+    }
+
+    /// How long the values of this window need to be kept around, i.e. until
+    /// the window that starts with the next bucket is over.
+    pub fn retention(&self) -> Duration {
+        self.expires_in() + self.length * self.buckets as u32
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SlidingWindowValue {
+    state: Mutex<SlidingWindowState>,
+}
+
+// Stored as the `current` and `previous` values of the windows of a single
+// bucket, followed by the `earlier` ones of the windows of more
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "StoredSlidingWindowState", into = "StoredSlidingWindowState")]
+pub(crate) struct SlidingWindowState {
+    index: Option<u64>,
+    values: Vec<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredSlidingWindowState {
+    index: Option<u64>,
+    current: i64,
+    previous: i64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    earlier: Vec<i64>,
+}
+
+impl From<StoredSlidingWindowState> for SlidingWindowState {
+    fn from(stored: StoredSlidingWindowState) -> Self {
+        let mut values = vec![stored.current, stored.previous];
+        values.extend(stored.earlier);
+        Self {
+            index: stored.index,
+            values,
+        }
+    }
+}
+
+impl From<SlidingWindowState> for StoredSlidingWindowState {
+    fn from(state: SlidingWindowState) -> Self {
+        let mut values = state.values.into_iter();
+        Self {
+            index: state.index,
+            current: values.next().unwrap_or_default(),
+            previous: values.next().unwrap_or_default(),
+            earlier: values.collect(),
+        }
+    }
+}
+
+impl SlidingWindowValue {
+    pub fn value_at(&self, window: &SlidingWindow) -> i64 {
+        let state = self.state.lock().unwrap();
+        window.hits(state.index, &state.values)
+    }
+
+    pub fn update(&self, delta: i64, window: &SlidingWindow) -> i64 {
+        let mut state = self.state.lock().unwrap();
+        let mut values = window.rolled(state.index, &state.values);
+        values[0] = values[0].saturating_add(delta);
+        *state = SlidingWindowState {
+            index: Some(window.index()),
+            values,
+        };
+        window.hits(state.index, &state.values)
+    }
+
+    pub fn state(&self) -> SlidingWindowState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Whether the hits stored still weigh on `window`.
+    pub fn is_live_at(&self, window: &SlidingWindow) -> bool {
+        let state = self.state.lock().unwrap();
+        window.is_live(state.index, &state.values)
+    }
+}
+
+impl From<SlidingWindowState> for SlidingWindowValue {
+    fn from(state: SlidingWindowState) -> Self {
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+}
+
+impl Clone for SlidingWindowValue {
+    fn clone(&self) -> Self {
+        Self {
+            state: Mutex::new(self.state()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64, millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis)
+    }
+
+    fn window(when: SystemTime, length: Duration) -> SlidingWindow {
+        SlidingWindow::at(when, length, 1)
+    }
+
+    #[test]
+    fn window_is_aligned_on_the_clock() {
+        let window = window(at(125, 500), Duration::from_secs(60));
+        assert_eq!(window.index(), 2);
+        assert_eq!(window.expires_in(), Duration::from_millis(54_500));
+        assert_eq!(window.retention(), Duration::from_millis(114_500));
+    }
+
+    #[test]
+    fn previous_window_is_weighted_by_its_overlap() {
+        let value = SlidingWindowValue::default();
+        let length = Duration::from_secs(10);
+        value.update(10, &window(at(100, 0), length));
+        assert_eq!(value.value_at(&window(at(105, 0), length)), 10);
+        // a quarter into the next window, 3/4 of the previous hits still count
+        assert_eq!(value.value_at(&window(at(112, 500), length)), 7);
+        assert_eq!(value.update(2, &window(at(112, 500), length)), 9);
+        // the hits from two windows ago don't matter anymore
+        assert_eq!(value.value_at(&window(at(125, 0), length)), 1);
+        assert_eq!(value.value_at(&window(at(130, 0), length)), 0);
+        assert!(!value.is_live_at(&window(at(130, 0), length)));
+    }
+
+    #[test]
+    fn windows_can_last_less_than_a_second() {
+        let sliding = window(at(100, 300), Duration::from_millis(250));
+        assert_eq!(sliding.index(), 401);
+        assert_eq!(sliding.expires_in(), Duration::from_millis(200));
+
+        let value = SlidingWindowValue::default();
+        let length = Duration::from_millis(500);
+        value.update(4, &window(at(100, 0), length));
+        // half into the next window, half of the previous hits still count
+        assert_eq!(value.value_at(&window(at(100, 750), length)), 2);
+        assert!(!value.is_live_at(&window(at(101, 0), length)));
+    }
+
+    #[test]
+    fn windows_of_buckets_slide_a_bucket_at_a_time() {
+        let length = Duration::from_secs(60);
+        let window = SlidingWindow::at(at(125, 500), length, 4);
+        assert_eq!(window.index(), 8);
+        assert_eq!(window.buckets(), 4);
+        assert_eq!(window.expires_in(), Duration::from_millis(9_500));
+        assert_eq!(window.retention(), Duration::from_millis(69_500));
+
+        let value = SlidingWindowValue::default();
+        value.update(8, &SlidingWindow::at(at(100, 0), length, 4));
+        value.update(4, &SlidingWindow::at(at(130, 0), length, 4));
+        assert_eq!(
+            value.value_at(&SlidingWindow::at(at(150, 0), length, 4)),
+            12
+        );
+        // two thirds into the bucket after the window of the first hits, a
+        // third of them still count
+        assert_eq!(value.value_at(&SlidingWindow::at(at(160, 0), length, 4)), 6);
+        assert_eq!(value.value_at(&SlidingWindow::at(at(185, 0), length, 4)), 2);
+        assert_eq!(value.value_at(&SlidingWindow::at(at(225, 0), length, 4)), 0);
+        assert!(!value.is_live_at(&SlidingWindow::at(at(225, 0), length, 4)));
+    }
+
+    #[test]
+    fn buckets_are_more_accurate_across_a_boundary() {
+        // 10 hits at the very end of a window, looked at right past the next
+        // boundary, when all of them happened less than a window ago
+        let length = Duration::from_secs(60);
+        let hit = at(119, 0);
+        let later = at(150, 0);
+
+        let single = SlidingWindowValue::default();
+        single.update(10, &SlidingWindow::at(hit, length, 1));
+        assert_eq!(single.value_at(&SlidingWindow::at(later, length, 1)), 5);
+
+        let buckets = SlidingWindowValue::default();
+        buckets.update(10, &SlidingWindow::at(hit, length, 6));
+        assert_eq!(buckets.value_at(&SlidingWindow::at(later, length, 6)), 10);
+        // and let go of them once the window has moved past them
+        assert_eq!(
+            buckets.value_at(&SlidingWindow::at(at(185, 0), length, 6)),
+            0
+        );
+    }
+
+    #[test]
+    fn state_of_a_single_bucket_is_stored_as_before() {
+        let value = SlidingWindowValue::default();
+        value.update(3, &window(at(100, 0), Duration::from_secs(10)));
+        let stored = serde_json::to_value(value.state()).unwrap();
+        assert_eq!(
+            stored,
+            serde_json::json!({"index": 10, "current": 3, "previous": 0})
+        );
+        let state: SlidingWindowState = serde_json::from_value(stored).unwrap();
+        assert_eq!(state, value.state());
+    }
+}
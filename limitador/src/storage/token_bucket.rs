@@ -0,0 +1,158 @@
+use crate::counter::Counter;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Token buckets hold up to `max_value` tokens, and are refilled continuously at
+// the limit's refill rate. Each hit consumes tokens, so bursts of up to the
+// bucket's capacity are allowed, while the sustained throughput is bound by the
+// refill rate. A bucket that has been refilled completely is the same as one
+// that was never hit, so there's no need to keep it around.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    refill_per_ms: f64,
+}
+
+impl TokenBucket {
+    pub fn of(counter: &Counter) -> Self {
+        let capacity = counter.max_value() as f64;
+        // Unless told otherwise, refill the whole bucket over its window
+        let refill_per_sec = match counter.limit().refill_rate() {
+            Some(rate) if rate > 0 => rate as f64,
+            _ => match counter.window().as_secs_f64() {
+                window if window > 0.0 => capacity / window,
+                _ => capacity,
+            },
+        };
+        Self {
+            capacity,
+            refill_per_ms: refill_per_sec / 1000.0,
+        }
+    }
+
+    pub fn capacity(&self) -> f64 {
+        self.capacity
+    }
+
+    pub fn refill_per_ms(&self) -> f64 {
+        self.refill_per_ms
+    }
+
+    /// Tokens available at `now_ms`, given the ones stored at `last_ms`.
+    pub fn tokens_at(&self, stored: Option<(f64, u64)>, now_ms: u64) -> f64 {
+        match stored {
+            None => self.capacity,
+            Some((tokens, last_ms)) => {
+                let refilled = now_ms.saturating_sub(last_ms) as f64 * self.refill_per_ms;
+                self.capacity.min(tokens + refilled)
+            }
+        }
+    }
+
+    /// Time it takes for a bucket holding `tokens` to be full again.
+    pub fn full_in(&self, tokens: f64) -> Duration {
+        if tokens >= self.capacity || self.refill_per_ms <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_millis(((self.capacity - tokens) / self.refill_per_ms).ceil() as u64)
+    }
+}
+
+pub(crate) fn millis_since_epoch(when: SystemTime) -> u64 {
+    when.duration_since(UNIX_EPOCH)
+        .expect("SystemTime before UNIX EPOCH!")
+        .as_millis() as u64
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TokenBucketValue {
+    // tokens left, and when they were last counted
+    state: Mutex<Option<(f64, u64)>>,
+}
+
+impl TokenBucketValue {
+    pub fn tokens_at(&self, bucket: &TokenBucket, when: SystemTime) -> f64 {
+        bucket.tokens_at(*self.state.lock().unwrap(), millis_since_epoch(when))
+    }
+
+    pub fn consume(&self, bucket: &TokenBucket, delta: i64, when: SystemTime) -> f64 {
+        let now_ms = millis_since_epoch(when);
+        let mut state = self.state.lock().unwrap();
+        let tokens = bucket.tokens_at(*state, now_ms) - delta as f64;
+        *state = Some((tokens, now_ms));
+        tokens
+    }
+
+    /// The tokens left, and when they were last counted, unless never hit.
+    pub fn state(&self) -> Option<(f64, u64)> {
+        *self.state.lock().unwrap()
+    }
+
+    /// Whether the bucket is still missing some tokens at `when`.
+    pub fn is_live_at(&self, bucket: &TokenBucket, when: SystemTime) -> bool {
+        self.tokens_at(bucket, when) < bucket.capacity()
+    }
+}
+
+impl From<(f64, u64)> for TokenBucketValue {
+    fn from(state: (f64, u64)) -> Self {
+        Self {
+            state: Mutex::new(Some(state)),
+        }
+    }
+}
+
+impl Clone for TokenBucketValue {
+    fn clone(&self) -> Self {
+        Self {
+            state: Mutex::new(*self.state.lock().unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limit::{Limit, WindowType};
+    use std::collections::HashMap;
+
+    fn bucket(max_value: i64, seconds: u64, refill_rate: Option<u64>) -> TokenBucket {
+        let mut limit = Limit::new(
+            "ns",
+            max_value,
+            seconds,
+            Vec::<String>::default(),
+            vec!["id"],
+        );
+        limit.set_window_type(WindowType::TokenBucket);
+        limit.set_refill_rate(refill_rate);
+        let values = HashMap::from([("id".to_string(), "1".to_string())]);
+        TokenBucket::of(&Counter::new(limit, values))
+    }
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn refills_the_whole_bucket_over_the_limit_seconds_by_default() {
+        let bucket = bucket(10, 10, None);
+        assert_eq!(bucket.refill_per_ms(), 0.001);
+        assert_eq!(bucket.tokens_at(None, 1_000), 10.0);
+        assert_eq!(bucket.full_in(5.0), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn consumed_tokens_are_refilled_at_the_refill_rate() {
+        let bucket = bucket(10, 60, Some(2));
+        let value = TokenBucketValue::default();
+        assert_eq!(value.consume(&bucket, 10, at(1_000)), 0.0);
+        assert!(value.is_live_at(&bucket, at(1_000)));
+        assert_eq!(value.tokens_at(&bucket, at(2_500)), 3.0);
+        assert_eq!(value.consume(&bucket, 3, at(2_500)), 0.0);
+        // never goes above the capacity
+        assert_eq!(value.tokens_at(&bucket, at(60_000)), 10.0);
+        assert!(!value.is_live_at(&bucket, at(60_000)));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::counter::Counter;
 use crate::limit::{Limit, Namespace};
-use crate::storage::{Authorization, CounterStorage, StorageErr};
+use crate::storage::{only_fixed_windows, Authorization, CounterStorage, StorageErr};
 use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 use std::sync::RwLock;
@@ -11,9 +11,7 @@ use std::time::{Duration, SystemTime};
 // WASM, because it relies on std:time functions. This implementation avoids
 // that.
 
-pub trait Clock: Sync + Send {
-    fn get_current_time(&self) -> SystemTime;
-}
+pub use crate::storage::clock::Clock;
 
 pub struct CacheEntry<V> {
     pub value: V,
@@ -22,7 +20,7 @@ pub struct CacheEntry<V> {
 
 impl<V: Copy> CacheEntry<V> {
     fn is_expired(&self, current_time: SystemTime) -> bool {
-        current_time > self.expires_at
+        current_time >= self.expires_at
     }
 }
 
@@ -41,17 +39,23 @@ impl<K: Eq + Hash + Clone, V: Copy> Cache<K, V> {
         self.map.get(key)
     }
 
+    pub fn get_key_value(&self, key: &K) -> Option<(&K, &CacheEntry<V>)> {
+        self.map.get_key_value(key)
+    }
+
     pub fn get_mut(&mut self, key: &K) -> Option<&mut CacheEntry<V>> {
         self.map.get_mut(key)
     }
 
     pub fn insert(&mut self, key: &K, value: V, expires_at: SystemTime) {
+        // The key is replaced too, as keys equal to it can still differ
+        self.map.remove(key);
         self.map
             .insert(key.clone(), CacheEntry { value, expires_at });
     }
 
-    pub fn remove(&mut self, key: &K) {
-        self.map.remove(key);
+    pub fn remove(&mut self, key: &K) -> Option<CacheEntry<V>> {
+        self.map.remove(key)
     }
 
     pub fn get_all(&mut self, current_time: SystemTime) -> Vec<(K, V, SystemTime)> {
@@ -81,8 +85,9 @@ pub struct WasmStorage {
 
 impl CounterStorage for WasmStorage {
     fn is_within_limits(&self, counter: &Counter, delta: i64) -> Result<bool, StorageErr> {
+        only_fixed_windows([counter])?;
         let stored_counters = self.counters.read().unwrap();
-        Ok(self.counter_is_within_limits(counter, stored_counters.get(counter), delta))
+        Ok(self.counter_is_within_limits(counter, stored_counters.get_key_value(counter), delta))
     }
 
     fn add_counter(&self, _limit: &Limit) -> Result<(), StorageErr> {
@@ -90,6 +95,7 @@ impl CounterStorage for WasmStorage {
     }
 
     fn update_counter(&self, counter: &Counter, delta: i64) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
         let mut counters = self.counters.write().unwrap();
         self.insert_or_update_counter(&mut counters, counter, delta);
         Ok(())
@@ -101,24 +107,27 @@ impl CounterStorage for WasmStorage {
         delta: i64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
+        only_fixed_windows(counters.iter())?;
         // This makes the operator of check + update atomic
         let mut stored_counters = self.counters.write().unwrap();
 
         if load_counters {
+            let now = self.clock.get_current_time();
             let mut first_limited = None;
             for counter in counters.iter_mut() {
-                let (remaining, expires_in) = match stored_counters.get(counter) {
-                    Some(entry) => (
-                        entry.value - delta,
+                let delta = counter.hits_for(delta);
+                let entry = stored_counters
+                    .get_key_value(counter)
+                    .filter(|(_, entry)| !entry.is_expired(now));
+                let (remaining, expires_in) = match entry {
+                    Some((stored, entry)) => (
+                        remaining_of(counter, stored, entry.value) - delta,
                         entry
                             .expires_at
-                            .duration_since(self.clock.get_current_time())
+                            .duration_since(now)
                             .unwrap_or(Duration::from_secs(0)),
                     ),
-                    None => (
-                        counter.max_value() - delta,
-                        Duration::from_secs(counter.seconds()),
-                    ),
+                    None => (counter.max_value() - delta, counter.window_at(now)),
                 };
                 counter.set_remaining(remaining);
                 counter.set_expires_in(expires_in);
@@ -134,7 +143,11 @@ impl CounterStorage for WasmStorage {
             }
         } else {
             for counter in counters.iter() {
-                if !self.counter_is_within_limits(counter, stored_counters.get(counter), delta) {
+                if !self.counter_is_within_limits(
+                    counter,
+                    stored_counters.get_key_value(counter),
+                    delta,
+                ) {
                     return Ok(Authorization::Limited(
                         counter.limit().name().map(|n| n.to_owned()),
                     ));
@@ -149,6 +162,40 @@ impl CounterStorage for WasmStorage {
         Ok(Authorization::Ok)
     }
 
+    fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<(), StorageErr> {
+        only_fixed_windows([counter])?;
+        let expires_at = self.clock.get_current_time() + expires_in;
+        self.add_counter(counter, remaining, expires_at);
+        self.add_counter_limit_association(counter);
+        Ok(())
+    }
+
+    fn delete_counter(&self, counter: &Counter) -> Result<bool, StorageErr> {
+        only_fixed_windows([counter])?;
+        if let Some(counters_by_limit) = self
+            .limits_for_namespace
+            .write()
+            .unwrap()
+            .get_mut(counter.namespace())
+        {
+            if let Some(counters_of_limit) = counters_by_limit.get_mut(counter.limit()) {
+                counters_of_limit.remove(counter);
+            }
+        }
+        let now = self.clock.get_current_time();
+        Ok(self
+            .counters
+            .write()
+            .unwrap()
+            .remove(counter)
+            .is_some_and(|entry| !entry.is_expired(now)))
+    }
+
     fn get_counters(&self, limits: &HashSet<Limit>) -> Result<HashSet<Counter>, StorageErr> {
         // TODO: optimize to avoid iterating over all of them.
 
@@ -247,14 +294,16 @@ impl WasmStorage {
         counter: &Counter,
         delta: i64,
     ) {
+        let now = self.clock.get_current_time();
+        let delta = counter.hits_for(delta);
         match counters.get_mut(counter) {
             Some(entry) => {
-                if entry.is_expired(self.clock.get_current_time()) {
+                if entry.is_expired(now) {
                     // TODO: remove duplication. "None" branch is identical.
                     counters.insert(
                         counter,
                         counter.max_value() - delta,
-                        self.clock.get_current_time() + Duration::from_secs(counter.seconds()),
+                        now + counter.window_at(now),
                     );
                 } else {
                     entry.value -= delta;
@@ -264,7 +313,7 @@ impl WasmStorage {
                 counters.insert(
                     counter,
                     counter.max_value() - delta,
-                    self.clock.get_current_time() + Duration::from_secs(counter.seconds()),
+                    now + counter.window_at(now),
                 );
 
                 self.add_counter_limit_association(counter);
@@ -275,18 +324,25 @@ impl WasmStorage {
     fn counter_is_within_limits(
         &self,
         counter: &Counter,
-        cache_entry: Option<&CacheEntry<i64>>,
+        cache_entry: Option<(&Counter, &CacheEntry<i64>)>,
         delta: i64,
     ) -> bool {
+        let delta = counter.hits_for(delta);
         match cache_entry {
-            Some(entry) => {
+            Some((stored, entry)) => {
                 if entry.is_expired(self.clock.get_current_time()) {
                     counter.max_value() - delta >= 0
                 } else {
-                    entry.value - delta >= 0
+                    remaining_of(counter, stored, entry.value) - delta >= 0
                 }
             }
             None => counter.max_value() - delta >= 0,
         }
     }
 }
+
+// What's stored is what remains of the max value of the counter when stored,
+// to be made relative to the max value the counter is checked against now.
+fn remaining_of(counter: &Counter, stored: &Counter, value: i64) -> i64 {
+    value + counter.max_value() - stored.max_value()
+}
@@ -3,11 +3,15 @@ use limitador::errors::LimitadorError;
 use limitador::limit::{Limit, Namespace};
 use limitador::{AsyncRateLimiter, CheckResult, RateLimiter};
 use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 // This exposes a struct that wraps both implementations of the rate limiter,
 // the blocking and the async one. This allows us to avoid duplications in the
 // tests.
 
+// Same as limitador::OverridingCheck, with the namespace as a &str
+type OverridingCheck<'a> = (&'a str, &'a HashMap<String, String>, i64, Option<i64>);
+
 enum LimiterImpl {
     Blocking(RateLimiter),
     #[allow(dead_code)] // dead when no "redis_storage"
@@ -53,6 +57,30 @@ impl TestsLimiter {
         }
     }
 
+    pub async fn delete_limit_by_name(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Result<bool, LimitadorError> {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => limiter.delete_limit_by_name(&namespace.into(), name),
+            LimiterImpl::Async(limiter) => {
+                limiter.delete_limit_by_name(&namespace.into(), name).await
+            }
+        }
+    }
+
+    pub fn set_limit_enabled_by_name(&self, namespace: &str, name: &str, enabled: bool) -> bool {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => {
+                limiter.set_limit_enabled_by_name(&namespace.into(), name, enabled)
+            }
+            LimiterImpl::Async(limiter) => {
+                limiter.set_limit_enabled_by_name(&namespace.into(), name, enabled)
+            }
+        }
+    }
+
     pub async fn get_limits(&self, namespace: &str) -> HashSet<Limit> {
         match &self.limiter_impl {
             LimiterImpl::Blocking(limiter) => limiter.get_limits(&namespace.into()),
@@ -85,6 +113,25 @@ impl TestsLimiter {
         }
     }
 
+    pub async fn is_rate_limited_overriding(
+        &self,
+        namespace: &str,
+        values: &HashMap<String, String>,
+        delta: i64,
+        max_value: Option<i64>,
+    ) -> Result<bool, LimitadorError> {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => {
+                limiter.is_rate_limited_overriding(&namespace.into(), values, delta, max_value)
+            }
+            LimiterImpl::Async(limiter) => {
+                limiter
+                    .is_rate_limited_overriding(&namespace.into(), values, delta, max_value)
+                    .await
+            }
+        }
+    }
+
     pub async fn update_counters(
         &self,
         namespace: &str,
@@ -125,6 +172,62 @@ impl TestsLimiter {
         }
     }
 
+    pub async fn check_rate_limited_and_update_batch(
+        &self,
+        batch: &[(&str, &HashMap<String, String>, i64)],
+        load_counters: bool,
+    ) -> Result<Vec<CheckResult>, LimitadorError> {
+        let namespaces: Vec<Namespace> = batch
+            .iter()
+            .map(|(namespace, _, _)| (*namespace).into())
+            .collect();
+        let batch: Vec<(&Namespace, &HashMap<String, String>, i64)> = batch
+            .iter()
+            .zip(&namespaces)
+            .map(|((_, values, delta), namespace)| (namespace, *values, *delta))
+            .collect();
+
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => {
+                limiter.check_rate_limited_and_update_batch(&batch, load_counters)
+            }
+            LimiterImpl::Async(limiter) => {
+                limiter
+                    .check_rate_limited_and_update_batch(&batch, load_counters)
+                    .await
+            }
+        }
+    }
+
+    pub async fn check_rate_limited_and_update_batch_overriding(
+        &self,
+        batch: &[OverridingCheck<'_>],
+        load_counters: bool,
+    ) -> Result<Vec<CheckResult>, LimitadorError> {
+        let namespaces: Vec<Namespace> = batch
+            .iter()
+            .map(|(namespace, _, _, _)| (*namespace).into())
+            .collect();
+        let batch: Vec<_> = batch
+            .iter()
+            .zip(&namespaces)
+            .map(|((_, values, delta, max_value), namespace)| {
+                (namespace, *values, *delta, *max_value)
+            })
+            .collect();
+
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => {
+                limiter.check_rate_limited_and_update_batch_overriding(&batch, load_counters)
+            }
+            LimiterImpl::Async(limiter) => {
+                limiter
+                    .check_rate_limited_and_update_batch_overriding(&batch, load_counters)
+                    .await
+            }
+        }
+    }
+
     pub async fn get_counters(&self, namespace: &str) -> Result<HashSet<Counter>, LimitadorError> {
         match &self.limiter_impl {
             LimiterImpl::Blocking(limiter) => limiter.get_counters(&namespace.into()),
@@ -132,6 +235,45 @@ impl TestsLimiter {
         }
     }
 
+    pub async fn warm_up(&self) -> Result<(), LimitadorError> {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => limiter.warm_up(),
+            LimiterImpl::Async(limiter) => limiter.warm_up().await,
+        }
+    }
+
+    pub async fn set_counter(
+        &self,
+        counter: &Counter,
+        remaining: i64,
+        expires_in: Duration,
+    ) -> Result<bool, LimitadorError> {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => limiter.set_counter(counter, remaining, expires_in),
+            LimiterImpl::Async(limiter) => {
+                limiter.set_counter(counter, remaining, expires_in).await
+            }
+        }
+    }
+
+    pub async fn reset_counter(
+        &self,
+        namespace: &str,
+        limit_name: &str,
+        values: &HashMap<String, String>,
+    ) -> Result<Option<bool>, LimitadorError> {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => {
+                limiter.reset_counter(&namespace.into(), limit_name, values)
+            }
+            LimiterImpl::Async(limiter) => {
+                limiter
+                    .reset_counter(&namespace.into(), limit_name, values)
+                    .await
+            }
+        }
+    }
+
     pub async fn configure_with(
         &self,
         limits: impl IntoIterator<Item = Limit>,
@@ -55,6 +55,23 @@ macro_rules! test_with_all_storage_impls {
                 $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
             }
 
+            #[cfg(feature = "redis_storage")]
+            #[tokio::test]
+            #[serial]
+            async fn [<$function _with_pooled_and_pipelined_async_redis>]() {
+                let storage = AsyncRedisStorageBuilder::new("redis://127.0.0.1:6379")
+                    .pool_size(4)
+                    .pipelining(10, Duration::from_micros(100))
+                    .build()
+                    .await
+                    .expect("We need a Redis running locally");
+                storage.clear().await.unwrap();
+                let rate_limiter = AsyncRateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
+            }
+
             #[cfg(feature = "infinispan_storage")]
             #[tokio::test]
             #[serial]
@@ -68,6 +85,23 @@ macro_rules! test_with_all_storage_impls {
                 );
                 $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
             }
+
+            #[cfg(feature = "postgres_storage")]
+            #[tokio::test]
+            #[serial]
+            async fn [<$function _with_postgres>]() {
+                // Only run when told where to find a Postgres
+                let Ok(url) = std::env::var("LIMITADOR_TEST_POSTGRES_URL") else { return };
+                let storage = PostgresStorage::new(&url)
+                    .await
+                    .expect("We need the Postgres at LIMITADOR_TEST_POSTGRES_URL running");
+                storage.migrate().await.unwrap();
+                storage.clear().await.unwrap();
+                let rate_limiter = AsyncRateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
+            }
         }
     };
 }
@@ -82,12 +116,14 @@ mod test {
     cfg_if::cfg_if! {
         if #[cfg(feature = "redis_storage")] {
             use limitador::storage::redis::AsyncRedisStorage;
+            use limitador::storage::redis::AsyncRedisStorageBuilder;
             use limitador::storage::redis::RedisStorage;
 
             use limitador::AsyncRateLimiter;
             use serial_test::serial;
             use crate::test::limitador::storage::CounterStorage;
             use crate::test::limitador::storage::AsyncCounterStorage;
+            use limitador::storage::Authorization;
         }
     }
 
@@ -97,17 +133,29 @@ mod test {
        }
     }
 
+    cfg_if::cfg_if! {
+       if #[cfg(feature = "postgres_storage")] {
+           use limitador::storage::postgres::PostgresStorage;
+       }
+    }
+
     use self::limitador::counter::Counter;
     use self::limitador::storage::wasm::Clock;
-    use self::limitador::RateLimiter;
+    use self::limitador::{CheckOutcome, RateLimiter};
     use crate::helpers::tests_limiter::*;
-    use limitador::limit::Limit;
+    use limitador::errors::LimitadorError;
+    use limitador::limit::{
+        CalendarPeriod, CalendarWindow, Cost, HttpMethod, Limit, MaxValues, Tz, WindowLevel,
+        WindowType, WILDCARD_NAMESPACE,
+    };
+    use limitador::storage::clock::ManualClock;
     use limitador::storage::disk::{DiskStorage, OptimizeFor};
     use limitador::storage::in_memory::InMemoryStorage;
     use limitador::storage::wasm::WasmStorage;
     use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
     use std::thread::sleep;
-    use std::time::{Duration, SystemTime};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
     use tempfile::TempDir;
 
     // This is only needed for the WASM-compatible storage.
@@ -140,9 +188,35 @@ mod test {
     test_with_all_storage_impls!(is_rate_limited_applies_limit_if_its_unconditional);
     test_with_all_storage_impls!(check_rate_limited_and_update);
     test_with_all_storage_impls!(check_rate_limited_and_update_load_counters);
+    test_with_all_storage_impls!(check_rate_limited_and_update_reports_when_to_retry);
+    test_with_all_storage_impls!(
+        check_rate_limited_and_update_reports_the_limit_of_the_highest_priority
+    );
+    test_with_all_storage_impls!(check_rate_limited_and_update_weighs_the_hits_by_their_cost);
+    test_with_all_storage_impls!(check_rate_limited_and_update_looks_up_the_max_value_of_a_request);
+    test_with_all_storage_impls!(check_rate_limited_and_update_counts_in_shared_counters);
+    test_with_all_storage_impls!(check_rate_limited_and_update_spreads_the_resets_by_the_jitter);
+    test_with_all_storage_impls!(
+        check_rate_limited_and_update_counts_without_the_optional_variables_missing
+    );
+    test_with_all_storage_impls!(check_rate_limited_and_update_falls_back_when_no_limit_applies);
+    test_with_all_storage_impls!(
+        check_rate_limited_and_update_counts_once_in_a_counter_shared_by_the_limits_that_apply
+    );
+    test_with_all_storage_impls!(check_rate_limited_and_update_counts_no_hits_when_a_limit_is_over);
+    test_with_all_storage_impls!(check_rate_limited_and_update_batch_across_namespaces);
+    test_with_all_storage_impls!(check_rate_limited_and_update_batch_overriding_the_max_value);
     test_with_all_storage_impls!(check_rate_limited_and_update_returns_true_if_no_limits_apply);
     test_with_all_storage_impls!(check_rate_limited_and_update_applies_limit_if_its_unconditional);
+    test_with_all_storage_impls!(
+        check_rate_limited_and_update_matches_case_insensitive_conditions_whatever_the_case
+    );
+    test_with_all_storage_impls!(check_rate_limited_and_update_resets_windows_of_milliseconds);
+    test_with_all_storage_impls!(check_rate_limited_and_update_sums_the_shards_of_the_counters);
     test_with_all_storage_impls!(get_counters);
+    test_with_all_storage_impls!(set_counter_replaces_the_hits_of_the_counter);
+    test_with_all_storage_impls!(set_counter_only_sets_valid_counters_of_known_limits);
+    test_with_all_storage_impls!(reset_counter_starts_the_counter_of_the_values_anew);
     test_with_all_storage_impls!(get_counters_returns_empty_when_no_limits_in_namespace);
     test_with_all_storage_impls!(get_counters_returns_empty_when_no_counters_in_namespace);
     test_with_all_storage_impls!(get_counters_does_not_return_expired_ones);
@@ -151,6 +225,13 @@ mod test {
     test_with_all_storage_impls!(configure_with_deletes_all_except_the_limits_given);
     test_with_all_storage_impls!(configure_with_updates_the_limits);
     test_with_all_storage_impls!(add_limit_only_adds_if_not_present);
+    test_with_all_storage_impls!(add_limit_only_adds_if_its_name_is_not_taken);
+    test_with_all_storage_impls!(delete_limit_by_name_also_deletes_its_counters);
+    test_with_all_storage_impls!(disabled_limits_stop_enforcing_and_keep_their_counters);
+    test_with_all_storage_impls!(shadow_limits_count_hits_but_never_limit);
+    test_with_all_storage_impls!(soft_limits_flag_the_requests_past_them_and_let_them_through);
+    test_with_all_storage_impls!(wildcard_limits_apply_to_all_namespaces_counting_apart);
+    test_with_all_storage_impls!(warm_up_leaves_the_limits_and_their_counters_alone);
 
     // All these functions need to use async/await. That's needed to support
     // both the sync and the async implementations of the rate limiter.
@@ -655,6 +736,134 @@ mod test {
         );
     }
 
+    async fn check_rate_limited_and_update_counts_no_hits_when_a_limit_is_over(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let per_app = Limit::new(namespace, 10, 60, Vec::<String>::new(), vec!["app_id"]);
+        let posts_per_app = Limit::new(namespace, 2, 60, vec!["method == 'POST'"], vec!["app_id"]);
+        rate_limiter.add_limit(&per_app).await;
+        rate_limiter.add_limit(&posts_per_app).await;
+
+        let values = HashMap::from([
+            ("app_id".to_string(), "test_app_id".to_string()),
+            ("method".to_string(), "POST".to_string()),
+        ]);
+        for limited in [false, false, true] {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap();
+            assert_eq!(result.limited, limited);
+        }
+        let results = rate_limiter
+            .check_rate_limited_and_update_batch(&[(namespace, &values, 1)], false)
+            .await
+            .unwrap();
+        assert!(results[0].limited);
+
+        // The limited checks didn't count in the limit they were within
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+        assert_eq!(counters.len(), 2);
+        for counter in counters {
+            if counter.limit() == &per_app {
+                assert_eq!(counter.remaining(), Some(8));
+            } else {
+                assert_eq!(counter.remaining(), Some(0));
+            }
+        }
+    }
+
+    async fn check_rate_limited_and_update_batch_across_namespaces(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let limit_1 = Limit::new("test_namespace_1", 1, 60, vec!["x == '1'"], vec!["z"]);
+        let limit_2 = Limit::new("test_namespace_2", 2, 60, vec!["x == '1'"], vec!["z"]);
+
+        rate_limiter.add_limit(&limit_1).await;
+        rate_limiter.add_limit(&limit_2).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("x".to_string(), "1".to_string());
+        values.insert("z".to_string(), "1".to_string());
+
+        let batch = [
+            ("test_namespace_1", &values, 1),
+            ("test_namespace_2", &values, 1),
+        ];
+
+        let results = rate_limiter
+            .check_rate_limited_and_update_batch(&batch, true)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(!results[0].limited);
+        assert_eq!(results[0].counters[0].remaining(), Some(0));
+        assert!(!results[1].limited);
+        assert_eq!(results[1].counters[0].remaining(), Some(1));
+
+        // Only the first namespace is over its limit now, and that doesn't
+        // prevent the hits of the second one from being counted
+        let results = rate_limiter
+            .check_rate_limited_and_update_batch(&batch, false)
+            .await
+            .unwrap();
+        assert!(results[0].limited);
+        assert!(!results[1].limited);
+
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update("test_namespace_2", &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+    }
+
+    async fn check_rate_limited_and_update_batch_overriding_the_max_value(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let limit = Limit::new(namespace, 1, 60, vec!["x == '1'"], vec!["z"]);
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("x".to_string(), "1".to_string());
+        values.insert("z".to_string(), "1".to_string());
+
+        for remaining in [2, 1, 0] {
+            let results = rate_limiter
+                .check_rate_limited_and_update_batch_overriding(
+                    &[(namespace, &values, 1, Some(3))],
+                    true,
+                )
+                .await
+                .unwrap();
+            assert!(!results[0].limited);
+            assert_eq!(results[0].counters[0].max_value(), 3);
+            assert_eq!(results[0].counters[0].remaining(), Some(remaining));
+        }
+
+        let results = rate_limiter
+            .check_rate_limited_and_update_batch_overriding(
+                &[(namespace, &values, 1, Some(3))],
+                false,
+            )
+            .await
+            .unwrap();
+        assert!(results[0].limited);
+
+        // The counters are the same, whatever the max value they're checked against
+        assert!(rate_limiter
+            .is_rate_limited_overriding(namespace, &values, 1, None)
+            .await
+            .unwrap());
+        assert!(!rate_limiter
+            .is_rate_limited_overriding(namespace, &values, 1, Some(4))
+            .await
+            .unwrap());
+    }
+
     async fn check_rate_limited_and_update_load_counters(rate_limiter: &mut TestsLimiter) {
         let namespace = "test_namespace";
         let max_hits = 3;
@@ -704,213 +913,385 @@ mod test {
         }
     }
 
-    async fn check_rate_limited_and_update_returns_true_if_no_limits_apply(
-        rate_limiter: &mut TestsLimiter,
-    ) {
+    async fn check_rate_limited_and_update_reports_when_to_retry(rate_limiter: &mut TestsLimiter) {
         let namespace = "test_namespace";
+        let per_minute = Limit::new(namespace, 1, 60, Vec::<String>::new(), vec!["app_id"]);
+        let per_second = Limit::new(namespace, 10, 1, Vec::<String>::new(), vec!["app_id"]);
+        rate_limiter.add_limit(&per_minute).await;
+        rate_limiter.add_limit(&per_second).await;
 
-        let limit = Limit::new(
-            namespace,
-            10,
-            60,
-            vec!["req.method == 'GET'"],
-            vec!["app_id"],
-        );
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "test_app_id".to_string());
 
-        rate_limiter.add_limit(&limit).await;
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &values, 1, true)
+            .await
+            .unwrap();
+        assert!(!result.limited);
+        assert_eq!(result.retry_after(), None);
+
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &values, 1, true)
+            .await
+            .unwrap();
+        assert!(result.limited);
+        // Only the counter over its limit, the one per minute, has to reset
+        if let Some(retry_after) = result.retry_after() {
+            assert!(retry_after > Duration::from_secs(1));
+            assert!(retry_after <= Duration::from_secs(60));
+        }
+    }
+
+    async fn check_rate_limited_and_update_reports_the_limit_of_the_highest_priority(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let mut per_app = Limit::new(namespace, 5, 60, Vec::<String>::new(), vec!["app_id"]);
+        per_app.set_name("per_app".to_string());
+        per_app.set_priority(1);
+        let mut global = Limit::new(namespace, 2, 60, Vec::<String>::new(), Vec::<String>::new());
+        global.set_name("global".to_string());
+        rate_limiter.add_limit(&per_app).await;
+        rate_limiter.add_limit(&global).await;
 
         let mut values: HashMap<String, String> = HashMap::new();
         values.insert("app_id".to_string(), "test_app_id".to_string());
-        // Does not match the limit defined
-        values.insert("req.method".to_string(), "POST".to_string());
 
-        assert!(
-            !rate_limiter
-                .check_rate_limited_and_update(namespace, &values, 1, false)
+        // Even though fewer hits are left to the global limit
+        for remaining in [4, 3] {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, true)
                 .await
-                .unwrap()
-                .limited
-        );
+                .unwrap();
+            assert!(!result.limited);
+            let counter = result.most_restrictive_counter().unwrap();
+            assert_eq!(counter.limit().name(), Some("per_app"));
+            assert_eq!(result.remaining(), Some(remaining));
+        }
+
+        // Only the global limit is over its max value
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &values, 1, true)
+            .await
+            .unwrap();
+        assert!(result.limited);
+        let counter = result.most_restrictive_counter().unwrap();
+        assert_eq!(counter.limit().name(), Some("global"));
+
+        // Both are, in a namespace where the global limit comes first
+        let namespace = "other_namespace";
+        let mut per_app = Limit::new(namespace, 1, 60, Vec::<String>::new(), vec!["app_id"]);
+        per_app.set_name("per_app".to_string());
+        let mut global = Limit::new(namespace, 1, 60, Vec::<String>::new(), Vec::<String>::new());
+        global.set_name("global".to_string());
+        global.set_priority(1);
+        rate_limiter.add_limit(&per_app).await;
+        rate_limiter.add_limit(&global).await;
+
+        for limited in [false, true] {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, true)
+                .await
+                .unwrap();
+            assert_eq!(result.limited, limited);
+            let counter = result.most_restrictive_counter().unwrap();
+            assert_eq!(counter.limit().name(), Some("global"));
+        }
     }
 
-    async fn check_rate_limited_and_update_applies_limit_if_its_unconditional(
+    async fn check_rate_limited_and_update_looks_up_the_max_value_of_a_request(
         rate_limiter: &mut TestsLimiter,
     ) {
         let namespace = "test_namespace";
+        let mut limit = Limit::new(namespace, 1, 60, Vec::<String>::new(), vec!["user_id"]);
+        limit.set_max_values(Some(MaxValues::new(
+            "plan",
+            HashMap::from([("free".to_string(), 2), ("pro".to_string(), 5)]),
+        )));
+        rate_limiter.add_limit(&limit).await;
 
-        let limit = Limit::new(
-            namespace,
-            0, // So reporting 1 more would not be allowed
-            60,
-            Vec::<String>::new(), // unconditional
-            vec!["app_id"],
-        );
+        for (plan, max_value) in [
+            (Some("free"), 2),
+            (Some("pro"), 5),
+            (Some("other"), 1),
+            (None, 1),
+        ] {
+            let mut values = HashMap::from([(
+                "user_id".to_string(),
+                format!("user_of_{}", plan.unwrap_or("none")),
+            )]);
+            if let Some(plan) = plan {
+                values.insert("plan".to_string(), plan.to_string());
+            }
+            for _ in 0..max_value {
+                assert!(
+                    !rate_limiter
+                        .check_rate_limited_and_update(namespace, &values, 1, false)
+                        .await
+                        .unwrap()
+                        .limited
+                );
+            }
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, true)
+                .await
+                .unwrap();
+            assert!(result.limited);
+            assert_eq!(
+                result.most_restrictive_counter().unwrap().max_value(),
+                max_value
+            );
+        }
+    }
 
+    async fn check_rate_limited_and_update_weighs_the_hits_by_their_cost(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let mut limit = Limit::new(namespace, 20, 60, Vec::<String>::new(), vec!["app_id"]);
+        limit.set_cost(Some(Cost::new(
+            "endpoint",
+            HashMap::from([("bulk".to_string(), 10), ("health".to_string(), 0)]),
+            1,
+        )));
         rate_limiter.add_limit(&limit).await;
 
         let mut values: HashMap<String, String> = HashMap::new();
         values.insert("app_id".to_string(), "test_app_id".to_string());
+        let mut bulk = values.clone();
+        bulk.insert("endpoint".to_string(), "bulk".to_string());
+        let mut health = values.clone();
+        health.insert("endpoint".to_string(), "health".to_string());
 
-        assert!(
-            rate_limiter
-                .check_rate_limited_and_update(namespace, &values, 1, false)
+        for (values, delta, remaining) in [(&bulk, 1, 10), (&values, 2, 8), (&health, 5, 8)] {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, values, delta, true)
                 .await
-                .unwrap()
-                .limited
-        );
-    }
-
-    async fn get_counters(rate_limiter: &mut TestsLimiter) {
-        let namespace = "test_namespace";
-        let max_hits = 10;
-        let hits_app_1 = 1;
-        let hits_app_2 = 5;
-
-        let limit = Limit::new(
-            namespace,
-            max_hits,
-            60,
-            vec!["req.method == 'GET'"],
-            vec!["app_id"],
-        );
+                .unwrap();
+            assert!(!result.limited);
+            assert_eq!(result.remaining(), Some(remaining));
+        }
 
-        rate_limiter.add_limit(&limit).await;
+        assert!(rate_limiter
+            .is_rate_limited(namespace, &bulk, 1)
+            .await
+            .unwrap());
+        assert!(!rate_limiter
+            .is_rate_limited(namespace, &values, 8)
+            .await
+            .unwrap());
 
-        let mut values = HashMap::new();
-        values.insert("req.method".to_string(), "GET".to_string());
-        values.insert("app_id".to_string(), "1".to_string());
-        rate_limiter
-            .update_counters(namespace, &values, hits_app_1)
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &bulk, 1, false)
             .await
             .unwrap();
+        assert!(result.limited);
 
-        values.insert("app_id".to_string(), "2".to_string());
         rate_limiter
-            .update_counters(namespace, &values, hits_app_2)
+            .update_counters(namespace, &values, 8)
             .await
             .unwrap();
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &values, 1, false)
+            .await
+            .unwrap();
+        assert!(result.limited);
+    }
 
-        assert_eq!(rate_limiter.get_limits(namespace).await.len(), 1);
-
-        let counters = rate_limiter.get_counters(namespace).await.unwrap();
-
-        assert_eq!(counters.len(), 2);
+    async fn check_rate_limited_and_update_spreads_the_resets_by_the_jitter(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let mut limit = Limit::new(namespace, 10, 60, Vec::<String>::new(), vec!["app_id"]);
+        limit.set_jitter(Some(30));
+        rate_limiter.add_limit(&limit).await;
 
-        for counter in counters {
-            let app_id = counter.set_variables().get("app_id").unwrap();
-            let remaining = counter.remaining().unwrap();
+        let mut windows = Vec::new();
+        for app_id in ["1", "2", "3"] {
+            let values = HashMap::from([("app_id".to_string(), app_id.to_string())]);
+            let window = Counter::new(limit.clone(), values.clone()).window_at(SystemTime::now());
+            assert!(window >= Duration::from_secs(60));
+            assert!(window <= Duration::from_secs(90));
 
-            match app_id.as_str() {
-                "1" => assert_eq!(remaining, max_hits - hits_app_1),
-                "2" => assert_eq!(remaining, max_hits - hits_app_2),
-                _ => panic!("Unexpected app ID"),
-            }
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, true)
+                .await
+                .unwrap();
+            let expires_in = result.counters[0].expires_in().unwrap();
+            assert!(expires_in <= window);
+            assert!(expires_in + Duration::from_secs(2) > window);
+            windows.push(window);
         }
-    }
 
-    async fn get_counters_returns_empty_when_no_limits_in_namespace(
-        rate_limiter: &mut TestsLimiter,
-    ) {
-        assert!(rate_limiter
-            .get_counters("test_namespace")
-            .await
-            .unwrap()
-            .is_empty())
+        // Each counter is pushed back by an offset of its own
+        windows.sort();
+        windows.dedup_by(|later, earlier| *later - *earlier < Duration::from_secs(2));
+        assert_eq!(windows.len(), 3);
     }
 
-    async fn get_counters_returns_empty_when_no_counters_in_namespace(
+    async fn check_rate_limited_and_update_counts_in_shared_counters(
         rate_limiter: &mut TestsLimiter,
     ) {
-        // There's a limit, but no counters. The result should be empty.
-
-        let limit = Limit::new(
-            "test_namespace",
-            10,
+        let namespace = "test_namespace";
+        let mut reads = Limit::new(
+            namespace,
+            5,
             60,
             vec!["req.method == 'GET'"],
-            vec!["app_id"],
+            vec!["tenant"],
         );
-
-        rate_limiter.add_limit(&limit).await;
-
-        assert!(rate_limiter
-            .get_counters("test_namespace")
-            .await
-            .unwrap()
-            .is_empty())
-    }
-
-    async fn get_counters_does_not_return_expired_ones(rate_limiter: &mut TestsLimiter) {
-        let namespace = "test_namespace";
-        let limit_time = 1;
-
-        let limit = Limit::new(
+        reads.set_shared_counter(Some("budget".to_string()));
+        let mut writes = Limit::new(
             namespace,
-            10,
-            limit_time,
-            vec!["req.method == 'GET'"],
-            vec!["app_id"],
+            3,
+            60,
+            vec!["req.method == 'POST'"],
+            vec!["tenant"],
         );
+        writes.set_shared_counter(Some("budget".to_string()));
+        rate_limiter.add_limit(&reads).await;
+        rate_limiter.add_limit(&writes).await;
+
+        let mut get_values: HashMap<String, String> = HashMap::new();
+        get_values.insert("req.method".to_string(), "GET".to_string());
+        get_values.insert("tenant".to_string(), "a_tenant".to_string());
+        let mut post_values = get_values.clone();
+        post_values.insert("req.method".to_string(), "POST".to_string());
+
+        // Each limit checks the hits of both against its own max value
+        for (values, delta, limited, remaining) in [
+            (&get_values, 2, false, 3),
+            (&post_values, 1, false, 0),
+            (&post_values, 1, true, -1),
+            (&get_values, 2, false, 0),
+            (&get_values, 1, true, -1),
+        ] {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, values, delta, true)
+                .await
+                .unwrap();
+            assert_eq!(result.limited, limited);
+            assert_eq!(result.remaining(), Some(remaining));
+        }
 
-        rate_limiter.add_limit(&limit).await;
+        assert_eq!(rate_limiter.get_counters(namespace).await.unwrap().len(), 1);
+    }
 
-        let mut values = HashMap::new();
-        values.insert("req.method".to_string(), "GET".to_string());
-        values.insert("app_id".to_string(), "1".to_string());
-        rate_limiter
-            .update_counters(namespace, &values, 1)
-            .await
-            .unwrap();
+    async fn check_rate_limited_and_update_counts_without_the_optional_variables_missing(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let mut limit = Limit::new::<_, &str>(namespace, 2, 60, vec![], vec!["app_id"]);
+        limit.set_optional_variables(["region"]);
+        rate_limiter.add_limit(&limit).await;
 
-        // Give it some extra time to expire
-        sleep(Duration::from_secs(limit_time + 1));
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "an_app".to_string());
+        let mut eu_values = values.clone();
+        eu_values.insert("region".to_string(), "eu".to_string());
+
+        // The requests without a region count apart from those of a region
+        for (values, limited) in [
+            (&values, false),
+            (&values, false),
+            (&values, true),
+            (&eu_values, false),
+            (&eu_values, false),
+            (&eu_values, true),
+        ] {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, values, 1, false)
+                .await
+                .unwrap();
+            assert_eq!(result.limited, limited);
+        }
 
-        assert_eq!(rate_limiter.get_counters(namespace).await.unwrap().len(), 0);
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+        assert_eq!(counters.len(), 2);
+        assert!(counters
+            .iter()
+            .any(|counter| !counter.set_variables().contains_key("region")));
     }
 
-    async fn configure_with_creates_the_given_limits(rate_limiter: &mut TestsLimiter) {
-        let first_limit = Limit::new(
-            "first_namespace",
-            10,
+    async fn check_rate_limited_and_update_falls_back_when_no_limit_applies(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let reads = Limit::new(
+            namespace,
+            2,
             60,
             vec!["req.method == 'GET'"],
             vec!["app_id"],
         );
+        let mut fallback = Limit::new::<_, &str>(namespace, 1, 60, vec![], vec!["app_id"]);
+        fallback.set_fallback(true);
+        rate_limiter.add_limit(&reads).await;
+        rate_limiter.add_limit(&fallback).await;
+
+        let mut get_values: HashMap<String, String> = HashMap::new();
+        get_values.insert("req.method".to_string(), "GET".to_string());
+        get_values.insert("app_id".to_string(), "an_app".to_string());
+        let mut post_values = get_values.clone();
+        post_values.insert("req.method".to_string(), "POST".to_string());
+
+        // The fallback limit doesn't apply to the requests the other one does
+        for (values, limited) in [
+            (&post_values, false),
+            (&post_values, true),
+            (&get_values, false),
+            (&get_values, false),
+            (&get_values, true),
+        ] {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, values, 1, false)
+                .await
+                .unwrap();
+            assert_eq!(result.limited, limited);
+        }
 
-        let second_limit = Limit::new(
-            "second_namespace",
-            20,
+        assert_eq!(rate_limiter.get_counters(namespace).await.unwrap().len(), 2);
+    }
+
+    async fn check_rate_limited_and_update_counts_once_in_a_counter_shared_by_the_limits_that_apply(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let mut per_app = Limit::new(namespace, 10, 60, Vec::<String>::new(), vec!["app_id"]);
+        per_app.set_shared_counter(Some("budget".to_string()));
+        let mut per_app_on_get = Limit::new(
+            namespace,
+            2,
             60,
             vec!["req.method == 'GET'"],
             vec!["app_id"],
         );
+        per_app_on_get.set_shared_counter(Some("budget".to_string()));
+        rate_limiter.add_limit(&per_app).await;
+        rate_limiter.add_limit(&per_app_on_get).await;
 
-        rate_limiter
-            .configure_with(vec![first_limit.clone(), second_limit.clone()])
-            .await
-            .unwrap();
-
-        assert!(rate_limiter
-            .get_limits("first_namespace")
-            .await
-            .contains(&first_limit));
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("req.method".to_string(), "GET".to_string());
+        values.insert("app_id".to_string(), "test_app_id".to_string());
 
-        assert!(rate_limiter
-            .get_limits("second_namespace")
-            .await
-            .contains(&second_limit));
+        // Against the lowest of the max values
+        for limited in [false, false, true] {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap();
+            assert_eq!(result.limited, limited);
+        }
     }
 
-    async fn configure_with_keeps_the_given_limits_and_counters_if_they_exist(
+    async fn check_rate_limited_and_update_returns_true_if_no_limits_apply(
         rate_limiter: &mut TestsLimiter,
     ) {
         let namespace = "test_namespace";
-        let max_value = 10;
-        let hits_to_report = 1;
 
         let limit = Limit::new(
             namespace,
-            max_value,
+            10,
             60,
             vec!["req.method == 'GET'"],
             vec!["app_id"],
@@ -918,135 +1299,1544 @@ mod test {
 
         rate_limiter.add_limit(&limit).await;
 
-        let mut values = HashMap::new();
-        values.insert("req.method".to_string(), "GET".to_string());
-        values.insert("app_id".to_string(), "1".to_string());
-        rate_limiter
-            .update_counters(namespace, &values, hits_to_report)
-            .await
-            .unwrap();
-
-        rate_limiter
-            .configure_with(vec![limit.clone()])
-            .await
-            .unwrap();
-
-        assert!(rate_limiter.get_limits(namespace).await.contains(&limit));
-
-        let counters: Vec<Counter> = rate_limiter
-            .get_counters(namespace)
-            .await
-            .unwrap()
-            .drain()
-            .collect();
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+        // Does not match the limit defined
+        values.insert("req.method".to_string(), "POST".to_string());
 
-        assert_eq!(counters.len(), 1);
-        assert_eq!(counters[0].remaining().unwrap(), max_value - hits_to_report);
+        assert!(
+            !rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
     }
 
-    async fn configure_with_deletes_all_except_the_limits_given(rate_limiter: &mut TestsLimiter) {
+    async fn check_rate_limited_and_update_applies_limit_if_its_unconditional(
+        rate_limiter: &mut TestsLimiter,
+    ) {
         let namespace = "test_namespace";
 
-        let limit_to_be_kept = Limit::new(
-            namespace,
-            10,
-            1,
-            vec!["req.method == 'GET'"],
-            vec!["app_id"],
-        );
-
-        let limit_to_be_deleted = Limit::new(
+        let limit = Limit::new(
             namespace,
-            20,
+            0, // So reporting 1 more would not be allowed
             60,
-            vec!["req.method == 'GET'"],
+            Vec::<String>::new(), // unconditional
             vec!["app_id"],
         );
 
-        for limit in [&limit_to_be_kept, &limit_to_be_deleted].iter() {
-            rate_limiter.add_limit(limit).await;
-        }
-
-        rate_limiter
-            .configure_with(vec![limit_to_be_kept.clone()])
-            .await
-            .unwrap();
+        rate_limiter.add_limit(&limit).await;
 
-        let limits = rate_limiter.get_limits(namespace).await;
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "test_app_id".to_string());
 
-        assert!(limits.contains(&limit_to_be_kept));
-        assert!(!limits.contains(&limit_to_be_deleted));
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
     }
 
-    async fn configure_with_updates_the_limits(rate_limiter: &mut TestsLimiter) {
+    async fn check_rate_limited_and_update_matches_case_insensitive_conditions_whatever_the_case(
+        rate_limiter: &mut TestsLimiter,
+    ) {
         let namespace = "test_namespace";
-
-        let limit_orig = Limit::new(
-            namespace,
-            10,
-            60,
-            vec!["req.method == 'GET'"],
-            vec!["app_id"],
-        );
-
-        let limit_update = Limit::new(
+        let limit = Limit::new(
             namespace,
-            20,
+            3,
             60,
-            vec!["req.method == 'GET'"],
+            vec!["req.method == 'GET'i"],
             vec!["app_id"],
         );
+        rate_limiter.add_limit(&limit).await;
 
-        rate_limiter.add_limit(&limit_orig).await;
-
-        rate_limiter
-            .configure_with(vec![limit_update.clone()])
-            .await
-            .unwrap();
+        for method in ["GET", "get", "Get"] {
+            let values = HashMap::from([
+                ("req.method".to_string(), method.to_string()),
+                ("app_id".to_string(), "test_app_id".to_string()),
+            ]);
+            assert!(
+                !rate_limiter
+                    .check_rate_limited_and_update(namespace, &values, 1, false)
+                    .await
+                    .unwrap()
+                    .limited
+            );
+        }
 
-        let limits = rate_limiter.get_limits(namespace).await;
+        // All the cases of the method count in the same counter
+        let values = HashMap::from([
+            ("req.method".to_string(), "gEt".to_string()),
+            ("app_id".to_string(), "test_app_id".to_string()),
+        ]);
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
 
-        assert_eq!(limits.len(), 1);
-        assert_eq!(limits.iter().next().unwrap().max_value(), 20);
+        let values = HashMap::from([
+            ("req.method".to_string(), "POST".to_string()),
+            ("app_id".to_string(), "test_app_id".to_string()),
+        ]);
+        assert!(
+            !rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
     }
 
-    async fn add_limit_only_adds_if_not_present(rate_limiter: &mut TestsLimiter) {
+    async fn check_rate_limited_and_update_resets_windows_of_milliseconds(
+        rate_limiter: &mut TestsLimiter,
+    ) {
         let namespace = "test_namespace";
+        let mut limit = Limit::new(namespace, 2, 0, vec!["req.method == 'GET'"], vec!["app_id"]);
+        limit.set_milliseconds(Some(500));
+        rate_limiter.add_limit(&limit).await;
 
-        let limit_1 = Limit::new(
-            namespace,
-            10,
-            60,
-            vec!["req.method == 'GET'"],
-            vec!["app_id"],
+        let values = HashMap::from([
+            ("req.method".to_string(), "GET".to_string()),
+            ("app_id".to_string(), "test_app_id".to_string()),
+        ]);
+        for _ in 0..2 {
+            assert!(
+                !rate_limiter
+                    .check_rate_limited_and_update(namespace, &values, 1, false)
+                    .await
+                    .unwrap()
+                    .limited
+            );
+        }
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
         );
 
-        let limit_2 = Limit::new(
+        // The window is over well before a second is
+        sleep(Duration::from_millis(600));
+
+        assert!(
+            !rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+    }
+
+    async fn check_rate_limited_and_update_sums_the_shards_of_the_counters(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let max_hits = 10;
+        let mut limit = Limit::new(
             namespace,
-            20,
+            max_hits,
             60,
-            vec!["req.method == 'GET'"],
+            Vec::<String>::new(),
             vec!["app_id"],
         );
+        limit.set_shards(Some(4));
+        rate_limiter.add_limit(&limit).await;
 
-        let mut limit_3 = Limit::new(
+        let values = HashMap::from([("app_id".to_string(), "test_app_id".to_string())]);
+        // The hits are spread across the shards, but one at a time, they are
+        // all accounted for
+        for hit in 0..max_hits {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, true)
+                .await
+                .unwrap();
+            assert!(!result.limited);
+            assert_eq!(result.counters[0].remaining(), Some(max_hits - hit - 1));
+        }
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+        assert_eq!(counters.len(), 1);
+        let counter = counters.iter().next().unwrap();
+        assert_eq!(counter.remaining(), Some(0));
+        assert!(counter.expires_in().unwrap() <= Duration::from_secs(60));
+    }
+
+    async fn set_counter_replaces_the_hits_of_the_counter(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+        let limit = Limit::new(namespace, 10, 60, Vec::<String>::new(), vec!["app_id"]);
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values = HashMap::new();
+        values.insert("app_id".to_string(), "1".to_string());
+        rate_limiter
+            .update_counters(namespace, &values, 8)
+            .await
+            .unwrap();
+
+        let counter = Counter::new(limit.clone(), values.clone());
+        assert!(rate_limiter
+            .set_counter(&counter, 3, Duration::from_secs(30))
+            .await
+            .unwrap());
+        values.insert("app_id".to_string(), "2".to_string());
+        assert!(rate_limiter
+            .set_counter(
+                &Counter::new(limit.clone(), values.clone()),
+                0,
+                Duration::from_secs(30)
+            )
+            .await
+            .unwrap());
+
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+        assert_eq!(counters.len(), 2);
+        for counter in counters {
+            let expected = match counter.set_variables()["app_id"].as_str() {
+                "1" => 3,
+                _ => 0,
+            };
+            assert_eq!(counter.remaining(), Some(expected));
+            assert!(counter.expires_in().unwrap() <= Duration::from_secs(30));
+        }
+
+        assert!(rate_limiter
+            .is_rate_limited(namespace, &values, 1)
+            .await
+            .unwrap());
+        values.insert("app_id".to_string(), "1".to_string());
+        assert!(!rate_limiter
+            .is_rate_limited(namespace, &values, 3)
+            .await
+            .unwrap());
+        assert!(rate_limiter
+            .is_rate_limited(namespace, &values, 4)
+            .await
+            .unwrap());
+    }
+
+    async fn set_counter_only_sets_valid_counters_of_known_limits(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+        let limit = Limit::new(namespace, 10, 60, Vec::<String>::new(), vec!["app_id"]);
+        let mut values = HashMap::new();
+        values.insert("app_id".to_string(), "1".to_string());
+        let counter = Counter::new(limit.clone(), values);
+
+        assert!(!rate_limiter
+            .set_counter(&counter, 3, Duration::from_secs(30))
+            .await
+            .unwrap());
+
+        rate_limiter.add_limit(&limit).await;
+        for (remaining, expires_in) in [(3, 61), (11, 30)] {
+            assert!(matches!(
+                rate_limiter
+                    .set_counter(&counter, remaining, Duration::from_secs(expires_in))
+                    .await,
+                Err(LimitadorError::InvalidCounter(_))
+            ));
+        }
+        assert!(rate_limiter
+            .get_counters(namespace)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    async fn reset_counter_starts_the_counter_of_the_values_anew(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+        let other_namespace = "other_namespace";
+        for namespace in [namespace, other_namespace] {
+            let mut limit = Limit::new(namespace, 2, 60, Vec::<String>::new(), vec!["app_id"]);
+            limit.set_name("per_app".to_owned());
+            rate_limiter.add_limit(&limit).await;
+        }
+
+        let mut values = HashMap::new();
+        for app_id in ["1", "2"] {
+            values.insert("app_id".to_string(), app_id.to_string());
+            for namespace in [namespace, other_namespace] {
+                rate_limiter
+                    .update_counters(namespace, &values, 2)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        values.insert("app_id".to_string(), "1".to_string());
+        assert_eq!(
+            rate_limiter
+                .reset_counter(namespace, "per_app", &values)
+                .await
+                .unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            rate_limiter
+                .reset_counter(namespace, "per_app", &values)
+                .await
+                .unwrap(),
+            Some(false)
+        );
+        assert!(!rate_limiter
+            .is_rate_limited(namespace, &values, 2)
+            .await
+            .unwrap());
+        // Neither the counters of the other values, nor the ones of the other
+        // namespaces are reset
+        assert!(rate_limiter
+            .is_rate_limited(other_namespace, &values, 1)
+            .await
+            .unwrap());
+        values.insert("app_id".to_string(), "2".to_string());
+        assert!(rate_limiter
+            .is_rate_limited(namespace, &values, 1)
+            .await
+            .unwrap());
+
+        assert_eq!(
+            rate_limiter
+                .reset_counter(namespace, "per_user", &values)
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(matches!(
+            rate_limiter
+                .reset_counter(namespace, "per_app", &HashMap::new())
+                .await,
+            Err(LimitadorError::InvalidCounter(_))
+        ));
+    }
+
+    async fn get_counters(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+        let max_hits = 10;
+        let hits_app_1 = 1;
+        let hits_app_2 = 5;
+
+        let limit = Limit::new(
+            namespace,
+            max_hits,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values = HashMap::new();
+        values.insert("req.method".to_string(), "GET".to_string());
+        values.insert("app_id".to_string(), "1".to_string());
+        rate_limiter
+            .update_counters(namespace, &values, hits_app_1)
+            .await
+            .unwrap();
+
+        values.insert("app_id".to_string(), "2".to_string());
+        rate_limiter
+            .update_counters(namespace, &values, hits_app_2)
+            .await
+            .unwrap();
+
+        assert_eq!(rate_limiter.get_limits(namespace).await.len(), 1);
+
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+
+        assert_eq!(counters.len(), 2);
+
+        for counter in counters {
+            let app_id = counter.set_variables().get("app_id").unwrap();
+            let remaining = counter.remaining().unwrap();
+
+            match app_id.as_str() {
+                "1" => assert_eq!(remaining, max_hits - hits_app_1),
+                "2" => assert_eq!(remaining, max_hits - hits_app_2),
+                _ => panic!("Unexpected app ID"),
+            }
+        }
+    }
+
+    async fn get_counters_returns_empty_when_no_limits_in_namespace(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        assert!(rate_limiter
+            .get_counters("test_namespace")
+            .await
+            .unwrap()
+            .is_empty())
+    }
+
+    async fn get_counters_returns_empty_when_no_counters_in_namespace(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        // There's a limit, but no counters. The result should be empty.
+
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+
+        rate_limiter.add_limit(&limit).await;
+
+        assert!(rate_limiter
+            .get_counters("test_namespace")
+            .await
+            .unwrap()
+            .is_empty())
+    }
+
+    async fn get_counters_does_not_return_expired_ones(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+        let limit_time = 1;
+
+        let limit = Limit::new(
             namespace,
+            10,
+            limit_time,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values = HashMap::new();
+        values.insert("req.method".to_string(), "GET".to_string());
+        values.insert("app_id".to_string(), "1".to_string());
+        rate_limiter
+            .update_counters(namespace, &values, 1)
+            .await
+            .unwrap();
+
+        // Give it some extra time to expire
+        sleep(Duration::from_secs(limit_time + 1));
+
+        assert_eq!(rate_limiter.get_counters(namespace).await.unwrap().len(), 0);
+    }
+
+    async fn configure_with_creates_the_given_limits(rate_limiter: &mut TestsLimiter) {
+        let first_limit = Limit::new(
+            "first_namespace",
+            10,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+
+        let second_limit = Limit::new(
+            "second_namespace",
             20,
             60,
             vec!["req.method == 'GET'"],
             vec!["app_id"],
         );
-        limit_3.set_name("Name is irrelevant too".to_owned());
 
-        assert!(rate_limiter.add_limit(&limit_1).await);
-        assert!(!rate_limiter.add_limit(&limit_2).await);
-        assert!(!rate_limiter.add_limit(&limit_3).await);
+        rate_limiter
+            .configure_with(vec![first_limit.clone(), second_limit.clone()])
+            .await
+            .unwrap();
 
-        let limits = rate_limiter.get_limits(namespace).await;
+        assert!(rate_limiter
+            .get_limits("first_namespace")
+            .await
+            .contains(&first_limit));
 
-        assert_eq!(limits.len(), 1);
-        let known_limit = limits.iter().next().unwrap();
-        assert_eq!(known_limit.max_value(), 10);
-        assert_eq!(known_limit.name(), None);
+        assert!(rate_limiter
+            .get_limits("second_namespace")
+            .await
+            .contains(&second_limit));
+    }
+
+    async fn configure_with_keeps_the_given_limits_and_counters_if_they_exist(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let max_value = 10;
+        let hits_to_report = 1;
+
+        let limit = Limit::new(
+            namespace,
+            max_value,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values = HashMap::new();
+        values.insert("req.method".to_string(), "GET".to_string());
+        values.insert("app_id".to_string(), "1".to_string());
+        rate_limiter
+            .update_counters(namespace, &values, hits_to_report)
+            .await
+            .unwrap();
+
+        rate_limiter
+            .configure_with(vec![limit.clone()])
+            .await
+            .unwrap();
+
+        assert!(rate_limiter.get_limits(namespace).await.contains(&limit));
+
+        let counters: Vec<Counter> = rate_limiter
+            .get_counters(namespace)
+            .await
+            .unwrap()
+            .drain()
+            .collect();
+
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].remaining().unwrap(), max_value - hits_to_report);
+    }
+
+    async fn configure_with_deletes_all_except_the_limits_given(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+
+        let limit_to_be_kept = Limit::new(
+            namespace,
+            10,
+            1,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+
+        let limit_to_be_deleted = Limit::new(
+            namespace,
+            20,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+
+        for limit in [&limit_to_be_kept, &limit_to_be_deleted].iter() {
+            rate_limiter.add_limit(limit).await;
+        }
+
+        rate_limiter
+            .configure_with(vec![limit_to_be_kept.clone()])
+            .await
+            .unwrap();
+
+        let limits = rate_limiter.get_limits(namespace).await;
+
+        assert!(limits.contains(&limit_to_be_kept));
+        assert!(!limits.contains(&limit_to_be_deleted));
+    }
+
+    async fn configure_with_updates_the_limits(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+
+        let limit_orig = Limit::new(
+            namespace,
+            10,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+
+        let limit_update = Limit::new(
+            namespace,
+            20,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+
+        rate_limiter.add_limit(&limit_orig).await;
+
+        rate_limiter
+            .configure_with(vec![limit_update.clone()])
+            .await
+            .unwrap();
+
+        let limits = rate_limiter.get_limits(namespace).await;
+
+        assert_eq!(limits.len(), 1);
+        assert_eq!(limits.iter().next().unwrap().max_value(), 20);
+    }
+
+    async fn add_limit_only_adds_if_not_present(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+
+        let limit_1 = Limit::new(
+            namespace,
+            10,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+
+        let limit_2 = Limit::new(
+            namespace,
+            20,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+
+        let mut limit_3 = Limit::new(
+            namespace,
+            20,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+        limit_3.set_name("Name is irrelevant too".to_owned());
+
+        assert!(rate_limiter.add_limit(&limit_1).await);
+        assert!(!rate_limiter.add_limit(&limit_2).await);
+        assert!(!rate_limiter.add_limit(&limit_3).await);
+
+        let limits = rate_limiter.get_limits(namespace).await;
+
+        assert_eq!(limits.len(), 1);
+        let known_limit = limits.iter().next().unwrap();
+        assert_eq!(known_limit.max_value(), 10);
+        assert_eq!(known_limit.name(), None);
+    }
+
+    async fn add_limit_only_adds_if_its_name_is_not_taken(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+
+        let mut per_app = Limit::new(namespace, 10, 60, Vec::<String>::new(), vec!["app_id"]);
+        per_app.set_name("limit".to_owned());
+        let mut per_user = Limit::new(namespace, 10, 60, Vec::<String>::new(), vec!["user_id"]);
+        per_user.set_name("limit".to_owned());
+        let mut elsewhere = Limit::new(
+            "other_namespace",
+            10,
+            60,
+            Vec::<String>::new(),
+            vec!["app_id"],
+        );
+        elsewhere.set_name("limit".to_owned());
+        let unnamed = Limit::new(namespace, 10, 60, Vec::<String>::new(), vec!["user_id"]);
+
+        assert!(rate_limiter.add_limit(&per_app).await);
+        assert!(!rate_limiter.add_limit(&per_user).await);
+        assert!(rate_limiter.add_limit(&elsewhere).await);
+        assert!(rate_limiter.add_limit(&unnamed).await);
+
+        let limits = rate_limiter.get_limits(namespace).await;
+        assert_eq!(limits.len(), 2);
+        assert!(limits.contains(&per_app));
+        assert!(limits.contains(&unnamed));
+    }
+
+    async fn delete_limit_by_name_also_deletes_its_counters(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+
+        let mut named = Limit::new(namespace, 10, 60, Vec::<String>::new(), vec!["app_id"]);
+        named.set_name("per_app".to_owned());
+        let unnamed = Limit::new(
+            namespace,
+            10,
+            60,
+            Vec::<String>::new(),
+            Vec::<String>::new(),
+        );
+        rate_limiter.add_limit(&named).await;
+        rate_limiter.add_limit(&unnamed).await;
+
+        let mut values = HashMap::new();
+        values.insert("app_id".to_string(), "1".to_string());
+        rate_limiter
+            .update_counters(namespace, &values, 1)
+            .await
+            .unwrap();
+
+        assert!(rate_limiter
+            .delete_limit_by_name(namespace, "per_app")
+            .await
+            .unwrap());
+        assert!(!rate_limiter
+            .delete_limit_by_name(namespace, "per_app")
+            .await
+            .unwrap());
+
+        let limits = rate_limiter.get_limits(namespace).await;
+        assert_eq!(limits, HashSet::from([unnamed]));
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+        assert!(counters
+            .iter()
+            .all(|counter| counter.limit().name().is_none()));
+        assert_eq!(counters.len(), 1);
+    }
+
+    async fn disabled_limits_stop_enforcing_and_keep_their_counters(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let mut limit = Limit::new(
+            namespace,
+            2,
+            60,
+            vec!["req_method == 'GET'"],
+            vec!["app_id"],
+        );
+        limit.set_name("per_app".to_owned());
+        rate_limiter.add_limit(&limit).await;
+
+        let values = HashMap::from([
+            ("req_method".to_string(), "GET".to_string()),
+            ("app_id".to_string(), "1".to_string()),
+        ]);
+        for _ in 0..2 {
+            assert!(
+                !rate_limiter
+                    .check_rate_limited_and_update(namespace, &values, 1, false)
+                    .await
+                    .unwrap()
+                    .limited
+            );
+        }
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        // Neither limited nor counted once disabled
+        assert!(rate_limiter.set_limit_enabled_by_name(namespace, "per_app", false));
+        for _ in 0..3 {
+            assert!(
+                !rate_limiter
+                    .check_rate_limited_and_update(namespace, &values, 1, false)
+                    .await
+                    .unwrap()
+                    .limited
+            );
+        }
+        assert!(!rate_limiter
+            .get_limits(namespace)
+            .await
+            .iter()
+            .next()
+            .unwrap()
+            .is_enabled());
+
+        // Back to where it was, as the hits while disabled weren't counted
+        assert!(rate_limiter.set_limit_enabled_by_name(namespace, "per_app", true));
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters.iter().next().unwrap().remaining(), Some(0));
+
+        assert!(!rate_limiter.set_limit_enabled_by_name(namespace, "per_user", false));
+    }
+
+    async fn warm_up_leaves_the_limits_and_their_counters_alone(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+        let limit = Limit::new(
+            namespace,
+            1,
+            60,
+            vec!["req_method == 'GET'"],
+            vec!["app_id"],
+        );
+        rate_limiter.add_limit(&limit).await;
+
+        // Over and over, e.g. as retried until the storage is ready
+        for _ in 0..3 {
+            rate_limiter.warm_up().await.unwrap();
+        }
+
+        assert_eq!(
+            rate_limiter.get_limits(namespace).await,
+            HashSet::from([limit])
+        );
+        assert!(rate_limiter
+            .get_counters(namespace)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(rate_limiter
+            .get_limits("limitador.warm_up")
+            .await
+            .is_empty());
+    }
+
+    async fn shadow_limits_count_hits_but_never_limit(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+        let enforced = Limit::new(
+            namespace,
+            10,
+            120,
+            vec!["req_method == 'GET'"],
+            vec!["app_id"],
+        );
+        let mut shadow = Limit::new(
+            namespace,
+            1,
+            60,
+            vec!["req_method == 'GET'"],
+            vec!["app_id"],
+        );
+        shadow.set_shadow(true);
+        rate_limiter.add_limit(&enforced).await;
+        rate_limiter.add_limit(&shadow).await;
+
+        let values = HashMap::from([
+            ("req_method".to_string(), "GET".to_string()),
+            ("app_id".to_string(), "1".to_string()),
+        ]);
+        for _ in 0..3 {
+            assert!(
+                !rate_limiter
+                    .check_rate_limited_and_update(namespace, &values, 1, false)
+                    .await
+                    .unwrap()
+                    .limited
+            );
+        }
+        assert!(!rate_limiter
+            .is_rate_limited(namespace, &values, 1)
+            .await
+            .unwrap());
+
+        // Enforced, it limits on the hits it counted meanwhile
+        shadow.set_shadow(false);
+        rate_limiter
+            .configure_with(vec![enforced, shadow])
+            .await
+            .unwrap();
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+    }
+
+    async fn soft_limits_flag_the_requests_past_them_and_let_them_through(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+        let mut limit = Limit::new(
+            namespace,
+            4,
+            60,
+            vec!["req_method == 'GET'"],
+            vec!["app_id"],
+        );
+        limit.set_soft_max_value(Some(2));
+        rate_limiter.add_limit(&limit).await;
+
+        let values = HashMap::from([
+            ("req_method".to_string(), "GET".to_string()),
+            ("app_id".to_string(), "1".to_string()),
+        ]);
+        let mut outcomes = vec![];
+        for _ in 0..5 {
+            outcomes.push(
+                rate_limiter
+                    .check_rate_limited_and_update(namespace, &values, 1, false)
+                    .await
+                    .unwrap()
+                    .outcome(),
+            );
+        }
+        assert_eq!(
+            outcomes,
+            vec![
+                CheckOutcome::Ok,
+                CheckOutcome::Ok,
+                CheckOutcome::SoftLimited,
+                CheckOutcome::SoftLimited,
+                CheckOutcome::Limited,
+            ]
+        );
+    }
+
+    async fn wildcard_limits_apply_to_all_namespaces_counting_apart(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let global = Limit::new(
+            WILDCARD_NAMESPACE,
+            2,
+            60,
+            Vec::<String>::new(),
+            Vec::<String>::new(),
+        );
+        let own = Limit::new(
+            "test_namespace_1",
+            5,
+            60,
+            Vec::<String>::new(),
+            vec!["app_id"],
+        );
+        rate_limiter.add_limit(&global).await;
+        rate_limiter.add_limit(&own).await;
+
+        let values = HashMap::from([("app_id".to_string(), "1".to_string())]);
+        for namespace in ["test_namespace_1", "test_namespace_2"] {
+            for _ in 0..2 {
+                assert!(
+                    !rate_limiter
+                        .check_rate_limited_and_update(namespace, &values, 1, false)
+                        .await
+                        .unwrap()
+                        .limited
+                );
+            }
+            assert!(
+                rate_limiter
+                    .check_rate_limited_and_update(namespace, &values, 1, false)
+                    .await
+                    .unwrap()
+                    .limited
+            );
+        }
+
+        // Along with the limits of their own, under their namespace
+        let counters = rate_limiter.get_counters("test_namespace_1").await.unwrap();
+        assert_eq!(counters.len(), 2);
+        assert!(counters
+            .iter()
+            .all(|counter| counter.namespace().as_ref() == "test_namespace_1"));
+        let counters = rate_limiter.get_counters("test_namespace_2").await.unwrap();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters.iter().next().unwrap().remaining(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn sliding_window_limits_in_memory_storage() {
+        let rate_limiter = RateLimiter::new_with_storage(Box::<InMemoryStorage>::default());
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+        let max_hits = 3;
+
+        let mut limit = Limit::new(
+            namespace,
+            max_hits,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+        limit.set_window_type(WindowType::Sliding);
+
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("req.method".to_string(), "GET".to_string());
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+
+        for hit in 0..max_hits {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, true)
+                .await
+                .unwrap();
+            assert!(!result.limited);
+            assert_eq!(result.counters[0].remaining(), Some(max_hits - hit - 1));
+        }
+
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters.iter().next().unwrap().remaining(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn sliding_window_of_buckets_limits_in_memory_storage() {
+        let rate_limiter = RateLimiter::new_with_storage(Box::<InMemoryStorage>::default());
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+        let max_hits = 3;
+
+        let mut limit = Limit::new(
+            namespace,
+            max_hits,
+            60,
+            Vec::<String>::new(),
+            vec!["app_id"],
+        );
+        limit.set_window_type(WindowType::Sliding);
+        limit.set_sliding_buckets(Some(6));
+
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+
+        for hit in 0..max_hits {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, true)
+                .await
+                .unwrap();
+            assert!(!result.limited);
+            assert_eq!(result.counters[0].remaining(), Some(max_hits - hit - 1));
+            // the window slides a bucket of 10 seconds at a time
+            assert!(result.counters[0].expires_in().unwrap() <= Duration::from_secs(10));
+        }
+
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+    }
+
+    #[tokio::test]
+    async fn token_bucket_limits_in_memory_storage() {
+        let clock = ManualClock::default();
+        let rate_limiter = RateLimiter::new_with_storage(Box::new(
+            InMemoryStorage::default().with_clock(clock.clone()),
+        ));
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+
+        let mut limit = Limit::new(namespace, 2, 60, Vec::<String>::new(), vec!["app_id"]);
+        limit.set_window_type(WindowType::TokenBucket);
+        limit.set_refill_rate(Some(100));
+
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+
+        // The whole capacity can be used in a burst
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &values, 2, true)
+            .await
+            .unwrap();
+        assert!(!result.limited);
+        assert_eq!(result.counters[0].remaining(), Some(0));
+
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 2, false)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        // 100 tokens per second refill the bucket in 20ms
+        clock.advance(Duration::from_millis(20));
+
+        assert!(
+            !rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 2, false)
+                .await
+                .unwrap()
+                .limited
+        );
+    }
+
+    #[tokio::test]
+    async fn nested_windows_limit_on_the_level_a_request_is_over_in_memory_storage() {
+        let clock = ManualClock::default();
+        let rate_limiter = RateLimiter::new_with_storage(Box::new(
+            InMemoryStorage::default().with_clock(clock.clone()),
+        ));
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+
+        let mut limit = Limit::new(namespace, 0, 0, Vec::<String>::new(), vec!["app_id"]);
+        limit.set_name("api".to_string());
+        limit.set_levels(vec![
+            WindowLevel::new("minute", 60, 2),
+            WindowLevel::new("hour", 3600, 3),
+            WindowLevel::new("day", 86400, 4),
+        ]);
+        for level in limit.into_levels() {
+            rate_limiter.add_limit(&level).await;
+        }
+
+        let values = HashMap::from([("app_id".to_string(), "test_app_id".to_string())]);
+        let check = || async {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, true)
+                .await
+                .unwrap();
+            result.limited.then(|| {
+                let counter = result.most_restrictive_counter().unwrap();
+                counter.limit().name().unwrap().to_string()
+            })
+        };
+
+        assert_eq!(check().await, None);
+        assert_eq!(check().await, None);
+        assert_eq!(check().await.as_deref(), Some("minute"));
+
+        // A minute later, the hour is the tightest level
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(check().await, None);
+        assert_eq!(check().await.as_deref(), Some("hour"));
+
+        // Then the day is, none of the limited requests having counted
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(check().await, None);
+        assert_eq!(check().await.as_deref(), Some("day"));
+
+        clock.advance(Duration::from_secs(86400));
+        assert_eq!(check().await, None);
+    }
+
+    #[tokio::test]
+    async fn leaky_bucket_limits_in_memory_storage() {
+        let clock = ManualClock::default();
+        let rate_limiter = RateLimiter::new_with_storage(Box::new(
+            InMemoryStorage::default().with_clock(clock.clone()),
+        ));
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+
+        let mut limit = Limit::new(namespace, 5, 60, Vec::<String>::new(), vec!["app_id"]);
+        limit.set_window_type(WindowType::LeakyBucket);
+        limit.set_leak_rate(Some(20));
+
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+
+        // A burst is only let in for as long as the bucket has room for it
+        let mut admitted = 0;
+        for _ in 0..10 {
+            if !rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+            {
+                admitted += 1;
+            }
+        }
+        assert_eq!(admitted, 5);
+
+        // 20 hits per second leak 2 of them in 100ms, making room for as many
+        clock.advance(Duration::from_millis(100));
+
+        let mut admitted = 0;
+        for _ in 0..10 {
+            if !rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+            {
+                admitted += 1;
+            }
+        }
+        assert_eq!(admitted, 2);
+    }
+
+    #[tokio::test]
+    async fn distinct_limits_in_memory_storage() {
+        let rate_limiter = RateLimiter::new_with_storage(Box::<InMemoryStorage>::default());
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+
+        let mut limit = Limit::new(namespace, 3, 3600, Vec::<String>::new(), vec!["tenant"]);
+        limit.set_window_type(WindowType::Distinct);
+        limit.set_distinct_variable(Some("source.address".to_string()));
+
+        rate_limiter.add_limit(&limit).await;
+
+        let values_of = |address: &str| {
+            HashMap::from([
+                ("tenant".to_string(), "test_tenant".to_string()),
+                ("source.address".to_string(), address.to_string()),
+            ])
+        };
+
+        // The addresses seen already don't count again
+        for (address, remaining) in [("10.0.0.1", 2), ("10.0.0.2", 1), ("10.0.0.1", 1)] {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values_of(address), 1, true)
+                .await
+                .unwrap();
+            assert!(!result.limited);
+            assert_eq!(result.counters[0].remaining(), Some(remaining));
+        }
+        assert!(
+            !rate_limiter
+                .check_rate_limited_and_update(namespace, &values_of("10.0.0.3"), 5, false)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        // Once as many as the max value were seen, only those get through
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values_of("10.0.0.4"), 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+        assert!(
+            !rate_limiter
+                .check_rate_limited_and_update(namespace, &values_of("10.0.0.2"), 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters.iter().next().unwrap().remaining(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn sliding_window_limits_are_rejected_by_disk_storage() {
+        let dir = TempDir::new().expect("We should have a dir!");
+        let rate_limiter = RateLimiter::new_with_storage(Box::new(
+            DiskStorage::open(dir.path(), OptimizeFor::Throughput).expect("Couldn't open temp dir"),
+        ));
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+
+        let mut limit = Limit::new(namespace, 3, 60, Vec::<String>::new(), vec!["app_id"]);
+        limit.set_window_type(WindowType::Sliding);
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+
+        assert!(rate_limiter
+            .check_rate_limited_and_update(namespace, &values, 1, false)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn calendar_window_counters_expire_at_the_end_of_the_window_in_memory_storage() {
+        let rate_limiter = RateLimiter::new_with_storage(Box::<InMemoryStorage>::default());
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+
+        let calendar_window = CalendarWindow::new(CalendarPeriod::Daily, Tz::Asia__Kolkata);
+        let mut limit = Limit::new(namespace, 3, 86400, Vec::<String>::new(), vec!["app_id"]);
+        limit.set_calendar_window(Some(calendar_window));
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+
+        let before = SystemTime::now();
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &values, 1, true)
+            .await
+            .unwrap();
+        let after = SystemTime::now();
+        assert!(!result.limited);
+
+        let expires_in = result.counters[0].expires_in().unwrap();
+        let end = calendar_window.ends_at(after);
+        assert!(expires_in <= end.duration_since(before).unwrap());
+        assert!(expires_in >= end.duration_since(after).unwrap());
+    }
+
+    #[tokio::test]
+    async fn calendar_window_limits_are_rejected_by_disk_storage() {
+        let dir = TempDir::new().expect("We should have a dir!");
+        let rate_limiter = RateLimiter::new_with_storage(Box::new(
+            DiskStorage::open(dir.path(), OptimizeFor::Throughput).expect("Couldn't open temp dir"),
+        ));
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+
+        let mut limit = Limit::new(namespace, 3, 2678400, Vec::<String>::new(), vec!["app_id"]);
+        limit.set_calendar_window(Some(CalendarWindow::new(CalendarPeriod::Monthly, Tz::UTC)));
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+
+        assert!(rate_limiter
+            .check_rate_limited_and_update(namespace, &values, 1, false)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn methods_limits_in_memory_storage() {
+        let rate_limiter = RateLimiter::new_with_storage(Box::<InMemoryStorage>::default());
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+
+        let mut limit = Limit::new(namespace, 2, 60, Vec::<String>::new(), vec!["app_id"]);
+        limit.set_methods(vec![HttpMethod::Post, HttpMethod::Put]);
+        rate_limiter.add_limit(&limit).await;
+
+        let values = |method: &str| {
+            HashMap::from([
+                ("app_id".to_string(), "test_app_id".to_string()),
+                ("req.method".to_string(), method.to_string()),
+            ])
+        };
+
+        // Both methods count in the same counter
+        for method in ["POST", "PUT"] {
+            assert!(
+                !rate_limiter
+                    .check_rate_limited_and_update(namespace, &values(method), 1, false)
+                    .await
+                    .unwrap()
+                    .limited
+            );
+        }
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values("POST"), 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+        // The other methods aren't limited
+        assert!(
+            !rate_limiter
+                .check_rate_limited_and_update(namespace, &values("GET"), 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+    }
+
+    #[tokio::test]
+    async fn methods_limits_are_rejected_by_disk_storage() {
+        let dir = TempDir::new().expect("We should have a dir!");
+        let rate_limiter = RateLimiter::new_with_storage(Box::new(
+            DiskStorage::open(dir.path(), OptimizeFor::Throughput).expect("Couldn't open temp dir"),
+        ));
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+
+        let mut limit = Limit::new(namespace, 3, 60, Vec::<String>::new(), vec!["app_id"]);
+        limit.set_methods(vec![HttpMethod::Get]);
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+        values.insert("req.method".to_string(), "GET".to_string());
+
+        assert!(rate_limiter
+            .check_rate_limited_and_update(namespace, &values, 1, false)
+            .await
+            .is_err());
+    }
+
+    struct SettableClock(Arc<Mutex<SystemTime>>);
+    impl Clock for SettableClock {
+        fn get_current_time(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn monthly_limits_reset_at_the_month_rollover_with_wasm_storage() {
+        // 2024-01-31T22:59:59Z, a second to February in Madrid
+        let now = Arc::new(Mutex::new(UNIX_EPOCH + Duration::from_secs(1706741999)));
+        let rate_limiter = RateLimiter::new_with_storage(Box::new(WasmStorage::new(Box::new(
+            SettableClock(now.clone()),
+        ))));
+        let rate_limiter = TestsLimiter::new_from_blocking_impl(rate_limiter);
+        let namespace = "test_namespace";
+
+        let mut limit = Limit::new(namespace, 2, 2678400, Vec::<String>::new(), vec!["app_id"]);
+        limit.set_calendar_window(Some(CalendarWindow::new(
+            CalendarPeriod::Monthly,
+            Tz::Europe__Madrid,
+        )));
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &values, 2, true)
+            .await
+            .unwrap();
+        assert!(!result.limited);
+        assert_eq!(
+            result.counters[0].expires_in(),
+            Some(Duration::from_secs(1))
+        );
+
+        *now.lock().unwrap() += Duration::from_millis(999);
+        assert!(
+            rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        // February starts, even though it's still January in UTC
+        *now.lock().unwrap() += Duration::from_millis(1);
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &values, 2, true)
+            .await
+            .unwrap();
+        assert!(!result.limited);
+        // 29 days of February 2024, in Madrid
+        assert_eq!(
+            result.counters[0].expires_in(),
+            Some(Duration::from_secs(29 * 86400))
+        );
+    }
+
+    #[cfg(feature = "redis_storage")]
+    #[tokio::test]
+    #[serial]
+    async fn limiters_with_different_key_prefixes_dont_interfere_with_redis() {
+        let staging = RedisStorage::default().with_key_prefix("staging:");
+        let production = AsyncRedisStorage::new("redis://127.0.0.1:6379")
+            .await
+            .expect("We need a Redis running locally")
+            .with_key_prefix("production:");
+        staging.clear().unwrap();
+        production.clear().await.unwrap();
+        let staging =
+            TestsLimiter::new_from_blocking_impl(RateLimiter::new_with_storage(Box::new(staging)));
+        let production = TestsLimiter::new_from_async_impl(AsyncRateLimiter::new_with_storage(
+            Box::new(production),
+        ));
+
+        let namespace = "test_namespace";
+        let limit = Limit::new(
+            namespace,
+            1,
+            60,
+            vec!["req.method == 'GET'"],
+            vec!["app_id"],
+        );
+        staging.add_limit(&limit).await;
+        production.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("req.method".to_string(), "GET".to_string());
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+
+        for rate_limiter in [&staging, &production] {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap();
+            assert!(!result.limited);
+        }
+        assert!(
+            staging
+                .check_rate_limited_and_update(namespace, &values, 1, false)
+                .await
+                .unwrap()
+                .limited
+        );
+
+        // Clearing one leaves the counters of the other alone
+        RedisStorage::default()
+            .with_key_prefix("staging:")
+            .clear()
+            .unwrap();
+        assert!(staging.get_counters(namespace).await.unwrap().is_empty());
+        let counters = production.get_counters(namespace).await.unwrap();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters.iter().next().unwrap().remaining(), Some(0));
+    }
+
+    #[cfg(feature = "redis_storage")]
+    #[tokio::test]
+    #[serial]
+    async fn only_the_pure_reads_go_to_the_read_replica_with_redis() {
+        // Another DB stands in for a replica that never catches up, so that
+        // what each call reads tells the connection that served it
+        let primary = AsyncRedisStorage::new("redis://127.0.0.1:6379/0")
+            .await
+            .expect("We need a Redis running locally");
+        let replica = AsyncRedisStorage::new("redis://127.0.0.1:6379/1")
+            .await
+            .expect("We need a Redis running locally");
+        primary.clear().await.unwrap();
+        replica.clear().await.unwrap();
+        let storage = AsyncRedisStorage::new("redis://127.0.0.1:6379/0")
+            .await
+            .unwrap()
+            .with_read_replica("redis://127.0.0.1:6379/1")
+            .await
+            .unwrap();
+
+        let limit = Limit::new("test_namespace", 1, 60, vec!["x == '1'"], vec!["app_id"]);
+        let limits = HashSet::from([limit.clone()]);
+        let counter = Counter::new(
+            limit,
+            HashMap::from([("app_id".to_string(), "test_app_id".to_string())]),
+        );
+
+        // The updates go to the primary, which the reads don't see
+        storage.update_counter(&counter, 1).await.unwrap();
+        assert!(storage.is_within_limits(&counter, 1).await.unwrap());
+        assert!(storage
+            .get_counters(limits.clone())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(primary.get_counters(limits.clone()).await.unwrap().len(), 1);
+        assert!(replica
+            .get_counters(limits.clone())
+            .await
+            .unwrap()
+            .is_empty());
+
+        // The checks that update the counters are made on the primary
+        assert!(matches!(
+            storage
+                .check_and_update(&mut vec![counter.clone()], 1, false)
+                .await
+                .unwrap(),
+            Authorization::Limited(_)
+        ));
+
+        // The reads see whatever the replica holds
+        replica.update_counter(&counter, 1).await.unwrap();
+        assert!(!storage.is_within_limits(&counter, 1).await.unwrap());
+        let counters = storage.get_counters(limits.clone()).await.unwrap();
+        assert_eq!(counters.iter().next().unwrap().remaining(), Some(0));
+
+        // The deletes go to the primary, leaving the replica alone
+        assert!(storage.delete_counter(&counter).await.unwrap());
+        assert!(primary
+            .get_counters(limits.clone())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(replica.get_counters(limits).await.unwrap().len(), 1);
+
+        replica.clear().await.unwrap();
     }
 }